@@ -21,6 +21,8 @@ fn create_sample_flow_data(keystroke_count: usize) -> FlowStateData {
         timestamp: chrono::Utc::now().timestamp_millis(),
         typing_velocity: Some(280.0),
         pause_patterns: None,
+        aggregates: None,
+        velocity_unit: Default::default(),
     }
 }
 
@@ -46,6 +48,8 @@ fn bench_flow_detection_latency(c: &mut Criterion) {
                     notification_threshold: 0.6,
                     focus_mode_enabled: true,
                     break_reminders_enabled: true,
+                    personalized_calibration: false,
+                    use_ml: None,
                 });
 
                 b.to_async(&rt).iter(|| async {
@@ -0,0 +1,12 @@
+-- Editor-reported interruptions (calls, meetings, notifications)
+CREATE TABLE flow_interruptions (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    session_id UUID NOT NULL REFERENCES coding_sessions(id) ON DELETE CASCADE,
+    interruption_type VARCHAR(50) NOT NULL,
+    duration_ms BIGINT NOT NULL DEFAULT 0,
+    occurred_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW()
+);
+
+CREATE INDEX idx_flow_interruptions_session_id ON flow_interruptions(session_id);
+CREATE INDEX idx_flow_interruptions_occurred_at ON flow_interruptions(occurred_at);
@@ -0,0 +1,9 @@
+-- Per-user rolling baseline of flow intensities, used to express scores
+-- relative to the user's own normal
+CREATE TABLE user_flow_baselines (
+    user_id UUID PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    sample_count BIGINT NOT NULL DEFAULT 0,
+    mean_intensity DOUBLE PRECISION NOT NULL DEFAULT 0,
+    intensity_variance DOUBLE PRECISION NOT NULL DEFAULT 0,
+    updated_at TIMESTAMP WITH TIME ZONE DEFAULT NOW()
+);
@@ -0,0 +1,6 @@
+-- Final flow aggregates written once when a session ends, so history and
+-- summaries don't re-scan flow_states
+ALTER TABLE coding_sessions
+    ADD COLUMN total_flow_time_ms BIGINT,
+    ADD COLUMN avg_flow_intensity DOUBLE PRECISION,
+    ADD COLUMN peak_flow_intensity DOUBLE PRECISION;
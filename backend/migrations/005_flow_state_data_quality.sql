@@ -0,0 +1,5 @@
+-- Sample-size quality of each persisted analysis (1.0 = 10+ keystroke
+-- intervals). NULL for rows written before quality was tracked.
+ALTER TABLE flow_states ADD COLUMN data_quality DOUBLE PRECISION;
+
+CREATE INDEX idx_flow_states_data_quality ON flow_states(data_quality);
@@ -0,0 +1,16 @@
+-- Append-only, hash-chained record of privacy operations. Each entry's
+-- hash covers the previous entry's hash, so editing or deleting any row
+-- breaks verification of everything after it. No foreign keys: entries
+-- must outlive the users they describe.
+CREATE TABLE audit_log (
+    sequence BIGINT PRIMARY KEY,
+    actor_id UUID,
+    subject_user_id UUID,
+    operation VARCHAR(50) NOT NULL,
+    details JSONB NOT NULL DEFAULT '{}',
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    prev_hash CHAR(64) NOT NULL,
+    entry_hash CHAR(64) NOT NULL
+);
+
+CREATE INDEX idx_audit_log_subject_user_id ON audit_log(subject_user_id);
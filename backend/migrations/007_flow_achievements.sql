@@ -0,0 +1,11 @@
+-- Per-user flow streak and best-session records, updated when a session ends.
+-- Streak days are calendar days in the user's own timezone.
+CREATE TABLE user_flow_achievements (
+    user_id UUID PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    current_streak_days INTEGER NOT NULL DEFAULT 0,
+    longest_streak_days INTEGER NOT NULL DEFAULT 0,
+    last_flow_date DATE,
+    best_session_flow_ms BIGINT NOT NULL DEFAULT 0,
+    best_session_id UUID REFERENCES coding_sessions(id) ON DELETE SET NULL,
+    updated_at TIMESTAMP WITH TIME ZONE DEFAULT NOW()
+);
@@ -0,0 +1,12 @@
+-- Standing flow detection preferences, used when a detection request
+-- doesn't carry its own
+CREATE TABLE user_flow_preferences (
+    user_id UUID PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    sensitivity_level DOUBLE PRECISION NOT NULL CHECK (sensitivity_level BETWEEN 0 AND 1),
+    notification_threshold DOUBLE PRECISION NOT NULL CHECK (notification_threshold BETWEEN 0 AND 1),
+    focus_mode_enabled BOOLEAN NOT NULL DEFAULT false,
+    break_reminders_enabled BOOLEAN NOT NULL DEFAULT true,
+    personalized_calibration BOOLEAN NOT NULL DEFAULT false,
+    use_ml BOOLEAN,
+    updated_at TIMESTAMP WITH TIME ZONE DEFAULT NOW()
+);
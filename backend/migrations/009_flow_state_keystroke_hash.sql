@@ -0,0 +1,8 @@
+-- Salted hash of a sample's keystroke timings, stored instead of anything
+-- derived from the raw sequence for users with High/Military encryption
+ALTER TABLE flow_states
+    ADD COLUMN keystroke_hash CHAR(64);
+
+CREATE INDEX idx_flow_states_keystroke_hash
+    ON flow_states(session_id, keystroke_hash)
+    WHERE keystroke_hash IS NOT NULL;
@@ -0,0 +1,16 @@
+-- Manager-set team targets, one per metric. last_completed_period records
+-- the start of the last period the goal was met in, so the completion
+-- alert goes out once per period.
+CREATE TABLE team_goals (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    team_id UUID NOT NULL REFERENCES teams(id) ON DELETE CASCADE,
+    metric VARCHAR(50) NOT NULL,
+    target_value DOUBLE PRECISION NOT NULL,
+    created_by UUID REFERENCES users(id) ON DELETE SET NULL,
+    last_completed_period TIMESTAMP WITH TIME ZONE,
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+    updated_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+    UNIQUE(team_id, metric)
+);
+
+CREATE INDEX idx_team_goals_team_id ON team_goals(team_id);
@@ -0,0 +1,5 @@
+-- Model that scored each flow state. Rows from before versioning stay NULL.
+ALTER TABLE flow_states
+    ADD COLUMN model_version VARCHAR(100);
+
+CREATE INDEX idx_flow_states_model_version ON flow_states(model_version);
@@ -0,0 +1,13 @@
+-- Ranked team roles: member < manager < owner. Every team's owner_id gets
+-- an owner membership row so role checks only need team_members.
+UPDATE team_members SET role = 'member'
+WHERE role IS NULL OR role NOT IN ('member', 'manager', 'owner');
+
+ALTER TABLE team_members
+    ALTER COLUMN role SET DEFAULT 'member',
+    ALTER COLUMN role SET NOT NULL,
+    ADD CONSTRAINT team_members_role_check CHECK (role IN ('member', 'manager', 'owner'));
+
+INSERT INTO team_members (team_id, user_id, role)
+SELECT id, owner_id, 'owner' FROM teams
+ON CONFLICT (team_id, user_id) DO UPDATE SET role = 'owner';
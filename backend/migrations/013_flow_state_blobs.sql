@@ -0,0 +1,6 @@
+-- Compressed alternatives to typing_rhythm_data and ml_features, written
+-- when FLOW_BLOB_COMPRESSION is on. The first byte of a blob names its
+-- encoding; rows without a blob keep their data in the JSONB columns.
+ALTER TABLE flow_states
+    ADD COLUMN typing_rhythm_blob BYTEA,
+    ADD COLUMN ml_features_blob BYTEA;
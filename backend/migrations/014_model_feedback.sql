@@ -0,0 +1,13 @@
+-- Labelled examples for retraining the flow model, used when
+-- FEEDBACK_STORE=postgres. Capped at FEEDBACK_STORE_CAPACITY rows and
+-- pruned after FEEDBACK_RETENTION_DAYS.
+CREATE TABLE model_feedback (
+    id BIGSERIAL PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    features REAL[] NOT NULL,
+    actual_flow_state REAL NOT NULL,
+    user_feedback REAL,
+    recorded_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_model_feedback_recorded_at ON model_feedback(recorded_at DESC, id DESC);
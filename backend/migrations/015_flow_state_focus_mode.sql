@@ -0,0 +1,4 @@
+-- Whether the user had focus mode on when the flow state was recorded, so
+-- focus-mode periods can be compared against the rest.
+ALTER TABLE flow_states
+    ADD COLUMN focus_mode BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,6 @@
+-- Scores a flow state had before it was first recomputed by an admin
+-- replay ({"intensity_score", "confidence_score", "model_version"}), and
+-- when it was last recomputed. Both stay NULL for rows never replayed.
+ALTER TABLE flow_states
+    ADD COLUMN original_scores JSONB,
+    ADD COLUMN replayed_at TIMESTAMP WITH TIME ZONE;
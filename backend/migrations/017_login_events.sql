@@ -0,0 +1,17 @@
+-- One row per login or registration, for spotting account takeover.
+-- Locations are coarse (city level, coordinates rounded to ~10 km);
+-- ip_address stays NULL for users with high-security privacy settings.
+CREATE TABLE login_events (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    ip_address VARCHAR(45),
+    country VARCHAR(2),
+    city VARCHAR(255),
+    latitude DOUBLE PRECISION,
+    longitude DOUBLE PRECISION,
+    new_location BOOLEAN NOT NULL DEFAULT FALSE,
+    impossible_travel BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_login_events_user_created ON login_events(user_id, created_at DESC);
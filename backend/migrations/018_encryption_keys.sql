@@ -0,0 +1,16 @@
+-- Field encryption keys created by scheduled rotation, wrapped under
+-- ENCRYPTION_KEY so they survive restarts. The ENCRYPTION_KEY key itself is
+-- registered with a NULL wrapped_key. retired_at is NULL for the one active
+-- key; a retired key is deleted once past retention and no
+-- encrypted_user_data row still uses it.
+CREATE TABLE encryption_keys (
+    key_id VARCHAR(100) PRIMARY KEY,
+    wrapped_key TEXT,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    retired_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE UNIQUE INDEX idx_encryption_keys_active ON encryption_keys ((retired_at IS NULL))
+    WHERE retired_at IS NULL;
+
+CREATE INDEX idx_encrypted_data_key_id ON encrypted_user_data(encryption_key_id);
@@ -0,0 +1,5 @@
+-- environment_data holds a typed session environment (editor, os,
+-- monitor_count, theme, extensions) alongside any extra client keys.
+-- Analytics filter on it by containment.
+CREATE INDEX idx_coding_sessions_environment
+    ON coding_sessions USING GIN (environment_data jsonb_path_ops);
@@ -0,0 +1,4 @@
+-- Id of the queued write that stored the row. A write replayed from the
+-- flow WAL after a crash may already have been stored; the unique id makes
+-- the replay a no-op instead of a duplicate row.
+ALTER TABLE flow_states ADD COLUMN write_id UUID UNIQUE;
@@ -0,0 +1,12 @@
+-- One insight per user, type and period. Regenerating an insight for the
+-- same period updates it in place instead of adding a duplicate.
+DELETE FROM user_insights a
+USING user_insights b
+WHERE a.user_id = b.user_id
+  AND a.insight_type = b.insight_type
+  AND a.date_range_start IS NOT DISTINCT FROM b.date_range_start
+  AND a.date_range_end IS NOT DISTINCT FROM b.date_range_end
+  AND (a.updated_at, a.id) < (b.updated_at, b.id);
+
+CREATE UNIQUE INDEX idx_user_insights_period
+    ON user_insights(user_id, insight_type, date_range_start, date_range_end);
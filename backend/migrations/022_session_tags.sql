@@ -0,0 +1,10 @@
+-- User-chosen labels on sessions ("bug fix", "deep work"), for searching
+-- history. Tags are stored normalized: trimmed, single-spaced, lowercase.
+CREATE TABLE session_tags (
+    session_id UUID NOT NULL REFERENCES coding_sessions(id) ON DELETE CASCADE,
+    tag VARCHAR(50) NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (session_id, tag)
+);
+
+CREATE INDEX idx_session_tags_tag ON session_tags(tag);
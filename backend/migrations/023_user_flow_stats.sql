@@ -0,0 +1,8 @@
+-- Lifetime flow totals per user, added to whenever a session's in-memory
+-- flow engine is retired, so they outlive the engines.
+CREATE TABLE user_flow_stats (
+    user_id UUID PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    flow_session_count BIGINT NOT NULL DEFAULT 0,
+    total_flow_time_ms BIGINT NOT NULL DEFAULT 0,
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
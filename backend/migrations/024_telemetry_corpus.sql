@@ -0,0 +1,25 @@
+-- De-identified feature vectors from users who opted in to telemetry, for
+-- training the default model. Vectors are quantised into coarse cells and
+-- store no user, session, keystrokes or timestamps; a cell is released for
+-- training only once enough distinct users have contributed to it.
+CREATE TABLE telemetry_feature_cells (
+    cell_key TEXT PRIMARY KEY,
+    features JSONB NOT NULL,
+    flow_intensity DOUBLE PRECISION NOT NULL,
+    is_in_flow BOOLEAN NOT NULL,
+    model_version VARCHAR(50) NOT NULL,
+    contributor_count INTEGER NOT NULL DEFAULT 0,
+    sample_count BIGINT NOT NULL DEFAULT 0
+);
+
+CREATE INDEX idx_telemetry_feature_cells_contributors
+    ON telemetry_feature_cells(contributor_count);
+
+-- Which cells a user has already been counted in, so each user adds to a
+-- cell's contributor count once. Kept out of the corpus itself and removed
+-- with the user.
+CREATE TABLE telemetry_contributions (
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    cell_key TEXT NOT NULL REFERENCES telemetry_feature_cells(cell_key) ON DELETE CASCADE,
+    PRIMARY KEY (user_id, cell_key)
+);
@@ -0,0 +1,11 @@
+-- Anonymous trials already carried over to a registered account. Each
+-- trial can be claimed once; repeating the claim is answered from here
+-- instead of copying its sessions again.
+CREATE TABLE anonymous_claims (
+    anonymous_id UUID PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    sessions_migrated INTEGER NOT NULL DEFAULT 0,
+    claimed_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_anonymous_claims_user_id ON anonymous_claims(user_id);
@@ -0,0 +1,15 @@
+-- Detached flow-state writes that still failed after their retries, kept
+-- for investigation instead of being dropped. The payload is the queued
+-- write as JSON, so a fixed row can be stored by hand; entries are removed
+-- with the user.
+CREATE TABLE flow_write_dead_letters (
+    write_id UUID PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    session_id UUID NOT NULL,
+    payload JSONB NOT NULL,
+    error TEXT NOT NULL,
+    attempts INTEGER NOT NULL,
+    failed_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_flow_write_dead_letters_failed_at ON flow_write_dead_letters(failed_at);
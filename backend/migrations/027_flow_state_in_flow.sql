@@ -0,0 +1,5 @@
+-- Whether the sample found the user in flow. When a session ends, each
+-- in-flow sample's duration_ms is filled in with the time until the next
+-- sample, which is what the session's flow time adds up.
+ALTER TABLE flow_states
+    ADD COLUMN is_in_flow BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,18 @@
+-- Retained session context is kept once per session: later updates replace
+-- it instead of adding another row. Existing rows are linked to their
+-- session and all but the latest per session dropped.
+ALTER TABLE encrypted_user_data
+    ADD COLUMN session_id UUID REFERENCES coding_sessions(id) ON DELETE CASCADE;
+
+UPDATE encrypted_user_data d
+SET session_id = cs.id
+FROM coding_sessions cs
+WHERE d.data_type = 'session_context:' || cs.id::TEXT;
+
+DELETE FROM encrypted_user_data a
+USING encrypted_user_data b
+WHERE a.session_id = b.session_id
+  AND (a.created_at, a.id) < (b.created_at, b.id);
+
+ALTER TABLE encrypted_user_data
+    ADD CONSTRAINT encrypted_user_data_session_id_key UNIQUE (session_id);
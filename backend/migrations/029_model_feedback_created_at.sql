@@ -0,0 +1,9 @@
+-- When each feedback example was stored. Both feedback stores evict and
+-- batch by this rather than the example's recorded_at, so they agree
+-- on which examples go first.
+ALTER TABLE model_feedback
+    ADD COLUMN created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW();
+
+UPDATE model_feedback SET created_at = recorded_at;
+
+CREATE INDEX idx_model_feedback_created_at ON model_feedback(created_at DESC, id DESC);
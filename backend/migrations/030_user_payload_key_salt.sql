@@ -0,0 +1,3 @@
+-- Random per-user salt for the WebSocket payload key, generated the first
+-- time a high-security user signs in.
+ALTER TABLE users ADD COLUMN payload_key_salt BYTEA;
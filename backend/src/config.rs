@@ -1,16 +1,1301 @@
+use crate::{
+    models::flow::UserFlowPreferences,
+    services::{feature_flags::FeatureFlags, flow::KEYSTROKE_BUFFER_CAPACITY},
+    utils::auth::{parse_jwt_algorithm, SubscriptionTier, DEFAULT_JWT_ALGORITHM},
+};
 use anyhow::Result;
+use jsonwebtoken::Algorithm;
 use serde::{Deserialize, Serialize};
 use std::env;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub database_url: String,
+    pub database_replica_url: Option<String>,
     pub port: u16,
     pub jwt_secret: String,
+    pub jwt_algorithm: Algorithm,
+    /// Until then, refresh tokens signed with HS256 before `jwt_algorithm`
+    /// changed are still accepted. `None` accepts only `jwt_algorithm`
+    pub jwt_refresh_hs256_until: Option<chrono::DateTime<chrono::Utc>>,
     pub encryption_key: String,
     pub environment: Environment,
     pub max_connections: u32,
     pub worker_threads: usize,
+    pub flow_sample_interval_secs: u64,
+    pub onnx_model_path: Option<String>,
+    pub flow_engine: FlowEngineConfig,
+    pub response_envelope: bool,
+    pub feature_flags: FeatureFlags,
+    pub anonymous_token_ttl_minutes: i64,
+    pub sanitizer: SanitizerConfig,
+    pub metrics_auth: MetricsAuth,
+    /// Lets operators call admin endpoints without a user account; `None`
+    /// leaves them to users with the admin role
+    pub admin_api_key: Option<String>,
+    pub secure_delete_passes: u32,
+    pub verbose_errors: bool,
+    pub flow_persist_concurrency: usize,
+    pub achievements: AchievementConfig,
+    pub keystroke_hash_salt: String,
+    pub plugin_signing: PluginSigningConfig,
+    pub plugin_limits: PluginLimitsConfig,
+    pub model_registry_dir: Option<String>,
+    pub session_idle_timeout_minutes: i64,
+    pub pattern_half_life_days: f64,
+    pub flow_blob_compression: bool,
+    pub retention: RetentionConfig,
+    pub flow_detect_concurrency_per_user: usize,
+    pub feedback_store: FeedbackStoreConfig,
+    pub focus_mode: FocusModeConfig,
+    pub score_decimal_places: Option<u32>,
+    pub flow_replay: FlowReplayConfig,
+    pub login_security: LoginSecurityConfig,
+    pub key_rotation: KeyRotationConfig,
+    pub websocket_compression: WebSocketCompressionConfig,
+    pub flow_wal: FlowWalConfig,
+    pub flow_write_retry: FlowWriteRetryConfig,
+    pub websocket_malformed: WebSocketMalformedConfig,
+    pub load_shedding: LoadSheddingConfig,
+    pub require_model_ready: bool,
+    pub insights: InsightConfig,
+    pub websocket_payload_encryption: bool,
+    pub ml_batch_max_wait_ms: u64,
+    pub ml_batch_max_size: usize,
+    pub analytics_cache_max_age_secs: u64,
+    pub run_self_test: bool,
+    pub telemetry_min_contributors: u32,
+    pub request_timeouts: RequestTimeoutConfig,
+    pub anonymous_claim_window_minutes: i64,
+    pub flow_engine_pool_size: usize,
+    /// Least time between updates to one team's shared dashboards
+    pub team_dashboard_min_interval_ms: u64,
+    /// On-demand analytics pushes a user may request over WebSocket per
+    /// minute
+    pub analytics_refreshes_per_minute: usize,
+    /// Timed queries taking at least this long are logged and counted; 0
+    /// turns that off
+    pub slow_query_threshold_ms: u64,
+    pub trusted_proxies: Vec<std::net::IpAddr>,
+}
+
+/// Tunables for the per-user flow detection engine.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FlowEngineConfig {
+    /// Seconds after a reported interruption during which scores are dampened
+    pub interruption_recovery_secs: u64,
+    /// Score reduction applied right after an interruption, fading linearly
+    /// to zero over the recovery window
+    pub interruption_penalty: f32,
+    /// Analyses needed before scores are compared against the user's baseline
+    pub baseline_min_samples: u64,
+    /// Flow threshold for users who haven't set a sensitivity
+    pub default_sensitivity: f32,
+    /// Flow threshold for premium-and-above users who haven't set one
+    pub premium_default_sensitivity: f32,
+    /// Whether premium users calibrate against their own baseline by default
+    pub premium_personalized_calibration: bool,
+    /// How far below the entry threshold a score may fall before flow ends
+    /// (`flow_hysteresis` flag)
+    pub hysteresis_margin: f32,
+    /// Weight of the newest score in the moving average (`ema_smoothing` flag)
+    pub ema_alpha: f32,
+    /// Analyses at the start of each session reported as warming up, during
+    /// which flow can't be entered
+    pub warmup_analyses: u32,
+    /// Recent results kept per user to answer retried requests
+    /// (same `session_id` and `timestamp`) without re-analysing
+    pub dedup_cache_size: usize,
+    /// Flow stretches shorter than this don't count towards session stats
+    pub min_flow_duration_ms: u64,
+    /// Score with the ML model unless a user's preferences opt out
+    pub default_use_ml: bool,
+    /// Samples dated further ahead of the server clock are rejected
+    pub max_future_skew_ms: i64,
+    /// Samples dated further back are ordered by server receive time
+    /// instead, and flagged as adjusted
+    pub max_past_skew_ms: i64,
+    /// Results kept per engine for identical repeated input; 0 disables
+    /// the cache
+    pub analysis_cache_size: usize,
+    /// Samples with fewer keystrokes aren't scored at all, only reported as
+    /// insufficient data; 0 scores every sample
+    pub min_inference_keystrokes: usize,
+    /// Z-score against the user's baseline at or below which an analysis
+    /// counts as a focus dip
+    pub intensity_drop_z: f32,
+    /// How long every analysis must stay dipped before the user is alerted,
+    /// and the least time between alerts; 0 disables dip alerts
+    pub intensity_drop_window_secs: u64,
+    /// Most recent keystroke intervals that count as current typing: the
+    /// window rhythm's sustained-rhythm bonus and consistency's recent
+    /// variation are measured over
+    pub rhythm_short_window: usize,
+    /// Intervals the overall rhythm and consistency are measured over; rhythm
+    /// takes them from the sample, consistency from the engine's history.
+    /// At most `KEYSTROKE_BUFFER_CAPACITY`
+    pub rhythm_long_window: usize,
+    /// Reject empty or self-contradictory samples with a validation error
+    /// instead of scoring them neutrally; requests can override it
+    pub strict_validation: bool,
+}
+
+impl Default for FlowEngineConfig {
+    fn default() -> Self {
+        Self {
+            interruption_recovery_secs: 300,
+            interruption_penalty: 0.3,
+            baseline_min_samples: 20,
+            default_sensitivity: 0.7,
+            premium_default_sensitivity: 0.7,
+            premium_personalized_calibration: true,
+            hysteresis_margin: 0.1,
+            ema_alpha: 0.3,
+            warmup_analyses: 3,
+            dedup_cache_size: 32,
+            min_flow_duration_ms: 120_000,
+            default_use_ml: true,
+            max_future_skew_ms: 300_000,
+            max_past_skew_ms: 86_400_000,
+            analysis_cache_size: 0,
+            min_inference_keystrokes: 0,
+            intensity_drop_z: -1.5,
+            intensity_drop_window_secs: 600,
+            rhythm_short_window: 20,
+            rhythm_long_window: KEYSTROKE_BUFFER_CAPACITY,
+            strict_validation: false,
+        }
+    }
+}
+
+impl FlowEngineConfig {
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+
+        let interruption_recovery_secs = env::var("FLOW_INTERRUPTION_RECOVERY_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(defaults.interruption_recovery_secs);
+
+        let interruption_penalty = env::var("FLOW_INTERRUPTION_PENALTY")
+            .ok()
+            .and_then(|v| v.parse::<f32>().ok())
+            .map(|penalty| penalty.clamp(0.0, 1.0))
+            .unwrap_or(defaults.interruption_penalty);
+
+        let baseline_min_samples = env::var("FLOW_BASELINE_MIN_SAMPLES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(defaults.baseline_min_samples);
+
+        let default_sensitivity = env::var("FLOW_DEFAULT_SENSITIVITY")
+            .ok()
+            .and_then(|v| v.parse::<f32>().ok())
+            .map(|sensitivity| sensitivity.clamp(0.0, 1.0))
+            .unwrap_or(defaults.default_sensitivity);
+
+        let premium_default_sensitivity = env::var("FLOW_PREMIUM_DEFAULT_SENSITIVITY")
+            .ok()
+            .and_then(|v| v.parse::<f32>().ok())
+            .map(|sensitivity| sensitivity.clamp(0.0, 1.0))
+            .unwrap_or(default_sensitivity);
+
+        let premium_personalized_calibration = env::var("FLOW_PREMIUM_PERSONALIZED_CALIBRATION")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(defaults.premium_personalized_calibration);
+
+        let hysteresis_margin = env::var("FLOW_HYSTERESIS_MARGIN")
+            .ok()
+            .and_then(|v| v.parse::<f32>().ok())
+            .map(|margin| margin.clamp(0.0, 1.0))
+            .unwrap_or(defaults.hysteresis_margin);
+
+        let ema_alpha = env::var("FLOW_EMA_ALPHA")
+            .ok()
+            .and_then(|v| v.parse::<f32>().ok())
+            .map(|alpha| alpha.clamp(0.01, 1.0))
+            .unwrap_or(defaults.ema_alpha);
+
+        let warmup_analyses = env::var("FLOW_WARMUP_ANALYSES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(defaults.warmup_analyses);
+
+        let dedup_cache_size = env::var("FLOW_DEDUP_CACHE_SIZE")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(defaults.dedup_cache_size);
+
+        let min_flow_duration_ms = env::var("FLOW_MIN_DURATION_MS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(defaults.min_flow_duration_ms);
+
+        let default_use_ml = env::var("FLOW_DEFAULT_USE_ML")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(defaults.default_use_ml);
+
+        let max_future_skew_ms = env::var("FLOW_MAX_FUTURE_SKEW_MS")
+            .ok()
+            .and_then(|v| v.parse::<i64>().ok())
+            .map(|ms| ms.max(0))
+            .unwrap_or(defaults.max_future_skew_ms);
+
+        let max_past_skew_ms = env::var("FLOW_MAX_PAST_SKEW_MS")
+            .ok()
+            .and_then(|v| v.parse::<i64>().ok())
+            .map(|ms| ms.max(0))
+            .unwrap_or(defaults.max_past_skew_ms);
+
+        let analysis_cache_size = env::var("FLOW_ANALYSIS_CACHE_SIZE")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(defaults.analysis_cache_size);
+
+        let min_inference_keystrokes = env::var("FLOW_MIN_INFERENCE_KEYSTROKES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(defaults.min_inference_keystrokes);
+
+        let intensity_drop_z = env::var("FLOW_INTENSITY_DROP_Z")
+            .ok()
+            .and_then(|v| v.parse::<f32>().ok())
+            .map(|z| z.min(0.0))
+            .unwrap_or(defaults.intensity_drop_z);
+
+        let intensity_drop_window_secs = env::var("FLOW_INTENSITY_DROP_WINDOW_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(defaults.intensity_drop_window_secs);
+
+        let rhythm_long_window = env::var("FLOW_RHYTHM_LONG_WINDOW")
+            .ok()
+            .and_then(|v| v.parse::<usize>().ok())
+            .map(|window| window.clamp(2, KEYSTROKE_BUFFER_CAPACITY))
+            .unwrap_or(defaults.rhythm_long_window);
+
+        let rhythm_short_window = env::var("FLOW_RHYTHM_SHORT_WINDOW")
+            .ok()
+            .and_then(|v| v.parse::<usize>().ok())
+            .unwrap_or(defaults.rhythm_short_window)
+            .clamp(2, rhythm_long_window);
+
+        let strict_validation = env::var("FLOW_STRICT_VALIDATION")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(defaults.strict_validation);
+
+        Self {
+            interruption_recovery_secs,
+            interruption_penalty,
+            baseline_min_samples,
+            default_sensitivity,
+            premium_default_sensitivity,
+            premium_personalized_calibration,
+            hysteresis_margin,
+            ema_alpha,
+            warmup_analyses,
+            dedup_cache_size,
+            min_flow_duration_ms,
+            default_use_ml,
+            max_future_skew_ms,
+            max_past_skew_ms,
+            analysis_cache_size,
+            min_inference_keystrokes,
+            intensity_drop_z,
+            intensity_drop_window_secs,
+            rhythm_short_window,
+            rhythm_long_window,
+            strict_validation,
+        }
+    }
+
+    /// Preferences applied when a detection request doesn't carry any.
+    pub fn default_preferences(&self, tier: SubscriptionTier) -> UserFlowPreferences {
+        let is_premium = tier.has_at_least(SubscriptionTier::Premium);
+
+        UserFlowPreferences {
+            sensitivity_level: if is_premium {
+                self.premium_default_sensitivity
+            } else {
+                self.default_sensitivity
+            },
+            notification_threshold: 0.6,
+            focus_mode_enabled: false,
+            break_reminders_enabled: true,
+            personalized_calibration: is_premium && self.premium_personalized_calibration,
+            use_ml: None,
+        }
+    }
+}
+
+/// What gets stripped from `project_path` and `environment_data` before
+/// sessions are persisted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SanitizerConfig {
+    /// Replace home directories (and the username elsewhere in the path) with `~`
+    pub redact_home_dirs: bool,
+    /// Extra regexes redacted wherever they match, on top of the built-in
+    /// token formats
+    pub secret_patterns: Vec<String>,
+}
+
+impl Default for SanitizerConfig {
+    fn default() -> Self {
+        Self {
+            redact_home_dirs: true,
+            secret_patterns: Vec::new(),
+        }
+    }
+}
+
+impl SanitizerConfig {
+    pub fn from_env() -> Result<Self> {
+        let redact_home_dirs = env::var("SANITIZE_HOME_DIRS")
+            .unwrap_or_else(|_| "true".to_string())
+            .parse()
+            .unwrap_or(true);
+
+        // JSON array, since regexes commonly contain commas
+        let secret_patterns = match env::var("SANITIZE_SECRET_PATTERNS") {
+            Ok(json) if !json.trim().is_empty() => serde_json::from_str(&json)
+                .map_err(|e| anyhow::anyhow!("Invalid SANITIZE_SECRET_PATTERNS: {}", e))?,
+            _ => Vec::new(),
+        };
+
+        Ok(Self {
+            redact_home_dirs,
+            secret_patterns,
+        })
+    }
+}
+
+/// Default data retention per subscription tier, for users who haven't
+/// set their own `data_retention_days`, and whether the sweeper enforces it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetentionConfig {
+    pub free_days: i32,
+    pub premium_days: i32,
+    pub team_days: i32,
+    pub enterprise_days: i32,
+    pub sweep: RetentionSweepMode,
+}
+
+/// What the hourly retention sweep does with sessions past retention.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetentionSweepMode {
+    /// Nothing is deleted
+    #[default]
+    Off,
+    /// Expired sessions are counted and logged, not deleted
+    DryRun,
+    Delete,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            free_days: 30,
+            premium_days: 365,
+            team_days: 730,
+            enterprise_days: 730,
+            sweep: RetentionSweepMode::Off,
+        }
+    }
+}
+
+impl RetentionConfig {
+    pub fn from_env() -> Result<Self> {
+        let defaults = Self::default();
+        let days = |name: &str, default: i32| -> Result<i32> {
+            match env::var(name) {
+                Ok(value) => value
+                    .parse()
+                    .ok()
+                    .filter(|days: &i32| *days > 0)
+                    .ok_or_else(|| anyhow::anyhow!("Invalid {}: {}", name, value)),
+                Err(_) => Ok(default),
+            }
+        };
+
+        // Deleting user data is opt-in
+        let sweep = match env::var("RETENTION_SWEEP") {
+            Ok(value) => match value.as_str() {
+                "off" => RetentionSweepMode::Off,
+                "dry_run" => RetentionSweepMode::DryRun,
+                "delete" => RetentionSweepMode::Delete,
+                _ => return Err(anyhow::anyhow!("Invalid RETENTION_SWEEP: {}", value)),
+            },
+            Err(_) => defaults.sweep,
+        };
+
+        Ok(Self {
+            free_days: days("RETENTION_DAYS_FREE", defaults.free_days)?,
+            premium_days: days("RETENTION_DAYS_PREMIUM", defaults.premium_days)?,
+            team_days: days("RETENTION_DAYS_TEAM", defaults.team_days)?,
+            enterprise_days: days("RETENTION_DAYS_ENTERPRISE", defaults.enterprise_days)?,
+            sweep,
+        })
+    }
+
+    /// Anonymous users are never persisted, but get the free default.
+    pub fn default_days(&self, tier: SubscriptionTier) -> i32 {
+        match tier {
+            SubscriptionTier::Anonymous | SubscriptionTier::Free => self.free_days,
+            SubscriptionTier::Premium => self.premium_days,
+            SubscriptionTier::Team => self.team_days,
+            SubscriptionTier::Enterprise => self.enterprise_days,
+        }
+    }
+}
+
+/// Where model feedback examples are kept for retraining, and for how long.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FeedbackStoreConfig {
+    pub backend: FeedbackStoreBackend,
+    /// Examples kept before the oldest are evicted
+    pub capacity: usize,
+    /// Examples older than this are pruned regardless of capacity
+    pub retention_days: i64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FeedbackStoreBackend {
+    /// Lost on restart; for development and tests
+    #[default]
+    Memory,
+    Postgres,
+}
+
+impl Default for FeedbackStoreConfig {
+    fn default() -> Self {
+        Self {
+            backend: FeedbackStoreBackend::Memory,
+            capacity: 10_000,
+            retention_days: 90,
+        }
+    }
+}
+
+impl FeedbackStoreConfig {
+    pub fn from_env() -> Result<Self> {
+        let defaults = Self::default();
+
+        let backend = match env::var("FEEDBACK_STORE") {
+            Ok(value) => match value.as_str() {
+                "memory" => FeedbackStoreBackend::Memory,
+                "postgres" => FeedbackStoreBackend::Postgres,
+                _ => return Err(anyhow::anyhow!("Invalid FEEDBACK_STORE: {}", value)),
+            },
+            Err(_) => defaults.backend,
+        };
+
+        let capacity = match env::var("FEEDBACK_STORE_CAPACITY") {
+            Ok(value) => value
+                .parse()
+                .ok()
+                .filter(|capacity: &usize| *capacity > 0)
+                .ok_or_else(|| anyhow::anyhow!("Invalid FEEDBACK_STORE_CAPACITY: {}", value))?,
+            Err(_) => defaults.capacity,
+        };
+
+        let retention_days = match env::var("FEEDBACK_RETENTION_DAYS") {
+            Ok(value) => value
+                .parse()
+                .ok()
+                .filter(|days: &i64| *days > 0)
+                .ok_or_else(|| anyhow::anyhow!("Invalid FEEDBACK_RETENTION_DAYS: {}", value))?,
+            Err(_) => defaults.retention_days,
+        };
+
+        Ok(Self {
+            backend,
+            capacity,
+            retention_days,
+        })
+    }
+}
+
+/// Break reminders, and what focus mode does with the ones it holds back.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FocusModeConfig {
+    /// Minutes of continuous flow before a break reminder is sent
+    pub break_reminder_after_minutes: u64,
+    /// What happens to a break reminder held back by focus mode once the
+    /// flow stretch (or focus mode) ends
+    pub deferred_reminders: DeferredReminderPolicy,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeferredReminderPolicy {
+    #[default]
+    Deliver,
+    Drop,
+}
+
+impl Default for FocusModeConfig {
+    fn default() -> Self {
+        Self {
+            break_reminder_after_minutes: 50,
+            deferred_reminders: DeferredReminderPolicy::Deliver,
+        }
+    }
+}
+
+impl FocusModeConfig {
+    pub fn from_env() -> Result<Self> {
+        let defaults = Self::default();
+
+        let break_reminder_after_minutes = match env::var("BREAK_REMINDER_AFTER_MINUTES") {
+            Ok(value) => value
+                .parse()
+                .ok()
+                .filter(|minutes: &u64| *minutes > 0)
+                .ok_or_else(|| anyhow::anyhow!("Invalid BREAK_REMINDER_AFTER_MINUTES: {}", value))?,
+            Err(_) => defaults.break_reminder_after_minutes,
+        };
+
+        let deferred_reminders = match env::var("FOCUS_MODE_DEFERRED_REMINDERS") {
+            Ok(value) => match value.as_str() {
+                "deliver" => DeferredReminderPolicy::Deliver,
+                "drop" => DeferredReminderPolicy::Drop,
+                _ => {
+                    return Err(anyhow::anyhow!(
+                        "Invalid FOCUS_MODE_DEFERRED_REMINDERS: {}",
+                        value
+                    ))
+                }
+            },
+            Err(_) => defaults.deferred_reminders,
+        };
+
+        Ok(Self {
+            break_reminder_after_minutes,
+            deferred_reminders,
+        })
+    }
+}
+
+/// Limits for recomputing stored flow states under another model.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FlowReplayConfig {
+    /// Most rows one replay request recomputes; larger ranges take several
+    /// requests, each resuming from the cursor the last one returned
+    pub batch_size: usize,
+    /// Rows recomputed per second within a batch, so a replay doesn't
+    /// crowd out live writes; 0 disables the pacing
+    pub rows_per_second: u32,
+}
+
+impl Default for FlowReplayConfig {
+    fn default() -> Self {
+        Self {
+            batch_size: 500,
+            rows_per_second: 200,
+        }
+    }
+}
+
+impl FlowReplayConfig {
+    pub fn from_env() -> Result<Self> {
+        let defaults = Self::default();
+
+        let batch_size = match env::var("FLOW_REPLAY_BATCH_SIZE") {
+            Ok(value) => value
+                .parse()
+                .ok()
+                .filter(|size: &usize| *size > 0)
+                .ok_or_else(|| anyhow::anyhow!("Invalid FLOW_REPLAY_BATCH_SIZE: {}", value))?,
+            Err(_) => defaults.batch_size,
+        };
+
+        let rows_per_second = match env::var("FLOW_REPLAY_ROWS_PER_SECOND") {
+            Ok(value) => value
+                .parse()
+                .map_err(|_| anyhow::anyhow!("Invalid FLOW_REPLAY_ROWS_PER_SECOND: {}", value))?,
+            Err(_) => defaults.rows_per_second,
+        };
+
+        Ok(Self {
+            batch_size,
+            rows_per_second,
+        })
+    }
+
+    /// Pause between recomputed rows.
+    pub fn row_interval(&self) -> Option<std::time::Duration> {
+        (self.rows_per_second > 0)
+            .then(|| std::time::Duration::from_secs_f64(1.0 / f64::from(self.rows_per_second)))
+    }
+}
+
+/// Login geolocation and impossible-travel detection.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoginSecurityConfig {
+    /// MaxMind GeoLite2/GeoIP2 City database; needs the `geoip` feature.
+    /// Without it logins are recorded without a location.
+    pub geoip_database_path: Option<String>,
+    /// Consecutive logins implying faster travel than this are flagged
+    pub max_travel_speed_kmh: f64,
+    /// Logins closer together than this are never flagged; GeoIP locations
+    /// are only accurate to a city or so
+    pub min_travel_distance_km: f64,
+}
+
+impl Default for LoginSecurityConfig {
+    fn default() -> Self {
+        Self {
+            geoip_database_path: None,
+            max_travel_speed_kmh: 1000.0,
+            min_travel_distance_km: 500.0,
+        }
+    }
+}
+
+impl LoginSecurityConfig {
+    pub fn from_env() -> Result<Self> {
+        let defaults = Self::default();
+        let positive = |name: &str, default: f64| -> Result<f64> {
+            match env::var(name) {
+                Ok(value) => value
+                    .parse()
+                    .ok()
+                    .filter(|km: &f64| *km > 0.0)
+                    .ok_or_else(|| anyhow::anyhow!("Invalid {}: {}", name, value)),
+                Err(_) => Ok(default),
+            }
+        };
+
+        Ok(Self {
+            geoip_database_path: env::var("GEOIP_DATABASE_PATH")
+                .ok()
+                .filter(|path| !path.is_empty()),
+            max_travel_speed_kmh: positive(
+                "IMPOSSIBLE_TRAVEL_SPEED_KMH",
+                defaults.max_travel_speed_kmh,
+            )?,
+            min_travel_distance_km: positive(
+                "IMPOSSIBLE_TRAVEL_MIN_DISTANCE_KM",
+                defaults.min_travel_distance_km,
+            )?,
+        })
+    }
+}
+
+/// Schedule for rotating the field encryption key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyRotationConfig {
+    /// Age at which the active key is replaced; 0 disables scheduled rotation
+    pub interval_days: i64,
+    /// How long a retired key is kept after rotation, at least; it also
+    /// stays while any data is still encrypted under it
+    pub retired_key_retention_days: i64,
+    /// How often the schedule is checked
+    pub check_interval_secs: u64,
+}
+
+impl Default for KeyRotationConfig {
+    fn default() -> Self {
+        Self {
+            interval_days: 90,
+            retired_key_retention_days: 30,
+            check_interval_secs: 60 * 60,
+        }
+    }
+}
+
+impl KeyRotationConfig {
+    pub fn from_env() -> Result<Self> {
+        let defaults = Self::default();
+
+        let interval_days = match env::var("KEY_ROTATION_INTERVAL_DAYS") {
+            Ok(value) => value
+                .parse()
+                .ok()
+                .filter(|days: &i64| *days >= 0)
+                .ok_or_else(|| anyhow::anyhow!("Invalid KEY_ROTATION_INTERVAL_DAYS: {}", value))?,
+            Err(_) => defaults.interval_days,
+        };
+
+        let retired_key_retention_days = match env::var("RETIRED_KEY_RETENTION_DAYS") {
+            Ok(value) => value
+                .parse()
+                .ok()
+                .filter(|days: &i64| *days >= 0)
+                .ok_or_else(|| anyhow::anyhow!("Invalid RETIRED_KEY_RETENTION_DAYS: {}", value))?,
+            Err(_) => defaults.retired_key_retention_days,
+        };
+
+        let check_interval_secs = match env::var("KEY_ROTATION_CHECK_INTERVAL_SECS") {
+            Ok(value) => value
+                .parse()
+                .ok()
+                .filter(|secs: &u64| *secs > 0)
+                .ok_or_else(|| {
+                    anyhow::anyhow!("Invalid KEY_ROTATION_CHECK_INTERVAL_SECS: {}", value)
+                })?,
+            Err(_) => defaults.check_interval_secs,
+        };
+
+        Ok(Self {
+            interval_days,
+            retired_key_retention_days,
+            check_interval_secs,
+        })
+    }
+}
+
+/// Deflate compression of large outbound WebSocket messages, for clients
+/// that ask for it in their hello.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebSocketCompressionConfig {
+    pub enabled: bool,
+    /// Encoded messages smaller than this go out uncompressed; for small
+    /// frames the deflate overhead outweighs the saving
+    pub threshold_bytes: usize,
+}
+
+impl Default for WebSocketCompressionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            threshold_bytes: 1024,
+        }
+    }
+}
+
+impl WebSocketCompressionConfig {
+    pub fn from_env() -> Result<Self> {
+        let defaults = Self::default();
+
+        let enabled = env::var("WS_COMPRESSION_ENABLED")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(defaults.enabled);
+
+        let threshold_bytes = match env::var("WS_COMPRESSION_THRESHOLD_BYTES") {
+            Ok(value) => value.parse().map_err(|_| {
+                anyhow::anyhow!("Invalid WS_COMPRESSION_THRESHOLD_BYTES: {}", value)
+            })?,
+            Err(_) => defaults.threshold_bytes,
+        };
+
+        Ok(Self {
+            enabled,
+            threshold_bytes,
+        })
+    }
+}
+
+/// How many undecodable frames a WebSocket client may send before it is
+/// disconnected. Each one is answered with a 400 error frame.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebSocketMalformedConfig {
+    /// Malformed frames tolerated within the window; the next one closes
+    /// the connection
+    pub max_messages: u32,
+    pub window_secs: u64,
+}
+
+impl Default for WebSocketMalformedConfig {
+    fn default() -> Self {
+        Self {
+            max_messages: 10,
+            window_secs: 60,
+        }
+    }
+}
+
+impl WebSocketMalformedConfig {
+    pub fn from_env() -> Result<Self> {
+        let defaults = Self::default();
+
+        let max_messages = match env::var("WS_MAX_MALFORMED_MESSAGES") {
+            Ok(value) => value
+                .parse()
+                .map_err(|_| anyhow::anyhow!("Invalid WS_MAX_MALFORMED_MESSAGES: {}", value))?,
+            Err(_) => defaults.max_messages,
+        };
+
+        let window_secs = match env::var("WS_MALFORMED_WINDOW_SECS") {
+            Ok(value) => value
+                .parse()
+                .map_err(|_| anyhow::anyhow!("Invalid WS_MALFORMED_WINDOW_SECS: {}", value))?,
+            Err(_) => defaults.window_secs,
+        };
+
+        Ok(Self {
+            max_messages,
+            window_secs,
+        })
+    }
+}
+
+/// How long a route's handler may take before the request fails with a 408.
+/// Flow detection is meant to answer in milliseconds, so it gives up early;
+/// exports read a user's whole history and get longer. 0 means no limit.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RequestTimeoutConfig {
+    /// Routes without a timeout of their own
+    pub default_ms: u64,
+    pub flow_detect_ms: u64,
+    /// Flow history and privacy exports
+    pub export_ms: u64,
+}
+
+impl Default for RequestTimeoutConfig {
+    fn default() -> Self {
+        Self {
+            default_ms: 10_000,
+            flow_detect_ms: 2_000,
+            export_ms: 60_000,
+        }
+    }
+}
+
+impl RequestTimeoutConfig {
+    pub fn from_env() -> Result<Self> {
+        let defaults = Self::default();
+
+        let default_ms = match env::var("REQUEST_TIMEOUT_MS") {
+            Ok(value) => value
+                .parse()
+                .map_err(|_| anyhow::anyhow!("Invalid REQUEST_TIMEOUT_MS: {}", value))?,
+            Err(_) => defaults.default_ms,
+        };
+
+        let flow_detect_ms = match env::var("FLOW_DETECT_TIMEOUT_MS") {
+            Ok(value) => value
+                .parse()
+                .map_err(|_| anyhow::anyhow!("Invalid FLOW_DETECT_TIMEOUT_MS: {}", value))?,
+            Err(_) => defaults.flow_detect_ms,
+        };
+
+        let export_ms = match env::var("EXPORT_TIMEOUT_MS") {
+            Ok(value) => value
+                .parse()
+                .map_err(|_| anyhow::anyhow!("Invalid EXPORT_TIMEOUT_MS: {}", value))?,
+            Err(_) => defaults.export_ms,
+        };
+
+        Ok(Self {
+            default_ms,
+            flow_detect_ms,
+            export_ms,
+        })
+    }
+}
+
+/// Server-wide cap on requests being handled at once. Past it, requests
+/// are turned away with 503 rather than queued, so a spike can't exhaust
+/// the database pool or memory. Health checks are never shed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoadSheddingConfig {
+    /// 0 turns load shedding off
+    pub max_in_flight_requests: usize,
+    /// Sent as `Retry-After` on shed requests
+    pub retry_after_secs: u64,
+}
+
+impl Default for LoadSheddingConfig {
+    fn default() -> Self {
+        Self {
+            max_in_flight_requests: 1024,
+            retry_after_secs: 1,
+        }
+    }
+}
+
+impl LoadSheddingConfig {
+    pub fn from_env() -> Result<Self> {
+        let defaults = Self::default();
+
+        let max_in_flight_requests = match env::var("MAX_IN_FLIGHT_REQUESTS") {
+            Ok(value) => value
+                .parse()
+                .map_err(|_| anyhow::anyhow!("Invalid MAX_IN_FLIGHT_REQUESTS: {}", value))?,
+            Err(_) => defaults.max_in_flight_requests,
+        };
+
+        let retry_after_secs = match env::var("LOAD_SHED_RETRY_AFTER_SECS") {
+            Ok(value) => value
+                .parse()
+                .map_err(|_| anyhow::anyhow!("Invalid LOAD_SHED_RETRY_AFTER_SECS: {}", value))?,
+            Err(_) => defaults.retry_after_secs,
+        };
+
+        Ok(Self {
+            max_in_flight_requests,
+            retry_after_secs,
+        })
+    }
+}
+
+/// Background insight generation and how long an insight stays current.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InsightConfig {
+    /// Insights not refreshed for this long are deactivated
+    pub expiry_days: i64,
+    /// How often insights are regenerated for recently active users
+    pub refresh_interval_secs: u64,
+}
+
+impl Default for InsightConfig {
+    fn default() -> Self {
+        Self {
+            expiry_days: 14,
+            refresh_interval_secs: 60 * 60,
+        }
+    }
+}
+
+impl InsightConfig {
+    pub fn from_env() -> Result<Self> {
+        let defaults = Self::default();
+
+        let expiry_days = match env::var("INSIGHT_EXPIRY_DAYS") {
+            Ok(value) => value
+                .parse()
+                .ok()
+                .filter(|days: &i64| *days > 0)
+                .ok_or_else(|| anyhow::anyhow!("Invalid INSIGHT_EXPIRY_DAYS: {}", value))?,
+            Err(_) => defaults.expiry_days,
+        };
+
+        let refresh_interval_secs = match env::var("INSIGHT_REFRESH_INTERVAL_SECS") {
+            Ok(value) => value
+                .parse()
+                .ok()
+                .filter(|secs: &u64| *secs > 0)
+                .ok_or_else(|| {
+                    anyhow::anyhow!("Invalid INSIGHT_REFRESH_INTERVAL_SECS: {}", value)
+                })?,
+            Err(_) => defaults.refresh_interval_secs,
+        };
+
+        Ok(Self {
+            expiry_days,
+            refresh_interval_secs,
+        })
+    }
+}
+
+/// Durability for the detached flow_states writes. With a WAL path set,
+/// each write is logged before it is queued and replayed at startup if the
+/// process died before it reached the database.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FlowWalConfig {
+    /// Unset keeps writes in memory only, as before
+    pub path: Option<String>,
+    pub sync: WalSyncPolicy,
+    /// With `WalSyncPolicy::Interval`, the longest a logged write may sit
+    /// unsynced; a crash can lose at most this much
+    pub sync_interval_ms: u64,
+    /// How long shutdown waits for queued writes to reach the database;
+    /// anything left is replayed from the WAL on the next start
+    pub drain_timeout_secs: u64,
+}
+
+/// When WAL appends are fsynced.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WalSyncPolicy {
+    /// Every batch of appends, before any write in it is queued
+    Always,
+    /// On the first batch after `sync_interval_ms` has passed
+    #[default]
+    Interval,
+}
+
+impl Default for FlowWalConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            sync: WalSyncPolicy::Interval,
+            sync_interval_ms: 100,
+            drain_timeout_secs: 30,
+        }
+    }
+}
+
+impl FlowWalConfig {
+    pub fn from_env() -> Result<Self> {
+        let defaults = Self::default();
+
+        let path = env::var("FLOW_WAL_PATH").ok().filter(|path| !path.is_empty());
+
+        let sync = match env::var("FLOW_WAL_SYNC") {
+            Ok(value) => match value.to_lowercase().as_str() {
+                "always" => WalSyncPolicy::Always,
+                "interval" => WalSyncPolicy::Interval,
+                _ => return Err(anyhow::anyhow!("Invalid FLOW_WAL_SYNC: {}", value)),
+            },
+            Err(_) => defaults.sync,
+        };
+
+        let sync_interval_ms = match env::var("FLOW_WAL_SYNC_INTERVAL_MS") {
+            Ok(value) => value
+                .parse()
+                .map_err(|_| anyhow::anyhow!("Invalid FLOW_WAL_SYNC_INTERVAL_MS: {}", value))?,
+            Err(_) => defaults.sync_interval_ms,
+        };
+
+        let drain_timeout_secs = match env::var("FLOW_WRITE_DRAIN_TIMEOUT_SECS") {
+            Ok(value) => value
+                .parse()
+                .map_err(|_| anyhow::anyhow!("Invalid FLOW_WRITE_DRAIN_TIMEOUT_SECS: {}", value))?,
+            Err(_) => defaults.drain_timeout_secs,
+        };
+
+        Ok(Self {
+            path,
+            sync,
+            sync_interval_ms,
+            drain_timeout_secs,
+        })
+    }
+}
+
+/// Retries for detached flow-state writes that hit a transient database
+/// error. A write still failing after `max_attempts` goes to the dead-letter
+/// table.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FlowWriteRetryConfig {
+    /// Tries per write, the first included; 1 disables retrying
+    pub max_attempts: u32,
+    /// Wait before the first retry, doubling for each one after
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+}
+
+impl Default for FlowWriteRetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff_ms: 100,
+            max_backoff_ms: 2000,
+        }
+    }
+}
+
+impl FlowWriteRetryConfig {
+    pub fn from_env() -> Result<Self> {
+        let defaults = Self::default();
+
+        let max_attempts = match env::var("FLOW_WRITE_MAX_ATTEMPTS") {
+            Ok(value) => value
+                .parse::<u32>()
+                .map_err(|_| anyhow::anyhow!("Invalid FLOW_WRITE_MAX_ATTEMPTS: {}", value))?
+                .max(1),
+            Err(_) => defaults.max_attempts,
+        };
+
+        let initial_backoff_ms = match env::var("FLOW_WRITE_RETRY_BACKOFF_MS") {
+            Ok(value) => value
+                .parse()
+                .map_err(|_| anyhow::anyhow!("Invalid FLOW_WRITE_RETRY_BACKOFF_MS: {}", value))?,
+            Err(_) => defaults.initial_backoff_ms,
+        };
+
+        let max_backoff_ms = match env::var("FLOW_WRITE_RETRY_MAX_BACKOFF_MS") {
+            Ok(value) => value.parse().map_err(|_| {
+                anyhow::anyhow!("Invalid FLOW_WRITE_RETRY_MAX_BACKOFF_MS: {}", value)
+            })?,
+            Err(_) => defaults.max_backoff_ms,
+        };
+
+        Ok(Self {
+            max_attempts,
+            initial_backoff_ms,
+            max_backoff_ms: max_backoff_ms.max(initial_backoff_ms),
+        })
+    }
+}
+
+/// What counts towards flow streaks and which streak lengths are announced.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AchievementConfig {
+    /// Flow time a session needs for its day to count towards the streak
+    pub min_session_flow_ms: u64,
+    /// Streak lengths, in days, that trigger a notification
+    pub streak_milestones: Vec<u32>,
+}
+
+impl Default for AchievementConfig {
+    fn default() -> Self {
+        Self {
+            min_session_flow_ms: 600_000,
+            streak_milestones: vec![3, 7, 14, 30, 60, 100, 365],
+        }
+    }
+}
+
+impl AchievementConfig {
+    pub fn from_env() -> Result<Self> {
+        let defaults = Self::default();
+
+        let min_session_flow_ms = env::var("FLOW_STREAK_MIN_FLOW_MS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(defaults.min_session_flow_ms);
+
+        let streak_milestones = match env::var("FLOW_STREAK_MILESTONES") {
+            Ok(list) if !list.trim().is_empty() => list
+                .split(',')
+                .map(|days| days.trim().parse())
+                .collect::<std::result::Result<Vec<u32>, _>>()
+                .map_err(|e| anyhow::anyhow!("Invalid FLOW_STREAK_MILESTONES: {}", e))?,
+            _ => defaults.streak_milestones,
+        };
+
+        Ok(Self {
+            min_session_flow_ms,
+            streak_milestones,
+        })
+    }
+}
+
+/// Which plugin signers are trusted, and whether unsigned plugins may load.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PluginSigningConfig {
+    /// Reject any `.wasm` without a valid signature from `trusted_signers`
+    pub require_signatures: bool,
+    pub trusted_signers: Vec<TrustedSigner>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrustedSigner {
+    /// Reported as the plugin's signer
+    pub name: String,
+    /// Hex-encoded Ed25519 public key
+    pub public_key: String,
+}
+
+impl PluginSigningConfig {
+    /// Reads `PLUGIN_TRUSTED_SIGNERS` as comma-separated `name=hexkey`
+    /// pairs. Requiring signatures without trusting anyone is a startup
+    /// error, since no plugin could ever load.
+    pub fn from_env() -> Result<Self> {
+        let require_signatures = env::var("PLUGIN_REQUIRE_SIGNATURES")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(false);
+
+        let trusted_signers = match env::var("PLUGIN_TRUSTED_SIGNERS") {
+            Ok(list) if !list.trim().is_empty() => list
+                .split(',')
+                .map(|entry| {
+                    let (name, public_key) = entry.trim().split_once('=').ok_or_else(|| {
+                        anyhow::anyhow!("Invalid PLUGIN_TRUSTED_SIGNERS entry: {}", entry)
+                    })?;
+                    Ok(TrustedSigner {
+                        name: name.trim().to_string(),
+                        public_key: public_key.trim().to_string(),
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?,
+            _ => Vec::new(),
+        };
+
+        if require_signatures && trusted_signers.is_empty() {
+            return Err(anyhow::anyhow!(
+                "PLUGIN_REQUIRE_SIGNATURES is set but PLUGIN_TRUSTED_SIGNERS is empty"
+            ));
+        }
+
+        Ok(Self {
+            require_signatures,
+            trusted_signers,
+        })
+    }
+}
+
+/// How much the plugin manager may hold at once. Compiled modules stay in
+/// memory until unloaded, so both the count and their total size are capped.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PluginLimitsConfig {
+    pub max_loaded: usize,
+    /// Approximate compiled size of all loaded modules together
+    pub max_module_bytes: usize,
+}
+
+impl Default for PluginLimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_loaded: 16,
+            max_module_bytes: 256 * 1024 * 1024,
+        }
+    }
+}
+
+impl PluginLimitsConfig {
+    pub fn from_env() -> Result<Self> {
+        let defaults = Self::default();
+
+        let max_loaded = match env::var("PLUGIN_MAX_LOADED") {
+            Ok(value) => value
+                .parse()
+                .map_err(|_| anyhow::anyhow!("Invalid PLUGIN_MAX_LOADED: {}", value))?,
+            Err(_) => defaults.max_loaded,
+        };
+
+        let max_module_bytes = match env::var("PLUGIN_MODULE_MEMORY_MB") {
+            Ok(value) => value
+                .parse::<usize>()
+                .map_err(|_| anyhow::anyhow!("Invalid PLUGIN_MODULE_MEMORY_MB: {}", value))?
+                .saturating_mul(1024 * 1024),
+            Err(_) => defaults.max_module_bytes,
+        };
+
+        Ok(Self {
+            max_loaded,
+            max_module_bytes,
+        })
+    }
+}
+
+/// Credential a scraper must present to read `/metrics`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "scheme", rename_all = "snake_case")]
+pub enum MetricsAuth {
+    #[default]
+    Open,
+    Bearer { token: String },
+    Basic { username: String, password: String },
+}
+
+impl MetricsAuth {
+    /// Protected by default in production, open elsewhere. A required
+    /// credential that is not configured is a startup error rather than a
+    /// silently unreachable endpoint.
+    pub fn from_env(environment: &Environment) -> Result<Self> {
+        let required = env::var("METRICS_AUTH_REQUIRED")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(matches!(environment, Environment::Production));
+
+        if !required {
+            return Ok(Self::Open);
+        }
+
+        let token = env::var("METRICS_AUTH_TOKEN").ok().filter(|t| !t.is_empty());
+        let username = env::var("METRICS_AUTH_USERNAME").ok().filter(|u| !u.is_empty());
+        let password = env::var("METRICS_AUTH_PASSWORD").ok().filter(|p| !p.is_empty());
+
+        match (token, username, password) {
+            (Some(token), _, _) => Ok(Self::Bearer { token }),
+            (None, Some(username), Some(password)) => Ok(Self::Basic { username, password }),
+            _ => Err(anyhow::anyhow!(
+                "/metrics requires authentication: set METRICS_AUTH_TOKEN or \
+                 METRICS_AUTH_USERNAME/METRICS_AUTH_PASSWORD (or METRICS_AUTH_REQUIRED=false)"
+            )),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -27,6 +1312,11 @@ impl Config {
         let database_url = env::var("DATABASE_URL")
             .unwrap_or_else(|_| "postgresql://localhost:5432/mindful_code".to_string());
 
+        // Optional read-only replica for analytics queries
+        let database_replica_url = env::var("DATABASE_REPLICA_URL")
+            .ok()
+            .filter(|url| !url.is_empty());
+
         let port = env::var("PORT")
             .unwrap_or_else(|_| "3001".to_string())
             .parse()
@@ -35,6 +1325,32 @@ impl Config {
         let jwt_secret = env::var("JWT_SECRET")
             .unwrap_or_else(|_| "your-super-secret-jwt-key-change-in-production".to_string());
 
+        // The only algorithm tokens are signed and accepted with
+        let jwt_algorithm = match env::var("JWT_ALGORITHM") {
+            Ok(value) => parse_jwt_algorithm(&value)
+                .ok_or_else(|| anyhow::anyhow!("Invalid JWT_ALGORITHM: {}", value))?,
+            Err(_) => DEFAULT_JWT_ALGORITHM,
+        };
+
+        // Migration window for refresh tokens issued with HS256 before
+        // JWT_ALGORITHM was changed; they live 30 days
+        let jwt_refresh_hs256_until = match env::var("JWT_REFRESH_HS256_UNTIL") {
+            Ok(value) if value.is_empty() => None,
+            Ok(value) => Some(
+                chrono::DateTime::parse_from_rfc3339(&value)
+                    .map_err(|_| anyhow::anyhow!("Invalid JWT_REFRESH_HS256_UNTIL: {}", value))?
+                    .with_timezone(&chrono::Utc),
+            ),
+            Err(_) => None,
+        };
+
+        let admin_api_key = env::var("ADMIN_API_KEY").ok().filter(|key| !key.is_empty());
+        if admin_api_key.as_ref().is_some_and(|key| key.len() < 32) {
+            return Err(anyhow::anyhow!(
+                "ADMIN_API_KEY must be at least 32 characters; generate one with `openssl rand -hex 32`"
+            ));
+        }
+
         let encryption_key = env::var("ENCRYPTION_KEY")
             .unwrap_or_else(|_| "change-this-32-byte-key-in-production!!".to_string());
 
@@ -57,14 +1373,321 @@ impl Config {
             .parse()
             .unwrap_or(4);
 
+        // Minimum spacing between persisted flow_states rows per session;
+        // flow enter/exit transitions are always persisted. 0 persists every analysis.
+        let flow_sample_interval_secs = env::var("FLOW_SAMPLE_INTERVAL_SECS")
+            .unwrap_or_else(|_| "30".to_string())
+            .parse()
+            .unwrap_or(30);
+
+        // Optional exported ONNX flow model (requires the `onnx` feature)
+        let onnx_model_path = env::var("ONNX_MODEL_PATH")
+            .ok()
+            .filter(|path| !path.is_empty());
+
+        let flow_engine = FlowEngineConfig::from_env();
+
+        // Wrap success bodies as {"data", "meta"} for every client; otherwise
+        // only requests that ask for the envelope media type get it
+        let response_envelope = env::var("RESPONSE_ENVELOPE")
+            .unwrap_or_else(|_| "false".to_string())
+            .parse()
+            .unwrap_or(false);
+
+        // JSON object of flag rollout rules, see services::feature_flags
+        let feature_flags = match env::var("FEATURE_FLAGS") {
+            Ok(json) if !json.trim().is_empty() => FeatureFlags::from_json(&json)
+                .map_err(|e| anyhow::anyhow!("Invalid FEATURE_FLAGS: {}", e))?,
+            _ => FeatureFlags::default(),
+        };
+
+        // Lifetime of trial tokens issued by /api/auth/anonymous
+        let anonymous_token_ttl_minutes = env::var("ANONYMOUS_TOKEN_TTL_MINUTES")
+            .unwrap_or_else(|_| "60".to_string())
+            .parse()
+            .unwrap_or(60);
+
+        let sanitizer = SanitizerConfig::from_env()?;
+
+        let metrics_auth = MetricsAuth::from_env(&environment)?;
+
+        // Random overwrite passes before zeroing when securely deleting buffers
+        let secure_delete_passes = env::var("SECURE_DELETE_PASSES")
+            .unwrap_or_else(|_| "3".to_string())
+            .parse()
+            .unwrap_or(3);
+
+        // Adds the error chain to 5xx response bodies; forced off in production
+        let verbose_errors = !matches!(environment, Environment::Production)
+            && env::var("VERBOSE_ERRORS")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(true);
+
+        // Detached flow_states writes allowed to hold a pool connection at
+        // once; further writes queue for a slot
+        let flow_persist_concurrency = env::var("FLOW_PERSIST_CONCURRENCY")
+            .unwrap_or_else(|_| "16".to_string())
+            .parse()
+            .unwrap_or(16);
+
+        let achievements = AchievementConfig::from_env()?;
+
+        // Salt for the keystroke hashes stored in place of timings for users
+        // with high encryption levels. A known salt makes the hashes easy to
+        // match against guessed timings, so production must set its own
+        let keystroke_hash_salt = match env::var("KEYSTROKE_HASH_SALT")
+            .ok()
+            .filter(|salt| !salt.is_empty())
+        {
+            Some(salt) => salt,
+            None if matches!(environment, Environment::Production) => {
+                return Err(anyhow::anyhow!(
+                    "KEYSTROKE_HASH_SALT must be set in production; generate one with `openssl rand -hex 32`"
+                ));
+            }
+            None => {
+                tracing::warn!(
+                    "KEYSTROKE_HASH_SALT is not set, using the built-in development salt"
+                );
+                "change-this-keystroke-hash-salt".to_string()
+            }
+        };
+
+        let plugin_signing = PluginSigningConfig::from_env()?;
+        let plugin_limits = PluginLimitsConfig::from_env()?;
+
+        // Directory of versioned models admins can pin for backtesting
+        let model_registry_dir = env::var("MODEL_REGISTRY_DIR")
+            .ok()
+            .filter(|dir| !dir.is_empty());
+
+        // Active sessions without activity for this long are ended automatically
+        let session_idle_timeout_minutes = env::var("SESSION_IDLE_TIMEOUT_MINUTES")
+            .ok()
+            .and_then(|minutes| minutes.parse().ok())
+            .filter(|minutes: &i64| *minutes > 0)
+            .unwrap_or(30);
+
+        // Older history counts half as much per half-life in pattern analysis;
+        // 0 weights all history equally
+        let pattern_half_life_days = env::var("PATTERN_HALF_LIFE_DAYS")
+            .ok()
+            .and_then(|days| days.parse().ok())
+            .filter(|days: &f64| days.is_finite() && *days >= 0.0)
+            .unwrap_or(21.0);
+
+        // Store flow_states rhythm/feature JSON as compressed blobs
+        let flow_blob_compression = env::var("FLOW_BLOB_COMPRESSION")
+            .unwrap_or_else(|_| "false".to_string())
+            .parse()
+            .unwrap_or(false);
+
+        let retention = RetentionConfig::from_env()?;
+
+        // In-flight /api/flow/detect requests allowed per user; more get 429
+        // while the rest finish
+        let flow_detect_concurrency_per_user = match env::var("FLOW_DETECT_CONCURRENCY_PER_USER") {
+            Ok(value) => value
+                .parse()
+                .ok()
+                .filter(|limit: &usize| *limit > 0)
+                .ok_or_else(|| {
+                    anyhow::anyhow!("Invalid FLOW_DETECT_CONCURRENCY_PER_USER: {}", value)
+                })?,
+            Err(_) => 4,
+        };
+
+        let feedback_store = FeedbackStoreConfig::from_env()?;
+
+        let focus_mode = FocusModeConfig::from_env()?;
+
+        // Decimal places flow scores are rounded to in API responses; unset
+        // keeps full f32 precision. Stored and cached values are unaffected
+        let score_decimal_places = match env::var("SCORE_DECIMAL_PLACES") {
+            Ok(value) if !value.trim().is_empty() => Some(
+                value
+                    .trim()
+                    .parse()
+                    .map_err(|_| anyhow::anyhow!("Invalid SCORE_DECIMAL_PLACES: {}", value))?,
+            ),
+            _ => None,
+        };
+
+        let flow_replay = FlowReplayConfig::from_env()?;
+
+        let login_security = LoginSecurityConfig::from_env()?;
+
+        let key_rotation = KeyRotationConfig::from_env()?;
+
+        let websocket_compression = WebSocketCompressionConfig::from_env()?;
+
+        let flow_wal = FlowWalConfig::from_env()?;
+        let flow_write_retry = FlowWriteRetryConfig::from_env()?;
+
+        let websocket_malformed = WebSocketMalformedConfig::from_env()?;
+
+        let load_shedding = LoadSheddingConfig::from_env()?;
+
+        // Answer flow detection with 503 until the model has warmed up,
+        // rather than scoring rule-based in the meantime
+        let require_model_ready = env::var("FLOW_REQUIRE_MODEL_READY")
+            .unwrap_or_else(|_| "false".to_string())
+            .parse()
+            .unwrap_or(false);
+
+        let insights = InsightConfig::from_env()?;
+
+        // Seal notification and team alert payloads for High/Military users
+        // with their login-derived key. Clients must support decrypting them
+        let websocket_payload_encryption = env::var("WS_ENCRYPT_SENSITIVE_PAYLOADS")
+            .unwrap_or_else(|_| "false".to_string())
+            .parse()
+            .unwrap_or(false);
+
+        // How long the first flow prediction in a batch waits for others to
+        // join it. Capped so a lone request is never held for long
+        let ml_batch_max_wait_ms = match env::var("ML_BATCH_MAX_WAIT_MS") {
+            Ok(value) => value
+                .parse()
+                .ok()
+                .filter(|ms: &u64| *ms <= 100)
+                .ok_or_else(|| anyhow::anyhow!("Invalid ML_BATCH_MAX_WAIT_MS: {}", value))?,
+            Err(_) => 2,
+        };
+
+        // Predictions scored together; a full batch is scored immediately,
+        // and 1, the default, turns batching off.
+        // Batches are still scored one prediction at a time, so they only
+        // pay off once a model scores them together
+        let ml_batch_max_size = match env::var("ML_BATCH_MAX_SIZE") {
+            Ok(value) => value
+                .parse()
+                .ok()
+                .filter(|size: &usize| *size > 0)
+                .ok_or_else(|| anyhow::anyhow!("Invalid ML_BATCH_MAX_SIZE: {}", value))?,
+            Err(_) => 1,
+        };
+
+        let analytics_cache_max_age_secs = match env::var("ANALYTICS_CACHE_MAX_AGE_SECS") {
+            Ok(value) => value
+                .parse()
+                .map_err(|_| anyhow::anyhow!("Invalid ANALYTICS_CACHE_MAX_AGE_SECS: {}", value))?,
+            Err(_) => 60,
+        };
+
+        let run_self_test = env::var("RUN_SELF_TEST")
+            .unwrap_or_else(|_| "false".to_string())
+            .parse()
+            .unwrap_or(false);
+
+        // Below two, a released cell could describe a single user
+        let telemetry_min_contributors = match env::var("TELEMETRY_MIN_CONTRIBUTORS") {
+            Ok(value) => value
+                .parse()
+                .ok()
+                .filter(|count: &u32| *count >= 2)
+                .ok_or_else(|| anyhow::anyhow!("Invalid TELEMETRY_MIN_CONTRIBUTORS: {}", value))?,
+            Err(_) => 5,
+        };
+
+        let request_timeouts = RequestTimeoutConfig::from_env()?;
+
+        let anonymous_claim_window_minutes = env::var("ANONYMOUS_CLAIM_WINDOW_MINUTES")
+            .unwrap_or_else(|_| "60".to_string())
+            .parse()
+            .unwrap_or(60);
+
+        let flow_engine_pool_size = env::var("FLOW_ENGINE_POOL_SIZE")
+            .unwrap_or_else(|_| "16".to_string())
+            .parse()
+            .unwrap_or(16);
+
+        let team_dashboard_min_interval_ms = env::var("TEAM_DASHBOARD_MIN_INTERVAL_MS")
+            .unwrap_or_else(|_| "2000".to_string())
+            .parse()
+            .unwrap_or(2000);
+
+        let analytics_refreshes_per_minute = env::var("WEBSOCKET_ANALYTICS_REFRESHES_PER_MINUTE")
+            .unwrap_or_else(|_| "6".to_string())
+            .parse()
+            .unwrap_or(6);
+
+        let slow_query_threshold_ms = env::var("SLOW_QUERY_THRESHOLD_MS")
+            .unwrap_or_else(|_| "100".to_string())
+            .parse()
+            .unwrap_or(100);
+
+        // Proxies whose X-Forwarded-For is believed; anyone else could put
+        // any address there
+        let trusted_proxies = match env::var("TRUSTED_PROXIES") {
+            Ok(list) if !list.trim().is_empty() => list
+                .split(',')
+                .map(|ip| ip.trim().parse())
+                .collect::<std::result::Result<Vec<std::net::IpAddr>, _>>()
+                .map_err(|e| anyhow::anyhow!("Invalid TRUSTED_PROXIES: {}", e))?,
+            _ => Vec::new(),
+        };
+
         Ok(Config {
             database_url,
+            database_replica_url,
             port,
             jwt_secret,
+            jwt_algorithm,
+            jwt_refresh_hs256_until,
             encryption_key,
             environment,
             max_connections,
             worker_threads,
+            flow_sample_interval_secs,
+            onnx_model_path,
+            flow_engine,
+            response_envelope,
+            feature_flags,
+            anonymous_token_ttl_minutes,
+            sanitizer,
+            metrics_auth,
+            admin_api_key,
+            secure_delete_passes,
+            verbose_errors,
+            flow_persist_concurrency,
+            achievements,
+            keystroke_hash_salt,
+            plugin_signing,
+            plugin_limits,
+            model_registry_dir,
+            session_idle_timeout_minutes,
+            pattern_half_life_days,
+            flow_blob_compression,
+            retention,
+            flow_detect_concurrency_per_user,
+            feedback_store,
+            focus_mode,
+            score_decimal_places,
+            flow_replay,
+            login_security,
+            key_rotation,
+            websocket_compression,
+            flow_wal,
+            flow_write_retry,
+            websocket_malformed,
+            load_shedding,
+            require_model_ready,
+            insights,
+            websocket_payload_encryption,
+            ml_batch_max_wait_ms,
+            ml_batch_max_size,
+            analytics_cache_max_age_secs,
+            run_self_test,
+            telemetry_min_contributors,
+            request_timeouts,
+            anonymous_claim_window_minutes,
+            flow_engine_pool_size,
+            team_dashboard_min_interval_ms,
+            analytics_refreshes_per_minute,
+            slow_query_threshold_ms,
+            trusted_proxies,
         })
     }
 
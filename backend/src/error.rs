@@ -4,8 +4,20 @@ use axum::{
     Json,
 };
 use serde_json::json;
+use std::{
+    backtrace::{Backtrace, BacktraceStatus},
+    sync::atomic::{AtomicBool, Ordering},
+};
 use thiserror::Error;
 
+static VERBOSE_ERRORS: AtomicBool = AtomicBool::new(false);
+
+/// Include the underlying error in 5xx response bodies. Set once at startup
+/// from `Config::verbose_errors`, which is never enabled in production.
+pub fn set_verbose_errors(enabled: bool) {
+    VERBOSE_ERRORS.store(enabled, Ordering::Relaxed);
+}
+
 #[derive(Error, Debug)]
 pub enum AppError {
     #[error("Database error: {0}")]
@@ -38,6 +50,9 @@ pub enum AppError {
     #[error("Service unavailable: {0}")]
     ServiceUnavailable(String),
 
+    #[error("Request timed out: {0}")]
+    Timeout(String),
+
     #[error("JWT error: {0}")]
     Jwt(#[from] jsonwebtoken::errors::Error),
 
@@ -53,6 +68,15 @@ pub enum AppError {
 
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
+        self.into_response_with_detail(VERBOSE_ERRORS.load(Ordering::Relaxed))
+    }
+}
+
+impl AppError {
+    /// Renders the error, adding `detail` (the full error chain, debug
+    /// representation and backtrace) to server-side failures when `verbose`
+    /// is set. Client errors already carry their own message.
+    pub fn into_response_with_detail(self, verbose: bool) -> Response {
         let (status, error_message) = match self {
             AppError::Database(ref e) => {
                 tracing::error!("Database error: {}", e);
@@ -81,6 +105,7 @@ impl IntoResponse for AppError {
             AppError::ServiceUnavailable(ref message) => {
                 (StatusCode::SERVICE_UNAVAILABLE, message.clone())
             }
+            AppError::Timeout(ref message) => (StatusCode::REQUEST_TIMEOUT, message.clone()),
             AppError::Jwt(_) => (
                 StatusCode::UNAUTHORIZED,
                 "Invalid authentication token".to_string(),
@@ -108,13 +133,79 @@ impl IntoResponse for AppError {
             }
         };
 
-        let body = Json(json!({
+        let mut body = json!({
             "error": error_message,
             "status": status.as_u16()
-        }));
+        });
+
+        if status.is_server_error() {
+            // Only taken when RUST_BACKTRACE or RUST_LIB_BACKTRACE asks for
+            // one, and logged whether or not the response shows it
+            let backtrace = Backtrace::capture();
+            let backtrace =
+                (backtrace.status() == BacktraceStatus::Captured).then(|| backtrace.to_string());
+            if let Some(backtrace) = &backtrace {
+                tracing::error!("{} backtrace:\n{}", self, backtrace);
+            }
 
-        (status, body).into_response()
+            if verbose {
+                body["detail"] = json!({
+                    "chain": self.error_chain(),
+                    "debug": format!("{:?}", self),
+                    "backtrace": backtrace,
+                });
+            }
+        }
+
+        (status, Json(body)).into_response()
+    }
+
+    fn error_chain(&self) -> Vec<String> {
+        let mut chain = vec![self.to_string()];
+        let mut source = std::error::Error::source(self);
+        while let Some(error) = source {
+            chain.push(error.to_string());
+            source = error.source();
+        }
+        chain
     }
 }
 
-pub type Result<T> = std::result::Result<T, AppError>;
\ No newline at end of file
+pub type Result<T> = std::result::Result<T, AppError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn body_json(response: Response) -> serde_json::Value {
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_database_error_detail_only_when_verbose() {
+        let db_error = || AppError::Database(sqlx::Error::ColumnNotFound("tier".to_string()));
+
+        let development = body_json(db_error().into_response_with_detail(true)).await;
+        assert_eq!(development["error"], "A database error occurred");
+        let chain = development["detail"]["chain"].as_array().unwrap();
+        assert!(chain.iter().any(|e| e.as_str().unwrap().contains("tier")));
+        // Null unless RUST_BACKTRACE is set for the test run
+        let backtrace = &development["detail"]["backtrace"];
+        assert!(backtrace.is_null() || backtrace.as_str().is_some_and(|bt| !bt.is_empty()));
+
+        let production = body_json(db_error().into_response_with_detail(false)).await;
+        assert_eq!(production["error"], "A database error occurred");
+        assert!(production.get("detail").is_none());
+        assert!(!production.to_string().contains("tier"));
+
+        // Client errors never gain a detail block
+        let validation = body_json(
+            AppError::Validation("bad input".to_string()).into_response_with_detail(true),
+        )
+        .await;
+        assert!(validation.get("detail").is_none());
+    }
+}
@@ -0,0 +1,367 @@
+use axum::{
+    extract::{Path, Query, State},
+    Json,
+};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use sqlx::migrate::Migrator;
+use tracing::{info, warn};
+use uuid::Uuid;
+use validator::Validate;
+
+use crate::{
+    error::{AppError, Result},
+    models::{
+        admin::{
+            FlowReplayCursor, FlowReplayRequest, FlowReplayResponse, KeyBackupRequest,
+            KeyBackupResponse, MigrationInfo, MigrationStatusResponse, UserEngineStateResponse,
+        },
+        audit::{AuditLogResponse, AuditOperation},
+    },
+    services::{
+        audit::{load_audit_chain, record_audit_entry, verify_audit_chain},
+        compression::read_json_column,
+    },
+    state::{AppState, MIGRATOR},
+    utils::{auth::AdminAuth, date_range::DateRange},
+};
+
+#[derive(Debug, Deserialize)]
+pub struct AuditLogQuery {
+    pub subject_user_id: Option<Uuid>,
+}
+
+pub async fn get_audit_log(
+    State(state): State<AppState>,
+    _admin: AdminAuth,
+    Query(query): Query<AuditLogQuery>,
+) -> Result<Json<AuditLogResponse>> {
+    // Read from the primary: a lagging replica would look like a truncated chain
+    let entries = load_audit_chain(&state.db).await?;
+    let verification = verify_audit_chain(&entries);
+
+    let entries = match query.subject_user_id {
+        Some(subject_user_id) => entries
+            .into_iter()
+            .filter(|entry| entry.subject_user_id == Some(subject_user_id))
+            .collect(),
+        None => entries,
+    };
+
+    Ok(Json(AuditLogResponse {
+        verification,
+        entries,
+    }))
+}
+
+/// Seals the active encryption key under an operator passphrase for
+/// disaster recovery. The raw key never leaves the server unencrypted, and
+/// every backup generated is recorded in the audit log.
+pub async fn create_key_backup(
+    State(state): State<AppState>,
+    admin: AdminAuth,
+    Json(payload): Json<KeyBackupRequest>,
+) -> Result<Json<KeyBackupResponse>> {
+    payload.validate().map_err(|e| {
+        AppError::Validation(format!("Invalid key backup request: {}", e))
+    })?;
+
+    let encryption = state.encryption.as_ref().ok_or_else(|| {
+        AppError::ServiceUnavailable("Encryption is not configured".to_string())
+    })?;
+
+    // Argon2 is deliberately slow; keep it off the async workers
+    let encryption = encryption.clone();
+    let passphrase = zeroize::Zeroizing::new(payload.passphrase);
+    let backup =
+        tokio::task::spawn_blocking(move || encryption.read().seal_key_backup(&passphrase))
+            .await
+            .map_err(|e| AppError::Internal(format!("Key backup task failed: {}", e)))??;
+
+    let mut tx = state.db.begin().await?;
+    record_audit_entry(
+        &mut tx,
+        admin.actor_id(),
+        None,
+        AuditOperation::KeyBackup,
+        serde_json::json!({ "key_id": backup.key_id }),
+    )
+    .await?;
+    tx.commit().await?;
+
+    info!(
+        "Encryption key backup generated for {} by {}",
+        backup.key_id, admin
+    );
+
+    Ok(Json(KeyBackupResponse {
+        key_id: backup.key_id.clone(),
+        backup,
+    }))
+}
+
+/// Snapshots of the user's live flow engines, one per session, to explain
+/// odd scores without reproducing them.
+pub async fn get_user_engine_state(
+    State(state): State<AppState>,
+    _admin: AdminAuth,
+    Path(user_id): Path<Uuid>,
+) -> Result<Json<UserEngineStateResponse>> {
+    let mut engines: Vec<_> = state
+        .user_flow_engines(user_id)
+        .iter()
+        .map(|engine| engine.read().snapshot())
+        .collect();
+    engines.sort_by_key(|snapshot| snapshot.session_id);
+
+    Ok(Json(UserEngineStateResponse { user_id, engines }))
+}
+
+/// Rescores a user's stored flow states under another model, one batch per
+/// request, so analytics stay comparable after a model change. A row's
+/// scores from before its first replay are kept in `original_scores`.
+///
+/// Confidence is recomputed as intensity times the stored data quality; the
+/// live engine's stability adjustment depends on history a replay doesn't
+/// have.
+pub async fn replay_flow_states(
+    State(state): State<AppState>,
+    admin: AdminAuth,
+    Path(user_id): Path<Uuid>,
+    Json(payload): Json<FlowReplayRequest>,
+) -> Result<Json<FlowReplayResponse>> {
+    let range = DateRange::between(payload.from, payload.to, chrono::Utc::now())?;
+
+    let ml_engine = match &payload.model_version {
+        Some(version) => state.model_registry.load(version)?,
+        None => state.ml_engine.clone(),
+    };
+    let model_version = ml_engine.model_version().to_string();
+    let config = &state.config.flow_replay;
+
+    let rows = sqlx::query!(
+        r#"
+        SELECT fs.id, fs.start_time, fs.model_version, fs.data_quality,
+               fs.ml_features, fs.ml_features_blob
+        FROM flow_states fs
+        JOIN coding_sessions cs ON cs.id = fs.session_id
+        WHERE cs.user_id = $1
+          AND fs.start_time >= $2
+          AND fs.start_time < $3
+          AND ($4::TIMESTAMPTZ IS NULL OR (fs.start_time, fs.id) > ($4, $5::UUID))
+        ORDER BY fs.start_time, fs.id
+        LIMIT $6
+        "#,
+        user_id,
+        range.from,
+        range.to,
+        payload.cursor.map(|cursor| cursor.start_time),
+        payload.cursor.map(|cursor| cursor.id),
+        config.batch_size as i64,
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    let mut updated = 0;
+    let mut skipped = 0;
+    for row in &rows {
+        if row.model_version.as_deref() == Some(model_version.as_str()) {
+            skipped += 1;
+            continue;
+        }
+
+        let features = read_json_column(row.ml_features_blob.as_deref(), row.ml_features.clone())
+            .ok()
+            .and_then(|ml_features| stored_features(&ml_features));
+        let Some(features) = features else {
+            warn!("Flow state {} has no usable features to replay", row.id);
+            skipped += 1;
+            continue;
+        };
+
+        let intensity = match ml_engine.predict_flow_state(features).await {
+            Ok(intensity) => intensity,
+            Err(e) => {
+                warn!("Replay of flow state {} failed: {}", row.id, e);
+                skipped += 1;
+                continue;
+            }
+        };
+        let confidence = intensity as f64 * row.data_quality.unwrap_or(1.0);
+
+        sqlx::query!(
+            r#"
+            UPDATE flow_states SET
+                original_scores = COALESCE(original_scores, jsonb_build_object(
+                    'intensity_score', intensity_score,
+                    'confidence_score', confidence_score,
+                    'model_version', model_version
+                )),
+                intensity_score = $2,
+                confidence_score = $3,
+                model_version = $4,
+                replayed_at = NOW()
+            WHERE id = $1
+            "#,
+            row.id,
+            intensity as f64,
+            confidence,
+            model_version,
+        )
+        .execute(&state.db)
+        .await?;
+        updated += 1;
+
+        if let Some(interval) = config.row_interval() {
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    // A short batch reached the end of the range
+    let next_cursor = rows
+        .last()
+        .filter(|_| rows.len() == config.batch_size)
+        .map(|row| FlowReplayCursor {
+            start_time: row.start_time,
+            id: row.id,
+        });
+
+    if updated > 0 {
+        let mut tx = state.db.begin().await?;
+        record_audit_entry(
+            &mut tx,
+            admin.actor_id(),
+            Some(user_id),
+            AuditOperation::FlowReplay,
+            serde_json::json!({
+                "model_version": model_version,
+                "from": range.from,
+                "to": range.to,
+                "updated": updated,
+            }),
+        )
+        .await?;
+        tx.commit().await?;
+    }
+
+    info!(
+        "Replayed {} flow states for {} under model {}",
+        updated, user_id, model_version
+    );
+
+    Ok(Json(FlowReplayResponse {
+        model_version,
+        updated,
+        skipped,
+        next_cursor,
+    }))
+}
+
+/// Model inputs rebuilt from a row's `ml_features`, in the order the flow
+/// engine passes them to the model.
+fn stored_features(ml_features: &serde_json::Value) -> Option<[f32; 5]> {
+    let score = |name: &str| ml_features.get(name)?.as_f64().map(|value| value as f32);
+    Some([
+        score("rhythm_score")?,
+        score("focus_score")?,
+        score("consistency_score")?,
+        1.0 - score("error_penalty")?,
+        score("velocity_score")?,
+    ])
+}
+
+/// A row of sqlx's `_sqlx_migrations` bookkeeping table.
+#[derive(Debug, Clone)]
+pub struct AppliedMigration {
+    pub version: i64,
+    pub description: String,
+    pub installed_on: chrono::DateTime<chrono::Utc>,
+    pub success: bool,
+    pub checksum: Vec<u8>,
+}
+
+pub async fn get_migration_status(
+    State(state): State<AppState>,
+    _admin: AdminAuth,
+) -> Result<Json<MigrationStatusResponse>> {
+    let applied = sqlx::query!(
+        r#"
+        SELECT version, description, installed_on, success, checksum
+        FROM _sqlx_migrations
+        ORDER BY version
+        "#
+    )
+    .fetch_all(&state.db)
+    .await?
+    .into_iter()
+    .map(|row| AppliedMigration {
+        version: row.version,
+        description: row.description,
+        installed_on: row.installed_on,
+        success: row.success,
+        checksum: row.checksum,
+    })
+    .collect::<Vec<_>>();
+
+    Ok(Json(summarize_migrations(&MIGRATOR, &applied)))
+}
+
+/// Lines up the migrations this build knows about with the ones the database
+/// has recorded. Versions only the database knows (from a newer build) are
+/// listed too, as applied.
+pub fn summarize_migrations(
+    migrator: &Migrator,
+    applied: &[AppliedMigration],
+) -> MigrationStatusResponse {
+    let known = migrator
+        .iter()
+        .filter(|migration| !migration.migration_type.is_down_migration());
+
+    let mut migrations: Vec<MigrationInfo> = known
+        .clone()
+        .map(|migration| {
+            let record = applied.iter().find(|a| a.version == migration.version);
+            MigrationInfo {
+                version: migration.version,
+                description: migration.description.to_string(),
+                applied: record.is_some_and(|a| a.success),
+                installed_on: record.map(|a| a.installed_on),
+                success: record.map(|a| a.success),
+                checksum_matches: record.map(|a| a.checksum == *migration.checksum),
+            }
+        })
+        .collect();
+
+    for record in applied {
+        if !known.clone().any(|migration| migration.version == record.version) {
+            migrations.push(MigrationInfo {
+                version: record.version,
+                description: record.description.clone(),
+                applied: record.success,
+                installed_on: Some(record.installed_on),
+                success: Some(record.success),
+                checksum_matches: None,
+            });
+        }
+    }
+    migrations.sort_by_key(|migration| migration.version);
+
+    let pending: Vec<i64> = migrations
+        .iter()
+        .filter(|migration| !migration.applied)
+        .map(|migration| migration.version)
+        .collect();
+
+    let mut hasher = Sha256::new();
+    for record in applied.iter().filter(|a| a.success) {
+        hasher.update(record.version.to_be_bytes());
+        hasher.update(&record.checksum);
+    }
+
+    MigrationStatusResponse {
+        has_pending: !pending.is_empty(),
+        pending,
+        migrations,
+        schema_checksum: hex::encode(hasher.finalize()),
+    }
+}
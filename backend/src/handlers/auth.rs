@@ -0,0 +1,463 @@
+use axum::{extract::State, Json};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use rand::{rngs::OsRng, RngCore};
+use std::net::IpAddr;
+use tracing::{info, warn};
+use uuid::Uuid;
+use validator::Validate;
+use zeroize::Zeroizing;
+
+use crate::{
+    error::{AppError, Result},
+    handlers::websocket::{send_notification, NotificationLevel},
+    models::auth::{
+        AnonymousSessionResponse, ClaimAnonymousSessionRequest, ClaimAnonymousSessionResponse,
+        LoginRequest, RefreshTokenRequest, RegisterRequest,
+    },
+    services::{
+        encryption::{decode_hex_key, derive_payload_key, PrivacySettings, PAYLOAD_KEY_SALT_LEN},
+        login_security::{record_login_event, LoginEvent},
+    },
+    state::AppState,
+    utils::{
+        auth::{
+            generate_jwt_token_with, generate_refresh_token, hash_password, require_registered,
+            validate_jwt_token_with, validate_refresh_token, verify_password, Claims,
+            SubscriptionTier, TokenPair,
+        },
+        ClientIp,
+    },
+};
+
+/// Creates a free-tier account and signs it in. The registration is recorded
+/// from the client's address like a login, so the first sign-in afterwards
+/// has a location to compare against.
+pub async fn register(
+    State(state): State<AppState>,
+    ClientIp(client_ip): ClientIp,
+    Json(payload): Json<RegisterRequest>,
+) -> Result<Json<TokenPair>> {
+    payload
+        .validate()
+        .map_err(|e| AppError::Validation(format!("Invalid registration: {}", e)))?;
+
+    // Argon2 is deliberately slow; keep it off the async workers
+    let password = Zeroizing::new(payload.password);
+    let candidate = password.clone();
+    let password_hash = tokio::task::spawn_blocking(move || hash_password(&candidate))
+        .await
+        .map_err(|e| AppError::Internal(format!("Password hashing failed: {}", e)))??;
+
+    let user = sqlx::query!(
+        r#"
+        INSERT INTO users (email, password_hash, subscription_tier)
+        VALUES ($1, $2, 'free')
+        ON CONFLICT (email) DO NOTHING
+        RETURNING id, email
+        "#,
+        payload.email,
+        password_hash
+    )
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or_else(|| AppError::Conflict("An account with this email already exists".to_string()))?;
+
+    let claims = Claims::new(user.id, user.email, SubscriptionTier::Free);
+    let access_token = generate_jwt_token_with(
+        &claims,
+        &state.config.jwt_secret,
+        state.config.jwt_algorithm,
+    )?;
+    let refresh_token = generate_refresh_token(
+        user.id,
+        &state.config.jwt_secret,
+        state.config.jwt_algorithm,
+    )?;
+
+    // Losing the record costs a location warning, not the account
+    if let Err(e) = record_login(&state, user.id, client_ip).await {
+        warn!("Failed to record registration for user {}: {}", user.id, e);
+    }
+
+    info!("User {} registered", user.id);
+
+    Ok(Json(TokenPair {
+        access_token,
+        refresh_token,
+        expires_in: (claims.exp - claims.iat) as u64,
+        payload_key: None,
+    }))
+}
+
+/// Signs a user in with their email and password. The login is recorded
+/// from the client's address, warning the user when it's from somewhere new.
+pub async fn login(
+    State(state): State<AppState>,
+    ClientIp(client_ip): ClientIp,
+    Json(payload): Json<LoginRequest>,
+) -> Result<Json<TokenPair>> {
+    // The same answer whether the account is missing or the password wrong
+    let rejected = || AppError::Authentication("Invalid email or password".to_string());
+
+    let user = sqlx::query!(
+        "SELECT id, email, password_hash, subscription_tier FROM users WHERE email = $1",
+        payload.email
+    )
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or_else(rejected)?;
+
+    // Argon2 is deliberately slow; keep it off the async workers
+    let password = Zeroizing::new(payload.password);
+    let password_hash = user.password_hash;
+    let candidate = password.clone();
+    let verified = tokio::task::spawn_blocking(move || verify_password(&candidate, &password_hash))
+        .await
+        .map_err(|e| AppError::Internal(format!("Password check failed: {}", e)))??;
+    if !verified {
+        return Err(rejected());
+    }
+
+    let tier = user.subscription_tier.unwrap_or_else(|| "free".to_string());
+    let claims = Claims::new(user.id, user.email, tier);
+    let access_token = generate_jwt_token_with(
+        &claims,
+        &state.config.jwt_secret,
+        state.config.jwt_algorithm,
+    )?;
+    let refresh_token = generate_refresh_token(
+        user.id,
+        &state.config.jwt_secret,
+        state.config.jwt_algorithm,
+    )?;
+
+    // Without a key their sensitive notifications are withheld, not leaked
+    let payload_key = match remember_payload_key(&state, user.id, &password).await {
+        Ok(key) => key.map(|key| BASE64.encode(key.as_ref())),
+        Err(e) => {
+            warn!("Failed to derive payload key for user {}: {}", user.id, e);
+            None
+        }
+    };
+
+    // Losing the record costs a location warning, not the login
+    if let Err(e) = record_login(&state, user.id, client_ip).await {
+        warn!("Failed to record login for user {}: {}", user.id, e);
+    }
+
+    info!("User {} logged in", user.id);
+
+    Ok(Json(TokenPair {
+        access_token,
+        refresh_token,
+        expires_in: (claims.exp - claims.iat) as u64,
+        payload_key,
+    }))
+}
+
+/// Exchanges a refresh token for a new token pair, with the user's current
+/// tier. The refresh token is replaced too.
+pub async fn refresh_token(
+    State(state): State<AppState>,
+    Json(payload): Json<RefreshTokenRequest>,
+) -> Result<Json<TokenPair>> {
+    let refresh = validate_refresh_token(
+        &payload.refresh_token,
+        &state.config.jwt_secret,
+        state.config.jwt_algorithm,
+        state.config.jwt_refresh_hs256_until,
+        chrono::Utc::now(),
+    )?;
+
+    let user = sqlx::query!(
+        "SELECT id, email, subscription_tier FROM users WHERE id = $1",
+        refresh.user_id
+    )
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or_else(|| AppError::Authentication("Invalid refresh token".to_string()))?;
+
+    let tier = user.subscription_tier.unwrap_or_else(|| "free".to_string());
+    let claims = Claims::new(user.id, user.email, tier);
+    let access_token = generate_jwt_token_with(
+        &claims,
+        &state.config.jwt_secret,
+        state.config.jwt_algorithm,
+    )?;
+    let refresh_token = generate_refresh_token(
+        user.id,
+        &state.config.jwt_secret,
+        state.config.jwt_algorithm,
+    )?;
+
+    Ok(Json(TokenPair {
+        access_token,
+        refresh_token,
+        expires_in: (claims.exp - claims.iat) as u64,
+        payload_key: None,
+    }))
+}
+
+/// Issues a short-lived trial token. Anonymous users can run flow detection
+/// against an in-memory engine; nothing they do is persisted.
+pub async fn create_anonymous_session(
+    State(state): State<AppState>,
+) -> Result<Json<AnonymousSessionResponse>> {
+    let anonymous_id = Uuid::new_v4();
+    let ttl_minutes = state.config.anonymous_token_ttl_minutes.max(1);
+    let claims = Claims::anonymous(anonymous_id, chrono::Duration::minutes(ttl_minutes));
+    let access_token = generate_jwt_token_with(
+        &claims,
+        &state.config.jwt_secret,
+        state.config.jwt_algorithm,
+    )?;
+
+    info!("Issued anonymous trial session {}", anonymous_id);
+
+    Ok(Json(AnonymousSessionResponse {
+        anonymous_id,
+        access_token,
+        expires_in: ttl_minutes as u64 * 60,
+    }))
+}
+
+/// Moves a trial onto the caller's newly registered account: its sessions
+/// become theirs and its in-memory flow engines carry over, so calibration
+/// and baseline survive signing up. A trial can only be claimed once, within
+/// `anonymous_claim_window_minutes` of registering; repeating the claim
+/// returns the original outcome without copying anything again.
+pub async fn claim_anonymous_session(
+    State(state): State<AppState>,
+    claims: Claims,
+    Json(payload): Json<ClaimAnonymousSessionRequest>,
+) -> Result<Json<ClaimAnonymousSessionResponse>> {
+    require_registered(&claims)?;
+
+    let anonymous = validate_jwt_token_with(
+        &payload.anonymous_token,
+        &state.config.jwt_secret,
+        state.config.jwt_algorithm,
+    )?;
+    if !anonymous.is_anonymous() {
+        return Err(AppError::BadRequest(
+            "Token does not belong to an anonymous session".to_string(),
+        ));
+    }
+
+    let mut tx = state.db.begin().await?;
+    let claimed = sqlx::query!(
+        r#"
+        INSERT INTO anonymous_claims (anonymous_id, user_id)
+        VALUES ($1, $2)
+        ON CONFLICT (anonymous_id) DO NOTHING
+        "#,
+        anonymous.user_id,
+        claims.user_id
+    )
+    .execute(&mut *tx)
+    .await?
+    .rows_affected()
+        > 0;
+
+    if !claimed {
+        tx.rollback().await?;
+        let previous = sqlx::query!(
+            "SELECT user_id, sessions_migrated FROM anonymous_claims WHERE anonymous_id = $1",
+            anonymous.user_id
+        )
+        .fetch_one(&state.db)
+        .await?;
+        if previous.user_id != claims.user_id {
+            return Err(AppError::Conflict(
+                "This anonymous session was claimed by another account".to_string(),
+            ));
+        }
+        return Ok(Json(ClaimAnonymousSessionResponse {
+            migrated: false,
+            sessions_migrated: previous.sessions_migrated as u32,
+            already_claimed: true,
+        }));
+    }
+
+    let registered_at =
+        sqlx::query_scalar!("SELECT created_at FROM users WHERE id = $1", claims.user_id)
+            .fetch_optional(&mut *tx)
+            .await?
+            .flatten()
+            .ok_or_else(|| AppError::NotFound("User not found".to_string()))?;
+    let window = chrono::Duration::minutes(state.config.anonymous_claim_window_minutes);
+    if chrono::Utc::now() - registered_at > window {
+        return Err(AppError::Authorization(format!(
+            "Anonymous sessions can only be claimed within {} minutes of registering",
+            state.config.anonymous_claim_window_minutes
+        )));
+    }
+
+    let now = chrono::Utc::now();
+    let mut sessions_migrated = 0;
+    for (session_id, started_at) in state.anonymous_trial_sessions(anonymous.user_id) {
+        sessions_migrated += sqlx::query!(
+            r#"
+            INSERT INTO coding_sessions (id, user_id, start_time)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (id) DO NOTHING
+            "#,
+            session_id,
+            claims.user_id,
+            started_at.unwrap_or(now)
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected() as u32;
+    }
+
+    sqlx::query!(
+        "UPDATE anonymous_claims SET sessions_migrated = $2 WHERE anonymous_id = $1",
+        anonymous.user_id,
+        sessions_migrated as i32
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    let migrated = state.migrate_anonymous_engine(anonymous.user_id, claims.user_id);
+    info!(
+        "User {} claimed anonymous session {} ({} sessions)",
+        claims.user_id, anonymous.user_id, sessions_migrated
+    );
+
+    Ok(Json(ClaimAnonymousSessionResponse {
+        migrated,
+        sessions_migrated,
+        already_claimed: false,
+    }))
+}
+
+/// Records a successful login from `client_ip` (see `utils::ClientIp`) and
+/// warns the user over WebSocket when it comes from somewhere new. High-security users' IPs aren't stored, only the coarse
+/// location.
+pub async fn record_login(
+    state: &AppState,
+    user_id: Uuid,
+    client_ip: Option<IpAddr>,
+) -> Result<LoginEvent> {
+    let high_security = is_high_security(state, user_id).await?;
+
+    let event = record_login_event(
+        &state.db,
+        &state.config.login_security,
+        state.geo_locator.as_ref(),
+        user_id,
+        client_ip,
+        !high_security,
+        chrono::Utc::now(),
+    )
+    .await?;
+
+    let place = event
+        .location
+        .as_ref()
+        .and_then(|location| location.city.clone().or_else(|| location.country.clone()))
+        .unwrap_or_else(|| "an unknown location".to_string());
+
+    if event.impossible_travel {
+        warn!("Impossible travel for user {}: login {} from {}", user_id, event.id, place);
+        // Error level, so focus mode doesn't hold it back
+        send_notification(
+            state,
+            user_id,
+            "Suspicious sign-in".to_string(),
+            format!(
+                "Your account was just signed into from {}, too far from your last sign-in to \
+                 have travelled. If this wasn't you, change your password.",
+                place
+            ),
+            NotificationLevel::Error,
+        )
+        .await;
+    } else if event.new_location {
+        send_notification(
+            state,
+            user_id,
+            "New sign-in location".to_string(),
+            format!("Your account was signed into from {}.", place),
+            NotificationLevel::Warning,
+        )
+        .await;
+    }
+
+    Ok(event)
+}
+
+/// Derives the user's WebSocket payload key from their password, their
+/// stored salt and the server secret, and holds it for sealing their
+/// notifications, when payload encryption is on and they are on a
+/// high-security level. Login calls this once the password has checked out
+/// and hands the key to the client. Returns the key when one is now held.
+pub async fn remember_payload_key(
+    state: &AppState,
+    user_id: Uuid,
+    password: &str,
+) -> Result<Option<Zeroizing<[u8; 32]>>> {
+    if !state.config.websocket_payload_encryption {
+        return Ok(None);
+    }
+    if !is_high_security(state, user_id).await? {
+        // They may have lowered their level since the last login
+        state.payload_keys.remove(user_id);
+        return Ok(None);
+    }
+
+    // The first sign-in stores a salt; concurrent ones agree on the same
+    let mut fresh_salt = [0u8; PAYLOAD_KEY_SALT_LEN];
+    OsRng.fill_bytes(&mut fresh_salt);
+    let salt = sqlx::query_scalar!(
+        r#"
+        UPDATE users SET payload_key_salt = COALESCE(payload_key_salt, $2)
+        WHERE id = $1
+        RETURNING payload_key_salt AS "payload_key_salt!"
+        "#,
+        user_id,
+        &fresh_salt[..]
+    )
+    .fetch_one(&state.db)
+    .await?;
+    let server_secret = decode_hex_key(&state.config.encryption_key)?;
+
+    // Argon2id is deliberately slow; keep it off the async workers
+    let password = Zeroizing::new(password.to_string());
+    let key = tokio::task::spawn_blocking(move || {
+        derive_payload_key(&password, &salt, server_secret.as_ref())
+    })
+    .await
+    .map_err(|e| AppError::Internal(format!("Payload key derivation failed: {}", e)))??;
+    state.payload_keys.insert(user_id, &key);
+
+    Ok(Some(key))
+}
+
+/// Whether the user is on the High or Military encryption level.
+pub async fn is_high_security(state: &AppState, user_id: Uuid) -> Result<bool> {
+    let high_security =
+        sqlx::query_scalar!("SELECT privacy_settings FROM users WHERE id = $1", user_id)
+            .fetch_optional(&state.db)
+            .await?
+            .flatten()
+            .and_then(|settings| serde_json::from_value::<PrivacySettings>(settings).ok())
+            .is_some_and(|settings| settings.is_high_security());
+
+    Ok(high_security)
+}
+
+/// Whether the user lets their data be aggregated into analytics. Users who
+/// never saved privacy settings have it on, as in the defaults.
+pub async fn analytics_enabled(db: &sqlx::PgPool, user_id: Uuid) -> Result<bool> {
+    let enabled = sqlx::query_scalar!("SELECT privacy_settings FROM users WHERE id = $1", user_id)
+        .fetch_optional(db)
+        .await?
+        .flatten()
+        .and_then(|settings| serde_json::from_value::<PrivacySettings>(settings).ok())
+        .map_or(true, |settings| settings.analytics_enabled);
+
+    Ok(enabled)
+}
@@ -0,0 +1,93 @@
+use axum::{
+    extract::{Query, State},
+    Json,
+};
+use tracing::warn;
+
+use crate::{
+    error::{AppError, Result},
+    handlers::flow::{get_flow_analytics, get_flow_insights, get_flow_patterns, FlowAnalyticsQuery},
+    models::{
+        dashboard::{DashboardResponse, DashboardSection},
+        team::TeamGoalProgress,
+    },
+    services::team_goals::load_member_goal_progress,
+    state::AppState,
+    utils::{
+        auth::{require_registered, Claims},
+        date_range::DateRangeQuery,
+        response::{ApiResponse, Cached, ResponseFormat},
+    },
+};
+
+/// Everything the dashboard shows, in one round trip. Sections load
+/// concurrently and each carries its own status, so the response is a
+/// success even when some of them are locked or failed.
+pub async fn get_dashboard(
+    State(state): State<AppState>,
+    claims: Claims,
+    response_format: ResponseFormat,
+    Query(range): Query<DateRangeQuery>,
+) -> Result<ApiResponse<DashboardResponse>> {
+    // Sections are gated by the same checks as their own endpoints
+    let (patterns, insights, analytics, goals) = tokio::join!(
+        get_flow_patterns(
+            State(state.clone()),
+            claims.clone(),
+            ResponseFormat::default(),
+            Query(range.clone()),
+        ),
+        get_flow_insights(State(state.clone()), claims.clone(), ResponseFormat::default()),
+        get_flow_analytics(
+            State(state.clone()),
+            claims.clone(),
+            ResponseFormat::default(),
+            Json(FlowAnalyticsQuery {
+                range,
+                min_data_quality: None,
+                environment: Default::default(),
+                granularity: Default::default(),
+            }),
+        ),
+        load_goals(&state, &claims),
+    );
+
+    Ok(response_format.respond(DashboardResponse {
+        patterns: section("patterns", patterns.and_then(fresh_data)),
+        insights: section("insights", insights.map(ApiResponse::into_data)),
+        analytics: section("analytics", analytics.and_then(fresh_data)),
+        goals: section("goals", goals),
+    }))
+}
+
+async fn load_goals(state: &AppState, claims: &Claims) -> Result<Vec<TeamGoalProgress>> {
+    require_registered(claims)?;
+
+    let mut conn = state.db.acquire().await?;
+    load_member_goal_progress(&mut conn, claims.user_id, chrono::Utc::now()).await
+}
+
+/// Sections are requested without `If-None-Match`, so they are never 304s.
+fn fresh_data<T>(response: Cached<T>) -> Result<T> {
+    response
+        .into_data()
+        .ok_or_else(|| AppError::Internal("Dashboard section was not modified".to_string()))
+}
+
+fn section<T>(name: &str, result: Result<T>) -> DashboardSection<T> {
+    match result {
+        Ok(data) => DashboardSection::Ok { data },
+        Err(AppError::Authorization(message)) => DashboardSection::Locked { message },
+        // Client errors carry a message meant for the user; anything else
+        // is logged rather than exposed
+        Err(AppError::Validation(message)) | Err(AppError::BadRequest(message)) => {
+            DashboardSection::Unavailable { message }
+        }
+        Err(e) => {
+            warn!("Dashboard section {} failed: {}", name, e);
+            DashboardSection::Unavailable {
+                message: format!("Could not load {}", name),
+            }
+        }
+    }
+}
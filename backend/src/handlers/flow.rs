@@ -1,15 +1,60 @@
-use axum::{extract::State, Json};
+use axum::{
+    extract::{Query, State},
+    http::header,
+    response::{IntoResponse, Response},
+    Json,
+};
 use serde::{Deserialize, Serialize};
 use tracing::{debug, instrument};
 use uuid::Uuid;
 use validator::Validate;
 
 use crate::{
+    config::FlowWriteRetryConfig,
     error::{AppError, Result},
-    models::flow::{FlowDetectionRequest, FlowStateResult, FlowPattern, FlowInsight, FlowAnalytics},
+    models::{
+        achievement::FlowAchievementsResponse,
+        audit::AuditOperation,
+        flow::{
+            FlowAnalytics, FlowBucket, FlowDetectionRequest, FlowDiffRequest, FlowDiffResponse,
+            FlowForecast, FlowForecastHour, FlowInsight, FlowPattern, FlowStateResult,
+            FocusModeRequest, FocusModeStatus, InterruptionEvent, InterruptionRequest, ServerMode,
+            SessionRecommendation, UserFlowPreferences,
+        },
+        session::SessionEnvironmentFilter,
+    },
+    handlers::{
+        auth::analytics_enabled,
+        websocket::{
+            publish_team_presence, record_focus_flow, send_break_reminder, send_focus_dip_notice,
+            set_focus_mode,
+        },
+    },
+    services::{
+        achievements::load_flow_achievements,
+        audit::record_audit_entry,
+        compression::encode_json_blob,
+        encryption::{ExportFormat, PrivacySettings},
+        export::{
+            export_flow_states_parquet, fetch_flow_state_page, FlowStateExportRow,
+            DEFAULT_ROW_GROUP_SIZE,
+        },
+        feature_flags::{EMA_SMOOTHING, FLOW_HYSTERESIS},
+        flow::{AnalysisStep, FlowBaseline, FlowDetectionEngine, ScoringFlags},
+        flow_diff::diff_sessions,
+        insights::{focus_dip_insight, insight_period, upsert_insight},
+        ml::{ProductivityPattern, ProductivityPredictor, TimeDecay},
+        telemetry::contribute_telemetry,
+        write_queue::retry_transient,
+    },
     state::AppState,
-    utils::auth::Claims,
+    utils::{
+        auth::{require_admin, require_premium, require_registered, Claims},
+        date_range::{DateRange, DateRangeQuery, Granularity},
+        response::{ApiResponse, CacheValidator, Cached, ResponseFormat},
+    },
 };
+use std::hash::Hash;
 
 #[derive(Debug, Deserialize, Validate)]
 pub struct FlowDetectionPayload {
@@ -17,64 +62,192 @@ pub struct FlowDetectionPayload {
     pub request: FlowDetectionRequest,
 }
 
+#[derive(Debug, Default, Deserialize)]
+pub struct DetectQuery {
+    /// Registry version to score with instead of the deployed model
+    /// (admins only)
+    pub model_version: Option<String>,
+    /// Reject malformed samples instead of scoring them neutrally; unset
+    /// uses the server default
+    pub strict: Option<bool>,
+}
+
 #[instrument(skip(state, claims))]
 pub async fn detect_flow_state(
     State(state): State<AppState>,
     claims: Claims,
+    response_format: ResponseFormat,
+    Query(query): Query<DetectQuery>,
     Json(payload): Json<FlowDetectionPayload>,
-) -> Result<Json<FlowStateResult>> {
+) -> Result<ApiResponse<FlowStateResult>> {
     // Validate input
     payload.validate().map_err(|e| {
         AppError::Validation(format!("Invalid flow detection request: {}", e))
     })?;
 
+    if state.config.require_model_ready && state.readiness.mode() == ServerMode::Initializing {
+        return Err(AppError::ServiceUnavailable(
+            "Flow model is still loading, retry shortly".to_string(),
+        ));
+    }
+
+    let _slot = state.try_acquire_flow_detect_slot(claims.user_id)?;
+
+    // Backtests score the sample on a reset engine from the pool, with the
+    // pinned model; nothing is persisted, broadcast or folded into the
+    // user's engine
+    if let Some(model_version) = query.model_version {
+        require_admin(&claims)?;
+        let ml_engine = state.model_registry.load(&model_version)?;
+        let mut engine = state.pooled_flow_engine();
+        engine.pin_model(ml_engine);
+        engine.set_strict_validation(query.strict);
+        let result = engine
+            .analyze_flow_state(payload.request.flow_data, payload.request.user_preferences)
+            .await?;
+        return Ok(response_format.respond(result));
+    }
+
     let user_id = claims.user_id;
     let flow_data = payload.request.flow_data;
-    let user_preferences = payload.request.user_preferences;
+    let session_id = flow_data.session_id;
+    let requested_preferences = payload.request.user_preferences;
 
-    // Get or create flow detection engine for this user
-    let flow_engine_arc = state.get_or_create_flow_engine(user_id);
-    let mut flow_engine = flow_engine_arc.write();
+    // Anonymous trial users get an in-memory engine and nothing is persisted
+    let persist = !claims.is_anonymous();
 
-    // Analyze flow state with ultra-low latency
-    let flow_result = flow_engine
-        .analyze_flow_state(flow_data.clone(), user_preferences)
-        .await?;
+    // Each of the user's sessions gets its own engine, so parallel windows
+    // don't merge their keystroke rhythms
+    let flow_engine_arc = if persist {
+        open_session_flow_engine(&state, user_id, session_id)
+            .await?
+            .ok_or_else(|| AppError::NotFound("Session not found".to_string()))?
+    } else {
+        state.get_or_create_flow_engine(user_id, session_id)
+    };
+    let (step, break_reminders_enabled) = {
+        let mut flow_engine = flow_engine_arc.write();
+        flow_engine.set_scoring_flags(ScoringFlags {
+            hysteresis: state.feature_flags.is_enabled(FLOW_HYSTERESIS, &claims),
+            ema_smoothing: state.feature_flags.is_enabled(EMA_SMOOTHING, &claims),
+        });
+        flow_engine.set_strict_validation(query.strict);
+
+        // A retried request gets its original result and isn't persisted or
+        // broadcast again. Only a retry landing while the original waits on
+        // its prediction, at most ML_BATCH_MAX_WAIT_MS, slips past
+        if let Some(cached) = flow_engine.cached_result(&flow_data)? {
+            debug!(
+                "Duplicate flow detection for session {} at {}, returning cached result",
+                flow_data.session_id, flow_data.timestamp
+            );
+            return Ok(response_format.respond(with_server_mode(&state, cached)));
+        }
+
+        let user_preferences = requested_preferences
+            .or_else(|| flow_engine.stored_preferences().cloned())
+            .unwrap_or_else(|| {
+                state
+                    .config
+                    .flow_engine
+                    .default_preferences(claims.subscription_tier)
+            });
+        let break_reminders_enabled = user_preferences.break_reminders_enabled;
+
+        (
+            flow_engine.begin_analysis(flow_data.clone(), Some(user_preferences))?,
+            break_reminders_enabled,
+        )
+    };
+
+    // The engine lock isn't held while the prediction waits for its batch,
+    // so the request doesn't pin it, or a worker thread, across the await
+    let prediction = match &step {
+        AnalysisStep::Done(_) => None,
+        AnalysisStep::Score(pending) => pending.predict().await,
+    };
+
+    let break_reminder_after_minutes = state.config.focus_mode.break_reminder_after_minutes;
+    let (flow_result, break_reminder_due, focus_dip_due, baseline, keystroke_hash) = {
+        let mut flow_engine = flow_engine_arc.write();
+        let flow_result = match step {
+            AnalysisStep::Done(result) => result,
+            AnalysisStep::Score(pending) => flow_engine.finish_analysis(pending, prediction),
+        };
+        let flow_result = with_server_mode(&state, flow_result);
+        let break_reminder_due = break_reminders_enabled
+            && flow_engine.break_reminder_due(std::time::Duration::from_secs(
+                break_reminder_after_minutes * 60,
+            ));
+        let focus_dip_due = !flow_result.insufficient_data && flow_engine.focus_dip_due();
+        let baseline = flow_engine.baseline();
+        let keystroke_hash = flow_engine.keystroke_hash(&flow_data);
+        (
+            flow_result,
+            break_reminder_due,
+            focus_dip_due,
+            baseline,
+            keystroke_hash,
+        )
+    };
 
     // Update session activity
     state.update_session_activity(flow_data.session_id);
 
-    // Store flow state in database (async, non-blocking)
-    let db = state.db.clone();
-    let session_id = flow_data.session_id;
-    let flow_result_clone = flow_result.clone();
-    
-    tokio::spawn(async move {
-        let result = sqlx::query!(
-            r#"
-            INSERT INTO flow_states (
-                session_id, start_time, intensity_score, typing_rhythm_data,
-                context_switches, ml_features, confidence_score
-            ) VALUES ($1, NOW(), $2, $3, $4, $5, $6)
-            "#,
+    // Store flow state in database (async, non-blocking), sampled so
+    // frequent analyses don't write a row per call. Writes go through the
+    // bounded queue so bursts wait for a slot instead of draining the pool,
+    // and are logged to the WAL first when one is configured. Unscored
+    // samples carry nothing worth storing
+    if persist
+        && !flow_result.insufficient_data
+        && state
+            .flow_sampler
+            .should_persist(session_id, flow_result.is_in_flow)
+    {
+        let write = PendingFlowWrite {
+            write_id: Uuid::new_v4(),
+            user_id,
             session_id,
-            flow_result_clone.flow_intensity as f64,
-            serde_json::to_value(&flow_result_clone.metrics).unwrap_or_default(),
-            flow_result_clone.metrics.focus_score as i32,
-            serde_json::json!({
-                "rhythm_score": flow_result_clone.metrics.rhythm_score,
-                "focus_score": flow_result_clone.metrics.focus_score,
-                "consistency_score": flow_result_clone.metrics.consistency_score,
-                "velocity_score": flow_result_clone.metrics.velocity_score,
-                "error_penalty": flow_result_clone.metrics.error_penalty
-            }),
-            flow_result_clone.confidence as f64,
-        ).execute(&db).await;
+            result: flow_result.clone(),
+            keystroke_hash,
+            focus_mode: state.focus_modes.is_active(user_id),
+            baseline_samples: flow_engine_arc.write().take_unsaved_baseline(),
+        };
+        // Only a logged write is completed in the WAL once stored
+        let wal = match state.flow_wal.clone() {
+            Some(wal) => match wal.append(write.write_id, &write).await {
+                Ok(()) => Some(wal),
+                Err(e) => {
+                    tracing::warn!("Flow state {} not logged to WAL: {}", write.write_id, e);
+                    None
+                }
+            },
+            None => None,
+        };
 
-        if let Err(e) = result {
-            tracing::error!("Failed to store flow state: {}", e);
-        }
-    });
+        let db = state.db.clone();
+        let compress_blobs = state.config.flow_blob_compression;
+        let retry = state.config.flow_write_retry.clone();
+        state.flow_writes.spawn(async move {
+            // A write that couldn't even be dead-lettered stays in the WAL
+            // and is tried again on the next start
+            match store_flow_write(&db, &write, compress_blobs, &retry).await {
+                Ok(_) => {
+                    if let Some(wal) = wal {
+                        if let Err(e) = wal.complete(write.write_id).await {
+                            tracing::warn!(
+                                "Failed to complete WAL entry {}: {}",
+                                write.write_id,
+                                e
+                            );
+                        }
+                    }
+                }
+                Err(e) => tracing::error!("Failed to store flow state: {}", e),
+            }
+        });
+    }
 
     // Send real-time update via WebSocket
     let websocket_message = serde_json::json!({
@@ -84,36 +257,742 @@ pub async fn detect_flow_state(
     }).to_string();
 
     state.broadcast_to_user(user_id, websocket_message).await;
+    if persist {
+        publish_team_presence(&state, user_id, &flow_result).await;
+    }
+
+    // Focus mode holds break reminders until the flow stretch ends
+    record_focus_flow(&state, user_id, flow_result.is_in_flow).await;
+    if break_reminder_due {
+        send_break_reminder(&state, user_id, break_reminder_after_minutes).await;
+    }
+    if focus_dip_due {
+        report_focus_dip(&state, user_id, baseline, &flow_result, persist).await;
+        if break_reminders_enabled {
+            send_focus_dip_notice(&state, user_id).await;
+        }
+    }
 
     debug!(
         "Flow state detected for user {}: intensity={:.3}, in_flow={}",
         user_id, flow_result.flow_intensity, flow_result.is_in_flow
     );
 
-    Ok(Json(flow_result))
+    Ok(response_format.respond(flow_result))
+}
+
+/// Merges `samples` into the user's stored baseline. The row is locked
+/// while it's read and written back, so concurrent sessions of the same user
+/// each add their samples instead of the last one overwriting the rest.
+async fn merge_baseline(
+    conn: &mut sqlx::PgConnection,
+    user_id: Uuid,
+    samples: &FlowBaseline,
+) -> Result<()> {
+    sqlx::query!(
+        "INSERT INTO user_flow_baselines (user_id) VALUES ($1) ON CONFLICT (user_id) DO NOTHING",
+        user_id
+    )
+    .execute(&mut *conn)
+    .await?;
+    let stored = sqlx::query!(
+        r#"
+        SELECT sample_count, mean_intensity, intensity_variance
+        FROM user_flow_baselines
+        WHERE user_id = $1
+        FOR UPDATE
+        "#,
+        user_id
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+
+    let mut baseline = FlowBaseline {
+        sample_count: stored.sample_count as u64,
+        mean: stored.mean_intensity,
+        variance: stored.intensity_variance,
+    };
+    baseline.merge(samples);
+
+    sqlx::query!(
+        r#"
+        UPDATE user_flow_baselines
+        SET sample_count = $2, mean_intensity = $3, intensity_variance = $4, updated_at = NOW()
+        WHERE user_id = $1
+        "#,
+        user_id,
+        baseline.sample_count as i64,
+        baseline.mean,
+        baseline.variance,
+    )
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+/// Records a sustained focus dip as an insight, off the detection path.
+async fn report_focus_dip(
+    state: &AppState,
+    user_id: Uuid,
+    baseline: FlowBaseline,
+    result: &FlowStateResult,
+    persist: bool,
+) {
+    if !persist {
+        return;
+    }
+
+    let minutes = (state.config.flow_engine.intensity_drop_window_secs / 60).max(1);
+    let insight = focus_dip_insight(&baseline, result.flow_intensity, minutes);
+    let db = state.db.clone();
+    state.flow_writes.spawn(async move {
+        let now = chrono::Utc::now();
+        let stored = async {
+            // Insights are analytics, so users who opted out don't get them
+            if !analytics_enabled(&db, user_id).await? {
+                return Ok(());
+            }
+            let mut conn = db.acquire().await?;
+            upsert_insight(&mut conn, user_id, insight_period(now), &insight, now).await
+        };
+        if let Err(e) = stored.await {
+            tracing::warn!("Failed to store focus dip insight: {}", e);
+        }
+    });
+}
+
+/// Marks results served before the model has warmed up, so clients know
+/// early scores may be rule-based.
+fn with_server_mode(state: &AppState, mut result: FlowStateResult) -> FlowStateResult {
+    if state.readiness.mode() == ServerMode::Initializing {
+        result.server_mode = Some(ServerMode::Initializing);
+    }
+    result
+}
+
+/// A flow state waiting to be stored: queued by detection and, with a WAL
+/// configured, logged until it is.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingFlowWrite {
+    /// Stored on the row, so replaying a write that already landed is a
+    /// no-op
+    pub write_id: Uuid,
+    pub user_id: Uuid,
+    pub session_id: Uuid,
+    pub result: FlowStateResult,
+    /// Only stored for high-security users
+    pub keystroke_hash: String,
+    pub focus_mode: bool,
+    /// The session's samples since its last write, merged into the user's
+    /// stored baseline
+    #[serde(default)]
+    pub baseline_samples: FlowBaseline,
+}
+
+/// Stores a detected flow state and merges the session's new samples into
+/// the user's baseline.
+pub async fn persist_flow_write(
+    db: &sqlx::PgPool,
+    write: &PendingFlowWrite,
+    compress_blobs: bool,
+) -> Result<()> {
+    let privacy = sqlx::query_scalar!(
+        "SELECT privacy_settings FROM users WHERE id = $1",
+        write.user_id
+    )
+    .fetch_optional(db)
+    .await?
+    .flatten()
+    .and_then(|settings| serde_json::from_value::<PrivacySettings>(settings).ok());
+    let high_security = privacy
+        .as_ref()
+        .is_some_and(|settings| settings.is_high_security());
+    let analytics = privacy
+        .as_ref()
+        .map_or(true, |settings| settings.analytics_enabled);
+
+    let mut row = FlowStateRow::new(
+        &write.result,
+        high_security.then(|| write.keystroke_hash.clone()),
+    );
+    row.focus_mode = write.focus_mode;
+    if !analytics {
+        // Session summaries only need the intensity and timing; the
+        // feature breakdown is kept for analytics alone
+        row.typing_rhythm_data = None;
+        row.ml_features = None;
+    }
+    if compress_blobs {
+        // Falls back to plain JSONB rather than dropping the row
+        if let Err(e) = row.compress() {
+            tracing::warn!("Failed to compress flow state blobs: {}", e);
+        }
+    }
+
+    // The row and the baseline land together, so a replay merges neither twice
+    let mut tx = db.begin().await?;
+    let inserted = sqlx::query!(
+        r#"
+        INSERT INTO flow_states (
+            session_id, start_time, intensity_score, typing_rhythm_data,
+            context_switches, ml_features, confidence_score, data_quality,
+            keystroke_hash, model_version, typing_rhythm_blob, ml_features_blob,
+            focus_mode, write_id, is_in_flow
+        ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15)
+        ON CONFLICT (write_id) DO NOTHING
+        "#,
+        write.session_id,
+        row.start_time,
+        row.intensity_score,
+        row.typing_rhythm_data,
+        row.context_switches,
+        row.ml_features,
+        row.confidence_score,
+        row.data_quality,
+        row.keystroke_hash,
+        row.model_version,
+        row.typing_rhythm_blob,
+        row.ml_features_blob,
+        row.focus_mode,
+        write.write_id,
+        row.is_in_flow,
+    )
+    .execute(&mut *tx)
+    .await?
+    .rows_affected()
+        > 0;
+
+    if inserted && write.baseline_samples.sample_count > 0 {
+        merge_baseline(&mut *tx, write.user_id, &write.baseline_samples).await?;
+    }
+    tx.commit().await?;
+
+    // A replayed write that had already landed was counted the first time.
+    // The corpus is best effort and never holds up the user's own data
+    if inserted && privacy.is_some_and(|settings| settings.telemetry_allowed()) {
+        let contributed = async {
+            let mut tx = db.begin().await?;
+            contribute_telemetry(&mut tx, write.user_id, &write.result).await?;
+            tx.commit().await?;
+            Ok::<_, AppError>(())
+        };
+        if let Err(e) = contributed.await {
+            tracing::warn!("Failed to contribute telemetry: {}", e);
+        }
+    }
+
+    Ok(())
+}
+
+/// What became of a queued flow write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlowWriteOutcome {
+    Stored {
+        attempts: u32,
+    },
+    /// Failed for good and kept in `flow_write_dead_letters` instead
+    DeadLettered {
+        attempts: u32,
+    },
+}
+
+/// Stores a queued flow write, retrying transient database errors with
+/// backoff. A write that still fails is dead-lettered; an error means even
+/// that failed.
+pub async fn store_flow_write(
+    db: &sqlx::PgPool,
+    write: &PendingFlowWrite,
+    compress_blobs: bool,
+    retry: &FlowWriteRetryConfig,
+) -> Result<FlowWriteOutcome> {
+    store_flow_write_with(db, write, retry, || {
+        persist_flow_write(db, write, compress_blobs)
+    })
+    .await
+}
+
+/// `store_flow_write` with the insert itself supplied by the caller.
+pub async fn store_flow_write_with<F, Fut>(
+    db: &sqlx::PgPool,
+    write: &PendingFlowWrite,
+    retry: &FlowWriteRetryConfig,
+    persist: F,
+) -> Result<FlowWriteOutcome>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<()>>,
+{
+    let (stored, attempts) = retry_transient(retry, persist).await;
+    let Err(e) = stored else {
+        return Ok(FlowWriteOutcome::Stored { attempts });
+    };
+
+    tracing::error!(
+        "Dead-lettering flow write {} after {} attempt(s): {}",
+        write.write_id,
+        attempts,
+        e
+    );
+    dead_letter_flow_write(db, write, &e, attempts).await?;
+    Ok(FlowWriteOutcome::DeadLettered { attempts })
+}
+
+/// Keeps a write that failed for good, with why, for investigation. A write
+/// dead-lettered again replaces its earlier entry.
+async fn dead_letter_flow_write(
+    db: &sqlx::PgPool,
+    write: &PendingFlowWrite,
+    error: &AppError,
+    attempts: u32,
+) -> Result<()> {
+    let payload = serde_json::to_value(write)
+        .map_err(|e| AppError::Internal(format!("Failed to serialize flow write: {}", e)))?;
+
+    sqlx::query!(
+        r#"
+        INSERT INTO flow_write_dead_letters (write_id, user_id, session_id, payload, error, attempts)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        ON CONFLICT (write_id) DO UPDATE SET
+            error = EXCLUDED.error,
+            attempts = EXCLUDED.attempts,
+            failed_at = NOW()
+        "#,
+        write.write_id,
+        write.user_id,
+        write.session_id,
+        payload,
+        error.to_string(),
+        attempts as i32,
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// Stores the flow writes a previous process logged but never finished.
+/// Run at startup, before detection starts adding new ones. A write that
+/// fails again is dead-lettered, or dropped if even that fails, so it can't
+/// block every later start. Returns the number stored.
+pub async fn replay_pending_flow_writes(state: &AppState) -> Result<usize> {
+    let Some(wal) = &state.flow_wal else {
+        return Ok(0);
+    };
+
+    let mut replayed = 0;
+    for (id, write) in wal.pending::<PendingFlowWrite>()? {
+        let stored = store_flow_write(
+            &state.db,
+            &write,
+            state.config.flow_blob_compression,
+            &state.config.flow_write_retry,
+        )
+        .await;
+        match stored {
+            Ok(FlowWriteOutcome::Stored { .. }) => replayed += 1,
+            Ok(FlowWriteOutcome::DeadLettered { .. }) => {}
+            Err(e) => tracing::error!("Dropping flow write {} after failed replay: {}", id, e),
+        }
+        wal.complete(id).await?;
+    }
+    Ok(replayed)
+}
+
+/// Column values for one persisted `flow_states` row. Keystroke timings
+/// are never among them; high-security users get a salted hash of them
+/// instead, for later duplicate and tamper checks.
+///
+/// The rhythm and feature JSON lives either in the JSONB columns or, once
+/// compressed, in the matching `_blob` columns; never both.
+#[derive(Debug, Serialize)]
+pub struct FlowStateRow {
+    pub start_time: chrono::DateTime<chrono::Utc>,
+    pub intensity_score: f64,
+    pub typing_rhythm_data: Option<serde_json::Value>,
+    pub typing_rhythm_blob: Option<Vec<u8>>,
+    pub context_switches: i32,
+    pub ml_features: Option<serde_json::Value>,
+    pub ml_features_blob: Option<Vec<u8>>,
+    pub confidence_score: f64,
+    pub data_quality: f64,
+    pub keystroke_hash: Option<String>,
+    pub model_version: String,
+    /// The user had focus mode on
+    pub focus_mode: bool,
+    pub is_in_flow: bool,
+}
+
+impl FlowStateRow {
+    pub fn new(result: &FlowStateResult, keystroke_hash: Option<String>) -> Self {
+        Self {
+            // Ordered by sample time; the engine already fell back to receive
+            // time for implausible client clocks
+            start_time: chrono::DateTime::from_timestamp_millis(result.sample_timestamp)
+                .unwrap_or_else(chrono::Utc::now),
+            intensity_score: result.flow_intensity as f64,
+            typing_rhythm_data: Some(serde_json::to_value(&result.metrics).unwrap_or_default()),
+            typing_rhythm_blob: None,
+            context_switches: result.metrics.focus_score as i32,
+            ml_features: Some(serde_json::json!({
+                "rhythm_score": result.metrics.rhythm_score,
+                "focus_score": result.metrics.focus_score,
+                "consistency_score": result.metrics.consistency_score,
+                "velocity_score": result.metrics.velocity_score,
+                "error_penalty": result.metrics.error_penalty
+            })),
+            ml_features_blob: None,
+            confidence_score: result.confidence as f64,
+            data_quality: result.data_quality as f64,
+            keystroke_hash,
+            model_version: result.model_version.clone(),
+            focus_mode: false,
+            is_in_flow: result.is_in_flow,
+        }
+    }
+
+    /// Moves the rhythm and feature JSON into compressed blobs. On error
+    /// the row is left as it was.
+    pub fn compress(&mut self) -> Result<()> {
+        let encode = |value: &Option<serde_json::Value>| {
+            value.as_ref().map(encode_json_blob).transpose()
+        };
+        let typing_rhythm_blob = encode(&self.typing_rhythm_data)?;
+        let ml_features_blob = encode(&self.ml_features)?;
+
+        if typing_rhythm_blob.is_some() {
+            self.typing_rhythm_data = None;
+            self.typing_rhythm_blob = typing_rhythm_blob;
+        }
+        if ml_features_blob.is_some() {
+            self.ml_features = None;
+            self.ml_features_blob = ml_features_blob;
+        }
+        Ok(())
+    }
+}
+
+/// The user's saved preferences, or the defaults for their tier if they
+/// haven't saved any.
+pub async fn get_flow_preferences(
+    State(state): State<AppState>,
+    claims: Claims,
+    response_format: ResponseFormat,
+) -> Result<ApiResponse<UserFlowPreferences>> {
+    require_registered(&claims)?;
+
+    let preferences = load_flow_preferences(&state.db, claims.user_id)
+        .await?
+        .unwrap_or_else(|| {
+            state
+                .config
+                .flow_engine
+                .default_preferences(claims.subscription_tier)
+        });
+
+    Ok(response_format.respond(preferences))
+}
+
+pub async fn update_flow_preferences(
+    State(state): State<AppState>,
+    claims: Claims,
+    response_format: ResponseFormat,
+    Json(preferences): Json<UserFlowPreferences>,
+) -> Result<ApiResponse<UserFlowPreferences>> {
+    require_registered(&claims)?;
+    preferences.validate().map_err(|e| {
+        AppError::Validation(format!("Invalid flow preferences: {}", e))
+    })?;
+
+    store_flow_preferences(&state, claims.user_id, &preferences).await?;
+    if preferences.focus_mode_enabled != state.focus_modes.is_active(claims.user_id) {
+        set_focus_mode(&state, claims.user_id, preferences.focus_mode_enabled).await;
+    }
+
+    Ok(response_format.respond(preferences))
+}
+
+/// Whether focus mode is on, and since when. Only reads: a period lost to
+/// a restart shows as enabled without a start until detection resumes it.
+pub async fn get_focus_mode(
+    State(state): State<AppState>,
+    claims: Claims,
+    response_format: ResponseFormat,
+) -> Result<ApiResponse<FocusModeStatus>> {
+    require_registered(&claims)?;
+
+    let started_at = state.focus_modes.started_at(claims.user_id);
+    // After a restart, focus mode is only in the saved preferences
+    let enabled = started_at.is_some()
+        || load_flow_preferences(&state.db, claims.user_id)
+            .await?
+            .is_some_and(|preferences| preferences.focus_mode_enabled);
+
+    Ok(response_format.respond(FocusModeStatus { enabled, started_at }))
+}
+
+/// Turns focus mode on or off. The choice is saved with the user's flow
+/// preferences and broadcast to their connected clients.
+pub async fn update_focus_mode(
+    State(state): State<AppState>,
+    claims: Claims,
+    response_format: ResponseFormat,
+    Json(request): Json<FocusModeRequest>,
+) -> Result<ApiResponse<FocusModeStatus>> {
+    require_registered(&claims)?;
+
+    let mut preferences = load_flow_preferences(&state.db, claims.user_id)
+        .await?
+        .unwrap_or_else(|| {
+            state
+                .config
+                .flow_engine
+                .default_preferences(claims.subscription_tier)
+        });
+    preferences.focus_mode_enabled = request.enabled;
+    store_flow_preferences(&state, claims.user_id, &preferences).await?;
+
+    let status = set_focus_mode(&state, claims.user_id, request.enabled).await;
+    Ok(response_format.respond(status))
+}
+
+async fn store_flow_preferences(
+    state: &AppState,
+    user_id: Uuid,
+    preferences: &UserFlowPreferences,
+) -> Result<()> {
+    sqlx::query!(
+        r#"
+        INSERT INTO user_flow_preferences (
+            user_id, sensitivity_level, notification_threshold, focus_mode_enabled,
+            break_reminders_enabled, personalized_calibration, use_ml
+        ) VALUES ($1, $2, $3, $4, $5, $6, $7)
+        ON CONFLICT (user_id) DO UPDATE SET
+            sensitivity_level = EXCLUDED.sensitivity_level,
+            notification_threshold = EXCLUDED.notification_threshold,
+            focus_mode_enabled = EXCLUDED.focus_mode_enabled,
+            break_reminders_enabled = EXCLUDED.break_reminders_enabled,
+            personalized_calibration = EXCLUDED.personalized_calibration,
+            use_ml = EXCLUDED.use_ml,
+            updated_at = NOW()
+        "#,
+        user_id,
+        preferences.sensitivity_level as f64,
+        preferences.notification_threshold as f64,
+        preferences.focus_mode_enabled,
+        preferences.break_reminders_enabled,
+        preferences.personalized_calibration,
+        preferences.use_ml,
+    )
+    .execute(&state.db)
+    .await?;
+
+    // Live engines pick the change up on their next analysis
+    for engine in state.user_flow_engines(user_id) {
+        engine.write().set_stored_preferences(Some(preferences.clone()));
+    }
+
+    Ok(())
+}
+
+async fn load_flow_preferences(
+    db: &sqlx::PgPool,
+    user_id: Uuid,
+) -> Result<Option<UserFlowPreferences>> {
+    let row = sqlx::query!(
+        r#"
+        SELECT sensitivity_level, notification_threshold, focus_mode_enabled,
+               break_reminders_enabled, personalized_calibration, use_ml
+        FROM user_flow_preferences
+        WHERE user_id = $1
+        "#,
+        user_id
+    )
+    .fetch_optional(db)
+    .await?;
+
+    Ok(row.map(|row| UserFlowPreferences {
+        sensitivity_level: row.sensitivity_level as f32,
+        notification_threshold: row.notification_threshold as f32,
+        focus_mode_enabled: row.focus_mode_enabled,
+        break_reminders_enabled: row.break_reminders_enabled,
+        personalized_calibration: row.personalized_calibration,
+        use_ml: row.use_ml,
+    }))
+}
+
+/// The engine for one of the user's open sessions, created and seeded with
+/// their persisted baseline and standing preferences if it isn't live yet.
+/// `None` when the session isn't one of their open sessions, so made-up
+/// session ids can't grow the engine map. Registered users only.
+async fn open_session_flow_engine(
+    state: &AppState,
+    user_id: Uuid,
+    session_id: Uuid,
+) -> Result<Option<std::sync::Arc<parking_lot::RwLock<FlowDetectionEngine>>>> {
+    if let Some(engine) = state.flow_engines.get(&(user_id, session_id)) {
+        return Ok(Some(engine.clone()));
+    }
+
+    let session_open = sqlx::query_scalar!(
+        r#"
+        SELECT EXISTS (
+            SELECT 1 FROM coding_sessions
+            WHERE id = $1 AND user_id = $2 AND end_time IS NULL
+        ) AS "open!"
+        "#,
+        session_id,
+        user_id
+    )
+    .fetch_one(&state.db)
+    .await?;
+    if !session_open {
+        return Ok(None);
+    }
+
+    let stored_baseline = sqlx::query!(
+        r#"
+        SELECT sample_count, mean_intensity, intensity_variance
+        FROM user_flow_baselines
+        WHERE user_id = $1
+        "#,
+        user_id
+    )
+    .fetch_optional(&state.db)
+    .await?;
+    let stored_preferences = load_flow_preferences(&state.db, user_id).await?;
+    // Focus mode outlives restarts through the saved preference
+    if stored_preferences
+        .as_ref()
+        .is_some_and(|p| p.focus_mode_enabled)
+    {
+        state.focus_modes.enable(user_id, chrono::Utc::now());
+    }
+
+    let engine_arc = state.get_or_create_flow_engine(user_id, session_id);
+    {
+        let mut engine = engine_arc.write();
+        if let Some(row) = stored_baseline {
+            engine.restore_baseline(FlowBaseline {
+                sample_count: row.sample_count as u64,
+                mean: row.mean_intensity,
+                variance: row.intensity_variance,
+            });
+        }
+        engine.set_stored_preferences(stored_preferences);
+    }
+    Ok(Some(engine_arc))
+}
+
+pub async fn record_interruption(
+    State(state): State<AppState>,
+    claims: Claims,
+    response_format: ResponseFormat,
+    Json(payload): Json<InterruptionRequest>,
+) -> Result<ApiResponse<InterruptionEvent>> {
+    require_registered(&claims)?;
+    payload.validate().map_err(|e| {
+        AppError::Validation(format!("Invalid interruption: {}", e))
+    })?;
+
+    let user_id = claims.user_id;
+
+    let event = sqlx::query!(
+        r#"
+        INSERT INTO flow_interruptions (session_id, interruption_type, duration_ms)
+        SELECT id, $3, $4
+        FROM coding_sessions
+        WHERE id = $1 AND user_id = $2
+        RETURNING id, occurred_at
+        "#,
+        payload.session_id,
+        user_id,
+        payload.interruption_type.as_str(),
+        payload.duration_ms as i64,
+    )
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or_else(|| AppError::NotFound("Session not found".to_string()))?;
+
+    // Dampen the next analyses while the user recovers their focus. The
+    // engine is seeded here if the interruption comes before any sample;
+    // an ended session has none left to dampen
+    if let Some(engine) = open_session_flow_engine(&state, user_id, payload.session_id).await? {
+        engine.write().record_interruption();
+    }
+
+    debug!(
+        "Interruption '{}' recorded for user {} in session {}",
+        payload.interruption_type.as_str(),
+        user_id,
+        payload.session_id
+    );
+
+    Ok(response_format.respond(InterruptionEvent {
+        id: event.id,
+        session_id: payload.session_id,
+        interruption_type: payload.interruption_type,
+        duration_ms: payload.duration_ms,
+        occurred_at: event.occurred_at,
+    }))
 }
 
 pub async fn get_flow_patterns(
     State(state): State<AppState>,
     claims: Claims,
-) -> Result<Json<FlowPattern>> {
+    response_format: ResponseFormat,
+    Query(range_query): Query<DateRangeQuery>,
+) -> Result<Cached<FlowPattern>> {
+    // Historical analysis is a premium feature; real-time detection stays free
+    require_premium(&claims)?;
+
     let user_id = claims.user_id;
+    if !analytics_enabled(&state.db, user_id).await? {
+        return Ok(Cached::Fresh(
+            response_format.respond(FlowPattern::opted_out(user_id)),
+        ));
+    }
+    let range = range_query.resolve(chrono::Utc::now(), 30)?;
+    let validator = analytics_validator(
+        &state,
+        &response_format,
+        user_id,
+        range,
+        ("flow_patterns", &range_query),
+    )
+    .await?;
+    if response_format.has_current(&validator) {
+        return Ok(Cached::NotModified(validator));
+    }
+    // Sessions are weighted by 0.5^(age / half-life), aged from the end of
+    // the range; a zero half-life weights them all equally
+    let half_life_days = state.config.pattern_half_life_days;
 
     // Query flow patterns from the database
     let patterns = sqlx::query!(
         r#"
         SELECT 
-            AVG(cs.total_duration_ms) as avg_session_length,
+            (SUM(cs.total_duration_ms * w.weight) / NULLIF(SUM(w.weight), 0))::FLOAT8
+                as avg_session_length,
             AVG(fs.intensity_score) as avg_flow_intensity,
             json_agg(DISTINCT cs.language_breakdown) as languages
         FROM coding_sessions cs
+        CROSS JOIN LATERAL (
+            SELECT CASE WHEN $4::FLOAT8 > 0
+                THEN POWER(0.5, EXTRACT(EPOCH FROM ($3 - cs.start_time)) / 86400.0 / $4::FLOAT8)
+                ELSE 1.0
+            END::FLOAT8 as weight
+        ) w
         LEFT JOIN flow_states fs ON cs.id = fs.session_id
         WHERE cs.user_id = $1 
           AND cs.end_time IS NOT NULL
-          AND cs.created_at >= NOW() - INTERVAL '30 days'
+          AND cs.created_at >= $2
+          AND cs.created_at < $3
         "#,
-        user_id
-    ).fetch_optional(&state.db).await?;
+        user_id,
+        range.from,
+        range.to,
+        half_life_days
+    ).fetch_optional(state.read_db()).await?;
 
     let flow_pattern = if let Some(row) = patterns {
         // Analyze peak hours from session data
@@ -121,17 +1000,27 @@ pub async fn get_flow_patterns(
             r#"
             SELECT 
                 EXTRACT(HOUR FROM start_time) as hour,
-                AVG(focus_score) as avg_focus,
-                COUNT(*) as session_count
+                SUM(focus_score::FLOAT8 * w.weight) / NULLIF(SUM(w.weight), 0) as avg_focus,
+                SUM(w.weight) as session_weight
             FROM coding_sessions
+            CROSS JOIN LATERAL (
+                SELECT CASE WHEN $4::FLOAT8 > 0
+                    THEN POWER(0.5, EXTRACT(EPOCH FROM ($3 - start_time)) / 86400.0 / $4::FLOAT8)
+                    ELSE 1.0
+                END::FLOAT8 as weight
+            ) w
             WHERE user_id = $1 
-              AND created_at >= NOW() - INTERVAL '30 days'
+              AND created_at >= $2
+              AND created_at < $3
             GROUP BY EXTRACT(HOUR FROM start_time)
-            ORDER BY avg_focus DESC, session_count DESC
+            ORDER BY avg_focus DESC NULLS LAST, session_weight DESC
             LIMIT 3
             "#,
-            user_id
-        ).fetch_all(&state.db).await?;
+            user_id,
+            range.from,
+            range.to,
+            half_life_days
+        ).fetch_all(state.read_db()).await?;
 
         let peak_hours: Vec<u8> = peak_hours_query
             .into_iter()
@@ -154,7 +1043,7 @@ pub async fn get_flow_patterns(
 
         FlowPattern {
             user_id,
-            optimal_session_length: row.avg_session_length.unwrap_or(0) as u64,
+            optimal_session_length: row.avg_session_length.unwrap_or(0.0) as u64,
             peak_hours,
             average_flow_intensity: row.avg_flow_intensity.unwrap_or(0.0) as f32,
             flow_triggers: vec![
@@ -168,6 +1057,7 @@ pub async fn get_flow_patterns(
                 "preferred_session_length": row.avg_session_length,
                 "optimal_break_frequency": 25
             }),
+            opted_out: false,
         }
     } else {
         // Default pattern for new users
@@ -180,35 +1070,65 @@ pub async fn get_flow_patterns(
             interruption_tolerance: 0.5,
             best_languages: vec!["javascript".to_string(), "typescript".to_string()],
             environmental_factors: serde_json::json!({}),
+            opted_out: false,
         }
     };
 
-    Ok(Json(flow_pattern))
+    Ok(response_format.respond_cached(flow_pattern, validator))
+}
+
+/// Compares the caller's sessions from before and after a change, such as
+/// trying a new tool, metric by metric.
+pub async fn diff_flow_sessions(
+    State(state): State<AppState>,
+    claims: Claims,
+    response_format: ResponseFormat,
+    Json(payload): Json<FlowDiffRequest>,
+) -> Result<ApiResponse<FlowDiffResponse>> {
+    require_registered(&claims)?;
+    payload
+        .validate()
+        .map_err(|e| AppError::Validation(format!("Invalid flow diff request: {}", e)))?;
+
+    let mut conn = state.read_db().acquire().await?;
+    let diff = diff_sessions(&mut conn, claims.user_id, &payload.before, &payload.after).await?;
+
+    Ok(response_format.respond(diff))
 }
 
 pub async fn get_flow_insights(
     State(state): State<AppState>,
     claims: Claims,
-) -> Result<Json<Vec<FlowInsight>>> {
+    response_format: ResponseFormat,
+) -> Result<ApiResponse<Vec<FlowInsight>>> {
+    require_premium(&claims)?;
+
     let user_id = claims.user_id;
+    if !analytics_enabled(&state.db, user_id).await? {
+        return Ok(response_format.respond(Vec::new()));
+    }
 
-    // Query recent insights
+    // Query current insights. The expiry sweep deactivates stale ones; the
+    // window here also covers those it hasn't reached yet
     let insights_data = sqlx::query!(
         r#"
         SELECT 
             insight_type,
             insight_data,
             confidence_score,
+            date_range_start,
+            date_range_end,
             created_at
         FROM user_insights
         WHERE user_id = $1 
           AND is_active = true
-          AND created_at >= NOW() - INTERVAL '7 days'
+          AND updated_at >= NOW() - make_interval(days => $2)
         ORDER BY confidence_score DESC, created_at DESC
         LIMIT 10
         "#,
-        user_id
-    ).fetch_all(&state.db).await?;
+        user_id,
+        state.config.insights.expiry_days as i32
+    ).fetch_all(state.read_db()).await?;
 
     let mut insights = Vec::new();
 
@@ -239,7 +1159,10 @@ pub async fn get_flow_insights(
                 data_points: insight_data["data_points"]
                     .as_u64()
                     .unwrap_or(0) as u32,
-                time_range: "Last 7 days".to_string(),
+                time_range: match (row.date_range_start, row.date_range_end) {
+                    (Some(start), Some(end)) => format!("{} to {}", start, end),
+                    _ => "Last 7 days".to_string(),
+                },
             };
             insights.push(insight);
         }
@@ -266,57 +1189,439 @@ pub async fn get_flow_insights(
         insights.extend(basic_insights);
     }
 
-    Ok(Json(insights))
+    Ok(response_format.respond(insights))
+}
+
+pub async fn get_flow_achievements(
+    State(state): State<AppState>,
+    claims: Claims,
+    response_format: ResponseFormat,
+) -> Result<ApiResponse<FlowAchievementsResponse>> {
+    require_registered(&claims)?;
+
+    let mut conn = state.db.acquire().await?;
+    let (achievements, today) =
+        load_flow_achievements(&mut conn, claims.user_id, chrono::Utc::now()).await?;
+
+    Ok(response_format.respond(achievements.to_response(today, &state.config.achievements)))
+}
+
+const FORECAST_HOURS: u32 = 24;
+const FORECAST_BEST_WINDOWS: usize = 3;
+// Session-hours of history below which the forecast is flagged as sparse
+const FORECAST_MIN_SESSION_HOURS: u32 = 10;
+
+pub async fn get_flow_forecast(
+    State(state): State<AppState>,
+    claims: Claims,
+    response_format: ResponseFormat,
+) -> Result<ApiResponse<FlowForecast>> {
+    require_premium(&claims)?;
+
+    let forecast = load_flow_forecast(&state, claims.user_id, chrono::Utc::now()).await?;
+    Ok(response_format.respond(forecast))
+}
+
+pub async fn get_session_recommendation(
+    State(state): State<AppState>,
+    claims: Claims,
+    response_format: ResponseFormat,
+) -> Result<ApiResponse<SessionRecommendation>> {
+    require_premium(&claims)?;
+
+    let now = chrono::Utc::now();
+    let forecast = load_flow_forecast(&state, claims.user_id, now).await?;
+
+    // Recent sessions count more, as in the forecast
+    let average_session_ms = sqlx::query_scalar!(
+        r#"
+        SELECT (SUM(cs.total_duration_ms * w.weight) / NULLIF(SUM(w.weight), 0))::FLOAT8
+        FROM coding_sessions cs
+        CROSS JOIN LATERAL (
+            SELECT CASE WHEN $2::FLOAT8 > 0
+                THEN POWER(0.5, EXTRACT(EPOCH FROM (NOW() - cs.start_time)) / 86400.0 / $2::FLOAT8)
+                ELSE 1.0
+            END::FLOAT8 as weight
+        ) w
+        WHERE cs.user_id = $1
+          AND cs.end_time IS NOT NULL
+          AND cs.total_duration_ms > 0
+          AND cs.start_time >= NOW() - INTERVAL '90 days'
+        "#,
+        claims.user_id,
+        state.config.pattern_half_life_days
+    )
+    .fetch_one(state.read_db())
+    .await?;
+
+    Ok(response_format.respond(recommend_session(&forecast, average_session_ms, now)))
+}
+
+async fn load_flow_forecast(
+    state: &AppState,
+    user_id: Uuid,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Result<FlowForecast> {
+    // Hours and weekdays are bucketed in UTC, same as the forecast itself.
+    // Each week gets its own patterns so older weeks can be decayed.
+    let history = sqlx::query!(
+        r#"
+        SELECT
+            EXTRACT(HOUR FROM fs.start_time)::INT as "hour_of_day!",
+            EXTRACT(DOW FROM fs.start_time)::INT as "day_of_week!",
+            AVG(fs.intensity_score)::FLOAT8 as "average_flow_score!",
+            COUNT(DISTINCT fs.session_id) as "session_count!",
+            AVG(EXTRACT(EPOCH FROM (NOW() - fs.start_time)) / 86400.0)::FLOAT8 as "age_days!"
+        FROM flow_states fs
+        JOIN coding_sessions cs ON cs.id = fs.session_id
+        WHERE cs.user_id = $1
+          AND fs.start_time >= NOW() - INTERVAL '90 days'
+        GROUP BY 1, 2, date_trunc('week', fs.start_time)
+        "#,
+        user_id
+    )
+    .fetch_all(state.read_db())
+    .await?;
+
+    let patterns = history
+        .into_iter()
+        .map(|row| ProductivityPattern {
+            hour_of_day: row.hour_of_day as u8,
+            day_of_week: row.day_of_week as u8,
+            average_flow_score: row.average_flow_score as f32,
+            session_count: row.session_count as u32,
+            age_days: row.age_days as f32,
+        })
+        .collect();
+
+    let decay = TimeDecay::new(state.config.pattern_half_life_days);
+    Ok(forecast_from_patterns(patterns, now, decay).await)
+}
+
+pub async fn forecast_from_patterns(
+    patterns: Vec<ProductivityPattern>,
+    now: chrono::DateTime<chrono::Utc>,
+    decay: TimeDecay,
+) -> FlowForecast {
+    let history_session_hours: u32 = patterns.iter().map(|p| p.session_count).sum();
+
+    let mut predictor = ProductivityPredictor::new().with_time_decay(decay);
+    for pattern in patterns {
+        predictor.add_pattern(pattern);
+    }
+
+    let hours: Vec<FlowForecastHour> = predictor
+        .forecast(now, FORECAST_HOURS)
+        .await
+        .into_iter()
+        .map(|hour| FlowForecastHour {
+            starts_at: hour.starts_at,
+            expected_flow_score: hour.expected_flow_score,
+            session_count: hour.session_count,
+        })
+        .collect();
+
+    // Hours with no history at all only carry the neutral default
+    let mut best_windows: Vec<FlowForecastHour> = hours
+        .iter()
+        .filter(|hour| hour.session_count > 0)
+        .cloned()
+        .collect();
+    best_windows.sort_by(|a, b| b.expected_flow_score.total_cmp(&a.expected_flow_score));
+    best_windows.truncate(FORECAST_BEST_WINDOWS);
+
+    FlowForecast {
+        generated_at: now,
+        hours,
+        best_windows,
+        sparse_history: history_session_hours < FORECAST_MIN_SESSION_HOURS,
+    }
+}
+
+// Bounds on the recommended session length; the default applies until
+// the user has completed sessions
+const DEFAULT_SESSION_MS: u64 = 30 * 60_000;
+const MIN_SESSION_MS: u64 = 25 * 60_000;
+const MAX_SESSION_MS: u64 = 3 * 60 * 60_000;
+// Common productive hours (UTC), used until there is personal history
+const DEFAULT_PEAK_HOURS: [u32; 3] = [9, 14, 16];
+// Upcoming hours this close to the best score count as equally good, so
+// the earliest of them wins
+const RECOMMENDATION_SCORE_TOLERANCE: f32 = 0.05;
+
+/// Picks the next session window from the forecast: the earliest upcoming
+/// hour close to the best expected flow, sized to the user's typical
+/// session length.
+pub fn recommend_session(
+    forecast: &FlowForecast,
+    average_session_ms: Option<f64>,
+    now: chrono::DateTime<chrono::Utc>,
+) -> SessionRecommendation {
+    use chrono::Timelike;
+
+    let mut reasons = Vec::new();
+
+    let best_score = forecast
+        .hours
+        .iter()
+        .filter(|hour| hour.session_count > 0)
+        .map(|hour| hour.expected_flow_score)
+        .max_by(|a, b| a.total_cmp(b));
+
+    let window = best_score.and_then(|best| {
+        forecast.hours.iter().find(|hour| {
+            hour.session_count > 0
+                && hour.expected_flow_score >= best - RECOMMENDATION_SCORE_TOLERANCE
+        })
+    });
+    let default_window = window.is_none();
+
+    let (hour_start, expected_flow_score) = match window {
+        Some(hour) => {
+            reasons.push(format!(
+                "Your flow around {:02}:00 UTC is typically {:.0}%, among your best in the next day",
+                hour.starts_at.hour(),
+                hour.expected_flow_score * 100.0
+            ));
+            if forecast.sparse_history {
+                reasons.push(
+                    "Based on limited history; this will sharpen as you log more sessions"
+                        .to_string(),
+                );
+            }
+            (hour.starts_at, hour.expected_flow_score)
+        }
+        None => {
+            // Default to the next common productive hour
+            let hour = forecast
+                .hours
+                .iter()
+                .find(|hour| DEFAULT_PEAK_HOURS.contains(&hour.starts_at.hour()))
+                .or_else(|| forecast.hours.first());
+            reasons.push(
+                "Not enough history yet; suggesting a commonly productive hour".to_string(),
+            );
+            match hour {
+                Some(hour) => (hour.starts_at, hour.expected_flow_score),
+                None => (now, 0.5),
+            }
+        }
+    };
+
+    // Already inside the chosen hour: start right away
+    let starts_at = hour_start.max(now);
+    if starts_at == now {
+        reasons.push("Now is a good time to start".to_string());
+    }
+
+    let target_length_ms = match average_session_ms {
+        Some(average) if average > 0.0 => {
+            let target = (average as u64).clamp(MIN_SESSION_MS, MAX_SESSION_MS);
+            reasons.push(format!(
+                "Sized to your recent sessions, about {} minutes",
+                target / 60_000
+            ));
+            target
+        }
+        _ => {
+            reasons.push(format!(
+                "Default {}-minute session until you've completed a few",
+                DEFAULT_SESSION_MS / 60_000
+            ));
+            DEFAULT_SESSION_MS
+        }
+    };
+
+    SessionRecommendation {
+        generated_at: now,
+        starts_at,
+        ends_at: starts_at + chrono::Duration::milliseconds(target_length_ms as i64),
+        target_length_ms,
+        expected_flow_score,
+        default_window,
+        reasons,
+    }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct FlowAnalyticsQuery {
-    pub days: Option<i32>,
+    #[serde(flatten)]
+    pub range: DateRangeQuery,
+    /// Only aggregate flow states at or above this data quality (0.0-1.0);
+    /// states persisted before quality was tracked are then excluded
+    pub min_data_quality: Option<f32>,
+    #[serde(default)]
+    pub environment: SessionEnvironmentFilter,
+    /// Bucket size of `distribution`
+    #[serde(default)]
+    pub granularity: Granularity,
 }
 
 pub async fn get_flow_analytics(
     State(state): State<AppState>,
     claims: Claims,
+    response_format: ResponseFormat,
     Json(query): Json<FlowAnalyticsQuery>,
-) -> Result<Json<FlowAnalytics>> {
+) -> Result<Cached<FlowAnalytics>> {
+    require_premium(&claims)?;
+
     let user_id = claims.user_id;
-    let days = query.days.unwrap_or(30).min(365); // Max 365 days
+    if !analytics_enabled(&state.db, user_id).await? {
+        return Ok(Cached::Fresh(
+            response_format.respond(FlowAnalytics::opted_out(query.granularity)),
+        ));
+    }
+    let range = query.range.resolve(chrono::Utc::now(), 30)?;
+    let min_data_quality = query.min_data_quality.map(|q| q.clamp(0.0, 1.0) as f64);
+    let environment = query.environment.to_containment();
+    query.granularity.check_bucket_count(range)?;
+    // Buckets follow the user's calendar; unknown zones fall back to UTC
+    let timezone = sqlx::query_scalar!("SELECT timezone FROM users WHERE id = $1", user_id)
+        .fetch_optional(state.read_db())
+        .await?
+        .flatten()
+        .filter(|name| name.parse::<chrono_tz::Tz>().is_ok())
+        .unwrap_or_else(|| "UTC".to_string());
 
-    let analytics_data = sqlx::query!(
-        r#"
-        SELECT 
-            SUM(COALESCE(fs.duration_ms, 0)) as total_flow_time,
-            AVG(fs.intensity_score) as avg_intensity,
-            COUNT(DISTINCT fs.session_id) as flow_sessions,
-            MAX(fs.duration_ms) as longest_flow,
-            AVG(cs.interruption_count::float / GREATEST(cs.total_duration_ms::float / 60000, 1)) as interruption_rate,
-            AVG(cs.focus_score) as productivity_score
-        FROM flow_states fs
-        JOIN coding_sessions cs ON fs.session_id = cs.id
-        WHERE cs.user_id = $1 
-          AND fs.created_at >= NOW() - INTERVAL '%s days'
-        "#,
+    let validator = analytics_validator(
+        &state,
+        &response_format,
         user_id,
-        &format!("{}", days)
-    ).fetch_optional(&state.db).await?;
+        range,
+        (
+            "flow_analytics",
+            &query.range,
+            min_data_quality.map(f64::to_bits),
+            environment.to_string(),
+            query.granularity,
+            &timezone,
+        ),
+    )
+    .await?;
+    if response_format.has_current(&validator) {
+        return Ok(Cached::NotModified(validator));
+    }
 
-    let daily_data = sqlx::query!(
+    let analytics_data = state
+        .slow_queries
+        .time(
+            "flow_analytics.summary",
+            sqlx::query!(
+                r#"
+                SELECT
+                    SUM(COALESCE(fs.duration_ms, 0)) as total_flow_time,
+                    AVG(fs.intensity_score) as avg_intensity,
+                    COUNT(DISTINCT fs.session_id) as flow_sessions,
+                    MAX(fs.duration_ms) as longest_flow,
+                    AVG(cs.interruption_count::float / GREATEST(cs.total_duration_ms::float / 60000, 1)) as interruption_rate,
+                    AVG(cs.focus_score) as productivity_score
+                FROM flow_states fs
+                JOIN coding_sessions cs ON fs.session_id = cs.id
+                WHERE cs.user_id = $1
+                  AND fs.created_at >= $2
+                  AND fs.created_at < $3
+                  AND ($4::FLOAT8 IS NULL OR fs.data_quality >= $4)
+                  AND ($5::JSONB = '{}'::JSONB OR cs.environment_data @> $5)
+                "#,
+                user_id,
+                range.from,
+                range.to,
+                min_data_quality,
+                environment
+            )
+            .fetch_optional(state.read_db()),
+        )
+        .await?;
+
+    let daily_data = state
+        .slow_queries
+        .time(
+            "flow_analytics.daily",
+            sqlx::query!(
+                r#"
+                SELECT
+                    DATE(fs.start_time) as date,
+                    SUM(COALESCE(fs.duration_ms, 0)) as daily_flow_time,
+                    COUNT(*) as session_count,
+                    AVG(fs.intensity_score) as avg_intensity
+                FROM flow_states fs
+                JOIN coding_sessions cs ON fs.session_id = cs.id
+                WHERE cs.user_id = $1
+                  AND fs.created_at >= $2
+                  AND fs.created_at < $3
+                  AND ($4::FLOAT8 IS NULL OR fs.data_quality >= $4)
+                  AND ($5::JSONB = '{}'::JSONB OR cs.environment_data @> $5)
+                GROUP BY DATE(fs.start_time)
+                ORDER BY date DESC
+                "#,
+                user_id,
+                range.from,
+                range.to,
+                min_data_quality,
+                environment
+            )
+            .fetch_all(state.read_db()),
+        )
+        .await?;
+
+    let buckets = state
+        .slow_queries
+        .time(
+            "flow_analytics.distribution",
+            sqlx::query!(
+                r#"
+                SELECT
+                    date_trunc($6, fs.start_time AT TIME ZONE $7) AT TIME ZONE $7 as "start!",
+                    SUM(COALESCE(fs.duration_ms, 0))::BIGINT as "flow_time_ms!",
+                    COUNT(DISTINCT fs.session_id) as "sessions!",
+                    AVG(fs.intensity_score)::FLOAT8 as "avg_intensity!"
+                FROM flow_states fs
+                JOIN coding_sessions cs ON fs.session_id = cs.id
+                WHERE cs.user_id = $1
+                  AND fs.created_at >= $2
+                  AND fs.created_at < $3
+                  AND ($4::FLOAT8 IS NULL OR fs.data_quality >= $4)
+                  AND ($5::JSONB = '{}'::JSONB OR cs.environment_data @> $5)
+                GROUP BY 1
+                ORDER BY 1
+                "#,
+                user_id,
+                range.from,
+                range.to,
+                min_data_quality,
+                environment,
+                query.granularity.as_str(),
+                timezone
+            )
+            .fetch_all(state.read_db()),
+        )
+        .await?;
+
+    let distribution = buckets
+        .into_iter()
+        .map(|bucket| FlowBucket {
+            start: bucket.start,
+            total_flow_time_ms: bucket.flow_time_ms as u64,
+            session_count: bucket.sessions as u32,
+            average_intensity: bucket.avg_intensity as f32,
+        })
+        .collect();
+
+    let reported_interruptions = sqlx::query_scalar!(
         r#"
-        SELECT 
-            DATE(fs.start_time) as date,
-            SUM(COALESCE(fs.duration_ms, 0)) as daily_flow_time,
-            COUNT(*) as session_count,
-            AVG(fs.intensity_score) as avg_intensity
-        FROM flow_states fs
-        JOIN coding_sessions cs ON fs.session_id = cs.id
-        WHERE cs.user_id = $1 
-          AND fs.created_at >= NOW() - INTERVAL '%s days'
-        GROUP BY DATE(fs.start_time)
-        ORDER BY date DESC
+        SELECT COUNT(*)
+        FROM flow_interruptions fi
+        JOIN coding_sessions cs ON fi.session_id = cs.id
+        WHERE cs.user_id = $1
+          AND fi.occurred_at >= $2
+          AND fi.occurred_at < $3
+          AND ($4::JSONB = '{}'::JSONB OR cs.environment_data @> $4)
         "#,
         user_id,
-        &format!("{}", days)
-    ).fetch_all(&state.db).await?;
+        range.from,
+        range.to,
+        environment
+    ).fetch_one(state.read_db()).await?.unwrap_or(0) as u32;
 
     let daily_distribution = daily_data
         .into_iter()
@@ -336,9 +1641,13 @@ pub async fn get_flow_analytics(
             flow_sessions_count: data.flow_sessions.unwrap_or(0) as u32,
             longest_flow_session_ms: data.longest_flow.unwrap_or(0) as u64,
             interruption_rate: data.interruption_rate.unwrap_or(0.0) as f32,
+            reported_interruptions,
             productivity_score: data.productivity_score.unwrap_or(0.0) as f32,
             weekly_trend: 0.0, // Could calculate week-over-week change
             daily_distribution,
+            granularity: query.granularity,
+            distribution,
+            opted_out: false,
         }
     } else {
         FlowAnalytics {
@@ -347,11 +1656,160 @@ pub async fn get_flow_analytics(
             flow_sessions_count: 0,
             longest_flow_session_ms: 0,
             interruption_rate: 0.0,
+            reported_interruptions,
             productivity_score: 0.0,
             weekly_trend: 0.0,
             daily_distribution: vec![],
+            granularity: query.granularity,
+            distribution,
+            opted_out: false,
         }
     };
 
-    Ok(Json(analytics))
-}
\ No newline at end of file
+    Ok(response_format.respond_cached(analytics, validator))
+}
+
+/// The `ETag` of an analytics response: the request, the range it resolved
+/// to (to the minute) and a summary of the user's data that changes
+/// whenever a session, flow state or interruption is added, updated or
+/// deleted.
+async fn analytics_validator(
+    state: &AppState,
+    response_format: &ResponseFormat,
+    user_id: Uuid,
+    range: DateRange,
+    request: impl Hash,
+) -> Result<CacheValidator> {
+    let version = sqlx::query!(
+        r#"
+        SELECT
+            s.sessions as "sessions!",
+            s.latest as latest_session,
+            f.flow_states as "flow_states!",
+            f.latest as latest_flow_state,
+            i.interruptions as "interruptions!",
+            i.latest as latest_interruption
+        FROM (
+            SELECT COUNT(*) as sessions, MAX(COALESCE(updated_at, created_at)) as latest
+            FROM coding_sessions
+            WHERE user_id = $1
+        ) s
+        CROSS JOIN (
+            SELECT COUNT(*) as flow_states, MAX(fs.created_at) as latest
+            FROM flow_states fs
+            JOIN coding_sessions cs ON fs.session_id = cs.id
+            WHERE cs.user_id = $1
+        ) f
+        CROSS JOIN (
+            SELECT COUNT(*) as interruptions, MAX(fi.created_at) as latest
+            FROM flow_interruptions fi
+            JOIN coding_sessions cs ON fi.session_id = cs.id
+            WHERE cs.user_id = $1
+        ) i
+        "#,
+        user_id
+    )
+    .fetch_one(state.read_db())
+    .await?;
+
+    Ok(CacheValidator::new(
+        (
+            request,
+            user_id,
+            response_format.is_enveloped(),
+            range.from.timestamp() / 60,
+            range.to.timestamp() / 60,
+            (version.sessions, version.latest_session),
+            (version.flow_states, version.latest_flow_state),
+            (version.interruptions, version.latest_interruption),
+        ),
+        state.config.analytics_cache_max_age_secs,
+    ))
+}
+#[derive(Debug, Deserialize)]
+pub struct FlowExportQuery {
+    #[serde(default)]
+    pub format: ExportFormat,
+}
+
+/// Exports the caller's full flow history, as a JSON array or a Parquet
+/// file for warehouse loading. Available on every registered tier, since
+/// it doubles as the flow part of a GDPR data export.
+pub async fn export_flow_history(
+    State(state): State<AppState>,
+    claims: Claims,
+    Query(query): Query<FlowExportQuery>,
+) -> Result<Response> {
+    require_registered(&claims)?;
+
+    let (response, rows_exported) = match query.format {
+        ExportFormat::Parquet => {
+            let (parquet, rows_exported) = export_flow_states_parquet(
+                state.read_db(),
+                claims.user_id,
+                Vec::new(),
+                DEFAULT_ROW_GROUP_SIZE,
+            )
+            .await?;
+
+            let response = (
+                [
+                    (header::CONTENT_TYPE, "application/vnd.apache.parquet"),
+                    (
+                        header::CONTENT_DISPOSITION,
+                        "attachment; filename=\"flow_states.parquet\"",
+                    ),
+                ],
+                parquet,
+            )
+                .into_response();
+            (response, rows_exported)
+        }
+        ExportFormat::Json => {
+            let mut rows = Vec::new();
+            loop {
+                let after = rows
+                    .last()
+                    .map(|row: &FlowStateExportRow| (row.start_time, row.id));
+                let page = fetch_flow_state_page(
+                    state.read_db(),
+                    claims.user_id,
+                    after,
+                    DEFAULT_ROW_GROUP_SIZE,
+                )
+                .await?;
+                let is_last_page = page.len() < DEFAULT_ROW_GROUP_SIZE;
+                rows.extend(page);
+                if is_last_page {
+                    break;
+                }
+            }
+
+            let rows_exported = rows.len();
+            (Json(rows).into_response(), rows_exported)
+        }
+        ExportFormat::Csv | ExportFormat::Xml => {
+            return Err(AppError::Validation(format!(
+                "Flow history can't be exported as {}; use json or parquet",
+                query.format.as_str()
+            )));
+        }
+    };
+
+    let mut tx = state.db.begin().await?;
+    record_audit_entry(
+        &mut tx,
+        Some(claims.user_id),
+        Some(claims.user_id),
+        AuditOperation::Export,
+        serde_json::json!({
+            "dataset": "flow_states",
+            "format": query.format.as_str(),
+            "rows": rows_exported,
+        }),
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok(response)
+}
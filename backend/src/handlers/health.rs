@@ -1,8 +1,12 @@
-use axum::{extract::State, http::StatusCode, Json};
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    Json,
+};
 use serde_json::{json, Value};
-use std::time::SystemTime;
+use std::{sync::atomic::Ordering, time::SystemTime};
 
-use crate::{error::Result, state::AppState};
+use crate::{error::Result, state::AppState, utils::auth::authorize_metrics_scrape};
 
 pub async fn health_check(State(state): State<AppState>) -> Result<Json<Value>> {
     // Check database connection
@@ -31,21 +35,32 @@ pub async fn health_check(State(state): State<AppState>) -> Result<Json<Value>>
         "services": {
             "active_sessions": active_sessions,
             "flow_engines": flow_engines,
-            "websocket_connections": websocket_connections
+            "websocket_connections": websocket_connections,
+            "plugins": if state.wasm_plugins.is_some() { "available" } else { "unavailable" }
         },
-        "environment": state.config.environment
+        "environment": state.config.environment,
+        "server_mode": state.readiness.mode()
     });
 
     Ok(Json(response))
 }
 
-pub async fn metrics(State(state): State<AppState>) -> Result<(StatusCode, String)> {
+pub async fn metrics(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<(StatusCode, String)> {
+    authorize_metrics_scrape(&state.config.metrics_auth, &headers)?;
+
     // Prometheus-compatible metrics format
     let active_sessions = state.get_active_sessions_count();
     let flow_engines = state.flow_engines.len();
     let websocket_connections = state.websocket_connections.len();
     let db_pool_size = state.db.size();
     let db_idle_connections = state.db.num_idle();
+    let flow_write_queue_depth = state.flow_writes.queue_depth();
+    let flow_writes_in_flight = state.flow_writes.in_flight();
+    let analysis_cache = &state.analysis_cache_stats;
+    let ml_fallbacks = state.ml_fallbacks.load(Ordering::Relaxed);
 
     let metrics = format!(
         r#"# HELP mindful_code_active_sessions Number of active coding sessions
@@ -67,12 +82,46 @@ mindful_code_db_pool_size {{}} {}
 # HELP mindful_code_db_idle_connections Number of idle database connections
 # TYPE mindful_code_db_idle_connections gauge
 mindful_code_db_idle_connections {{}} {}
-"#,
+
+# HELP mindful_code_flow_write_queue_depth Flow state writes waiting for a write slot
+# TYPE mindful_code_flow_write_queue_depth gauge
+mindful_code_flow_write_queue_depth {{}} {}
+
+# HELP mindful_code_flow_writes_in_flight Flow state writes currently running
+# TYPE mindful_code_flow_writes_in_flight gauge
+mindful_code_flow_writes_in_flight {{}} {}
+
+# HELP mindful_code_flow_analysis_cache_hits_total Flow analyses served from the analysis cache
+# TYPE mindful_code_flow_analysis_cache_hits_total counter
+mindful_code_flow_analysis_cache_hits_total {{}} {}
+
+# HELP mindful_code_flow_analysis_cache_misses_total Flow analyses computed after a cache miss
+# TYPE mindful_code_flow_analysis_cache_misses_total counter
+mindful_code_flow_analysis_cache_misses_total {{}} {}
+
+# HELP mindful_code_flow_analysis_cache_hit_rate Share of cache lookups that hit
+# TYPE mindful_code_flow_analysis_cache_hit_rate gauge
+mindful_code_flow_analysis_cache_hit_rate {{}} {:.4}
+
+# HELP mindful_code_ml_fallbacks_total Flow analyses scored rule-based after ML inference failed
+# TYPE mindful_code_ml_fallbacks_total counter
+mindful_code_ml_fallbacks_total {{}} {}
+
+{}
+{}"#,
         active_sessions,
         flow_engines,
         websocket_connections,
         db_pool_size,
-        db_idle_connections
+        db_idle_connections,
+        flow_write_queue_depth,
+        flow_writes_in_flight,
+        analysis_cache.hits(),
+        analysis_cache.misses(),
+        analysis_cache.hit_rate(),
+        ml_fallbacks,
+        state.tier_usage.render(),
+        state.slow_queries.render()
     );
 
     Ok((
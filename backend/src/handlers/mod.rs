@@ -1,14 +1,20 @@
+pub mod admin;
 pub mod auth;
+pub mod dashboard;
 pub mod flow;
 pub mod health;
+pub mod plugins;
 pub mod privacy;
 pub mod sessions;
 pub mod teams;
 pub mod websocket;
 
+pub use admin::*;
 pub use auth::*;
+pub use dashboard::*;
 pub use flow::*;
 pub use health::*;
+pub use plugins::*;
 pub use privacy::*;
 pub use sessions::*;
 pub use teams::*;
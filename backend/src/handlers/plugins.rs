@@ -0,0 +1,17 @@
+use axum::{extract::State, Json};
+
+use crate::{
+    error::Result,
+    services::wasm::PluginInfo,
+    state::AppState,
+    utils::auth::{require_registered, Claims},
+};
+
+pub async fn list_plugins(
+    State(state): State<AppState>,
+    claims: Claims,
+) -> Result<Json<Vec<PluginInfo>>> {
+    require_registered(&claims)?;
+
+    Ok(Json(state.plugins()?.get_loaded_plugins()))
+}
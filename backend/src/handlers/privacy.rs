@@ -0,0 +1,157 @@
+use axum::{
+    body::Body,
+    extract::{Query, State},
+    http::header,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Deserialize;
+use tokio::sync::mpsc;
+use tracing::warn;
+
+use crate::{
+    error::{AppError, Result},
+    services::{
+        encryption::{EncryptionService, ExportFormat, PrivacyManager, PrivacySettings},
+        export_bundle::ChannelSink,
+    },
+    state::AppState,
+    utils::auth::{require_registered, Claims},
+};
+
+/// Archive chunks waiting for the client before the export holds back.
+const ARCHIVE_CHUNKS_IN_FLIGHT: usize = 8;
+
+#[derive(Debug, Deserialize)]
+pub struct PrivacyExportQuery {
+    #[serde(default)]
+    pub format: ExportFormat,
+    /// Download the data itself as a zip instead of the summary
+    #[serde(default)]
+    pub archive: bool,
+}
+
+fn privacy_manager(state: &AppState) -> Result<PrivacyManager> {
+    let encryption = EncryptionService::from_hex_key(&state.config.encryption_key)?
+        .with_secure_delete_passes(state.config.secure_delete_passes);
+    Ok(PrivacyManager::new(encryption))
+}
+
+/// The caller's GDPR export. By default a summary of what is held per
+/// category; with `archive=true` the data itself, streamed as a zip with a
+/// file per category in `format` (json or csv) and a manifest.
+pub async fn export_user_data(
+    State(state): State<AppState>,
+    claims: Claims,
+    Query(query): Query<PrivacyExportQuery>,
+) -> Result<Response> {
+    require_registered(&claims)?;
+    let privacy = privacy_manager(&state)?;
+
+    if !query.archive {
+        let export = privacy
+            .export_user_data(
+                &state.db,
+                claims.user_id,
+                claims.user_id,
+                query.format,
+                &state.config.retention,
+            )
+            .await?;
+        return Ok(Json(export).into_response());
+    }
+
+    if !matches!(query.format, ExportFormat::Json | ExportFormat::Csv) {
+        return Err(AppError::Validation(format!(
+            "Export archives can't be written as {}; use json or csv",
+            query.format.as_str()
+        )));
+    }
+
+    // The archive is built while the client downloads it, so errors after
+    // this point can only end the body early
+    let (chunks, body) = mpsc::channel(ARCHIVE_CHUNKS_IN_FLIGHT);
+    let sink = ChannelSink::new(chunks);
+    let db = state.db.clone();
+    let user_id = claims.user_id;
+    tokio::spawn(async move {
+        let pacer = sink.clone();
+        let written = privacy
+            .export_user_data_archive(&db, user_id, user_id, query.format, sink, || {
+                pacer.send_buffered()
+            })
+            .await;
+        match written {
+            Ok((sink, _)) => {
+                if let Err(e) = sink.send_buffered().await {
+                    warn!("Export archive for user {} not delivered: {}", user_id, e);
+                }
+            }
+            Err(e) => {
+                warn!("Export archive for user {} failed: {}", user_id, e);
+                pacer.fail(&e).await;
+            }
+        }
+    });
+
+    let stream = futures::stream::unfold(body, |mut body| async move {
+        body.recv().await.map(|chunk| (chunk, body))
+    });
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/zip"),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"mindful-code-export.zip\"",
+            ),
+        ],
+        Body::from_stream(stream),
+    )
+        .into_response())
+}
+
+/// Erases everything held for the caller and returns how many records went.
+pub async fn purge_user_data(
+    State(state): State<AppState>,
+    claims: Claims,
+) -> Result<Json<serde_json::Value>> {
+    require_registered(&claims)?;
+
+    let records_deleted = privacy_manager(&state)?
+        .delete_user_data(&state.db, claims.user_id, claims.user_id)
+        .await?;
+
+    Ok(Json(
+        serde_json::json!({ "records_deleted": records_deleted }),
+    ))
+}
+
+pub async fn update_privacy_settings(
+    State(state): State<AppState>,
+    claims: Claims,
+    Json(settings): Json<PrivacySettings>,
+) -> Result<Json<PrivacySettings>> {
+    require_registered(&claims)?;
+    if settings.data_retention_days.is_some_and(|days| days <= 0) {
+        return Err(AppError::Validation(
+            "data_retention_days must be positive".to_string(),
+        ));
+    }
+
+    let value = serde_json::to_value(&settings)
+        .map_err(|e| AppError::Internal(format!("Failed to encode privacy settings: {}", e)))?;
+    let updated = sqlx::query!(
+        "UPDATE users SET privacy_settings = $2 WHERE id = $1",
+        claims.user_id,
+        value
+    )
+    .execute(&state.db)
+    .await?
+    .rows_affected();
+    if updated == 0 {
+        return Err(AppError::NotFound("User not found".to_string()));
+    }
+
+    Ok(Json(settings))
+}
@@ -0,0 +1,1065 @@
+use axum::{
+    extract::{Path, Query, State},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use tracing::{info, warn};
+use uuid::Uuid;
+use validator::Validate;
+
+use crate::{
+    error::{AppError, Result},
+    handlers::{
+        teams::check_team_goals_for_user,
+        websocket::{broadcast_session_update, send_notification, NotificationLevel},
+    },
+    models::{
+        achievement::AchievementEvent,
+        flow::{InterruptionType, SessionTimeline, TimelineEvent},
+        session::{
+            normalize_tag, DeleteSessionsRequest, DeleteSessionsResponse, EndSessionResponse,
+            SessionAggregates, SessionEnvironment, SessionResponse, SessionSearchCursor,
+            SessionSearchQuery, SessionSearchResponse, SessionSearchResult, SessionTagsRequest,
+            SessionTagsResponse, StartSessionRequest, UpdateSessionRequest, MAX_TAGS_PER_SESSION,
+        },
+    },
+    services::{
+        achievements::record_session_achievements,
+        encryption::{EncryptedData, PrivacySettings},
+        flow_stats::retire_flow_engine,
+        sanitizer::Sanitizer,
+    },
+    state::{AppState, SessionInfo},
+    utils::{
+        auth::{require_registered, Claims},
+        date_range::{DateRangeQuery, MAX_RANGE_DAYS},
+    },
+};
+
+/// Search page size when the client doesn't ask for one.
+const DEFAULT_SEARCH_LIMIT: u32 = 20;
+
+pub async fn start_session(
+    State(state): State<AppState>,
+    claims: Claims,
+    Json(payload): Json<StartSessionRequest>,
+) -> Result<Json<SessionResponse>> {
+    require_registered(&claims)?;
+    payload.validate().map_err(|e| {
+        AppError::Validation(format!("Invalid session request: {}", e))
+    })?;
+
+    let (project_path, environment_data) = sanitize_session_context(
+        &state.sanitizer,
+        payload.project_path.as_deref(),
+        payload.environment_data.as_ref(),
+    );
+
+    let session = sqlx::query!(
+        r#"
+        INSERT INTO coding_sessions (user_id, start_time, project_path, environment_data)
+        VALUES ($1, NOW(), $2, $3)
+        RETURNING id, start_time
+        "#,
+        claims.user_id,
+        project_path,
+        environment_data.clone().unwrap_or_else(|| serde_json::json!({})),
+    )
+    .fetch_one(&state.db)
+    .await?;
+
+    retain_encrypted_context(
+        &state,
+        claims.user_id,
+        session.id,
+        payload.project_path.as_deref(),
+        payload.environment_data.as_ref(),
+    )
+    .await?;
+
+    state.add_active_session(SessionInfo {
+        user_id: claims.user_id,
+        session_id: session.id,
+        started_at: session.start_time,
+        last_activity: session.start_time,
+        is_active: true,
+    });
+
+    Ok(Json(SessionResponse {
+        session_id: session.id,
+        started_at: session.start_time,
+        project_path,
+        environment_data: environment_data
+            .map(SessionEnvironment::from_stored)
+            .unwrap_or_default(),
+    }))
+}
+
+pub async fn update_session(
+    State(state): State<AppState>,
+    claims: Claims,
+    Path(session_id): Path<Uuid>,
+    Json(payload): Json<UpdateSessionRequest>,
+) -> Result<Json<SessionResponse>> {
+    require_registered(&claims)?;
+    payload.validate().map_err(|e| {
+        AppError::Validation(format!("Invalid session update: {}", e))
+    })?;
+
+    let (project_path, environment_data) = sanitize_session_context(
+        &state.sanitizer,
+        payload.project_path.as_deref(),
+        payload.environment_data.as_ref(),
+    );
+
+    let session = sqlx::query!(
+        r#"
+        UPDATE coding_sessions
+        SET files_modified = COALESCE($3, files_modified),
+            keystrokes = COALESCE($4, keystrokes),
+            lines_added = COALESCE($5, lines_added),
+            lines_deleted = COALESCE($6, lines_deleted),
+            project_path = COALESCE($7, project_path),
+            environment_data = COALESCE($8, environment_data)
+        WHERE id = $1 AND user_id = $2 AND end_time IS NULL
+        RETURNING start_time, project_path, environment_data
+        "#,
+        session_id,
+        claims.user_id,
+        payload.files_modified,
+        payload.keystrokes,
+        payload.lines_added,
+        payload.lines_deleted,
+        project_path,
+        environment_data,
+    )
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or_else(|| AppError::NotFound("Active session not found".to_string()))?;
+
+    retain_encrypted_context(
+        &state,
+        claims.user_id,
+        session_id,
+        payload.project_path.as_deref(),
+        payload.environment_data.as_ref(),
+    )
+    .await?;
+
+    state.update_session_activity(session_id);
+
+    Ok(Json(SessionResponse {
+        session_id,
+        started_at: session.start_time,
+        project_path: session.project_path,
+        environment_data: session
+            .environment_data
+            .map(SessionEnvironment::from_stored)
+            .unwrap_or_default(),
+    }))
+}
+
+/// Ends a session and stores its final aggregates. Safe to retry: ending an
+/// already-ended session returns the aggregates stored the first time.
+pub async fn end_session(
+    State(state): State<AppState>,
+    claims: Claims,
+    Path(session_id): Path<Uuid>,
+) -> Result<Json<EndSessionResponse>> {
+    require_registered(&claims)?;
+
+    let response = close_session(&state, claims.user_id, session_id, chrono::Utc::now(), "ended").await?;
+
+    Ok(Json(response))
+}
+
+pub async fn delete_session(
+    State(state): State<AppState>,
+    claims: Claims,
+    Path(session_id): Path<Uuid>,
+) -> Result<Json<DeleteSessionsResponse>> {
+    require_registered(&claims)?;
+
+    let response = delete_owned_sessions(&state, claims.user_id, vec![session_id]).await?;
+
+    Ok(Json(response))
+}
+
+/// Deletes a batch of the caller's sessions. All or nothing: if any id
+/// isn't one of their sessions, nothing is deleted.
+pub async fn delete_sessions(
+    State(state): State<AppState>,
+    claims: Claims,
+    Json(payload): Json<DeleteSessionsRequest>,
+) -> Result<Json<DeleteSessionsResponse>> {
+    require_registered(&claims)?;
+    payload.validate().map_err(|e| {
+        AppError::Validation(format!("Invalid session delete request: {}", e))
+    })?;
+
+    let response = delete_owned_sessions(&state, claims.user_id, payload.session_ids).await?;
+
+    Ok(Json(response))
+}
+
+pub async fn get_session_tags(
+    State(state): State<AppState>,
+    claims: Claims,
+    Path(session_id): Path<Uuid>,
+) -> Result<Json<SessionTagsResponse>> {
+    require_registered(&claims)?;
+
+    let mut tx = state.db.begin().await?;
+    lock_owned_session(&mut tx, claims.user_id, session_id).await?;
+    let tags = load_session_tags(&mut tx, session_id).await?;
+    tx.commit().await?;
+
+    Ok(Json(SessionTagsResponse { session_id, tags }))
+}
+
+/// Adds tags to a session, keeping the ones it already has.
+pub async fn add_session_tags(
+    State(state): State<AppState>,
+    claims: Claims,
+    Path(session_id): Path<Uuid>,
+    Json(payload): Json<SessionTagsRequest>,
+) -> Result<Json<SessionTagsResponse>> {
+    require_registered(&claims)?;
+
+    let response = write_session_tags(&state, claims.user_id, session_id, payload, false).await?;
+
+    Ok(Json(response))
+}
+
+/// Replaces a session's tags; an empty list clears them.
+pub async fn replace_session_tags(
+    State(state): State<AppState>,
+    claims: Claims,
+    Path(session_id): Path<Uuid>,
+    Json(payload): Json<SessionTagsRequest>,
+) -> Result<Json<SessionTagsResponse>> {
+    require_registered(&claims)?;
+
+    let response = write_session_tags(&state, claims.user_id, session_id, payload, true).await?;
+
+    Ok(Json(response))
+}
+
+pub async fn delete_session_tag(
+    State(state): State<AppState>,
+    claims: Claims,
+    Path((session_id, tag)): Path<(Uuid, String)>,
+) -> Result<Json<SessionTagsResponse>> {
+    require_registered(&claims)?;
+
+    let mut tx = state.db.begin().await?;
+    lock_owned_session(&mut tx, claims.user_id, session_id).await?;
+    let tag = normalize_tag(&tag).unwrap_or_default();
+    let removed = sqlx::query!(
+        "DELETE FROM session_tags WHERE session_id = $1 AND tag = $2",
+        session_id,
+        tag
+    )
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+    if removed == 0 {
+        return Err(AppError::NotFound(format!("Session has no tag '{}'", tag)));
+    }
+    let tags = load_session_tags(&mut tx, session_id).await?;
+    tx.commit().await?;
+
+    Ok(Json(SessionTagsResponse { session_id, tags }))
+}
+
+/// Searches the caller's sessions, newest first, by tag, language and
+/// minimum average flow intensity within a date range (default: the last
+/// year). Pages with `limit` and `cursor`.
+pub async fn search_sessions(
+    State(state): State<AppState>,
+    claims: Claims,
+    Query(range): Query<DateRangeQuery>,
+    Query(query): Query<SessionSearchQuery>,
+) -> Result<Json<SessionSearchResponse>> {
+    require_registered(&claims)?;
+    query
+        .validate()
+        .map_err(|e| AppError::Validation(format!("Invalid session search: {}", e)))?;
+
+    let range = range.resolve(chrono::Utc::now(), MAX_RANGE_DAYS)?;
+    let tag = match query.tag.as_deref() {
+        Some(tag) => Some(
+            normalize_tag(tag).ok_or_else(|| AppError::Validation("Invalid tag".to_string()))?,
+        ),
+        None => None,
+    };
+    let cursor = match query.cursor.as_deref() {
+        Some(cursor) => Some(
+            SessionSearchCursor::decode(cursor)
+                .ok_or_else(|| AppError::Validation("Invalid cursor".to_string()))?,
+        ),
+        None => None,
+    };
+    let (after_time, after_id) = cursor.map(|cursor| (cursor.start_time, cursor.id)).unzip();
+    let limit = query.limit.unwrap_or(DEFAULT_SEARCH_LIMIT) as usize;
+
+    // One extra row tells whether there is another page
+    let rows = sqlx::query!(
+        r#"
+        SELECT
+            cs.id,
+            cs.start_time,
+            cs.end_time,
+            cs.total_duration_ms,
+            cs.avg_flow_intensity,
+            cs.project_path,
+            cs.language_breakdown,
+            ARRAY(
+                SELECT st.tag::TEXT FROM session_tags st
+                WHERE st.session_id = cs.id
+                ORDER BY st.tag
+            ) as "tags!"
+        FROM coding_sessions cs
+        WHERE cs.user_id = $1
+          AND cs.start_time >= $2 AND cs.start_time < $3
+          AND ($4::TEXT IS NULL OR EXISTS (
+              SELECT 1 FROM session_tags st WHERE st.session_id = cs.id AND st.tag = $4
+          ))
+          AND ($5::TEXT IS NULL OR cs.language_breakdown ? $5)
+          AND ($6::FLOAT8 IS NULL OR cs.avg_flow_intensity >= $6)
+          AND ($7::TIMESTAMPTZ IS NULL OR (cs.start_time, cs.id) < ($7, $8::UUID))
+        ORDER BY cs.start_time DESC, cs.id DESC
+        LIMIT $9
+        "#,
+        claims.user_id,
+        range.from,
+        range.to,
+        tag,
+        query.language,
+        query.min_intensity,
+        after_time,
+        after_id,
+        limit as i64 + 1,
+    )
+    .fetch_all(state.read_db())
+    .await?;
+
+    let mut sessions: Vec<SessionSearchResult> = rows
+        .into_iter()
+        .map(|row| SessionSearchResult {
+            session_id: row.id,
+            started_at: row.start_time,
+            ended_at: row.end_time,
+            total_duration_ms: row.total_duration_ms,
+            avg_flow_intensity: row.avg_flow_intensity,
+            project_path: row.project_path,
+            languages: row
+                .language_breakdown
+                .as_ref()
+                .and_then(|languages| languages.as_object())
+                .map(|languages| languages.keys().cloned().collect())
+                .unwrap_or_default(),
+            tags: row.tags,
+        })
+        .collect();
+
+    let next_cursor = if sessions.len() > limit {
+        sessions.truncate(limit);
+        sessions.last().map(|last| {
+            SessionSearchCursor {
+                start_time: last.started_at,
+                id: last.session_id,
+            }
+            .encode()
+        })
+    } else {
+        None
+    };
+
+    Ok(Json(SessionSearchResponse {
+        sessions,
+        next_cursor,
+    }))
+}
+
+/// The session's flow samples and reported interruptions as one
+/// time-ordered series, so a timeline can show where focus broke.
+pub async fn get_session_timeline(
+    State(state): State<AppState>,
+    claims: Claims,
+    Path(session_id): Path<Uuid>,
+) -> Result<Json<SessionTimeline>> {
+    require_registered(&claims)?;
+
+    let session = sqlx::query!(
+        "SELECT start_time, end_time FROM coding_sessions WHERE id = $1 AND user_id = $2",
+        session_id,
+        claims.user_id
+    )
+    .fetch_optional(state.read_db())
+    .await?
+    .ok_or_else(|| AppError::NotFound("Session not found".to_string()))?;
+
+    let flow_points = sqlx::query!(
+        r#"
+        SELECT start_time,
+               intensity_score::FLOAT8 as "intensity!",
+               COALESCE(confidence_score, 0)::FLOAT8 as "confidence!"
+        FROM flow_states
+        WHERE session_id = $1
+        ORDER BY start_time
+        "#,
+        session_id
+    )
+    .fetch_all(state.read_db())
+    .await?
+    .into_iter()
+    .map(|row| TimelineEvent::FlowPoint {
+        at: row.start_time,
+        intensity: row.intensity as f32,
+        confidence: row.confidence as f32,
+    })
+    .collect();
+
+    let interruptions = sqlx::query!(
+        r#"
+        SELECT occurred_at, interruption_type, duration_ms
+        FROM flow_interruptions
+        WHERE session_id = $1
+        ORDER BY occurred_at
+        "#,
+        session_id
+    )
+    .fetch_all(state.read_db())
+    .await?
+    .into_iter()
+    .map(|row| TimelineEvent::Interruption {
+        at: row.occurred_at,
+        interruption_type: row
+            .interruption_type
+            .parse()
+            .unwrap_or(InterruptionType::Other),
+        duration_ms: row.duration_ms.max(0) as u64,
+    })
+    .collect();
+
+    Ok(Json(SessionTimeline::merge(
+        session_id,
+        session.start_time,
+        session.end_time,
+        flow_points,
+        interruptions,
+    )))
+}
+
+/// Most sessions left open in the database that one sweep ends.
+const IDLE_SWEEP_BATCH: i64 = 500;
+
+/// Ends every session idle for longer than the configured timeout, as of
+/// its last activity, and tells the user's clients it was `auto_ended`.
+/// Sessions this process is tracking are ended from memory; sessions only
+/// the database knows about, such as those left open across a restart, are
+/// ended as of their last update or flow sample. Returns how many sessions
+/// were ended.
+pub async fn auto_end_idle_sessions(state: &AppState) -> usize {
+    let idle_timeout_minutes = state.config.session_idle_timeout_minutes;
+    let mut ended = 0;
+
+    for session in state.idle_sessions(idle_timeout_minutes) {
+        match close_session(
+            state,
+            session.user_id,
+            session.session_id,
+            session.last_activity,
+            "auto_ended",
+        )
+        .await
+        {
+            Ok(response) => {
+                if response.already_ended {
+                    forget_ended_session(state, session.user_id, session.session_id).await;
+                } else {
+                    ended += 1;
+                }
+            }
+            // Nothing left to end, so stop tracking it
+            Err(AppError::NotFound(_)) => {
+                forget_ended_session(state, session.user_id, session.session_id).await;
+            }
+            // Still tracked, so the next sweep tries again
+            Err(e) => warn!(
+                "Failed to auto-end idle session {}: {}",
+                session.session_id, e
+            ),
+        }
+    }
+
+    let cutoff = chrono::Utc::now() - chrono::Duration::minutes(idle_timeout_minutes);
+    let stale_sessions = match sqlx::query!(
+        r#"
+        SELECT cs.id, cs.user_id, activity.last_activity AS "last_activity!"
+        FROM coding_sessions cs
+        CROSS JOIN LATERAL (
+            SELECT GREATEST(
+                cs.start_time,
+                cs.updated_at,
+                (SELECT MAX(fs.start_time) FROM flow_states fs WHERE fs.session_id = cs.id)
+            ) AS last_activity
+        ) activity
+        WHERE cs.end_time IS NULL AND activity.last_activity < $1
+        ORDER BY activity.last_activity
+        LIMIT $2
+        "#,
+        cutoff,
+        IDLE_SWEEP_BATCH
+    )
+    .fetch_all(&state.db)
+    .await
+    {
+        Ok(rows) => rows,
+        Err(e) => {
+            warn!("Failed to look up stale open sessions: {}", e);
+            return ended;
+        }
+    };
+
+    for session in stale_sessions {
+        // Tracked sessions carry fresher activity than the row and were
+        // handled above
+        if state.is_session_active(session.id) {
+            continue;
+        }
+        match close_session(
+            state,
+            session.user_id,
+            session.id,
+            session.last_activity,
+            "auto_ended",
+        )
+        .await
+        {
+            Ok(response) if !response.already_ended => ended += 1,
+            Ok(_) => {}
+            Err(e) => warn!("Failed to auto-end stale session {}: {}", session.id, e),
+        }
+    }
+
+    ended
+}
+
+async fn forget_ended_session(state: &AppState, user_id: Uuid, session_id: Uuid) {
+    if let Some(engine) = state.remove_active_session(user_id, session_id) {
+        retire_flow_engine(&state.db, user_id, &engine).await;
+    }
+}
+
+/// Stamps `ended_at` on the session and stores its final aggregates, then
+/// broadcasts `status` and any records the session broke. Ending a session
+/// twice returns the stored aggregates without touching anything.
+async fn close_session(
+    state: &AppState,
+    user_id: Uuid,
+    session_id: Uuid,
+    ended_at: chrono::DateTime<chrono::Utc>,
+    status: &str,
+) -> Result<EndSessionResponse> {
+    let mut tx = state.db.begin().await?;
+
+    let session = sqlx::query!(
+        r#"
+        SELECT start_time, end_time, total_duration_ms, total_flow_time_ms,
+               avg_flow_intensity, peak_flow_intensity, interruption_count
+        FROM coding_sessions
+        WHERE id = $1 AND user_id = $2
+        FOR UPDATE
+        "#,
+        session_id,
+        user_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or_else(|| AppError::NotFound("Session not found".to_string()))?;
+
+    if let Some(ended_at) = session.end_time {
+        return Ok(EndSessionResponse {
+            session_id,
+            ended_at,
+            already_ended: true,
+            aggregates: aggregates_from_columns(
+                session.total_duration_ms,
+                session.total_flow_time_ms,
+                session.avg_flow_intensity,
+                session.peak_flow_intensity,
+                session.interruption_count,
+            ),
+        });
+    }
+
+    // Each sample stands for the time until the next one, and the last for
+    // the time until the session ended. Samples are stored at least once a
+    // sampling interval while the client reports, so a longer gap means it
+    // stopped and only two intervals are credited as flow
+    let max_span_ms = (state.config.flow_sample_interval_secs * 2_000) as i64;
+    sqlx::query!(
+        r#"
+        WITH spans AS (
+            SELECT id, is_in_flow, start_time,
+                   COALESCE(LEAD(start_time) OVER (ORDER BY start_time, id), $2) AS next_start
+            FROM flow_states
+            WHERE session_id = $1
+        )
+        UPDATE flow_states fs
+        SET end_time = GREATEST(spans.next_start, spans.start_time),
+            duration_ms = CASE
+                WHEN spans.is_in_flow THEN LEAST(
+                    GREATEST(EXTRACT(EPOCH FROM spans.next_start - spans.start_time) * 1000, 0),
+                    $3
+                )::BIGINT
+                ELSE 0
+            END
+        FROM spans
+        WHERE fs.id = spans.id
+        "#,
+        session_id,
+        ended_at,
+        max_span_ms
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let flow_states: Vec<(f32, Option<u64>)> = sqlx::query!(
+        r#"
+        SELECT intensity_score::FLOAT8 as "intensity!", duration_ms
+        FROM flow_states
+        WHERE session_id = $1
+        "#,
+        session_id
+    )
+    .fetch_all(&mut *tx)
+    .await?
+    .into_iter()
+    .map(|row| (row.intensity as f32, row.duration_ms.map(|ms| ms.max(0) as u64)))
+    .collect();
+
+    let reported_interruptions = sqlx::query_scalar!(
+        "SELECT COUNT(*) FROM flow_interruptions WHERE session_id = $1",
+        session_id
+    )
+    .fetch_one(&mut *tx)
+    .await?
+    .unwrap_or(0) as u32;
+
+    // Clients may already have counted interruptions they inferred
+    // themselves; keep whichever count is larger
+    let interruption_count =
+        reported_interruptions.max(session.interruption_count.unwrap_or(0).max(0) as u32);
+
+    let total_duration_ms = (ended_at - session.start_time).num_milliseconds().max(0) as u64;
+    let aggregates =
+        SessionAggregates::from_flow_states(total_duration_ms, &flow_states, interruption_count);
+
+    sqlx::query!(
+        r#"
+        UPDATE coding_sessions
+        SET end_time = $2,
+            total_duration_ms = $3,
+            total_flow_time_ms = $4,
+            avg_flow_intensity = $5,
+            peak_flow_intensity = $6,
+            interruption_count = $7,
+            updated_at = NOW()
+        WHERE id = $1
+        "#,
+        session_id,
+        ended_at,
+        aggregates.total_duration_ms as i64,
+        aggregates.total_flow_time_ms as i64,
+        aggregates.avg_flow_intensity as f64,
+        aggregates.peak_flow_intensity as f64,
+        aggregates.interruption_count as i32,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let achievement_events = record_session_achievements(
+        &mut tx,
+        user_id,
+        session_id,
+        ended_at,
+        aggregates.total_flow_time_ms,
+        &state.config.achievements,
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    forget_ended_session(state, user_id, session_id).await;
+    broadcast_session_update(
+        state,
+        user_id,
+        session_id,
+        status.to_string(),
+        serde_json::to_value(&aggregates).unwrap_or_default(),
+    )
+    .await;
+
+    for event in achievement_events {
+        notify_achievement(state, user_id, event).await;
+    }
+    check_team_goals_for_user(state, user_id).await;
+
+    info!(
+        "Session {} {}: {}ms total, {}ms in flow",
+        session_id, status, aggregates.total_duration_ms, aggregates.total_flow_time_ms
+    );
+
+    Ok(EndSessionResponse {
+        session_id,
+        ended_at,
+        already_ended: false,
+        aggregates,
+    })
+}
+
+async fn notify_achievement(state: &AppState, user_id: Uuid, event: AchievementEvent) {
+    let (title, message) = match event {
+        AchievementEvent::BestSession { flow_ms, .. } => (
+            "New best flow session".to_string(),
+            format!("{} minutes in flow, your longest yet", flow_ms / 60_000),
+        ),
+        AchievementEvent::StreakMilestone { days } => (
+            format!("{}-day flow streak", days),
+            format!("You've found flow {} days in a row", days),
+        ),
+    };
+
+    send_notification(state, user_id, title, message, NotificationLevel::Success).await;
+}
+
+fn sanitize_session_context(
+    sanitizer: &Sanitizer,
+    project_path: Option<&str>,
+    environment_data: Option<&SessionEnvironment>,
+) -> (Option<String>, Option<serde_json::Value>) {
+    let environment_data = environment_data
+        .and_then(|environment| serde_json::to_value(environment).ok())
+        .map(|data| sanitizer.sanitize_environment(&data));
+    (
+        project_path.map(|path| sanitizer.sanitize_path(path)),
+        environment_data,
+    )
+}
+
+/// The unsanitized context retained for a session.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RetainedContext {
+    project_path: Option<String>,
+    environment_data: Option<SessionEnvironment>,
+}
+
+/// Keeps the unsanitized context, encrypted, for users whose privacy
+/// settings ask for high or military-grade encryption. Everyone else only
+/// ever has the sanitized values stored. A session keeps one copy, which
+/// each update replaces.
+async fn retain_encrypted_context(
+    state: &AppState,
+    user_id: Uuid,
+    session_id: Uuid,
+    project_path: Option<&str>,
+    environment_data: Option<&SessionEnvironment>,
+) -> Result<()> {
+    if project_path.is_none() && environment_data.is_none() {
+        return Ok(());
+    }
+
+    let retain_original = sqlx::query_scalar!(
+        "SELECT privacy_settings FROM users WHERE id = $1",
+        user_id
+    )
+    .fetch_optional(&state.db)
+    .await?
+    .flatten()
+    .and_then(|settings| serde_json::from_value::<PrivacySettings>(settings).ok())
+    .is_some_and(|settings| settings.is_high_security());
+
+    if !retain_original {
+        return Ok(());
+    }
+
+    let Some(encryption) = state.encryption.as_ref() else {
+        warn!("Encryption unavailable, original session context for {} not retained", session_id);
+        return Ok(());
+    };
+
+    let retained = sqlx::query_scalar!(
+        "SELECT encrypted_data FROM encrypted_user_data WHERE session_id = $1",
+        session_id
+    )
+    .fetch_optional(&state.db)
+    .await?;
+    // An update may carry only one of the two; the other stays as retained
+    let previous: RetainedContext = retained
+        .and_then(|bytes| serde_json::from_slice::<EncryptedData>(&bytes).ok())
+        .and_then(|encrypted| encryption.read().decrypt_sensitive_data(&encrypted).ok())
+        .unwrap_or_default();
+    let context = RetainedContext {
+        project_path: project_path.map(str::to_string).or(previous.project_path),
+        environment_data: environment_data.cloned().or(previous.environment_data),
+    };
+
+    let encrypted = encryption.read().encrypt_sensitive_data(&context)?;
+    let encrypted_bytes = serde_json::to_vec(&encrypted)
+        .map_err(|e| AppError::Encryption(format!("Failed to encode session context: {}", e)))?;
+
+    sqlx::query!(
+        r#"
+        INSERT INTO encrypted_user_data
+            (user_id, data_type, encrypted_data, encryption_key_id, session_id)
+        VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (session_id) DO UPDATE SET
+            encrypted_data = EXCLUDED.encrypted_data,
+            encryption_key_id = EXCLUDED.encryption_key_id,
+            created_at = NOW()
+        "#,
+        user_id,
+        format!("session_context:{}", session_id),
+        encrypted_bytes,
+        encrypted.key_id,
+        session_id,
+    )
+    .execute(&state.db)
+    .await?;
+
+    Ok(())
+}
+
+/// Deletes the sessions along with their flow states, interruptions and
+/// retained context. Other users' sessions are reported as not found, so
+/// their ids can't be probed.
+async fn delete_owned_sessions(
+    state: &AppState,
+    user_id: Uuid,
+    mut session_ids: Vec<Uuid>,
+) -> Result<DeleteSessionsResponse> {
+    let mut seen = HashSet::new();
+    session_ids.retain(|session_id| seen.insert(*session_id));
+
+    let mut tx = state.db.begin().await?;
+
+    let owned: HashSet<Uuid> = sqlx::query_scalar!(
+        "SELECT id FROM coding_sessions WHERE user_id = $1 AND id = ANY($2) FOR UPDATE",
+        user_id,
+        &session_ids
+    )
+    .fetch_all(&mut *tx)
+    .await?
+    .into_iter()
+    .collect();
+
+    let missing: Vec<String> = session_ids
+        .iter()
+        .filter(|session_id| !owned.contains(session_id))
+        .map(|session_id| session_id.to_string())
+        .collect();
+    if !missing.is_empty() {
+        return Err(AppError::NotFound(format!(
+            "Sessions not found: {}",
+            missing.join(", ")
+        )));
+    }
+
+    // flow_states and flow_interruptions cascade
+    let deleted = sqlx::query!(
+        "DELETE FROM coding_sessions WHERE user_id = $1 AND id = ANY($2)",
+        user_id,
+        &session_ids
+    )
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+
+    let context_keys: Vec<String> = session_ids
+        .iter()
+        .map(|session_id| format!("session_context:{}", session_id))
+        .collect();
+    sqlx::query!(
+        "DELETE FROM encrypted_user_data WHERE user_id = $1 AND data_type = ANY($2)",
+        user_id,
+        &context_keys
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    // Deleted sessions' engine stats aren't added to the lifetime totals
+    for session_id in &session_ids {
+        state.remove_active_session(user_id, *session_id);
+    }
+    info!("Deleted {} sessions for user {}", deleted, user_id);
+
+    Ok(DeleteSessionsResponse {
+        deleted,
+        session_ids,
+    })
+}
+
+/// Adds the requested tags, or replaces the session's tags with them, and
+/// returns the resulting set. Nothing changes if it would exceed
+/// `MAX_TAGS_PER_SESSION`.
+async fn write_session_tags(
+    state: &AppState,
+    user_id: Uuid,
+    session_id: Uuid,
+    payload: SessionTagsRequest,
+    replace: bool,
+) -> Result<SessionTagsResponse> {
+    payload
+        .validate()
+        .map_err(|e| AppError::Validation(format!("Invalid session tags: {}", e)))?;
+    let requested = payload
+        .tags
+        .iter()
+        .map(|tag| {
+            normalize_tag(tag).ok_or_else(|| AppError::Validation(format!("Invalid tag '{}'", tag)))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut tx = state.db.begin().await?;
+    lock_owned_session(&mut tx, user_id, session_id).await?;
+
+    if replace {
+        sqlx::query!("DELETE FROM session_tags WHERE session_id = $1", session_id)
+            .execute(&mut *tx)
+            .await?;
+    }
+    sqlx::query!(
+        r#"
+        INSERT INTO session_tags (session_id, tag)
+        SELECT $1, UNNEST($2::TEXT[])
+        ON CONFLICT DO NOTHING
+        "#,
+        session_id,
+        &requested
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let tags = load_session_tags(&mut tx, session_id).await?;
+    if tags.len() > MAX_TAGS_PER_SESSION {
+        return Err(AppError::Validation(format!(
+            "A session can have at most {} tags",
+            MAX_TAGS_PER_SESSION
+        )));
+    }
+    tx.commit().await?;
+
+    Ok(SessionTagsResponse { session_id, tags })
+}
+
+/// Locks the session row, so concurrent tag writes see each other's
+/// counts, or fails with `NotFound` if it isn't the user's.
+async fn lock_owned_session(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    user_id: Uuid,
+    session_id: Uuid,
+) -> Result<()> {
+    sqlx::query_scalar!(
+        "SELECT id FROM coding_sessions WHERE id = $1 AND user_id = $2 FOR UPDATE",
+        session_id,
+        user_id
+    )
+    .fetch_optional(&mut **tx)
+    .await?
+    .ok_or_else(|| AppError::NotFound("Session not found".to_string()))?;
+
+    Ok(())
+}
+
+async fn load_session_tags(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    session_id: Uuid,
+) -> Result<Vec<String>> {
+    let tags = sqlx::query_scalar!(
+        "SELECT tag FROM session_tags WHERE session_id = $1 ORDER BY tag",
+        session_id
+    )
+    .fetch_all(&mut **tx)
+    .await?;
+
+    Ok(tags)
+}
+
+fn aggregates_from_columns(
+    total_duration_ms: Option<i64>,
+    total_flow_time_ms: Option<i64>,
+    avg_flow_intensity: Option<f64>,
+    peak_flow_intensity: Option<f64>,
+    interruption_count: Option<i32>,
+) -> SessionAggregates {
+    SessionAggregates {
+        total_duration_ms: total_duration_ms.unwrap_or(0).max(0) as u64,
+        total_flow_time_ms: total_flow_time_ms.unwrap_or(0).max(0) as u64,
+        avg_flow_intensity: avg_flow_intensity.unwrap_or(0.0) as f32,
+        peak_flow_intensity: peak_flow_intensity.unwrap_or(0.0) as f32,
+        interruption_count: interruption_count.unwrap_or(0).max(0) as u32,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_end_session_aggregates_round_trip() {
+        let flow_states = [(0.5, Some(60_000)), (0.9, Some(120_000)), (0.7, None)];
+        let aggregates = SessionAggregates::from_flow_states(600_000, &flow_states, 2);
+
+        assert_eq!(aggregates.total_flow_time_ms, 180_000);
+        assert!((aggregates.avg_flow_intensity - 0.7).abs() < 1e-6);
+        assert_eq!(aggregates.peak_flow_intensity, 0.9);
+        assert_eq!(aggregates.interruption_count, 2);
+
+        // A repeated end reads back exactly what the first end stored
+        let stored = aggregates_from_columns(
+            Some(aggregates.total_duration_ms as i64),
+            Some(aggregates.total_flow_time_ms as i64),
+            Some(aggregates.avg_flow_intensity as f64),
+            Some(aggregates.peak_flow_intensity as f64),
+            Some(aggregates.interruption_count as i32),
+        );
+        assert_eq!(stored, aggregates);
+    }
+
+    #[test]
+    fn test_session_context_is_sanitized_before_persist() {
+        let sanitizer = Sanitizer::new(&crate::config::SanitizerConfig::default());
+        let environment = SessionEnvironment::from_stored(
+            serde_json::json!({ "cwd": "/home/dana/src", "API_KEY": "abc123", "monitor_count": 2 }),
+        );
+
+        let (project_path, environment_data) = sanitize_session_context(
+            &sanitizer,
+            Some("/home/dana/src/mindful-code"),
+            Some(&environment),
+        );
+
+        assert_eq!(project_path.as_deref(), Some("~/src/mindful-code"));
+        let environment_data = environment_data.unwrap();
+        assert_eq!(environment_data["cwd"], "~/src");
+        assert_eq!(environment_data["API_KEY"], "[REDACTED]");
+        assert_eq!(environment_data["monitor_count"], 2);
+        assert!(!environment_data.to_string().contains("dana"));
+    }
+
+    #[test]
+    fn test_end_session_without_flow_states() {
+        let aggregates = SessionAggregates::from_flow_states(30_000, &[], 0);
+
+        assert_eq!(aggregates.total_duration_ms, 30_000);
+        assert_eq!(aggregates.total_flow_time_ms, 0);
+        assert_eq!(aggregates.avg_flow_intensity, 0.0);
+        assert_eq!(aggregates.peak_flow_intensity, 0.0);
+    }
+}
@@ -0,0 +1,523 @@
+use axum::{
+    extract::{Path, Query, State},
+    Json,
+};
+use sqlx::PgConnection;
+use std::collections::{HashMap, HashSet};
+use tracing::{info, warn};
+use uuid::Uuid;
+use validator::Validate;
+
+use crate::{
+    error::{AppError, Result},
+    handlers::websocket::send_team_alert,
+    models::team::{
+        AddTeamMembersRequest, AddTeamMembersResponse, CreateTeamAlertRequest, CreateTeamRequest,
+        FlowSimilarityQuery, FlowSimilarityResponse, RemoveTeamMembersRequest,
+        RemoveTeamMembersResponse, SetTeamGoalRequest, Team, TeamGoalProgress, TeamRole,
+    },
+    services::{
+        flow_profile::load_flow_profile,
+        team_goals::{load_team_goal_progress, mark_goal_completed, sharing_team_ids},
+    },
+    state::AppState,
+    utils::auth::{require_registered, Claims},
+};
+
+pub async fn create_team(
+    State(state): State<AppState>,
+    claims: Claims,
+    Json(payload): Json<CreateTeamRequest>,
+) -> Result<Json<Team>> {
+    require_registered(&claims)?;
+    payload.validate().map_err(|e| {
+        AppError::Validation(format!("Invalid team: {}", e))
+    })?;
+
+    let mut tx = state.db.begin().await?;
+    let team = sqlx::query_as!(
+        Team,
+        r#"
+        INSERT INTO teams (name, owner_id)
+        VALUES ($1, $2)
+        RETURNING id, name, owner_id, created_at as "created_at!"
+        "#,
+        payload.name,
+        claims.user_id,
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        "INSERT INTO team_members (team_id, user_id, role) VALUES ($1, $2, $3)",
+        team.id,
+        claims.user_id,
+        TeamRole::Owner.as_str(),
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    info!("User {} created team {}", claims.user_id, team.id);
+
+    Ok(Json(team))
+}
+
+pub async fn create_alert(
+    State(state): State<AppState>,
+    claims: Claims,
+    Path(team_id): Path<Uuid>,
+    Json(payload): Json<CreateTeamAlertRequest>,
+) -> Result<Json<serde_json::Value>> {
+    require_registered(&claims)?;
+    payload.validate().map_err(|e| {
+        AppError::Validation(format!("Invalid team alert: {}", e))
+    })?;
+
+    {
+        let mut conn = state.db.acquire().await?;
+        require_team_role(&mut conn, claims.user_id, team_id, TeamRole::Manager).await?;
+    }
+
+    send_team_alert(&state, team_id, payload.alert_type.clone(), payload.data).await?;
+
+    Ok(Json(serde_json::json!({
+        "team_id": team_id,
+        "alert_type": payload.alert_type,
+    })))
+}
+
+pub async fn add_team_members(
+    State(state): State<AppState>,
+    claims: Claims,
+    Path(team_id): Path<Uuid>,
+    Json(payload): Json<AddTeamMembersRequest>,
+) -> Result<Json<AddTeamMembersResponse>> {
+    require_registered(&claims)?;
+    payload.validate().map_err(|e| {
+        AppError::Validation(format!("Invalid team members request: {}", e))
+    })?;
+
+    let mut tx = state.db.begin().await?;
+    let requester_role =
+        require_team_role(&mut tx, claims.user_id, team_id, TeamRole::Manager).await?;
+    if payload.members.iter().any(|member| member.role > requester_role) {
+        return Err(AppError::Authorization(
+            "Cannot grant a team role above your own".to_string(),
+        ));
+    }
+
+    let requested: Vec<Uuid> = payload.members.iter().map(|m| m.user_id).collect();
+    let known_users: HashSet<Uuid> = sqlx::query_scalar!(
+        "SELECT id FROM users WHERE id = ANY($1)",
+        &requested
+    )
+    .fetch_all(&mut *tx)
+    .await?
+    .into_iter()
+    .collect();
+
+    let unknown_users: Vec<String> = requested
+        .iter()
+        .filter(|user_id| !known_users.contains(user_id))
+        .map(|user_id| user_id.to_string())
+        .collect();
+    if !unknown_users.is_empty() {
+        return Err(AppError::NotFound(format!(
+            "Users not found: {}",
+            unknown_users.join(", ")
+        )));
+    }
+
+    let current_members = current_team_members(&mut tx, team_id).await?;
+    let (added, already_members) = partition_by_membership(requested, &current_members);
+
+    for user_id in &added {
+        let role = payload
+            .members
+            .iter()
+            .find(|m| m.user_id == *user_id)
+            .map(|m| m.role)
+            .unwrap_or_default();
+
+        sqlx::query!(
+            r#"
+            INSERT INTO team_members (team_id, user_id, role)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (team_id, user_id) DO NOTHING
+            "#,
+            team_id,
+            user_id,
+            role.as_str(),
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+
+    if !added.is_empty() {
+        info!("Added {} members to team {}", added.len(), team_id);
+        broadcast_membership_change(&state, team_id, "members_added", &added).await;
+    }
+
+    Ok(Json(AddTeamMembersResponse {
+        team_id,
+        added,
+        already_members,
+    }))
+}
+
+pub async fn remove_team_members(
+    State(state): State<AppState>,
+    claims: Claims,
+    Path(team_id): Path<Uuid>,
+    Json(payload): Json<RemoveTeamMembersRequest>,
+) -> Result<Json<RemoveTeamMembersResponse>> {
+    require_registered(&claims)?;
+    payload.validate().map_err(|e| {
+        AppError::Validation(format!("Invalid team members request: {}", e))
+    })?;
+
+    let mut tx = state.db.begin().await?;
+    let requester_role =
+        require_team_role(&mut tx, claims.user_id, team_id, TeamRole::Manager).await?;
+
+    let current_members = current_team_members(&mut tx, team_id).await?;
+    let (not_members, removed) = partition_by_membership(payload.user_ids, &current_members);
+    if removed.iter().any(|user_id| current_members[user_id] > requester_role) {
+        return Err(AppError::Authorization(
+            "Cannot remove members ranked above you".to_string(),
+        ));
+    }
+
+    sqlx::query!(
+        "DELETE FROM team_members WHERE team_id = $1 AND user_id = ANY($2)",
+        team_id,
+        &removed
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    if !removed.is_empty() {
+        info!("Removed {} members from team {}", removed.len(), team_id);
+        broadcast_membership_change(&state, team_id, "members_removed", &removed).await;
+    }
+
+    Ok(Json(RemoveTeamMembersResponse {
+        team_id,
+        removed,
+        not_members,
+    }))
+}
+
+pub async fn get_team_goals(
+    State(state): State<AppState>,
+    claims: Claims,
+    Path(team_id): Path<Uuid>,
+) -> Result<Json<Vec<TeamGoalProgress>>> {
+    require_registered(&claims)?;
+
+    let mut tx = state.db.begin().await?;
+    require_team_role(&mut tx, claims.user_id, team_id, TeamRole::Member).await?;
+    let progress = load_team_goal_progress(&mut tx, team_id, chrono::Utc::now()).await?;
+    tx.commit().await?;
+
+    Ok(Json(progress))
+}
+
+pub async fn set_team_goal(
+    State(state): State<AppState>,
+    claims: Claims,
+    Path(team_id): Path<Uuid>,
+    Json(payload): Json<SetTeamGoalRequest>,
+) -> Result<Json<TeamGoalProgress>> {
+    require_registered(&claims)?;
+    payload.validate().map_err(|e| {
+        AppError::Validation(format!("Invalid team goal: {}", e))
+    })?;
+
+    let mut tx = state.db.begin().await?;
+    require_team_role(&mut tx, claims.user_id, team_id, TeamRole::Manager).await?;
+
+    let goal_id = sqlx::query_scalar!(
+        r#"
+        INSERT INTO team_goals (team_id, metric, target_value, created_by)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (team_id, metric) DO UPDATE SET
+            target_value = EXCLUDED.target_value,
+            updated_at = NOW()
+        RETURNING id
+        "#,
+        team_id,
+        payload.metric.as_str(),
+        payload.target_value,
+        claims.user_id,
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    info!("Team {} goal {} set to {}", team_id, payload.metric.as_str(), payload.target_value);
+
+    // A lowered target may already be met
+    let progress = announce_completed_goals(&state, team_id)
+        .await?
+        .into_iter()
+        .find(|progress| progress.goal_id == goal_id)
+        .ok_or_else(|| AppError::Internal("Saved team goal not found".to_string()))?;
+
+    Ok(Json(progress))
+}
+
+/// Scores how alike two members' flow habits are, for pairing mentors.
+/// Managers only, and both members must share their data with the team;
+/// the profiles behind the score are never returned.
+pub async fn get_member_flow_similarity(
+    State(state): State<AppState>,
+    claims: Claims,
+    Path(team_id): Path<Uuid>,
+    Query(query): Query<FlowSimilarityQuery>,
+) -> Result<Json<FlowSimilarityResponse>> {
+    require_registered(&claims)?;
+    if query.member_a == query.member_b {
+        return Err(AppError::Validation(
+            "Choose two different members to compare".to_string(),
+        ));
+    }
+
+    let mut tx = state.db.begin().await?;
+    require_team_role(&mut tx, claims.user_id, team_id, TeamRole::Manager).await?;
+
+    let consent: HashMap<Uuid, bool> = sqlx::query!(
+        r#"
+        SELECT tm.user_id,
+               COALESCE(
+                   tm.data_sharing_consent = true
+                       AND COALESCE((u.privacy_settings->>'analytics_enabled')::BOOLEAN, true),
+                   false
+               ) as "consent!"
+        FROM team_members tm
+        JOIN users u ON u.id = tm.user_id
+        WHERE tm.team_id = $1 AND tm.user_id = ANY($2)
+        "#,
+        team_id,
+        &[query.member_a, query.member_b][..],
+    )
+    .fetch_all(&mut *tx)
+    .await?
+    .into_iter()
+    .map(|row| (row.user_id, row.consent))
+    .collect();
+
+    for member in [query.member_a, query.member_b] {
+        match consent.get(&member) {
+            None => {
+                return Err(AppError::NotFound(format!(
+                    "{} is not a member of this team",
+                    member
+                )))
+            }
+            Some(false) => {
+                return Err(AppError::Authorization(format!(
+                    "{} has not consented to sharing flow data with this team",
+                    member
+                )))
+            }
+            Some(true) => {}
+        }
+    }
+
+    let now = chrono::Utc::now();
+    let mut profiles = Vec::with_capacity(2);
+    for member in [query.member_a, query.member_b] {
+        let profile = load_flow_profile(&mut tx, member, now).await?.ok_or_else(|| {
+            AppError::NotFound(format!("Not enough flow history to profile {}", member))
+        })?;
+        profiles.push(profile);
+    }
+    tx.commit().await?;
+
+    Ok(Json(FlowSimilarityResponse {
+        team_id,
+        member_a: query.member_a,
+        member_b: query.member_b,
+        similarity: profiles[0].similarity(&profiles[1]),
+    }))
+}
+
+/// Re-checks the goals of every team the user shares flow data with, after
+/// their flow time changed. Failures are logged, not returned: the session
+/// end that triggered this has already been committed.
+pub async fn check_team_goals_for_user(state: &AppState, user_id: Uuid) {
+    let team_ids = match state.db.acquire().await {
+        Ok(mut conn) => sharing_team_ids(&mut conn, user_id).await,
+        Err(e) => Err(e.into()),
+    };
+
+    match team_ids {
+        Ok(team_ids) => {
+            for team_id in team_ids {
+                if let Err(e) = announce_completed_goals(state, team_id).await {
+                    warn!("Failed to check goals for team {}: {}", team_id, e);
+                }
+            }
+        }
+        Err(e) => warn!("Failed to look up team goals for user {}: {}", user_id, e),
+    }
+}
+
+/// Loads the team's goal progress and sends a `goal_completed` alert for
+/// each goal met for the first time this period.
+async fn announce_completed_goals(
+    state: &AppState,
+    team_id: Uuid,
+) -> Result<Vec<TeamGoalProgress>> {
+    let mut conn = state.db.acquire().await?;
+    let progress = load_team_goal_progress(&mut conn, team_id, chrono::Utc::now()).await?;
+
+    for goal in progress.iter().filter(|goal| goal.newly_completed) {
+        if !mark_goal_completed(&mut conn, goal).await? {
+            continue;
+        }
+
+        info!("Team {} met its {} goal", team_id, goal.metric.as_str());
+        let data = serde_json::to_value(goal).unwrap_or_default();
+        if let Err(e) = send_team_alert(state, team_id, "goal_completed".to_string(), data).await {
+            warn!("Failed to send goal completion for team {}: {}", team_id, e);
+        }
+    }
+
+    Ok(progress)
+}
+
+/// Fails unless the user holds at least `min_role` on the team, and returns
+/// the role they do hold.
+pub async fn require_team_role(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    team_id: Uuid,
+    min_role: TeamRole,
+) -> Result<TeamRole> {
+    let team = sqlx::query!("SELECT owner_id FROM teams WHERE id = $1", team_id)
+        .fetch_optional(&mut *conn)
+        .await?
+        .ok_or_else(|| AppError::NotFound("Team not found".to_string()))?;
+
+    let stored_role = sqlx::query_scalar!(
+        "SELECT role FROM team_members WHERE team_id = $1 AND user_id = $2",
+        team_id,
+        user_id
+    )
+    .fetch_optional(&mut *conn)
+    .await?;
+
+    match effective_team_role(team.owner_id, user_id, stored_role.as_deref()) {
+        Some(role) if role >= min_role => Ok(role),
+        Some(_) => Err(AppError::Authorization(format!(
+            "Requires the {} role on this team",
+            min_role.as_str()
+        ))),
+        None => Err(AppError::Authorization("Not a member of this team".to_string())),
+    }
+}
+
+async fn current_team_members(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    team_id: Uuid,
+) -> Result<HashMap<Uuid, TeamRole>> {
+    let members = sqlx::query!(
+        "SELECT user_id, role FROM team_members WHERE team_id = $1 FOR UPDATE",
+        team_id
+    )
+    .fetch_all(&mut **tx)
+    .await?;
+
+    Ok(members
+        .into_iter()
+        .map(|member| (member.user_id, TeamRole::from_db(&member.role).unwrap_or_default()))
+        .collect())
+}
+
+async fn broadcast_membership_change(
+    state: &AppState,
+    team_id: Uuid,
+    alert_type: &str,
+    user_ids: &[Uuid],
+) {
+    let data = serde_json::json!({ "user_ids": user_ids });
+    if let Err(e) = send_team_alert(state, team_id, alert_type.to_string(), data).await {
+        warn!("Failed to broadcast membership change for team {}: {}", team_id, e);
+    }
+}
+
+/// The team's `owner_id` is always an owner, whatever its membership row
+/// says; unrecognised stored roles get the least privilege.
+fn effective_team_role(owner_id: Uuid, user_id: Uuid, stored_role: Option<&str>) -> Option<TeamRole> {
+    if owner_id == user_id {
+        return Some(TeamRole::Owner);
+    }
+    stored_role.map(|role| TeamRole::from_db(role).unwrap_or_default())
+}
+
+/// Splits requested users into (not yet members, already members),
+/// dropping duplicates while keeping request order.
+fn partition_by_membership(
+    requested: impl IntoIterator<Item = Uuid>,
+    current_members: &HashMap<Uuid, TeamRole>,
+) -> (Vec<Uuid>, Vec<Uuid>) {
+    let mut seen = HashSet::new();
+    requested
+        .into_iter()
+        .filter(|user_id| seen.insert(*user_id))
+        .partition(|user_id| !current_members.contains_key(user_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_batch_is_idempotent() {
+        let existing = Uuid::new_v4();
+        let new_members = [Uuid::new_v4(), Uuid::new_v4()];
+        let current_members = HashMap::from([(existing, TeamRole::Member)]);
+
+        let (added, already_members) = partition_by_membership(
+            [new_members[0], existing, new_members[1], new_members[0]],
+            &current_members,
+        );
+        assert_eq!(added, new_members.to_vec());
+        assert_eq!(already_members, vec![existing]);
+
+        // Re-adding the same batch once applied changes nothing
+        let current_members: HashMap<Uuid, TeamRole> = current_members
+            .into_iter()
+            .chain(added.into_iter().map(|user_id| (user_id, TeamRole::Member)))
+            .collect();
+        let (added, already_members) =
+            partition_by_membership([new_members[0], new_members[1]], &current_members);
+        assert!(added.is_empty());
+        assert_eq!(already_members, new_members.to_vec());
+    }
+
+    #[test]
+    fn test_only_owners_and_managers_manage_members() {
+        let owner = Uuid::new_v4();
+        let requester = Uuid::new_v4();
+        let can_manage = |user_id, stored_role| {
+            effective_team_role(owner, user_id, stored_role)
+                .is_some_and(|role| role >= TeamRole::Manager)
+        };
+
+        assert!(can_manage(owner, None));
+        assert!(can_manage(owner, Some("member")));
+        assert!(can_manage(requester, Some("manager")));
+        assert!(can_manage(requester, Some("owner")));
+        assert!(!can_manage(requester, Some("member")));
+        assert!(!can_manage(requester, Some("admin")));
+        assert!(!can_manage(requester, None));
+    }
+}
@@ -1,24 +1,81 @@
 use axum::{
     extract::{
-        ws::{Message, WebSocket, WebSocketUpgrade},
+        ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade},
         Query, State,
     },
+    Json,
     http::StatusCode,
-    response::Response,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        Response,
+    },
+};
+use flate2::{write::DeflateEncoder, Compression};
+use futures::{
+    sink::SinkExt,
+    stream::{self, Stream, StreamExt},
 };
-use futures::{sink::SinkExt, stream::StreamExt};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    io::Write,
+    time::{Duration, Instant},
+};
 use tokio::sync::mpsc;
 use tracing::{debug, error, info, warn};
 use uuid::Uuid;
 
 use crate::{
+    config::{WebSocketCompressionConfig, WebSocketMalformedConfig},
     error::{AppError, Result},
+    handlers::{
+        auth::is_high_security,
+        flow::{get_flow_analytics, FlowAnalyticsQuery},
+        sessions::auto_end_idle_sessions,
+        teams::require_team_role,
+    },
+    models::{
+        flow::{FlowAnalytics, FlowStateResult, FocusModeStatus},
+        team::TeamRole,
+    },
+    services::{
+        encryption::PayloadCipher,
+        team_presence::{MemberPresence, PresenceDue, TeamPresence},
+    },
     state::AppState,
-    utils::auth::validate_jwt_token,
+    utils::{
+        auth::{validate_access_token, Claims},
+        date_range::{DateRange, DateRangeQuery},
+        response::ResponseFormat,
+    },
 };
 
+/// Current WebSocket protocol version spoken by the server.
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Oldest client protocol version the server still accepts.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+/// Close code sent when a client's hello names an unsupported version.
+pub const CLOSE_UNSUPPORTED_PROTOCOL: u16 = 4001;
+/// Close code sent after too many frames that couldn't be decoded.
+pub const CLOSE_TOO_MANY_MALFORMED: u16 = 4002;
+/// Close code sent when the client stopped answering pings.
+pub const CLOSE_STALE: u16 = 4003;
+/// Close code (RFC 6455 "internal error") sent when the connection failed
+/// on the server's side.
+pub const CLOSE_INTERNAL_ERROR: u16 = 1011;
+/// Close code (RFC 6455 "try again later") sent when the server shuts
+/// down; clients should reconnect after a backoff.
+pub const CLOSE_TRY_AGAIN_LATER: u16 = 1013;
+/// Connections with no pong for this long are closed as stale.
+const STALE_AFTER: Duration = Duration::from_secs(90);
+/// First byte of a binary frame on a compressing connection: the rest is
+/// the payload as-is.
+pub const FRAME_PLAIN: u8 = 0;
+/// First byte of a binary frame on a compressing connection: the rest is
+/// the payload raw-deflated (RFC 1951).
+pub const FRAME_DEFLATE: u8 = 1;
+
 #[derive(Debug, Deserialize)]
 pub struct WebSocketQuery {
     token: String,
@@ -45,20 +102,294 @@ pub enum WebSocketMessage {
     #[serde(rename = "notification")]
     Notification {
         title: String,
+        /// Sealed when `encryption` is set
         message: String,
         level: NotificationLevel,
         timestamp: i64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        encryption: Option<PayloadCipher>,
     },
     #[serde(rename = "team_alert")]
     TeamAlert {
         team_id: Uuid,
         alert_type: String,
+        /// A string holding the sealed JSON when `encryption` is set
         data: serde_json::Value,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        encryption: Option<PayloadCipher>,
+    },
+    #[serde(rename = "focus_mode_update")]
+    FocusModeUpdate {
+        enabled: bool,
+        started_at: Option<chrono::DateTime<chrono::Utc>>,
+    },
+    /// Sent to managers who subscribed to the team's dashboard
+    #[serde(rename = "team_presence")]
+    TeamPresence(TeamPresence),
+    #[serde(rename = "subscribe_team_dashboard")]
+    SubscribeTeamDashboard { team_id: Uuid },
+    #[serde(rename = "unsubscribe_team_dashboard")]
+    UnsubscribeTeamDashboard { team_id: Uuid },
+    /// Asks for the user's flow analytics over `range` to be pushed back
+    /// as an `analytics` message
+    #[serde(rename = "request_analytics")]
+    RequestAnalytics {
+        #[serde(default)]
+        range: DateRangeQuery,
+    },
+    #[serde(rename = "analytics")]
+    Analytics {
+        range: DateRange,
+        analytics: FlowAnalytics,
     },
     #[serde(rename = "system_message")]
     SystemMessage { message: String },
     #[serde(rename = "error")]
     Error { code: u16, message: String },
+    #[serde(rename = "hello")]
+    Hello {
+        protocol_version: u32,
+        client: String,
+        #[serde(default)]
+        encoding: WireEncoding,
+        #[serde(default)]
+        compression: Option<WireCompression>,
+    },
+    #[serde(rename = "welcome")]
+    Welcome {
+        protocol_version: u32,
+        capabilities: Vec<String>,
+        encoding: WireEncoding,
+        /// Absent when the client didn't ask or the server has it disabled
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        compression: Option<CompressionParams>,
+    },
+}
+
+/// How outbound messages are framed once the hello is answered. The
+/// welcome itself is always JSON; after it, a msgpack connection gets
+/// `Binary` frames, though a `Text` frame is always JSON and clients
+/// should accept both.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WireEncoding {
+    #[default]
+    Json,
+    Msgpack,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WireCompression {
+    Deflate,
+}
+
+/// Compression agreed in the welcome. From then on, outbound messages
+/// of at least `threshold_bytes` once encoded are sent deflated in a binary
+/// frame; smaller text frames are unchanged. Every binary frame starts
+/// with `FRAME_PLAIN` or `FRAME_DEFLATE`. Inbound frames are never
+/// compressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompressionParams {
+    pub algorithm: WireCompression,
+    pub threshold_bytes: usize,
+}
+
+/// Frames for the connection's writer task that must stay ordered
+/// relative to each other.
+enum Outbound {
+    Frame(Message),
+    Encoding(WireEncoding),
+    Compression(Option<CompressionParams>),
+}
+
+/// Queues a close frame; the writer sends it and then stops.
+fn send_close(control_tx: &mpsc::UnboundedSender<Outbound>, close_frame: CloseFrame<'static>) {
+    let _ = control_tx.send(Outbound::Frame(Message::Close(Some(close_frame))));
+}
+
+/// The close frame for a connection whose last pong was `since_pong` ago,
+/// if that makes it stale.
+pub fn stale_close_frame(since_pong: Duration) -> Option<CloseFrame<'static>> {
+    (since_pong > STALE_AFTER).then(|| CloseFrame {
+        code: CLOSE_STALE,
+        reason: format!("No pong for {}s", since_pong.as_secs()).into(),
+    })
+}
+
+/// Turns a broadcast (always JSON text, see `AppState::broadcast_to_user`)
+/// into the frame this connection expects.
+pub fn encode_outbound(json: String, encoding: WireEncoding) -> Message {
+    if encoding == WireEncoding::Msgpack {
+        let packed = serde_json::from_str::<serde_json::Value>(&json)
+            .ok()
+            .and_then(|value| rmp_serde::to_vec_named(&value).ok());
+        if let Some(bytes) = packed {
+            return Message::Binary(bytes);
+        }
+    }
+    Message::Text(json)
+}
+
+/// Applies the connection's negotiated compression to an encoded frame.
+/// Payloads that deflate doesn't shrink are sent uncompressed.
+pub fn compress_outbound(frame: Message, compression: Option<CompressionParams>) -> Message {
+    let Some(compression) = compression else {
+        return frame;
+    };
+
+    match frame {
+        Message::Text(text) if text.len() < compression.threshold_bytes => Message::Text(text),
+        Message::Text(text) => match deflate_frame(text.as_bytes()) {
+            Some(deflated) => Message::Binary(deflated),
+            None => Message::Text(text),
+        },
+        Message::Binary(bytes) => {
+            let deflated = (bytes.len() >= compression.threshold_bytes)
+                .then(|| deflate_frame(&bytes))
+                .flatten();
+            Message::Binary(deflated.unwrap_or_else(|| [&[FRAME_PLAIN], bytes.as_slice()].concat()))
+        }
+        other => other,
+    }
+}
+
+fn deflate_frame(payload: &[u8]) -> Option<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(vec![FRAME_DEFLATE], Compression::default());
+    encoder.write_all(payload).ok()?;
+    let frame = encoder.finish().ok()?;
+    (frame.len() < payload.len()).then_some(frame)
+}
+
+/// Text frames are JSON and binary frames msgpack, whatever was negotiated.
+pub fn decode_inbound(frame: &Message) -> Result<WebSocketMessage> {
+    let decoded = match frame {
+        Message::Text(text) => serde_json::from_str(text).map_err(|e| e.to_string()),
+        Message::Binary(bytes) => rmp_serde::from_slice(bytes).map_err(|e| e.to_string()),
+        _ => Err("unsupported frame type".to_string()),
+    };
+    decoded.map_err(|e| {
+        AppError::BadRequest(format!(
+            "Invalid WebSocket message: {}. Messages are JSON objects with a \"type\" field",
+            e
+        ))
+    })
+}
+
+/// The error frame answering a message that failed. Client mistakes get a
+/// 4xx with the reason; anything else is reported as a server error
+/// without details.
+pub fn error_reply(error: &AppError) -> WebSocketMessage {
+    match error {
+        AppError::BadRequest(message) | AppError::Validation(message) => WebSocketMessage::Error {
+            code: 400,
+            message: message.clone(),
+        },
+        AppError::Authorization(message) => WebSocketMessage::Error {
+            code: 403,
+            message: message.clone(),
+        },
+        AppError::NotFound(message) => WebSocketMessage::Error {
+            code: 404,
+            message: message.clone(),
+        },
+        AppError::RateLimit => WebSocketMessage::Error {
+            code: 429,
+            message: "Rate limit exceeded".to_string(),
+        },
+        _ => WebSocketMessage::Error {
+            code: 500,
+            message: "Internal server error".to_string(),
+        },
+    }
+}
+
+/// Malformed frames a connection has sent within the current window.
+pub struct MalformedFrames {
+    limit: u32,
+    window: Duration,
+    count: u32,
+    window_start: Instant,
+}
+
+impl MalformedFrames {
+    pub fn new(config: &WebSocketMalformedConfig, now: Instant) -> Self {
+        Self {
+            limit: config.max_messages,
+            window: Duration::from_secs(config.window_secs),
+            count: 0,
+            window_start: now,
+        }
+    }
+
+    /// Counts a malformed frame. Returns true once the client has sent more
+    /// than the limit within one window and should be disconnected.
+    pub fn record(&mut self, now: Instant) -> bool {
+        if now.duration_since(self.window_start) >= self.window {
+            self.count = 0;
+            self.window_start = now;
+        }
+        self.count += 1;
+        self.count > self.limit
+    }
+}
+
+impl WebSocketMessage {
+    /// Protocol version that introduced this message type. Inbound messages
+    /// newer than the connection's negotiated version are rejected.
+    pub fn min_protocol_version(&self) -> u32 {
+        1
+    }
+}
+
+fn server_capabilities() -> Vec<String> {
+    vec![
+        "flow_state_update".to_string(),
+        "session_update".to_string(),
+        "notification".to_string(),
+        "team_alert".to_string(),
+        "focus_mode_update".to_string(),
+        "team_presence".to_string(),
+        "analytics".to_string(),
+    ]
+}
+
+/// The compression to offer a client that asked for `requested`.
+pub fn negotiate_compression(
+    requested: Option<WireCompression>,
+    config: &WebSocketCompressionConfig,
+) -> Option<CompressionParams> {
+    let algorithm = requested.filter(|_| config.enabled)?;
+    Some(CompressionParams {
+        algorithm,
+        threshold_bytes: config.threshold_bytes,
+    })
+}
+
+/// Resolves a client hello to the version both sides will speak, or the
+/// close frame to send when the client is too old to be served.
+pub fn negotiate_protocol(
+    requested_version: u32,
+    encoding: WireEncoding,
+    compression: Option<CompressionParams>,
+) -> std::result::Result<WebSocketMessage, CloseFrame<'static>> {
+    if requested_version < MIN_PROTOCOL_VERSION {
+        return Err(CloseFrame {
+            code: CLOSE_UNSUPPORTED_PROTOCOL,
+            reason: format!(
+                "Unsupported protocol version {}, server supports {}-{}",
+                requested_version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            )
+            .into(),
+        });
+    }
+
+    Ok(WebSocketMessage::Welcome {
+        protocol_version: requested_version.min(PROTOCOL_VERSION),
+        capabilities: server_capabilities(),
+        encoding,
+        compression,
+    })
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -75,27 +406,57 @@ pub async fn websocket_handler(
     State(state): State<AppState>,
 ) -> Result<Response> {
     // Validate JWT token
-    let claims = validate_jwt_token(&params.token, &state.config.jwt_secret)
-        .map_err(|e| AppError::Authentication(format!("Invalid WebSocket token: {}", e)))?;
+    let claims = validate_access_token(
+        &params.token,
+        &state.config.jwt_secret,
+        state.config.jwt_algorithm,
+    )
+    .map_err(|e| AppError::Authentication(format!("Invalid WebSocket token: {}", e)))?;
 
     info!("WebSocket connection established for user {}", claims.user_id);
 
-    Ok(ws.on_upgrade(move |socket| websocket_connection(socket, claims.user_id, state)))
+    Ok(ws.on_upgrade(move |socket| websocket_connection(socket, claims, state)))
 }
 
-async fn websocket_connection(socket: WebSocket, user_id: Uuid, state: AppState) {
+async fn websocket_connection(socket: WebSocket, claims: Claims, state: AppState) {
+    let user_id = claims.user_id;
+    let is_admin = claims.has_admin_access();
     let (mut sender, mut receiver) = socket.split();
     
     // Create a channel for sending messages to this WebSocket
     let (tx, mut rx) = mpsc::unbounded_channel::<String>();
+    // Control frames (welcome, close) and encoding switches bypass the
+    // shared text broadcast channel
+    let (control_tx, mut control_rx) = mpsc::unbounded_channel::<Outbound>();
     
-    // Register this connection
-    state.add_websocket_connection(user_id, tx);
+    // Register this connection; replies to its own requests go on `tx`
+    // directly, since a newer connection for the user takes over broadcasts
+    state.add_websocket_connection(user_id, tx.clone(), is_admin);
     
     // Spawn task to handle outgoing messages
-    let sender_task = tokio::spawn(async move {
-        while let Some(msg) = rx.recv().await {
-            if sender.send(Message::Text(msg)).await.is_err() {
+    let mut sender_task = tokio::spawn(async move {
+        let mut encoding = WireEncoding::Json;
+        let mut compression = None;
+        loop {
+            let outgoing = tokio::select! {
+                Some(msg) = rx.recv() => compress_outbound(encode_outbound(msg, encoding), compression),
+                control = control_rx.recv() => match control {
+                    Some(Outbound::Frame(frame)) => frame,
+                    Some(Outbound::Encoding(negotiated)) => {
+                        encoding = negotiated;
+                        continue;
+                    }
+                    Some(Outbound::Compression(negotiated)) => {
+                        compression = negotiated;
+                        continue;
+                    }
+                    // The handler lets go of the control channel once the
+                    // connection is over
+                    None => break,
+                },
+            };
+            let is_close = matches!(outgoing, Message::Close(_));
+            if sender.send(outgoing).await.is_err() || is_close {
                 break;
             }
         }
@@ -104,21 +465,68 @@ async fn websocket_connection(socket: WebSocket, user_id: Uuid, state: AppState)
     // Handle incoming messages
     let mut ping_interval = tokio::time::interval(tokio::time::Duration::from_secs(30));
     let mut last_pong = tokio::time::Instant::now();
+    // Set by the client's hello; clients that skip it are treated as version 1
+    let mut protocol_version: Option<u32> = None;
+    let mut malformed = MalformedFrames::new(&state.config.websocket_malformed, Instant::now());
     
     loop {
         tokio::select! {
             // Handle incoming WebSocket messages
             msg = receiver.next() => {
                 match msg {
-                    Some(Ok(Message::Text(text))) => {
-                        if let Err(e) = handle_websocket_message(&text, user_id, &state).await {
-                            error!("Error handling WebSocket message: {}", e);
-                            let error_msg = WebSocketMessage::Error {
-                                code: 500,
-                                message: "Internal server error".to_string(),
-                            };
-                            if let Ok(error_json) = serde_json::to_string(&error_msg) {
-                                let _ = state.broadcast_to_user(user_id, error_json).await;
+                    Some(Ok(frame @ (Message::Text(_) | Message::Binary(_)))) => {
+                        let decoded = decode_inbound(&frame);
+                        let version = match protocol_version {
+                            Some(version) => version,
+                            None => {
+                                if let Ok(WebSocketMessage::Hello { protocol_version: requested, client, encoding, compression }) = &decoded {
+                                    let compression = negotiate_compression(*compression, &state.config.websocket_compression);
+                                    match negotiate_protocol(*requested, *encoding, compression) {
+                                        Ok(welcome) => {
+                                            if let WebSocketMessage::Welcome { protocol_version: negotiated, .. } = &welcome {
+                                                info!("User {} negotiated WebSocket protocol v{} with {:?} encoding ({})", user_id, negotiated, encoding, client);
+                                                protocol_version = Some(*negotiated);
+                                            }
+                                            if let Ok(welcome_json) = serde_json::to_string(&welcome) {
+                                                let _ = control_tx.send(Outbound::Frame(Message::Text(welcome_json)));
+                                            }
+                                            let _ = control_tx.send(Outbound::Encoding(*encoding));
+                                            let _ = control_tx.send(Outbound::Compression(compression));
+                                        }
+                                        Err(close_frame) => {
+                                            warn!("Rejecting WebSocket client {} for user {}: {}", client, user_id, close_frame.reason);
+                                            send_close(&control_tx, close_frame);
+                                            break;
+                                        }
+                                    }
+                                    continue;
+                                }
+                                *protocol_version.insert(MIN_PROTOCOL_VERSION)
+                            }
+                        };
+
+                        let handled = match decoded {
+                            Ok(ws_message) => handle_websocket_message(ws_message, &claims, version, &state, &tx).await,
+                            Err(e) => {
+                                if malformed.record(Instant::now()) {
+                                    warn!("Disconnecting user {} after repeated malformed WebSocket messages", user_id);
+                                    send_close(&control_tx, CloseFrame {
+                                        code: CLOSE_TOO_MANY_MALFORMED,
+                                        reason: "Too many malformed messages".into(),
+                                    });
+                                    break;
+                                }
+                                Err(e)
+                            }
+                        };
+                        if let Err(e) = handled {
+                            let reply = error_reply(&e);
+                            match &reply {
+                                WebSocketMessage::Error { code: 400..=499, .. } => debug!("Rejected WebSocket message from user {}: {}", user_id, e),
+                                _ => error!("Error handling WebSocket message: {}", e),
+                            }
+                            if let Ok(error_json) = serde_json::to_string(&reply) {
+                                let _ = tx.send(error_json);
                             }
                         }
                     }
@@ -132,6 +540,11 @@ async fn websocket_connection(socket: WebSocket, user_id: Uuid, state: AppState)
                     }
                     Some(Err(e)) => {
                         warn!("WebSocket error for user {}: {}", user_id, e);
+                        // Best effort: the socket may already be unusable
+                        send_close(&control_tx, CloseFrame {
+                            code: CLOSE_INTERNAL_ERROR,
+                            reason: "Connection error".into(),
+                        });
                         break;
                     }
                     None => {
@@ -139,8 +552,8 @@ async fn websocket_connection(socket: WebSocket, user_id: Uuid, state: AppState)
                         break;
                     }
                     _ => {
-                        // Handle other message types (binary, etc.)
-                        debug!("Received non-text WebSocket message from user {}", user_id);
+                        // Pings are answered by axum itself
+                        debug!("Received control frame from user {}", user_id);
                     }
                 }
             }
@@ -151,42 +564,142 @@ async fn websocket_connection(socket: WebSocket, user_id: Uuid, state: AppState)
                     timestamp: chrono::Utc::now().timestamp_millis(),
                 };
                 if let Ok(ping_json) = serde_json::to_string(&ping_msg) {
-                    let _ = state.broadcast_to_user(user_id, ping_json).await;
+                    let _ = tx.send(ping_json);
                 }
                 
                 // Check if connection is stale
-                if last_pong.elapsed() > tokio::time::Duration::from_secs(90) {
+                if let Some(close_frame) = stale_close_frame(last_pong.elapsed()) {
                     warn!("WebSocket connection stale for user {}, disconnecting", user_id);
+                    send_close(&control_tx, close_frame);
                     break;
                 }
             }
+
+            _ = state.readiness.shutdown_started() => {
+                info!("Closing WebSocket for user {} for shutdown", user_id);
+                send_close(&control_tx, CloseFrame {
+                    code: CLOSE_TRY_AGAIN_LATER,
+                    reason: "Server shutting down, reconnect shortly".into(),
+                });
+                break;
+            }
         }
     }
     
-    // Cleanup
-    sender_task.abort();
-    state.remove_websocket_connection(user_id);
+    // Cleanup: dropping both senders lets the writer flush any queued close
+    // frame and exit; abort it if the peer stops reading
+    drop(tx);
+    drop(control_tx);
+    if tokio::time::timeout(tokio::time::Duration::from_millis(100), &mut sender_task)
+        .await
+        .is_err()
+    {
+        sender_task.abort();
+        let _ = sender_task.await;
+    }
+    // The writer is gone, so only this connection's own registration reads
+    // as closed; a newer WebSocket or event stream for the user keeps
+    // theirs, and their dashboards
+    if state.remove_closed_websocket_connection(user_id) {
+        state.team_dashboards.unsubscribe_all(user_id);
+    }
     info!("WebSocket connection cleaned up for user {}", user_id);
 }
 
-async fn handle_websocket_message(
-    message: &str,
+/// Receiving end of an event-stream client's registration; dropped with
+/// the response body when the client disconnects.
+struct EventSubscription {
+    receiver: mpsc::UnboundedReceiver<String>,
+    state: AppState,
     user_id: Uuid,
+}
+
+impl Drop for EventSubscription {
+    fn drop(&mut self) {
+        self.receiver.close();
+        self.state.remove_closed_websocket_connection(self.user_id);
+        info!("Event stream cleaned up for user {}", self.user_id);
+    }
+}
+
+/// Wraps a broadcast as an SSE event named after its message type.
+pub fn encode_event(json: String) -> Event {
+    let event_type = serde_json::from_str::<serde_json::Value>(&json)
+        .ok()
+        .and_then(|value| value.get("type")?.as_str().map(str::to_string));
+
+    let event = match event_type {
+        Some(event_type) => Event::default().event(event_type),
+        None => Event::default(),
+    };
+    event.data(json)
+}
+
+/// Server-sent events alternative to `/ws` for clients that can't hold a
+/// WebSocket open. Takes over the user's broadcast channel the same way a
+/// WebSocket connection does; there is no inbound side, so no hello and
+/// always JSON.
+pub async fn event_stream_handler(
+    State(state): State<AppState>,
+    claims: Claims,
+) -> Sse<impl Stream<Item = std::result::Result<Event, Infallible>>> {
+    let (tx, rx) = mpsc::unbounded_channel::<String>();
+    state.add_websocket_connection(claims.user_id, tx, claims.has_admin_access());
+    info!("Event stream established for user {}", claims.user_id);
+
+    let subscription = EventSubscription {
+        receiver: rx,
+        state,
+        user_id: claims.user_id,
+    };
+    let events = stream::unfold(subscription, |mut subscription| async move {
+        let json = subscription.receiver.recv().await?;
+        Some((Ok(encode_event(json)), subscription))
+    });
+
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+/// Handles a decoded client message. Replies go to `reply`, the requesting
+/// connection, rather than to whichever connection the user registered last.
+pub async fn handle_websocket_message(
+    ws_message: WebSocketMessage,
+    claims: &Claims,
+    protocol_version: u32,
     state: &AppState,
+    reply: &mpsc::UnboundedSender<String>,
 ) -> Result<()> {
-    let ws_message: WebSocketMessage = serde_json::from_str(message)
-        .map_err(|e| AppError::BadRequest(format!("Invalid WebSocket message: {}", e)))?;
+    let user_id = claims.user_id;
+    if ws_message.min_protocol_version() > protocol_version {
+        return Err(AppError::BadRequest(format!(
+            "Message requires protocol v{}, connection negotiated v{}",
+            ws_message.min_protocol_version(),
+            protocol_version
+        )));
+    }
 
     match ws_message {
         WebSocketMessage::Ping { timestamp } => {
             let pong_msg = WebSocketMessage::Pong { timestamp };
             let pong_json = serde_json::to_string(&pong_msg)
                 .map_err(|e| AppError::Internal(format!("Failed to serialize pong: {}", e)))?;
-            state.broadcast_to_user(user_id, pong_json).await;
+            let _ = reply.send(pong_json);
         }
         WebSocketMessage::Pong { .. } => {
             debug!("Received pong from user {}", user_id);
         }
+        WebSocketMessage::Hello { .. } => {
+            debug!("Ignoring repeated hello from user {}", user_id);
+        }
+        WebSocketMessage::SubscribeTeamDashboard { team_id } => {
+            subscribe_team_dashboard(state, user_id, team_id).await?;
+        }
+        WebSocketMessage::UnsubscribeTeamDashboard { team_id } => {
+            state.team_dashboards.unsubscribe(team_id, user_id);
+        }
+        WebSocketMessage::RequestAnalytics { range } => {
+            push_analytics(state, claims, range, reply).await?;
+        }
         _ => {
             debug!("Received WebSocket message from user {}: {:?}", user_id, ws_message);
         }
@@ -229,6 +742,8 @@ pub async fn broadcast_session_update(
     }
 }
 
+/// Sends a notification unless the user has focus mode on, in which case
+/// only errors get through.
 pub async fn send_notification(
     state: &AppState,
     user_id: Uuid,
@@ -236,11 +751,23 @@ pub async fn send_notification(
     message: String,
     level: NotificationLevel,
 ) {
+    if !matches!(level, NotificationLevel::Error) && state.focus_modes.is_active(user_id) {
+        debug!("Suppressed notification '{}' for user {} in focus mode", title, user_id);
+        return;
+    }
+
+    let (message, encryption) = match seal_for_user(state, user_id, message).await {
+        Some(sealed) => sealed,
+        // Still let them know something happened
+        None => ("Sign in again to read this notification".to_string(), None),
+    };
+
     let notification = WebSocketMessage::Notification {
         title,
         message,
         level,
         timestamp: chrono::Utc::now().timestamp_millis(),
+        encryption,
     };
 
     if let Ok(json) = serde_json::to_string(&notification) {
@@ -248,6 +775,77 @@ pub async fn send_notification(
     }
 }
 
+/// Suggests a break after a long flow stretch. With focus mode on, the
+/// reminder is held until the stretch ends.
+pub async fn send_break_reminder(state: &AppState, user_id: Uuid, flow_minutes: u64) {
+    let reminder = WebSocketMessage::Notification {
+        title: "Time for a break".to_string(),
+        message: format!(
+            "You've been in flow for {} minutes. A short break now helps the next stretch",
+            flow_minutes
+        ),
+        level: NotificationLevel::Info,
+        timestamp: chrono::Utc::now().timestamp_millis(),
+        encryption: None,
+    };
+
+    let Ok(json) = serde_json::to_string(&reminder) else {
+        return;
+    };
+    if state.focus_modes.hold_break_reminder(user_id, json.clone()) {
+        debug!("Holding break reminder for user {} until flow ends", user_id);
+        return;
+    }
+    state.broadcast_to_user(user_id, json).await;
+}
+
+/// Suggests a break after a sustained focus dip. Not sent in focus mode,
+/// where the user has asked not to be disturbed.
+pub async fn send_focus_dip_notice(state: &AppState, user_id: Uuid) {
+    if state.focus_modes.is_active(user_id) {
+        return;
+    }
+
+    let notice = WebSocketMessage::Notification {
+        title: "Your focus dipped".to_string(),
+        message: "Your focus dipped — maybe time for a break".to_string(),
+        level: NotificationLevel::Info,
+        timestamp: chrono::Utc::now().timestamp_millis(),
+        encryption: None,
+    };
+    if let Ok(json) = serde_json::to_string(&notice) {
+        state.broadcast_to_user(user_id, json).await;
+    }
+}
+
+/// Feeds the latest flow state to focus mode, delivering a held break
+/// reminder once the flow stretch it was waiting on ends.
+pub async fn record_focus_flow(state: &AppState, user_id: Uuid, in_flow: bool) {
+    if let Some(reminder) = state.focus_modes.record_flow(user_id, in_flow) {
+        state.broadcast_to_user(user_id, reminder).await;
+    }
+}
+
+/// Switches focus mode and tells the user's other clients. Turning it off
+/// releases a held break reminder.
+pub async fn set_focus_mode(state: &AppState, user_id: Uuid, enabled: bool) -> FocusModeStatus {
+    let started_at = if enabled {
+        Some(state.focus_modes.enable(user_id, chrono::Utc::now()))
+    } else {
+        if let Some(reminder) = state.focus_modes.disable(user_id) {
+            state.broadcast_to_user(user_id, reminder).await;
+        }
+        None
+    };
+
+    let update = WebSocketMessage::FocusModeUpdate { enabled, started_at };
+    if let Ok(json) = serde_json::to_string(&update) {
+        state.broadcast_to_user(user_id, json).await;
+    }
+
+    FocusModeStatus { enabled, started_at }
+}
+
 pub async fn send_team_alert(
     state: &AppState,
     team_id: Uuid,
@@ -262,18 +860,41 @@ pub async fn send_team_alert(
     .fetch_all(&state.db)
     .await?;
 
+    let data_json = data.to_string();
     let alert = WebSocketMessage::TeamAlert {
         team_id,
         alert_type: alert_type.clone(),
         data,
+        encryption: None,
     };
 
     let json = serde_json::to_string(&alert)
         .map_err(|e| AppError::Internal(format!("Failed to serialize team alert: {}", e)))?;
 
-    // Broadcast to all team members
-    for member in team_members {
-        state.broadcast_to_user(member.user_id, json.clone()).await;
+    // Broadcast to all team members, sealed for those who need it
+    for member in &team_members {
+        let json = match seal_for_user(state, member.user_id, data_json.clone()).await {
+            Some((_, None)) => json.clone(),
+            Some((sealed, encryption)) => {
+                let alert = WebSocketMessage::TeamAlert {
+                    team_id,
+                    alert_type: alert_type.clone(),
+                    data: serde_json::Value::String(sealed),
+                    encryption,
+                };
+                serde_json::to_string(&alert).map_err(|e| {
+                    AppError::Internal(format!("Failed to serialize team alert: {}", e))
+                })?
+            }
+            None => {
+                debug!(
+                    "Withholding team alert from user {} until they sign in",
+                    member.user_id
+                );
+                continue;
+            }
+        };
+        state.broadcast_to_user(member.user_id, json).await;
     }
 
     info!("Team alert '{}' sent to {} members of team {}", 
@@ -282,6 +903,209 @@ pub async fn send_team_alert(
     Ok(())
 }
 
+/// Subscribes a manager's connection to the team's shared dashboard and
+/// sends it the presence seen so far. Closing the connection ends the
+/// subscription.
+pub async fn subscribe_team_dashboard(
+    state: &AppState,
+    user_id: Uuid,
+    team_id: Uuid,
+) -> Result<()> {
+    let mut conn = state.db.acquire().await?;
+    require_team_role(&mut conn, user_id, team_id, TeamRole::Manager).await?;
+
+    state.team_dashboards.subscribe(team_id, user_id);
+    info!("User {} opened the dashboard for team {}", user_id, team_id);
+
+    if let Some(presence) = state.team_dashboards.snapshot(team_id, Instant::now()) {
+        let json = serde_json::to_string(&WebSocketMessage::TeamPresence(presence))
+            .map_err(|e| AppError::Internal(format!("Failed to serialize team presence: {}", e)))?;
+        state.broadcast_to_user(user_id, json).await;
+    }
+    Ok(())
+}
+
+/// Computes the user's flow analytics as `GET /api/flow/analytics` would
+/// and sends them to `reply`, the connection that asked, so dashboards can
+/// refresh without polling. Requests beyond `analytics_refreshes_per_minute`
+/// are refused before any query runs.
+pub async fn push_analytics(
+    state: &AppState,
+    claims: &Claims,
+    range: DateRangeQuery,
+    reply: &mpsc::UnboundedSender<String>,
+) -> Result<()> {
+    if !state
+        .analytics_refreshes
+        .check_rate_limit(&claims.user_id.to_string())
+    {
+        return Err(AppError::RateLimit);
+    }
+
+    // Resolved once here, so the window reported is exactly the one queried
+    let range = range.resolve(chrono::Utc::now(), 30)?;
+    let analytics = get_flow_analytics(
+        State(state.clone()),
+        claims.clone(),
+        ResponseFormat::default(),
+        Json(FlowAnalyticsQuery {
+            range: DateRangeQuery {
+                from: Some(range.from),
+                to: Some(range.to),
+                ..DateRangeQuery::default()
+            },
+            min_data_quality: None,
+            environment: Default::default(),
+            granularity: Default::default(),
+        }),
+    )
+    .await?
+    .into_data()
+    .ok_or_else(|| AppError::Internal("Analytics push was not modified".to_string()))?;
+
+    let json = serde_json::to_string(&WebSocketMessage::Analytics { range, analytics })
+        .map_err(|e| AppError::Internal(format!("Failed to serialize analytics: {}", e)))?;
+    if reply.send(json).is_err() {
+        debug!("Analytics for user {} dropped: connection closed", claims.user_id);
+    }
+    Ok(())
+}
+
+/// Puts a member's latest flow state on the dashboards of the teams they
+/// share their data with, and takes them off those they've stopped sharing
+/// with. Only queries the database while some dashboard is open.
+pub async fn publish_team_presence(state: &AppState, user_id: Uuid, result: &FlowStateResult) {
+    let watched = state.team_dashboards.watched_teams();
+    if watched.is_empty() {
+        return;
+    }
+
+    let memberships = sqlx::query!(
+        r#"
+        SELECT tm.team_id,
+               COALESCE(
+                   tm.data_sharing_consent = true
+                       AND COALESCE((u.privacy_settings->>'analytics_enabled')::BOOLEAN, true),
+                   false
+               ) as "shares!"
+        FROM team_members tm
+        JOIN users u ON u.id = tm.user_id
+        WHERE tm.user_id = $1 AND tm.team_id = ANY($2)
+        "#,
+        user_id,
+        &watched[..],
+    )
+    .fetch_all(&state.db)
+    .await;
+    let memberships = match memberships {
+        Ok(memberships) => memberships,
+        Err(e) => {
+            warn!("Failed to load team sharing for user {}: {}", user_id, e);
+            return;
+        }
+    };
+
+    let presence = MemberPresence {
+        user_id,
+        is_in_flow: result.is_in_flow,
+        flow_intensity: result.flow_intensity,
+        updated_at: chrono::Utc::now(),
+    };
+    for membership in memberships {
+        let team_id = membership.team_id;
+        let shared = membership.shares.then(|| presence.clone());
+        match state
+            .team_dashboards
+            .record(team_id, user_id, shared, Instant::now())
+        {
+            PresenceDue::Now => send_team_presence(state, team_id).await,
+            PresenceDue::After(delay) => {
+                let state = state.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    send_team_presence(&state, team_id).await;
+                });
+            }
+            PresenceDue::Scheduled | PresenceDue::Never => {}
+        }
+    }
+}
+
+/// Sends the team's presence to its dashboards. Each subscriber's manager
+/// role is checked again first, so someone demoted or removed from the team
+/// since subscribing is dropped instead of being sent the update.
+async fn send_team_presence(state: &AppState, team_id: Uuid) {
+    let Some((presence, subscribers)) = state.team_dashboards.take_update(team_id, Instant::now())
+    else {
+        return;
+    };
+    let Ok(json) = serde_json::to_string(&WebSocketMessage::TeamPresence(presence)) else {
+        return;
+    };
+    let mut conn = match state.db.acquire().await {
+        Ok(conn) => conn,
+        Err(e) => {
+            warn!("Failed to check dashboard access for team {}: {}", team_id, e);
+            return;
+        }
+    };
+    for subscriber in subscribers {
+        match require_team_role(&mut conn, subscriber, team_id, TeamRole::Manager).await {
+            Ok(_) => {
+                state.broadcast_to_user(subscriber, json.clone()).await;
+            }
+            Err(AppError::Authorization(_) | AppError::NotFound(_)) => {
+                state.team_dashboards.unsubscribe(team_id, subscriber);
+                info!(
+                    "User {} lost access to the dashboard for team {}",
+                    subscriber, team_id
+                );
+            }
+            Err(e) => warn!(
+                "Failed to check dashboard access of user {} for team {}: {}",
+                subscriber, team_id, e
+            ),
+        }
+    }
+}
+
+/// Seals a sensitive payload for the user when payload encryption is on and
+/// they are on a high-security encryption level. `None` means it has to be
+/// withheld: no key is held for them, as after a restart until they sign in
+/// again, or sealing failed.
+async fn seal_for_user(
+    state: &AppState,
+    user_id: Uuid,
+    plaintext: String,
+) -> Option<(String, Option<PayloadCipher>)> {
+    if !state.config.websocket_payload_encryption {
+        return Some((plaintext, None));
+    }
+
+    match state.payload_keys.seal(user_id, &plaintext) {
+        Some(Ok(sealed)) => Some((sealed, Some(PayloadCipher::Aes256Gcm))),
+        Some(Err(e)) => {
+            warn!(
+                "Failed to seal WebSocket payload for user {}: {}",
+                user_id, e
+            );
+            None
+        }
+        // Keys are only held for high-security users, so anyone else is
+        // sent cleartext
+        None => match is_high_security(state, user_id).await {
+            Ok(high_security) => (!high_security).then_some((plaintext, None)),
+            Err(e) => {
+                warn!(
+                    "Failed to load privacy settings for user {}: {}",
+                    user_id, e
+                );
+                None
+            }
+        },
+    }
+}
+
 pub async fn send_system_message(state: &AppState, user_id: Uuid, message: String) {
     let sys_message = WebSocketMessage::SystemMessage { message };
 
@@ -290,6 +1114,20 @@ pub async fn send_system_message(state: &AppState, user_id: Uuid, message: Strin
     }
 }
 
+pub async fn send_admin_alert(state: &AppState, title: String, message: String, level: NotificationLevel) {
+    let alert = WebSocketMessage::Notification {
+        title,
+        message,
+        level,
+        timestamp: chrono::Utc::now().timestamp_millis(),
+        encryption: None,
+    };
+
+    if let Ok(json) = serde_json::to_string(&alert) {
+        state.broadcast_to_admins(json).await;
+    }
+}
+
 // WebSocket metrics and monitoring
 pub struct WebSocketMetrics {
     pub active_connections: usize,
@@ -322,10 +1160,21 @@ pub async fn monitor_websocket_performance(state: AppState) {
         debug!("WebSocket metrics: {} active connections, {} active sessions",
                active_connections, active_sessions);
         
-        // Cleanup idle sessions
-        state.cleanup_idle_sessions(30); // 30 minutes timeout
+        // End sessions that have gone idle
+        let auto_ended = auto_end_idle_sessions(&state).await;
+        if auto_ended > 0 {
+            info!("Auto-ended {} idle sessions", auto_ended);
+        }
+        state.cleanup_idle_flow_detect_slots();
+        state.analytics_refreshes.cleanup_old_entries();
+        for team_id in state.team_dashboards.expire_idle(Instant::now()) {
+            send_team_presence(&state, team_id).await;
+        }
+        state.cleanup_idle_flow_engines(Duration::from_secs(
+            state.config.session_idle_timeout_minutes as u64 * 60,
+        ));
         
-        // Send system health updates to connected clients
+        // Send system health updates to connected admins
         if active_connections > 0 {
             let health_msg = WebSocketMessage::SystemMessage {
                 message: format!(
@@ -335,8 +1184,8 @@ pub async fn monitor_websocket_performance(state: AppState) {
             };
             
             if let Ok(json) = serde_json::to_string(&health_msg) {
-                // This would broadcast to admin users only
-                debug!("System health: {}", json);
+                let delivered = state.broadcast_to_admins(json).await;
+                debug!("System health sent to {} admin connections", delivered);
             }
         }
     }
@@ -359,6 +1208,7 @@ mod tests {
             message: "Test message".to_string(),
             level: NotificationLevel::Info,
             timestamp: 12345,
+            encryption: None,
         };
         let json = serde_json::to_string(&notification).unwrap();
         assert!(json.contains("notification"));
@@ -374,4 +1224,211 @@ mod tests {
             _ => panic!("Wrong message type"),
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_protocol_negotiation() {
+        let hello: WebSocketMessage =
+            serde_json::from_str(r#"{"type":"hello","protocol_version":1,"client":"vscode/0.1.0"}"#)
+                .unwrap();
+        let requested = match hello {
+            WebSocketMessage::Hello { protocol_version, .. } => protocol_version,
+            _ => panic!("Wrong message type"),
+        };
+
+        match negotiate_protocol(requested, WireEncoding::default(), None) {
+            Ok(WebSocketMessage::Welcome { protocol_version, capabilities, encoding, .. }) => {
+                assert_eq!(protocol_version, PROTOCOL_VERSION);
+                assert!(!capabilities.is_empty());
+                assert_eq!(encoding, WireEncoding::Json);
+            }
+            _ => panic!("Supported version should be welcomed"),
+        }
+
+        let close_frame = negotiate_protocol(0, WireEncoding::Json, None).unwrap_err();
+        assert_eq!(close_frame.code, CLOSE_UNSUPPORTED_PROTOCOL);
+        assert!(close_frame.reason.contains("Unsupported protocol version 0"));
+    }
+
+    #[test]
+    fn test_msgpack_client_receives_decodable_flow_updates() {
+        let hello: WebSocketMessage = serde_json::from_str(
+            r#"{"type":"hello","protocol_version":1,"client":"ios/2.0","encoding":"msgpack"}"#,
+        )
+        .unwrap();
+        let encoding = match hello {
+            WebSocketMessage::Hello { encoding, .. } => encoding,
+            _ => panic!("Wrong message type"),
+        };
+        assert_eq!(encoding, WireEncoding::Msgpack);
+
+        // Outbound messages are broadcast as JSON and re-encoded per connection
+        let session_id = Uuid::new_v4();
+        let update = WebSocketMessage::FlowStateUpdate {
+            session_id,
+            flow_state: serde_json::json!({ "is_in_flow": true, "flow_intensity": 0.82 }),
+        };
+        let frame = encode_outbound(serde_json::to_string(&update).unwrap(), encoding);
+
+        let bytes = match &frame {
+            Message::Binary(bytes) => bytes.clone(),
+            other => panic!("Expected a binary frame, got {:?}", other),
+        };
+        match rmp_serde::from_slice::<WebSocketMessage>(&bytes).unwrap() {
+            WebSocketMessage::FlowStateUpdate { session_id: decoded, flow_state } => {
+                assert_eq!(decoded, session_id);
+                assert_eq!(flow_state["is_in_flow"], true);
+                assert_eq!(flow_state["flow_intensity"], 0.82);
+            }
+            other => panic!("Wrong message type: {:?}", other),
+        }
+        assert!(matches!(decode_inbound(&frame), Ok(WebSocketMessage::FlowStateUpdate { .. })));
+
+        // JSON stays the default
+        assert!(matches!(
+            encode_outbound("{\"type\":\"ping\",\"timestamp\":1}".to_string(), WireEncoding::default()),
+            Message::Text(_)
+        ));
+    }
+
+    #[test]
+    fn test_large_messages_are_deflated_when_negotiated() {
+        use flate2::read::DeflateDecoder;
+        use std::io::Read;
+
+        let hello: WebSocketMessage = serde_json::from_str(
+            r#"{"type":"hello","protocol_version":1,"client":"android/3.1","compression":"deflate"}"#,
+        )
+        .unwrap();
+        let requested = match hello {
+            WebSocketMessage::Hello { compression, .. } => compression,
+            _ => panic!("Wrong message type"),
+        };
+        let config = WebSocketCompressionConfig::default();
+        let compression = negotiate_compression(requested, &config);
+
+        // The welcome tells the client compression is on, and from what size
+        let welcome = negotiate_protocol(1, WireEncoding::Json, compression).unwrap();
+        let welcome = serde_json::to_value(&welcome).unwrap();
+        assert_eq!(welcome["compression"]["algorithm"], "deflate");
+        assert_eq!(
+            welcome["compression"]["threshold_bytes"],
+            config.threshold_bytes
+        );
+
+        let update = WebSocketMessage::FlowStateUpdate {
+            session_id: Uuid::new_v4(),
+            flow_state: serde_json::json!({ "keystroke_intervals": vec![120; 500] }),
+        };
+        let json = serde_json::to_string(&update).unwrap();
+        assert!(json.len() >= config.threshold_bytes);
+
+        let frame = compress_outbound(
+            encode_outbound(json.clone(), WireEncoding::Json),
+            compression,
+        );
+        let bytes = match frame {
+            Message::Binary(bytes) => bytes,
+            other => panic!("Expected a compressed frame, got {:?}", other),
+        };
+        assert_eq!(bytes[0], FRAME_DEFLATE);
+        assert!(bytes.len() < json.len());
+        let mut inflated = String::new();
+        DeflateDecoder::new(&bytes[1..])
+            .read_to_string(&mut inflated)
+            .unwrap();
+        assert_eq!(inflated, json);
+
+        // Small messages go out as before
+        let ping = r#"{"type":"ping","timestamp":1}"#.to_string();
+        assert_eq!(
+            compress_outbound(
+                encode_outbound(ping.clone(), WireEncoding::Json),
+                compression
+            ),
+            Message::Text(ping.clone())
+        );
+        // Small msgpack frames only gain the header byte
+        match compress_outbound(encode_outbound(ping, WireEncoding::Msgpack), compression) {
+            Message::Binary(bytes) => assert_eq!(bytes[0], FRAME_PLAIN),
+            other => panic!("Expected a binary frame, got {:?}", other),
+        }
+
+        // Nothing is negotiated when the server has compression off
+        let disabled = WebSocketCompressionConfig {
+            enabled: false,
+            ..config
+        };
+        let compression = negotiate_compression(requested, &disabled);
+        assert_eq!(compression, None);
+        let welcome =
+            serde_json::to_value(negotiate_protocol(1, WireEncoding::Json, compression).unwrap())
+                .unwrap();
+        assert!(welcome.get("compression").is_none());
+        assert!(matches!(
+            compress_outbound(encode_outbound(json, WireEncoding::Json), compression),
+            Message::Text(_)
+        ));
+    }
+
+    #[test]
+    fn test_stale_connections_are_closed_with_a_stale_code() {
+        assert!(stale_close_frame(Duration::from_secs(30)).is_none());
+        assert!(stale_close_frame(STALE_AFTER).is_none());
+
+        let close_frame = stale_close_frame(Duration::from_secs(120)).unwrap();
+        assert_eq!(close_frame.code, CLOSE_STALE);
+        assert_eq!(close_frame.reason, "No pong for 120s");
+
+        // Distinct from every other reason the server disconnects
+        let other_codes = [
+            CLOSE_UNSUPPORTED_PROTOCOL,
+            CLOSE_TOO_MANY_MALFORMED,
+            CLOSE_INTERNAL_ERROR,
+            CLOSE_TRY_AGAIN_LATER,
+        ];
+        assert!(!other_codes.contains(&close_frame.code));
+    }
+
+    #[test]
+    fn test_malformed_messages_get_a_client_error_until_abuse() {
+        let config = WebSocketMalformedConfig {
+            max_messages: 3,
+            window_secs: 60,
+        };
+        let start = Instant::now();
+        let mut malformed = MalformedFrames::new(&config, start);
+
+        let error = decode_inbound(&Message::Text("definitely not json".to_string())).unwrap_err();
+        match error_reply(&error) {
+            WebSocketMessage::Error { code, message } => {
+                assert_eq!(code, 400);
+                assert!(message.contains("\"type\" field"), "{}", message);
+            }
+            other => panic!("Wrong message type: {:?}", other),
+        }
+        let unknown_type = decode_inbound(&Message::Text(r#"{"type":"dance"}"#.to_string()));
+        assert!(matches!(
+            error_reply(&unknown_type.unwrap_err()),
+            WebSocketMessage::Error { code: 400, .. }
+        ));
+
+        // The connection stays open up to the limit, and closes past it
+        assert!(!malformed.record(start));
+        assert!(!malformed.record(start));
+        assert!(!malformed.record(start));
+        assert!(malformed.record(start));
+
+        // Once the window has passed the count starts over
+        let later = start + Duration::from_secs(config.window_secs);
+        assert!(!malformed.record(later));
+
+        // Server-side failures still don't leak details
+        match error_reply(&AppError::Internal("pool exhausted".to_string())) {
+            WebSocketMessage::Error { code, message } => {
+                assert_eq!(code, 500);
+                assert_eq!(message, "Internal server error");
+            }
+            other => panic!("Wrong message type: {:?}", other),
+        }
+    }
+}
@@ -8,7 +8,6 @@ use tower::ServiceBuilder;
 use tower_http::{
     compression::CompressionLayer,
     cors::{Any, CorsLayer},
-    timeout::TimeoutLayer,
     trace::TraceLayer,
 };
 use tracing::{info, warn};
@@ -25,9 +24,13 @@ mod utils;
 
 use crate::{
     config::Config,
-    handlers::{auth, flow, health, privacy, sessions, teams, websocket},
+    handlers::{admin, auth, dashboard, flow, health, plugins, privacy, sessions, teams, websocket},
     middleware::auth::auth_middleware,
     state::AppState,
+    utils::{
+        load_shed::load_shed_middleware, request_timeout::request_timeout_middleware,
+        tier_usage::tier_usage_middleware,
+    },
 };
 
 #[tokio::main]
@@ -43,12 +46,46 @@ async fn main() -> Result<()> {
 
     // Load configuration
     let config = Config::from_env()?;
+    error::set_verbose_errors(config.verbose_errors);
     info!("Starting Mindful Code Backend API");
     info!("Database URL: {}", config.database_url.chars().take(20).collect::<String>() + "...");
 
     // Initialize application state
     let app_state = AppState::new(config.clone()).await?;
 
+    // Catch a broken subsystem before serving traffic rather than on the
+    // first request that needs it
+    if config.run_self_test {
+        let report = services::self_test::run_self_test(&app_state).await;
+        report.log();
+        if !report.passed() {
+            let failed: Vec<_> = report.required_failures().map(|check| check.name).collect();
+            if config.is_production() {
+                anyhow::bail!("Startup self-test failed: {}", failed.join(", "));
+            }
+            warn!("Startup self-test failed ({}), starting anyway outside production", failed.join(", "));
+        }
+    }
+
+    // Store flow writes the last run logged but didn't get to
+    match flow::replay_pending_flow_writes(&app_state).await {
+        Ok(0) => {}
+        Ok(replayed) => info!("Replayed {} flow writes from the WAL", replayed),
+        Err(e) => warn!("Flow WAL replay failed: {}", e),
+    }
+
+    // Flow results carry `server_mode: initializing` until this finishes
+    tokio::spawn(services::readiness::warm_up_flow_model(app_state.clone()));
+
+    // Prune old feedback and, when enabled, data past retention
+    tokio::spawn(services::retention::run_retention_sweeper(app_state.clone()));
+
+    // Rotate the field encryption key on schedule
+    tokio::spawn(services::key_rotation::run_key_rotation_scheduler(app_state.clone()));
+
+    // Regenerate flow insights and expire stale ones
+    tokio::spawn(services::insights::run_insight_scheduler(app_state.clone()));
+
     // Build our application with routes
     let app = Router::new()
         // Health check (no auth required)
@@ -59,37 +96,107 @@ async fn main() -> Result<()> {
         .route("/api/auth/register", post(auth::register))
         .route("/api/auth/login", post(auth::login))
         .route("/api/auth/refresh", post(auth::refresh_token))
+        .route("/api/auth/anonymous", post(auth::create_anonymous_session))
+        .route("/api/auth/anonymous/claim", post(auth::claim_anonymous_session))
         
         // Session management (requires auth)
         .route("/api/sessions/start", post(sessions::start_session))
         .route("/api/sessions/:id/update", put(sessions::update_session))
         .route("/api/sessions/:id/end", post(sessions::end_session))
         .route("/api/sessions/history", get(sessions::get_session_history))
+        .route("/api/sessions/search", get(sessions::search_sessions))
+        .route(
+            "/api/sessions/:id/tags",
+            get(sessions::get_session_tags)
+                .post(sessions::add_session_tags)
+                .put(sessions::replace_session_tags),
+        )
+        .route("/api/sessions/:id/tags/:tag", delete(sessions::delete_session_tag))
+        .route("/api/sessions/:id/timeline", get(sessions::get_session_timeline))
+        .route("/api/sessions", delete(sessions::delete_sessions))
+        .route("/api/sessions/:id", delete(sessions::delete_session))
         
         // Real-time flow state detection (requires auth)
         .route("/api/flow/detect", post(flow::detect_flow_state))
+        .route("/api/flow/interruption", post(flow::record_interruption))
+        .route(
+            "/api/flow/preferences",
+            get(flow::get_flow_preferences).put(flow::update_flow_preferences),
+        )
         .route("/api/flow/patterns", get(flow::get_flow_patterns))
+        .route("/api/flow/diff", post(flow::diff_flow_sessions))
         .route("/api/flow/insights", get(flow::get_flow_insights))
+        .route("/api/flow/forecast", get(flow::get_flow_forecast))
+        .route(
+            "/api/flow/session-recommendation",
+            get(flow::get_session_recommendation),
+        )
+        .route(
+            "/api/flow/focus-mode",
+            get(flow::get_focus_mode).put(flow::update_focus_mode),
+        )
+        .route("/api/flow/achievements", get(flow::get_flow_achievements))
+        .route("/api/flow/export", get(flow::export_flow_history))
+        .route("/api/flow/events", get(websocket::event_stream_handler))
+        .route("/api/dashboard", get(dashboard::get_dashboard))
         
         // Team features (requires auth)
+        .route("/api/teams", post(teams::create_team))
         .route("/api/teams/:id/analytics", get(teams::get_team_analytics))
         .route("/api/teams/:id/insights", get(teams::get_team_insights))
         .route("/api/teams/:id/alerts", post(teams::create_alert))
+        .route(
+            "/api/teams/:id/flow-similarity",
+            get(teams::get_member_flow_similarity),
+        )
+        .route(
+            "/api/teams/:id/goals",
+            get(teams::get_team_goals).post(teams::set_team_goal),
+        )
+        .route(
+            "/api/teams/:id/members",
+            post(teams::add_team_members).delete(teams::remove_team_members),
+        )
+        
+        // Plugins (503 when the WASM engine failed to start)
+        .route("/api/plugins", get(plugins::list_plugins))
         
         // Privacy and data control (requires auth)
         .route("/api/privacy/export", get(privacy::export_user_data))
         .route("/api/privacy/purge", delete(privacy::purge_user_data))
         .route("/api/privacy/settings", put(privacy::update_privacy_settings))
         
+        // Admin (requires admin role)
+        .route("/api/admin/audit-log", get(admin::get_audit_log))
+        .route("/api/admin/encryption/key-backup", post(admin::create_key_backup))
+        .route("/api/admin/migrations", get(admin::get_migration_status))
+        .route(
+            "/api/admin/users/:id/flow-engines",
+            get(admin::get_user_engine_state),
+        )
+        .route(
+            "/api/admin/users/:id/flow-states/replay",
+            post(admin::replay_flow_states),
+        )
+        
         // WebSocket for real-time updates
         .route("/ws", get(websocket::websocket_handler))
+        // Per-tier usage counts; a route layer so the matched route is known
+        .route_layer(axum::middleware::from_fn_with_state(
+            app_state.clone(),
+            tier_usage_middleware,
+        ))
+        // Per-route time limits, set by the matched route
+        .route_layer(axum::middleware::from_fn_with_state(
+            app_state.clone(),
+            request_timeout_middleware,
+        ))
         
         // Apply middleware layers
         .layer(
             ServiceBuilder::new()
                 .layer(TraceLayer::new_for_http())
                 .layer(CompressionLayer::new())
-                .layer(TimeoutLayer::new(std::time::Duration::from_secs(10)))
                 .layer(
                     CorsLayer::new()
                         .allow_origin(Any)
@@ -101,7 +208,12 @@ async fn main() -> Result<()> {
             app_state.clone(),
             auth_middleware,
         ))
-        .with_state(app_state);
+        // Outermost, so shed requests cost nothing past the semaphore
+        .layer(axum::middleware::from_fn_with_state(
+            app_state.clone(),
+            load_shed_middleware,
+        ))
+        .with_state(app_state.clone());
 
     // Start the server
     let addr = SocketAddr::from(([0, 0, 0, 0], config.port));
@@ -111,12 +223,60 @@ async fn main() -> Result<()> {
     info!("📊 Health check available at http://{}/health", addr);
     info!("🔌 WebSocket endpoint at ws://{}/ws", addr);
     
-    axum::serve(listener, app.into_make_service())
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown({
+            let app_state = app_state.clone();
+            async move {
+                shutdown_signal().await;
+                app_state.readiness.begin_shutdown();
+            }
+        })
         .await
         .map_err(|e| {
             warn!("Server error: {}", e);
             anyhow::anyhow!("Server failed to start: {}", e)
         })?;
 
+    // Let queued flow writes reach the database; with a WAL, any that
+    // don't are replayed on the next start
+    let drain_timeout = std::time::Duration::from_secs(config.flow_wal.drain_timeout_secs);
+    if !app_state.flow_writes.drain(drain_timeout).await {
+        warn!(
+            "Shut down with {} flow writes still queued",
+            app_state.flow_writes.queue_depth() + app_state.flow_writes.in_flight()
+        );
+    }
+
     Ok(())
+}
+
+/// Resolves on Ctrl-C or, on Unix, SIGTERM.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            warn!("Failed to listen for Ctrl-C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                warn!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    info!("Shutting down, finishing in-flight requests");
 }
\ No newline at end of file
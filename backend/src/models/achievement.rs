@@ -0,0 +1,227 @@
+use crate::config::AchievementConfig;
+use chrono::{DateTime, NaiveDate, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// A user's flow streak and best session, as stored in
+/// `user_flow_achievements`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FlowAchievements {
+    /// Consecutive days with a flow session, ending on `last_flow_date`
+    pub current_streak_days: u32,
+    pub longest_streak_days: u32,
+    /// Day of the last counted flow session, in the user's timezone
+    pub last_flow_date: Option<NaiveDate>,
+    pub best_session_flow_ms: u64,
+    pub best_session_id: Option<Uuid>,
+}
+
+/// A record broken by the session that just ended.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum AchievementEvent {
+    BestSession {
+        session_id: Uuid,
+        flow_ms: u64,
+        previous_best_ms: u64,
+    },
+    StreakMilestone {
+        days: u32,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FlowAchievementsResponse {
+    /// Zero once a full day has passed without a flow session
+    pub current_streak_days: u32,
+    pub longest_streak_days: u32,
+    pub last_flow_date: Option<NaiveDate>,
+    pub best_session_flow_ms: u64,
+    pub best_session_id: Option<Uuid>,
+    pub next_milestone_days: Option<u32>,
+}
+
+impl FlowAchievements {
+    /// Folds a finished session into the records. Sessions with less flow
+    /// than `config.min_session_flow_ms` can still set a best session but
+    /// don't count as a streak day.
+    pub fn record_session(
+        &mut self,
+        session_id: Uuid,
+        local_date: NaiveDate,
+        flow_ms: u64,
+        config: &AchievementConfig,
+    ) -> Vec<AchievementEvent> {
+        let mut events = Vec::new();
+
+        if flow_ms > self.best_session_flow_ms {
+            // The very first session isn't announced as a record
+            if self.best_session_flow_ms > 0 {
+                events.push(AchievementEvent::BestSession {
+                    session_id,
+                    flow_ms,
+                    previous_best_ms: self.best_session_flow_ms,
+                });
+            }
+            self.best_session_flow_ms = flow_ms;
+            self.best_session_id = Some(session_id);
+        }
+
+        if flow_ms < config.min_session_flow_ms {
+            return events;
+        }
+
+        let extends_streak = match self.last_flow_date {
+            // Already counted today, or a late-arriving session for an
+            // earlier day
+            Some(last) if local_date <= last => return events,
+            Some(last) => last.succ_opt() == Some(local_date),
+            None => false,
+        };
+
+        self.current_streak_days = if extends_streak {
+            self.current_streak_days + 1
+        } else {
+            1
+        };
+        self.longest_streak_days = self.longest_streak_days.max(self.current_streak_days);
+        self.last_flow_date = Some(local_date);
+
+        if config.streak_milestones.contains(&self.current_streak_days) {
+            events.push(AchievementEvent::StreakMilestone {
+                days: self.current_streak_days,
+            });
+        }
+
+        events
+    }
+
+    /// The streak as it stands on `today`: still alive if the last flow day
+    /// was today or yesterday.
+    pub fn current_streak_on(&self, today: NaiveDate) -> u32 {
+        match self.last_flow_date {
+            Some(last) if last == today || last.succ_opt() == Some(today) => {
+                self.current_streak_days
+            }
+            _ => 0,
+        }
+    }
+
+    pub fn to_response(
+        &self,
+        today: NaiveDate,
+        config: &AchievementConfig,
+    ) -> FlowAchievementsResponse {
+        let current_streak_days = self.current_streak_on(today);
+
+        FlowAchievementsResponse {
+            current_streak_days,
+            longest_streak_days: self.longest_streak_days,
+            last_flow_date: self.last_flow_date,
+            best_session_flow_ms: self.best_session_flow_ms,
+            best_session_id: self.best_session_id,
+            next_milestone_days: config
+                .streak_milestones
+                .iter()
+                .copied()
+                .filter(|days| *days > current_streak_days)
+                .min(),
+        }
+    }
+}
+
+/// Calendar day of `at` in the user's IANA timezone; unknown or missing
+/// zones count as UTC.
+pub fn local_date(at: DateTime<Utc>, timezone: Option<&str>) -> NaiveDate {
+    match timezone.and_then(|name| name.parse::<Tz>().ok()) {
+        Some(tz) => at.with_timezone(&tz).date_naive(),
+        None => at.date_naive(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    const HOUR_MS: u64 = 3_600_000;
+
+    fn day(d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 5, d).unwrap()
+    }
+
+    #[test]
+    fn test_streak_increments_on_consecutive_days() {
+        let config = AchievementConfig::default();
+        let mut achievements = FlowAchievements::default();
+
+        for d in 1..=3 {
+            achievements.record_session(Uuid::new_v4(), day(d), HOUR_MS, &config);
+        }
+        // A second session the same day doesn't count twice
+        achievements.record_session(Uuid::new_v4(), day(3), HOUR_MS, &config);
+
+        assert_eq!(achievements.current_streak_days, 3);
+        assert_eq!(achievements.longest_streak_days, 3);
+        assert_eq!(achievements.current_streak_on(day(4)), 3);
+    }
+
+    #[test]
+    fn test_streak_resets_after_gap() {
+        let config = AchievementConfig::default();
+        let mut achievements = FlowAchievements::default();
+
+        for d in [1, 2, 3, 4] {
+            achievements.record_session(Uuid::new_v4(), day(d), HOUR_MS, &config);
+        }
+        assert_eq!(achievements.current_streak_on(day(6)), 0);
+
+        achievements.record_session(Uuid::new_v4(), day(6), HOUR_MS, &config);
+        assert_eq!(achievements.current_streak_days, 1);
+        assert_eq!(achievements.longest_streak_days, 4);
+
+        // Too little flow keeps the day from counting
+        achievements.record_session(Uuid::new_v4(), day(7), 1_000, &config);
+        assert_eq!(achievements.current_streak_days, 1);
+        assert_eq!(achievements.last_flow_date, Some(day(6)));
+    }
+
+    #[test]
+    fn test_records_fire_events() {
+        let config = AchievementConfig {
+            streak_milestones: vec![2],
+            ..AchievementConfig::default()
+        };
+        let mut achievements = FlowAchievements::default();
+
+        let first = achievements.record_session(Uuid::new_v4(), day(1), HOUR_MS, &config);
+        assert!(first.is_empty());
+
+        let best = Uuid::new_v4();
+        let events = achievements.record_session(best, day(2), 2 * HOUR_MS, &config);
+        assert_eq!(
+            events,
+            vec![
+                AchievementEvent::BestSession {
+                    session_id: best,
+                    flow_ms: 2 * HOUR_MS,
+                    previous_best_ms: HOUR_MS,
+                },
+                AchievementEvent::StreakMilestone { days: 2 },
+            ]
+        );
+        assert_eq!(achievements.best_session_id, Some(best));
+    }
+
+    #[test]
+    fn test_day_boundary_uses_user_timezone() {
+        // 23:30 UTC on May 1st is already May 2nd in Tokyo
+        let late_evening_utc = Utc.with_ymd_and_hms(2024, 5, 1, 23, 30, 0).unwrap();
+
+        assert_eq!(local_date(late_evening_utc, Some("Asia/Tokyo")), day(2));
+        assert_eq!(local_date(late_evening_utc, Some("America/New_York")), day(1));
+        assert_eq!(local_date(late_evening_utc, Some("Not/AZone")), day(1));
+        assert_eq!(local_date(late_evening_utc, None), day(1));
+    }
+}
@@ -0,0 +1,98 @@
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use validator::Validate;
+
+use crate::services::encryption::SealedKeyBackup;
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct KeyBackupRequest {
+    /// Operator passphrase the backup is sealed under; never stored
+    #[validate(length(min = 12, max = 1024))]
+    pub passphrase: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct KeyBackupResponse {
+    pub key_id: String,
+    pub backup: SealedKeyBackup,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MigrationInfo {
+    pub version: i64,
+    pub description: String,
+    pub applied: bool,
+    pub installed_on: Option<chrono::DateTime<chrono::Utc>>,
+    /// `false` when a migration started but didn't complete
+    pub success: Option<bool>,
+    /// `false` when the applied script differs from the one in this build
+    pub checksum_matches: Option<bool>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MigrationStatusResponse {
+    pub migrations: Vec<MigrationInfo>,
+    pub pending: Vec<i64>,
+    pub has_pending: bool,
+    /// SHA-256 over the applied versions and their checksums, for comparing
+    /// schemas across environments at a glance
+    pub schema_checksum: String,
+}
+
+/// Read-only view of one session's in-memory flow engine, for support.
+/// Keystroke timings are never included; only how many are buffered.
+#[derive(Debug, Clone, Serialize)]
+pub struct FlowEngineSnapshot {
+    pub session_id: Option<Uuid>,
+    pub current_intensity: f32,
+    pub in_flow: bool,
+    pub current_flow_duration_ms: u64,
+    pub keystroke_buffer_len: usize,
+    /// Analyses so far in the current session, including warm-up
+    pub session_analyses: u32,
+    pub flow_session_count: u32,
+    pub total_flow_time_ms: u64,
+    /// Oldest first
+    pub confidence_history: Vec<f32>,
+    pub baseline_sample_count: u64,
+    pub baseline_mean: f64,
+    /// Scores are still dampened after a reported interruption
+    pub recovering_from_interruption: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UserEngineStateResponse {
+    pub user_id: Uuid,
+    /// One per live session; empty when the user has no engine in memory
+    pub engines: Vec<FlowEngineSnapshot>,
+}
+
+/// Recomputes a user's stored flow states in `[from, to)` with
+/// `model_version` (the active model when omitted).
+#[derive(Debug, Deserialize)]
+pub struct FlowReplayRequest {
+    pub from: chrono::DateTime<chrono::Utc>,
+    pub to: chrono::DateTime<chrono::Utc>,
+    pub model_version: Option<String>,
+    /// `next_cursor` from the previous batch, to resume after it
+    pub cursor: Option<FlowReplayCursor>,
+}
+
+/// Position of the last row a replay batch looked at, in
+/// `(start_time, id)` order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FlowReplayCursor {
+    pub start_time: chrono::DateTime<chrono::Utc>,
+    pub id: Uuid,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FlowReplayResponse {
+    pub model_version: String,
+    /// Rows rescored in this batch
+    pub updated: u32,
+    /// Rows already scored by `model_version`, or without usable features
+    pub skipped: u32,
+    /// Send back as `cursor` to continue; `None` once the range is done
+    pub next_cursor: Option<FlowReplayCursor>,
+}
@@ -0,0 +1,57 @@
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOperation {
+    Export,
+    Purge,
+    Anonymize,
+    KeyRotation,
+    KeyBackup,
+    FlowReplay,
+}
+
+impl AuditOperation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditOperation::Export => "export",
+            AuditOperation::Purge => "purge",
+            AuditOperation::Anonymize => "anonymize",
+            AuditOperation::KeyRotation => "key_rotation",
+            AuditOperation::KeyBackup => "key_backup",
+            AuditOperation::FlowReplay => "flow_replay",
+        }
+    }
+}
+
+/// One link of the audit chain. `operation` is kept as stored rather than
+/// parsed, so entries replay and verify exactly as written.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct AuditEntry {
+    pub sequence: i64,
+    /// None for system-initiated operations
+    pub actor_id: Option<Uuid>,
+    /// None for operations not tied to one user, such as key rotation
+    pub subject_user_id: Option<Uuid>,
+    pub operation: String,
+    pub details: serde_json::Value,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub prev_hash: String,
+    pub entry_hash: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ChainVerification {
+    pub valid: bool,
+    pub entries_checked: usize,
+    /// First entry whose hash or link to its predecessor doesn't match
+    pub first_invalid_sequence: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuditLogResponse {
+    /// Always computed over the full chain, even when entries are filtered
+    pub verification: ChainVerification,
+    pub entries: Vec<AuditEntry>,
+}
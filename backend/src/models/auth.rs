@@ -0,0 +1,47 @@
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use validator::Validate;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LoginRequest {
+    pub email: String,
+    pub password: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub struct RegisterRequest {
+    #[validate(email, length(max = 255))]
+    pub email: String,
+    #[validate(length(min = 8, max = 128))]
+    pub password: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RefreshTokenRequest {
+    pub refresh_token: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AnonymousSessionResponse {
+    pub anonymous_id: Uuid,
+    pub access_token: String,
+    pub expires_in: u64,
+}
+
+/// Sent by a newly registered user to carry over what they built up while
+/// trying the product anonymously.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ClaimAnonymousSessionRequest {
+    pub anonymous_token: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ClaimAnonymousSessionResponse {
+    /// False when the trial had no in-memory state left to migrate
+    pub migrated: bool,
+    /// Trial sessions now recorded as the user's own
+    pub sessions_migrated: u32,
+    /// The trial had been claimed by this user before; nothing was copied
+    /// again
+    pub already_claimed: bool,
+}
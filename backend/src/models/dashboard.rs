@@ -0,0 +1,36 @@
+use serde::Serialize;
+
+use crate::models::{
+    flow::{FlowAnalytics, FlowInsight, FlowPattern},
+    team::TeamGoalProgress,
+};
+
+/// One part of the dashboard. Sections are loaded independently, so one
+/// failing or being outside the user's plan doesn't hide the others.
+#[derive(Debug, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum DashboardSection<T> {
+    Ok { data: T },
+    /// Not available on the user's subscription tier
+    Locked { message: String },
+    /// Could not be loaded this time; retrying the dashboard may succeed
+    Unavailable { message: String },
+}
+
+impl<T> DashboardSection<T> {
+    pub fn data(&self) -> Option<&T> {
+        match self {
+            DashboardSection::Ok { data } => Some(data),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct DashboardResponse {
+    pub patterns: DashboardSection<FlowPattern>,
+    pub insights: DashboardSection<Vec<FlowInsight>>,
+    pub analytics: DashboardSection<FlowAnalytics>,
+    /// Goal progress across all the user's teams
+    pub goals: DashboardSection<Vec<TeamGoalProgress>>,
+}
@@ -1,11 +1,16 @@
+use crate::utils::{serialize_optional_score, serialize_score, Granularity};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
-use validator::Validate;
+use validator::{Validate, ValidationError};
 
 #[derive(Debug, Serialize, Deserialize, Clone, Validate)]
+#[validate(schema(function = "validate_keystroke_input"))]
 pub struct FlowStateData {
     pub session_id: Uuid,
-    #[validate(length(min = 1, max = 1000))]
+    /// Raw inter-keystroke timings; empty in minimized mode, where the
+    /// client sends only `aggregates`
+    #[serde(default)]
+    #[validate(length(max = 1000))]
     pub keystroke_intervals: Vec<u64>,
     #[validate(range(min = 0, max = 1000))]
     pub context_switches: u32,
@@ -16,22 +21,162 @@ pub struct FlowStateData {
     #[validate(range(min = 0, max = 10000))]
     pub file_modifications: u32,
     pub timestamp: i64,
+    /// In `velocity_unit`
     pub typing_velocity: Option<f32>,
     pub pause_patterns: Option<Vec<u64>>,
+    #[serde(default)]
+    #[validate(nested)]
+    pub aggregates: Option<KeystrokeAggregates>,
+    #[serde(default)]
+    pub velocity_unit: VelocityUnit,
+}
+
+/// Characters a typed word is counted as, by the usual typing-test
+/// convention.
+pub const CHARS_PER_WORD: f32 = 5.0;
+
+/// Unit of `typing_velocity`. Scoring works in characters per minute, the
+/// default for clients that don't say.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum VelocityUnit {
+    #[default]
+    CharsPerMinute,
+    WordsPerMinute,
+    /// Each keystroke counts as one character
+    KeystrokesPerSecond,
+}
+
+impl VelocityUnit {
+    pub fn to_chars_per_minute(self, velocity: f32) -> f32 {
+        match self {
+            VelocityUnit::CharsPerMinute => velocity,
+            VelocityUnit::WordsPerMinute => velocity * CHARS_PER_WORD,
+            VelocityUnit::KeystrokesPerSecond => velocity * 60.0,
+        }
+    }
+}
+
+/// Summary of a batch of keystroke intervals, sent instead of the raw
+/// timings by privacy-minimizing clients.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Validate)]
+pub struct KeystrokeAggregates {
+    #[validate(range(min = 1, max = 100000))]
+    pub count: u32,
+    #[validate(range(min = 0.0))]
+    pub mean_interval_ms: f32,
+    /// Standard deviation over the mean (population, not sample)
+    #[validate(range(min = 0.0))]
+    pub coefficient_of_variation: f32,
+}
+
+impl KeystrokeAggregates {
+    pub fn from_intervals(intervals: &[u64]) -> Option<Self> {
+        if intervals.is_empty() {
+            return None;
+        }
+
+        let count = intervals.len() as f32;
+        let mean = intervals.iter().sum::<u64>() as f32 / count;
+        let variance = intervals
+            .iter()
+            .map(|&x| (x as f32 - mean).powi(2))
+            .sum::<f32>()
+            / count;
+
+        Some(Self {
+            count: intervals.len() as u32,
+            mean_interval_ms: mean,
+            coefficient_of_variation: if mean > 0.0 { variance.sqrt() / mean } else { 1.0 },
+        })
+    }
+}
+
+impl FlowStateData {
+    /// `typing_velocity` in characters per minute.
+    pub fn typing_velocity_cpm(&self) -> Option<f32> {
+        self.typing_velocity
+            .map(|velocity| self.velocity_unit.to_chars_per_minute(velocity))
+    }
+
+    /// Keystrokes behind this sample, from the raw intervals when present.
+    pub fn keystroke_count(&self) -> usize {
+        if !self.keystroke_intervals.is_empty() {
+            return self.keystroke_intervals.len();
+        }
+        self.aggregates.map_or(0, |aggregates| aggregates.count as usize)
+    }
+
+    pub fn mean_keystroke_interval(&self) -> Option<f32> {
+        if !self.keystroke_intervals.is_empty() {
+            let sum = self.keystroke_intervals.iter().sum::<u64>() as f32;
+            return Some(sum / self.keystroke_intervals.len() as f32);
+        }
+        self.aggregates.map(|aggregates| aggregates.mean_interval_ms)
+    }
+}
+
+fn validate_keystroke_input(data: &FlowStateData) -> Result<(), ValidationError> {
+    if data.keystroke_intervals.is_empty() && data.aggregates.is_none() {
+        return Err(ValidationError::new("keystroke_intervals_or_aggregates_required"));
+    }
+    Ok(())
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct FlowStateResult {
     pub is_in_flow: bool,
+    /// Too early in the session to judge; `is_in_flow` stays false until
+    /// the warm-up window has passed
+    pub warming_up: bool,
+    #[serde(serialize_with = "serialize_score")]
     pub flow_intensity: f32,
     pub flow_duration_ms: u64,
+    #[serde(serialize_with = "serialize_score")]
     pub confidence: f32,
+    /// Sample-size quality of the analysed window, persisted so analytics
+    /// can exclude noisy early-session data
+    #[serde(serialize_with = "serialize_score")]
+    pub data_quality: f32,
+    /// Z-score of `flow_intensity` against the user's own history; `None`
+    /// until enough analyses have been seen
+    #[serde(serialize_with = "serialize_optional_score")]
+    pub relative_flow_score: Option<f32>,
     pub recommendations: Vec<String>,
     pub metrics: FlowMetrics,
     pub analysis_time_ms: f32,
+    /// Model that produced `flow_intensity`; scores from different models
+    /// aren't directly comparable
+    pub model_version: String,
+    /// ML inference failed and `flow_intensity` came from the rule-based
+    /// fallback instead
+    #[serde(default)]
+    pub degraded: bool,
+    /// When the sample was taken, in epoch milliseconds: the client's
+    /// `timestamp`, or the server receive time if that was implausibly old
+    pub sample_timestamp: i64,
+    /// The client clock was too far behind to trust
+    pub timestamp_adjusted: bool,
+    /// Set while the flow model is still warming up after a restart, when
+    /// scores may come from the rule-based fallback
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_mode: Option<ServerMode>,
+    /// Too few keystrokes to score: neither model ran, `flow_intensity` and
+    /// `confidence` are zero and the engine's flow state is unchanged
+    #[serde(default)]
+    pub insufficient_data: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+/// Server start-up phase: `Initializing` until the flow model has warmed
+/// up, then `Ready` for the life of the process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ServerMode {
+    Initializing,
+    Ready,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct FlowMetrics {
     pub rhythm_score: f32,
     pub focus_score: f32,
@@ -50,6 +195,25 @@ pub struct FlowPattern {
     pub interruption_tolerance: f32,
     pub best_languages: Vec<String>,
     pub environmental_factors: serde_json::Value,
+    /// The user turned analytics off; nothing else in the pattern is set
+    #[serde(default)]
+    pub opted_out: bool,
+}
+
+impl FlowPattern {
+    pub fn opted_out(user_id: Uuid) -> Self {
+        Self {
+            user_id,
+            optimal_session_length: 0,
+            peak_hours: vec![],
+            average_flow_intensity: 0.0,
+            flow_triggers: vec![],
+            interruption_tolerance: 0.0,
+            best_languages: vec![],
+            environmental_factors: serde_json::json!({}),
+            opted_out: true,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -64,18 +228,42 @@ pub struct FlowInsight {
     pub time_range: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Validate)]
 pub struct FlowDetectionRequest {
+    #[validate(nested)]
     pub flow_data: FlowStateData,
+    #[validate(nested)]
     pub user_preferences: Option<UserFlowPreferences>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, Validate)]
 pub struct UserFlowPreferences {
+    #[validate(range(min = 0.0, max = 1.0))]
     pub sensitivity_level: f32,
+    #[validate(range(min = 0.0, max = 1.0))]
     pub notification_threshold: f32,
     pub focus_mode_enabled: bool,
     pub break_reminders_enabled: bool,
+    /// Derive the flow threshold from the user's own baseline once it is
+    /// warm, instead of the fixed `sensitivity_level`
+    #[serde(default)]
+    pub personalized_calibration: bool,
+    /// `false` forces reproducible rule-based scoring instead of the ML
+    /// model; unset uses the server default
+    #[serde(default)]
+    pub use_ml: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FocusModeRequest {
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FocusModeStatus {
+    pub enabled: bool,
+    /// Start of the current focus-mode period
+    pub started_at: Option<chrono::DateTime<chrono::Utc>>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -85,9 +273,44 @@ pub struct FlowAnalytics {
     pub flow_sessions_count: u32,
     pub longest_flow_session_ms: u64,
     pub interruption_rate: f32,
+    pub reported_interruptions: u32,
     pub productivity_score: f32,
     pub weekly_trend: f32,
     pub daily_distribution: Vec<DailyFlowData>,
+    pub granularity: Granularity,
+    /// Flow per `granularity` bucket in the user's timezone, oldest first;
+    /// buckets without flow data are left out
+    pub distribution: Vec<FlowBucket>,
+    /// The user turned analytics off, so none of their data was aggregated
+    #[serde(default)]
+    pub opted_out: bool,
+}
+
+impl FlowAnalytics {
+    pub fn opted_out(granularity: Granularity) -> Self {
+        Self {
+            total_flow_time_ms: 0,
+            average_flow_intensity: 0.0,
+            flow_sessions_count: 0,
+            longest_flow_session_ms: 0,
+            interruption_rate: 0.0,
+            reported_interruptions: 0,
+            productivity_score: 0.0,
+            weekly_trend: 0.0,
+            daily_distribution: vec![],
+            granularity,
+            distribution: vec![],
+            opted_out: true,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FlowBucket {
+    pub start: chrono::DateTime<chrono::Utc>,
+    pub total_flow_time_ms: u64,
+    pub session_count: u32,
+    pub average_intensity: f32,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -97,4 +320,183 @@ pub struct DailyFlowData {
     pub session_count: u32,
     pub average_intensity: f32,
     pub peak_intensity_hour: Option<u8>,
-}
\ No newline at end of file
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum InterruptionType {
+    Call,
+    Meeting,
+    Notification,
+    Colleague,
+    Other,
+}
+
+impl InterruptionType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            InterruptionType::Call => "call",
+            InterruptionType::Meeting => "meeting",
+            InterruptionType::Notification => "notification",
+            InterruptionType::Colleague => "colleague",
+            InterruptionType::Other => "other",
+        }
+    }
+}
+
+impl std::str::FromStr for InterruptionType {
+    type Err = std::convert::Infallible;
+
+    /// Types the API no longer accepts read back as `Other`.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(match s {
+            "call" => InterruptionType::Call,
+            "meeting" => InterruptionType::Meeting,
+            "notification" => InterruptionType::Notification,
+            "colleague" => InterruptionType::Colleague,
+            _ => InterruptionType::Other,
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub struct InterruptionRequest {
+    pub session_id: Uuid,
+    pub interruption_type: InterruptionType,
+    // Capped at one day; longer gaps are session boundaries, not interruptions
+    #[validate(range(min = 0, max = 86400000))]
+    pub duration_ms: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InterruptionEvent {
+    pub id: Uuid,
+    pub session_id: Uuid,
+    pub interruption_type: InterruptionType,
+    pub duration_ms: u64,
+    pub occurred_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// One entry of a session's flow timeline: a scored flow sample, or an
+/// interruption the editor reported.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum TimelineEvent {
+    FlowPoint {
+        at: chrono::DateTime<chrono::Utc>,
+        #[serde(serialize_with = "serialize_score")]
+        intensity: f32,
+        #[serde(serialize_with = "serialize_score")]
+        confidence: f32,
+    },
+    Interruption {
+        at: chrono::DateTime<chrono::Utc>,
+        interruption_type: InterruptionType,
+        duration_ms: u64,
+    },
+}
+
+impl TimelineEvent {
+    pub fn at(&self) -> chrono::DateTime<chrono::Utc> {
+        match self {
+            TimelineEvent::FlowPoint { at, .. } | TimelineEvent::Interruption { at, .. } => *at,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct SessionTimeline {
+    pub session_id: Uuid,
+    pub started_at: chrono::DateTime<chrono::Utc>,
+    pub ended_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// Oldest first
+    pub events: Vec<TimelineEvent>,
+}
+
+impl SessionTimeline {
+    pub fn merge(
+        session_id: Uuid,
+        started_at: chrono::DateTime<chrono::Utc>,
+        ended_at: Option<chrono::DateTime<chrono::Utc>>,
+        flow_points: Vec<TimelineEvent>,
+        interruptions: Vec<TimelineEvent>,
+    ) -> Self {
+        let mut events = flow_points;
+        events.extend(interruptions);
+        // Stable, so ties keep flow points ahead of interruptions
+        events.sort_by_key(TimelineEvent::at);
+
+        Self {
+            session_id,
+            started_at,
+            ended_at,
+            events,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FlowForecastHour {
+    pub starts_at: chrono::DateTime<chrono::Utc>,
+    pub expected_flow_score: f32,
+    pub session_count: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FlowForecast {
+    pub generated_at: chrono::DateTime<chrono::Utc>,
+    /// The next 24 hours, in order
+    pub hours: Vec<FlowForecastHour>,
+    /// Upcoming hours with the highest expected flow, best first
+    pub best_windows: Vec<FlowForecastHour>,
+    /// Too little history for a personal forecast; scores lean on defaults
+    pub sparse_history: bool,
+}
+
+/// When to start the next coding session and for how long.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SessionRecommendation {
+    pub generated_at: chrono::DateTime<chrono::Utc>,
+    /// Now, if the current hour is the pick
+    pub starts_at: chrono::DateTime<chrono::Utc>,
+    pub ends_at: chrono::DateTime<chrono::Utc>,
+    pub target_length_ms: u64,
+    pub expected_flow_score: f32,
+    /// No personal history behind the window; it comes from defaults
+    pub default_window: bool,
+    pub reasons: Vec<String>,
+}
+
+/// Two groups of the caller's sessions to compare, e.g. from before and
+/// after adopting a tool.
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub struct FlowDiffRequest {
+    #[validate(length(min = 1, max = 100))]
+    pub before: Vec<Uuid>,
+    #[validate(length(min = 1, max = 100))]
+    pub after: Vec<Uuid>,
+}
+
+/// How one metric moved from the before sessions to the after sessions.
+/// Each session counts as one sample.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MetricDelta {
+    pub before: f64,
+    pub after: f64,
+    /// `after - before`
+    pub delta: f64,
+    pub before_samples: usize,
+    pub after_samples: usize,
+    /// How sure the difference isn't chance, 0 to 1. Zero with fewer than
+    /// two samples on either side
+    pub confidence: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FlowDiffResponse {
+    pub rhythm: MetricDelta,
+    pub focus: MetricDelta,
+    pub consistency: MetricDelta,
+    pub velocity: MetricDelta,
+    pub flow_time_ms: MetricDelta,
+}
@@ -1,10 +1,18 @@
+pub mod achievement;
+pub mod admin;
+pub mod audit;
 pub mod auth;
+pub mod dashboard;
 pub mod flow;
 pub mod session;
 pub mod team;
 pub mod user;
 
+pub use achievement::*;
+pub use admin::*;
+pub use audit::*;
 pub use auth::*;
+pub use dashboard::*;
 pub use flow::*;
 pub use session::*;
 pub use team::*;
@@ -0,0 +1,263 @@
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{Map, Value};
+use uuid::Uuid;
+use validator::Validate;
+
+/// The coding environment a session ran in, stored as `environment_data`.
+/// Known keys are typed so analytics can filter on them; anything else the
+/// client sends is kept as-is in `extra`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, Validate)]
+pub struct SessionEnvironment {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[validate(length(max = 100))]
+    pub editor: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[validate(length(max = 100))]
+    pub os: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[validate(range(min = 1, max = 16))]
+    pub monitor_count: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[validate(length(max = 50))]
+    pub theme: Option<String>,
+    /// Editor extension ids
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[validate(length(max = 200))]
+    pub extensions: Vec<String>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl SessionEnvironment {
+    /// Reads a stored `environment_data` blob. Blobs from before the
+    /// environment was typed may hold a known key with some other type;
+    /// such values are kept in `extra` rather than failing the read.
+    pub fn from_stored(value: Value) -> Self {
+        let Value::Object(mut map) = value else {
+            return Self::default();
+        };
+
+        Self {
+            editor: take_known(&mut map, "editor"),
+            os: take_known(&mut map, "os"),
+            monitor_count: take_known(&mut map, "monitor_count"),
+            theme: take_known(&mut map, "theme"),
+            extensions: take_known(&mut map, "extensions").unwrap_or_default(),
+            extra: map,
+        }
+    }
+}
+
+fn take_known<T: DeserializeOwned>(map: &mut Map<String, Value>, key: &str) -> Option<T> {
+    let parsed = T::deserialize(map.get(key)?).ok()?;
+    map.remove(key);
+    Some(parsed)
+}
+
+/// Restricts flow analytics to sessions whose environment matches every
+/// field given.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SessionEnvironmentFilter {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub editor: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub os: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub monitor_count: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub theme: Option<String>,
+}
+
+impl SessionEnvironmentFilter {
+    /// The filter as a JSONB containment pattern (`environment_data @> $n`);
+    /// `{}` when no field is set.
+    pub fn to_containment(&self) -> Value {
+        serde_json::to_value(self).unwrap_or_else(|_| Value::Object(Map::new()))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub struct StartSessionRequest {
+    #[validate(length(max = 500))]
+    pub project_path: Option<String>,
+    #[validate(nested)]
+    pub environment_data: Option<SessionEnvironment>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub struct UpdateSessionRequest {
+    #[validate(range(min = 0))]
+    pub files_modified: Option<i32>,
+    #[validate(range(min = 0))]
+    pub keystrokes: Option<i32>,
+    #[validate(range(min = 0))]
+    pub lines_added: Option<i32>,
+    #[validate(range(min = 0))]
+    pub lines_deleted: Option<i32>,
+    #[validate(length(max = 500))]
+    pub project_path: Option<String>,
+    #[validate(nested)]
+    pub environment_data: Option<SessionEnvironment>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub struct DeleteSessionsRequest {
+    #[validate(length(min = 1, max = 100))]
+    pub session_ids: Vec<Uuid>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeleteSessionsResponse {
+    pub deleted: u64,
+    pub session_ids: Vec<Uuid>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SessionResponse {
+    pub session_id: Uuid,
+    pub started_at: chrono::DateTime<chrono::Utc>,
+    /// Sanitized values as persisted
+    pub project_path: Option<String>,
+    pub environment_data: SessionEnvironment,
+}
+
+/// Flow metrics computed once when a session ends and stored on the
+/// `coding_sessions` row.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SessionAggregates {
+    pub total_duration_ms: u64,
+    pub total_flow_time_ms: u64,
+    pub avg_flow_intensity: f32,
+    pub peak_flow_intensity: f32,
+    pub interruption_count: u32,
+}
+
+impl SessionAggregates {
+    /// Builds the aggregates from the session's persisted flow samples,
+    /// given as `(intensity, duration_ms)` pairs.
+    pub fn from_flow_states(
+        total_duration_ms: u64,
+        flow_states: &[(f32, Option<u64>)],
+        interruption_count: u32,
+    ) -> Self {
+        let total_flow_time_ms = flow_states
+            .iter()
+            .filter_map(|(_, duration_ms)| *duration_ms)
+            .sum();
+
+        let avg_flow_intensity = if flow_states.is_empty() {
+            0.0
+        } else {
+            flow_states.iter().map(|(intensity, _)| intensity).sum::<f32>()
+                / flow_states.len() as f32
+        };
+
+        let peak_flow_intensity = flow_states
+            .iter()
+            .map(|(intensity, _)| *intensity)
+            .fold(0.0, f32::max);
+
+        Self {
+            total_duration_ms,
+            total_flow_time_ms,
+            avg_flow_intensity,
+            peak_flow_intensity,
+            interruption_count,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EndSessionResponse {
+    pub session_id: Uuid,
+    pub ended_at: chrono::DateTime<chrono::Utc>,
+    /// True when the session had already been ended and the stored
+    /// aggregates were returned unchanged
+    pub already_ended: bool,
+    pub aggregates: SessionAggregates,
+}
+
+/// Longest tag accepted, once normalized.
+pub const MAX_TAG_LENGTH: usize = 50;
+pub const MAX_TAGS_PER_SESSION: usize = 20;
+
+/// Tags to add to a session, or to replace its tags with.
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub struct SessionTagsRequest {
+    #[validate(length(max = 20))]
+    pub tags: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SessionTagsResponse {
+    pub session_id: Uuid,
+    /// Normalized and sorted
+    pub tags: Vec<String>,
+}
+
+/// Trims a tag, collapses its inner whitespace and lowercases it, so
+/// "Deep  Work" and "deep work" are the same tag. `None` when nothing is
+/// left or it is longer than `MAX_TAG_LENGTH`.
+pub fn normalize_tag(tag: &str) -> Option<String> {
+    let tag = tag.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase();
+    (!tag.is_empty() && tag.chars().count() <= MAX_TAG_LENGTH).then_some(tag)
+}
+
+/// Filters for searching session history, alongside a date range over the
+/// sessions' start times. Results are newest first.
+#[derive(Debug, Default, Deserialize, Validate)]
+pub struct SessionSearchQuery {
+    pub tag: Option<String>,
+    /// A key of the session's `language_breakdown`
+    #[validate(length(max = 50))]
+    pub language: Option<String>,
+    /// Only ended sessions have an average intensity, so this excludes
+    /// sessions still running
+    #[validate(range(min = 0.0, max = 1.0))]
+    pub min_intensity: Option<f64>,
+    #[validate(range(min = 1, max = 100))]
+    pub limit: Option<u32>,
+    /// `next_cursor` from the previous page
+    pub cursor: Option<String>,
+}
+
+/// Position of the last session on a search page, in `(start_time, id)`
+/// descending order. Sent to clients as an opaque string.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionSearchCursor {
+    pub start_time: chrono::DateTime<chrono::Utc>,
+    pub id: Uuid,
+}
+
+impl SessionSearchCursor {
+    pub fn encode(&self) -> String {
+        format!("{}_{}", self.start_time.timestamp_micros(), self.id)
+    }
+
+    pub fn decode(cursor: &str) -> Option<Self> {
+        let (micros, id) = cursor.split_once('_')?;
+        Some(Self {
+            start_time: chrono::DateTime::from_timestamp_micros(micros.parse().ok()?)?,
+            id: id.parse().ok()?,
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SessionSearchResult {
+    pub session_id: Uuid,
+    pub started_at: chrono::DateTime<chrono::Utc>,
+    pub ended_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub total_duration_ms: Option<i64>,
+    pub avg_flow_intensity: Option<f64>,
+    pub project_path: Option<String>,
+    pub languages: Vec<String>,
+    pub tags: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SessionSearchResponse {
+    pub sessions: Vec<SessionSearchResult>,
+    /// Send back as `cursor` for the next page; `None` on the last one
+    pub next_cursor: Option<String>,
+}
@@ -0,0 +1,312 @@
+use crate::utils::date_range::DateRange;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use validator::Validate;
+
+/// Roles are ordered by privilege, so `role >= TeamRole::Manager` reads as
+/// "manager or above".
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TeamRole {
+    #[default]
+    Member,
+    Manager,
+    Owner,
+}
+
+impl TeamRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TeamRole::Member => "member",
+            TeamRole::Manager => "manager",
+            TeamRole::Owner => "owner",
+        }
+    }
+
+    pub fn from_db(value: &str) -> Option<Self> {
+        match value {
+            "member" => Some(TeamRole::Member),
+            "manager" => Some(TeamRole::Manager),
+            "owner" => Some(TeamRole::Owner),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub struct CreateTeamRequest {
+    #[validate(length(min = 1, max = 255))]
+    pub name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Team {
+    pub id: Uuid,
+    pub name: String,
+    pub owner_id: Uuid,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub struct CreateTeamAlertRequest {
+    #[validate(length(min = 1, max = 50))]
+    pub alert_type: String,
+    #[serde(default)]
+    pub data: serde_json::Value,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TeamMemberSpec {
+    pub user_id: Uuid,
+    #[serde(default)]
+    pub role: TeamRole,
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub struct AddTeamMembersRequest {
+    #[validate(length(min = 1, max = 100))]
+    pub members: Vec<TeamMemberSpec>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub struct RemoveTeamMembersRequest {
+    #[validate(length(min = 1, max = 100))]
+    pub user_ids: Vec<Uuid>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AddTeamMembersResponse {
+    pub team_id: Uuid,
+    pub added: Vec<Uuid>,
+    /// Requested users that were already on the team and left untouched
+    pub already_members: Vec<Uuid>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RemoveTeamMembersResponse {
+    pub team_id: Uuid,
+    pub removed: Vec<Uuid>,
+    /// Requested users that weren't on the team; nothing was changed for them
+    pub not_members: Vec<Uuid>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TeamGoalMetric {
+    /// Combined flow time of sharing members, per calendar week (UTC,
+    /// starting Monday)
+    #[default]
+    WeeklyFlowHours,
+}
+
+impl TeamGoalMetric {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TeamGoalMetric::WeeklyFlowHours => "weekly_flow_hours",
+        }
+    }
+
+    pub fn from_db(value: &str) -> Option<Self> {
+        match value {
+            "weekly_flow_hours" => Some(TeamGoalMetric::WeeklyFlowHours),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub struct SetTeamGoalRequest {
+    #[serde(default)]
+    pub metric: TeamGoalMetric,
+    /// Target for the whole team, as if every member shared their data
+    #[validate(range(min = 0.5, max = 100000.0))]
+    pub target_value: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct TeamGoal {
+    pub id: Uuid,
+    pub team_id: Uuid,
+    pub metric: TeamGoalMetric,
+    pub target_value: f64,
+    pub last_completed_period: Option<DateTime<Utc>>,
+}
+
+/// One member's input to a goal. Flow time is only ever loaded for members
+/// who consented to share it.
+#[derive(Debug, Clone)]
+pub struct MemberFlowContribution {
+    pub user_id: Uuid,
+    pub data_sharing_consent: bool,
+    pub flow_ms: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TeamGoalProgress {
+    pub goal_id: Uuid,
+    pub team_id: Uuid,
+    pub metric: TeamGoalMetric,
+    pub target_value: f64,
+    /// `target_value` scaled to the members who share their data; this is
+    /// what progress is measured against
+    pub effective_target: f64,
+    pub achieved_value: f64,
+    /// `achieved_value / effective_target`, capped at 1
+    pub progress: f64,
+    pub contributing_members: u32,
+    /// Members left out of both sides of the ratio because they don't share
+    pub excluded_members: u32,
+    pub period_start: DateTime<Utc>,
+    pub period_end: DateTime<Utc>,
+    pub completed: bool,
+    /// Met in this period for the first time; the completion alert is due
+    #[serde(skip)]
+    pub newly_completed: bool,
+}
+
+impl TeamGoal {
+    pub fn progress(
+        &self,
+        members: &[MemberFlowContribution],
+        period: DateRange,
+    ) -> TeamGoalProgress {
+        let (sharing, excluded): (Vec<_>, Vec<_>) =
+            members.iter().partition(|member| member.data_sharing_consent);
+
+        let effective_target = if members.is_empty() {
+            0.0
+        } else {
+            self.target_value * sharing.len() as f64 / members.len() as f64
+        };
+        let achieved_value = match self.metric {
+            TeamGoalMetric::WeeklyFlowHours => {
+                sharing.iter().map(|member| member.flow_ms).sum::<u64>() as f64 / 3_600_000.0
+            }
+        };
+        let progress = if effective_target > 0.0 {
+            (achieved_value / effective_target).min(1.0)
+        } else {
+            0.0
+        };
+        let completed = effective_target > 0.0 && achieved_value >= effective_target;
+
+        TeamGoalProgress {
+            goal_id: self.id,
+            team_id: self.team_id,
+            metric: self.metric,
+            target_value: self.target_value,
+            effective_target,
+            achieved_value,
+            progress,
+            contributing_members: sharing.len() as u32,
+            excluded_members: excluded.len() as u32,
+            period_start: period.from,
+            period_end: period.to,
+            completed,
+            newly_completed: completed && self.last_completed_period != Some(period.from),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FlowSimilarityQuery {
+    pub member_a: Uuid,
+    pub member_b: Uuid,
+}
+
+/// How alike two members' flow habits are, each part from 0 (nothing in
+/// common) to 1 (identical). Only the scores leave the server; the
+/// profiles they are computed from do not.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub struct FlowProfileSimilarity {
+    pub overall: f64,
+    /// Overlap of the hours of the day each member reaches flow
+    pub peak_hours: f64,
+    /// Closeness of their typical flow session length
+    pub session_length: f64,
+    /// Closeness of their typing rhythm, consistency and velocity
+    pub rhythm: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FlowSimilarityResponse {
+    pub team_id: Uuid,
+    pub member_a: Uuid,
+    pub member_b: Uuid,
+    pub similarity: FlowProfileSimilarity,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, TimeZone};
+
+    const HOUR_MS: u64 = 3_600_000;
+
+    fn goal(target_value: f64) -> TeamGoal {
+        TeamGoal {
+            id: Uuid::new_v4(),
+            team_id: Uuid::new_v4(),
+            metric: TeamGoalMetric::WeeklyFlowHours,
+            target_value,
+            last_completed_period: None,
+        }
+    }
+
+    fn member(data_sharing_consent: bool, flow_hours: u64) -> MemberFlowContribution {
+        MemberFlowContribution {
+            user_id: Uuid::new_v4(),
+            data_sharing_consent,
+            flow_ms: flow_hours * HOUR_MS,
+        }
+    }
+
+    fn week() -> DateRange {
+        let monday = Utc.with_ymd_and_hms(2024, 3, 11, 0, 0, 0).unwrap();
+        DateRange {
+            from: monday,
+            to: monday + Duration::days(3),
+        }
+    }
+
+    #[test]
+    fn test_non_sharing_members_leave_the_denominator() {
+        let members = [member(true, 10), member(true, 5), member(false, 40), member(false, 0)];
+
+        let progress = goal(40.0).progress(&members, week());
+        assert_eq!(progress.contributing_members, 2);
+        assert_eq!(progress.excluded_members, 2);
+        // Half the team shares, so half the target applies, and the
+        // non-sharing member's 40h never counts
+        assert_eq!(progress.effective_target, 20.0);
+        assert_eq!(progress.achieved_value, 15.0);
+        assert_eq!(progress.progress, 0.75);
+        assert!(!progress.completed);
+
+        let nobody_shares = goal(40.0).progress(&[member(false, 50)], week());
+        assert_eq!(nobody_shares.effective_target, 0.0);
+        assert!(!nobody_shares.completed);
+    }
+
+    #[test]
+    fn test_completion_is_announced_once_per_period() {
+        let members = [member(true, 12), member(false, 0)];
+        let mut goal = goal(20.0);
+
+        let progress = goal.progress(&members, week());
+        assert!(progress.completed && progress.newly_completed);
+        assert_eq!(progress.progress, 1.0);
+
+        // Once recorded, later checks in the same week stay quiet
+        goal.last_completed_period = Some(progress.period_start);
+        let progress = goal.progress(&members, week());
+        assert!(progress.completed && !progress.newly_completed);
+
+        let next_week = DateRange {
+            from: week().from + Duration::days(7),
+            to: week().to + Duration::days(7),
+        };
+        assert!(goal.progress(&members, next_week).newly_completed);
+    }
+}
@@ -0,0 +1,96 @@
+use crate::{
+    config::AchievementConfig,
+    error::Result,
+    models::achievement::{local_date, AchievementEvent, FlowAchievements},
+};
+use chrono::{DateTime, NaiveDate, Utc};
+use sqlx::{PgConnection, Postgres, Transaction};
+use uuid::Uuid;
+
+/// Loads the user's stored records along with today's date in their
+/// timezone.
+pub async fn load_flow_achievements(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    now: DateTime<Utc>,
+) -> Result<(FlowAchievements, NaiveDate)> {
+    let timezone = sqlx::query_scalar!("SELECT timezone FROM users WHERE id = $1", user_id)
+        .fetch_optional(&mut *conn)
+        .await?
+        .flatten();
+
+    let achievements = sqlx::query!(
+        r#"
+        SELECT current_streak_days, longest_streak_days, last_flow_date,
+               best_session_flow_ms, best_session_id
+        FROM user_flow_achievements
+        WHERE user_id = $1
+        "#,
+        user_id
+    )
+    .fetch_optional(&mut *conn)
+    .await?
+    .map(|row| FlowAchievements {
+        current_streak_days: row.current_streak_days.max(0) as u32,
+        longest_streak_days: row.longest_streak_days.max(0) as u32,
+        last_flow_date: row.last_flow_date,
+        best_session_flow_ms: row.best_session_flow_ms.max(0) as u64,
+        best_session_id: row.best_session_id,
+    })
+    .unwrap_or_default();
+
+    Ok((achievements, local_date(now, timezone.as_deref())))
+}
+
+/// Updates the user's records for a session that ended at `ended_at`, in
+/// the same transaction that stores the session's aggregates. Returns the
+/// records it broke.
+pub async fn record_session_achievements(
+    tx: &mut Transaction<'_, Postgres>,
+    user_id: Uuid,
+    session_id: Uuid,
+    ended_at: DateTime<Utc>,
+    flow_ms: u64,
+    config: &AchievementConfig,
+) -> Result<Vec<AchievementEvent>> {
+    // Serialize concurrent session ends for the same user
+    sqlx::query!(
+        "INSERT INTO user_flow_achievements (user_id) VALUES ($1) ON CONFLICT (user_id) DO NOTHING",
+        user_id
+    )
+    .execute(&mut **tx)
+    .await?;
+    sqlx::query!(
+        "SELECT user_id FROM user_flow_achievements WHERE user_id = $1 FOR UPDATE",
+        user_id
+    )
+    .fetch_one(&mut **tx)
+    .await?;
+
+    let (mut achievements, session_date) =
+        load_flow_achievements(&mut **tx, user_id, ended_at).await?;
+    let events = achievements.record_session(session_id, session_date, flow_ms, config);
+
+    sqlx::query!(
+        r#"
+        UPDATE user_flow_achievements
+        SET current_streak_days = $2,
+            longest_streak_days = $3,
+            last_flow_date = $4,
+            best_session_flow_ms = $5,
+            best_session_id = $6,
+            updated_at = NOW()
+        WHERE user_id = $1
+        "#,
+        user_id,
+        achievements.current_streak_days as i32,
+        achievements.longest_streak_days as i32,
+        achievements.last_flow_date,
+        achievements.best_session_flow_ms as i64,
+        achievements.best_session_id,
+    )
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(events)
+}
@@ -0,0 +1,180 @@
+use crate::models::flow::MetricDelta;
+
+/// Continued fraction terms evaluated before giving up on convergence.
+const MAX_FRACTION_TERMS: usize = 200;
+
+/// Mean and sample variance of one side of a comparison.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SampleSummary {
+    pub count: usize,
+    pub mean: f64,
+    pub variance: f64,
+}
+
+impl SampleSummary {
+    pub fn of(values: &[f64]) -> Self {
+        let count = values.len();
+        if count == 0 {
+            return Self {
+                count,
+                mean: 0.0,
+                variance: 0.0,
+            };
+        }
+
+        let mean = values.iter().sum::<f64>() / count as f64;
+        let variance = if count > 1 {
+            values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (count - 1) as f64
+        } else {
+            0.0
+        };
+        Self {
+            count,
+            mean,
+            variance,
+        }
+    }
+}
+
+/// Compares two samples of one metric.
+pub fn compare_samples(before: &[f64], after: &[f64]) -> MetricDelta {
+    let before = SampleSummary::of(before);
+    let after = SampleSummary::of(after);
+
+    MetricDelta {
+        before: before.mean,
+        after: after.mean,
+        delta: after.mean - before.mean,
+        before_samples: before.count,
+        after_samples: after.count,
+        confidence: difference_confidence(&before, &after),
+    }
+}
+
+/// One minus the two-sided p-value of Welch's t-test, so small or noisy
+/// samples give low confidence even when their means are far apart.
+pub fn difference_confidence(before: &SampleSummary, after: &SampleSummary) -> f64 {
+    if before.count < 2 || after.count < 2 {
+        return 0.0;
+    }
+
+    let before_se = before.variance / before.count as f64;
+    let after_se = after.variance / after.count as f64;
+    let se = before_se + after_se;
+    let difference = (after.mean - before.mean).abs();
+    if se <= f64::EPSILON {
+        // Neither side varies at all
+        return if difference > f64::EPSILON { 1.0 } else { 0.0 };
+    }
+
+    let t = difference / se.sqrt();
+    // Welch–Satterthwaite
+    let df = se.powi(2)
+        / (before_se.powi(2) / (before.count - 1) as f64
+            + after_se.powi(2) / (after.count - 1) as f64);
+
+    (1.0 - two_sided_p_value(t, df)).clamp(0.0, 1.0)
+}
+
+/// P(|T| >= t) for Student's t with `df` degrees of freedom.
+fn two_sided_p_value(t: f64, df: f64) -> f64 {
+    regularized_incomplete_beta(df / 2.0, 0.5, df / (df + t * t))
+}
+
+/// I_x(a, b), by the continued fraction on whichever side converges faster.
+fn regularized_incomplete_beta(a: f64, b: f64, x: f64) -> f64 {
+    if x <= 0.0 {
+        return 0.0;
+    }
+    if x >= 1.0 {
+        return 1.0;
+    }
+
+    let front =
+        (ln_gamma(a + b) - ln_gamma(a) - ln_gamma(b) + a * x.ln() + b * (1.0 - x).ln()).exp();
+    if x < (a + 1.0) / (a + b + 2.0) {
+        front * beta_continued_fraction(a, b, x) / a
+    } else {
+        1.0 - front * beta_continued_fraction(b, a, 1.0 - x) / b
+    }
+}
+
+/// Lentz's method, as in Numerical Recipes' `betacf`.
+fn beta_continued_fraction(a: f64, b: f64, x: f64) -> f64 {
+    const TINY: f64 = 1e-300;
+    let nonzero = |v: f64| if v.abs() < TINY { TINY } else { v };
+
+    let mut c = 1.0;
+    let mut d = 1.0 / nonzero(1.0 - (a + b) * x / (a + 1.0));
+    let mut h = d;
+    for m in 1..=MAX_FRACTION_TERMS {
+        let m = m as f64;
+        let even = m * (b - m) * x / ((a + 2.0 * m - 1.0) * (a + 2.0 * m));
+        d = 1.0 / nonzero(1.0 + even * d);
+        c = nonzero(1.0 + even / c);
+        h *= d * c;
+
+        let odd = -(a + m) * (a + b + m) * x / ((a + 2.0 * m) * (a + 2.0 * m + 1.0));
+        d = 1.0 / nonzero(1.0 + odd * d);
+        c = nonzero(1.0 + odd / c);
+        let step = d * c;
+        h *= step;
+        if (step - 1.0).abs() < 1e-12 {
+            break;
+        }
+    }
+    h
+}
+
+/// Lanczos approximation (g = 7, n = 9).
+fn ln_gamma(x: f64) -> f64 {
+    const COEFFICIENTS: [f64; 9] = [
+        0.999_999_999_999_81,
+        676.520_368_121_885,
+        -1_259.139_216_722_4,
+        771.323_428_777_653,
+        -176.615_029_162_141,
+        12.507_343_278_687,
+        -0.138_571_095_265_72,
+        9.984_369_578_019_57e-6,
+        1.505_632_735_149_31e-7,
+    ];
+
+    if x < 0.5 {
+        // Reflection formula
+        let pi = std::f64::consts::PI;
+        return (pi / (pi * x).sin()).ln() - ln_gamma(1.0 - x);
+    }
+
+    let x = x - 1.0;
+    let sum = COEFFICIENTS[1..]
+        .iter()
+        .enumerate()
+        .fold(COEFFICIENTS[0], |sum, (i, c)| {
+            sum + c / (x + i as f64 + 1.0)
+        });
+    let t = x + 7.5;
+    0.5 * (2.0 * std::f64::consts::PI).ln() + (x + 0.5) * t.ln() - t + sum.ln()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_p_values_match_t_tables() {
+        // Two-sided 5% critical values
+        assert!((two_sided_p_value(2.228, 10.0) - 0.05).abs() < 1e-3);
+        assert!((two_sided_p_value(12.706, 1.0) - 0.05).abs() < 1e-3);
+        assert!((two_sided_p_value(0.0, 10.0) - 1.0).abs() < 1e-9);
+
+        let noisy = compare_samples(&[0.2, 0.9, 0.5], &[0.3, 1.0, 0.6]);
+        assert!((noisy.delta - 0.1).abs() < 1e-9);
+        assert!(noisy.confidence < 0.5, "{:?}", noisy);
+
+        let clear = compare_samples(&[0.40, 0.42, 0.41, 0.39], &[0.80, 0.82, 0.79, 0.81]);
+        assert!(clear.confidence > 0.99, "{:?}", clear);
+
+        assert_eq!(compare_samples(&[0.4], &[0.9, 0.8]).confidence, 0.0);
+    }
+}
@@ -0,0 +1,96 @@
+use crate::error::{AppError, Result};
+
+/// First byte of a stored blob.
+const FORMAT_JSON: u8 = 0;
+const FORMAT_ZSTD: u8 = 1;
+
+const ZSTD_LEVEL: i32 = 3;
+
+/// Encodes a JSON column value as a self-describing blob: zstd-compressed,
+/// or plain JSON when compression wouldn't save anything.
+pub fn encode_json_blob(value: &serde_json::Value) -> Result<Vec<u8>> {
+    let json = serde_json::to_vec(value)
+        .map_err(|e| AppError::Internal(format!("Failed to serialize blob: {}", e)))?;
+    let compressed = zstd::bulk::compress(&json, ZSTD_LEVEL)
+        .map_err(|e| AppError::Internal(format!("Failed to compress blob: {}", e)))?;
+
+    let (format, body) = if compressed.len() < json.len() {
+        (FORMAT_ZSTD, compressed)
+    } else {
+        (FORMAT_JSON, json)
+    };
+
+    let mut blob = Vec::with_capacity(body.len() + 1);
+    blob.push(format);
+    blob.extend_from_slice(&body);
+    Ok(blob)
+}
+
+pub fn decode_json_blob(blob: &[u8]) -> Result<serde_json::Value> {
+    let json = match blob.split_first() {
+        Some((&FORMAT_JSON, json)) => json.to_vec(),
+        Some((&FORMAT_ZSTD, compressed)) => zstd::stream::decode_all(compressed)
+            .map_err(|e| AppError::Internal(format!("Failed to decompress blob: {}", e)))?,
+        Some((format, _)) => {
+            return Err(AppError::Internal(format!("Unknown blob format {}", format)))
+        }
+        None => return Err(AppError::Internal("Empty blob".to_string())),
+    };
+
+    serde_json::from_slice(&json)
+        .map_err(|e| AppError::Internal(format!("Failed to parse blob: {}", e)))
+}
+
+/// Reads a column stored either as a blob or, for rows written without
+/// compression, as plain JSONB.
+pub fn read_json_column(
+    blob: Option<&[u8]>,
+    legacy: Option<serde_json::Value>,
+) -> Result<serde_json::Value> {
+    match blob {
+        Some(blob) => decode_json_blob(blob),
+        None => Ok(legacy.unwrap_or_else(|| serde_json::json!({}))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_blob_round_trip() {
+        let metrics = json!({
+            "rhythm_score": 0.82,
+            "focus_score": 0.91,
+            "consistency_score": 0.77,
+            "velocity_score": 0.64,
+            "error_penalty": 0.05,
+            "history": vec![0.5; 64],
+        });
+
+        let blob = encode_json_blob(&metrics).unwrap();
+        assert_eq!(blob[0], FORMAT_ZSTD);
+        assert!(blob.len() < serde_json::to_vec(&metrics).unwrap().len());
+        assert_eq!(decode_json_blob(&blob).unwrap(), metrics);
+
+        // Too small to compress: stored as plain JSON, still readable
+        let tiny = json!({});
+        let blob = encode_json_blob(&tiny).unwrap();
+        assert_eq!(blob[0], FORMAT_JSON);
+        assert_eq!(decode_json_blob(&blob).unwrap(), tiny);
+
+        assert!(decode_json_blob(&[]).is_err());
+        assert!(decode_json_blob(&[7, 1, 2]).is_err());
+    }
+
+    #[test]
+    fn test_legacy_rows_read_from_jsonb() {
+        let legacy = json!({ "rhythm_score": 0.5 });
+        assert_eq!(read_json_column(None, Some(legacy.clone())).unwrap(), legacy);
+        assert_eq!(read_json_column(None, None).unwrap(), json!({}));
+
+        let blob = encode_json_blob(&legacy).unwrap();
+        assert_eq!(read_json_column(Some(&blob), None).unwrap(), legacy);
+    }
+}
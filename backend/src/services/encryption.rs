@@ -1,17 +1,61 @@
-use crate::error::{AppError, Result};
+use crate::{
+    config::RetentionConfig,
+    error::{AppError, Result},
+    models::audit::AuditOperation,
+    services::{
+        audit::record_audit_entry,
+        export_bundle::{write_export_bundle, ExportManifest},
+        retention::load_retention_days,
+    },
+};
 use aes_gcm::{
-    aead::{Aead, KeyInit, OsRng},
+    aead::{Aead, KeyInit, OsRng, Payload},
     Aes256Gcm, Key, Nonce,
 };
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use dashmap::DashMap;
 use rand::RngCore;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use tracing::{debug, info, warn};
+use zeroize::{Zeroize, Zeroizing};
+
+pub const DEFAULT_SECURE_DELETE_PASSES: u32 = 3;
+
+const KEY_BACKUP_MIN_PASSPHRASE_LEN: usize = 12;
+// Argon2id cost for deriving key-backup KEKs (OWASP baseline)
+const KEY_BACKUP_M_COST_KIB: u32 = 19_456;
+const KEY_BACKUP_T_COST: u32 = 2;
+const KEY_BACKUP_P_COST: u32 = 1;
+
+/// Length of the random per-user salt payload keys are derived with
+pub const PAYLOAD_KEY_SALT_LEN: usize = 16;
 
+/// Ciphers zeroize their expanded keys on drop (aes-gcm `zeroize` feature).
+/// The active master key is only kept, in zeroize-on-drop storage, so that
+/// it can be sealed into operator backups.
 pub struct EncryptionService {
     cipher: Aes256Gcm,
+    master_key: Zeroizing<[u8; 32]>,
     key_id: String,
     rotation_keys: HashMap<String, Aes256Gcm>,
+    secure_delete_passes: u32,
+}
+
+/// The active master key encrypted under a KEK derived from an operator
+/// passphrase with Argon2id. Binary fields are base64; the key id is bound
+/// as associated data, so a backup can't be relabelled as another key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SealedKeyBackup {
+    pub key_id: String,
+    pub kdf: String,
+    pub m_cost_kib: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+    pub salt: String,
+    pub nonce: String,
+    pub sealed_key: String,
+    pub created_at: i64,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -24,11 +68,17 @@ pub struct EncryptedData {
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct PrivacySettings {
-    pub data_retention_days: i32,
+    /// The user's own retention period; `None` follows their tier default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data_retention_days: Option<i32>,
     pub analytics_enabled: bool,
     pub sharing_enabled: bool,
     pub encryption_level: EncryptionLevel,
     pub gdpr_compliant: bool,
+    /// Explicit consent to contribute de-identified feature vectors to the
+    /// model training corpus; only honoured with `analytics_enabled`
+    #[serde(default)]
+    pub telemetry_consent: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -42,50 +92,65 @@ pub enum EncryptionLevel {
 impl Default for PrivacySettings {
     fn default() -> Self {
         Self {
-            data_retention_days: 365,
+            data_retention_days: None,
             analytics_enabled: true,
             sharing_enabled: false,
             encryption_level: EncryptionLevel::Standard,
             gdpr_compliant: true,
+            telemetry_consent: false,
         }
     }
 }
 
+impl PrivacySettings {
+    /// Whether the user's flow results may be added to the telemetry corpus.
+    pub fn telemetry_allowed(&self) -> bool {
+        self.analytics_enabled && self.telemetry_consent
+    }
+
+    /// High and Military levels: sensitive originals are kept only
+    /// encrypted, and keystroke samples only as salted hashes.
+    pub fn is_high_security(&self) -> bool {
+        matches!(
+            self.encryption_level,
+            EncryptionLevel::High | EncryptionLevel::Military
+        )
+    }
+}
+
 impl EncryptionService {
     pub fn new(master_key: &[u8; 32]) -> Result<Self> {
         let key = Key::<Aes256Gcm>::from_slice(master_key);
         let cipher = Aes256Gcm::new(key);
         let key_id = format!("key_{}", chrono::Utc::now().timestamp());
 
-        info!("✅ Encryption service initialized with AES-256-GCM");
-
         Ok(Self {
             cipher,
+            master_key: Zeroizing::new(*master_key),
             key_id,
             rotation_keys: HashMap::new(),
+            secure_delete_passes: DEFAULT_SECURE_DELETE_PASSES,
         })
     }
 
-    pub fn from_hex_key(hex_key: &str) -> Result<Self> {
-        if hex_key.len() != 64 {
-            return Err(AppError::Encryption(
-                "Master key must be 32 bytes (64 hex characters)".to_string(),
-            ));
-        }
-
-        let mut key_bytes = [0u8; 32];
-        hex::decode_to_slice(hex_key, &mut key_bytes)
-            .map_err(|e| AppError::Encryption(format!("Invalid hex key: {}", e)))?;
+    /// Random overwrites `secure_delete_data` makes before the final zeroing.
+    pub fn with_secure_delete_passes(mut self, passes: u32) -> Self {
+        self.secure_delete_passes = passes;
+        self
+    }
 
-        Self::new(&key_bytes)
+    pub fn from_hex_key(hex_key: &str) -> Result<Self> {
+        Self::new(&decode_hex_key(hex_key)?)
     }
 
     pub fn encrypt_sensitive_data<T>(&self, data: &T) -> Result<EncryptedData>
     where
         T: Serialize,
     {
-        let serialized = serde_json::to_vec(data)
-            .map_err(|e| AppError::Encryption(format!("Serialization failed: {}", e)))?;
+        let serialized = Zeroizing::new(
+            serde_json::to_vec(data)
+                .map_err(|e| AppError::Encryption(format!("Serialization failed: {}", e)))?,
+        );
 
         let mut nonce_bytes = [0u8; 12];
         OsRng.fill_bytes(&mut nonce_bytes);
@@ -93,7 +158,7 @@ impl EncryptionService {
 
         let encrypted = self
             .cipher
-            .encrypt(nonce, serialized.as_ref())
+            .encrypt(nonce, serialized.as_slice())
             .map_err(|e| AppError::Encryption(format!("Encryption failed: {}", e)))?;
 
         debug!("Encrypted {} bytes of sensitive data", serialized.len());
@@ -125,9 +190,11 @@ impl EncryptionService {
 
         let nonce = Nonce::from_slice(&encrypted_data.nonce);
 
-        let decrypted = cipher
-            .decrypt(nonce, encrypted_data.data.as_ref())
-            .map_err(|e| AppError::Encryption(format!("Decryption failed: {}", e)))?;
+        let decrypted = Zeroizing::new(
+            cipher
+                .decrypt(nonce, encrypted_data.data.as_ref())
+                .map_err(|e| AppError::Encryption(format!("Decryption failed: {}", e)))?,
+        );
 
         let data = serde_json::from_slice(&decrypted)
             .map_err(|e| AppError::Encryption(format!("Deserialization failed: {}", e)))?;
@@ -148,11 +215,11 @@ impl EncryptionService {
             .map_err(|e| AppError::Encryption(format!("Field encryption failed: {}", e)))?;
 
         let combined = [nonce_bytes.as_slice(), &encrypted].concat();
-        Ok(base64::encode(combined))
+        Ok(BASE64.encode(combined))
     }
 
     pub fn decrypt_field(&self, encrypted_field: &str) -> Result<String> {
-        let combined = base64::decode(encrypted_field)
+        let combined = BASE64.decode(encrypted_field)
             .map_err(|e| AppError::Encryption(format!("Base64 decode failed: {}", e)))?;
 
         if combined.len() < 12 {
@@ -167,11 +234,25 @@ impl EncryptionService {
             .decrypt(nonce, encrypted_data)
             .map_err(|e| AppError::Encryption(format!("Field decryption failed: {}", e)))?;
 
-        String::from_utf8(decrypted)
-            .map_err(|e| AppError::Encryption(format!("UTF-8 decode failed: {}", e)))
+        // On success the plaintext buffer moves into the String without a copy
+        String::from_utf8(decrypted).map_err(|e| {
+            let reason = e.utf8_error();
+            e.into_bytes().zeroize();
+            AppError::Encryption(format!("UTF-8 decode failed: {}", reason))
+        })
     }
 
     pub fn rotate_key(&mut self, new_master_key: &[u8; 32]) -> Result<String> {
+        self.rotate_key_as(new_master_key, format!("key_{}", chrono::Utc::now().timestamp()))
+    }
+
+    /// Rotates to `new_master_key` under a caller-chosen id, as persisted
+    /// by the key rotation schedule. Returns the old key id.
+    pub fn rotate_key_as(&mut self, new_master_key: &[u8; 32], key_id: String) -> Result<String> {
+        if self.has_key(&key_id) {
+            return Err(AppError::Encryption(format!("Key id {} is already in use", key_id)));
+        }
+
         let old_key_id = self.key_id.clone();
         let old_cipher = std::mem::replace(
             &mut self.cipher,
@@ -180,18 +261,120 @@ impl EncryptionService {
 
         // Store old key for decrypting existing data
         self.rotation_keys.insert(old_key_id.clone(), old_cipher);
+        self.master_key.copy_from_slice(new_master_key);
 
-        // Generate new key ID
-        self.key_id = format!("key_{}", chrono::Utc::now().timestamp());
+        self.key_id = key_id;
 
         info!("🔄 Encryption key rotated. Old key ID: {}", old_key_id);
 
         Ok(old_key_id)
     }
 
-    pub fn generate_master_key() -> [u8; 32] {
-        let mut key = [0u8; 32];
-        OsRng.fill_bytes(&mut key);
+    /// Seals the active master key for disaster recovery. Every call uses a
+    /// fresh salt and nonce, so regenerating a backup never reuses a KEK.
+    pub fn seal_key_backup(&self, passphrase: &str) -> Result<SealedKeyBackup> {
+        if passphrase.chars().count() < KEY_BACKUP_MIN_PASSPHRASE_LEN {
+            return Err(AppError::Validation(format!(
+                "Backup passphrase must be at least {} characters",
+                KEY_BACKUP_MIN_PASSPHRASE_LEN
+            )));
+        }
+
+        let mut salt = [0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+        let mut nonce_bytes = [0u8; 12];
+        OsRng.fill_bytes(&mut nonce_bytes);
+
+        let kek = derive_backup_kek(
+            passphrase,
+            &salt,
+            KEY_BACKUP_M_COST_KIB,
+            KEY_BACKUP_T_COST,
+            KEY_BACKUP_P_COST,
+        )?;
+        let sealed_key = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(kek.as_ref()))
+            .encrypt(
+                Nonce::from_slice(&nonce_bytes),
+                Payload {
+                    msg: self.master_key.as_ref(),
+                    aad: self.key_id.as_bytes(),
+                },
+            )
+            .map_err(|e| AppError::Encryption(format!("Key backup sealing failed: {}", e)))?;
+
+        Ok(SealedKeyBackup {
+            key_id: self.key_id.clone(),
+            kdf: "argon2id".to_string(),
+            m_cost_kib: KEY_BACKUP_M_COST_KIB,
+            t_cost: KEY_BACKUP_T_COST,
+            p_cost: KEY_BACKUP_P_COST,
+            salt: BASE64.encode(salt),
+            nonce: BASE64.encode(nonce_bytes),
+            sealed_key: BASE64.encode(sealed_key),
+            created_at: chrono::Utc::now().timestamp_millis(),
+        })
+    }
+
+    /// Recovers the master key from a sealed backup. A wrong passphrase and a
+    /// tampered backup are indistinguishable and both fail.
+    pub fn unseal_key_backup(
+        backup: &SealedKeyBackup,
+        passphrase: &str,
+    ) -> Result<Zeroizing<[u8; 32]>> {
+        if backup.kdf != "argon2id" {
+            return Err(AppError::Encryption(format!(
+                "Unsupported key backup KDF: {}",
+                backup.kdf
+            )));
+        }
+
+        let decode = |field: &str| {
+            BASE64
+                .decode(field)
+                .map_err(|e| AppError::Encryption(format!("Invalid key backup encoding: {}", e)))
+        };
+        let salt = decode(&backup.salt)?;
+        let nonce_bytes = decode(&backup.nonce)?;
+        let sealed_key = decode(&backup.sealed_key)?;
+        if nonce_bytes.len() != 12 {
+            return Err(AppError::Encryption("Invalid key backup nonce".to_string()));
+        }
+
+        let kek = derive_backup_kek(
+            passphrase,
+            &salt,
+            backup.m_cost_kib,
+            backup.t_cost,
+            backup.p_cost,
+        )?;
+        let key = Zeroizing::new(
+            Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(kek.as_ref()))
+                .decrypt(
+                    Nonce::from_slice(&nonce_bytes),
+                    Payload {
+                        msg: &sealed_key,
+                        aad: backup.key_id.as_bytes(),
+                    },
+                )
+                .map_err(|_| {
+                    AppError::Encryption(
+                        "Key backup could not be unsealed: wrong passphrase or corrupted backup"
+                            .to_string(),
+                    )
+                })?,
+        );
+
+        let mut master_key = Zeroizing::new([0u8; 32]);
+        if key.len() != master_key.len() {
+            return Err(AppError::Encryption("Invalid key backup length".to_string()));
+        }
+        master_key.copy_from_slice(&key);
+        Ok(master_key)
+    }
+
+    pub fn generate_master_key() -> Zeroizing<[u8; 32]> {
+        let mut key = Zeroizing::new([0u8; 32]);
+        OsRng.fill_bytes(key.as_mut());
         key
     }
 
@@ -201,11 +384,11 @@ impl EncryptionService {
 
     pub async fn secure_delete_data(&self, data: &mut [u8]) {
         // Overwrite with random data multiple times for secure deletion
-        for _ in 0..3 {
+        for _ in 0..self.secure_delete_passes {
             OsRng.fill_bytes(data);
         }
-        // Final overwrite with zeros
-        data.fill(0);
+        // Final overwrite with zeros; volatile so it isn't optimized away
+        data.zeroize();
     }
 
     pub fn get_current_key_id(&self) -> &str {
@@ -216,6 +399,28 @@ impl EncryptionService {
         key_id == self.key_id || self.rotation_keys.contains_key(key_id)
     }
 
+    /// Replaces every key with ones restored from storage: the active key
+    /// and the retired keys still needed for decryption.
+    pub fn restore_keys(
+        &mut self,
+        active_key_id: String,
+        active_key: &[u8; 32],
+        retired: Vec<(String, Zeroizing<[u8; 32]>)>,
+    ) {
+        self.cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(active_key));
+        self.master_key.copy_from_slice(active_key);
+        self.key_id = active_key_id;
+        self.rotation_keys = retired
+            .into_iter()
+            .map(|(key_id, key)| (key_id, Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key.as_ref()))))
+            .collect();
+    }
+
+    /// Forgets a retired key; the active key can't be removed.
+    pub fn remove_rotation_key(&mut self, key_id: &str) -> bool {
+        self.rotation_keys.remove(key_id).is_some()
+    }
+
     pub fn cleanup_old_keys(&mut self, max_age_days: i64) {
         let cutoff_timestamp = chrono::Utc::now().timestamp() - (max_age_days * 24 * 60 * 60);
         
@@ -243,7 +448,143 @@ impl EncryptionService {
     }
 }
 
-#[derive(Debug, Clone)]
+/// Parses a 32-byte master key given as 64 hex characters.
+pub fn decode_hex_key(hex_key: &str) -> Result<Zeroizing<[u8; 32]>> {
+    if hex_key.len() != 64 {
+        return Err(AppError::Encryption(
+            "Master key must be 32 bytes (64 hex characters)".to_string(),
+        ));
+    }
+
+    let mut key_bytes = Zeroizing::new([0u8; 32]);
+    hex::decode_to_slice(hex_key, key_bytes.as_mut())
+        .map_err(|e| AppError::Encryption(format!("Invalid hex key: {}", e)))?;
+    Ok(key_bytes)
+}
+
+/// Cipher of a sealed WebSocket payload field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PayloadCipher {
+    /// Base64 of the 12-byte nonce followed by the ciphertext, with the
+    /// recipient's user id bytes as associated data
+    #[serde(rename = "aes-256-gcm")]
+    Aes256Gcm,
+}
+
+/// Derives the key a user's sensitive WebSocket payloads are sealed with,
+/// at login, from their password and the random salt stored for them. The
+/// server secret is Argon2id's secret input, so payloads captured off the
+/// wire can't be attacked offline by guessing passwords. Uses the key
+/// backup Argon2id costs.
+pub fn derive_payload_key(
+    password: &str,
+    salt: &[u8],
+    server_secret: &[u8],
+) -> Result<Zeroizing<[u8; 32]>> {
+    use argon2::{Algorithm, Argon2, Params, Version};
+
+    let params = Params::new(
+        KEY_BACKUP_M_COST_KIB,
+        KEY_BACKUP_T_COST,
+        KEY_BACKUP_P_COST,
+        Some(32),
+    )
+    .map_err(|e| AppError::Encryption(format!("Invalid payload KDF parameters: {}", e)))?;
+
+    let mut key = Zeroizing::new([0u8; 32]);
+    Argon2::new_with_secret(server_secret, Algorithm::Argon2id, Version::V0x13, params)
+        .and_then(|argon2| argon2.hash_password_into(password.as_bytes(), salt, key.as_mut()))
+        .map_err(|e| AppError::Encryption(format!("Payload key derivation failed: {}", e)))?;
+
+    Ok(key)
+}
+
+/// Reverses `PayloadKeys::seal` with the client's copy of the key, the one
+/// handed over in the login response.
+pub fn open_payload(key: &[u8; 32], user_id: uuid::Uuid, sealed: &str) -> Result<String> {
+    let combined = BASE64
+        .decode(sealed)
+        .map_err(|e| AppError::Encryption(format!("Base64 decode failed: {}", e)))?;
+    if combined.len() < 12 {
+        return Err(AppError::Encryption(
+            "Invalid sealed payload format".to_string(),
+        ));
+    }
+
+    let (nonce_bytes, ciphertext) = combined.split_at(12);
+    let plaintext = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key))
+        .decrypt(
+            Nonce::from_slice(nonce_bytes),
+            Payload {
+                msg: ciphertext,
+                aad: user_id.as_bytes(),
+            },
+        )
+        .map_err(|e| AppError::Encryption(format!("Payload decryption failed: {}", e)))?;
+
+    String::from_utf8(plaintext)
+        .map_err(|e| AppError::Encryption(format!("UTF-8 decode failed: {}", e.utf8_error())))
+}
+
+/// Payload keys of signed-in high-security users. They are only ever held
+/// in memory, so after a restart a user has none until they sign in again.
+#[derive(Default)]
+pub struct PayloadKeys {
+    ciphers: DashMap<uuid::Uuid, Aes256Gcm>,
+}
+
+impl PayloadKeys {
+    pub fn insert(&self, user_id: uuid::Uuid, key: &[u8; 32]) {
+        self.ciphers
+            .insert(user_id, Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key)));
+    }
+
+    pub fn remove(&self, user_id: uuid::Uuid) -> bool {
+        self.ciphers.remove(&user_id).is_some()
+    }
+
+    /// Seals `plaintext` for the user, or `None` when no key is held for
+    /// them.
+    pub fn seal(&self, user_id: uuid::Uuid, plaintext: &str) -> Option<Result<String>> {
+        let cipher = self.ciphers.get(&user_id)?;
+
+        let mut nonce_bytes = [0u8; 12];
+        OsRng.fill_bytes(&mut nonce_bytes);
+        let sealed = cipher
+            .encrypt(
+                Nonce::from_slice(&nonce_bytes),
+                Payload {
+                    msg: plaintext.as_bytes(),
+                    aad: user_id.as_bytes(),
+                },
+            )
+            .map(|ciphertext| BASE64.encode([nonce_bytes.as_slice(), &ciphertext].concat()))
+            .map_err(|e| AppError::Encryption(format!("Payload encryption failed: {}", e)));
+        Some(sealed)
+    }
+}
+
+fn derive_backup_kek(
+    passphrase: &str,
+    salt: &[u8],
+    m_cost_kib: u32,
+    t_cost: u32,
+    p_cost: u32,
+) -> Result<Zeroizing<[u8; 32]>> {
+    use argon2::{Algorithm, Argon2, Params, Version};
+
+    let params = Params::new(m_cost_kib, t_cost, p_cost, Some(32))
+        .map_err(|e| AppError::Encryption(format!("Invalid key backup KDF parameters: {}", e)))?;
+
+    let mut kek = Zeroizing::new([0u8; 32]);
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, kek.as_mut())
+        .map_err(|e| AppError::Encryption(format!("Key derivation failed: {}", e)))?;
+
+    Ok(kek)
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct GdprDataExport {
     pub user_id: uuid::Uuid,
     pub exported_at: chrono::DateTime<chrono::Utc>,
@@ -252,7 +593,7 @@ pub struct GdprDataExport {
     pub format: ExportFormat,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct DataCategory {
     pub category: String,
     pub record_count: u32,
@@ -260,11 +601,26 @@ pub struct DataCategory {
     pub retention_period_days: i32,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum ExportFormat {
+    #[default]
     Json,
     Csv,
     Xml,
+    /// Columnar flow history for analytics pipelines
+    Parquet,
+}
+
+impl ExportFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ExportFormat::Json => "json",
+            ExportFormat::Csv => "csv",
+            ExportFormat::Xml => "xml",
+            ExportFormat::Parquet => "parquet",
+        }
+    }
 }
 
 pub struct PrivacyManager {
@@ -279,10 +635,16 @@ impl PrivacyManager {
     pub async fn export_user_data(
         &self,
         db: &sqlx::PgPool,
+        actor_id: uuid::Uuid,
         user_id: uuid::Uuid,
         format: ExportFormat,
+        retention: &RetentionConfig,
     ) -> Result<GdprDataExport> {
         let mut data_categories = Vec::new();
+        // Sessions and their flow states are kept as long as the sweeper keeps them
+        let retention_days = load_retention_days(&mut *db.acquire().await?, user_id, retention)
+            .await?
+            .ok_or_else(|| AppError::NotFound("User not found".to_string()))?;
 
         // Export coding sessions
         let sessions_count = sqlx::query_scalar!(
@@ -298,7 +660,7 @@ impl PrivacyManager {
                 category: "coding_sessions".to_string(),
                 record_count: sessions_count,
                 encrypted: false,
-                retention_period_days: 365,
+                retention_period_days: retention_days,
             });
         }
 
@@ -321,7 +683,7 @@ impl PrivacyManager {
                 category: "flow_states".to_string(),
                 record_count: flow_states_count,
                 encrypted: false,
-                retention_period_days: 365,
+                retention_period_days: retention_days,
             });
         }
 
@@ -345,6 +707,17 @@ impl PrivacyManager {
 
         let total_records = data_categories.iter().map(|c| c.record_count).sum();
 
+        let mut tx = db.begin().await?;
+        record_audit_entry(
+            &mut tx,
+            Some(actor_id),
+            Some(user_id),
+            AuditOperation::Export,
+            serde_json::json!({ "total_records": total_records }),
+        )
+        .await?;
+        tx.commit().await?;
+
         info!("📊 GDPR export prepared for user {}: {} records", user_id, total_records);
 
         Ok(GdprDataExport {
@@ -356,9 +729,56 @@ impl PrivacyManager {
         })
     }
 
+    /// `export_user_data` as a single zip streamed into `sink`, with a file
+    /// per non-empty category and a manifest of what the archive holds.
+    /// `page_written` is awaited as the archive grows; see
+    /// `write_export_bundle`.
+    pub async fn export_user_data_archive<W, P, PFut>(
+        &self,
+        db: &sqlx::PgPool,
+        actor_id: uuid::Uuid,
+        user_id: uuid::Uuid,
+        format: ExportFormat,
+        sink: W,
+        page_written: P,
+    ) -> Result<(W, ExportManifest)>
+    where
+        W: std::io::Write + Send,
+        P: FnMut() -> PFut,
+        PFut: std::future::Future<Output = Result<()>>,
+    {
+        let (sink, manifest) =
+            write_export_bundle(db, user_id, format, sink, page_written).await?;
+
+        let mut tx = db.begin().await?;
+        record_audit_entry(
+            &mut tx,
+            Some(actor_id),
+            Some(user_id),
+            AuditOperation::Export,
+            serde_json::json!({
+                "total_records": manifest.total_records,
+                "format": format.as_str(),
+                "archive": true,
+            }),
+        )
+        .await?;
+        tx.commit().await?;
+
+        info!(
+            "📊 GDPR export archive written for user {}: {} records in {} files",
+            user_id,
+            manifest.total_records,
+            manifest.files.len()
+        );
+
+        Ok((sink, manifest))
+    }
+
     pub async fn delete_user_data(
         &self,
         db: &sqlx::PgPool,
+        actor_id: uuid::Uuid,
         user_id: uuid::Uuid,
     ) -> Result<u32> {
         let mut tx = db.begin().await?;
@@ -414,6 +834,15 @@ impl PrivacyManager {
         .rows_affected();
         deleted_count += user_deleted;
 
+        record_audit_entry(
+            &mut tx,
+            Some(actor_id),
+            Some(user_id),
+            AuditOperation::Purge,
+            serde_json::json!({ "records_deleted": deleted_count }),
+        )
+        .await?;
+
         tx.commit().await?;
 
         info!("🗑️ GDPR deletion completed for user {}: {} records deleted", user_id, deleted_count);
@@ -424,6 +853,7 @@ impl PrivacyManager {
     pub async fn anonymize_user_data(
         &self,
         db: &sqlx::PgPool,
+        actor_id: uuid::Uuid,
         user_id: uuid::Uuid,
     ) -> Result<u32> {
         let mut tx = db.begin().await?;
@@ -460,6 +890,31 @@ impl PrivacyManager {
         .rows_affected();
         anonymized_count += sessions_updated;
 
+        // The corpus itself holds nothing of theirs, only which cells they
+        // were counted in
+        sqlx::query!(
+            "DELETE FROM telemetry_contributions WHERE user_id = $1",
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        // Failed writes waiting for investigation still name them
+        sqlx::query!(
+            "DELETE FROM flow_write_dead_letters WHERE user_id = $1",
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        record_audit_entry(
+            &mut tx,
+            Some(actor_id),
+            Some(user_id),
+            AuditOperation::Anonymize,
+            serde_json::json!({ "records_anonymized": anonymized_count }),
+        )
+        .await?;
+
         tx.commit().await?;
 
         info!("🎭 Data anonymization completed for user {}: {} records anonymized", user_id, anonymized_count);
@@ -0,0 +1,87 @@
+use crate::services::flow::FlowDetectionEngine;
+use parking_lot::Mutex;
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    ops::{Deref, DerefMut},
+    sync::Arc,
+};
+
+/// Idle flow engines kept for stateless scoring, where every request would
+/// otherwise build an engine and drop it again. Engines are shelved per
+/// worker thread, so busy workers don't contend for one list, and are reset
+/// on the way back so nothing from one request reaches the next.
+pub struct FlowEnginePool {
+    workers: Vec<Mutex<Vec<FlowDetectionEngine>>>,
+    per_worker: usize,
+}
+
+impl FlowEnginePool {
+    /// Keeps up to `per_worker` idle engines for each of `workers` threads;
+    /// a `per_worker` of 0 turns pooling off.
+    pub fn new(workers: usize, per_worker: usize) -> Self {
+        Self {
+            workers: (0..workers.max(1))
+                .map(|_| Mutex::new(Vec::new()))
+                .collect(),
+            per_worker,
+        }
+    }
+
+    /// An idle engine from this worker's shelf, or one from `create` when
+    /// it is empty. The engine goes back on the shelf when dropped.
+    pub fn acquire(self: &Arc<Self>, create: impl FnOnce() -> FlowDetectionEngine) -> PooledEngine {
+        let engine = self.shelf().lock().pop().unwrap_or_else(create);
+        PooledEngine {
+            pool: self.clone(),
+            engine: Some(engine),
+        }
+    }
+
+    /// Idle engines across all workers.
+    pub fn idle(&self) -> usize {
+        self.workers.iter().map(|shelf| shelf.lock().len()).sum()
+    }
+
+    fn shelf(&self) -> &Mutex<Vec<FlowDetectionEngine>> {
+        let mut hasher = DefaultHasher::new();
+        std::thread::current().id().hash(&mut hasher);
+        &self.workers[hasher.finish() as usize % self.workers.len()]
+    }
+
+    fn release(&self, mut engine: FlowDetectionEngine) {
+        engine.reset();
+        let mut shelf = self.shelf().lock();
+        if shelf.len() < self.per_worker {
+            shelf.push(engine);
+        }
+    }
+}
+
+/// An engine on loan from a `FlowEnginePool`.
+pub struct PooledEngine {
+    pool: Arc<FlowEnginePool>,
+    engine: Option<FlowDetectionEngine>,
+}
+
+impl Deref for PooledEngine {
+    type Target = FlowDetectionEngine;
+
+    fn deref(&self) -> &Self::Target {
+        self.engine.as_ref().expect("engine is only taken on drop")
+    }
+}
+
+impl DerefMut for PooledEngine {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.engine.as_mut().expect("engine is only taken on drop")
+    }
+}
+
+impl Drop for PooledEngine {
+    fn drop(&mut self) {
+        if let Some(engine) = self.engine.take() {
+            self.pool.release(engine);
+        }
+    }
+}
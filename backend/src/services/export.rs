@@ -0,0 +1,219 @@
+use crate::error::{AppError, Result};
+use arrow::{
+    array::{
+        ArrayRef, Float64Builder, Int32Builder, Int64Builder, StringBuilder,
+        TimestampMicrosecondBuilder,
+    },
+    datatypes::{DataType, Field, Schema, SchemaRef, TimeUnit},
+    record_batch::RecordBatch,
+};
+use chrono::{DateTime, Utc};
+use parquet::{arrow::ArrowWriter, basic::Compression, file::properties::WriterProperties};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::{io::Write, sync::Arc};
+use uuid::Uuid;
+
+/// Rows fetched per page and written per Parquet row group.
+pub const DEFAULT_ROW_GROUP_SIZE: usize = 10_000;
+
+/// One exported `flow_states` row, in the column order of
+/// [`flow_states_schema`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FlowStateExportRow {
+    pub id: Uuid,
+    pub session_id: Uuid,
+    pub start_time: DateTime<Utc>,
+    pub end_time: Option<DateTime<Utc>>,
+    pub duration_ms: Option<i64>,
+    pub intensity_score: f64,
+    pub context_switches: Option<i32>,
+    pub confidence_score: Option<f64>,
+    pub data_quality: Option<f64>,
+}
+
+/// Ids are strings and timestamps UTC microseconds, which every common
+/// warehouse loader maps without custom type handling.
+pub fn flow_states_schema() -> SchemaRef {
+    let timestamp = DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into()));
+
+    Arc::new(Schema::new(vec![
+        Field::new("id", DataType::Utf8, false),
+        Field::new("session_id", DataType::Utf8, false),
+        Field::new("start_time", timestamp.clone(), false),
+        Field::new("end_time", timestamp, true),
+        Field::new("duration_ms", DataType::Int64, true),
+        Field::new("intensity_score", DataType::Float64, false),
+        Field::new("context_switches", DataType::Int32, true),
+        Field::new("confidence_score", DataType::Float64, true),
+        Field::new("data_quality", DataType::Float64, true),
+    ]))
+}
+
+/// Writes flow-state rows to Parquet one row group at a time, so an export
+/// only ever holds a single page of rows in memory.
+pub struct FlowStateParquetWriter<W: Write + Send> {
+    writer: ArrowWriter<W>,
+    schema: SchemaRef,
+    rows_written: usize,
+}
+
+impl<W: Write + Send> FlowStateParquetWriter<W> {
+    pub fn new(sink: W, row_group_size: usize) -> Result<Self> {
+        let schema = flow_states_schema();
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .set_max_row_group_size(row_group_size.max(1))
+            .build();
+
+        let writer = ArrowWriter::try_new(sink, schema.clone(), Some(properties))
+            .map_err(|e| AppError::Internal(format!("Parquet export failed: {}", e)))?;
+
+        Ok(Self {
+            writer,
+            schema,
+            rows_written: 0,
+        })
+    }
+
+    pub fn write_row_group(&mut self, rows: &[FlowStateExportRow]) -> Result<()> {
+        if rows.is_empty() {
+            return Ok(());
+        }
+
+        let batch = self.record_batch(rows)?;
+        self.writer
+            .write(&batch)
+            .and_then(|_| self.writer.flush())
+            .map_err(|e| AppError::Internal(format!("Parquet export failed: {}", e)))?;
+
+        self.rows_written += rows.len();
+        Ok(())
+    }
+
+    /// Writes the footer and hands back the sink with the number of rows.
+    pub fn finish(self) -> Result<(W, usize)> {
+        let sink = self
+            .writer
+            .into_inner()
+            .map_err(|e| AppError::Internal(format!("Parquet export failed: {}", e)))?;
+
+        Ok((sink, self.rows_written))
+    }
+
+    fn record_batch(&self, rows: &[FlowStateExportRow]) -> Result<RecordBatch> {
+        let mut id = StringBuilder::new();
+        let mut session_id = StringBuilder::new();
+        let mut start_time = TimestampMicrosecondBuilder::new().with_timezone("UTC");
+        let mut end_time = TimestampMicrosecondBuilder::new().with_timezone("UTC");
+        let mut duration_ms = Int64Builder::new();
+        let mut intensity_score = Float64Builder::new();
+        let mut context_switches = Int32Builder::new();
+        let mut confidence_score = Float64Builder::new();
+        let mut data_quality = Float64Builder::new();
+
+        for row in rows {
+            id.append_value(row.id.to_string());
+            session_id.append_value(row.session_id.to_string());
+            start_time.append_value(row.start_time.timestamp_micros());
+            end_time.append_option(row.end_time.map(|t| t.timestamp_micros()));
+            duration_ms.append_option(row.duration_ms);
+            intensity_score.append_value(row.intensity_score);
+            context_switches.append_option(row.context_switches);
+            confidence_score.append_option(row.confidence_score);
+            data_quality.append_option(row.data_quality);
+        }
+
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(id.finish()),
+            Arc::new(session_id.finish()),
+            Arc::new(start_time.finish()),
+            Arc::new(end_time.finish()),
+            Arc::new(duration_ms.finish()),
+            Arc::new(intensity_score.finish()),
+            Arc::new(context_switches.finish()),
+            Arc::new(confidence_score.finish()),
+            Arc::new(data_quality.finish()),
+        ];
+
+        RecordBatch::try_new(self.schema.clone(), columns)
+            .map_err(|e| AppError::Internal(format!("Parquet export failed: {}", e)))
+    }
+}
+
+/// One keyset page of a user's flow states, ordered by `(start_time, id)`.
+pub async fn fetch_flow_state_page(
+    db: &PgPool,
+    user_id: Uuid,
+    after: Option<(DateTime<Utc>, Uuid)>,
+    limit: usize,
+) -> Result<Vec<FlowStateExportRow>> {
+    let (after_time, after_id) = after.unzip();
+
+    let rows = sqlx::query!(
+        r#"
+        SELECT
+            fs.id,
+            fs.session_id,
+            fs.start_time,
+            fs.end_time,
+            fs.duration_ms,
+            fs.intensity_score::FLOAT8 as "intensity_score!",
+            fs.context_switches,
+            fs.confidence_score::FLOAT8 as confidence_score,
+            fs.data_quality
+        FROM flow_states fs
+        JOIN coding_sessions cs ON cs.id = fs.session_id
+        WHERE cs.user_id = $1
+          AND ($2::TIMESTAMPTZ IS NULL OR (fs.start_time, fs.id) > ($2, $3::UUID))
+        ORDER BY fs.start_time, fs.id
+        LIMIT $4
+        "#,
+        user_id,
+        after_time,
+        after_id,
+        limit as i64,
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| FlowStateExportRow {
+            id: row.id,
+            session_id: row.session_id,
+            start_time: row.start_time,
+            end_time: row.end_time,
+            duration_ms: row.duration_ms,
+            intensity_score: row.intensity_score,
+            context_switches: row.context_switches,
+            confidence_score: row.confidence_score,
+            data_quality: row.data_quality,
+        })
+        .collect())
+}
+
+/// Streams all of a user's flow states into `sink` as Parquet, one page
+/// per row group. Returns the sink and the number of rows written.
+pub async fn export_flow_states_parquet<W: Write + Send>(
+    db: &PgPool,
+    user_id: Uuid,
+    sink: W,
+    row_group_size: usize,
+) -> Result<(W, usize)> {
+    let row_group_size = row_group_size.max(1);
+    let mut writer = FlowStateParquetWriter::new(sink, row_group_size)?;
+    let mut after = None;
+
+    loop {
+        let page = fetch_flow_state_page(db, user_id, after, row_group_size).await?;
+        writer.write_row_group(&page)?;
+
+        match page.last() {
+            Some(last) if page.len() == row_group_size => after = Some((last.start_time, last.id)),
+            _ => break,
+        }
+    }
+
+    writer.finish()
+}
@@ -0,0 +1,390 @@
+use crate::{
+    error::{AppError, Result},
+    services::{
+        encryption::ExportFormat,
+        export::{fetch_flow_state_page, FlowStateExportRow, DEFAULT_ROW_GROUP_SIZE},
+    },
+};
+use axum::body::Bytes;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::{fmt::Display, future::Future, io::Write, sync::Arc};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+use zip::{
+    write::{SimpleFileOptions, StreamWriter},
+    CompressionMethod, ZipWriter,
+};
+
+/// Name of the archive entry describing the rest of the bundle.
+pub const EXPORT_MANIFEST_FILE: &str = "manifest.json";
+
+/// What a GDPR export archive holds, written into it as
+/// [`EXPORT_MANIFEST_FILE`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportManifest {
+    pub user_id: Uuid,
+    pub exported_at: DateTime<Utc>,
+    pub format: ExportFormat,
+    /// One per non-empty category, in archive order
+    pub files: Vec<ExportManifestFile>,
+    pub total_records: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportManifestFile {
+    pub category: String,
+    pub file: String,
+    pub record_count: usize,
+    /// Records are still ciphertext, exported as stored
+    pub encrypted: bool,
+}
+
+/// One exported `coding_sessions` row. Flat, so it maps onto CSV columns.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionExportRow {
+    pub id: Uuid,
+    pub start_time: DateTime<Utc>,
+    pub end_time: Option<DateTime<Utc>>,
+    pub total_duration_ms: Option<i64>,
+    pub active_duration_ms: Option<i64>,
+    pub files_modified: Option<i32>,
+    pub keystrokes: Option<i32>,
+    pub lines_added: Option<i32>,
+    pub lines_deleted: Option<i32>,
+    pub interruption_count: Option<i32>,
+    pub total_flow_time_ms: Option<i64>,
+    pub avg_flow_intensity: Option<f64>,
+    pub peak_flow_intensity: Option<f64>,
+    pub project_path: Option<String>,
+}
+
+/// One exported `encrypted_user_data` row, with the ciphertext in base64.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncryptedDataExportRow {
+    pub id: Uuid,
+    pub data_type: String,
+    pub encryption_key_id: String,
+    pub created_at: Option<DateTime<Utc>>,
+    pub encrypted_data: String,
+}
+
+/// Writes all of a user's data to `sink` as a zip: one file per non-empty
+/// category in `format`, then the manifest. Rows are fetched and written a
+/// page at a time and each entry goes out as soon as it is compressed, so
+/// neither the data nor the archive is ever held whole in memory.
+/// `page_written` is awaited after every page, which lets a streaming sink
+/// hold the export back until what it has buffered is taken up. Returns
+/// the sink and the manifest.
+pub async fn write_export_bundle<W, P, PFut>(
+    db: &PgPool,
+    user_id: Uuid,
+    format: ExportFormat,
+    sink: W,
+    mut page_written: P,
+) -> Result<(W, ExportManifest)>
+where
+    W: Write + Send,
+    P: FnMut() -> PFut,
+    PFut: Future<Output = Result<()>>,
+{
+    let extension = match format {
+        ExportFormat::Json | ExportFormat::Csv => format.as_str(),
+        ExportFormat::Xml | ExportFormat::Parquet => {
+            return Err(AppError::Validation(format!(
+                "Export archives can't be built as {}; use json or csv",
+                format.as_str()
+            )))
+        }
+    };
+
+    let mut zip = ZipWriter::new_stream(sink);
+    let mut files = Vec::new();
+    let mut add = |category: &str, encrypted: bool, record_count: usize| {
+        if record_count > 0 {
+            files.push(ExportManifestFile {
+                category: category.to_string(),
+                file: format!("{}.{}", category, extension),
+                record_count,
+                encrypted,
+            });
+        }
+    };
+
+    let sessions = write_category(
+        &mut zip,
+        "coding_sessions",
+        format,
+        &mut page_written,
+        |after| {
+            fetch_session_page(
+                db,
+                user_id,
+                after.map(|row: SessionExportRow| (row.start_time, row.id)),
+                DEFAULT_ROW_GROUP_SIZE,
+            )
+        },
+    )
+    .await?;
+    add("coding_sessions", false, sessions);
+
+    let flow_states = write_category(
+        &mut zip,
+        "flow_states",
+        format,
+        &mut page_written,
+        |after| {
+            fetch_flow_state_page(
+                db,
+                user_id,
+                after.map(|row: FlowStateExportRow| (row.start_time, row.id)),
+                DEFAULT_ROW_GROUP_SIZE,
+            )
+        },
+    )
+    .await?;
+    add("flow_states", false, flow_states);
+
+    let encrypted = write_category(
+        &mut zip,
+        "encrypted_data",
+        format,
+        &mut page_written,
+        |after| {
+            fetch_encrypted_data_page(
+                db,
+                user_id,
+                after.map(|row: EncryptedDataExportRow| row.id),
+                DEFAULT_ROW_GROUP_SIZE,
+            )
+        },
+    )
+    .await?;
+    add("encrypted_data", true, encrypted);
+
+    let manifest = ExportManifest {
+        user_id,
+        exported_at: Utc::now(),
+        format,
+        total_records: files.iter().map(|file| file.record_count).sum(),
+        files,
+    };
+    zip.start_file(EXPORT_MANIFEST_FILE, entry_options())
+        .map_err(archive_error)?;
+    serde_json::to_writer_pretty(&mut zip, &manifest).map_err(archive_error)?;
+
+    let sink = zip.finish().map_err(archive_error)?.into_inner();
+    Ok((sink, manifest))
+}
+
+/// Writes one category's pages into its own entry, opened only once the
+/// first row arrives so empty categories leave nothing behind. `fetch_page`
+/// gets the last row of the previous page. Returns the rows written.
+async fn write_category<W, T, F, Fut, P, PFut>(
+    zip: &mut ZipWriter<StreamWriter<W>>,
+    category: &str,
+    format: ExportFormat,
+    page_written: &mut P,
+    mut fetch_page: F,
+) -> Result<usize>
+where
+    W: Write + Send,
+    T: Serialize,
+    F: FnMut(Option<T>) -> Fut,
+    Fut: Future<Output = Result<Vec<T>>>,
+    P: FnMut() -> PFut,
+    PFut: Future<Output = Result<()>>,
+{
+    let mut written = 0;
+    let mut after = None;
+
+    loop {
+        let page = fetch_page(after.take()).await?;
+        if page.is_empty() {
+            break;
+        }
+        if written == 0 {
+            zip.start_file(format!("{}.{}", category, format.as_str()), entry_options())
+                .map_err(archive_error)?;
+        }
+        write_rows(&mut *zip, format, &page, written == 0)?;
+        written += page.len();
+        after = page.into_iter().last();
+        page_written().await?;
+    }
+
+    if written > 0 && format == ExportFormat::Json {
+        zip.write_all(b"\n]\n").map_err(archive_error)?;
+    }
+    Ok(written)
+}
+
+/// Appends rows to an open entry: elements of one JSON array, or CSV lines
+/// under a header written with the first page.
+fn write_rows<T: Serialize>(
+    out: &mut impl Write,
+    format: ExportFormat,
+    rows: &[T],
+    first_page: bool,
+) -> Result<()> {
+    match format {
+        ExportFormat::Csv => {
+            let mut csv = csv::WriterBuilder::new()
+                .has_headers(first_page)
+                .from_writer(out);
+            for row in rows {
+                csv.serialize(row).map_err(archive_error)?;
+            }
+            csv.flush().map_err(archive_error)?;
+        }
+        _ => {
+            for (i, row) in rows.iter().enumerate() {
+                let separator = if first_page && i == 0 { "[\n" } else { ",\n" };
+                out.write_all(separator.as_bytes()).map_err(archive_error)?;
+                serde_json::to_writer(&mut *out, row).map_err(archive_error)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Archive bytes bound for a streamed response body. Writes only buffer;
+/// `send_buffered` passes what has built up to the body's channel, waiting
+/// while it is full, so a slow download holds the export back instead of
+/// the archive piling up in memory. Clones share the buffer and channel.
+#[derive(Clone)]
+pub struct ChannelSink {
+    buffer: Arc<Mutex<Vec<u8>>>,
+    chunks: mpsc::Sender<std::io::Result<Bytes>>,
+}
+
+impl ChannelSink {
+    pub fn new(chunks: mpsc::Sender<std::io::Result<Bytes>>) -> Self {
+        Self {
+            buffer: Arc::new(Mutex::new(Vec::new())),
+            chunks,
+        }
+    }
+
+    /// Sends the buffered bytes, if any. Fails once the body is dropped,
+    /// as when the client goes away, which stops the export.
+    pub async fn send_buffered(&self) -> Result<()> {
+        let chunk = std::mem::take(&mut *self.buffer.lock());
+        if chunk.is_empty() {
+            return Ok(());
+        }
+        self.chunks
+            .send(Ok(Bytes::from(chunk)))
+            .await
+            .map_err(|_| AppError::Internal("Export download was abandoned".to_string()))
+    }
+
+    /// Ends the body with an error, so the client sees a failed download
+    /// rather than a truncated archive.
+    pub async fn fail(&self, error: &AppError) {
+        let _ = self
+            .chunks
+            .send(Err(std::io::Error::other(error.to_string())))
+            .await;
+    }
+}
+
+impl Write for ChannelSink {
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+        self.buffer.lock().extend_from_slice(bytes);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn entry_options() -> SimpleFileOptions {
+    SimpleFileOptions::default().compression_method(CompressionMethod::Deflated)
+}
+
+fn archive_error(e: impl Display) -> AppError {
+    AppError::Internal(format!("Export archive failed: {}", e))
+}
+
+/// One keyset page of a user's sessions, ordered by `(start_time, id)`.
+pub async fn fetch_session_page(
+    db: &PgPool,
+    user_id: Uuid,
+    after: Option<(DateTime<Utc>, Uuid)>,
+    limit: usize,
+) -> Result<Vec<SessionExportRow>> {
+    let (after_time, after_id) = after.unzip();
+
+    let rows = sqlx::query_as!(
+        SessionExportRow,
+        r#"
+        SELECT
+            id,
+            start_time,
+            end_time,
+            total_duration_ms,
+            active_duration_ms,
+            files_modified,
+            keystrokes,
+            lines_added,
+            lines_deleted,
+            interruption_count,
+            total_flow_time_ms,
+            avg_flow_intensity,
+            peak_flow_intensity,
+            project_path
+        FROM coding_sessions
+        WHERE user_id = $1
+          AND ($2::TIMESTAMPTZ IS NULL OR (start_time, id) > ($2, $3::UUID))
+        ORDER BY start_time, id
+        LIMIT $4
+        "#,
+        user_id,
+        after_time,
+        after_id,
+        limit as i64,
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(rows)
+}
+
+/// One keyset page of a user's encrypted records, ordered by id.
+pub async fn fetch_encrypted_data_page(
+    db: &PgPool,
+    user_id: Uuid,
+    after: Option<Uuid>,
+    limit: usize,
+) -> Result<Vec<EncryptedDataExportRow>> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT id, data_type, encryption_key_id, created_at, encrypted_data
+        FROM encrypted_user_data
+        WHERE user_id = $1 AND ($2::UUID IS NULL OR id > $2)
+        ORDER BY id
+        LIMIT $3
+        "#,
+        user_id,
+        after,
+        limit as i64,
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| EncryptedDataExportRow {
+            id: row.id,
+            data_type: row.data_type,
+            encryption_key_id: row.encryption_key_id,
+            created_at: row.created_at,
+            encrypted_data: BASE64.encode(row.encrypted_data),
+        })
+        .collect())
+}
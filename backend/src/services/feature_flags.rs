@@ -0,0 +1,85 @@
+use crate::utils::auth::{Claims, SubscriptionTier};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+
+/// Keeps a user in flow until the score drops below the entry threshold
+/// minus a margin, instead of flickering around a single cut-off.
+pub const FLOW_HYSTERESIS: &str = "flow_hysteresis";
+/// Smooths successive flow scores with an exponential moving average.
+pub const EMA_SMOOTHING: &str = "ema_smoothing";
+
+/// Rollout rule for a single flag. A flag is on for a request when it is
+/// enabled globally, or the caller's tier or user id is listed.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FlagRule {
+    pub enabled: bool,
+    pub tiers: HashSet<SubscriptionTier>,
+    pub users: HashSet<Uuid>,
+}
+
+/// Feature flags loaded from the `FEATURE_FLAGS` JSON object, e.g.
+/// `{"ema_smoothing": {"tiers": ["premium"]}, "flow_hysteresis": {"enabled": true}}`.
+/// Unknown flags are simply off.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct FeatureFlags {
+    flags: HashMap<String, FlagRule>,
+}
+
+impl FeatureFlags {
+    pub fn from_json(json: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(json)
+    }
+
+    pub fn set(&mut self, flag: &str, rule: FlagRule) {
+        self.flags.insert(flag.to_string(), rule);
+    }
+
+    pub fn is_enabled(&self, flag: &str, claims: &Claims) -> bool {
+        self.flags.get(flag).is_some_and(|rule| {
+            rule.enabled
+                || rule.tiers.contains(&claims.subscription_tier)
+                || rule.users.contains(&claims.user_id)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims_for(tier: &str) -> Claims {
+        Claims::new(Uuid::new_v4(), format!("{}@example.com", tier), tier.to_string())
+    }
+
+    #[test]
+    fn test_flag_enabled_for_tier_only() {
+        let flags = FeatureFlags::from_json(
+            r#"{"ema_smoothing": {"enabled": false, "tiers": ["premium"]}}"#,
+        )
+        .unwrap();
+
+        assert!(flags.is_enabled(EMA_SMOOTHING, &claims_for("premium")));
+        assert!(!flags.is_enabled(EMA_SMOOTHING, &claims_for("free")));
+        assert!(!flags.is_enabled(EMA_SMOOTHING, &claims_for("team")));
+        assert!(!flags.is_enabled(FLOW_HYSTERESIS, &claims_for("premium")));
+    }
+
+    #[test]
+    fn test_flag_enabled_for_listed_user() {
+        let claims = claims_for("free");
+        let mut flags = FeatureFlags::default();
+        flags.set(
+            FLOW_HYSTERESIS,
+            FlagRule {
+                users: HashSet::from([claims.user_id]),
+                ..FlagRule::default()
+            },
+        );
+
+        assert!(flags.is_enabled(FLOW_HYSTERESIS, &claims));
+        assert!(!flags.is_enabled(FLOW_HYSTERESIS, &claims_for("free")));
+    }
+}
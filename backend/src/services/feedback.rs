@@ -0,0 +1,268 @@
+use crate::{
+    config::{FeedbackStoreBackend, FeedbackStoreConfig},
+    error::{AppError, Result},
+};
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::{collections::VecDeque, sync::Arc};
+use uuid::Uuid;
+
+/// One labelled example for retraining the flow model.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FeedbackExample {
+    pub user_id: Uuid,
+    /// Model inputs, in `predict_flow_state` order
+    pub features: [f32; 5],
+    /// Score the engine reported for these features
+    pub actual_flow_state: f32,
+    /// The user's own rating, when they gave one
+    pub user_feedback: Option<f32>,
+    pub recorded_at: DateTime<Utc>,
+}
+
+/// Training examples collected from user feedback. Stores keep about
+/// `capacity` examples, evicting those stored earliest first.
+#[axum::async_trait]
+pub trait FeedbackStore: Send + Sync {
+    async fn record(&self, example: FeedbackExample) -> Result<()>;
+
+    /// Up to `size` of the most recently stored examples, newest first.
+    async fn training_batch(&self, size: usize) -> Result<Vec<FeedbackExample>>;
+
+    /// Drops examples past the retention period, then those stored earliest
+    /// beyond `capacity`. Returns how many went.
+    async fn prune(&self, now: DateTime<Utc>) -> Result<u64>;
+
+    async fn count(&self) -> Result<usize>;
+}
+
+pub fn feedback_store(config: &FeedbackStoreConfig, db: &PgPool) -> Arc<dyn FeedbackStore> {
+    match config.backend {
+        FeedbackStoreBackend::Memory => Arc::new(InMemoryFeedbackStore::new(config.clone())),
+        FeedbackStoreBackend::Postgres => {
+            Arc::new(PgFeedbackStore::new(db.clone(), config.clone()))
+        }
+    }
+}
+
+fn retention_cutoff(config: &FeedbackStoreConfig, now: DateTime<Utc>) -> DateTime<Utc> {
+    now - chrono::Duration::days(config.retention_days)
+}
+
+pub struct InMemoryFeedbackStore {
+    config: FeedbackStoreConfig,
+    /// Oldest first
+    examples: Mutex<VecDeque<FeedbackExample>>,
+}
+
+impl InMemoryFeedbackStore {
+    pub fn new(config: FeedbackStoreConfig) -> Self {
+        Self {
+            config,
+            examples: Mutex::new(VecDeque::new()),
+        }
+    }
+}
+
+#[axum::async_trait]
+impl FeedbackStore for InMemoryFeedbackStore {
+    async fn record(&self, example: FeedbackExample) -> Result<()> {
+        let mut examples = self.examples.lock();
+        examples.push_back(example);
+        while examples.len() > self.config.capacity {
+            examples.pop_front();
+        }
+        Ok(())
+    }
+
+    async fn training_batch(&self, size: usize) -> Result<Vec<FeedbackExample>> {
+        Ok(self.examples.lock().iter().rev().take(size).cloned().collect())
+    }
+
+    async fn prune(&self, now: DateTime<Utc>) -> Result<u64> {
+        // Capacity is already kept on every record
+        let cutoff = retention_cutoff(&self.config, now);
+        let mut examples = self.examples.lock();
+        let before = examples.len();
+        examples.retain(|example| example.recorded_at >= cutoff);
+        Ok((before - examples.len()) as u64)
+    }
+
+    async fn count(&self) -> Result<usize> {
+        Ok(self.examples.lock().len())
+    }
+}
+
+/// Keeps examples in `model_feedback`, so they survive restarts and are
+/// shared between instances. Capacity is only enforced by `prune`, so the
+/// table can run over it between prunes.
+pub struct PgFeedbackStore {
+    db: PgPool,
+    config: FeedbackStoreConfig,
+}
+
+impl PgFeedbackStore {
+    pub fn new(db: PgPool, config: FeedbackStoreConfig) -> Self {
+        Self { db, config }
+    }
+}
+
+#[axum::async_trait]
+impl FeedbackStore for PgFeedbackStore {
+    async fn record(&self, example: FeedbackExample) -> Result<()> {
+        sqlx::query!(
+            r#"
+            INSERT INTO model_feedback
+                (user_id, features, actual_flow_state, user_feedback, recorded_at)
+            VALUES ($1, $2, $3, $4, $5)
+            "#,
+            example.user_id,
+            &example.features[..],
+            example.actual_flow_state,
+            example.user_feedback,
+            example.recorded_at,
+        )
+        .execute(&self.db)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn training_batch(&self, size: usize) -> Result<Vec<FeedbackExample>> {
+        let rows = sqlx::query!(
+            r#"
+            SELECT user_id, features, actual_flow_state, user_feedback, recorded_at
+            FROM model_feedback
+            ORDER BY created_at DESC, id DESC
+            LIMIT $1
+            "#,
+            size as i64
+        )
+        .fetch_all(&self.db)
+        .await?;
+
+        rows.into_iter()
+            .map(|row| {
+                let features = <[f32; 5]>::try_from(row.features.as_slice()).map_err(|_| {
+                    AppError::Internal(format!(
+                        "Feedback example has {} features, expected 5",
+                        row.features.len()
+                    ))
+                })?;
+                Ok(FeedbackExample {
+                    user_id: row.user_id,
+                    features,
+                    actual_flow_state: row.actual_flow_state,
+                    user_feedback: row.user_feedback,
+                    recorded_at: row.recorded_at,
+                })
+            })
+            .collect()
+    }
+
+    async fn prune(&self, now: DateTime<Utc>) -> Result<u64> {
+        let mut tx = self.db.begin().await?;
+
+        let expired = sqlx::query!(
+            "DELETE FROM model_feedback WHERE recorded_at < $1",
+            retention_cutoff(&self.config, now)
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+
+        let evicted = sqlx::query!(
+            r#"
+            DELETE FROM model_feedback
+            WHERE id IN (
+                SELECT id FROM model_feedback
+                ORDER BY created_at DESC, id DESC
+                OFFSET $1
+            )
+            "#,
+            self.config.capacity as i64
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+
+        tx.commit().await?;
+        Ok(expired + evicted)
+    }
+
+    async fn count(&self) -> Result<usize> {
+        let count = sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!" FROM model_feedback"#)
+            .fetch_one(&self.db)
+            .await?;
+
+        Ok(count as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn example(minutes_ago: i64, actual_flow_state: f32) -> FeedbackExample {
+        FeedbackExample {
+            user_id: Uuid::new_v4(),
+            features: [0.8, 0.7, 0.6, 0.1, 0.5],
+            actual_flow_state,
+            user_feedback: Some(0.9),
+            recorded_at: Utc::now() - chrono::Duration::minutes(minutes_ago),
+        }
+    }
+
+    fn store(capacity: usize) -> InMemoryFeedbackStore {
+        InMemoryFeedbackStore::new(FeedbackStoreConfig {
+            capacity,
+            ..FeedbackStoreConfig::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn test_batches_are_newest_first() {
+        let store = store(10);
+        for (i, minutes_ago) in [30, 20, 10].into_iter().enumerate() {
+            store.record(example(minutes_ago, i as f32 / 10.0)).await.unwrap();
+        }
+
+        let batch = store.training_batch(2).await.unwrap();
+        let scores: Vec<f32> = batch.iter().map(|e| e.actual_flow_state).collect();
+        assert_eq!(scores, vec![0.2, 0.1]);
+        assert_eq!(store.training_batch(10).await.unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_full_store_evicts_earliest_stored() {
+        let store = store(2);
+        // Stored in the opposite order to when they were recorded
+        for i in 0..3 {
+            store.record(example(i, i as f32 / 10.0)).await.unwrap();
+        }
+
+        assert_eq!(store.count().await.unwrap(), 2);
+        let scores: Vec<f32> = store
+            .training_batch(10)
+            .await
+            .unwrap()
+            .iter()
+            .map(|e| e.actual_flow_state)
+            .collect();
+        assert_eq!(scores, vec![0.2, 0.1]);
+    }
+
+    #[tokio::test]
+    async fn test_expired_examples_are_pruned() {
+        let store = store(10);
+        let retention_minutes = FeedbackStoreConfig::default().retention_days * 24 * 60;
+        store.record(example(retention_minutes + 60, 0.1)).await.unwrap();
+        store.record(example(60, 0.2)).await.unwrap();
+
+        assert_eq!(store.prune(Utc::now()).await.unwrap(), 1);
+        assert_eq!(store.count().await.unwrap(), 1);
+        assert_eq!(store.training_batch(10).await.unwrap()[0].actual_flow_state, 0.2);
+    }
+}
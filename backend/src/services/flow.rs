@@ -1,37 +1,195 @@
 use crate::{
+    config::FlowEngineConfig,
     error::{AppError, Result},
-    models::flow::{FlowMetrics, FlowStateData, FlowStateResult, UserFlowPreferences},
-    services::ml::MLInferenceEngine,
+    models::{
+        admin::FlowEngineSnapshot,
+        flow::{
+            FlowMetrics, FlowStateData, FlowStateResult, KeystrokeAggregates, UserFlowPreferences,
+        },
+    },
+    services::{
+        ml::{MLInferenceEngine, RULE_BASED_MODEL_VERSION, UNSCORED_MODEL_VERSION},
+        ml_batch::InferenceBatcher,
+    },
 };
+use dashmap::{mapref::entry::Entry, DashMap};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::{
-    collections::VecDeque,
+    collections::{hash_map::DefaultHasher, VecDeque},
+    hash::{Hash, Hasher},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
     time::{Duration, Instant},
 };
 use tokio::time;
 use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+/// Keystroke intervals kept per engine for consistency scoring; the
+/// longest `rhythm_long_window` that can be configured.
+pub const KEYSTROKE_BUFFER_CAPACITY: usize = 100;
 
 pub struct FlowDetectionEngine {
+    config: FlowEngineConfig,
     keystroke_buffer: VecDeque<u64>,
     flow_start_time: Option<Instant>,
+    /// A break reminder went out for the current flow stretch
+    break_reminded: bool,
     current_intensity: f32,
     ml_engine: MLInferenceEngine,
     last_analysis: Instant,
     flow_session_count: u32,
     total_flow_time: Duration,
     confidence_history: VecDeque<f32>,
+    last_interruption: Option<Instant>,
+    baseline: FlowBaseline,
+    /// Samples added to the baseline since it was last handed off to be
+    /// stored, merged into the stored baseline rather than replacing it
+    unsaved_baseline: FlowBaseline,
+    /// First of the unbroken run of analyses well below the baseline
+    dipped_since: Option<Instant>,
+    last_dip_alert: Option<Instant>,
+    scoring_flags: ScoringFlags,
+    /// This request's choice of strict validation; `None` follows the config
+    strict_validation: Option<bool>,
+    smoothed_score: Option<f32>,
+    current_session: Option<Uuid>,
+    /// Sample time of the current session's first analysis
+    session_started_at: Option<chrono::DateTime<chrono::Utc>>,
+    session_analyses: u32,
+    recent_results: VecDeque<CachedResult>,
+    stored_preferences: Option<UserFlowPreferences>,
+    keystroke_hasher: Arc<KeystrokeHasher>,
+    /// Most recently used last; keyed by `analysis_cache_key`
+    analysis_cache: VecDeque<(u64, FlowStateResult)>,
+    analysis_cache_stats: Arc<AnalysisCacheStats>,
+    ml_fallbacks: Arc<AtomicU64>,
+    /// Shared with other live engines; `None` predicts on `ml_engine` directly
+    ml_batcher: Option<Arc<InferenceBatcher>>,
+    /// The engine's own model and batcher while `pin_model` has another in
+    /// their place
+    unpinned: Option<(MLInferenceEngine, Option<Arc<InferenceBatcher>>)>,
+}
+
+/// Hit and miss counts of the analysis caches, shared across engines for
+/// `/metrics`.
+#[derive(Debug, Default)]
+pub struct AnalysisCacheStats {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl AnalysisCacheStats {
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    pub fn hit_rate(&self) -> f64 {
+        let lookups = self.hits() + self.misses();
+        if lookups == 0 {
+            0.0
+        } else {
+            self.hits() as f64 / lookups as f64
+        }
+    }
+}
+
+struct CachedResult {
+    session_id: Uuid,
+    timestamp: i64,
+    keystroke_hash: String,
+    result: FlowStateResult,
+}
+
+/// Outcome of `FlowDetectionEngine::begin_analysis`.
+pub enum AnalysisStep {
+    /// Answered without a prediction: too little data, or a repeat of a
+    /// sample already scored
+    Done(FlowStateResult),
+    Score(PendingAnalysis),
+}
+
+/// A sample whose metrics are taken, waiting for its model prediction.
+pub struct PendingAnalysis {
+    data: FlowStateData,
+    user_preferences: Option<UserFlowPreferences>,
+    metrics: FlowMetrics,
+    features: [f32; 5],
+    /// `None` when the user asked for rule-based scores
+    scorer: Option<(MLInferenceEngine, Option<Arc<InferenceBatcher>>)>,
+    warming_up: bool,
+    start_time: Instant,
+    sample_timestamp: i64,
+    timestamp_adjusted: bool,
+    cache_key: Option<u64>,
+}
+
+impl PendingAnalysis {
+    /// Runs the model on the sample's features, through the batcher when
+    /// the engine has one. Needs nothing from the engine, so a caller
+    /// holding it behind a lock can release it while the batch fills.
+    /// `None` when the sample is scored rule-based.
+    pub async fn predict(&self) -> Option<Result<f32>> {
+        let (ml_engine, batcher) = self.scorer.as_ref()?;
+        let prediction = match batcher {
+            Some(batcher) => batcher.predict(self.features).await,
+            None => ml_engine.predict_flow_state(self.features).await,
+        };
+        Some(prediction)
+    }
+}
+
+/// Experimental scoring behaviours, resolved per request from feature flags.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct ScoringFlags {
+    pub hysteresis: bool,
+    pub ema_smoothing: bool,
 }
 
 impl FlowDetectionEngine {
     pub fn new() -> Self {
+        Self::with_config(FlowEngineConfig::default(), MLInferenceEngine::new())
+    }
+
+    pub fn with_config(config: FlowEngineConfig, ml_engine: MLInferenceEngine) -> Self {
         Self {
-            keystroke_buffer: VecDeque::with_capacity(100),
+            config,
+            keystroke_buffer: VecDeque::with_capacity(KEYSTROKE_BUFFER_CAPACITY),
             flow_start_time: None,
+            break_reminded: false,
             current_intensity: 0.0,
-            ml_engine: MLInferenceEngine::new(),
+            ml_engine,
             last_analysis: Instant::now(),
             flow_session_count: 0,
             total_flow_time: Duration::new(0, 0),
             confidence_history: VecDeque::with_capacity(50),
+            last_interruption: None,
+            baseline: FlowBaseline::default(),
+            unsaved_baseline: FlowBaseline::default(),
+            dipped_since: None,
+            last_dip_alert: None,
+            scoring_flags: ScoringFlags::default(),
+            strict_validation: None,
+            smoothed_score: None,
+            current_session: None,
+            session_started_at: None,
+            session_analyses: 0,
+            recent_results: VecDeque::new(),
+            stored_preferences: None,
+            keystroke_hasher: Arc::new(KeystrokeHasher::default()),
+            analysis_cache: VecDeque::new(),
+            analysis_cache_stats: Arc::new(AnalysisCacheStats::default()),
+            ml_fallbacks: Arc::new(AtomicU64::new(0)),
+            ml_batcher: None,
+            unpinned: None,
         }
     }
 
@@ -40,44 +198,190 @@ impl FlowDetectionEngine {
         data: FlowStateData,
         user_preferences: Option<UserFlowPreferences>,
     ) -> Result<FlowStateResult> {
+        match self.begin_analysis(data, user_preferences)? {
+            AnalysisStep::Done(result) => Ok(result),
+            AnalysisStep::Score(pending) => {
+                let prediction = pending.predict().await;
+                Ok(self.finish_analysis(pending, prediction))
+            }
+        }
+    }
+
+    /// First half of `analyze_flow_state`: validates the sample and takes
+    /// its metrics. Samples that need no model prediction are answered
+    /// here; the rest come back to be scored with `PendingAnalysis::predict`
+    /// and handed to `finish_analysis`.
+    pub fn begin_analysis(
+        &mut self,
+        data: FlowStateData,
+        user_preferences: Option<UserFlowPreferences>,
+    ) -> Result<AnalysisStep> {
         let start_time = Instant::now();
 
+        // Checked before any state changes, so a rejected sample leaves the
+        // engine untouched
+        let (sample_timestamp, timestamp_adjusted) =
+            self.sample_timestamp(data.timestamp, chrono::Utc::now().timestamp_millis())?;
+        if self
+            .strict_validation
+            .unwrap_or(self.config.strict_validation)
+        {
+            validate_strict(&data)?;
+        }
+
+        if self.current_session != Some(data.session_id) {
+            self.current_session = Some(data.session_id);
+            self.session_started_at = chrono::DateTime::from_timestamp_millis(sample_timestamp);
+            self.session_analyses = 0;
+            self.analysis_cache.clear();
+            self.dipped_since = None;
+        }
+
+        if data.keystroke_count() < self.config.min_inference_keystrokes {
+            let result = self.insufficient_data_result(&data, sample_timestamp, timestamp_adjusted);
+            self.remember_result(&data, &result);
+            return Ok(AnalysisStep::Done(result));
+        }
+
+        // Identical input within the session: hand back the earlier result
+        // without touching flow state, baseline or smoothing
+        let cache_key = (self.config.analysis_cache_size > 0)
+            .then(|| self.analysis_cache_key(&data, user_preferences.as_ref()));
+        if let Some(key) = cache_key {
+            if let Some(mut result) = self.cached_analysis(key) {
+                result.sample_timestamp = sample_timestamp;
+                result.timestamp_adjusted = timestamp_adjusted;
+                result.analysis_time_ms = start_time.elapsed().as_secs_f32() * 1000.0;
+                self.remember_result(&data, &result);
+                return Ok(AnalysisStep::Done(result));
+            }
+        }
+
+        self.session_analyses = self.session_analyses.saturating_add(1);
+        let warming_up = self.session_analyses <= self.config.warmup_analyses;
+
         // Update keystroke buffer with ring buffer for memory efficiency
         for interval in &data.keystroke_intervals {
             self.keystroke_buffer.push_back(*interval);
-            if self.keystroke_buffer.len() > 100 {
+            if self.keystroke_buffer.len() > KEYSTROKE_BUFFER_CAPACITY {
                 self.keystroke_buffer.pop_front();
             }
         }
 
-        // Calculate individual metrics
-        let rhythm_score = self.analyze_keystroke_rhythm(&data.keystroke_intervals)?;
-        let focus_score = self.calculate_focus_score(data.context_switches);
-        let consistency_score = self.calculate_consistency_score(&data)?;
-        let error_penalty = self.calculate_error_penalty(data.error_events);
-        let velocity_score = self.calculate_velocity_score(&data)?;
-
-        // Combine metrics using ML model for optimal weighting
-        let combined_score = self
-            .ml_engine
-            .predict_flow_state([
-                rhythm_score,
-                focus_score,
-                consistency_score,
-                1.0 - error_penalty,
-                velocity_score,
-            ])
-            .await?;
-
-        let sensitivity = user_preferences
+        // Calculate individual metrics; consistency looks across samples
+        let secs_since_last = self.last_analysis.elapsed().as_secs_f32();
+        let metrics = FlowMetrics::score(
+            &data,
+            &self.config,
+            self.keystroke_buffer.make_contiguous(),
+            secs_since_last,
+        );
+
+        let features = [
+            metrics.rhythm_score,
+            metrics.focus_score,
+            metrics.consistency_score,
+            1.0 - metrics.error_penalty,
+            metrics.velocity_score,
+        ];
+        let use_ml = user_preferences
             .as_ref()
-            .map(|p| p.sensitivity_level)
-            .unwrap_or(0.7);
+            .and_then(|preferences| preferences.use_ml)
+            .unwrap_or(self.config.default_use_ml);
+
+        Ok(AnalysisStep::Score(PendingAnalysis {
+            data,
+            user_preferences,
+            metrics,
+            features,
+            scorer: use_ml.then(|| (self.ml_engine.clone(), self.ml_batcher.clone())),
+            warming_up,
+            start_time,
+            sample_timestamp,
+            timestamp_adjusted,
+            cache_key,
+        }))
+    }
+
+    /// Second half of `analyze_flow_state`: combines the prediction with
+    /// the engine's flow tracking into the result.
+    pub fn finish_analysis(
+        &mut self,
+        pending: PendingAnalysis,
+        prediction: Option<Result<f32>>,
+    ) -> FlowStateResult {
+        let PendingAnalysis {
+            data,
+            user_preferences,
+            metrics,
+            features,
+            scorer: _,
+            warming_up,
+            start_time,
+            sample_timestamp,
+            timestamp_adjusted,
+            cache_key,
+        } = pending;
+
+        // Combine metrics using ML model for optimal weighting, unless the
+        // user asked for reproducible rule-based scores. A failing model
+        // degrades to the rule-based score rather than failing detection.
+        let (combined_score, model_version, degraded) = match prediction {
+            Some(Ok(score)) => (score, self.ml_engine.model_version().to_string(), false),
+            Some(Err(e)) => {
+                warn!(
+                    "ML inference failed, falling back to rule-based scoring: {}",
+                    e
+                );
+                self.ml_fallbacks.fetch_add(1, Ordering::Relaxed);
+                (
+                    self.ml_engine.rule_based_prediction(features),
+                    RULE_BASED_MODEL_VERSION.to_string(),
+                    true,
+                )
+            }
+            None => (
+                self.ml_engine.rule_based_prediction(features),
+                RULE_BASED_MODEL_VERSION.to_string(),
+                false,
+            ),
+        };
+
+        // Flow takes time to rebuild after an explicit interruption
+        let combined_score = combined_score * (1.0 - self.interruption_recovery_penalty());
 
-        let is_in_flow = combined_score > sensitivity;
+        let combined_score = if self.scoring_flags.ema_smoothing {
+            self.smooth_score(combined_score)
+        } else {
+            combined_score
+        };
+
+        let sensitivity = self.flow_threshold(user_preferences.as_ref());
+
+        // With hysteresis, staying in flow takes less than entering it
+        let already_in_flow = self.flow_start_time.is_some();
+        let is_in_flow = if self.scoring_flags.hysteresis && already_in_flow {
+            combined_score > sensitivity - self.config.hysteresis_margin
+        } else if warming_up && !already_in_flow {
+            // Entering flow needs more than a warm-up window's worth of data
+            false
+        } else {
+            combined_score > sensitivity
+        };
         let flow_duration = self.calculate_flow_duration(is_in_flow);
         let confidence = self.calculate_confidence(combined_score, &data);
 
+        // Compare against history before this analysis joins it
+        let relative_flow_score = self
+            .baseline
+            .relative_score(combined_score, self.config.baseline_min_samples);
+        self.baseline.update(combined_score);
+        self.unsaved_baseline.update(combined_score);
+        self.dipped_since = match relative_flow_score {
+            Some(z) if z <= self.config.intensity_drop_z => self.dipped_since.or(Some(start_time)),
+            _ => None,
+        };
+
         // Update flow tracking state
         self.update_flow_tracking(is_in_flow, combined_score);
         self.confidence_history.push_back(confidence);
@@ -85,14 +389,6 @@ impl FlowDetectionEngine {
             self.confidence_history.pop_front();
         }
 
-        let metrics = FlowMetrics {
-            rhythm_score,
-            focus_score,
-            consistency_score,
-            error_penalty,
-            velocity_score,
-        };
-
         let recommendations = self.generate_recommendations(combined_score, &data, &metrics);
         let analysis_time = start_time.elapsed().as_secs_f32() * 1000.0;
 
@@ -109,166 +405,299 @@ impl FlowDetectionEngine {
             );
         }
 
-        Ok(FlowStateResult {
+        let result = FlowStateResult {
             is_in_flow,
+            warming_up,
             flow_intensity: combined_score,
             flow_duration_ms: flow_duration.as_millis() as u64,
             confidence,
+            data_quality: Self::data_quality(&data),
+            relative_flow_score,
             recommendations,
             metrics,
             analysis_time_ms: analysis_time,
-        })
+            model_version,
+            degraded,
+            sample_timestamp,
+            timestamp_adjusted,
+            server_mode: None,
+            insufficient_data: false,
+        };
+
+        self.remember_result(&data, &result);
+        if let Some(key) = cache_key {
+            if self.analysis_cache.len() >= self.config.analysis_cache_size {
+                self.analysis_cache.pop_front();
+            }
+            self.analysis_cache.push_back((key, result.clone()));
+        }
+        result
     }
 
-    fn analyze_keystroke_rhythm(&self, intervals: &[u64]) -> Result<f32> {
-        if intervals.len() < 3 {
-            return Ok(0.0);
+    /// Answer for a sample too small to score. Nothing is updated, so an
+    /// ongoing flow stretch is still reported and carries on.
+    fn insufficient_data_result(
+        &self,
+        data: &FlowStateData,
+        sample_timestamp: i64,
+        timestamp_adjusted: bool,
+    ) -> FlowStateResult {
+        FlowStateResult {
+            is_in_flow: self.flow_start_time.is_some(),
+            warming_up: self.session_analyses < self.config.warmup_analyses,
+            flow_intensity: 0.0,
+            flow_duration_ms: self
+                .flow_start_time
+                .map_or(0, |start_time| start_time.elapsed().as_millis() as u64),
+            confidence: 0.0,
+            data_quality: Self::data_quality(data),
+            relative_flow_score: None,
+            recommendations: vec![],
+            metrics: FlowMetrics {
+                rhythm_score: 0.0,
+                focus_score: 0.0,
+                consistency_score: 0.0,
+                error_penalty: 0.0,
+                velocity_score: 0.0,
+            },
+            analysis_time_ms: 0.0,
+            model_version: UNSCORED_MODEL_VERSION.to_string(),
+            degraded: false,
+            sample_timestamp,
+            timestamp_adjusted,
+            server_mode: None,
+            insufficient_data: true,
         }
+    }
 
-        let mean_interval = intervals.iter().sum::<u64>() as f32 / intervals.len() as f32;
-        let variance = intervals
-            .iter()
-            .map(|&x| (x as f32 - mean_interval).powi(2))
-            .sum::<f32>()
-            / intervals.len() as f32;
+    /// Puts the engine back in the state `with_config` left it in, for
+    /// reuse. Config, model and shared handles are kept, as is the buffers'
+    /// capacity; a pinned model is swapped back out. Every field is named,
+    /// so a new one can't be missed here.
+    pub fn reset(&mut self) {
+        let Self {
+            config: _,
+            keystroke_buffer,
+            flow_start_time,
+            break_reminded,
+            current_intensity,
+            ml_engine,
+            last_analysis,
+            flow_session_count,
+            total_flow_time,
+            confidence_history,
+            last_interruption,
+            baseline,
+            unsaved_baseline,
+            dipped_since,
+            last_dip_alert,
+            scoring_flags,
+            strict_validation,
+            smoothed_score,
+            current_session,
+            session_started_at,
+            session_analyses,
+            recent_results,
+            stored_preferences,
+            keystroke_hasher: _,
+            analysis_cache,
+            analysis_cache_stats: _,
+            ml_fallbacks: _,
+            ml_batcher,
+            unpinned,
+        } = self;
 
-        let std_dev = variance.sqrt();
-        let coefficient_of_variation = if mean_interval > 0.0 {
-            std_dev / mean_interval
-        } else {
-            return Ok(0.0);
-        };
+        keystroke_buffer.clear();
+        *flow_start_time = None;
+        *break_reminded = false;
+        *current_intensity = 0.0;
+        *last_analysis = Instant::now();
+        *flow_session_count = 0;
+        *total_flow_time = Duration::ZERO;
+        confidence_history.clear();
+        *last_interruption = None;
+        *baseline = FlowBaseline::default();
+        *unsaved_baseline = FlowBaseline::default();
+        *dipped_since = None;
+        *last_dip_alert = None;
+        *scoring_flags = ScoringFlags::default();
+        *strict_validation = None;
+        *smoothed_score = None;
+        *current_session = None;
+        *session_started_at = None;
+        *session_analyses = 0;
+        recent_results.clear();
+        *stored_preferences = None;
+        analysis_cache.clear();
+        if let Some((own_engine, own_batcher)) = unpinned.take() {
+            *ml_engine = own_engine;
+            *ml_batcher = own_batcher;
+        }
+    }
 
-        // Optimal keystroke rhythm analysis based on research
-        let score = match mean_interval {
-            // Optimal flow rhythm: 80-200ms with low variance
-            80.0..=200.0 if coefficient_of_variation < 0.3 => 0.95,
-            // Good rhythm: 50-300ms with moderate variance
-            50.0..=300.0 if coefficient_of_variation < 0.5 => 0.80,
-            // Acceptable rhythm: 30-500ms with higher variance
-            30.0..=500.0 if coefficient_of_variation < 0.8 => 0.60,
-            // Poor rhythm patterns
-            _ => 0.20,
-        };
+    /// Shares the server-wide hit/miss counters.
+    pub fn with_analysis_cache_stats(mut self, stats: Arc<AnalysisCacheStats>) -> Self {
+        self.analysis_cache_stats = stats;
+        self
+    }
 
-        // Bonus for sustained rhythm patterns
-        let sustained_bonus = if intervals.len() > 20 {
-            let recent_intervals = &intervals[intervals.len() - 20..];
-            let recent_cv = self.calculate_coefficient_of_variation(recent_intervals);
-            if recent_cv < coefficient_of_variation - 0.1 {
-                0.1 // Improving rhythm gets bonus
-            } else {
-                0.0
-            }
-        } else {
-            0.0
-        };
+    pub fn analysis_cache_stats(&self) -> &AnalysisCacheStats {
+        &self.analysis_cache_stats
+    }
 
-        Ok((score + sustained_bonus).min(1.0))
+    /// Shares the server-wide count of analyses that fell back from ML to
+    /// rule-based scoring.
+    pub fn with_ml_fallback_counter(mut self, counter: Arc<AtomicU64>) -> Self {
+        self.ml_fallbacks = counter;
+        self
     }
 
-    fn calculate_focus_score(&self, context_switches: u32) -> f32 {
-        // Exponential decay for context switching penalty
-        let base_score = (-0.2 * context_switches as f32).exp();
+    /// Routes ML predictions through a batcher. It must wrap the same model
+    /// as this engine, whose version is reported with the scores.
+    pub fn with_ml_batcher(mut self, batcher: Arc<InferenceBatcher>) -> Self {
+        self.ml_batcher = Some(batcher);
+        self
+    }
 
-        // Time-based focus bonus
-        let time_since_last = self.last_analysis.elapsed().as_secs_f32();
-        let time_bonus = if time_since_last > 10.0 {
-            // Sustained focus bonus
-            (time_since_last / 60.0).min(0.2)
-        } else {
-            0.0
+    /// Scores with `ml_engine` instead of the engine's own model, directly
+    /// rather than through its batcher, until `reset`.
+    pub fn pin_model(&mut self, ml_engine: MLInferenceEngine) {
+        let own = (
+            std::mem::replace(&mut self.ml_engine, ml_engine),
+            self.ml_batcher.take(),
+        );
+        self.unpinned.get_or_insert(own);
+    }
+
+    pub fn ml_fallbacks(&self) -> u64 {
+        self.ml_fallbacks.load(Ordering::Relaxed)
+    }
+
+    fn cached_analysis(&mut self, key: u64) -> Option<FlowStateResult> {
+        let Some(position) = self.analysis_cache.iter().position(|(cached, _)| *cached == key)
+        else {
+            self.analysis_cache_stats.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
         };
 
-        (base_score + time_bonus).min(1.0)
+        self.analysis_cache_stats.hits.fetch_add(1, Ordering::Relaxed);
+        let entry = self.analysis_cache.remove(position)?;
+        let result = entry.1.clone();
+        self.analysis_cache.push_back(entry);
+        Some(result)
     }
 
-    fn calculate_consistency_score(&self, data: &FlowStateData) -> Result<f32> {
-        if self.keystroke_buffer.len() < 10 {
-            return Ok(0.5); // Neutral score for insufficient data
-        }
+    /// Everything that feeds the score except the sample's timestamp, so
+    /// a client re-sending the same window hits the cache.
+    fn analysis_cache_key(
+        &self,
+        data: &FlowStateData,
+        preferences: Option<&UserFlowPreferences>,
+    ) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        data.session_id.hash(&mut hasher);
+        data.keystroke_intervals.hash(&mut hasher);
+        data.context_switches.hash(&mut hasher);
+        data.error_events.hash(&mut hasher);
+        data.window_focus_duration.hash(&mut hasher);
+        data.file_modifications.hash(&mut hasher);
+        data.typing_velocity.map(f32::to_bits).hash(&mut hasher);
+        data.velocity_unit.hash(&mut hasher);
+        data.pause_patterns.hash(&mut hasher);
+        data.aggregates
+            .map(|aggregates| {
+                (
+                    aggregates.count,
+                    aggregates.mean_interval_ms.to_bits(),
+                    aggregates.coefficient_of_variation.to_bits(),
+                )
+            })
+            .hash(&mut hasher);
+        preferences
+            .map(|preferences| {
+                (
+                    preferences.sensitivity_level.to_bits(),
+                    preferences.notification_threshold.to_bits(),
+                    preferences.focus_mode_enabled,
+                    preferences.break_reminders_enabled,
+                    preferences.personalized_calibration,
+                    preferences.use_ml,
+                )
+            })
+            .hash(&mut hasher);
+        self.scoring_flags.hash(&mut hasher);
+        hasher.finish()
+    }
 
-        // Analyze typing pattern consistency over time
-        let recent_intervals: Vec<u64> = self.keystroke_buffer.iter().rev().take(20).copied().collect();
-        let all_intervals: Vec<u64> = self.keystroke_buffer.iter().copied().collect();
+    /// Shares the server-wide salted hasher, so hashes match what other
+    /// engines and persisted rows use.
+    pub fn with_keystroke_hasher(mut self, hasher: Arc<KeystrokeHasher>) -> Self {
+        self.keystroke_hasher = hasher;
+        self
+    }
 
-        let recent_cv = self.calculate_coefficient_of_variation(&recent_intervals);
-        let overall_cv = self.calculate_coefficient_of_variation(&all_intervals);
+    pub fn keystroke_hash(&self, data: &FlowStateData) -> String {
+        self.keystroke_hasher.hash(data)
+    }
 
-        // Reward improving consistency
-        let consistency_score = if recent_cv < overall_cv {
-            (1.0 - recent_cv).max(0.0)
-        } else {
-            (1.0 - overall_cv).max(0.0)
+    /// The result already computed for this exact sample, if the client is
+    /// retrying a request this engine has seen recently. A retry whose
+    /// keystrokes don't hash to the original's is a conflict, not a retry.
+    pub fn cached_result(&self, data: &FlowStateData) -> Result<Option<FlowStateResult>> {
+        let Some(cached) = self.recent_results.iter().find(|cached| {
+            cached.session_id == data.session_id && cached.timestamp == data.timestamp
+        }) else {
+            return Ok(None);
         };
 
-        // Factor in file modification patterns
-        let mod_consistency = if data.file_modifications > 0 {
-            let mod_rate = data.file_modifications as f32 / data.window_focus_duration as f32 * 1000.0;
-            // Optimal modification rate: 0.5-2.0 modifications per second
-            match mod_rate {
-                0.5..=2.0 => 0.2,
-                0.1..=0.5 => 0.1,
-                _ => 0.0,
-            }
-        } else {
-            0.0
-        };
+        if cached.keystroke_hash != self.keystroke_hash(data) {
+            return Err(AppError::Conflict(
+                "Flow sample was already submitted with different keystroke data".to_string(),
+            ));
+        }
 
-        Ok((consistency_score + mod_consistency).min(1.0))
+        Ok(Some(cached.result.clone()))
     }
 
-    fn calculate_error_penalty(&self, error_events: u32) -> f32 {
-        // Logarithmic penalty for errors to avoid harsh punishment
-        if error_events == 0 {
-            0.0
-        } else {
-            (error_events as f32).ln() / 10.0
+    fn remember_result(&mut self, data: &FlowStateData, result: &FlowStateResult) {
+        if self.config.dedup_cache_size == 0 {
+            return;
         }
-    }
-
-    fn calculate_velocity_score(&self, data: &FlowStateData) -> Result<f32> {
-        if let Some(velocity) = data.typing_velocity {
-            // Optimal typing velocity: 200-400 characters per minute
-            match velocity {
-                200.0..=400.0 => Ok(0.9),
-                100.0..=200.0 => Ok(0.7),
-                400.0..=600.0 => Ok(0.8),
-                _ => Ok(0.5),
-            }
-        } else if !data.keystroke_intervals.is_empty() {
-            // Calculate velocity from keystroke intervals
-            let avg_interval_ms = data.keystroke_intervals.iter().sum::<u64>() as f32
-                / data.keystroke_intervals.len() as f32;
-            let chars_per_minute = 60000.0 / avg_interval_ms;
-
-            match chars_per_minute {
-                200.0..=400.0 => Ok(0.9),
-                100.0..=600.0 => Ok(0.7),
-                _ => Ok(0.5),
-            }
-        } else {
-            Ok(0.5)
+        if self.recent_results.len() >= self.config.dedup_cache_size {
+            self.recent_results.pop_front();
         }
+        self.recent_results.push_back(CachedResult {
+            session_id: data.session_id,
+            timestamp: data.timestamp,
+            keystroke_hash: self.keystroke_hash(data),
+            result: result.clone(),
+        });
     }
 
-    fn calculate_coefficient_of_variation(&self, intervals: &[u64]) -> f32 {
-        if intervals.len() < 2 {
-            return 1.0;
-        }
+    /// The timestamp to order a sample by, and whether it had to be replaced
+    /// with `received_at`. Clocks running ahead are rejected outright: a
+    /// future-dated sample can't be ordered meaningfully and would land
+    /// after everything the client sends next.
+    fn sample_timestamp(&self, client_timestamp: i64, received_at: i64) -> Result<(i64, bool)> {
+        let skew = client_timestamp.saturating_sub(received_at);
 
-        let mean = intervals.iter().sum::<u64>() as f32 / intervals.len() as f32;
-        let variance = intervals
-            .iter()
-            .map(|&x| (x as f32 - mean).powi(2))
-            .sum::<f32>()
-            / intervals.len() as f32;
+        if skew > self.config.max_future_skew_ms {
+            return Err(AppError::Validation(format!(
+                "Flow sample timestamp is {}s ahead of server time; check the client clock",
+                skew / 1000
+            )));
+        }
 
-        if mean > 0.0 {
-            variance.sqrt() / mean
-        } else {
-            1.0
+        if -skew > self.config.max_past_skew_ms {
+            warn!(
+                "Flow sample timestamp {} is {}s behind server time, using receive time",
+                client_timestamp,
+                -skew / 1000
+            );
+            return Ok((received_at, true));
         }
+
+        Ok((client_timestamp, false))
     }
 
     fn calculate_flow_duration(&mut self, is_in_flow: bool) -> Duration {
@@ -276,6 +705,7 @@ impl FlowDetectionEngine {
             (true, None) => {
                 // Starting new flow session
                 self.flow_start_time = Some(Instant::now());
+                self.break_reminded = false;
                 Duration::new(0, 0)
             }
             (true, Some(start_time)) => {
@@ -283,10 +713,13 @@ impl FlowDetectionEngine {
                 start_time.elapsed()
             }
             (false, Some(start_time)) => {
-                // Ending flow session
+                // Ending flow session; brief blips above threshold aren't
+                // counted as real flow
                 let duration = start_time.elapsed();
-                self.total_flow_time += duration;
-                self.flow_session_count += 1;
+                if duration >= Duration::from_millis(self.config.min_flow_duration_ms) {
+                    self.total_flow_time += duration;
+                    self.flow_session_count += 1;
+                }
                 self.flow_start_time = None;
                 duration
             }
@@ -297,19 +730,21 @@ impl FlowDetectionEngine {
         }
     }
 
+    /// How much the sample size of an analysis can be trusted: 1.0 with 10+
+    /// keystroke intervals, 0.8 with 5-9 and 0.5 below that.
+    pub fn data_quality(data: &FlowStateData) -> f32 {
+        match data.keystroke_count() {
+            n if n >= 10 => 1.0,
+            n if n >= 5 => 0.8,
+            _ => 0.5,
+        }
+    }
+
     fn calculate_confidence(&self, score: f32, data: &FlowStateData) -> f32 {
         let mut confidence = score;
 
         // Adjust confidence based on data quality
-        let data_quality = if data.keystroke_intervals.len() >= 10 {
-            1.0
-        } else if data.keystroke_intervals.len() >= 5 {
-            0.8
-        } else {
-            0.5
-        };
-
-        confidence *= data_quality;
+        confidence *= Self::data_quality(data);
 
         // Adjust based on recent confidence history
         if let Some(avg_recent_confidence) = self.get_average_recent_confidence() {
@@ -392,6 +827,159 @@ impl FlowDetectionEngine {
         recommendations
     }
 
+    /// Records an editor-reported interruption (call, meeting, ...). The
+    /// following analyses are penalized until the recovery window passes.
+    pub fn record_interruption(&mut self) {
+        self.last_interruption = Some(Instant::now());
+    }
+
+    fn interruption_recovery_penalty(&self) -> f32 {
+        let Some(interrupted_at) = self.last_interruption else {
+            return 0.0;
+        };
+
+        let recovery_window = Duration::from_secs(self.config.interruption_recovery_secs);
+        let elapsed = interrupted_at.elapsed();
+        if elapsed >= recovery_window {
+            return 0.0;
+        }
+
+        let remaining = 1.0 - elapsed.as_secs_f32() / recovery_window.as_secs_f32();
+        self.config.interruption_penalty * remaining
+    }
+
+    pub fn set_scoring_flags(&mut self, flags: ScoringFlags) {
+        if !flags.ema_smoothing {
+            self.smoothed_score = None;
+        }
+        self.scoring_flags = flags;
+    }
+
+    /// Chooses strict or lenient handling of malformed samples for this
+    /// request; `None` goes back to the configured default.
+    pub fn set_strict_validation(&mut self, strict: Option<bool>) {
+        self.strict_validation = strict;
+    }
+
+    fn smooth_score(&mut self, score: f32) -> f32 {
+        let alpha = self.config.ema_alpha;
+        let smoothed = match self.smoothed_score {
+            Some(previous) => alpha * score + (1.0 - alpha) * previous,
+            None => score,
+        };
+        self.smoothed_score = Some(smoothed);
+        smoothed
+    }
+
+    fn flow_threshold(&self, preferences: Option<&UserFlowPreferences>) -> f32 {
+        let Some(preferences) = preferences else {
+            return self.config.default_sensitivity;
+        };
+
+        if preferences.personalized_calibration
+            && self.baseline.sample_count >= self.config.baseline_min_samples
+        {
+            // Flow means noticeably above this user's normal intensity
+            let calibrated = self.baseline.mean + 0.5 * self.baseline.std_dev();
+            return (calibrated as f32).clamp(0.4, 0.95);
+        }
+
+        preferences.sensitivity_level
+    }
+
+    pub fn baseline(&self) -> FlowBaseline {
+        self.baseline
+    }
+
+    pub fn session_started_at(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        self.session_started_at
+    }
+
+    /// When the engine last scored a sample, or was created if it hasn't.
+    pub fn last_analysis(&self) -> Instant {
+        self.last_analysis
+    }
+
+    pub fn snapshot(&self) -> FlowEngineSnapshot {
+        FlowEngineSnapshot {
+            session_id: self.current_session,
+            current_intensity: self.current_intensity,
+            in_flow: self.flow_start_time.is_some(),
+            current_flow_duration_ms: self
+                .flow_start_time
+                .map_or(0, |start| start.elapsed().as_millis() as u64),
+            keystroke_buffer_len: self.keystroke_buffer.len(),
+            session_analyses: self.session_analyses,
+            flow_session_count: self.flow_session_count,
+            total_flow_time_ms: self.total_flow_time.as_millis() as u64,
+            confidence_history: self.confidence_history.iter().copied().collect(),
+            baseline_sample_count: self.baseline.sample_count,
+            baseline_mean: self.baseline.mean,
+            recovering_from_interruption: self.interruption_recovery_penalty() > 0.0,
+        }
+    }
+
+    /// Seeds the baseline from a persisted snapshot so a restarted server
+    /// doesn't put the user back into cold start.
+    pub fn restore_baseline(&mut self, baseline: FlowBaseline) {
+        self.baseline = baseline;
+    }
+
+    /// Hands off the samples scored since the last call, for merging into
+    /// the stored baseline. Other sessions of the same user merge theirs
+    /// too, so none of them overwrites what the others learned.
+    pub fn take_unsaved_baseline(&mut self) -> FlowBaseline {
+        std::mem::take(&mut self.unsaved_baseline)
+    }
+
+    /// The user's saved preferences, which the detection handler falls back
+    /// to when a request doesn't carry any.
+    pub fn stored_preferences(&self) -> Option<&UserFlowPreferences> {
+        self.stored_preferences.as_ref()
+    }
+
+    pub fn set_stored_preferences(&mut self, preferences: Option<UserFlowPreferences>) {
+        self.stored_preferences = preferences;
+    }
+
+    /// True once per flow stretch, when it has run for `after` without a
+    /// break.
+    pub fn break_reminder_due(&mut self, after: Duration) -> bool {
+        match self.flow_start_time {
+            Some(start) if !self.break_reminded && start.elapsed() >= after => {
+                self.break_reminded = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// True once intensity has stayed well below the user's baseline for the
+    /// configured window, and then at most once per window while it does.
+    pub fn focus_dip_due(&mut self) -> bool {
+        self.focus_dip_due_at(Instant::now())
+    }
+
+    pub fn focus_dip_due_at(&mut self, now: Instant) -> bool {
+        let window = Duration::from_secs(self.config.intensity_drop_window_secs);
+        if window.is_zero() {
+            return false;
+        }
+
+        let sustained = self
+            .dipped_since
+            .is_some_and(|since| now.saturating_duration_since(since) >= window);
+        let debounced = self
+            .last_dip_alert
+            .is_some_and(|alerted| now.saturating_duration_since(alerted) < window);
+        if sustained && !debounced {
+            self.last_dip_alert = Some(now);
+            true
+        } else {
+            false
+        }
+    }
+
     pub fn get_session_stats(&self) -> (u32, Duration) {
         (self.flow_session_count, self.total_flow_time)
     }
@@ -400,5 +988,410 @@ impl FlowDetectionEngine {
         self.flow_session_count = 0;
         self.total_flow_time = Duration::new(0, 0);
         self.flow_start_time = None;
+        self.analysis_cache.clear();
+    }
+}
+
+impl FlowMetrics {
+    /// Scores a single sample on its own, as a fresh engine would: nothing
+    /// is buffered or timed, and consistency is judged on the sample's own
+    /// intervals.
+    pub fn compute(data: &FlowStateData, config: &FlowEngineConfig) -> FlowMetrics {
+        let intervals = &data.keystroke_intervals;
+        let history = &intervals[intervals.len().saturating_sub(KEYSTROKE_BUFFER_CAPACITY)..];
+        Self::score(data, config, history, 0.0)
+    }
+
+    /// `history` is the buffered intervals, oldest first, this sample's
+    /// included; `secs_since_last` the time since the previous analysis.
+    fn score(
+        data: &FlowStateData,
+        config: &FlowEngineConfig,
+        history: &[u64],
+        secs_since_last: f32,
+    ) -> FlowMetrics {
+        FlowMetrics {
+            rhythm_score: keystroke_rhythm_score(data, config),
+            focus_score: focus_score(data.context_switches, secs_since_last),
+            consistency_score: consistency_score(data, config, history),
+            error_penalty: error_penalty(data.error_events),
+            velocity_score: velocity_score(data),
+        }
+    }
+}
+
+/// Scored over the sample's last `rhythm_long_window` intervals, with a
+/// bonus when its last `rhythm_short_window` are steadier still.
+fn keystroke_rhythm_score(data: &FlowStateData, config: &FlowEngineConfig) -> f32 {
+    let intervals = &data.keystroke_intervals;
+    let skipped = intervals.len().saturating_sub(config.rhythm_long_window);
+    let long_window = &intervals[skipped..];
+
+    // Minimized clients only send the summary; score it the same way,
+    // minus the sustained-rhythm bonus that needs the raw timings
+    let (mean_interval, coefficient_of_variation) = match data.aggregates {
+        Some(aggregates) if intervals.is_empty() => {
+            (aggregates.mean_interval_ms, aggregates.coefficient_of_variation)
+        }
+        _ => {
+            let Some(aggregates) = KeystrokeAggregates::from_intervals(long_window) else {
+                return 0.0;
+            };
+            (aggregates.mean_interval_ms, aggregates.coefficient_of_variation)
+        }
+    };
+
+    if data.keystroke_count() < 3 || mean_interval <= 0.0 {
+        return 0.0;
+    }
+
+    // Optimal keystroke rhythm analysis based on research
+    let score = match mean_interval {
+        // Optimal flow rhythm: 80-200ms with low variance
+        80.0..=200.0 if coefficient_of_variation < 0.3 => 0.95,
+        // Good rhythm: 50-300ms with moderate variance
+        50.0..=300.0 if coefficient_of_variation < 0.5 => 0.80,
+        // Acceptable rhythm: 30-500ms with higher variance
+        30.0..=500.0 if coefficient_of_variation < 0.8 => 0.60,
+        // Poor rhythm patterns
+        _ => 0.20,
+    };
+
+    // Bonus for sustained rhythm patterns
+    let short_window = config.rhythm_short_window;
+    let sustained_bonus = if long_window.len() > short_window {
+        let recent_intervals = &long_window[long_window.len() - short_window..];
+        let recent_cv = coefficient_of_variation_of(recent_intervals);
+        if recent_cv < coefficient_of_variation - 0.1 {
+            0.1 // Improving rhythm gets bonus
+        } else {
+            0.0
+        }
+    } else {
+        0.0
+    };
+
+    (score + sustained_bonus).min(1.0)
+}
+
+fn focus_score(context_switches: u32, secs_since_last: f32) -> f32 {
+    // Exponential decay for context switching penalty
+    let base_score = (-0.2 * context_switches as f32).exp();
+
+    // Time-based focus bonus
+    let time_bonus = if secs_since_last > 10.0 {
+        // Sustained focus bonus
+        (secs_since_last / 60.0).min(0.2)
+    } else {
+        0.0
+    };
+
+    (base_score + time_bonus).min(1.0)
+}
+
+/// Compares the variation of the last `rhythm_short_window` buffered
+/// intervals against the last `rhythm_long_window`, across samples.
+fn consistency_score(data: &FlowStateData, config: &FlowEngineConfig, history: &[u64]) -> f32 {
+    let consistency_score = match data.aggregates {
+        // Without raw timings nothing reaches the buffer, so the
+        // batch's own variation stands in for the history
+        Some(aggregates) if data.keystroke_intervals.is_empty() => {
+            if aggregates.count < 10 {
+                return 0.5;
+            }
+            (1.0 - aggregates.coefficient_of_variation).max(0.0)
+        }
+        _ => {
+            if history.len() < 10 {
+                return 0.5; // Neutral score for insufficient data
+            }
+
+            // Analyze typing pattern consistency over time
+            let recent_intervals = last_intervals(history, config.rhythm_short_window);
+            let all_intervals = last_intervals(history, config.rhythm_long_window);
+
+            let recent_cv = coefficient_of_variation_of(recent_intervals);
+            let overall_cv = coefficient_of_variation_of(all_intervals);
+
+            // Reward improving consistency
+            if recent_cv < overall_cv {
+                (1.0 - recent_cv).max(0.0)
+            } else {
+                (1.0 - overall_cv).max(0.0)
+            }
+        }
+    };
+
+    // Factor in file modification patterns
+    let mod_consistency = if data.file_modifications > 0 {
+        let mod_rate = data.file_modifications as f32 / data.window_focus_duration as f32 * 1000.0;
+        // Optimal modification rate: 0.5-2.0 modifications per second
+        match mod_rate {
+            0.5..=2.0 => 0.2,
+            0.1..=0.5 => 0.1,
+            _ => 0.0,
+        }
+    } else {
+        0.0
+    };
+
+    (consistency_score + mod_consistency).min(1.0)
+}
+
+fn error_penalty(error_events: u32) -> f32 {
+    // Logarithmic penalty for errors to avoid harsh punishment
+    if error_events == 0 {
+        0.0
+    } else {
+        (error_events as f32).ln() / 10.0
+    }
+}
+
+fn velocity_score(data: &FlowStateData) -> f32 {
+    if let Some(velocity) = data.typing_velocity_cpm() {
+        // Optimal typing velocity: 200-400 characters per minute
+        match velocity {
+            200.0..=400.0 => 0.9,
+            100.0..=200.0 => 0.7,
+            400.0..=600.0 => 0.8,
+            _ => 0.5,
+        }
+    } else if let Some(avg_interval_ms) = data.mean_keystroke_interval() {
+        // Calculate velocity from keystroke intervals
+        let chars_per_minute = 60000.0 / avg_interval_ms;
+
+        match chars_per_minute {
+            200.0..=400.0 => 0.9,
+            100.0..=600.0 => 0.7,
+            _ => 0.5,
+        }
+    } else {
+        0.5
+    }
+}
+
+/// The last `window` intervals, oldest first.
+fn last_intervals(intervals: &[u64], window: usize) -> &[u64] {
+    &intervals[intervals.len().saturating_sub(window)..]
+}
+
+fn coefficient_of_variation_of(intervals: &[u64]) -> f32 {
+    if intervals.len() < 2 {
+        return 1.0;
+    }
+
+    let mean = intervals.iter().sum::<u64>() as f32 / intervals.len() as f32;
+    let variance = intervals
+        .iter()
+        .map(|&x| (x as f32 - mean).powi(2))
+        .sum::<f32>()
+        / intervals.len() as f32;
+
+    if mean > 0.0 {
+        variance.sqrt() / mean
+    } else {
+        1.0
     }
-}
\ No newline at end of file
+}
+
+/// How far, as a ratio either way, a reported typing velocity may stray from
+/// the one its keystroke intervals imply before strict validation rejects it.
+pub const STRICT_VELOCITY_TOLERANCE: f32 = 3.0;
+
+/// The checks strict mode adds: samples lenient scoring would quietly
+/// neutralise are rejected instead, so batch callers learn their data is bad.
+pub fn validate_strict(data: &FlowStateData) -> Result<()> {
+    let invalid = |reason: &str| {
+        Err(AppError::Validation(format!(
+            "Strict flow detection: {}",
+            reason
+        )))
+    };
+
+    if data.keystroke_count() == 0 {
+        return invalid("sample has no keystrokes");
+    }
+    if data.window_focus_duration == 0 && data.file_modifications > 0 {
+        return invalid("files were modified with zero window focus");
+    }
+
+    let Some(velocity) = data.typing_velocity_cpm() else {
+        return Ok(());
+    };
+    if !velocity.is_finite() || velocity < 0.0 {
+        return invalid("typing_velocity must be a non-negative number");
+    }
+    if let Some(mean_interval) = data.mean_keystroke_interval().filter(|&mean| mean > 0.0) {
+        let implied = 60_000.0 / mean_interval;
+        let ratio = velocity / implied;
+        if !(1.0 / STRICT_VELOCITY_TOLERANCE..=STRICT_VELOCITY_TOLERANCE).contains(&ratio) {
+            return invalid(&format!(
+                "typing_velocity of {:.0} chars/min contradicts the {:.0} its keystroke intervals imply",
+                velocity, implied
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Analyses after which the baseline stops averaging over all history and
+/// becomes an exponentially weighted window, so it follows gradual change.
+const BASELINE_WINDOW: u64 = 500;
+
+/// Rolling mean and variance of a user's flow intensities, updated
+/// incrementally so no history needs to be kept in memory.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct FlowBaseline {
+    pub sample_count: u64,
+    pub mean: f64,
+    pub variance: f64,
+}
+
+impl FlowBaseline {
+    pub fn update(&mut self, intensity: f32) {
+        self.sample_count += 1;
+        // Exact running average until the window fills, then an EWMA
+        let alpha = 1.0 / self.sample_count.min(BASELINE_WINDOW) as f64;
+        let delta = intensity as f64 - self.mean;
+        self.mean += alpha * delta;
+        self.variance = (1.0 - alpha) * (self.variance + alpha * delta * delta);
+    }
+
+    /// Folds in a baseline built from other samples, weighting each side by
+    /// its sample count. Exact while the combined history fits the window;
+    /// past it, `other` counts as that many of the latest samples would.
+    pub fn merge(&mut self, other: &FlowBaseline) {
+        if other.sample_count == 0 {
+            return;
+        }
+        let sample_count = self.sample_count + other.sample_count;
+        let weight =
+            (other.sample_count as f64 / sample_count.min(BASELINE_WINDOW) as f64).min(1.0);
+        let delta = other.mean - self.mean;
+        self.mean += weight * delta;
+        self.variance = (1.0 - weight) * self.variance
+            + weight * other.variance
+            + weight * (1.0 - weight) * delta * delta;
+        self.sample_count = sample_count;
+    }
+
+    pub fn std_dev(&self) -> f64 {
+        self.variance.sqrt()
+    }
+
+    /// Z-score of `intensity` against the baseline, or `None` during cold
+    /// start or when the history has no spread to compare against.
+    pub fn relative_score(&self, intensity: f32, min_samples: u64) -> Option<f32> {
+        let std_dev = self.std_dev();
+        if self.sample_count < min_samples.max(2) || std_dev < f64::EPSILON {
+            return None;
+        }
+        Some(((intensity as f64 - self.mean) / std_dev) as f32)
+    }
+}
+
+/// Decides which flow analyses get written to `flow_states`, so per-second
+/// detection calls don't turn into per-second rows. Flow enter/exit
+/// transitions are always kept; otherwise a session gets at most one
+/// keyframe row per sampling interval.
+pub struct FlowSampler {
+    interval: Duration,
+    sessions: DashMap<Uuid, SampleState>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SampleState {
+    last_persisted: Instant,
+    was_in_flow: bool,
+}
+
+impl FlowSampler {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            sessions: DashMap::new(),
+        }
+    }
+
+    pub fn should_persist(&self, session_id: Uuid, is_in_flow: bool) -> bool {
+        self.should_persist_at(session_id, is_in_flow, Instant::now())
+    }
+
+    pub fn should_persist_at(&self, session_id: Uuid, is_in_flow: bool, now: Instant) -> bool {
+        let mut state = match self.sessions.entry(session_id) {
+            Entry::Vacant(entry) => {
+                // First analysis for the session always creates a row
+                entry.insert(SampleState {
+                    last_persisted: now,
+                    was_in_flow: is_in_flow,
+                });
+                return true;
+            }
+            Entry::Occupied(entry) => entry.into_ref(),
+        };
+
+        let is_transition = state.was_in_flow != is_in_flow;
+        let interval_elapsed = now.saturating_duration_since(state.last_persisted) >= self.interval;
+        state.was_in_flow = is_in_flow;
+
+        if is_transition || interval_elapsed {
+            state.last_persisted = now;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn forget_session(&self, session_id: Uuid) {
+        self.sessions.remove(&session_id);
+    }
+}
+
+/// Salted SHA-256 over a sample's keystroke timings. Lets duplicate and
+/// tampered samples be recognised, and stored for users who don't want the
+/// timings kept, without the sequence itself being recoverable.
+pub struct KeystrokeHasher {
+    salt: Vec<u8>,
+}
+
+impl Default for KeystrokeHasher {
+    /// A random per-process salt, for engines that never persist hashes.
+    fn default() -> Self {
+        let mut salt = vec![0u8; 32];
+        rand::thread_rng().fill_bytes(&mut salt);
+        Self { salt }
+    }
+}
+
+impl KeystrokeHasher {
+    pub fn new(salt: &[u8]) -> Self {
+        Self {
+            salt: salt.to_vec(),
+        }
+    }
+
+    /// Hex digest of the raw intervals, or of the aggregates for minimized
+    /// samples. The two are domain-separated so they can never collide.
+    pub fn hash(&self, data: &FlowStateData) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(&self.salt);
+
+        match data.aggregates {
+            Some(aggregates) if data.keystroke_intervals.is_empty() => {
+                hasher.update(b"aggregates");
+                hasher.update(aggregates.count.to_le_bytes());
+                hasher.update(aggregates.mean_interval_ms.to_le_bytes());
+                hasher.update(aggregates.coefficient_of_variation.to_le_bytes());
+            }
+            _ => {
+                hasher.update(b"intervals");
+                hasher.update((data.keystroke_intervals.len() as u64).to_le_bytes());
+                for interval in &data.keystroke_intervals {
+                    hasher.update(interval.to_le_bytes());
+                }
+            }
+        }
+
+        hex::encode(hasher.finalize())
+    }
+}
@@ -0,0 +1,120 @@
+use crate::{
+    error::{AppError, Result},
+    models::flow::FlowDiffResponse,
+    services::{comparison::compare_samples, compression::read_json_column},
+};
+use sqlx::PgConnection;
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+
+/// Per-sample scores averaged into each session's value, in response order.
+const SCORE_FEATURES: [&str; 4] = [
+    "rhythm_score",
+    "focus_score",
+    "consistency_score",
+    "velocity_score",
+];
+
+/// One value per session for each compared metric. Sessions without flow
+/// samples have no scores, and unfinished ones no flow time.
+#[derive(Debug, Default)]
+struct SessionMetrics {
+    scores: [Vec<f64>; 4],
+    flow_time_ms: Vec<f64>,
+}
+
+/// Compares the user's `before` sessions with their `after` sessions.
+/// Every session must be theirs and in only one of the two groups.
+pub async fn diff_sessions(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    before: &[Uuid],
+    after: &[Uuid],
+) -> Result<FlowDiffResponse> {
+    let before_ids: HashSet<Uuid> = before.iter().copied().collect();
+    if after
+        .iter()
+        .any(|session_id| before_ids.contains(session_id))
+    {
+        return Err(AppError::Validation(
+            "A session can't be both before and after".to_string(),
+        ));
+    }
+
+    let before = load_session_metrics(&mut *conn, user_id, before).await?;
+    let after = load_session_metrics(&mut *conn, user_id, after).await?;
+    let [rhythm, focus, consistency, velocity] =
+        std::array::from_fn(|i| compare_samples(&before.scores[i], &after.scores[i]));
+
+    Ok(FlowDiffResponse {
+        rhythm,
+        focus,
+        consistency,
+        velocity,
+        flow_time_ms: compare_samples(&before.flow_time_ms, &after.flow_time_ms),
+    })
+}
+
+async fn load_session_metrics(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    session_ids: &[Uuid],
+) -> Result<SessionMetrics> {
+    let sessions = sqlx::query!(
+        "SELECT id, total_flow_time_ms FROM coding_sessions WHERE user_id = $1 AND id = ANY($2)",
+        user_id,
+        session_ids
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let owned: HashSet<Uuid> = sessions.iter().map(|session| session.id).collect();
+    let missing: Vec<String> = session_ids
+        .iter()
+        .filter(|session_id| !owned.contains(session_id))
+        .map(|session_id| session_id.to_string())
+        .collect();
+    if !missing.is_empty() {
+        return Err(AppError::NotFound(format!(
+            "Sessions not found: {}",
+            missing.join(", ")
+        )));
+    }
+
+    let samples = sqlx::query!(
+        r#"
+        SELECT fs.session_id, fs.ml_features, fs.ml_features_blob
+        FROM flow_states fs
+        JOIN coding_sessions cs ON fs.session_id = cs.id
+        WHERE cs.user_id = $1 AND cs.id = ANY($2)
+        "#,
+        user_id,
+        session_ids
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let mut totals: HashMap<Uuid, ([f64; 4], usize)> = HashMap::new();
+    for sample in samples {
+        let features = read_json_column(sample.ml_features_blob.as_deref(), sample.ml_features)?;
+        let (sums, count) = totals.entry(sample.session_id).or_default();
+        for (sum, key) in sums.iter_mut().zip(SCORE_FEATURES) {
+            *sum += features.get(key).and_then(|v| v.as_f64()).unwrap_or(0.0);
+        }
+        *count += 1;
+    }
+
+    let mut metrics = SessionMetrics::default();
+    for (sums, count) in totals.into_values() {
+        for (scores, sum) in metrics.scores.iter_mut().zip(sums) {
+            scores.push(sum / count as f64);
+        }
+    }
+    metrics.flow_time_ms = sessions
+        .iter()
+        .filter_map(|session| session.total_flow_time_ms)
+        .map(|ms| ms as f64)
+        .collect();
+
+    Ok(metrics)
+}
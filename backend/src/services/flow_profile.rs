@@ -0,0 +1,195 @@
+use crate::{
+    error::Result, models::team::FlowProfileSimilarity, services::compression::read_json_column,
+};
+use chrono::{DateTime, Duration, Timelike, Utc};
+use chrono_tz::Tz;
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+/// How far back a profile looks.
+const PROFILE_WINDOW_DAYS: i64 = 30;
+
+/// Fewer flow samples than this say too little about someone's habits.
+const MIN_FLOW_SAMPLES: usize = 10;
+
+const PEAK_HOURS_WEIGHT: f64 = 0.5;
+const SESSION_LENGTH_WEIGHT: f64 = 0.25;
+const RHYTHM_WEIGHT: f64 = 0.25;
+
+/// A summary of when and how a user reaches flow. It is only ever compared,
+/// never returned, so it can't be read back into someone's raw activity.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlowProfile {
+    /// Total flow intensity per hour of the user's local day
+    pub hourly_intensity: [f64; 24],
+    /// Median length of the sessions in which the user reached flow
+    pub typical_session_ms: f64,
+    /// Mean rhythm, consistency and velocity scores
+    pub rhythm: [f64; 3],
+}
+
+impl FlowProfile {
+    pub fn similarity(&self, other: &FlowProfile) -> FlowProfileSimilarity {
+        let peak_hours = cosine_similarity(&self.hourly_intensity, &other.hourly_intensity);
+
+        let longest = self.typical_session_ms.max(other.typical_session_ms);
+        let session_length = if longest > 0.0 {
+            1.0 - (self.typical_session_ms - other.typical_session_ms).abs() / longest
+        } else {
+            1.0
+        };
+
+        let rhythm_gap = self
+            .rhythm
+            .iter()
+            .zip(other.rhythm.iter())
+            .map(|(a, b)| (a - b).abs())
+            .sum::<f64>()
+            / self.rhythm.len() as f64;
+        let rhythm = (1.0 - rhythm_gap).clamp(0.0, 1.0);
+
+        let overall = PEAK_HOURS_WEIGHT * peak_hours
+            + SESSION_LENGTH_WEIGHT * session_length
+            + RHYTHM_WEIGHT * rhythm;
+
+        FlowProfileSimilarity {
+            overall: overall.clamp(0.0, 1.0),
+            peak_hours,
+            session_length,
+            rhythm,
+        }
+    }
+}
+
+/// Builds the user's profile from the last 30 days, or `None` when they
+/// have too little flow history to compare.
+pub async fn load_flow_profile(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    now: DateTime<Utc>,
+) -> Result<Option<FlowProfile>> {
+    let since = now - Duration::days(PROFILE_WINDOW_DAYS);
+
+    let timezone = sqlx::query_scalar!("SELECT timezone FROM users WHERE id = $1", user_id)
+        .fetch_optional(&mut *conn)
+        .await?
+        .flatten()
+        .and_then(|name| name.parse::<Tz>().ok());
+
+    let samples = sqlx::query!(
+        r#"
+        SELECT fs.start_time, fs.intensity_score::FLOAT8 as "intensity!",
+               fs.ml_features, fs.ml_features_blob
+        FROM flow_states fs
+        JOIN coding_sessions cs ON fs.session_id = cs.id
+        WHERE cs.user_id = $1 AND fs.start_time >= $2 AND fs.start_time < $3
+        "#,
+        user_id,
+        since,
+        now,
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    if samples.len() < MIN_FLOW_SAMPLES {
+        return Ok(None);
+    }
+
+    let mut session_lengths = sqlx::query_scalar!(
+        r#"
+        SELECT total_duration_ms as "total_duration_ms!"
+        FROM coding_sessions
+        WHERE user_id = $1 AND start_time >= $2 AND start_time < $3
+          AND total_flow_time_ms > 0 AND total_duration_ms > 0
+        "#,
+        user_id,
+        since,
+        now,
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let mut hourly_intensity = [0.0; 24];
+    let mut rhythm_totals = [0.0; 3];
+    for sample in &samples {
+        let hour = match timezone {
+            Some(tz) => sample.start_time.with_timezone(&tz).hour(),
+            None => sample.start_time.hour(),
+        };
+        hourly_intensity[hour as usize] += sample.intensity;
+
+        let features = read_json_column(
+            sample.ml_features_blob.as_deref(),
+            sample.ml_features.clone(),
+        )?;
+        for (total, key) in
+            rhythm_totals
+                .iter_mut()
+                .zip(["rhythm_score", "consistency_score", "velocity_score"])
+        {
+            *total += features.get(key).and_then(|v| v.as_f64()).unwrap_or(0.0);
+        }
+    }
+
+    Ok(Some(FlowProfile {
+        hourly_intensity,
+        typical_session_ms: median(&mut session_lengths),
+        rhythm: rhythm_totals.map(|total| total / samples.len() as f64),
+    }))
+}
+
+fn cosine_similarity(a: &[f64], b: &[f64]) -> f64 {
+    let dot = a.iter().zip(b).map(|(x, y)| x * y).sum::<f64>();
+    let norm = |v: &[f64]| v.iter().map(|x| x * x).sum::<f64>().sqrt();
+    let norms = norm(a) * norm(b);
+    if norms > 0.0 {
+        (dot / norms).clamp(0.0, 1.0)
+    } else {
+        0.0
+    }
+}
+
+fn median(values: &mut [i64]) -> f64 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    values.sort_unstable();
+    let mid = values.len() / 2;
+    if values.len() % 2 == 0 {
+        (values[mid - 1] + values[mid]) as f64 / 2.0
+    } else {
+        values[mid] as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(peak_hours: &[usize], typical_session_ms: f64, rhythm: f64) -> FlowProfile {
+        let mut hourly_intensity = [0.0; 24];
+        for &hour in peak_hours {
+            hourly_intensity[hour] = 0.8;
+        }
+        FlowProfile {
+            hourly_intensity,
+            typical_session_ms,
+            rhythm: [rhythm; 3],
+        }
+    }
+
+    #[test]
+    fn test_similar_habits_score_higher_than_different_ones() {
+        let morning = profile(&[9, 10, 11], 3_600_000.0, 0.8);
+        let also_morning = profile(&[9, 10], 3_000_000.0, 0.75);
+        let night = profile(&[21, 22, 23], 1_200_000.0, 0.3);
+
+        let close = morning.similarity(&also_morning);
+        let far = morning.similarity(&night);
+        assert!(close.overall > 0.8, "{:?}", close);
+        assert!(far.overall < 0.4, "{:?}", far);
+        assert_eq!(far.peak_hours, 0.0);
+
+        assert!((morning.similarity(&morning).overall - 1.0).abs() < 1e-9);
+    }
+}
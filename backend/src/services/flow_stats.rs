@@ -0,0 +1,71 @@
+use crate::{error::Result, services::flow::FlowDetectionEngine};
+use parking_lot::RwLock;
+use serde::Serialize;
+use sqlx::PgPool;
+use tracing::warn;
+use uuid::Uuid;
+
+/// A user's flow stretches and flow time over every retired engine.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct UserFlowTotals {
+    pub flow_session_count: u64,
+    pub total_flow_time_ms: u64,
+}
+
+/// Adds a retired engine's flow stretch count and flow time to the user's
+/// lifetime totals. Call once per engine, after it has left the engine map,
+/// or its stats are counted twice. Anonymous trial users have no row to add
+/// to, so theirs are skipped.
+pub async fn persist_engine_stats(
+    db: &PgPool,
+    user_id: Uuid,
+    engine: &RwLock<FlowDetectionEngine>,
+) -> Result<()> {
+    let (flow_session_count, total_flow_time) = engine.read().get_session_stats();
+    if flow_session_count == 0 && total_flow_time.is_zero() {
+        return Ok(());
+    }
+
+    sqlx::query!(
+        r#"
+        INSERT INTO user_flow_stats (user_id, flow_session_count, total_flow_time_ms)
+        SELECT id, $2, $3 FROM users WHERE id = $1
+        ON CONFLICT (user_id) DO UPDATE SET
+            flow_session_count = user_flow_stats.flow_session_count + EXCLUDED.flow_session_count,
+            total_flow_time_ms = user_flow_stats.total_flow_time_ms + EXCLUDED.total_flow_time_ms,
+            updated_at = NOW()
+        "#,
+        user_id,
+        flow_session_count as i64,
+        total_flow_time.as_millis() as i64,
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// `persist_engine_stats` for an engine leaving the map, whether its session
+/// ended or it was evicted. A failed write only costs those totals, so it
+/// doesn't fail the caller.
+pub async fn retire_flow_engine(db: &PgPool, user_id: Uuid, engine: &RwLock<FlowDetectionEngine>) {
+    if let Err(e) = persist_engine_stats(db, user_id, engine).await {
+        warn!("Failed to persist flow stats for user {}: {}", user_id, e);
+    }
+}
+
+pub async fn load_user_flow_totals(db: &PgPool, user_id: Uuid) -> Result<UserFlowTotals> {
+    let totals = sqlx::query!(
+        "SELECT flow_session_count, total_flow_time_ms FROM user_flow_stats WHERE user_id = $1",
+        user_id
+    )
+    .fetch_optional(db)
+    .await?
+    .map(|row| UserFlowTotals {
+        flow_session_count: row.flow_session_count.max(0) as u64,
+        total_flow_time_ms: row.total_flow_time_ms.max(0) as u64,
+    })
+    .unwrap_or_default();
+
+    Ok(totals)
+}
@@ -0,0 +1,141 @@
+use crate::config::DeferredReminderPolicy;
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use uuid::Uuid;
+
+#[derive(Debug, Clone)]
+struct FocusModeState {
+    started_at: DateTime<Utc>,
+    in_flow: bool,
+    /// Break reminder waiting for the current flow stretch to end
+    held_reminder: Option<String>,
+}
+
+/// Users with focus mode on. While it is, only critical notifications get
+/// through, and break reminders wait until the user's flow stretch ends.
+pub struct FocusModes {
+    users: DashMap<Uuid, FocusModeState>,
+    deferred_reminders: DeferredReminderPolicy,
+}
+
+impl FocusModes {
+    pub fn new(deferred_reminders: DeferredReminderPolicy) -> Self {
+        Self {
+            users: DashMap::new(),
+            deferred_reminders,
+        }
+    }
+
+    /// Turns focus mode on; an already active period keeps its start time.
+    pub fn enable(&self, user_id: Uuid, now: DateTime<Utc>) -> DateTime<Utc> {
+        self.users
+            .entry(user_id)
+            .or_insert_with(|| FocusModeState {
+                started_at: now,
+                in_flow: false,
+                held_reminder: None,
+            })
+            .started_at
+    }
+
+    /// Turns focus mode off, releasing any held break reminder per policy.
+    pub fn disable(&self, user_id: Uuid) -> Option<String> {
+        let (_, focus) = self.users.remove(&user_id)?;
+        self.release(focus.held_reminder)
+    }
+
+    pub fn is_active(&self, user_id: Uuid) -> bool {
+        self.users.contains_key(&user_id)
+    }
+
+    pub fn started_at(&self, user_id: Uuid) -> Option<DateTime<Utc>> {
+        self.users.get(&user_id).map(|focus| focus.started_at)
+    }
+
+    /// Tracks the user's latest flow state. Returns the held break reminder,
+    /// per policy, when a flow stretch has just ended.
+    pub fn record_flow(&self, user_id: Uuid, in_flow: bool) -> Option<String> {
+        let held = {
+            let mut focus = self.users.get_mut(&user_id)?;
+            let ended = focus.in_flow && !in_flow;
+            focus.in_flow = in_flow;
+            if !ended {
+                return None;
+            }
+            focus.held_reminder.take()
+        };
+        self.release(held)
+    }
+
+    /// Holds a break reminder back if the user is in flow with focus mode
+    /// on. Returns whether it was held; a newer reminder replaces an older
+    /// one.
+    pub fn hold_break_reminder(&self, user_id: Uuid, reminder: String) -> bool {
+        match self.users.get_mut(&user_id) {
+            Some(mut focus) if focus.in_flow => {
+                focus.held_reminder = Some(reminder);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn release(&self, held: Option<String>) -> Option<String> {
+        match self.deferred_reminders {
+            DeferredReminderPolicy::Deliver => held,
+            DeferredReminderPolicy::Drop => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reminders_wait_for_flow_to_end() {
+        let focus_modes = FocusModes::new(DeferredReminderPolicy::Deliver);
+        let user_id = Uuid::new_v4();
+
+        // Without focus mode nothing is held
+        assert!(!focus_modes.hold_break_reminder(user_id, "reminder".to_string()));
+
+        focus_modes.enable(user_id, Utc::now());
+        // Focus mode alone doesn't hold reminders, only a flow stretch does
+        assert!(!focus_modes.hold_break_reminder(user_id, "reminder".to_string()));
+
+        assert_eq!(focus_modes.record_flow(user_id, true), None);
+        assert!(focus_modes.hold_break_reminder(user_id, "first".to_string()));
+        assert!(focus_modes.hold_break_reminder(user_id, "second".to_string()));
+        assert_eq!(focus_modes.record_flow(user_id, true), None);
+
+        assert_eq!(focus_modes.record_flow(user_id, false), Some("second".to_string()));
+        assert_eq!(focus_modes.record_flow(user_id, false), None);
+    }
+
+    #[test]
+    fn test_drop_policy_discards_held_reminders() {
+        let focus_modes = FocusModes::new(DeferredReminderPolicy::Drop);
+        let user_id = Uuid::new_v4();
+
+        focus_modes.enable(user_id, Utc::now());
+        focus_modes.record_flow(user_id, true);
+        assert!(focus_modes.hold_break_reminder(user_id, "reminder".to_string()));
+        assert_eq!(focus_modes.disable(user_id), None);
+        assert!(!focus_modes.is_active(user_id));
+    }
+
+    #[test]
+    fn test_enabling_twice_keeps_start_time() {
+        let focus_modes = FocusModes::new(DeferredReminderPolicy::Deliver);
+        let user_id = Uuid::new_v4();
+        let started_at = Utc::now();
+
+        assert_eq!(focus_modes.enable(user_id, started_at), started_at);
+        assert_eq!(
+            focus_modes.enable(user_id, started_at + chrono::Duration::minutes(5)),
+            started_at
+        );
+        assert_eq!(focus_modes.started_at(user_id), Some(started_at));
+    }
+}
@@ -0,0 +1,268 @@
+use crate::{
+    error::Result,
+    services::flow::FlowBaseline,
+    state::AppState,
+    utils::date_range::{DateRange, NamedRange},
+};
+use chrono::{DateTime, Duration, Utc};
+use sqlx::PgConnection;
+use std::time::Duration as StdDuration;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// Flow samples in a period below which no insight is generated.
+const MIN_INSIGHT_SAMPLES: i64 = 5;
+
+/// An insight computed for one user over one period, before it is stored.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeneratedInsight {
+    pub insight_type: &'static str,
+    pub title: String,
+    pub description: String,
+    pub impact_score: f64,
+    pub suggestions: Vec<String>,
+    pub confidence: f64,
+    pub data_points: u32,
+}
+
+impl GeneratedInsight {
+    /// The `insight_data` blob `get_flow_insights` reads back.
+    fn data(&self) -> serde_json::Value {
+        serde_json::json!({
+            "title": self.title,
+            "description": self.description,
+            "impact_score": self.impact_score,
+            "suggestions": self.suggestions,
+            "data_points": self.data_points,
+        })
+    }
+}
+
+/// Insights are generated per calendar week, so every run within a week
+/// refreshes the same rows. The range ends now.
+pub fn insight_period(now: DateTime<Utc>) -> DateRange {
+    DateRange::named(NamedRange::ThisWeek, now)
+}
+
+pub async fn generate_insights(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    period: DateRange,
+) -> Result<Vec<GeneratedInsight>> {
+    let mut insights = Vec::new();
+
+    let hours = sqlx::query!(
+        r#"
+        SELECT EXTRACT(HOUR FROM fs.start_time)::INT as "hour!",
+               AVG(fs.intensity_score)::FLOAT8 as "intensity!",
+               COUNT(*) as "samples!"
+        FROM flow_states fs
+        JOIN coding_sessions cs ON fs.session_id = cs.id
+        WHERE cs.user_id = $1 AND fs.start_time >= $2 AND fs.start_time < $3
+        GROUP BY 1
+        "#,
+        user_id,
+        period.from,
+        period.to,
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let samples: i64 = hours.iter().map(|hour| hour.samples).sum();
+    let peak = hours
+        .iter()
+        .max_by(|a, b| a.intensity.total_cmp(&b.intensity));
+    if let Some(peak) = peak.filter(|_| samples >= MIN_INSIGHT_SAMPLES) {
+        insights.push(GeneratedInsight {
+            insight_type: "peak_flow_hour",
+            title: format!("Your flow peaks around {:02}:00", peak.hour),
+            description: format!(
+                "This week your deepest focus came at {:02}:00 UTC, averaging {:.0}% intensity.",
+                peak.hour,
+                peak.intensity * 100.0
+            ),
+            impact_score: peak.intensity,
+            suggestions: vec![
+                "Schedule demanding work for this hour".to_string(),
+                "Keep meetings out of it where you can".to_string(),
+            ],
+            confidence: (samples as f64 / 50.0).min(1.0),
+            data_points: samples as u32,
+        });
+    }
+
+    let flow_time = sqlx::query!(
+        r#"
+        SELECT COUNT(*) as "sessions!",
+               COALESCE(SUM(total_flow_time_ms), 0)::BIGINT as "flow_ms!"
+        FROM coding_sessions
+        WHERE user_id = $1 AND end_time >= $2 AND end_time < $3
+        "#,
+        user_id,
+        period.from,
+        period.to,
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+
+    if flow_time.sessions > 0 && samples >= MIN_INSIGHT_SAMPLES {
+        let hours = flow_time.flow_ms as f64 / 3_600_000.0;
+        insights.push(GeneratedInsight {
+            insight_type: "weekly_flow_time",
+            title: format!("{:.1} hours in flow this week", hours),
+            description: format!(
+                "You reached flow for {:.1} hours across {} sessions.",
+                hours, flow_time.sessions
+            ),
+            impact_score: (hours / 20.0).min(1.0),
+            suggestions: vec!["Protect long uninterrupted blocks to add to it".to_string()],
+            confidence: (flow_time.sessions as f64 / 10.0).min(1.0),
+            data_points: flow_time.sessions as u32,
+        });
+    }
+
+    Ok(insights)
+}
+
+/// Recorded when a user's flow intensity has stayed well below their
+/// baseline for `minutes`, which often means fatigue.
+pub fn focus_dip_insight(baseline: &FlowBaseline, intensity: f32, minutes: u64) -> GeneratedInsight {
+    GeneratedInsight {
+        insight_type: "focus_dip",
+        title: "Your focus dipped".to_string(),
+        description: format!(
+            "For {} minutes your flow intensity stayed around {:.0}%, well below your usual {:.0}%.",
+            minutes,
+            intensity * 100.0,
+            baseline.mean * 100.0
+        ),
+        impact_score: (baseline.mean - intensity as f64).clamp(0.0, 1.0),
+        suggestions: vec![
+            "Take a short break away from the screen".to_string(),
+            "Come back to a smaller, well-defined task".to_string(),
+        ],
+        confidence: (baseline.sample_count as f64 / 100.0).min(1.0),
+        data_points: baseline.sample_count.min(u32::MAX as u64) as u32,
+    }
+}
+
+/// Stores the insight, or refreshes the one already stored for the same
+/// user, type and period, reactivating it if it had expired.
+pub async fn upsert_insight(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    period: DateRange,
+    insight: &GeneratedInsight,
+    now: DateTime<Utc>,
+) -> Result<()> {
+    // Keyed on the whole week rather than the part of it that has passed,
+    // so a later run in the week finds this row
+    let week_start = period.from.date_naive();
+    let week_end = week_start + Duration::days(6);
+
+    sqlx::query!(
+        r#"
+        INSERT INTO user_insights (
+            user_id, insight_type, insight_data, confidence_score,
+            date_range_start, date_range_end, is_active, created_at, updated_at
+        ) VALUES ($1, $2, $3, $4::FLOAT8, $5, $6, true, $7, $7)
+        ON CONFLICT (user_id, insight_type, date_range_start, date_range_end) DO UPDATE SET
+            insight_data = EXCLUDED.insight_data,
+            confidence_score = EXCLUDED.confidence_score,
+            is_active = true,
+            updated_at = EXCLUDED.updated_at
+        "#,
+        user_id,
+        insight.insight_type,
+        insight.data(),
+        insight.confidence,
+        week_start,
+        week_end,
+        now,
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
+
+/// Deactivates insights not refreshed within `expiry_days`. Returns how
+/// many were deactivated.
+pub async fn expire_insights(
+    conn: &mut PgConnection,
+    now: DateTime<Utc>,
+    expiry_days: i64,
+) -> Result<u64> {
+    let expired = sqlx::query!(
+        r#"
+        UPDATE user_insights
+        SET is_active = false
+        WHERE is_active = true AND updated_at < $1
+        "#,
+        now - Duration::days(expiry_days),
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(expired.rows_affected())
+}
+
+/// Regenerates the user's insights for the current period. Returns how many
+/// were stored or refreshed.
+pub async fn refresh_user_insights(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    now: DateTime<Utc>,
+) -> Result<usize> {
+    let period = insight_period(now);
+    let insights = generate_insights(&mut *conn, user_id, period).await?;
+    for insight in &insights {
+        upsert_insight(&mut *conn, user_id, period, insight, now).await?;
+    }
+    Ok(insights.len())
+}
+
+/// One pipeline run: refreshes insights for every user with a session in
+/// the current period, then expires stale ones.
+pub async fn run_insight_pipeline(state: &AppState, now: DateTime<Utc>) -> Result<(usize, u64)> {
+    let mut conn = state.db.acquire().await?;
+    let period = insight_period(now);
+
+    let user_ids = sqlx::query_scalar!(
+        "SELECT DISTINCT user_id FROM coding_sessions WHERE start_time >= $1",
+        period.from
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let mut refreshed = 0;
+    for user_id in user_ids {
+        match refresh_user_insights(&mut conn, user_id, now).await {
+            Ok(count) => refreshed += count,
+            Err(e) => warn!("Failed to refresh insights for user {}: {}", user_id, e),
+        }
+    }
+
+    let expired = expire_insights(&mut conn, now, state.config.insights.expiry_days).await?;
+    Ok((refreshed, expired))
+}
+
+/// Runs the insight pipeline on the configured interval for the life of
+/// the server.
+pub async fn run_insight_scheduler(state: AppState) {
+    let mut interval = tokio::time::interval(StdDuration::from_secs(
+        state.config.insights.refresh_interval_secs,
+    ));
+    loop {
+        interval.tick().await;
+
+        match run_insight_pipeline(&state, Utc::now()).await {
+            Ok((refreshed, expired)) => {
+                if refreshed > 0 || expired > 0 {
+                    info!("Refreshed {} insights, expired {}", refreshed, expired);
+                }
+            }
+            Err(e) => warn!("Insight pipeline failed: {}", e),
+        }
+    }
+}
@@ -0,0 +1,304 @@
+use crate::{
+    error::{AppError, Result},
+    models::audit::AuditOperation,
+    services::{
+        audit::record_audit_entry,
+        encryption::{decode_hex_key, EncryptedData, EncryptionService},
+    },
+    state::AppState,
+};
+use chrono::{DateTime, Utc};
+use parking_lot::RwLock;
+use std::time::Duration;
+use tracing::{info, warn};
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+/// encrypted_user_data rows re-encrypted per query.
+const REENCRYPT_BATCH_SIZE: i64 = 500;
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct KeyRotationReport {
+    /// The new key id, when this cycle rotated
+    pub rotated_to: Option<String>,
+    /// Rows moved onto the active key
+    pub reencrypted: u64,
+    /// Retired keys deleted
+    pub pruned: Vec<String>,
+}
+
+/// Makes the service use the keys in `encryption_keys`: the active one for
+/// new data and the retired ones for decryption. On first run, registers
+/// the ENCRYPTION_KEY key as the active key instead.
+pub async fn load_encryption_keys(state: &AppState, now: DateTime<Utc>) -> Result<()> {
+    let Some(encryption) = &state.encryption else {
+        return Ok(());
+    };
+
+    let rows = sqlx::query!("SELECT key_id, wrapped_key, retired_at FROM encryption_keys")
+        .fetch_all(&state.db)
+        .await?;
+
+    if rows.is_empty() {
+        let key_id = encryption.read().get_current_key_id().to_string();
+        sqlx::query!(
+            r#"
+            INSERT INTO encryption_keys (key_id, created_at)
+            VALUES ($1, $2)
+            ON CONFLICT DO NOTHING
+            "#,
+            key_id,
+            now
+        )
+        .execute(&state.db)
+        .await?;
+        return Ok(());
+    }
+
+    let root_key = decode_hex_key(&state.config.encryption_key)?;
+    let root = EncryptionService::new(&root_key)?;
+
+    let mut active = None;
+    let mut retired = Vec::new();
+    for row in rows {
+        let key = match &row.wrapped_key {
+            Some(wrapped) => unwrap_key(&root, wrapped)?,
+            None => root_key.clone(),
+        };
+        match row.retired_at {
+            None => active = Some((row.key_id, key)),
+            Some(_) => retired.push((row.key_id, key)),
+        }
+    }
+
+    let (active_key_id, active_key) = active
+        .ok_or_else(|| AppError::Encryption("No active encryption key is stored".to_string()))?;
+    encryption
+        .write()
+        .restore_keys(active_key_id, &active_key, retired);
+    Ok(())
+}
+
+/// One pass of the schedule: picks up keys rotated by other instances,
+/// rotates if the active key is due, moves data off retired keys, then
+/// prunes retired keys past retention that nothing uses any more.
+pub async fn run_key_rotation_cycle(
+    state: &AppState,
+    now: DateTime<Utc>,
+) -> Result<KeyRotationReport> {
+    let Some(encryption) = &state.encryption else {
+        return Ok(KeyRotationReport::default());
+    };
+
+    load_encryption_keys(state, now).await?;
+    let rotated_to = rotate_if_due(state, encryption, now).await?;
+    let reencrypted = reencrypt_retired_data(state, encryption).await?;
+
+    let retention = chrono::Duration::days(state.config.key_rotation.retired_key_retention_days);
+    let pruned = sqlx::query_scalar!(
+        r#"
+        DELETE FROM encryption_keys k
+        WHERE k.retired_at < $1
+          AND NOT EXISTS (
+              SELECT 1 FROM encrypted_user_data d WHERE d.encryption_key_id = k.key_id
+          )
+        RETURNING k.key_id
+        "#,
+        now - retention
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    {
+        let mut encryption = encryption.write();
+        for key_id in &pruned {
+            encryption.remove_rotation_key(key_id);
+        }
+    }
+
+    Ok(KeyRotationReport {
+        rotated_to,
+        reencrypted,
+        pruned,
+    })
+}
+
+/// Runs the rotation schedule for the life of the server. Does nothing when
+/// encryption or scheduled rotation is off.
+pub async fn run_key_rotation_scheduler(state: AppState) {
+    let config = state.config.key_rotation.clone();
+    if config.interval_days == 0 || state.encryption.is_none() {
+        return;
+    }
+
+    let mut interval = tokio::time::interval(Duration::from_secs(config.check_interval_secs));
+    loop {
+        interval.tick().await;
+
+        match run_key_rotation_cycle(&state, Utc::now()).await {
+            Ok(report) => {
+                if let Some(key_id) = &report.rotated_to {
+                    info!("Rotated field encryption key to {}", key_id);
+                }
+                if report.reencrypted > 0 {
+                    info!(
+                        "Re-encrypted {} records under the active key",
+                        report.reencrypted
+                    );
+                }
+                for key_id in &report.pruned {
+                    info!("Pruned retired encryption key {}", key_id);
+                }
+            }
+            Err(e) => warn!("Key rotation cycle failed: {}", e),
+        }
+    }
+}
+
+/// The new key is stored before the service switches to it, so nothing is
+/// ever encrypted under a key that isn't persisted.
+async fn rotate_if_due(
+    state: &AppState,
+    encryption: &RwLock<EncryptionService>,
+    now: DateTime<Utc>,
+) -> Result<Option<String>> {
+    let interval = chrono::Duration::days(state.config.key_rotation.interval_days);
+    if interval.is_zero() {
+        return Ok(None);
+    }
+
+    let mut tx = state.db.begin().await?;
+
+    // Locks the active key, so concurrent instances can't both rotate it
+    let Some(active) = sqlx::query!(
+        "SELECT key_id, created_at FROM encryption_keys WHERE retired_at IS NULL FOR UPDATE"
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    else {
+        return Ok(None);
+    };
+    if now - active.created_at < interval {
+        return Ok(None);
+    }
+
+    let root = EncryptionService::from_hex_key(&state.config.encryption_key)?;
+    let new_key = EncryptionService::generate_master_key();
+    let new_key_id = format!("key_{}", now.timestamp());
+    let wrapped_key =
+        root.encrypt_field(&Zeroizing::new(EncryptionService::key_to_hex(&new_key)))?;
+
+    sqlx::query!(
+        "UPDATE encryption_keys SET retired_at = $2 WHERE key_id = $1",
+        active.key_id,
+        now
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        "INSERT INTO encryption_keys (key_id, wrapped_key, created_at) VALUES ($1, $2, $3)",
+        new_key_id,
+        wrapped_key,
+        now
+    )
+    .execute(&mut *tx)
+    .await?;
+    record_audit_entry(
+        &mut tx,
+        None,
+        None,
+        AuditOperation::KeyRotation,
+        serde_json::json!({
+            "old_key_id": active.key_id,
+            "new_key_id": new_key_id,
+            "scheduled": true,
+        }),
+    )
+    .await?;
+    tx.commit().await?;
+
+    encryption
+        .write()
+        .rotate_key_as(&new_key, new_key_id.clone())?;
+    Ok(Some(new_key_id))
+}
+
+/// Re-encrypts every encrypted_user_data row still under a retired key.
+/// Rows that fail to decrypt are left, and keep their key from being pruned.
+async fn reencrypt_retired_data(
+    state: &AppState,
+    encryption: &RwLock<EncryptionService>,
+) -> Result<u64> {
+    let active_key_id = encryption.read().get_current_key_id().to_string();
+    let mut after = Uuid::nil();
+    let mut reencrypted = 0;
+
+    loop {
+        let rows = sqlx::query!(
+            r#"
+            SELECT id, encrypted_data, encryption_key_id
+            FROM encrypted_user_data
+            WHERE encryption_key_id <> $1 AND id > $2
+            ORDER BY id
+            LIMIT $3
+            "#,
+            active_key_id,
+            after,
+            REENCRYPT_BATCH_SIZE
+        )
+        .fetch_all(&state.db)
+        .await?;
+
+        let Some(last) = rows.last() else {
+            break;
+        };
+        after = last.id;
+
+        for row in rows {
+            let resealed = {
+                let encryption = encryption.read();
+                reseal(&encryption, &row.encrypted_data)
+            };
+            let (encrypted_data, key_id) = match resealed {
+                Ok(resealed) => resealed,
+                Err(e) => {
+                    warn!("Could not re-encrypt record {}: {}", row.id, e);
+                    continue;
+                }
+            };
+
+            // Skips rows rewritten since they were read
+            reencrypted += sqlx::query!(
+                r#"
+                UPDATE encrypted_user_data
+                SET encrypted_data = $2, encryption_key_id = $3
+                WHERE id = $1 AND encryption_key_id = $4
+                "#,
+                row.id,
+                encrypted_data,
+                key_id,
+                row.encryption_key_id
+            )
+            .execute(&state.db)
+            .await?
+            .rows_affected();
+        }
+    }
+
+    Ok(reencrypted)
+}
+
+fn reseal(encryption: &EncryptionService, stored: &[u8]) -> Result<(Vec<u8>, String)> {
+    let sealed: EncryptedData = serde_json::from_slice(stored)
+        .map_err(|e| AppError::Encryption(format!("Invalid encrypted record: {}", e)))?;
+    let value: serde_json::Value = encryption.decrypt_sensitive_data(&sealed)?;
+    let resealed = encryption.encrypt_sensitive_data(&value)?;
+    let bytes = serde_json::to_vec(&resealed)
+        .map_err(|e| AppError::Encryption(format!("Failed to encode record: {}", e)))?;
+    Ok((bytes, resealed.key_id))
+}
+
+fn unwrap_key(root: &EncryptionService, wrapped: &str) -> Result<Zeroizing<[u8; 32]>> {
+    let hex_key = Zeroizing::new(root.decrypt_field(wrapped)?);
+    decode_hex_key(&hex_key)
+}
@@ -0,0 +1,283 @@
+use crate::{config::LoginSecurityConfig, error::Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::{net::IpAddr, sync::Arc};
+use uuid::Uuid;
+
+const EARTH_RADIUS_KM: f64 = 6371.0;
+
+/// Located logins a new one is compared against, most recent first.
+const LOGIN_HISTORY_LIMIT: i64 = 100;
+
+/// City-level location of an IP address.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GeoLocation {
+    /// ISO 3166-1 alpha-2
+    pub country: Option<String>,
+    pub city: Option<String>,
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+impl GeoLocation {
+    /// Rounded to a tenth of a degree (~10 km), so stored login history
+    /// never pinpoints a user more closely than their city.
+    pub fn coarse(self) -> Self {
+        let round = |degrees: f64| (degrees * 10.0).round() / 10.0;
+        Self {
+            latitude: round(self.latitude),
+            longitude: round(self.longitude),
+            ..self
+        }
+    }
+
+    /// Great-circle distance.
+    pub fn distance_km(&self, other: &GeoLocation) -> f64 {
+        let (lat1, lat2) = (self.latitude.to_radians(), other.latitude.to_radians());
+        let dlat = lat2 - lat1;
+        let dlon = (other.longitude - self.longitude).to_radians();
+        let a = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+        2.0 * EARTH_RADIUS_KM * a.sqrt().asin()
+    }
+
+    fn same_place(&self, other: &GeoLocation) -> bool {
+        self.country == other.country && self.city == other.city
+    }
+}
+
+/// Resolves client IPs to locations. Lookups are in-memory, so they run
+/// inline on the request.
+pub trait GeoLocator: Send + Sync {
+    /// `None` for private, reserved or unknown addresses.
+    fn locate(&self, ip: IpAddr) -> Option<GeoLocation>;
+}
+
+/// Used when no GeoIP database is configured; logins are still recorded,
+/// just never flagged.
+pub struct NoGeoLocator;
+
+impl GeoLocator for NoGeoLocator {
+    fn locate(&self, _ip: IpAddr) -> Option<GeoLocation> {
+        None
+    }
+}
+
+/// Offline lookups against a MaxMind City database.
+#[cfg(feature = "geoip")]
+pub struct MaxMindGeoLocator {
+    reader: maxminddb::Reader<Vec<u8>>,
+}
+
+#[cfg(feature = "geoip")]
+impl MaxMindGeoLocator {
+    pub fn open(path: &str) -> Result<Self> {
+        let reader = maxminddb::Reader::open_readfile(path).map_err(|e| {
+            crate::error::AppError::Internal(format!("Failed to open GeoIP database: {}", e))
+        })?;
+        Ok(Self { reader })
+    }
+}
+
+#[cfg(feature = "geoip")]
+impl GeoLocator for MaxMindGeoLocator {
+    fn locate(&self, ip: IpAddr) -> Option<GeoLocation> {
+        let record: maxminddb::geoip2::City = self.reader.lookup(ip).ok()?;
+        let location = record.location?;
+        Some(GeoLocation {
+            country: record
+                .country
+                .and_then(|country| country.iso_code)
+                .map(str::to_string),
+            city: record
+                .city
+                .and_then(|city| city.names)
+                .and_then(|names| names.get("en").map(|name| name.to_string())),
+            latitude: location.latitude?,
+            longitude: location.longitude?,
+        })
+    }
+}
+
+pub fn geo_locator(config: &LoginSecurityConfig) -> Arc<dyn GeoLocator> {
+    let Some(path) = config.geoip_database_path.as_deref() else {
+        return Arc::new(NoGeoLocator);
+    };
+
+    #[cfg(feature = "geoip")]
+    {
+        match MaxMindGeoLocator::open(path) {
+            Ok(locator) => return Arc::new(locator),
+            Err(e) => tracing::warn!("Login geolocation disabled: {}", e),
+        }
+    }
+
+    #[cfg(not(feature = "geoip"))]
+    {
+        tracing::warn!(
+            "GEOIP_DATABASE_PATH is set to {} but this build lacks the geoip feature",
+            path
+        );
+    }
+
+    Arc::new(NoGeoLocator)
+}
+
+/// Whether getting from one login to the next would take faster travel
+/// than `config.max_travel_speed_kmh`.
+pub fn is_impossible_travel(
+    from: &GeoLocation,
+    from_time: DateTime<Utc>,
+    to: &GeoLocation,
+    to_time: DateTime<Utc>,
+    config: &LoginSecurityConfig,
+) -> bool {
+    let distance_km = from.distance_km(to);
+    if distance_km < config.min_travel_distance_km {
+        return false;
+    }
+
+    let hours = (to_time - from_time).num_milliseconds().max(0) as f64 / 3_600_000.0;
+    distance_km > config.max_travel_speed_kmh * hours
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LoginEvent {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub ip_address: Option<String>,
+    pub location: Option<GeoLocation>,
+    /// The user has logged in from elsewhere before, but never from here
+    pub new_location: bool,
+    pub impossible_travel: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Locates and stores a login, flagging it against the user's recent
+/// history. The IP itself is stored only when `store_ip` is set.
+pub async fn record_login_event(
+    db: &PgPool,
+    config: &LoginSecurityConfig,
+    locator: &dyn GeoLocator,
+    user_id: Uuid,
+    ip: Option<IpAddr>,
+    store_ip: bool,
+    now: DateTime<Utc>,
+) -> Result<LoginEvent> {
+    let location = ip
+        .and_then(|ip| locator.locate(ip))
+        .map(GeoLocation::coarse);
+
+    let mut new_location = false;
+    let mut impossible_travel = false;
+    if let Some(location) = &location {
+        let history = sqlx::query!(
+            r#"
+            SELECT country, city, latitude AS "latitude!", longitude AS "longitude!", created_at
+            FROM login_events
+            WHERE user_id = $1 AND latitude IS NOT NULL AND longitude IS NOT NULL
+            ORDER BY created_at DESC
+            LIMIT $2
+            "#,
+            user_id,
+            LOGIN_HISTORY_LIMIT
+        )
+        .fetch_all(db)
+        .await?;
+
+        let history: Vec<(GeoLocation, DateTime<Utc>)> = history
+            .into_iter()
+            .map(|row| {
+                let previous = GeoLocation {
+                    country: row.country,
+                    city: row.city,
+                    latitude: row.latitude,
+                    longitude: row.longitude,
+                };
+                (previous, row.created_at)
+            })
+            .collect();
+
+        new_location = !history.is_empty()
+            && !history.iter().any(|(previous, _)| previous.same_place(location));
+        impossible_travel = history.first().is_some_and(|(previous, at)| {
+            is_impossible_travel(previous, *at, location, now, config)
+        });
+    }
+
+    let ip_address = ip.filter(|_| store_ip).map(|ip| ip.to_string());
+    let id = sqlx::query_scalar!(
+        r#"
+        INSERT INTO login_events
+            (user_id, ip_address, country, city, latitude, longitude,
+             new_location, impossible_travel, created_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+        RETURNING id
+        "#,
+        user_id,
+        ip_address,
+        location.as_ref().and_then(|location| location.country.clone()),
+        location.as_ref().and_then(|location| location.city.clone()),
+        location.as_ref().map(|location| location.latitude),
+        location.as_ref().map(|location| location.longitude),
+        new_location,
+        impossible_travel,
+        now,
+    )
+    .fetch_one(db)
+    .await?;
+
+    Ok(LoginEvent {
+        id,
+        user_id,
+        ip_address,
+        location,
+        new_location,
+        impossible_travel,
+        created_at: now,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn place(city: &str, latitude: f64, longitude: f64) -> GeoLocation {
+        GeoLocation {
+            country: None,
+            city: Some(city.to_string()),
+            latitude,
+            longitude,
+        }
+    }
+
+    #[test]
+    fn test_distance_between_cities() {
+        let london = place("London", 51.5, -0.1);
+        let paris = place("Paris", 48.9, 2.4);
+        assert!((london.distance_km(&paris) - 340.0).abs() < 15.0);
+        assert_eq!(london.distance_km(&london), 0.0);
+    }
+
+    #[test]
+    fn test_impossible_travel_needs_distance_and_speed() {
+        let config = LoginSecurityConfig::default();
+        let london = place("London", 51.5, -0.1);
+        let paris = place("Paris", 48.9, 2.4);
+        let tokyo = place("Tokyo", 35.7, 139.7);
+        let now = Utc::now();
+
+        // Too close to call, however fast
+        assert!(!is_impossible_travel(&london, now, &paris, now, &config));
+        // ~9,600 km in an hour
+        assert!(is_impossible_travel(&london, now, &tokyo, now + chrono::Duration::hours(1), &config));
+        // A long-haul flight's worth of time
+        assert!(!is_impossible_travel(&london, now, &tokyo, now + chrono::Duration::hours(14), &config));
+    }
+
+    #[test]
+    fn test_coarse_location_rounds_coordinates() {
+        let location = place("London", 51.50735, -0.12776).coarse();
+        assert_eq!((location.latitude, location.longitude), (51.5, -0.1));
+    }
+}
@@ -0,0 +1,92 @@
+use sqlx::{
+    migrate::{MigrateError, Migrator},
+    PgPool,
+};
+use thiserror::Error;
+
+/// Why the schema couldn't be brought up to date, with what to do about it.
+/// Each message names the migration at fault, since sqlx's own errors leave
+/// most of that to the reader.
+#[derive(Debug, Error)]
+pub enum MigrationFailure {
+    #[error(
+        "migration {version} ({description}) was edited after it was applied, so its checksum \
+         no longer matches the database; restore the original file and put the change in a \
+         new migration"
+    )]
+    ChecksumMismatch { version: i64, description: String },
+
+    #[error(
+        "migration {version} ({description}) failed part-way on an earlier start and left the \
+         database dirty; finish or undo its changes by hand, delete its row from \
+         _sqlx_migrations, then restart"
+    )]
+    Dirty { version: i64, description: String },
+
+    #[error(
+        "the database has migration {version} applied, which this build doesn't include; \
+         deploy a build that has it, or restore the database from before it ran"
+    )]
+    UnknownVersion { version: i64 },
+
+    #[error(
+        "migration {version} ({description}) failed: {source}; fix its SQL and restart, nothing \
+         after it was applied"
+    )]
+    Failed {
+        version: i64,
+        description: String,
+        source: sqlx::Error,
+    },
+
+    #[error("couldn't reach the database to migrate it: {0}; check DATABASE_URL and that PostgreSQL is up")]
+    Connection(sqlx::Error),
+
+    #[error("migrations couldn't be run: {0}")]
+    Other(MigrateError),
+}
+
+impl MigrationFailure {
+    pub fn from_migrate_error(migrator: &Migrator, error: MigrateError) -> Self {
+        let description = |version: i64| {
+            migrator
+                .iter()
+                .find(|migration| migration.version == version)
+                .map_or_else(|| "unknown".to_string(), |m| m.description.to_string())
+        };
+
+        match error {
+            MigrateError::VersionMismatch(version) => MigrationFailure::ChecksumMismatch {
+                version,
+                description: description(version),
+            },
+            MigrateError::Dirty(version) => MigrationFailure::Dirty {
+                version,
+                description: description(version),
+            },
+            MigrateError::VersionMissing(version) => MigrationFailure::UnknownVersion { version },
+            MigrateError::ExecuteMigration(source, version) => MigrationFailure::Failed {
+                version,
+                description: description(version),
+                source,
+            },
+            MigrateError::Execute(
+                e @ (sqlx::Error::Io(_)
+                | sqlx::Error::Tls(_)
+                | sqlx::Error::PoolTimedOut
+                | sqlx::Error::PoolClosed),
+            ) => MigrationFailure::Connection(e),
+            other => MigrationFailure::Other(other),
+        }
+    }
+}
+
+/// Applies pending migrations. Failures are logged with guidance before
+/// being returned, so a server that won't start says why in its last line.
+pub async fn run_migrations(migrator: &Migrator, db: &PgPool) -> Result<(), MigrationFailure> {
+    migrator.run(db).await.map_err(|e| {
+        let failure = MigrationFailure::from_migrate_error(migrator, e);
+        tracing::error!("❌ Database migration failed: {}", failure);
+        failure
+    })
+}
@@ -1,15 +1,57 @@
 use crate::error::{AppError, Result};
 use candle_core::{DType, Device, Tensor};
 use candle_nn::{linear, Linear, Module, VarBuilder};
-use std::sync::Arc;
-use tracing::{debug, info};
-
+use dashmap::DashMap;
+use serde::Deserialize;
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tracing::{debug, info, warn};
+
+#[cfg(feature = "onnx")]
+use crate::services::onnx::OnnxFlowModel;
+
+/// Version reported for scores from `rule_based_prediction`.
+pub const RULE_BASED_MODEL_VERSION: &str = "rule-based-v1";
+/// Version reported for samples too small to be scored by any model.
+pub const UNSCORED_MODEL_VERSION: &str = "unscored";
+const BUILTIN_MODEL_VERSION: &str = "builtin-nn-v1";
+
+#[derive(Clone)]
 pub struct MLInferenceEngine {
     device: Device,
     model: Option<Arc<FlowPredictionModel>>,
+    #[cfg(feature = "onnx")]
+    onnx_model: Option<Arc<OnnxFlowModel>>,
+    linear_model: Option<LinearFlowModel>,
+    /// Name of the loaded model file; `None` for the built-in models
+    version: Option<String>,
     feature_scaler: FeatureScaler,
 }
 
+/// Weighted-sum model stored in the registry as `<version>.json`, e.g.
+/// `{"weights": [0.35, 0.25, 0.2, 0.1, 0.1], "bias": 0.0}`. Weights follow
+/// the feature order rhythm, focus, consistency, error, velocity.
+#[derive(Debug, Clone, Deserialize)]
+struct LinearFlowModel {
+    weights: [f32; 5],
+    #[serde(default)]
+    bias: f32,
+}
+
+impl LinearFlowModel {
+    fn predict(&self, features: [f32; 5]) -> f32 {
+        let score: f32 = self
+            .weights
+            .iter()
+            .zip(features)
+            .map(|(weight, feature)| weight * feature)
+            .sum();
+        (score + self.bias).clamp(0.0, 1.0)
+    }
+}
+
 struct FlowPredictionModel {
     layer1: Linear,
     layer2: Linear,
@@ -30,7 +72,7 @@ impl Module for FlowPredictionModel {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct FeatureScaler {
     means: Vec<f32>,
     stds: Vec<f32>,
@@ -60,10 +102,73 @@ impl MLInferenceEngine {
         Self {
             device,
             model: None,
+            #[cfg(feature = "onnx")]
+            onnx_model: None,
+            linear_model: None,
+            version: None,
             feature_scaler: FeatureScaler::new(),
         }
     }
 
+    /// Identifies the model `predict_flow_state` scores with, so persisted
+    /// scores can be compared only against others from the same model.
+    pub fn model_version(&self) -> &str {
+        if let Some(version) = &self.version {
+            return version;
+        }
+        if self.model.is_some() {
+            BUILTIN_MODEL_VERSION
+        } else {
+            RULE_BASED_MODEL_VERSION
+        }
+    }
+
+    fn from_linear_model_file(path: &Path, version: &str) -> Result<Self> {
+        let definition = std::fs::read_to_string(path).map_err(|e| {
+            AppError::MachineLearning(format!("Failed to read model {}: {}", version, e))
+        })?;
+        let linear_model: LinearFlowModel = serde_json::from_str(&definition).map_err(|e| {
+            AppError::MachineLearning(format!("Invalid model {}: {}", version, e))
+        })?;
+
+        let mut engine = Self::new();
+        engine.linear_model = Some(linear_model);
+        engine.version = Some(version.to_string());
+        Ok(engine)
+    }
+
+    /// Builds the engine from an optional exported ONNX model. A missing or
+    /// invalid model never fails startup: the engine falls back to the
+    /// built-in model path instead.
+    pub fn from_model_path(onnx_model_path: Option<&str>) -> Self {
+        let Some(model_path) = onnx_model_path else {
+            return Self::new();
+        };
+
+        #[cfg(feature = "onnx")]
+        {
+            match OnnxFlowModel::load(model_path) {
+                Ok(onnx_model) => {
+                    let mut engine = Self::new();
+                    engine.onnx_model = Some(Arc::new(onnx_model));
+                    engine.version = Path::new(model_path)
+                        .file_stem()
+                        .map(|stem| stem.to_string_lossy().into_owned());
+                    return engine;
+                }
+                Err(e) => warn!("Falling back to built-in flow model: {}", e),
+            }
+        }
+
+        #[cfg(not(feature = "onnx"))]
+        warn!(
+            "ONNX model {} configured but the `onnx` feature is disabled; using built-in flow model",
+            model_path
+        );
+
+        Self::new()
+    }
+
     pub async fn initialize_model(&mut self) -> Result<()> {
         info!("Initializing ML model for flow state prediction");
 
@@ -95,6 +200,24 @@ impl MLInferenceEngine {
     }
 
     pub async fn predict_flow_state(&self, features: [f32; 5]) -> Result<f32> {
+        if features.iter().any(|feature| !feature.is_finite()) {
+            return Err(AppError::MachineLearning(format!(
+                "Non-finite input features: {:?}",
+                features
+            )));
+        }
+
+        #[cfg(feature = "onnx")]
+        if let Some(onnx_model) = &self.onnx_model {
+            let prediction = onnx_model.predict(features)?;
+            debug!("ONNX prediction: {:.3}, features: {:?}", prediction, features);
+            return bounded_prediction(prediction);
+        }
+
+        if let Some(linear_model) = &self.linear_model {
+            return bounded_prediction(linear_model.predict(features));
+        }
+
         // Fallback to rule-based prediction if ML model not available
         if self.model.is_none() {
             return Ok(self.rule_based_prediction(features));
@@ -124,10 +247,12 @@ impl MLInferenceEngine {
             prediction, features
         );
 
-        Ok(prediction.max(0.0).min(1.0))
+        bounded_prediction(prediction)
     }
 
-    fn rule_based_prediction(&self, features: [f32; 5]) -> f32 {
+    /// Fixed-weight scoring used when no model is loaded, or when a user
+    /// opts out of ML. Pure: the same features always give the same score.
+    pub fn rule_based_prediction(&self, features: [f32; 5]) -> f32 {
         let [rhythm_score, focus_score, consistency_score, error_penalty, velocity_score] = features;
 
         // Weighted combination with research-backed weights
@@ -164,8 +289,8 @@ impl MLInferenceEngine {
             features, actual_flow_state, user_feedback
         );
 
-        // TODO: Implement online learning or batch update mechanism
-        // This could store training examples for periodic model retraining
+        // TODO: Implement online learning or batch update mechanism, training
+        // on `FeedbackStore::training_batch`
 
         Ok(())
     }
@@ -192,8 +317,26 @@ impl MLInferenceEngine {
     }
 
     pub fn is_model_loaded(&self) -> bool {
+        #[cfg(feature = "onnx")]
+        if self.onnx_model.is_some() {
+            return true;
+        }
+
         self.model.is_some()
     }
+
+    /// Runs one throwaway prediction, so the first real analysis doesn't
+    /// pay for the inference backend's lazy setup.
+    pub async fn warm_up(&self) -> Result<()> {
+        let started = std::time::Instant::now();
+        self.predict_flow_state([0.5; 5]).await?;
+        info!(
+            "Flow model {} warmed up in {:?}",
+            self.model_version(),
+            started.elapsed()
+        );
+        Ok(())
+    }
 }
 
 impl Default for MLInferenceEngine {
@@ -202,58 +345,237 @@ impl Default for MLInferenceEngine {
     }
 }
 
+/// Directory of past and candidate models, by version: `<version>.onnx`
+/// (with the `onnx` feature) or `<version>.json` linear models. Admins pin
+/// a version to backtest analyses against it.
+pub struct ModelRegistry {
+    dir: Option<PathBuf>,
+    loaded: DashMap<String, MLInferenceEngine>,
+}
+
+impl ModelRegistry {
+    pub fn new(dir: Option<&str>) -> Self {
+        Self {
+            dir: dir.map(PathBuf::from),
+            loaded: DashMap::new(),
+        }
+    }
+
+    /// Loads a version once and keeps it for later pins.
+    pub fn load(&self, version: &str) -> Result<MLInferenceEngine> {
+        if let Some(engine) = self.loaded.get(version) {
+            return Ok(engine.clone());
+        }
+
+        let dir = self.dir.as_ref().ok_or_else(|| {
+            AppError::ServiceUnavailable("No model registry is configured".to_string())
+        })?;
+
+        // Versions become file names, so nothing that could leave the directory
+        let valid_name = !version.is_empty()
+            && !version.starts_with('.')
+            && version
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'));
+        if !valid_name {
+            return Err(AppError::Validation(format!("Invalid model version: {}", version)));
+        }
+
+        #[cfg(feature = "onnx")]
+        {
+            let onnx_path = dir.join(format!("{}.onnx", version));
+            if onnx_path.exists() {
+                let mut engine = MLInferenceEngine::new();
+                engine.onnx_model = Some(Arc::new(OnnxFlowModel::load(&onnx_path)?));
+                engine.version = Some(version.to_string());
+                self.loaded.insert(version.to_string(), engine.clone());
+                return Ok(engine);
+            }
+        }
+
+        let linear_path = dir.join(format!("{}.json", version));
+        if !linear_path.exists() {
+            return Err(AppError::NotFound(format!("Model version {} not found", version)));
+        }
+
+        let engine = MLInferenceEngine::from_linear_model_file(&linear_path, version)?;
+        info!("Loaded model {} from registry", version);
+        self.loaded.insert(version.to_string(), engine.clone());
+        Ok(engine)
+    }
+}
+
+/// Clamps a model output to [0, 1]. NaN and infinities mean the model is
+/// broken for this input, not a very high or low score.
+fn bounded_prediction(prediction: f32) -> Result<f32> {
+    if !prediction.is_finite() {
+        return Err(AppError::MachineLearning(format!(
+            "Model produced a non-finite score: {}",
+            prediction
+        )));
+    }
+    Ok(prediction.clamp(0.0, 1.0))
+}
+
 // Additional ML utilities for advanced features
 pub struct ProductivityPredictor {
     ml_engine: MLInferenceEngine,
     historical_patterns: Vec<ProductivityPattern>,
+    decay: TimeDecay,
 }
 
+/// Average flow score of a user's sessions in one hour-of-week slot.
+/// `day_of_week` counts from Sunday = 0, matching Postgres `DOW`.
 #[derive(Debug, Clone)]
-struct ProductivityPattern {
-    hour_of_day: u8,
-    day_of_week: u8,
-    average_flow_score: f32,
-    session_count: u32,
+pub struct ProductivityPattern {
+    pub hour_of_day: u8,
+    pub day_of_week: u8,
+    pub average_flow_score: f32,
+    pub session_count: u32,
+    /// Average age of the sessions behind the pattern, in days
+    pub age_days: f32,
+}
+
+/// Exponential down-weighting of history: a session `half_life_days` old
+/// counts half as much as one from today. A zero half-life weights all
+/// history equally.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TimeDecay {
+    pub half_life_days: f64,
 }
 
+impl TimeDecay {
+    pub fn new(half_life_days: f64) -> Self {
+        Self { half_life_days }
+    }
+
+    pub fn weight(&self, age_days: f64) -> f64 {
+        if self.half_life_days <= 0.0 {
+            return 1.0;
+        }
+        0.5_f64.powf(age_days.max(0.0) / self.half_life_days)
+    }
+}
+
+/// Expected flow productivity for one upcoming hour.
+#[derive(Debug, Clone)]
+pub struct HourlyForecast {
+    pub starts_at: chrono::DateTime<chrono::Utc>,
+    pub expected_flow_score: f32,
+    /// Sessions behind the estimate; 0 means the neutral default was used
+    pub session_count: u32,
+}
+
+// One pattern per hour of the week, for each week of a year of history
+const MAX_PATTERNS: usize = 24 * 7 * 53;
+const NEUTRAL_FLOW_SCORE: f32 = 0.5;
+
 impl ProductivityPredictor {
     pub fn new() -> Self {
         Self {
             ml_engine: MLInferenceEngine::new(),
             historical_patterns: Vec::new(),
+            decay: TimeDecay::default(),
         }
     }
 
+    pub fn with_time_decay(mut self, decay: TimeDecay) -> Self {
+        self.decay = decay;
+        self
+    }
+
     pub async fn predict_optimal_session_time(&self, current_hour: u8, day_of_week: u8) -> f32 {
-        // Find similar historical patterns
-        let similar_patterns: Vec<&ProductivityPattern> = self
+        self.predict_hour(current_hour, day_of_week).0
+    }
+
+    /// Forecasts each of the next `hours` hours starting at the hour
+    /// containing `from`.
+    pub async fn forecast(
+        &self,
+        from: chrono::DateTime<chrono::Utc>,
+        hours: u32,
+    ) -> Vec<HourlyForecast> {
+        use chrono::{Datelike, DurationRound, Timelike};
+
+        let first_hour = from
+            .duration_trunc(chrono::Duration::hours(1))
+            .unwrap_or(from);
+
+        (0..hours)
+            .map(|offset| {
+                let starts_at = first_hour + chrono::Duration::hours(offset as i64);
+                let (expected_flow_score, session_count) = self.predict_hour(
+                    starts_at.hour() as u8,
+                    starts_at.weekday().num_days_from_sunday() as u8,
+                );
+                HourlyForecast {
+                    starts_at,
+                    expected_flow_score,
+                    session_count,
+                }
+            })
+            .collect()
+    }
+
+    /// Session-weighted score of nearby hours on the same weekday, with
+    /// older patterns decayed. Sparse history falls back to the same hours
+    /// on any day, then to neutral. The returned count is undecayed.
+    fn predict_hour(&self, hour: u8, day_of_week: u8) -> (f32, u32) {
+        let near_hour = |p: &&ProductivityPattern| {
+            let distance = (p.hour_of_day as i16 - hour as i16).abs();
+            distance.min(24 - distance) <= 2
+        };
+
+        let same_day: Vec<&ProductivityPattern> = self
             .historical_patterns
             .iter()
-            .filter(|p| {
-                (p.hour_of_day as i16 - current_hour as i16).abs() <= 2
-                    && p.day_of_week == day_of_week
-            })
+            .filter(near_hour)
+            .filter(|p| p.day_of_week == day_of_week)
             .collect();
 
-        if similar_patterns.is_empty() {
-            return 0.5; // Default neutral prediction
+        let similar_patterns = if same_day.is_empty() {
+            self.historical_patterns.iter().filter(near_hour).collect()
+        } else {
+            same_day
+        };
+
+        let session_count: u32 = similar_patterns.iter().map(|p| p.session_count.max(1)).sum();
+        if session_count == 0 {
+            return (NEUTRAL_FLOW_SCORE, 0);
         }
 
-        let avg_flow_score = similar_patterns
+        let weight = |p: &ProductivityPattern| {
+            p.session_count.max(1) as f32 * self.decay.weight(p.age_days as f64) as f32
+        };
+        let total_weight: f32 = similar_patterns.iter().map(|p| weight(p)).sum();
+        let weighted_score: f32 = similar_patterns
             .iter()
-            .map(|p| p.average_flow_score)
-            .sum::<f32>()
-            / similar_patterns.len() as f32;
+            .map(|p| p.average_flow_score * weight(p))
+            .sum();
+
+        // Everything decayed to nothing: fall back to uniform weighting
+        if total_weight <= f32::EPSILON {
+            let uniform: f32 = similar_patterns
+                .iter()
+                .map(|p| p.average_flow_score * p.session_count.max(1) as f32)
+                .sum();
+            return (uniform / session_count as f32, session_count);
+        }
 
-        avg_flow_score
+        (weighted_score / total_weight, session_count)
     }
 
     pub fn add_pattern(&mut self, pattern: ProductivityPattern) {
         self.historical_patterns.push(pattern);
-        
-        // Keep only recent patterns (last 100 entries)
-        if self.historical_patterns.len() > 100 {
+
+        if self.historical_patterns.len() > MAX_PATTERNS {
             self.historical_patterns.remove(0);
         }
     }
-}
\ No newline at end of file
+}
+
+impl Default for ProductivityPredictor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,174 @@
+use crate::{
+    error::{AppError, Result},
+    services::ml::MLInferenceEngine,
+};
+use std::{sync::OnceLock, time::Duration};
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::{self, Instant},
+};
+
+type PendingPrediction = ([f32; 5], oneshot::Sender<Result<f32>>);
+
+/// Full batches' worth of predictions that may queue for the batcher;
+/// callers past that wait for room instead of piling up in memory.
+const QUEUED_BATCHES: usize = 4;
+
+/// Coalesces concurrent flow predictions into batches for the inference
+/// engine. A batch is scored as soon as it holds `max_size` requests, or
+/// `max_wait` after its first request arrived, whichever comes first, so a
+/// lone request waits at most `max_wait`. The engine is only read, inside
+/// the batch; callers hold nothing of it while they wait.
+pub struct InferenceBatcher {
+    engine: MLInferenceEngine,
+    max_wait: Duration,
+    max_size: usize,
+    /// Started on first use, so the batcher can be built outside a runtime
+    queue: OnceLock<mpsc::Sender<PendingPrediction>>,
+}
+
+impl InferenceBatcher {
+    /// A `max_size` of 1 disables batching: every prediction runs directly.
+    pub fn new(engine: MLInferenceEngine, max_wait: Duration, max_size: usize) -> Self {
+        Self {
+            engine,
+            max_wait,
+            max_size: max_size.max(1),
+            queue: OnceLock::new(),
+        }
+    }
+
+    pub fn max_wait(&self) -> Duration {
+        self.max_wait
+    }
+
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    pub async fn predict(&self, features: [f32; 5]) -> Result<f32> {
+        if self.max_size == 1 {
+            return self.engine.predict_flow_state(features).await;
+        }
+
+        let (reply, scored) = oneshot::channel();
+        if self.queue().send((features, reply)).await.is_err() {
+            return self.engine.predict_flow_state(features).await;
+        }
+        scored
+            .await
+            .map_err(|_| AppError::MachineLearning("Inference batcher stopped".to_string()))?
+    }
+
+    fn queue(&self) -> &mpsc::Sender<PendingPrediction> {
+        self.queue.get_or_init(|| {
+            let (queue, pending) = mpsc::channel(self.max_size * QUEUED_BATCHES);
+            tokio::spawn(run_batches(
+                self.engine.clone(),
+                pending,
+                self.max_wait,
+                self.max_size,
+            ));
+            queue
+        })
+    }
+}
+
+/// Collects and scores batches until the batcher is dropped.
+async fn run_batches(
+    engine: MLInferenceEngine,
+    mut pending: mpsc::Receiver<PendingPrediction>,
+    max_wait: Duration,
+    max_size: usize,
+) {
+    while let Some(first) = pending.recv().await {
+        let deadline = Instant::now() + max_wait;
+        let mut batch = vec![first];
+        while batch.len() < max_size {
+            // Requests already queued are taken even once the deadline passed
+            match time::timeout_at(deadline, pending.recv()).await {
+                Ok(Some(prediction)) => batch.push(prediction),
+                Ok(None) | Err(_) => break,
+            }
+        }
+        score_batch(&engine, batch).await;
+    }
+}
+
+async fn score_batch(engine: &MLInferenceEngine, batch: Vec<PendingPrediction>) {
+    let features = batch.iter().map(|(features, _)| *features).collect();
+    match engine.batch_predict(features).await {
+        Ok(scores) => {
+            for ((_, reply), score) in batch.into_iter().zip(scores) {
+                let _ = reply.send(Ok(score));
+            }
+        }
+        // One bad input fails the whole batch; rescoring one by one keeps
+        // the error to its own request
+        Err(_) => {
+            for (features, reply) in batch {
+                let _ = reply.send(engine.predict_flow_state(features).await);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_lone_request_waits_at_most_max_wait() {
+        let max_wait = Duration::from_millis(20);
+        let batcher = InferenceBatcher::new(MLInferenceEngine::new(), max_wait, 64);
+
+        let started = std::time::Instant::now();
+        let score = batcher.predict([0.8, 0.8, 0.8, 0.1, 0.5]).await.unwrap();
+        let elapsed = started.elapsed();
+
+        assert_eq!(
+            score,
+            MLInferenceEngine::new().rule_based_prediction([0.8, 0.8, 0.8, 0.1, 0.5])
+        );
+        // Allowing for scheduling on a busy machine
+        assert!(
+            elapsed < max_wait + Duration::from_millis(50),
+            "{:?}",
+            elapsed
+        );
+    }
+
+    #[tokio::test]
+    async fn test_full_batch_flushes_without_waiting() {
+        // Long enough that waiting for it would time the test out
+        let batcher = Arc::new(InferenceBatcher::new(
+            MLInferenceEngine::new(),
+            Duration::from_secs(30),
+            4,
+        ));
+
+        let predictions: Vec<_> = (0..4)
+            .map(|i| {
+                let batcher = batcher.clone();
+                tokio::spawn(async move { batcher.predict([0.2 * i as f32; 5]).await })
+            })
+            .collect();
+        let scores = time::timeout(Duration::from_secs(1), async {
+            let mut scores = Vec::new();
+            for prediction in predictions {
+                scores.push(prediction.await.unwrap().unwrap());
+            }
+            scores
+        })
+        .await
+        .expect("a full batch should not wait for max_wait");
+        assert_eq!(scores.len(), 4);
+
+        // A bad input fails alone
+        let batcher = InferenceBatcher::new(MLInferenceEngine::new(), Duration::ZERO, 4);
+        let (bad, good) = tokio::join!(batcher.predict([f32::NAN; 5]), batcher.predict([0.5; 5]));
+        assert!(matches!(bad, Err(AppError::MachineLearning(_))));
+        assert!(good.is_ok());
+    }
+}
@@ -1,13 +1,73 @@
+pub mod achievements;
+pub mod audit;
 pub mod auth;
+pub mod comparison;
+pub mod compression;
 pub mod encryption;
+pub mod engine_pool;
+pub mod export;
+pub mod export_bundle;
+pub mod feature_flags;
+pub mod feedback;
 pub mod flow;
+pub mod flow_diff;
+pub mod flow_profile;
+pub mod flow_stats;
+pub mod focus;
+pub mod insights;
+pub mod key_rotation;
+pub mod login_security;
+pub mod migrations;
 pub mod ml;
+pub mod ml_batch;
+#[cfg(feature = "onnx")]
+pub mod onnx;
 pub mod privacy;
+pub mod readiness;
+pub mod retention;
+pub mod sanitizer;
+pub mod self_test;
+pub mod slow_queries;
+pub mod team_goals;
+pub mod team_presence;
+pub mod telemetry;
+pub mod usage_metrics;
+pub mod wal;
 pub mod wasm;
+pub mod write_queue;
 
+pub use achievements::*;
+pub use audit::*;
 pub use auth::*;
+pub use comparison::*;
+pub use compression::*;
 pub use encryption::*;
+pub use engine_pool::*;
+pub use export::*;
+pub use export_bundle::*;
+pub use feature_flags::*;
+pub use feedback::*;
 pub use flow::*;
+pub use flow_diff::*;
+pub use flow_profile::*;
+pub use flow_stats::*;
+pub use focus::*;
+pub use insights::*;
+pub use key_rotation::*;
+pub use login_security::*;
+pub use migrations::*;
 pub use ml::*;
+pub use ml_batch::*;
 pub use privacy::*;
-pub use wasm::*;
\ No newline at end of file
+pub use readiness::*;
+pub use retention::*;
+pub use sanitizer::*;
+pub use self_test::*;
+pub use slow_queries::*;
+pub use team_goals::*;
+pub use team_presence::*;
+pub use telemetry::*;
+pub use usage_metrics::*;
+pub use wal::*;
+pub use wasm::*;
+pub use write_queue::*;
\ No newline at end of file
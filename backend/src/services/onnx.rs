@@ -0,0 +1,107 @@
+use crate::error::{AppError, Result};
+use std::path::Path;
+use tract_onnx::prelude::*;
+use tracing::info;
+
+/// Name of the `[1, 5]` f32 input tensor an exported flow model must declare.
+pub const ONNX_INPUT_NAME: &str = "features";
+/// Name of the `[1, 1]` f32 output tensor holding the flow probability.
+pub const ONNX_OUTPUT_NAME: &str = "flow_score";
+
+type OnnxPlan = SimplePlan<TypedFact, Box<dyn TypedOp>, Graph<TypedFact, Box<dyn TypedOp>>>;
+
+/// Flow prediction model exported from PyTorch (or any ONNX producer).
+///
+/// The model receives the raw feature vector in the order
+/// rhythm, focus, consistency, error, velocity and is responsible for its
+/// own scaling.
+pub struct OnnxFlowModel {
+    plan: OnnxPlan,
+}
+
+impl OnnxFlowModel {
+    pub fn load<P: AsRef<Path>>(model_path: P) -> Result<Self> {
+        let model_path = model_path.as_ref();
+
+        let model = tract_onnx::onnx()
+            .model_for_path(model_path)
+            .map_err(|e| AppError::MachineLearning(format!("Failed to read ONNX model: {}", e)))?
+            .with_input_fact(0, InferenceFact::dt_shape(f32::datum_type(), tvec!(1, 5)))
+            .map_err(|e| AppError::MachineLearning(format!("Invalid ONNX input: {}", e)))?
+            .into_typed()
+            .map_err(|e| AppError::MachineLearning(format!("Failed to type ONNX model: {}", e)))?;
+
+        Self::validate_contract(&model)?;
+
+        let plan = model
+            .into_optimized()
+            .and_then(|model| model.into_runnable())
+            .map_err(|e| AppError::MachineLearning(format!("Failed to prepare ONNX model: {}", e)))?;
+
+        info!("✅ ONNX flow model loaded from {:?}", model_path);
+
+        Ok(Self { plan })
+    }
+
+    fn validate_contract(model: &TypedModel) -> Result<()> {
+        if model.inputs.len() != 1 || model.outputs.len() != 1 {
+            return Err(AppError::MachineLearning(format!(
+                "ONNX model must have exactly one input and one output, found {} and {}",
+                model.inputs.len(),
+                model.outputs.len()
+            )));
+        }
+
+        let input_name = &model.node(model.inputs[0].node).name;
+        if input_name != ONNX_INPUT_NAME {
+            return Err(AppError::MachineLearning(format!(
+                "ONNX input must be named '{}', found '{}'",
+                ONNX_INPUT_NAME, input_name
+            )));
+        }
+
+        let output_name = model
+            .outlet_label(model.outputs[0])
+            .unwrap_or(&model.node(model.outputs[0].node).name);
+        if output_name != ONNX_OUTPUT_NAME {
+            return Err(AppError::MachineLearning(format!(
+                "ONNX output must be named '{}', found '{}'",
+                ONNX_OUTPUT_NAME, output_name
+            )));
+        }
+
+        let output_fact = model
+            .output_fact(0)
+            .map_err(|e| AppError::MachineLearning(format!("Invalid ONNX output: {}", e)))?;
+        let output_volume = output_fact
+            .shape
+            .as_concrete()
+            .map(|dims| dims.iter().product::<usize>());
+        if output_fact.datum_type != f32::datum_type() || output_volume != Some(1) {
+            return Err(AppError::MachineLearning(format!(
+                "ONNX output must be a single f32 score, found {:?}",
+                output_fact
+            )));
+        }
+
+        Ok(())
+    }
+
+    pub fn predict(&self, features: [f32; 5]) -> Result<f32> {
+        let input = Tensor::from_shape(&[1, 5], &features)
+            .map_err(|e| AppError::MachineLearning(format!("Failed to create input tensor: {}", e)))?;
+
+        let outputs = self
+            .plan
+            .run(tvec!(input.into()))
+            .map_err(|e| AppError::MachineLearning(format!("ONNX inference failed: {}", e)))?;
+
+        let prediction = outputs[0]
+            .as_slice::<f32>()
+            .ok()
+            .and_then(|values| values.first().copied())
+            .ok_or_else(|| AppError::MachineLearning("ONNX model returned no score".to_string()))?;
+
+        Ok(prediction)
+    }
+}
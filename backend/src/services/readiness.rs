@@ -0,0 +1,62 @@
+use crate::{models::flow::ServerMode, state::AppState};
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+/// The server's start-up phase. Moves from initializing to ready once and
+/// never back.
+#[derive(Debug)]
+pub struct ServerReadiness {
+    ready: AtomicBool,
+    /// Flips to true once when the server starts shutting down
+    shutting_down: watch::Sender<bool>,
+}
+
+impl Default for ServerReadiness {
+    fn default() -> Self {
+        Self {
+            ready: AtomicBool::new(false),
+            shutting_down: watch::channel(false).0,
+        }
+    }
+}
+
+impl ServerReadiness {
+    pub fn mode(&self) -> ServerMode {
+        if self.ready.load(Ordering::Acquire) {
+            ServerMode::Ready
+        } else {
+            ServerMode::Initializing
+        }
+    }
+
+    pub fn mark_ready(&self) {
+        if !self.ready.swap(true, Ordering::AcqRel) {
+            info!("✅ Server ready");
+        }
+    }
+
+    /// Tells long-lived connections to close, since graceful shutdown
+    /// waits for them.
+    pub fn begin_shutdown(&self) {
+        self.shutting_down.send_replace(true);
+    }
+
+    /// Resolves once `begin_shutdown` has been called, immediately if it
+    /// already has.
+    pub async fn shutdown_started(&self) {
+        let mut shutting_down = self.shutting_down.subscribe();
+        // The sender lives as long as `self`, so this can't fail
+        let _ = shutting_down.wait_for(|down| *down).await;
+    }
+}
+
+/// Warms up the shared flow model, then marks the server ready. A failed
+/// warm-up still ends initialization: analyses the model can't score fall
+/// back to rule-based and are flagged `degraded` individually.
+pub async fn warm_up_flow_model(state: AppState) {
+    if let Err(e) = state.ml_engine.warm_up().await {
+        warn!("Flow model warm-up failed, scoring may fall back: {}", e);
+    }
+    state.readiness.mark_ready();
+}
@@ -0,0 +1,183 @@
+use crate::{
+    config::{RetentionConfig, RetentionSweepMode},
+    error::Result,
+    state::AppState,
+    utils::auth::SubscriptionTier,
+};
+use sqlx::{PgConnection, PgPool};
+use std::time::Duration;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Most sessions one sweep deletes; the rest wait for the next sweep.
+const SWEEP_BATCH: i64 = 1_000;
+
+/// Days of data kept for a user: their own `data_retention_days` if they
+/// set one, otherwise the default for their tier.
+pub fn effective_retention_days(
+    privacy_settings: Option<&serde_json::Value>,
+    tier: SubscriptionTier,
+    config: &RetentionConfig,
+) -> i32 {
+    privacy_settings
+        .and_then(|settings| settings.get("data_retention_days"))
+        .and_then(serde_json::Value::as_i64)
+        .filter(|days| *days > 0)
+        .and_then(|days| i32::try_from(days).ok())
+        .unwrap_or_else(|| config.default_days(tier))
+}
+
+pub async fn load_retention_days(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    config: &RetentionConfig,
+) -> Result<Option<i32>> {
+    let user = sqlx::query!(
+        "SELECT subscription_tier, privacy_settings FROM users WHERE id = $1",
+        user_id
+    )
+    .fetch_optional(&mut *conn)
+    .await?;
+
+    Ok(user.map(|user| {
+        let tier = user.subscription_tier.unwrap_or_else(|| "free".to_string());
+        effective_retention_days(user.privacy_settings.as_ref(), tier.into(), config)
+    }))
+}
+
+/// Writes the tier default into the row of every user without a retention
+/// period yet, such as accounts registered since the last sweep, so a later
+/// change to the tier defaults doesn't shorten what they signed up with.
+/// Returns the number of users updated.
+pub async fn assign_default_retention(db: &PgPool, config: &RetentionConfig) -> Result<u64> {
+    let assigned = sqlx::query!(
+        r#"
+        UPDATE users
+        SET privacy_settings = COALESCE(privacy_settings, '{}'::JSONB)
+            || jsonb_build_object('data_retention_days', CASE subscription_tier
+                WHEN 'premium' THEN $2
+                WHEN 'team' THEN $3
+                WHEN 'enterprise' THEN $4
+                ELSE $1
+            END)
+        WHERE NOT COALESCE(privacy_settings, '{}'::JSONB) ? 'data_retention_days'
+        "#,
+        config.free_days,
+        config.premium_days,
+        config.team_days,
+        config.enterprise_days,
+    )
+    .execute(db)
+    .await?
+    .rows_affected();
+
+    Ok(assigned)
+}
+
+/// Up to `limit` sessions older than their owner's retention period, oldest
+/// first. The period is worked out as in `effective_retention_days`, but in
+/// the query, so only users with expired sessions are looked at.
+async fn expired_session_ids(
+    db: &PgPool,
+    config: &RetentionConfig,
+    limit: i64,
+) -> Result<Vec<Uuid>> {
+    let ids = sqlx::query_scalar!(
+        r#"
+        SELECT cs.id
+        FROM coding_sessions cs
+        JOIN users u ON u.id = cs.user_id
+        WHERE cs.start_time < NOW() - make_interval(days => COALESCE(
+            CASE
+                WHEN jsonb_typeof(u.privacy_settings->'data_retention_days') = 'number'
+                 AND u.privacy_settings->>'data_retention_days' ~ '^[1-9][0-9]{0,8}$'
+                THEN (u.privacy_settings->>'data_retention_days')::INT
+            END,
+            CASE u.subscription_tier
+                WHEN 'premium' THEN $2
+                WHEN 'team' THEN $3
+                WHEN 'enterprise' THEN $4
+                ELSE $1
+            END
+        ))
+        ORDER BY cs.start_time
+        LIMIT $5
+        "#,
+        config.free_days,
+        config.premium_days,
+        config.team_days,
+        config.enterprise_days,
+        limit,
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(ids)
+}
+
+/// Deletes up to `limit` sessions older than their owner's retention
+/// period; flow states and interruptions go with them. With `dry_run`
+/// nothing is deleted. Returns the number of sessions deleted, or that
+/// would have been.
+pub async fn sweep_expired_sessions(
+    db: &PgPool,
+    config: &RetentionConfig,
+    limit: i64,
+    dry_run: bool,
+) -> Result<u64> {
+    let expired = expired_session_ids(db, config, limit).await?;
+    if dry_run || expired.is_empty() {
+        return Ok(expired.len() as u64);
+    }
+
+    let deleted = sqlx::query!("DELETE FROM coding_sessions WHERE id = ANY($1)", &expired)
+        .execute(db)
+        .await?
+        .rows_affected();
+
+    Ok(deleted)
+}
+
+/// Runs hourly for the life of the server, pruning old model feedback and,
+/// when `RETENTION_SWEEP` opts in, deleting data past each user's retention
+/// period.
+pub async fn run_retention_sweeper(state: AppState) {
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+    let config = &state.config.retention;
+
+    loop {
+        interval.tick().await;
+
+        match config.sweep {
+            RetentionSweepMode::Off => {}
+            RetentionSweepMode::DryRun => {
+                match sweep_expired_sessions(&state.db, config, SWEEP_BATCH, true).await {
+                    Ok(0) => {}
+                    Ok(expired) => info!(
+                        "Retention sweep (dry run) would delete {} expired sessions",
+                        expired
+                    ),
+                    Err(e) => warn!("Retention sweep failed: {}", e),
+                }
+            }
+            RetentionSweepMode::Delete => {
+                if let Err(e) = assign_default_retention(&state.db, config).await {
+                    warn!("Assigning default retention failed: {}", e);
+                }
+                match sweep_expired_sessions(&state.db, config, SWEEP_BATCH, false).await {
+                    Ok(0) => {}
+                    Ok(deleted) => info!("Retention sweep deleted {} expired sessions", deleted),
+                    Err(e) => warn!("Retention sweep failed: {}", e),
+                }
+            }
+        }
+
+        match state.feedback_store.prune(chrono::Utc::now()).await {
+            Ok(0) => {}
+            Ok(pruned) => info!("Retention sweep pruned {} feedback examples", pruned),
+            Err(e) => warn!("Feedback pruning failed: {}", e),
+        }
+    }
+}
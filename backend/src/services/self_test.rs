@@ -0,0 +1,115 @@
+use crate::{error::AppError, state::AppState};
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+/// One subsystem's self-test outcome.
+#[derive(Debug, Clone)]
+pub struct SubsystemCheck {
+    pub name: &'static str,
+    /// A failure here stops a production server from starting
+    pub required: bool,
+    pub duration: Duration,
+    pub error: Option<String>,
+}
+
+impl SubsystemCheck {
+    pub fn passed(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// What the startup self-test found, in the order the checks ran.
+#[derive(Debug, Clone)]
+pub struct SelfTestReport {
+    pub checks: Vec<SubsystemCheck>,
+}
+
+impl SelfTestReport {
+    pub fn check(&self, name: &str) -> Option<&SubsystemCheck> {
+        self.checks.iter().find(|check| check.name == name)
+    }
+
+    /// Required subsystems that failed.
+    pub fn required_failures(&self) -> impl Iterator<Item = &SubsystemCheck> {
+        self.checks
+            .iter()
+            .filter(|check| check.required && !check.passed())
+    }
+
+    pub fn passed(&self) -> bool {
+        self.required_failures().next().is_none()
+    }
+
+    pub fn log(&self) {
+        for check in &self.checks {
+            match &check.error {
+                None => info!("✅ Self-test {} passed in {:?}", check.name, check.duration),
+                Some(error) if check.required => {
+                    warn!("❌ Self-test {} failed: {}", check.name, error)
+                }
+                Some(error) => warn!("Self-test {} failed (optional): {}", check.name, error),
+            }
+        }
+    }
+}
+
+/// Exercises each subsystem the server depends on: a database ping, an
+/// encryption round trip, an ML prediction and the WASM engine. Plugins
+/// are optional, so a broken WASM engine is reported but not required.
+pub async fn run_self_test(state: &AppState) -> SelfTestReport {
+    let checks = vec![
+        run_check("database", true, async {
+            sqlx::query("SELECT 1").execute(&state.db).await?;
+            Ok(())
+        })
+        .await,
+        run_check("encryption", true, async {
+            let encryption = state.encryption.as_ref().ok_or_else(|| {
+                AppError::Encryption(
+                    "ENCRYPTION_KEY is not a valid 64-character hex key".to_string(),
+                )
+            })?;
+            let encryption = encryption.read();
+            let sealed = encryption.encrypt_field("self-test")?;
+            if encryption.decrypt_field(&sealed)? != "self-test" {
+                return Err(AppError::Encryption(
+                    "Round trip changed the plaintext".to_string(),
+                ));
+            }
+            Ok(())
+        })
+        .await,
+        run_check("ml", true, async {
+            let score = state.ml_engine.predict_flow_state([0.5; 5]).await?;
+            if !(0.0..=1.0).contains(&score) {
+                return Err(AppError::MachineLearning(format!(
+                    "Prediction out of range: {}",
+                    score
+                )));
+            }
+            Ok(())
+        })
+        .await,
+        run_check("wasm", false, async { state.plugins()?.check_engine() }).await,
+    ];
+
+    SelfTestReport { checks }
+}
+
+async fn run_check(
+    name: &'static str,
+    required: bool,
+    check: impl Future<Output = crate::error::Result<()>>,
+) -> SubsystemCheck {
+    let started = Instant::now();
+    let error = check.await.err().map(|e| e.to_string());
+    SubsystemCheck {
+        name,
+        required,
+        duration: started.elapsed(),
+        error,
+    }
+}
@@ -0,0 +1,108 @@
+use dashmap::DashMap;
+use std::{
+    fmt::Write,
+    future::Future,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+/// Times labelled database calls and reports those slower than a threshold:
+/// a warning with the label and duration, and a per-label counter for
+/// `/metrics`. Labels are literals at the call sites, so the metric's
+/// series stay bounded.
+#[derive(Debug)]
+pub struct SlowQueryLog {
+    /// `None` turns reporting off
+    threshold: Option<Duration>,
+    slow: DashMap<&'static str, AtomicU64>,
+}
+
+impl SlowQueryLog {
+    /// A `threshold_ms` of 0 disables reporting.
+    pub fn new(threshold_ms: u64) -> Self {
+        Self {
+            threshold: (threshold_ms > 0).then(|| Duration::from_millis(threshold_ms)),
+            slow: DashMap::new(),
+        }
+    }
+
+    /// Runs `query`, reporting it as `label` if it is slow. Errors are
+    /// timed like successes, since a query can be slow to fail.
+    pub async fn time<F: Future>(&self, label: &'static str, query: F) -> F::Output {
+        let started = Instant::now();
+        let output = query.await;
+        self.record(label, started.elapsed());
+        output
+    }
+
+    /// Reports `elapsed` if it reaches the threshold. Returns whether it did.
+    pub fn record(&self, label: &'static str, elapsed: Duration) -> bool {
+        let Some(threshold) = self.threshold.filter(|threshold| elapsed >= *threshold) else {
+            return false;
+        };
+
+        warn!(
+            query = label,
+            duration_ms = elapsed.as_millis() as u64,
+            threshold_ms = threshold.as_millis() as u64,
+            "Slow database query"
+        );
+        self.slow
+            .entry(label)
+            .or_default()
+            .fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    pub fn slow_queries(&self, label: &str) -> u64 {
+        self.slow
+            .get(label)
+            .map_or(0, |count| count.load(Ordering::Relaxed))
+    }
+
+    /// The counter in Prometheus text format, one series per label that has
+    /// been slow so far.
+    pub fn render(&self) -> String {
+        let mut series: Vec<_> = self
+            .slow
+            .iter()
+            .map(|entry| (*entry.key(), entry.value().load(Ordering::Relaxed)))
+            .collect();
+        series.sort();
+
+        let mut out = String::from(
+            "# HELP mindful_code_slow_queries_total Database queries slower than SLOW_QUERY_THRESHOLD_MS\n\
+             # TYPE mindful_code_slow_queries_total counter\n",
+        );
+        for (label, count) in series {
+            let _ = writeln!(
+                out,
+                "mindful_code_slow_queries_total{{query=\"{}\"}} {}",
+                label, count
+            );
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_queries_over_the_threshold_are_counted() {
+        let log = SlowQueryLog::new(50);
+        assert!(!log.record("flow_analytics.summary", Duration::from_millis(49)));
+        assert!(log.record("flow_analytics.summary", Duration::from_millis(50)));
+        assert!(log.record("flow_analytics.daily", Duration::from_secs(2)));
+        assert_eq!(log.slow_queries("flow_analytics.summary"), 1);
+        assert!(log
+            .render()
+            .contains("mindful_code_slow_queries_total{query=\"flow_analytics.daily\"} 1"));
+
+        let disabled = SlowQueryLog::new(0);
+        assert!(!disabled.record("flow_analytics.summary", Duration::from_secs(60)));
+        assert_eq!(disabled.slow_queries("flow_analytics.summary"), 0);
+    }
+}
@@ -0,0 +1,157 @@
+use crate::{
+    error::Result,
+    models::team::{MemberFlowContribution, TeamGoal, TeamGoalMetric, TeamGoalProgress},
+    utils::date_range::{DateRange, NamedRange},
+};
+use chrono::{DateTime, Utc};
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+/// Progress of each of the team's goals over the period containing `now`.
+pub async fn load_team_goal_progress(
+    conn: &mut PgConnection,
+    team_id: Uuid,
+    now: DateTime<Utc>,
+) -> Result<Vec<TeamGoalProgress>> {
+    let goals = sqlx::query!(
+        r#"
+        SELECT id, metric, target_value, last_completed_period
+        FROM team_goals
+        WHERE team_id = $1
+        ORDER BY created_at
+        "#,
+        team_id
+    )
+    .fetch_all(&mut *conn)
+    .await?
+    .into_iter()
+    .filter_map(|row| {
+        Some(TeamGoal {
+            id: row.id,
+            team_id,
+            metric: TeamGoalMetric::from_db(&row.metric)?,
+            target_value: row.target_value,
+            last_completed_period: row.last_completed_period,
+        })
+    })
+    .collect::<Vec<_>>();
+
+    let mut progress = Vec::with_capacity(goals.len());
+    for goal in goals {
+        let period = goal_period(goal.metric, now);
+        let members = member_contributions(&mut *conn, team_id, period).await?;
+        progress.push(goal.progress(&members, period));
+    }
+
+    Ok(progress)
+}
+
+/// Goal progress for every team the user belongs to.
+pub async fn load_member_goal_progress(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    now: DateTime<Utc>,
+) -> Result<Vec<TeamGoalProgress>> {
+    let team_ids = sqlx::query_scalar!(
+        "SELECT team_id FROM team_members WHERE user_id = $1 ORDER BY joined_at",
+        user_id
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let mut progress = Vec::new();
+    for team_id in team_ids {
+        progress.extend(load_team_goal_progress(&mut *conn, team_id, now).await?);
+    }
+    Ok(progress)
+}
+
+/// Records that the goal was met this period. Returns false if another
+/// request already did, so only one caller sends the completion alert.
+pub async fn mark_goal_completed(
+    conn: &mut PgConnection,
+    progress: &TeamGoalProgress,
+) -> Result<bool> {
+    let updated = sqlx::query!(
+        r#"
+        UPDATE team_goals
+        SET last_completed_period = $2
+        WHERE id = $1 AND last_completed_period IS DISTINCT FROM $2
+        "#,
+        progress.goal_id,
+        progress.period_start,
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(updated.rows_affected() == 1)
+}
+
+/// Teams whose goals the user's flow time counts towards.
+pub async fn sharing_team_ids(conn: &mut PgConnection, user_id: Uuid) -> Result<Vec<Uuid>> {
+    let team_ids = sqlx::query_scalar!(
+        r#"
+        SELECT DISTINCT tm.team_id
+        FROM team_members tm
+        JOIN team_goals tg ON tg.team_id = tm.team_id
+        JOIN users u ON u.id = tm.user_id
+        WHERE tm.user_id = $1
+          AND tm.data_sharing_consent = true
+          AND COALESCE((u.privacy_settings->>'analytics_enabled')::BOOLEAN, true)
+        "#,
+        user_id
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    Ok(team_ids)
+}
+
+fn goal_period(metric: TeamGoalMetric, now: DateTime<Utc>) -> DateRange {
+    match metric {
+        TeamGoalMetric::WeeklyFlowHours => DateRange::named(NamedRange::ThisWeek, now),
+    }
+}
+
+async fn member_contributions(
+    conn: &mut PgConnection,
+    team_id: Uuid,
+    period: DateRange,
+) -> Result<Vec<MemberFlowContribution>> {
+    // The consent check sits in the join so flow time of members who don't
+    // share is never read. Members who turned analytics off count as not
+    // sharing, whatever they agreed to with the team
+    let rows = sqlx::query!(
+        r#"
+        SELECT tm.user_id,
+               COALESCE(s.consent, false) as "consent!",
+               COALESCE(SUM(cs.total_flow_time_ms), 0)::BIGINT as "flow_ms!"
+        FROM team_members tm
+        JOIN users u ON u.id = tm.user_id
+        CROSS JOIN LATERAL (
+            SELECT tm.data_sharing_consent = true
+               AND COALESCE((u.privacy_settings->>'analytics_enabled')::BOOLEAN, true) as consent
+        ) s
+        LEFT JOIN coding_sessions cs
+            ON cs.user_id = tm.user_id
+           AND s.consent
+           AND cs.end_time >= $2 AND cs.end_time < $3
+        WHERE tm.team_id = $1
+        GROUP BY tm.user_id, s.consent
+        "#,
+        team_id,
+        period.from,
+        period.to,
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| MemberFlowContribution {
+            user_id: row.user_id,
+            data_sharing_consent: row.consent,
+            flow_ms: row.flow_ms.max(0) as u64,
+        })
+        .collect())
+}
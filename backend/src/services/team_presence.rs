@@ -0,0 +1,319 @@
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+use uuid::Uuid;
+
+/// One member's latest flow state as shown on a team dashboard.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MemberPresence {
+    pub user_id: Uuid,
+    pub is_in_flow: bool,
+    pub flow_intensity: f32,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// What a team dashboard shows: the consenting members seen within the
+/// presence TTL, and how many of them are in flow.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TeamPresence {
+    pub team_id: Uuid,
+    pub members: Vec<MemberPresence>,
+    pub in_flow_count: usize,
+    pub average_intensity: f32,
+}
+
+/// When a team's dashboards should next hear about a change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PresenceDue {
+    Now,
+    /// Sooner would exceed the rate limit; the caller should send then
+    After(Duration),
+    /// A send is already scheduled and will carry this change
+    Scheduled,
+    /// Nobody is watching the team, or nothing changed
+    Never,
+}
+
+#[derive(Debug, Default)]
+struct TeamDashboard {
+    subscribers: HashSet<Uuid>,
+    /// Each member's presence and when it was last recorded
+    members: HashMap<Uuid, (MemberPresence, Instant)>,
+    last_sent: Option<Instant>,
+    send_scheduled: bool,
+}
+
+/// Manager connections watching a team's shared dashboard, and the
+/// presence they are shown. Each team's dashboards get at most one update
+/// per `min_interval`; changes in between are coalesced into the next one.
+/// Members not heard from for `presence_ttl` drop off.
+pub struct TeamDashboards {
+    teams: DashMap<Uuid, TeamDashboard>,
+    min_interval: Duration,
+    presence_ttl: Duration,
+}
+
+impl TeamDashboards {
+    pub fn new(min_interval: Duration, presence_ttl: Duration) -> Self {
+        Self {
+            teams: DashMap::new(),
+            min_interval,
+            presence_ttl,
+        }
+    }
+
+    /// Callers check the user may see the team.
+    pub fn subscribe(&self, team_id: Uuid, user_id: Uuid) {
+        self.teams
+            .entry(team_id)
+            .or_default()
+            .subscribers
+            .insert(user_id);
+    }
+
+    pub fn unsubscribe(&self, team_id: Uuid, user_id: Uuid) {
+        if let Some(mut dashboard) = self.teams.get_mut(&team_id) {
+            dashboard.subscribers.remove(&user_id);
+        }
+        self.teams
+            .remove_if(&team_id, |_, dashboard| dashboard.subscribers.is_empty());
+    }
+
+    /// Drops all of the user's subscriptions, as when their connection
+    /// closes.
+    pub fn unsubscribe_all(&self, user_id: Uuid) {
+        self.teams.retain(|_, dashboard| {
+            dashboard.subscribers.remove(&user_id);
+            !dashboard.subscribers.is_empty()
+        });
+    }
+
+    /// Teams with at least one dashboard open.
+    pub fn watched_teams(&self) -> Vec<Uuid> {
+        self.teams
+            .iter()
+            .map(|dashboard| *dashboard.key())
+            .collect()
+    }
+
+    /// Records a member's flow state on the team's dashboard, or takes them
+    /// off it when `presence` is `None` because they no longer share.
+    pub fn record(
+        &self,
+        team_id: Uuid,
+        member_id: Uuid,
+        presence: Option<MemberPresence>,
+        now: Instant,
+    ) -> PresenceDue {
+        let Some(mut dashboard) = self.teams.get_mut(&team_id) else {
+            return PresenceDue::Never;
+        };
+
+        let changed = match presence {
+            Some(presence) => {
+                dashboard.members.insert(member_id, (presence, now));
+                true
+            }
+            None => dashboard.members.remove(&member_id).is_some(),
+        };
+        if !changed {
+            return PresenceDue::Never;
+        }
+        if dashboard.send_scheduled {
+            return PresenceDue::Scheduled;
+        }
+
+        let elapsed = dashboard
+            .last_sent
+            .map(|sent| now.saturating_duration_since(sent));
+        match elapsed {
+            Some(elapsed) if elapsed < self.min_interval => {
+                dashboard.send_scheduled = true;
+                PresenceDue::After(self.min_interval - elapsed)
+            }
+            _ => PresenceDue::Now,
+        }
+    }
+
+    /// Takes members not heard from for the presence TTL off every
+    /// dashboard. Returns the teams whose dashboards should hear about it
+    /// now; those with a send already scheduled get it with that one.
+    pub fn expire_idle(&self, now: Instant) -> Vec<Uuid> {
+        let mut changed = Vec::new();
+        for mut dashboard in self.teams.iter_mut() {
+            let before = dashboard.members.len();
+            let ttl = self.presence_ttl;
+            dashboard
+                .members
+                .retain(|_, (_, seen)| now.saturating_duration_since(*seen) < ttl);
+            if dashboard.members.len() < before && !dashboard.send_scheduled {
+                changed.push(*dashboard.key());
+            }
+        }
+        changed
+    }
+
+    /// The team's presence and who to send it to, marking it sent. `None`
+    /// once the last dashboard has closed.
+    pub fn take_update(&self, team_id: Uuid, now: Instant) -> Option<(TeamPresence, Vec<Uuid>)> {
+        let mut dashboard = self.teams.get_mut(&team_id)?;
+        dashboard.last_sent = Some(now);
+        dashboard.send_scheduled = false;
+        let subscribers = dashboard.subscribers.iter().copied().collect();
+        Some((
+            self.current_presence(team_id, &dashboard.members, now),
+            subscribers,
+        ))
+    }
+
+    /// The team's presence as it stands, for a dashboard that just opened.
+    pub fn snapshot(&self, team_id: Uuid, now: Instant) -> Option<TeamPresence> {
+        let dashboard = self.teams.get(&team_id)?;
+        Some(self.current_presence(team_id, &dashboard.members, now))
+    }
+
+    /// Leaves out members past the TTL that `expire_idle` hasn't got to yet.
+    fn current_presence(
+        &self,
+        team_id: Uuid,
+        members: &HashMap<Uuid, (MemberPresence, Instant)>,
+        now: Instant,
+    ) -> TeamPresence {
+        summarize(
+            team_id,
+            members
+                .values()
+                .filter(|(_, seen)| now.saturating_duration_since(*seen) < self.presence_ttl)
+                .map(|(presence, _)| presence.clone())
+                .collect(),
+        )
+    }
+}
+
+fn summarize(team_id: Uuid, mut members: Vec<MemberPresence>) -> TeamPresence {
+    members.sort_by_key(|member| member.user_id);
+
+    let average_intensity = if members.is_empty() {
+        0.0
+    } else {
+        members
+            .iter()
+            .map(|member| member.flow_intensity)
+            .sum::<f32>()
+            / members.len() as f32
+    };
+    TeamPresence {
+        team_id,
+        in_flow_count: members.iter().filter(|member| member.is_in_flow).count(),
+        average_intensity,
+        members,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn presence(user_id: Uuid, is_in_flow: bool) -> Option<MemberPresence> {
+        Some(MemberPresence {
+            user_id,
+            is_in_flow,
+            flow_intensity: if is_in_flow { 0.8 } else { 0.2 },
+            updated_at: Utc::now(),
+        })
+    }
+
+    #[test]
+    fn test_updates_within_interval_are_coalesced() {
+        let dashboards = TeamDashboards::new(Duration::from_secs(2), Duration::from_secs(60));
+        let (team_id, manager, member) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let start = Instant::now();
+
+        // Unwatched teams aren't tracked at all
+        assert_eq!(
+            dashboards.record(team_id, member, presence(member, true), start),
+            PresenceDue::Never
+        );
+
+        dashboards.subscribe(team_id, manager);
+        assert_eq!(
+            dashboards.record(team_id, member, presence(member, true), start),
+            PresenceDue::Now
+        );
+        let (update, subscribers) = dashboards.take_update(team_id, start).unwrap();
+        assert_eq!(update.in_flow_count, 1);
+        assert_eq!(subscribers, vec![manager]);
+
+        let later = start + Duration::from_millis(500);
+        assert_eq!(
+            dashboards.record(team_id, member, presence(member, false), later),
+            PresenceDue::After(Duration::from_millis(1500))
+        );
+        assert_eq!(
+            dashboards.record(team_id, member, presence(member, true), later),
+            PresenceDue::Scheduled
+        );
+
+        let (update, _) = dashboards
+            .take_update(team_id, start + Duration::from_secs(2))
+            .unwrap();
+        assert_eq!(update.in_flow_count, 1);
+        assert_eq!(update.members.len(), 1);
+    }
+
+    #[test]
+    fn test_withdrawn_members_leave_the_dashboard() {
+        let dashboards = TeamDashboards::new(Duration::ZERO, Duration::from_secs(60));
+        let (team_id, manager, member) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let now = Instant::now();
+
+        dashboards.subscribe(team_id, manager);
+        dashboards.record(team_id, member, presence(member, true), now);
+        assert_eq!(
+            dashboards.record(team_id, member, None, now),
+            PresenceDue::Now
+        );
+        assert!(dashboards
+            .snapshot(team_id, now)
+            .unwrap()
+            .members
+            .is_empty());
+        // Already off it, so nothing to send
+        assert_eq!(
+            dashboards.record(team_id, member, None, now),
+            PresenceDue::Never
+        );
+
+        dashboards.unsubscribe_all(manager);
+        assert!(dashboards.watched_teams().is_empty());
+        assert!(dashboards.take_update(team_id, now).is_none());
+    }
+
+    #[test]
+    fn test_idle_members_expire() {
+        let dashboards = TeamDashboards::new(Duration::ZERO, Duration::from_secs(60));
+        let (team_id, manager) = (Uuid::new_v4(), Uuid::new_v4());
+        let (idle, active) = (Uuid::new_v4(), Uuid::new_v4());
+        let start = Instant::now();
+
+        dashboards.subscribe(team_id, manager);
+        dashboards.record(team_id, idle, presence(idle, true), start);
+        let later = start + Duration::from_secs(30);
+        dashboards.record(team_id, active, presence(active, true), later);
+
+        // Past the TTL the idle member is left out even before expiry runs
+        let expired = start + Duration::from_secs(60);
+        let snapshot = dashboards.snapshot(team_id, expired).unwrap();
+        assert_eq!(snapshot.members.len(), 1);
+        assert_eq!(snapshot.members[0].user_id, active);
+
+        assert_eq!(dashboards.expire_idle(expired), vec![team_id]);
+        assert!(dashboards.expire_idle(expired).is_empty());
+        let (update, _) = dashboards.take_update(team_id, expired).unwrap();
+        assert_eq!(update.in_flow_count, 1);
+    }
+}
@@ -0,0 +1,156 @@
+use crate::{error::Result, models::flow::FlowStateResult};
+use serde::Serialize;
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+/// Bins per unit that features and intensity are rounded into. Coarse bins
+/// make identical vectors common enough to reach the release threshold and
+/// hide the exact scores of any one sample.
+pub const TELEMETRY_BINS_PER_UNIT: f64 = 10.0;
+
+/// A flow result reduced to what the training corpus keeps: binned scores,
+/// the outcome and the model that produced it. Nothing in it identifies the
+/// user, the session or when the sample was taken.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TelemetryVector {
+    pub rhythm_score: f64,
+    pub focus_score: f64,
+    pub consistency_score: f64,
+    pub velocity_score: f64,
+    pub error_penalty: f64,
+    pub flow_intensity: f64,
+    pub is_in_flow: bool,
+    pub model_version: String,
+}
+
+impl TelemetryVector {
+    pub fn from_result(result: &FlowStateResult) -> Self {
+        let metrics = &result.metrics;
+        Self {
+            rhythm_score: bin(metrics.rhythm_score),
+            focus_score: bin(metrics.focus_score),
+            consistency_score: bin(metrics.consistency_score),
+            velocity_score: bin(metrics.velocity_score),
+            error_penalty: bin(metrics.error_penalty),
+            flow_intensity: bin(result.flow_intensity),
+            is_in_flow: result.is_in_flow,
+            model_version: result.model_version.clone(),
+        }
+    }
+
+    /// Identifies the cell: equal vectors share a key.
+    pub fn cell_key(&self) -> String {
+        format!(
+            "{:.1}|{:.1}|{:.1}|{:.1}|{:.1}|{:.1}|{}|{}",
+            self.rhythm_score,
+            self.focus_score,
+            self.consistency_score,
+            self.velocity_score,
+            self.error_penalty,
+            self.flow_intensity,
+            self.is_in_flow,
+            self.model_version
+        )
+    }
+
+    fn features(&self) -> serde_json::Value {
+        serde_json::json!({
+            "rhythm_score": self.rhythm_score,
+            "focus_score": self.focus_score,
+            "consistency_score": self.consistency_score,
+            "velocity_score": self.velocity_score,
+            "error_penalty": self.error_penalty,
+        })
+    }
+}
+
+fn bin(value: f32) -> f64 {
+    (value as f64 * TELEMETRY_BINS_PER_UNIT).round() / TELEMETRY_BINS_PER_UNIT
+}
+
+/// A corpus cell with enough distinct contributors to be used for training.
+#[derive(Debug, Clone, Serialize)]
+pub struct TelemetryCell {
+    pub features: serde_json::Value,
+    pub flow_intensity: f64,
+    pub is_in_flow: bool,
+    pub model_version: String,
+    pub sample_count: i64,
+}
+
+/// Adds a consenting user's flow result to the corpus. The user is counted
+/// towards the cell's contributors only the first time they land in it;
+/// callers check consent.
+pub async fn contribute_telemetry(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    result: &FlowStateResult,
+) -> Result<()> {
+    let vector = TelemetryVector::from_result(result);
+    let cell_key = vector.cell_key();
+
+    sqlx::query!(
+        r#"
+        INSERT INTO telemetry_feature_cells (
+            cell_key, features, flow_intensity, is_in_flow, model_version, sample_count
+        ) VALUES ($1, $2, $3, $4, $5, 1)
+        ON CONFLICT (cell_key) DO UPDATE SET
+            sample_count = telemetry_feature_cells.sample_count + 1
+        "#,
+        cell_key,
+        vector.features(),
+        vector.flow_intensity,
+        vector.is_in_flow,
+        vector.model_version,
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    let first_contribution = sqlx::query!(
+        r#"
+        INSERT INTO telemetry_contributions (user_id, cell_key)
+        VALUES ($1, $2)
+        ON CONFLICT DO NOTHING
+        "#,
+        user_id,
+        cell_key,
+    )
+    .execute(&mut *conn)
+    .await?
+    .rows_affected()
+        > 0;
+
+    if first_contribution {
+        sqlx::query!(
+            "UPDATE telemetry_feature_cells SET contributor_count = contributor_count + 1 WHERE cell_key = $1",
+            cell_key
+        )
+        .execute(&mut *conn)
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// The cells at least `min_contributors` distinct users have contributed
+/// to. Cells below the threshold are never handed out, so no released
+/// vector can be traced back to fewer than that many people.
+pub async fn released_telemetry_cells(
+    conn: &mut PgConnection,
+    min_contributors: u32,
+) -> Result<Vec<TelemetryCell>> {
+    let cells = sqlx::query_as!(
+        TelemetryCell,
+        r#"
+        SELECT features, flow_intensity, is_in_flow, model_version, sample_count
+        FROM telemetry_feature_cells
+        WHERE contributor_count >= $1
+        ORDER BY sample_count DESC
+        "#,
+        min_contributors.max(2) as i32,
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    Ok(cells)
+}
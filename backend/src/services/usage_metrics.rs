@@ -0,0 +1,182 @@
+use crate::utils::auth::SubscriptionTier;
+use dashmap::DashMap;
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Endpoints whose use is counted per subscription tier. Requests are
+/// matched by route template, and only these routes are counted, so the
+/// metric's label set stays fixed whatever paths clients send.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum MeteredEndpoint {
+    SessionStart,
+    SessionEnd,
+    SessionHistory,
+    SessionSearch,
+    SessionTags,
+    SessionTimeline,
+    FlowDetect,
+    FlowInterruption,
+    FlowPatterns,
+    FlowDiff,
+    FlowInsights,
+    FlowForecast,
+    SessionRecommendation,
+    FocusMode,
+    FlowAchievements,
+    FlowExport,
+    FlowEvents,
+    Dashboard,
+    TeamAnalytics,
+    TeamInsights,
+    TeamGoals,
+    TeamFlowSimilarity,
+    PrivacyExport,
+}
+
+impl MeteredEndpoint {
+    /// The endpoint served by an axum route template, if it is metered.
+    pub fn from_route(route: &str) -> Option<Self> {
+        let endpoint = match route {
+            "/api/sessions/start" => MeteredEndpoint::SessionStart,
+            "/api/sessions/:id/end" => MeteredEndpoint::SessionEnd,
+            "/api/sessions/history" => MeteredEndpoint::SessionHistory,
+            "/api/sessions/search" => MeteredEndpoint::SessionSearch,
+            "/api/sessions/:id/tags" => MeteredEndpoint::SessionTags,
+            "/api/sessions/:id/timeline" => MeteredEndpoint::SessionTimeline,
+            "/api/flow/detect" => MeteredEndpoint::FlowDetect,
+            "/api/flow/interruption" => MeteredEndpoint::FlowInterruption,
+            "/api/flow/patterns" => MeteredEndpoint::FlowPatterns,
+            "/api/flow/diff" => MeteredEndpoint::FlowDiff,
+            "/api/flow/insights" => MeteredEndpoint::FlowInsights,
+            "/api/flow/forecast" => MeteredEndpoint::FlowForecast,
+            "/api/flow/session-recommendation" => MeteredEndpoint::SessionRecommendation,
+            "/api/flow/focus-mode" => MeteredEndpoint::FocusMode,
+            "/api/flow/achievements" => MeteredEndpoint::FlowAchievements,
+            "/api/flow/export" => MeteredEndpoint::FlowExport,
+            "/api/flow/events" => MeteredEndpoint::FlowEvents,
+            "/api/dashboard" => MeteredEndpoint::Dashboard,
+            "/api/teams/:id/analytics" => MeteredEndpoint::TeamAnalytics,
+            "/api/teams/:id/insights" => MeteredEndpoint::TeamInsights,
+            "/api/teams/:id/goals" => MeteredEndpoint::TeamGoals,
+            "/api/teams/:id/flow-similarity" => MeteredEndpoint::TeamFlowSimilarity,
+            "/api/privacy/export" => MeteredEndpoint::PrivacyExport,
+            _ => return None,
+        };
+        Some(endpoint)
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            MeteredEndpoint::SessionStart => "session_start",
+            MeteredEndpoint::SessionEnd => "session_end",
+            MeteredEndpoint::SessionHistory => "session_history",
+            MeteredEndpoint::SessionSearch => "session_search",
+            MeteredEndpoint::SessionTags => "session_tags",
+            MeteredEndpoint::SessionTimeline => "session_timeline",
+            MeteredEndpoint::FlowDetect => "flow_detect",
+            MeteredEndpoint::FlowInterruption => "flow_interruption",
+            MeteredEndpoint::FlowPatterns => "flow_patterns",
+            MeteredEndpoint::FlowDiff => "flow_diff",
+            MeteredEndpoint::FlowInsights => "flow_insights",
+            MeteredEndpoint::FlowForecast => "flow_forecast",
+            MeteredEndpoint::SessionRecommendation => "session_recommendation",
+            MeteredEndpoint::FocusMode => "focus_mode",
+            MeteredEndpoint::FlowAchievements => "flow_achievements",
+            MeteredEndpoint::FlowExport => "flow_export",
+            MeteredEndpoint::FlowEvents => "flow_events",
+            MeteredEndpoint::Dashboard => "dashboard",
+            MeteredEndpoint::TeamAnalytics => "team_analytics",
+            MeteredEndpoint::TeamInsights => "team_insights",
+            MeteredEndpoint::TeamGoals => "team_goals",
+            MeteredEndpoint::TeamFlowSimilarity => "team_flow_similarity",
+            MeteredEndpoint::PrivacyExport => "privacy_export",
+        }
+    }
+}
+
+/// Authenticated requests per subscription tier and endpoint, for
+/// `/metrics`. Both labels come from closed enums, so there are at most
+/// tiers × endpoints series.
+#[derive(Debug, Default)]
+pub struct TierUsageMetrics {
+    requests: DashMap<(SubscriptionTier, MeteredEndpoint), AtomicU64>,
+}
+
+impl TierUsageMetrics {
+    pub fn record(&self, tier: SubscriptionTier, endpoint: MeteredEndpoint) {
+        self.requests
+            .entry((tier, endpoint))
+            .or_default()
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn requests(&self, tier: SubscriptionTier, endpoint: MeteredEndpoint) -> u64 {
+        self.requests
+            .get(&(tier, endpoint))
+            .map_or(0, |count| count.load(Ordering::Relaxed))
+    }
+
+    /// The counter in Prometheus text format, one series per combination
+    /// seen so far.
+    pub fn render(&self) -> String {
+        let mut series: Vec<_> = self
+            .requests
+            .iter()
+            .map(|entry| (*entry.key(), entry.value().load(Ordering::Relaxed)))
+            .collect();
+        series.sort();
+
+        let mut out = String::from(
+            "# HELP mindful_code_tier_requests_total Authenticated requests by subscription tier and endpoint\n\
+             # TYPE mindful_code_tier_requests_total counter\n",
+        );
+        for ((tier, endpoint), count) in series {
+            let _ = writeln!(
+                out,
+                "mindful_code_tier_requests_total{{tier=\"{}\",endpoint=\"{}\"}} {}",
+                tier.as_str(),
+                endpoint.label(),
+                count
+            );
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_known_routes_are_metered() {
+        assert_eq!(
+            MeteredEndpoint::from_route("/api/flow/detect"),
+            Some(MeteredEndpoint::FlowDetect)
+        );
+        assert_eq!(
+            MeteredEndpoint::from_route("/api/teams/:id/goals"),
+            Some(MeteredEndpoint::TeamGoals)
+        );
+        // Concrete paths never become labels
+        assert_eq!(MeteredEndpoint::from_route("/api/teams/7f3c/goals"), None);
+        assert_eq!(MeteredEndpoint::from_route("/api/whatever"), None);
+
+        let metrics = TierUsageMetrics::default();
+        metrics.record(SubscriptionTier::Free, MeteredEndpoint::FlowDetect);
+        metrics.record(SubscriptionTier::Free, MeteredEndpoint::FlowDetect);
+        metrics.record(SubscriptionTier::Team, MeteredEndpoint::Dashboard);
+        assert_eq!(
+            metrics.requests(SubscriptionTier::Free, MeteredEndpoint::FlowDetect),
+            2
+        );
+
+        let rendered = metrics.render();
+        assert!(rendered.contains(
+            "mindful_code_tier_requests_total{tier=\"free\",endpoint=\"flow_detect\"} 2"
+        ));
+        assert!(rendered
+            .contains("mindful_code_tier_requests_total{tier=\"team\",endpoint=\"dashboard\"} 1"));
+    }
+}
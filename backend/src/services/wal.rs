@@ -0,0 +1,417 @@
+use crate::{
+    config::{FlowWalConfig, WalSyncPolicy},
+    error::{AppError, Result},
+};
+use parking_lot::Mutex;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::{mpsc, oneshot};
+use tracing::warn;
+use uuid::Uuid;
+
+/// One line of the log.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum WalRecord<T> {
+    Pending { id: Uuid, entry: T },
+    Done { id: Uuid },
+}
+
+/// Just enough of a line to tell what's outstanding, whatever the entry
+/// type.
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum WalMarker {
+    Pending { id: Uuid },
+    Done { id: Uuid },
+}
+
+/// Largest number of queued records the writer handles, and fsyncs, as one
+/// batch.
+const WAL_BATCH_MAX: usize = 256;
+
+/// Records waiting for the writer; appends past this wait for room.
+const WAL_QUEUE_CAPACITY: usize = 1024;
+
+/// Append-only, line-per-record log of writes handed to the write queue.
+/// A write is logged as pending before it is queued and marked done once
+/// stored, so whatever is still pending at startup is what the last
+/// process lost. The file is emptied whenever nothing is outstanding.
+///
+/// File I/O happens on a dedicated writer thread, never on the async
+/// runtime. The writer takes whatever records have queued up since its last
+/// pass, writes them and syncs once for the lot, so concurrent appends
+/// share an fsync instead of serialising on one each.
+pub struct WriteAheadLog {
+    path: PathBuf,
+    file: Arc<Mutex<WalFile>>,
+    outstanding: Arc<Mutex<HashSet<Uuid>>>,
+    commands: mpsc::Sender<WalCommand>,
+}
+
+struct WalFile {
+    file: File,
+    last_sync: Instant,
+}
+
+enum WalCommand {
+    Append {
+        id: Uuid,
+        line: Vec<u8>,
+        done: oneshot::Sender<Result<()>>,
+    },
+    Complete {
+        id: Uuid,
+        done: oneshot::Sender<Result<()>>,
+    },
+}
+
+impl WriteAheadLog {
+    /// Opens the log, creating it if needed, and starts its writer. Records
+    /// left by a previous process stay until they are replayed and
+    /// completed.
+    pub fn open(path: impl AsRef<Path>, config: &FlowWalConfig) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(parent) = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            std::fs::create_dir_all(parent).map_err(wal_error)?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(wal_error)?;
+
+        // Terminates a line torn by a crash, so the next record starts clean
+        let contents = std::fs::read(&path).map_err(wal_error)?;
+        if contents.last().is_some_and(|byte| *byte != b'\n') {
+            file.write_all(b"\n").map_err(wal_error)?;
+        }
+
+        let outstanding = read_lines::<WalMarker>(&path)?.into_iter().fold(
+            HashSet::new(),
+            |mut pending, marker| {
+                match marker {
+                    WalMarker::Pending { id } => pending.insert(id),
+                    WalMarker::Done { id } => pending.remove(&id),
+                };
+                pending
+            },
+        );
+
+        let file = Arc::new(Mutex::new(WalFile {
+            file,
+            last_sync: Instant::now(),
+        }));
+        let outstanding = Arc::new(Mutex::new(outstanding));
+        let (commands, queue) = mpsc::channel(WAL_QUEUE_CAPACITY);
+
+        let writer = WalWriter {
+            file: file.clone(),
+            outstanding: outstanding.clone(),
+            sync: config.sync,
+            sync_interval: Duration::from_millis(config.sync_interval_ms),
+        };
+        std::thread::Builder::new()
+            .name("flow-wal-writer".to_string())
+            .spawn(move || writer.run(queue))
+            .map_err(wal_error)?;
+
+        Ok(Self {
+            path,
+            file,
+            outstanding,
+            commands,
+        })
+    }
+
+    /// Logs a write before it is queued. Resolves once the record is
+    /// written, and synced if the configured policy calls for it.
+    pub async fn append<T: Serialize>(&self, id: Uuid, entry: &T) -> Result<()> {
+        let line = encode_line(&WalRecord::Pending { id, entry })?;
+        self.submit(|done| WalCommand::Append { id, line, done })
+            .await
+    }
+
+    /// Marks a logged write as stored. Done records aren't synced: losing
+    /// one only means the write is replayed, and replays are idempotent.
+    /// Completing an id that isn't outstanding, because its append failed
+    /// or it was already completed, does nothing.
+    pub async fn complete(&self, id: Uuid) -> Result<()> {
+        self.submit(|done| WalCommand::Complete { id, done }).await
+    }
+
+    async fn submit(
+        &self,
+        command: impl FnOnce(oneshot::Sender<Result<()>>) -> WalCommand,
+    ) -> Result<()> {
+        let (done, result) = oneshot::channel();
+        self.commands
+            .send(command(done))
+            .await
+            .map_err(|_| writer_gone())?;
+        result.await.map_err(|_| writer_gone())?
+    }
+
+    /// Writes logged but never completed, oldest first.
+    pub fn pending<T: DeserializeOwned>(&self) -> Result<Vec<(Uuid, T)>> {
+        let _wal = self.file.lock();
+
+        let mut pending = Vec::new();
+        let mut done = HashSet::new();
+        for record in read_lines::<WalRecord<T>>(&self.path)? {
+            match record {
+                WalRecord::Pending { id, entry } => pending.push((id, entry)),
+                WalRecord::Done { id } => {
+                    done.insert(id);
+                }
+            }
+        }
+        pending.retain(|(id, _)| !done.contains(id));
+        Ok(pending)
+    }
+
+    /// Logged writes not yet completed.
+    pub fn outstanding(&self) -> usize {
+        self.outstanding.lock().len()
+    }
+}
+
+/// The writer thread's half of the log. Runs until every `WriteAheadLog`
+/// handle is dropped.
+struct WalWriter {
+    file: Arc<Mutex<WalFile>>,
+    outstanding: Arc<Mutex<HashSet<Uuid>>>,
+    sync: WalSyncPolicy,
+    sync_interval: Duration,
+}
+
+impl WalWriter {
+    fn run(self, mut queue: mpsc::Receiver<WalCommand>) {
+        while let Some(first) = queue.blocking_recv() {
+            let mut batch = vec![first];
+            while batch.len() < WAL_BATCH_MAX {
+                match queue.try_recv() {
+                    Ok(command) => batch.push(command),
+                    Err(_) => break,
+                }
+            }
+            self.write_batch(batch);
+        }
+    }
+
+    /// Applies the batch in order, then syncs once if any append in it is
+    /// due one. Appends are acknowledged only after that sync.
+    fn write_batch(&self, batch: Vec<WalCommand>) {
+        let mut wal = self.file.lock();
+        let mut appended = Vec::new();
+
+        for command in batch {
+            match command {
+                WalCommand::Append { id, line, done } => {
+                    match wal.file.write_all(&line).map_err(wal_error) {
+                        Ok(()) => {
+                            self.outstanding.lock().insert(id);
+                            appended.push(done);
+                        }
+                        Err(e) => {
+                            let _ = done.send(Err(e));
+                        }
+                    }
+                }
+                WalCommand::Complete { id, done } => {
+                    let _ = done.send(self.complete(&mut wal, id));
+                }
+            }
+        }
+
+        let due = !appended.is_empty()
+            && match self.sync {
+                WalSyncPolicy::Always => true,
+                WalSyncPolicy::Interval => wal.last_sync.elapsed() >= self.sync_interval,
+            };
+        let synced = if due {
+            let synced = wal.file.sync_data();
+            wal.last_sync = Instant::now();
+            synced
+        } else {
+            Ok(())
+        };
+        drop(wal);
+
+        for done in appended {
+            let result = match &synced {
+                Ok(()) => Ok(()),
+                Err(e) => Err(AppError::Internal(format!("Flow WAL I/O failed: {}", e))),
+            };
+            let _ = done.send(result);
+        }
+    }
+
+    /// Truncates the file only once no logged write is left outstanding.
+    fn complete(&self, wal: &mut WalFile, id: Uuid) -> Result<()> {
+        let remaining = {
+            let mut outstanding = self.outstanding.lock();
+            if !outstanding.remove(&id) {
+                return Ok(());
+            }
+            outstanding.len()
+        };
+
+        if remaining == 0 {
+            wal.file.set_len(0).map_err(wal_error)?;
+        } else {
+            let line = encode_line(&WalRecord::<()>::Done { id })?;
+            wal.file.write_all(&line).map_err(wal_error)?;
+        }
+        Ok(())
+    }
+}
+
+fn encode_line<T: Serialize>(record: &T) -> Result<Vec<u8>> {
+    let mut line = serde_json::to_vec(record)
+        .map_err(|e| AppError::Internal(format!("Failed to encode WAL record: {}", e)))?;
+    line.push(b'\n');
+    Ok(line)
+}
+
+/// Parses every record in the file. A line that doesn't parse, such as one
+/// torn by a crash mid-append, is skipped.
+fn read_lines<T: DeserializeOwned>(path: &Path) -> Result<Vec<T>> {
+    let file = File::open(path).map_err(wal_error)?;
+    let mut records = Vec::new();
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(wal_error)?;
+        if line.is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line) {
+            Ok(record) => records.push(record),
+            Err(e) => warn!("Skipping unreadable WAL line {}: {}", number + 1, e),
+        }
+    }
+    Ok(records)
+}
+
+fn wal_error(e: std::io::Error) -> AppError {
+    AppError::Internal(format!("Flow WAL I/O failed: {}", e))
+}
+
+fn writer_gone() -> AppError {
+    AppError::Internal("Flow WAL writer has stopped".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[tokio::test]
+    async fn test_pending_writes_survive_reopen_until_completed() {
+        let path = std::env::temp_dir().join(format!("flow-wal-{}.log", Uuid::new_v4()));
+        let config = FlowWalConfig {
+            sync: WalSyncPolicy::Always,
+            ..FlowWalConfig::default()
+        };
+        let (first, second, third) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+
+        let wal = WriteAheadLog::open(&path, &config).unwrap();
+        wal.append(first, &"first").await.unwrap();
+        wal.append(second, &"second").await.unwrap();
+        wal.append(third, &"third").await.unwrap();
+        wal.complete(second).await.unwrap();
+        drop(wal);
+
+        // A crash mid-append leaves a torn line, which is skipped
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(br#"{"op":"pending","id":"#).unwrap();
+        drop(file);
+
+        let wal = WriteAheadLog::open(&path, &config).unwrap();
+        assert_eq!(wal.outstanding(), 2);
+        let pending: Vec<(Uuid, String)> = wal.pending().unwrap();
+        assert_eq!(
+            pending,
+            vec![(first, "first".to_string()), (third, "third".to_string())]
+        );
+
+        // Completing the last outstanding write empties the file
+        wal.complete(first).await.unwrap();
+        wal.complete(third).await.unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+        assert!(wal.pending::<String>().unwrap().is_empty());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_completing_unlogged_writes_keeps_pending_ones() {
+        let path = std::env::temp_dir().join(format!("flow-wal-{}.log", Uuid::new_v4()));
+        let config = FlowWalConfig {
+            sync: WalSyncPolicy::Always,
+            ..FlowWalConfig::default()
+        };
+        let wal = WriteAheadLog::open(&path, &config).unwrap();
+        let logged = Uuid::new_v4();
+        wal.append(logged, &"logged").await.unwrap();
+
+        // An entry that can't be encoded never reaches the log
+        let unlogged = Uuid::new_v4();
+        let unencodable = HashMap::from([((1, 2), "tuple keys aren't JSON")]);
+        assert!(wal.append(unlogged, &unencodable).await.is_err());
+        wal.complete(unlogged).await.unwrap();
+        assert_eq!(wal.outstanding(), 1);
+
+        // Nor does completing a write twice count it twice
+        let other = Uuid::new_v4();
+        wal.append(other, &"other").await.unwrap();
+        wal.complete(other).await.unwrap();
+        wal.complete(other).await.unwrap();
+        assert_eq!(wal.outstanding(), 1);
+        let pending: Vec<(Uuid, String)> = wal.pending().unwrap();
+        assert_eq!(pending, vec![(logged, "logged".to_string())]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_appends_are_all_logged() {
+        let path = std::env::temp_dir().join(format!("flow-wal-{}.log", Uuid::new_v4()));
+        let config = FlowWalConfig {
+            sync: WalSyncPolicy::Always,
+            ..FlowWalConfig::default()
+        };
+        let wal = Arc::new(WriteAheadLog::open(&path, &config).unwrap());
+
+        let appends: Vec<_> = (0..50)
+            .map(|index| {
+                let wal = wal.clone();
+                tokio::spawn(async move { wal.append(Uuid::new_v4(), &index).await })
+            })
+            .collect();
+        for append in appends {
+            append.await.unwrap().unwrap();
+        }
+
+        assert_eq!(wal.outstanding(), 50);
+        let mut logged: Vec<u32> = wal
+            .pending::<u32>()
+            .unwrap()
+            .into_iter()
+            .map(|(_, index)| index)
+            .collect();
+        logged.sort_unstable();
+        assert_eq!(logged, (0..50).collect::<Vec<_>>());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,4 +1,9 @@
-use crate::error::{AppError, Result};
+use crate::{
+    config::{PluginLimitsConfig, PluginSigningConfig},
+    error::{AppError, Result},
+};
+use ed25519_dalek::{Signature, VerifyingKey};
+use serde::Serialize;
 use std::{
     collections::HashMap,
     path::Path,
@@ -14,21 +19,87 @@ use wasmtime_wasi::{WasiCtx, WasiCtxBuilder};
 pub struct WasmPluginManager {
     engine: Engine,
     plugins: Arc<RwLock<HashMap<String, LoadedPlugin>>>,
+    verifier: PluginVerifier,
+    limits: PluginLimitsConfig,
+}
+
+/// Checks detached Ed25519 signatures on plugin binaries before they are
+/// compiled.
+#[derive(Debug, Clone, Default)]
+pub struct PluginVerifier {
+    required: bool,
+    trusted: Vec<(String, VerifyingKey)>,
 }
 
 struct LoadedPlugin {
     module: Module,
     plugin_info: PluginInfo,
     instance_count: u32,
+    /// Approximate memory held by the compiled module
+    module_bytes: usize,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct PluginInfo {
     pub name: String,
     pub version: String,
     pub description: String,
     pub author: String,
     pub capabilities: Vec<String>,
+    /// Trusted signer whose signature was verified; `None` for plugins
+    /// loaded with verification disabled
+    pub signer: Option<String>,
+}
+
+impl PluginVerifier {
+    pub fn from_config(config: &PluginSigningConfig) -> Result<Self> {
+        let trusted = config
+            .trusted_signers
+            .iter()
+            .map(|signer| {
+                let key_bytes: [u8; 32] = hex::decode(&signer.public_key)
+                    .ok()
+                    .and_then(|bytes| bytes.try_into().ok())
+                    .ok_or_else(|| {
+                        AppError::Wasm(format!("Invalid public key for signer '{}'", signer.name))
+                    })?;
+                let key = VerifyingKey::from_bytes(&key_bytes).map_err(|e| {
+                    AppError::Wasm(format!("Invalid public key for signer '{}': {}", signer.name, e))
+                })?;
+                Ok((signer.name.clone(), key))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            required: config.require_signatures,
+            trusted,
+        })
+    }
+
+    /// The name of the trusted signer that signed `plugin_bytes`. With
+    /// verification disabled every plugin passes, unattributed.
+    pub fn verify(&self, plugin_bytes: &[u8], signature: Option<&str>) -> Result<Option<String>> {
+        if !self.required {
+            return Ok(None);
+        }
+
+        let signature = signature
+            .ok_or_else(|| AppError::Authorization("Plugin is not signed".to_string()))?;
+        let signature = hex::decode(signature.trim())
+            .ok()
+            .and_then(|bytes| Signature::from_slice(&bytes).ok())
+            .ok_or_else(|| AppError::Authorization("Malformed plugin signature".to_string()))?;
+
+        self.trusted
+            .iter()
+            .find(|(_, key)| key.verify_strict(plugin_bytes, &signature).is_ok())
+            .map(|(name, _)| Some(name.clone()))
+            .ok_or_else(|| {
+                AppError::Authorization(
+                    "Plugin signature does not match any trusted signer".to_string(),
+                )
+            })
+    }
 }
 
 pub struct WasmRuntime {
@@ -60,35 +131,127 @@ impl WasmPluginManager {
         Ok(Self {
             engine,
             plugins: Arc::new(RwLock::new(HashMap::new())),
+            verifier: PluginVerifier::default(),
+            limits: PluginLimitsConfig::default(),
         })
     }
 
+    pub fn with_verifier(mut self, verifier: PluginVerifier) -> Self {
+        self.verifier = verifier;
+        self
+    }
+
+    pub fn with_limits(mut self, limits: PluginLimitsConfig) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Compiles the smallest valid module, to show the engine works
+    /// without registering a plugin or going through signature checks.
+    pub fn check_engine(&self) -> Result<()> {
+        Module::from_binary(&self.engine, b"\0asm\x01\0\0\0")
+            .map(|_| ())
+            .map_err(|e| AppError::Wasm(format!("Failed to compile test module: {}", e)))
+    }
+
+    /// Loads `plugin_path`, along with its detached signature at
+    /// `<plugin_path>.sig` if there is one.
     pub async fn load_plugin<P: AsRef<Path>>(&self, plugin_path: P, plugin_name: String) -> Result<()> {
         let plugin_bytes = tokio::fs::read(&plugin_path).await
             .map_err(|e| AppError::Wasm(format!("Failed to read plugin file: {}", e)))?;
 
-        let module = Module::from_binary(&self.engine, &plugin_bytes)
+        let mut signature_path = plugin_path.as_ref().as_os_str().to_owned();
+        signature_path.push(".sig");
+        let signature = match tokio::fs::read_to_string(&signature_path).await {
+            Ok(signature) => Some(signature),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => {
+                return Err(AppError::Wasm(format!("Failed to read plugin signature: {}", e)))
+            }
+        };
+
+        self.load_plugin_bytes(&plugin_bytes, signature.as_deref(), plugin_name)
+    }
+
+    /// Verifies and compiles a plugin. `signature` is the hex-encoded
+    /// detached signature over `plugin_bytes`. Loads that would take the
+    /// manager past its plugin count or module memory limit are refused
+    /// with a conflict; reloading a name replaces it within the limits.
+    pub fn load_plugin_bytes(
+        &self,
+        plugin_bytes: &[u8],
+        signature: Option<&str>,
+        plugin_name: String,
+    ) -> Result<()> {
+        // Untrusted bytes never reach the compiler
+        let signer = self.verifier.verify(plugin_bytes, signature)?;
+
+        // Refuse before compiling when there's no slot; the size of the
+        // compiled module is only known after, so that is checked below
+        let (others, _) = self.loaded_besides(&self.plugins.read().unwrap(), &plugin_name);
+        self.check_slot_free(&plugin_name, others)?;
+
+        let module = Module::from_binary(&self.engine, plugin_bytes)
             .map_err(|e| AppError::Wasm(format!("Failed to compile WASM module: {}", e)))?;
 
         // Extract plugin metadata
-        let plugin_info = self.extract_plugin_info(&module, &plugin_name)?;
+        let plugin_info = self.extract_plugin_info(&module, &plugin_name, signer)?;
 
         info!("Loading plugin: {} v{}", plugin_info.name, plugin_info.version);
         debug!("Plugin capabilities: {:?}", plugin_info.capabilities);
 
+        let module_bytes = module_footprint(&module, plugin_bytes.len());
         let loaded_plugin = LoadedPlugin {
             module,
             plugin_info,
             instance_count: 0,
+            module_bytes,
         };
 
+        // Checked again, as another load may have taken the slot meanwhile
         let mut plugins = self.plugins.write().unwrap();
+        let (others, others_bytes) = self.loaded_besides(&plugins, &plugin_name);
+        self.check_slot_free(&plugin_name, others)?;
+        if others_bytes + module_bytes > self.limits.max_module_bytes {
+            return Err(AppError::Conflict(format!(
+                "Plugin '{}' not loaded: its module needs ~{} KiB, but only {} KiB of the {} KiB plugin memory budget is free",
+                plugin_name,
+                module_bytes / 1024,
+                self.limits.max_module_bytes.saturating_sub(others_bytes) / 1024,
+                self.limits.max_module_bytes / 1024
+            )));
+        }
         plugins.insert(plugin_name.clone(), loaded_plugin);
 
         info!("✅ Plugin '{}' loaded successfully", plugin_name);
         Ok(())
     }
 
+    /// Count and module bytes of the loaded plugins other than `plugin_name`,
+    /// which a reload replaces.
+    fn loaded_besides(
+        &self,
+        plugins: &HashMap<String, LoadedPlugin>,
+        plugin_name: &str,
+    ) -> (usize, usize) {
+        plugins
+            .iter()
+            .filter(|(name, _)| name.as_str() != plugin_name)
+            .fold((0, 0), |(count, bytes), (_, plugin)| {
+                (count + 1, bytes + plugin.module_bytes)
+            })
+    }
+
+    fn check_slot_free(&self, plugin_name: &str, others: usize) -> Result<()> {
+        if others >= self.limits.max_loaded {
+            return Err(AppError::Conflict(format!(
+                "Plugin '{}' not loaded: {} plugins are already loaded, the most allowed; unload one first",
+                plugin_name, others
+            )));
+        }
+        Ok(())
+    }
+
     pub async fn create_runtime(&self, plugin_name: &str) -> Result<WasmRuntime> {
         let plugins = self.plugins.read().unwrap();
         let plugin = plugins
@@ -179,7 +342,12 @@ impl WasmPluginManager {
         Ok(())
     }
 
-    fn extract_plugin_info(&self, module: &Module, fallback_name: &str) -> Result<PluginInfo> {
+    fn extract_plugin_info(
+        &self,
+        module: &Module,
+        fallback_name: &str,
+        signer: Option<String>,
+    ) -> Result<PluginInfo> {
         // In a real implementation, this would parse plugin metadata
         // from custom sections or exported functions
         Ok(PluginInfo {
@@ -191,9 +359,16 @@ impl WasmPluginManager {
                 "flow_analysis".to_string(),
                 "data_processing".to_string(),
             ],
+            signer,
         })
     }
 
+    /// Approximate memory held by all loaded modules.
+    pub fn loaded_module_bytes(&self) -> usize {
+        let plugins = self.plugins.read().unwrap();
+        plugins.values().map(|p| p.module_bytes).sum()
+    }
+
     pub fn get_loaded_plugins(&self) -> Vec<PluginInfo> {
         let plugins = self.plugins.read().unwrap();
         plugins
@@ -213,6 +388,13 @@ impl WasmPluginManager {
     }
 }
 
+/// A module's compiled code and data image, or the binary it came from if
+/// that is larger. Runtime allocations aren't counted.
+fn module_footprint(module: &Module, binary_len: usize) -> usize {
+    let image = module.image_range();
+    (image.end as usize - image.start as usize).max(binary_len)
+}
+
 impl WasmRuntime {
     pub async fn execute_function<Params, Results>(
         &mut self,
@@ -370,9 +552,3 @@ pub fn {}(data: &str) -> String {{
         Ok(())
     }
 }
-
-impl Default for WasmPluginManager {
-    fn default() -> Self {
-        Self::new().expect("Failed to initialize WASM plugin manager")
-    }
-}
\ No newline at end of file
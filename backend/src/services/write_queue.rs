@@ -0,0 +1,207 @@
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{sync::Semaphore, task::JoinHandle};
+
+use crate::{
+    config::FlowWriteRetryConfig,
+    error::{AppError, Result},
+};
+
+/// Runs fire-and-forget database writes with bounded concurrency. Writes
+/// beyond the limit wait for a permit instead of each grabbing a pool
+/// connection, so a burst of flow detections can't starve request handlers.
+pub struct WriteQueue {
+    permits: Arc<Semaphore>,
+    limit: usize,
+    queued: Arc<AtomicUsize>,
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl WriteQueue {
+    pub fn new(limit: usize) -> Self {
+        let limit = limit.max(1);
+        Self {
+            permits: Arc::new(Semaphore::new(limit)),
+            limit,
+            queued: Arc::new(AtomicUsize::new(0)),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn spawn<F>(&self, write: F) -> JoinHandle<()>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let permits = self.permits.clone();
+        let queued = self.queued.clone();
+        let in_flight = self.in_flight.clone();
+
+        queued.fetch_add(1, Ordering::Relaxed);
+        tokio::spawn(async move {
+            // The semaphore is never closed
+            let permit = permits.acquire_owned().await;
+            // Counted in flight before leaving the queue, so a drain never
+            // sees the write in neither
+            in_flight.fetch_add(1, Ordering::Relaxed);
+            queued.fetch_sub(1, Ordering::Relaxed);
+
+            write.await;
+            in_flight.fetch_sub(1, Ordering::Relaxed);
+            drop(permit);
+        })
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Writes waiting for a permit.
+    pub fn queue_depth(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// Waits for every write spawned so far to finish, for at most
+    /// `timeout`. Returns whether the queue emptied.
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        let drained = async {
+            while self.queue_depth() + self.in_flight() > 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(timeout, drained).await.is_ok()
+    }
+}
+
+/// Whether a failed write may succeed if tried again: a dropped or
+/// unavailable connection, or a serialization failure or deadlock the
+/// database asks callers to retry. Constraint violations and the like fail
+/// the same way every time.
+pub fn is_transient_write_error(error: &AppError) -> bool {
+    match error {
+        AppError::Database(sqlx::Error::Io(_) | sqlx::Error::PoolTimedOut) => true,
+        AppError::Database(sqlx::Error::Database(e)) => e.code().is_some_and(|code| {
+            // serialization_failure, deadlock_detected, connection
+            // exceptions and server shutdowns
+            matches!(code.as_ref(), "40001" | "40P01")
+                || code.starts_with("08")
+                || code.starts_with("57P")
+        }),
+        _ => false,
+    }
+}
+
+/// Wait before retry number `retry` (1 for the first), doubling from the
+/// initial backoff up to the cap.
+pub fn retry_backoff(policy: &FlowWriteRetryConfig, retry: u32) -> Duration {
+    let factor = 1u64 << retry.saturating_sub(1).min(16);
+    Duration::from_millis(
+        policy
+            .initial_backoff_ms
+            .saturating_mul(factor)
+            .min(policy.max_backoff_ms),
+    )
+}
+
+/// Runs `attempt` until it succeeds, fails with an error that isn't
+/// transient, or has been tried `policy.max_attempts` times. Returns the
+/// last result and how many tries it took.
+pub async fn retry_transient<T, F, Fut>(
+    policy: &FlowWriteRetryConfig,
+    mut attempt: F,
+) -> (Result<T>, u32)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        match attempt().await {
+            Err(e) if attempts < policy.max_attempts && is_transient_write_error(&e) => {
+                let backoff = retry_backoff(policy, attempts);
+                tracing::debug!("Retrying write in {}ms after: {}", backoff.as_millis(), e);
+                tokio::time::sleep(backoff).await;
+            }
+            result => return (result, attempts),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_burst_never_exceeds_write_limit() {
+        let queue = WriteQueue::new(4);
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+
+        let handles: Vec<_> = (0..200)
+            .map(|_| {
+                let running = running.clone();
+                let peak = peak.clone();
+                queue.spawn(async move {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    peak.fetch_max(now, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                    running.fetch_sub(1, Ordering::SeqCst);
+                })
+            })
+            .collect();
+
+        // Most of the burst is still waiting for a permit
+        tokio::task::yield_now().await;
+        assert!(queue.in_flight() <= queue.limit());
+        assert!(queue.queue_depth() > 0);
+
+        for handle in handles {
+            handle.await.unwrap();
+        }
+
+        assert!(peak.load(Ordering::SeqCst) <= 4);
+        assert_eq!(queue.queue_depth(), 0);
+        assert_eq!(queue.in_flight(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_drain_waits_for_queued_writes() {
+        let queue = WriteQueue::new(1);
+        let done = Arc::new(AtomicUsize::new(0));
+
+        for _ in 0..5 {
+            let done = done.clone();
+            queue.spawn(async move {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                done.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        assert!(!queue.drain(Duration::from_millis(1)).await);
+        assert!(queue.drain(Duration::from_secs(5)).await);
+        assert_eq!(done.load(Ordering::SeqCst), 5);
+    }
+
+    #[test]
+    fn test_retry_backoff_doubles_up_to_the_cap() {
+        let policy = FlowWriteRetryConfig {
+            max_attempts: 10,
+            initial_backoff_ms: 100,
+            max_backoff_ms: 500,
+        };
+        let backoffs: Vec<u128> = (1..=5)
+            .map(|retry| retry_backoff(&policy, retry).as_millis())
+            .collect();
+        assert_eq!(backoffs, vec![100, 200, 400, 500, 500]);
+    }
+}
@@ -1,18 +1,111 @@
-use crate::{config::Config, services::flow::FlowDetectionEngine};
+use crate::{
+    config::Config,
+    services::{
+        encryption::{EncryptionService, PayloadKeys},
+        engine_pool::{FlowEnginePool, PooledEngine},
+        feature_flags::FeatureFlags,
+        feedback::{feedback_store, FeedbackStore},
+        flow::{AnalysisCacheStats, FlowDetectionEngine, FlowSampler, KeystrokeHasher},
+        flow_stats::retire_flow_engine,
+        focus::FocusModes,
+        key_rotation::load_encryption_keys,
+        login_security::{geo_locator, GeoLocator},
+        migrations::run_migrations,
+        ml::{MLInferenceEngine, ModelRegistry},
+        ml_batch::InferenceBatcher,
+        readiness::ServerReadiness,
+        sanitizer::Sanitizer,
+        slow_queries::SlowQueryLog,
+        team_presence::TeamDashboards,
+        usage_metrics::TierUsageMetrics,
+        wal::WriteAheadLog,
+        wasm::{PluginVerifier, WasmPluginManager},
+        write_queue::WriteQueue,
+    },
+    utils::auth::RateLimiter,
+};
 use anyhow::Result;
 use dashmap::DashMap;
 use parking_lot::RwLock;
-use sqlx::{postgres::PgPoolOptions, PgPool};
-use std::sync::Arc;
+use sqlx::{migrate::Migrator, postgres::PgPoolOptions, PgPool};
+use std::sync::{atomic::AtomicU64, Arc};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 use uuid::Uuid;
 
+/// Migrations embedded at compile time; also the reference list the admin
+/// migration-status endpoint compares the database against.
+pub static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
+/// Most flow engines a user keeps; creating another drops the least recently
+/// used. Bounds anonymous trials, whose session ids aren't checked.
+const MAX_FLOW_ENGINES_PER_USER: usize = 16;
+
 #[derive(Clone)]
 pub struct AppState {
     pub db: PgPool,
+    pub db_replica: Option<PgPool>,
     pub config: Config,
-    pub flow_engines: Arc<DashMap<Uuid, Arc<RwLock<FlowDetectionEngine>>>>,
+    /// Keyed by `(user_id, session_id)`, so a user's parallel sessions
+    /// keep separate keystroke rhythms and flow stretches
+    pub flow_engines: Arc<DashMap<(Uuid, Uuid), Arc<RwLock<FlowDetectionEngine>>>>,
+    /// Reset engines reused by scoring that keeps no per-user state
+    pub engine_pool: Arc<FlowEnginePool>,
+    /// Per-user cap on in-flight flow detections
+    pub flow_detect_slots: Arc<DashMap<Uuid, Arc<Semaphore>>>,
+    /// Server-wide cap on in-flight requests; `None` when load shedding is
+    /// off
+    pub request_slots: Option<Arc<Semaphore>>,
     pub active_sessions: Arc<DashMap<Uuid, SessionInfo>>,
-    pub websocket_connections: Arc<DashMap<Uuid, tokio::sync::mpsc::UnboundedSender<String>>>,
+    pub websocket_connections: Arc<DashMap<Uuid, WebSocketConnection>>,
+    pub focus_modes: Arc<FocusModes>,
+    /// Manager connections subscribed to teams' shared dashboards
+    pub team_dashboards: Arc<TeamDashboards>,
+    /// Per-user budget of analytics pushed on request over WebSocket
+    pub analytics_refreshes: Arc<RateLimiter>,
+    pub flow_sampler: Arc<FlowSampler>,
+    /// Shared model handle cloned into every per-user flow engine
+    pub ml_engine: MLInferenceEngine,
+    /// Batches live sessions' predictions on `ml_engine`
+    pub ml_batcher: Arc<InferenceBatcher>,
+    /// Initializing until `ml_engine` has warmed up
+    pub readiness: Arc<ServerReadiness>,
+    /// Versioned models admins can pin analyses to
+    pub model_registry: Arc<ModelRegistry>,
+    pub feature_flags: Arc<FeatureFlags>,
+    pub sanitizer: Arc<Sanitizer>,
+    /// `None` when ENCRYPTION_KEY isn't a valid 64-character hex key. The
+    /// active key changes under the lock on scheduled rotation
+    pub encryption: Option<Arc<RwLock<EncryptionService>>>,
+    /// Keys sensitive WebSocket payloads are sealed with, derived from
+    /// high-security users' passwords at login
+    pub payload_keys: Arc<PayloadKeys>,
+    /// Bounds the detached flow_states writes from flow detection
+    pub flow_writes: Arc<WriteQueue>,
+    /// Logs those writes until stored, when FLOW_WAL_PATH is set
+    pub flow_wal: Option<Arc<WriteAheadLog>>,
+    pub keystroke_hasher: Arc<KeystrokeHasher>,
+    /// Hit/miss counts across every engine's analysis cache
+    pub analysis_cache_stats: Arc<AnalysisCacheStats>,
+    /// Analyses scored rule-based because ML inference failed
+    pub ml_fallbacks: Arc<AtomicU64>,
+    /// Timed queries that ran past the slow-query threshold
+    pub slow_queries: Arc<SlowQueryLog>,
+    /// Authenticated requests per subscription tier and endpoint
+    pub tier_usage: Arc<TierUsageMetrics>,
+    /// Training examples for the flow model
+    pub feedback_store: Arc<dyn FeedbackStore>,
+    /// Locates login IPs for impossible-travel checks
+    pub geo_locator: Arc<dyn GeoLocator>,
+    /// `None` when the WASM engine couldn't be created; plugins are
+    /// optional, so everything else keeps working
+    pub wasm_plugins: Option<Arc<WasmPluginManager>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct WebSocketConnection {
+    pub sender: tokio::sync::mpsc::UnboundedSender<String>,
+    /// Resolved from the claims at connection time
+    pub is_admin: bool,
 }
 
 #[derive(Clone, Debug)]
@@ -27,62 +120,416 @@ pub struct SessionInfo {
 impl AppState {
     pub async fn new(config: Config) -> Result<Self> {
         // Connect to PostgreSQL with optimized pool settings
-        let db = PgPoolOptions::new()
-            .max_connections(config.max_connections)
-            .min_connections(5)
-            .acquire_timeout(std::time::Duration::from_secs(3))
-            .idle_timeout(std::time::Duration::from_secs(600))
-            .max_lifetime(std::time::Duration::from_secs(1800))
-            .connect(&config.database_url)
+        let db = Self::connect_pool(&config.database_url, config.max_connections)
             .await
             .map_err(|e| anyhow::anyhow!("Failed to connect to database: {}", e))?;
 
         // Run database migrations
-        sqlx::migrate!("./migrations")
-            .run(&db)
+        run_migrations(&MIGRATOR, &db)
             .await
             .map_err(|e| anyhow::anyhow!("Failed to run migrations: {}", e))?;
 
         tracing::info!("✅ Database connected and migrations applied");
 
-        Ok(Self {
+        // Analytics reads go to the replica when one is configured
+        let db_replica = match config.database_replica_url.as_deref() {
+            Some(replica_url) => {
+                let replica = Self::connect_pool(replica_url, config.max_connections)
+                    .await
+                    .map_err(|e| anyhow::anyhow!("Failed to connect to read replica: {}", e))?;
+                tracing::info!("✅ Read replica connected for analytics queries");
+                Some(replica)
+            }
+            None => None,
+        };
+
+        let state = Self::from_pools(config, db, db_replica);
+
+        // Keys from scheduled rotations; without them data re-encrypted
+        // under a rotated key couldn't be read after a restart
+        load_encryption_keys(&state, chrono::Utc::now())
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to load encryption keys: {}", e))?;
+
+        Ok(state)
+    }
+
+    pub fn from_pools(config: Config, db: PgPool, db_replica: Option<PgPool>) -> Self {
+        let flow_sampler = FlowSampler::new(std::time::Duration::from_secs(
+            config.flow_sample_interval_secs,
+        ));
+        let ml_engine = MLInferenceEngine::from_model_path(config.onnx_model_path.as_deref());
+        let ml_batcher = Arc::new(InferenceBatcher::new(
+            ml_engine.clone(),
+            std::time::Duration::from_millis(config.ml_batch_max_wait_ms),
+            config.ml_batch_max_size,
+        ));
+        let model_registry = Arc::new(ModelRegistry::new(config.model_registry_dir.as_deref()));
+        let feature_flags = Arc::new(config.feature_flags.clone());
+        let sanitizer = Arc::new(Sanitizer::new(&config.sanitizer));
+        let encryption = match EncryptionService::from_hex_key(&config.encryption_key) {
+            Ok(service) => {
+                tracing::info!("✅ Encryption service initialized with AES-256-GCM");
+                Some(Arc::new(RwLock::new(
+                    service.with_secure_delete_passes(config.secure_delete_passes),
+                )))
+            }
+            Err(e) => {
+                tracing::warn!("Field encryption disabled: {}", e);
+                None
+            }
+        };
+
+        let flow_writes = Arc::new(WriteQueue::new(config.flow_persist_concurrency));
+        let flow_wal = config.flow_wal.path.as_deref().and_then(|path| {
+            match WriteAheadLog::open(path, &config.flow_wal) {
+                Ok(wal) => Some(Arc::new(wal)),
+                Err(e) => {
+                    tracing::warn!("Flow write WAL disabled: {}", e);
+                    None
+                }
+            }
+        });
+        let feedback_store = feedback_store(&config.feedback_store, &db);
+        let focus_modes = Arc::new(FocusModes::new(config.focus_mode.deferred_reminders));
+        let team_dashboards = Arc::new(TeamDashboards::new(
+            std::time::Duration::from_millis(config.team_dashboard_min_interval_ms),
+            std::time::Duration::from_secs(config.session_idle_timeout_minutes as u64 * 60),
+        ));
+        let analytics_refreshes = Arc::new(RateLimiter::new(
+            config.analytics_refreshes_per_minute,
+            std::time::Duration::from_secs(60),
+        ));
+        let slow_queries = Arc::new(SlowQueryLog::new(config.slow_query_threshold_ms));
+        let geo_locator = geo_locator(&config.login_security);
+        let keystroke_hasher = Arc::new(KeystrokeHasher::new(config.keystroke_hash_salt.as_bytes()));
+        let request_slots = match config.load_shedding.max_in_flight_requests {
+            0 => None,
+            limit => Some(Arc::new(Semaphore::new(limit))),
+        };
+        let engine_pool = Arc::new(FlowEnginePool::new(
+            config.worker_threads,
+            config.flow_engine_pool_size,
+        ));
+        let wasm_plugins = WasmPluginManager::new().and_then(|manager| {
+            Ok(manager
+                .with_verifier(PluginVerifier::from_config(&config.plugin_signing)?)
+                .with_limits(config.plugin_limits.clone()))
+        });
+
+        Self {
             db,
+            db_replica,
             config,
             flow_engines: Arc::new(DashMap::new()),
+            engine_pool,
+            flow_detect_slots: Arc::new(DashMap::new()),
+            request_slots,
             active_sessions: Arc::new(DashMap::new()),
             websocket_connections: Arc::new(DashMap::new()),
+            focus_modes,
+            team_dashboards,
+            analytics_refreshes,
+            flow_sampler: Arc::new(flow_sampler),
+            ml_engine,
+            ml_batcher,
+            readiness: Arc::new(ServerReadiness::default()),
+            model_registry,
+            feature_flags,
+            sanitizer,
+            encryption,
+            payload_keys: Arc::new(PayloadKeys::default()),
+            flow_writes,
+            flow_wal,
+            keystroke_hasher,
+            analysis_cache_stats: Arc::new(AnalysisCacheStats::default()),
+            ml_fallbacks: Arc::new(AtomicU64::new(0)),
+            slow_queries,
+            tier_usage: Arc::new(TierUsageMetrics::default()),
+            feedback_store,
+            geo_locator,
+            wasm_plugins: None,
+        }
+        .with_wasm_plugins(wasm_plugins)
+    }
+
+    /// Installs the plugin manager, or runs without plugins if it failed to
+    /// initialize.
+    pub fn with_wasm_plugins(mut self, manager: crate::error::Result<WasmPluginManager>) -> Self {
+        self.wasm_plugins = match manager {
+            Ok(manager) => Some(Arc::new(manager)),
+            Err(e) => {
+                tracing::warn!("WASM plugins disabled: {}", e);
+                None
+            }
+        };
+        self
+    }
+
+    pub fn plugins(&self) -> crate::error::Result<&WasmPluginManager> {
+        self.wasm_plugins.as_deref().ok_or_else(|| {
+            crate::error::AppError::ServiceUnavailable("Plugins unavailable".to_string())
         })
     }
 
-    pub fn get_or_create_flow_engine(&self, user_id: Uuid) -> Arc<RwLock<FlowDetectionEngine>> {
+    async fn connect_pool(url: &str, max_connections: u32) -> sqlx::Result<PgPool> {
+        PgPoolOptions::new()
+            .max_connections(max_connections)
+            .min_connections(5)
+            .acquire_timeout(std::time::Duration::from_secs(3))
+            .idle_timeout(std::time::Duration::from_secs(600))
+            .max_lifetime(std::time::Duration::from_secs(1800))
+            .connect(url)
+            .await
+    }
+
+    /// Pool for read-only analytics queries; falls back to the primary
+    /// when no replica is configured. Writes must always use `db`.
+    pub fn read_db(&self) -> &PgPool {
+        self.db_replica.as_ref().unwrap_or(&self.db)
+    }
+
+    pub fn get_or_create_flow_engine(
+        &self,
+        user_id: Uuid,
+        session_id: Uuid,
+    ) -> Arc<RwLock<FlowDetectionEngine>> {
+        if let Some(engine) = self.flow_engines.get(&(user_id, session_id)) {
+            return engine.clone();
+        }
+
+        let mut engines: Vec<_> = self
+            .flow_engines
+            .iter()
+            .filter(|entry| entry.key().0 == user_id)
+            // An engine that's mid-analysis is in use, not a candidate
+            .filter_map(|entry| Some((entry.value().try_read()?.last_analysis(), entry.key().1)))
+            .collect();
+        if engines.len() >= MAX_FLOW_ENGINES_PER_USER {
+            engines.sort_unstable();
+            let evicted = engines[..=engines.len() - MAX_FLOW_ENGINES_PER_USER]
+                .iter()
+                .filter_map(|(_, stale_session)| {
+                    tracing::debug!("Dropping least recently used flow engine {}", stale_session);
+                    self.flow_engines.remove(&(user_id, *stale_session))
+                })
+                .map(|((user_id, _), engine)| (user_id, engine))
+                .collect();
+            self.retire_flow_engines(evicted);
+        }
+
         self.flow_engines
-            .entry(user_id)
-            .or_insert_with(|| Arc::new(RwLock::new(FlowDetectionEngine::new())))
+            .entry((user_id, session_id))
+            .or_insert_with(|| Arc::new(RwLock::new(self.new_flow_engine())))
             .clone()
     }
 
+    /// A fresh engine sharing the server's model, hasher and counters.
+    pub fn new_flow_engine(&self) -> FlowDetectionEngine {
+        FlowDetectionEngine::with_config(self.config.flow_engine.clone(), self.ml_engine.clone())
+            .with_keystroke_hasher(self.keystroke_hasher.clone())
+            .with_analysis_cache_stats(self.analysis_cache_stats.clone())
+            .with_ml_fallback_counter(self.ml_fallbacks.clone())
+            .with_ml_batcher(self.ml_batcher.clone())
+    }
+
+    /// An engine for a one-off analysis that belongs to no session, reused
+    /// from the pool when one is idle.
+    pub fn pooled_flow_engine(&self) -> PooledEngine {
+        self.engine_pool.acquire(|| self.new_flow_engine())
+    }
+
+    /// Engines for every live session of the user.
+    pub fn user_flow_engines(&self, user_id: Uuid) -> Vec<Arc<RwLock<FlowDetectionEngine>>> {
+        self.flow_engines
+            .iter()
+            .filter(|entry| entry.key().0 == user_id)
+            .map(|entry| entry.value().clone())
+            .collect()
+    }
+
+    /// Flow stretches and flow time summed over the user's live sessions.
+    pub fn user_flow_stats(&self, user_id: Uuid) -> (u32, std::time::Duration) {
+        self.user_flow_engines(user_id)
+            .iter()
+            .map(|engine| engine.read().get_session_stats())
+            .fold((0, std::time::Duration::ZERO), |(count, time), (sessions, flow_time)| {
+                (count + sessions, time + flow_time)
+            })
+    }
+
+    /// Drops the session's engine from the map and returns it, so its
+    /// stats can still be persisted.
+    pub fn remove_flow_engine(
+        &self,
+        user_id: Uuid,
+        session_id: Uuid,
+    ) -> Option<Arc<RwLock<FlowDetectionEngine>>> {
+        self.flow_engines
+            .remove(&(user_id, session_id))
+            .map(|(_, engine)| engine)
+    }
+
+    /// Drops engines that haven't scored a sample for `idle` and don't
+    /// belong to a tracked session, such as abandoned anonymous trials.
+    /// Tracked sessions keep theirs until they're ended.
+    pub fn cleanup_idle_flow_engines(&self, idle: std::time::Duration) {
+        let mut removed = Vec::new();
+        self.flow_engines.retain(|(user_id, session_id), engine| {
+            let keep = self.active_sessions.contains_key(session_id)
+                || engine
+                    .try_read()
+                    .map_or(true, |engine| engine.last_analysis().elapsed() < idle);
+            if !keep {
+                removed.push((*user_id, engine.clone()));
+            }
+            keep
+        });
+        self.retire_flow_engines(removed);
+    }
+
+    /// Adds the stats of engines dropped from the map to their users'
+    /// lifetime totals, as ending a session does, on a spawned task so
+    /// callers don't wait on the writes.
+    fn retire_flow_engines(&self, removed: Vec<(Uuid, Arc<RwLock<FlowDetectionEngine>>)>) {
+        if removed.is_empty() {
+            return;
+        }
+        let db = self.db.clone();
+        tokio::spawn(async move {
+            for (user_id, engine) in removed {
+                retire_flow_engine(&db, user_id, &engine).await;
+            }
+        });
+    }
+
+    /// Claims one of the user's flow detection slots for the life of the
+    /// returned permit, or fails with `RateLimit` when all are in use. Kept
+    /// separate from request-rate limits so one busy device can't hold up
+    /// other users' detections.
+    pub fn try_acquire_flow_detect_slot(
+        &self,
+        user_id: Uuid,
+    ) -> crate::error::Result<OwnedSemaphorePermit> {
+        let slots = self
+            .flow_detect_slots
+            .entry(user_id)
+            .or_insert_with(|| {
+                Arc::new(Semaphore::new(self.config.flow_detect_concurrency_per_user))
+            })
+            .clone();
+
+        slots.try_acquire_owned().map_err(|_| {
+            tracing::debug!("Flow detection concurrency limit reached for user {}", user_id);
+            crate::error::AppError::RateLimit
+        })
+    }
+
+    /// Forgets the slots of users with no detection in flight, so the map
+    /// only holds users who are detecting. Held permits keep their
+    /// semaphore referenced, so one referenced only by the map has every
+    /// permit free, and the map's lock stops anyone claiming it meanwhile.
+    pub fn cleanup_idle_flow_detect_slots(&self) {
+        self.flow_detect_slots.retain(|_, slots| Arc::strong_count(slots) > 1);
+    }
+
+    /// The sessions an anonymous trial still has engines for, with the
+    /// sample time each started at.
+    pub fn anonymous_trial_sessions(
+        &self,
+        anonymous_id: Uuid,
+    ) -> Vec<(Uuid, Option<chrono::DateTime<chrono::Utc>>)> {
+        self.flow_engines
+            .iter()
+            .filter(|entry| entry.key().0 == anonymous_id)
+            .map(|entry| (entry.key().1, entry.value().read().session_started_at()))
+            .collect()
+    }
+
+    /// Hands an anonymous trial's session engines to a registered user. An
+    /// engine the user already has for the same session is kept; returns
+    /// whether anything was migrated.
+    pub fn migrate_anonymous_engine(&self, anonymous_id: Uuid, user_id: Uuid) -> bool {
+        let trial_sessions: Vec<Uuid> = self
+            .flow_engines
+            .iter()
+            .filter(|entry| entry.key().0 == anonymous_id)
+            .map(|entry| entry.key().1)
+            .collect();
+
+        let mut migrated = false;
+        for session_id in trial_sessions {
+            let Some((_, engine)) = self.flow_engines.remove(&(anonymous_id, session_id)) else {
+                continue;
+            };
+            if let dashmap::mapref::entry::Entry::Vacant(entry) =
+                self.flow_engines.entry((user_id, session_id))
+            {
+                entry.insert(engine);
+                migrated = true;
+            }
+        }
+
+        migrated
+    }
+
     pub fn add_websocket_connection(
         &self,
         user_id: Uuid,
         sender: tokio::sync::mpsc::UnboundedSender<String>,
+        is_admin: bool,
     ) {
-        self.websocket_connections.insert(user_id, sender);
+        self.websocket_connections
+            .insert(user_id, WebSocketConnection { sender, is_admin });
         tracing::info!("WebSocket connection added for user {}", user_id);
     }
 
-    pub fn remove_websocket_connection(&self, user_id: Uuid) {
-        self.websocket_connections.remove(&user_id);
-        tracing::info!("WebSocket connection removed for user {}", user_id);
+    /// Removes the user's connection only once its receiving end is gone,
+    /// so a connection that has since replaced it stays registered. Returns
+    /// whether it was removed.
+    pub fn remove_closed_websocket_connection(&self, user_id: Uuid) -> bool {
+        let removed = self
+            .websocket_connections
+            .remove_if(&user_id, |_, connection| connection.sender.is_closed())
+            .is_some();
+        if removed {
+            tracing::info!("WebSocket connection removed for user {}", user_id);
+        }
+        removed
     }
 
     pub async fn broadcast_to_user(&self, user_id: Uuid, message: String) {
-        if let Some((_, sender)) = self.websocket_connections.get(&user_id) {
-            if let Err(e) = sender.send(message) {
-                tracing::warn!("Failed to send WebSocket message to user {}: {}", user_id, e);
-                // Remove the stale connection
-                self.websocket_connections.remove(&user_id);
+        let send_result = self
+            .websocket_connections
+            .get(&user_id)
+            .map(|connection| connection.sender.send(message));
+
+        if let Some(Err(e)) = send_result {
+            tracing::warn!("Failed to send WebSocket message to user {}: {}", user_id, e);
+            // Remove the stale connection
+            self.websocket_connections.remove(&user_id);
+        }
+    }
+
+    /// Fans a message out to admin and team-manager connections only.
+    /// Returns the number of connections it was delivered to.
+    pub async fn broadcast_to_admins(&self, message: String) -> usize {
+        let mut delivered = 0;
+        let mut stale = Vec::new();
+
+        for connection in self.websocket_connections.iter().filter(|c| c.is_admin) {
+            match connection.sender.send(message.clone()) {
+                Ok(()) => delivered += 1,
+                Err(_) => stale.push(*connection.key()),
             }
         }
+
+        for user_id in stale {
+            tracing::warn!("Removing stale admin WebSocket connection for user {}", user_id);
+            self.websocket_connections.remove(&user_id);
+        }
+
+        delivered
     }
 
     pub fn update_session_activity(&self, session_id: Uuid) {
@@ -96,27 +543,79 @@ impl AppState {
             .insert(session_info.session_id, session_info);
     }
 
-    pub fn remove_active_session(&self, session_id: Uuid) {
+    /// Forgets the session and returns its flow engine, if it had one.
+    pub fn remove_active_session(
+        &self,
+        user_id: Uuid,
+        session_id: Uuid,
+    ) -> Option<Arc<RwLock<FlowDetectionEngine>>> {
         self.active_sessions.remove(&session_id);
+        self.flow_sampler.forget_session(session_id);
+        self.remove_flow_engine(user_id, session_id)
     }
 
     pub fn get_active_sessions_count(&self) -> usize {
         self.active_sessions.len()
     }
 
-    pub fn cleanup_idle_sessions(&self, idle_timeout_minutes: i64) {
+    /// Sessions without activity for `idle_timeout_minutes`. They stay in
+    /// memory until the caller has ended them in the database, so a failed
+    /// end is retried on the next sweep.
+    pub fn idle_sessions(&self, idle_timeout_minutes: i64) -> Vec<SessionInfo> {
         let cutoff_time = chrono::Utc::now() - chrono::Duration::minutes(idle_timeout_minutes);
-        let mut to_remove = Vec::new();
 
-        for entry in self.active_sessions.iter() {
-            if entry.value().last_activity < cutoff_time {
-                to_remove.push(*entry.key());
-            }
-        }
+        self.active_sessions
+            .iter()
+            .filter(|entry| entry.value().last_activity < cutoff_time)
+            .map(|entry| entry.value().clone())
+            .collect()
+    }
 
-        for session_id in to_remove {
-            self.active_sessions.remove(&session_id);
-            tracing::info!("Cleaned up idle session: {}", session_id);
-        }
+    pub fn is_session_active(&self, session_id: Uuid) -> bool {
+        self.active_sessions.contains_key(&session_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_state() -> AppState {
+        let config = Config::from_env().unwrap();
+        AppState::from_pools(config, lazy_pool("postgresql://localhost/mindful_code"), None)
+    }
+
+    fn lazy_pool(url: &str) -> PgPool {
+        PgPoolOptions::new().connect_lazy(url).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_read_db_uses_replica_when_configured() {
+        let config = Config::from_env().unwrap();
+        let primary = lazy_pool("postgresql://primary.local/mindful_code");
+        let replica = lazy_pool("postgresql://replica.local/mindful_code");
+
+        let state = AppState::from_pools(config.clone(), primary.clone(), Some(replica));
+        assert_eq!(state.read_db().connect_options().get_host(), "replica.local");
+        assert_eq!(state.db.connect_options().get_host(), "primary.local");
+
+        let state = AppState::from_pools(config, primary, None);
+        assert_eq!(state.read_db().connect_options().get_host(), "primary.local");
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_broadcast_to_admins_skips_regular_users() {
+        let state = test_state();
+        let (admin_tx, mut admin_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (user_tx, mut user_rx) = tokio::sync::mpsc::unbounded_channel();
+
+        state.add_websocket_connection(Uuid::new_v4(), admin_tx, true);
+        state.add_websocket_connection(Uuid::new_v4(), user_tx, false);
+
+        let delivered = state.broadcast_to_admins("system healthy".to_string()).await;
+
+        assert_eq!(delivered, 1);
+        assert_eq!(admin_rx.try_recv().unwrap(), "system healthy");
+        assert!(user_rx.try_recv().is_err());
+    }
+}
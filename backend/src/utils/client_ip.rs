@@ -0,0 +1,114 @@
+use axum::{
+    extract::{ConnectInfo, FromRequestParts},
+    http::{request::Parts, HeaderMap},
+};
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+};
+
+use crate::state::AppState;
+
+const FORWARDED_FOR_HEADER: &str = "x-forwarded-for";
+
+/// Address of the client behind the request; see `resolve_client_ip`.
+/// `None` when the peer address isn't known.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientIp(pub Option<IpAddr>);
+
+#[axum::async_trait]
+impl FromRequestParts<AppState> for ClientIp {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> std::result::Result<Self, Self::Rejection> {
+        let peer = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip());
+
+        Ok(Self(resolve_client_ip(
+            peer,
+            &parts.headers,
+            &state.config.trusted_proxies,
+        )))
+    }
+}
+
+/// The peer address, unless the peer is one of `trusted_proxies`. Then
+/// `X-Forwarded-For` is read from the right, where each proxy appended the
+/// address it was reached from, skipping further trusted proxies; the first
+/// other address is the client. Entries left of an unparsable one could
+/// have been written by anyone, so the walk stops there and falls back to
+/// the peer.
+pub fn resolve_client_ip(
+    peer: Option<IpAddr>,
+    headers: &HeaderMap,
+    trusted_proxies: &[IpAddr],
+) -> Option<IpAddr> {
+    let peer = peer?;
+    if !trusted_proxies.contains(&peer) {
+        return Some(peer);
+    }
+
+    let entries: Vec<&str> = headers
+        .get_all(FORWARDED_FOR_HEADER)
+        .iter()
+        .filter_map(|header| header.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect();
+
+    for entry in entries.into_iter().rev() {
+        match entry.trim().parse::<IpAddr>() {
+            Ok(ip) if trusted_proxies.contains(&ip) => continue,
+            Ok(ip) => return Some(ip),
+            Err(_) => break,
+        }
+    }
+
+    Some(peer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    fn forwarded_for(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(FORWARDED_FOR_HEADER, value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_forwarded_for_is_ignored_from_untrusted_peers() {
+        let headers = forwarded_for("203.0.113.9");
+        let peer = Some(ip("198.51.100.7"));
+
+        assert_eq!(resolve_client_ip(peer, &headers, &[]), peer);
+        assert_eq!(resolve_client_ip(peer, &headers, &[ip("10.0.0.1")]), peer);
+        assert_eq!(resolve_client_ip(None, &headers, &[]), None);
+    }
+
+    #[test]
+    fn test_trusted_proxies_are_skipped_from_the_right() {
+        let proxies = [ip("10.0.0.1"), ip("10.0.0.2")];
+        let peer = Some(ip("10.0.0.1"));
+
+        // A client can prepend anything; only what the proxies appended counts
+        let headers = forwarded_for("1.2.3.4, 203.0.113.9, 10.0.0.2");
+        assert_eq!(
+            resolve_client_ip(peer, &headers, &proxies),
+            Some(ip("203.0.113.9"))
+        );
+
+        let garbled = forwarded_for("203.0.113.9, not-an-ip, 10.0.0.2");
+        assert_eq!(resolve_client_ip(peer, &garbled, &proxies), peer);
+        assert_eq!(resolve_client_ip(peer, &HeaderMap::new(), &proxies), peer);
+    }
+}
@@ -0,0 +1,273 @@
+use crate::error::{AppError, Result};
+use chrono::{DateTime, Datelike, Duration, NaiveTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Longest window any analytics query may cover.
+pub const MAX_RANGE_DAYS: i64 = 365;
+/// Most buckets an analytics distribution may have: a month of hours.
+pub const MAX_DISTRIBUTION_BUCKETS: i64 = 31 * 24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NamedRange {
+    Today,
+    ThisWeek,
+    #[serde(rename = "last_30d")]
+    Last30d,
+}
+
+/// Bucket size of an analytics distribution. Buckets follow the user's
+/// calendar; weeks start on Monday.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Granularity {
+    Hour,
+    #[default]
+    Day,
+    Week,
+    Month,
+}
+
+impl Granularity {
+    /// The field name `date_trunc` takes.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Granularity::Hour => "hour",
+            Granularity::Day => "day",
+            Granularity::Week => "week",
+            Granularity::Month => "month",
+        }
+    }
+
+    /// Shortest length a bucket can have; DST days and months vary.
+    fn min_bucket(&self) -> Duration {
+        match self {
+            Granularity::Hour => Duration::hours(1),
+            Granularity::Day => Duration::hours(23),
+            Granularity::Week => Duration::days(7),
+            Granularity::Month => Duration::days(28),
+        }
+    }
+
+    /// Rejects ranges that would split into more than
+    /// `MAX_DISTRIBUTION_BUCKETS` buckets at this size.
+    pub fn check_bucket_count(&self, range: DateRange) -> Result<()> {
+        let span = range.to - range.from;
+        let bucket = self.min_bucket();
+        // Partial buckets at both ends count too
+        let buckets = (span.num_seconds() + bucket.num_seconds() - 1) / bucket.num_seconds() + 1;
+        if buckets > MAX_DISTRIBUTION_BUCKETS {
+            return Err(AppError::Validation(format!(
+                "Range has too many {} buckets (at most {}); use a coarser granularity",
+                self.as_str(),
+                MAX_DISTRIBUTION_BUCKETS
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// How a client asks for an analytics window: a trailing number of `days`,
+/// an explicit `from`/`to`, or a named `range`. At most one form may be
+/// given; none falls back to the endpoint's default.
+#[derive(Debug, Clone, Default, Hash, Serialize, Deserialize)]
+pub struct DateRangeQuery {
+    pub days: Option<i64>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub range: Option<NamedRange>,
+}
+
+/// Resolved half-open window `[from, to)`, bound directly into queries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DateRange {
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+}
+
+impl DateRangeQuery {
+    pub fn resolve(&self, now: DateTime<Utc>, default_days: i64) -> Result<DateRange> {
+        let explicit = self.from.is_some() || self.to.is_some();
+        let forms = [self.days.is_some(), explicit, self.range.is_some()]
+            .into_iter()
+            .filter(|given| *given)
+            .count();
+        if forms > 1 {
+            return Err(AppError::Validation(
+                "Specify only one of days, from/to, or range".to_string(),
+            ));
+        }
+
+        if explicit {
+            let from = self
+                .from
+                .ok_or_else(|| AppError::Validation("to requires from".to_string()))?;
+            return DateRange::between(from, self.to.unwrap_or(now), now);
+        }
+
+        if let Some(range) = self.range {
+            return Ok(DateRange::named(range, now));
+        }
+
+        DateRange::trailing_days(self.days.unwrap_or(default_days), now)
+    }
+}
+
+impl DateRange {
+    pub fn trailing_days(days: i64, now: DateTime<Utc>) -> Result<Self> {
+        if !(1..=MAX_RANGE_DAYS).contains(&days) {
+            return Err(AppError::Validation(format!(
+                "days must be between 1 and {}",
+                MAX_RANGE_DAYS
+            )));
+        }
+
+        Ok(Self {
+            from: now - Duration::days(days),
+            to: now,
+        })
+    }
+
+    pub fn between(from: DateTime<Utc>, to: DateTime<Utc>, now: DateTime<Utc>) -> Result<Self> {
+        if from >= to {
+            return Err(AppError::Validation("from must be before to".to_string()));
+        }
+        if from > now {
+            return Err(AppError::Validation(
+                "Range lies entirely in the future".to_string(),
+            ));
+        }
+        if to - from > Duration::days(MAX_RANGE_DAYS) {
+            return Err(AppError::Validation(format!(
+                "Range may span at most {} days",
+                MAX_RANGE_DAYS
+            )));
+        }
+
+        Ok(Self { from, to })
+    }
+
+    /// Named ranges are calendar-aligned in UTC and end now; weeks start on
+    /// Monday.
+    pub fn named(range: NamedRange, now: DateTime<Utc>) -> Self {
+        let midnight = now.date_naive().and_time(NaiveTime::MIN).and_utc();
+
+        let from = match range {
+            NamedRange::Today => midnight,
+            NamedRange::ThisWeek => {
+                midnight - Duration::days(now.weekday().num_days_from_monday() as i64)
+            }
+            NamedRange::Last30d => now - Duration::days(30),
+        };
+
+        Self { from, to: now }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    // A Thursday afternoon
+    fn now() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 3, 14, 15, 30, 0).unwrap()
+    }
+
+    fn at(day: u32, hour: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 3, day, hour, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn test_days_and_default_ranges() {
+        let range = DateRangeQuery::default().resolve(now(), 30).unwrap();
+        assert_eq!(range.from, now() - Duration::days(30));
+        assert_eq!(range.to, now());
+
+        let query = DateRangeQuery {
+            days: Some(7),
+            ..Default::default()
+        };
+        assert_eq!(query.resolve(now(), 30).unwrap().from, at(7, 15) + Duration::minutes(30));
+
+        for days in [0, -3, 366] {
+            let query = DateRangeQuery {
+                days: Some(days),
+                ..Default::default()
+            };
+            assert!(query.resolve(now(), 30).is_err());
+        }
+    }
+
+    #[test]
+    fn test_explicit_ranges() {
+        let query = DateRangeQuery {
+            from: Some(at(1, 0)),
+            to: Some(at(10, 0)),
+            ..Default::default()
+        };
+        assert_eq!(
+            query.resolve(now(), 30).unwrap(),
+            DateRange { from: at(1, 0), to: at(10, 0) }
+        );
+
+        // Open-ended ranges run until now
+        let query = DateRangeQuery {
+            from: Some(at(1, 0)),
+            ..Default::default()
+        };
+        assert_eq!(query.resolve(now(), 30).unwrap().to, now());
+
+        let inverted = DateRangeQuery {
+            from: Some(at(10, 0)),
+            to: Some(at(1, 0)),
+            ..Default::default()
+        };
+        assert!(inverted.resolve(now(), 30).is_err());
+
+        let future = DateRangeQuery {
+            from: Some(at(20, 0)),
+            to: Some(at(25, 0)),
+            ..Default::default()
+        };
+        assert!(future.resolve(now(), 30).is_err());
+
+        let missing_from = DateRangeQuery {
+            to: Some(at(10, 0)),
+            ..Default::default()
+        };
+        assert!(missing_from.resolve(now(), 30).is_err());
+    }
+
+    #[test]
+    fn test_named_ranges() {
+        let resolve = |range| {
+            DateRangeQuery {
+                range: Some(range),
+                ..Default::default()
+            }
+            .resolve(now(), 30)
+            .unwrap()
+        };
+
+        assert_eq!(resolve(NamedRange::Today), DateRange { from: at(14, 0), to: now() });
+        assert_eq!(resolve(NamedRange::ThisWeek), DateRange { from: at(11, 0), to: now() });
+        assert_eq!(
+            resolve(NamedRange::Last30d),
+            DateRange { from: now() - Duration::days(30), to: now() }
+        );
+
+        let named: NamedRange = serde_json::from_str("\"last_30d\"").unwrap();
+        assert_eq!(named, NamedRange::Last30d);
+    }
+
+    #[test]
+    fn test_mixed_forms_are_rejected() {
+        let query = DateRangeQuery {
+            days: Some(7),
+            range: Some(NamedRange::Today),
+            ..Default::default()
+        };
+        assert!(query.resolve(now(), 30).is_err());
+    }
+}
@@ -0,0 +1,48 @@
+use axum::{
+    extract::{Request, State},
+    http::{header::RETRY_AFTER, HeaderValue},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::{error::AppError, state::AppState};
+
+/// Turns requests away with 503 while the server already has its configured
+/// maximum in flight. A slot is held until the handler has produced its
+/// response; a WebSocket upgrade only holds one until the switch.
+pub async fn load_shed_middleware(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let Some(slots) = state.request_slots.clone() else {
+        return next.run(req).await;
+    };
+    // Monitoring must keep working exactly when the server is saturated
+    if is_exempt(req.uri().path()) {
+        return next.run(req).await;
+    }
+
+    match slots.try_acquire_owned() {
+        Ok(_permit) => next.run(req).await,
+        Err(_) => {
+            tracing::debug!(
+                "Shedding {} {}: too many requests in flight",
+                req.method(),
+                req.uri().path()
+            );
+            let mut response =
+                AppError::ServiceUnavailable("Server is at capacity, retry shortly".to_string())
+                    .into_response();
+            response.headers_mut().insert(
+                RETRY_AFTER,
+                HeaderValue::from(state.config.load_shedding.retry_after_secs),
+            );
+            response
+        }
+    }
+}
+
+fn is_exempt(path: &str) -> bool {
+    matches!(path, "/health" | "/metrics")
+}
@@ -1,3 +1,15 @@
 pub mod auth;
+pub mod client_ip;
+pub mod date_range;
+pub mod load_shed;
+pub mod request_timeout;
+pub mod response;
+pub mod tier_usage;
 
-pub use auth::*;
\ No newline at end of file
+pub use auth::*;
+pub use client_ip::*;
+pub use date_range::*;
+pub use load_shed::*;
+pub use request_timeout::*;
+pub use response::*;
+pub use tier_usage::*;
\ No newline at end of file
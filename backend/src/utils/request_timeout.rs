@@ -0,0 +1,47 @@
+use axum::{
+    extract::{MatchedPath, Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::time::Duration;
+
+use crate::{config::RequestTimeoutConfig, error::AppError, state::AppState};
+
+/// The time limit for a route template, or `None` when it has none.
+/// Long-lived streams are never cut off; only their handshake goes through
+/// here, and it can't be slow.
+pub fn route_timeout(config: &RequestTimeoutConfig, route: &str) -> Option<Duration> {
+    let ms = match route {
+        "/ws" | "/api/flow/events" => return None,
+        "/api/flow/detect" => config.flow_detect_ms,
+        "/api/flow/export" | "/api/privacy/export" => config.export_ms,
+        _ => config.default_ms,
+    };
+    (ms > 0).then(|| Duration::from_millis(ms))
+}
+
+/// Fails the request with a 408 once its route's time limit has passed.
+/// Must be added with `route_layer` so the matched route template is known.
+pub async fn request_timeout_middleware(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let limit = req
+        .extensions()
+        .get::<MatchedPath>()
+        .and_then(|route| route_timeout(&state.config.request_timeouts, route.as_str()));
+    let Some(limit) = limit else {
+        return next.run(req).await;
+    };
+
+    let route = req.uri().path().to_string();
+    match tokio::time::timeout(limit, next.run(req)).await {
+        Ok(response) => response,
+        Err(_) => {
+            tracing::warn!("{} timed out after {}ms", route, limit.as_millis());
+            AppError::Timeout(format!("Request took longer than {}ms", limit.as_millis()))
+                .into_response()
+        }
+    }
+}
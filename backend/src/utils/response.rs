@@ -0,0 +1,286 @@
+use crate::state::AppState;
+use axum::{
+    extract::FromRequestParts,
+    http::{
+        header::{ACCEPT, CACHE_CONTROL, ETAG, IF_NONE_MATCH},
+        request::Parts,
+        HeaderName, StatusCode,
+    },
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Serialize, Serializer};
+use std::{
+    cell::Cell,
+    collections::hash_map::DefaultHasher,
+    convert::Infallible,
+    hash::{Hash, Hasher},
+    time::Instant,
+};
+use uuid::Uuid;
+
+/// Media type clients send in `Accept` to opt into enveloped responses
+/// when the server-wide `RESPONSE_ENVELOPE` flag is off.
+pub const ENVELOPE_MEDIA_TYPE: &str = "application/vnd.mindful-code.envelope+json";
+
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// How a handler's success payload is rendered: bare JSON (the historical
+/// format) or wrapped as `{"data": ..., "meta": {...}}`.
+#[derive(Debug, Clone)]
+pub struct ResponseFormat {
+    enveloped: bool,
+    request_id: String,
+    started_at: Instant,
+    score_decimal_places: Option<u32>,
+    /// The client's `If-None-Match`, for handlers that can answer 304
+    if_none_match: Option<String>,
+}
+
+impl Default for ResponseFormat {
+    fn default() -> Self {
+        Self::new(false, None)
+    }
+}
+
+impl ResponseFormat {
+    pub fn new(enveloped: bool, request_id: Option<String>) -> Self {
+        Self {
+            enveloped,
+            request_id: request_id.unwrap_or_else(|| Uuid::new_v4().to_string()),
+            started_at: Instant::now(),
+            score_decimal_places: None,
+            if_none_match: None,
+        }
+    }
+
+    pub fn with_if_none_match(mut self, if_none_match: Option<String>) -> Self {
+        self.if_none_match = if_none_match;
+        self
+    }
+
+    /// Rounds fields marked with [`serialize_score`] to this many decimal
+    /// places when the response body is written.
+    pub fn with_score_decimal_places(mut self, decimal_places: Option<u32>) -> Self {
+        self.score_decimal_places = decimal_places;
+        self
+    }
+
+    pub fn is_enveloped(&self) -> bool {
+        self.enveloped
+    }
+
+    pub fn respond<T: Serialize>(&self, data: T) -> ApiResponse<T> {
+        ApiResponse {
+            data,
+            format: self.clone(),
+            cache: None,
+        }
+    }
+
+    /// Whether the client already holds the response `validator` stands
+    /// for, so the handler can answer 304 without computing it.
+    pub fn has_current(&self, validator: &CacheValidator) -> bool {
+        self.if_none_match
+            .as_deref()
+            .is_some_and(|if_none_match| validator.matches(if_none_match))
+    }
+
+    /// A fresh response carrying `validator`'s `ETag` and `Cache-Control`.
+    pub fn respond_cached<T: Serialize>(&self, data: T, validator: CacheValidator) -> Cached<T> {
+        Cached::Fresh(ApiResponse {
+            data,
+            format: self.clone(),
+            cache: Some(validator),
+        })
+    }
+}
+
+#[axum::async_trait]
+impl FromRequestParts<AppState> for ResponseFormat {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> std::result::Result<Self, Self::Rejection> {
+        let accepts_envelope = parts
+            .headers
+            .get(ACCEPT)
+            .and_then(|header| header.to_str().ok())
+            .is_some_and(|accept| accept.contains(ENVELOPE_MEDIA_TYPE));
+
+        let request_id = parts
+            .headers
+            .get(REQUEST_ID_HEADER)
+            .and_then(|header| header.to_str().ok())
+            .map(str::to_string);
+
+        let if_none_match = parts
+            .headers
+            .get(IF_NONE_MATCH)
+            .and_then(|header| header.to_str().ok())
+            .map(str::to_string);
+
+        Ok(Self::new(
+            state.config.response_envelope || accepts_envelope,
+            request_id,
+        )
+        .with_score_decimal_places(state.config.score_decimal_places)
+        .with_if_none_match(if_none_match))
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ResponseMeta {
+    pub request_id: String,
+    pub duration_ms: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Envelope<T> {
+    pub data: T,
+    pub meta: ResponseMeta,
+}
+
+pub struct ApiResponse<T> {
+    data: T,
+    format: ResponseFormat,
+    cache: Option<CacheValidator>,
+}
+
+impl<T> ApiResponse<T> {
+    /// The payload, for handlers that embed another handler's response.
+    pub fn into_data(self) -> T {
+        self.data
+    }
+}
+
+impl<T: Serialize> IntoResponse for ApiResponse<T> {
+    fn into_response(self) -> Response {
+        // The body is serialized synchronously inside `Json::into_response`,
+        // so the precision only applies to this response
+        let previous = SCORE_DECIMAL_PLACES.replace(self.format.score_decimal_places);
+        let response = self.render();
+        SCORE_DECIMAL_PLACES.set(previous);
+        response
+    }
+}
+
+impl<T: Serialize> ApiResponse<T> {
+    fn render(self) -> Response {
+        let cache = self.cache.map(|validator| validator.headers());
+        if !self.format.enveloped {
+            return (cache, Json(self.data)).into_response();
+        }
+
+        let envelope = Envelope {
+            data: self.data,
+            meta: ResponseMeta {
+                request_id: self.format.request_id,
+                duration_ms: self.format.started_at.elapsed().as_secs_f64() * 1000.0,
+            },
+        };
+        (cache, Json(envelope)).into_response()
+    }
+}
+
+/// Identifies one version of a cacheable response: a strong `ETag` hashed
+/// from everything the response depends on, and how long a client may
+/// reuse it before revalidating. Per-user data, so caches are private.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheValidator {
+    etag: String,
+    max_age_secs: u64,
+}
+
+impl CacheValidator {
+    /// `DefaultHasher` may change between Rust releases; a new build then
+    /// only costs clients one full response.
+    pub fn new(fingerprint: impl Hash, max_age_secs: u64) -> Self {
+        let mut hasher = DefaultHasher::new();
+        fingerprint.hash(&mut hasher);
+        Self {
+            etag: format!("\"{:016x}\"", hasher.finish()),
+            max_age_secs,
+        }
+    }
+
+    pub fn etag(&self) -> &str {
+        &self.etag
+    }
+
+    /// Matches an `If-None-Match` list, which compares weakly.
+    fn matches(&self, if_none_match: &str) -> bool {
+        if_none_match
+            .split(',')
+            .map(str::trim)
+            .any(|etag| etag == "*" || etag.strip_prefix("W/").unwrap_or(etag) == self.etag)
+    }
+
+    fn headers(&self) -> [(HeaderName, String); 2] {
+        [
+            (ETAG, self.etag.clone()),
+            (
+                CACHE_CONTROL,
+                format!("private, max-age={}", self.max_age_secs),
+            ),
+        ]
+    }
+}
+
+/// A cacheable response: the payload, or `304 Not Modified` when the
+/// client's copy is still current.
+pub enum Cached<T> {
+    Fresh(ApiResponse<T>),
+    NotModified(CacheValidator),
+}
+
+impl<T> Cached<T> {
+    /// The payload; `None` for a 304, which only a request sending
+    /// `If-None-Match` can get.
+    pub fn into_data(self) -> Option<T> {
+        match self {
+            Cached::Fresh(response) => Some(response.into_data()),
+            Cached::NotModified(_) => None,
+        }
+    }
+}
+
+impl<T: Serialize> IntoResponse for Cached<T> {
+    fn into_response(self) -> Response {
+        match self {
+            Cached::Fresh(response) => response.into_response(),
+            Cached::NotModified(validator) => {
+                (StatusCode::NOT_MODIFIED, validator.headers()).into_response()
+            }
+        }
+    }
+}
+
+thread_local! {
+    static SCORE_DECIMAL_PLACES: Cell<Option<u32>> = const { Cell::new(None) };
+}
+
+/// Serializes a score at the precision of the response being written. Only
+/// the output is rounded; outside an [`ApiResponse`] (persistence, caches,
+/// websocket pushes) the full value is written.
+pub fn serialize_score<S: Serializer>(score: &f32, serializer: S) -> Result<S::Ok, S::Error> {
+    match SCORE_DECIMAL_PLACES.get() {
+        Some(decimal_places) => {
+            let factor = 10f64.powi(decimal_places.min(f64::DIGITS) as i32);
+            serializer.serialize_f64((f64::from(*score) * factor).round() / factor)
+        }
+        None => serializer.serialize_f32(*score),
+    }
+}
+
+pub fn serialize_optional_score<S: Serializer>(
+    score: &Option<f32>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match score {
+        Some(score) => serialize_score(score, serializer),
+        None => serializer.serialize_none(),
+    }
+}
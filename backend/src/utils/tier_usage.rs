@@ -0,0 +1,30 @@
+use axum::{
+    extract::{FromRequestParts, MatchedPath, Request, State},
+    middleware::Next,
+    response::Response,
+};
+
+use crate::{services::usage_metrics::MeteredEndpoint, state::AppState, utils::auth::Claims};
+
+/// Counts the request against the caller's subscription tier when it hits a
+/// metered endpoint with valid claims. Must be added with `route_layer` so
+/// the matched route template is known.
+pub async fn tier_usage_middleware(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let endpoint = req
+        .extensions()
+        .get::<MatchedPath>()
+        .and_then(|route| MeteredEndpoint::from_route(route.as_str()));
+    let Some(endpoint) = endpoint else {
+        return next.run(req).await;
+    };
+
+    let (mut parts, body) = req.into_parts();
+    if let Ok(claims) = Claims::from_request_parts(&mut parts, &state).await {
+        state.tier_usage.record(claims.subscription_tier, endpoint);
+    }
+    next.run(Request::from_parts(parts, body)).await
+}
@@ -0,0 +1,4 @@
+-- As first deployed
+CREATE TABLE widgets (
+    id INTEGER PRIMARY KEY
+);
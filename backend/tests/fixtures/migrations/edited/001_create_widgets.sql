@@ -0,0 +1,4 @@
+-- Edited in place after deploying, which changes its checksum
+CREATE TABLE widgets (
+    id BIGINT PRIMARY KEY
+);
@@ -1,14 +1,47 @@
 use mindful_code_backend::{
-    config::{Config, Environment},
+    config::{
+        Config, DeferredReminderPolicy, Environment, FeedbackStoreBackend, FeedbackStoreConfig,
+        FlowEngineConfig, FlowWalConfig, KeyRotationConfig, MetricsAuth, PluginSigningConfig,
+        RetentionConfig, TrustedSigner, WalSyncPolicy,
+    },
     services::{
-        flow::FlowDetectionEngine,
-        ml::MLInferenceEngine,
-        wasm::WasmPluginManager,
-        encryption::EncryptionService,
+        engine_pool::FlowEnginePool,
+        flow::{FlowBaseline, FlowDetectionEngine, FlowSampler, KeystrokeHasher},
+        ml::{
+            MLInferenceEngine, ModelRegistry, ProductivityPattern, ProductivityPredictor,
+            TimeDecay, RULE_BASED_MODEL_VERSION, UNSCORED_MODEL_VERSION,
+        },
+        wasm::{PluginVerifier, WasmPluginManager},
+        encryption::{EncryptedData, EncryptionService},
+        export::{flow_states_schema, FlowStateExportRow, FlowStateParquetWriter},
+        feedback::{feedback_store, FeedbackExample},
+        key_rotation::{self, KeyRotationReport},
+        login_security::{GeoLocation, GeoLocator},
+    },
+    handlers::{
+        admin::{self, summarize_migrations, AppliedMigration},
+        auth, dashboard, flow, health, plugins, privacy, sessions, teams, websocket,
+    },
+    error::AppError,
+    models::{
+        admin::{FlowReplayCursor, FlowReplayRequest},
+        flow::{FlowDetectionRequest, FlowStateData, KeystrokeAggregates, UserFlowPreferences},
+        session::{
+            DeleteSessionsRequest, SessionEnvironment, SessionEnvironmentFilter,
+            StartSessionRequest,
+        },
+        team::{
+            AddTeamMembersRequest, CreateTeamRequest, FlowSimilarityQuery,
+            RemoveTeamMembersRequest, SetTeamGoalRequest, TeamGoalMetric, TeamMemberSpec, TeamRole,
+        },
+    },
+    state::{AppState, SessionInfo, MIGRATOR},
+    utils::{
+        auth::{AdminAuth, Claims, SubscriptionTier, UserRole, generate_jwt_token, hash_password, verify_password},
+        response::ResponseFormat,
     },
-    models::flow::{FlowStateData, UserFlowPreferences},
-    utils::auth::{Claims, generate_jwt_token, hash_password, verify_password},
 };
+use axum::response::IntoResponse;
 use quickcheck::{quickcheck, TestResult};
 use std::time::Duration;
 use tokio_test;
@@ -18,17 +51,7 @@ use uuid::Uuid;
 async fn test_flow_detection_engine_performance() {
     let mut engine = FlowDetectionEngine::new();
     
-    let flow_data = FlowStateData {
-        session_id: Uuid::new_v4(),
-        keystroke_intervals: vec![120, 135, 98, 142, 156, 89, 167, 134, 145, 123],
-        context_switches: 2,
-        error_events: 1,
-        window_focus_duration: 30000,
-        file_modifications: 5,
-        timestamp: chrono::Utc::now().timestamp_millis(),
-        typing_velocity: Some(250.0),
-        pause_patterns: None,
-    };
+    let flow_data = sample_flow_data();
 
     let start = std::time::Instant::now();
     let result = engine.analyze_flow_state(flow_data, None).await;
@@ -59,15 +82,13 @@ async fn test_concurrent_flow_detection() {
         let handle = tokio::spawn(async move {
             let mut engine = FlowDetectionEngine::new();
             let flow_data = FlowStateData {
-                session_id: Uuid::new_v4(),
                 keystroke_intervals: vec![100, 120, 95, 130, 110, 140, 105, 125, 115, 135],
                 context_switches: 1,
                 error_events: 0,
                 window_focus_duration: 25000,
                 file_modifications: 3,
-                timestamp: chrono::Utc::now().timestamp_millis(),
                 typing_velocity: Some(275.0),
-                pause_patterns: None,
+                ..sample_flow_data()
             };
 
             engine.analyze_flow_state(flow_data, None).await
@@ -106,6 +127,25 @@ async fn test_ml_inference_engine() {
     assert!(score >= 0.0 && score <= 1.0);
 }
 
+#[cfg(feature = "onnx")]
+#[tokio::test]
+async fn test_onnx_model_inference() {
+    let model_path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/flow_model.onnx");
+    let ml_engine = MLInferenceEngine::from_model_path(Some(model_path));
+    assert!(ml_engine.is_model_loaded());
+
+    let score = ml_engine
+        .predict_flow_state([0.8, 0.7, 0.6, 0.1, 0.9])
+        .await
+        .unwrap();
+    assert!(score > 0.5 && score <= 1.0);
+
+    // An unreadable model falls back instead of failing
+    let fallback = MLInferenceEngine::from_model_path(Some("/nonexistent/flow_model.onnx"));
+    assert!(!fallback.is_model_loaded());
+    assert!(fallback.predict_flow_state([0.8, 0.7, 0.6, 0.1, 0.9]).await.is_ok());
+}
+
 #[tokio::test]
 async fn test_wasm_plugin_manager() {
     let wasm_manager = WasmPluginManager::new();
@@ -116,6 +156,101 @@ async fn test_wasm_plugin_manager() {
     assert!(loaded_plugins.is_empty()); // No plugins loaded initially
 }
 
+#[tokio::test]
+async fn test_plugin_signatures_are_verified_before_loading() {
+    use ed25519_dalek::{Signer, SigningKey};
+
+    // Smallest valid module, and the same module with a custom section added
+    let plugin = b"\0asm\x01\0\0\0".to_vec();
+    let mut tampered = plugin.clone();
+    tampered.extend_from_slice(&[0x00, 0x04, 0x03, b'e', b'v', b'l']);
+
+    let signing_key = SigningKey::from_bytes(&[7u8; 32]);
+    let signature = hex::encode(signing_key.sign(&plugin).to_bytes());
+    let stranger = SigningKey::from_bytes(&[9u8; 32]);
+    let stranger_signature = hex::encode(stranger.sign(&plugin).to_bytes());
+
+    let verifier = PluginVerifier::from_config(&PluginSigningConfig {
+        require_signatures: true,
+        trusted_signers: vec![TrustedSigner {
+            name: "mindful-code".to_string(),
+            public_key: hex::encode(signing_key.verifying_key().to_bytes()),
+        }],
+    })
+    .unwrap();
+    let manager = WasmPluginManager::new().unwrap().with_verifier(verifier);
+
+    manager
+        .load_plugin_bytes(&plugin, Some(&signature), "signed".to_string())
+        .unwrap();
+    let loaded = manager.get_loaded_plugins();
+    assert_eq!(loaded.len(), 1);
+    assert_eq!(loaded[0].signer.as_deref(), Some("mindful-code"));
+
+    for (bytes, signature) in [
+        (&tampered, Some(signature.as_str())),
+        (&plugin, None),
+        (&plugin, Some(stranger_signature.as_str())),
+        (&plugin, Some("not-hex")),
+    ] {
+        let result = manager.load_plugin_bytes(bytes, signature, "rejected".to_string());
+        assert!(result.is_err());
+    }
+    assert_eq!(manager.get_loaded_plugins().len(), 1);
+
+    // Verification is opt-in; without it plugins load unattributed
+    let permissive = WasmPluginManager::new().unwrap();
+    permissive
+        .load_plugin_bytes(&tampered, None, "unsigned".to_string())
+        .unwrap();
+    assert_eq!(permissive.get_loaded_plugins()[0].signer, None);
+}
+
+#[tokio::test]
+async fn test_plugin_loads_are_limited_by_count_and_memory() {
+    use mindful_code_backend::config::PluginLimitsConfig;
+
+    let plugin = b"\0asm\x01\0\0\0".to_vec();
+    let manager = WasmPluginManager::new()
+        .unwrap()
+        .with_limits(PluginLimitsConfig {
+            max_loaded: 2,
+            ..PluginLimitsConfig::default()
+        });
+    let load = |name: &str| manager.load_plugin_bytes(&plugin, None, name.to_string());
+
+    load("first").unwrap();
+    load("second").unwrap();
+    let refused = load("third").unwrap_err();
+    assert!(matches!(refused, AppError::Conflict(_)));
+    assert_eq!(manager.get_loaded_plugins().len(), 2);
+    // Refused before compiling, so even bytes that wouldn't compile get the
+    // limit error
+    let refused = manager
+        .load_plugin_bytes(b"not wasm", None, "third".to_string())
+        .unwrap_err();
+    assert!(matches!(refused, AppError::Conflict(_)));
+    assert!(manager.loaded_module_bytes() >= 2 * plugin.len());
+
+    // Reloading under a taken name replaces rather than adds
+    load("second").unwrap();
+    manager.unload_plugin("first").await.unwrap();
+    load("third").unwrap();
+    assert_eq!(manager.get_loaded_plugins().len(), 2);
+
+    let starved = WasmPluginManager::new()
+        .unwrap()
+        .with_limits(PluginLimitsConfig {
+            max_module_bytes: 1,
+            ..PluginLimitsConfig::default()
+        });
+    let refused = starved
+        .load_plugin_bytes(&plugin, None, "too-big".to_string())
+        .unwrap_err();
+    assert!(matches!(refused, AppError::Conflict(_)));
+    assert_eq!(starved.loaded_module_bytes(), 0);
+}
+
 #[tokio::test]
 async fn test_encryption_service() {
     let master_key = EncryptionService::generate_master_key();
@@ -201,15 +336,10 @@ fn prop_flow_intensity_bounds(
         let mut engine = FlowDetectionEngine::new();
         
         let flow_data = FlowStateData {
-            session_id: Uuid::new_v4(),
             keystroke_intervals: keystroke_intervals.into_iter().map(|x| x as u64).collect(),
             context_switches: context_switches as u32,
             error_events: error_events as u32,
-            window_focus_duration: 30000,
-            file_modifications: 5,
-            timestamp: chrono::Utc::now().timestamp_millis(),
-            typing_velocity: Some(250.0),
-            pause_patterns: None,
+            ..sample_flow_data()
         };
         
         let result = engine.analyze_flow_state(flow_data, None).await;
@@ -238,15 +368,13 @@ fn prop_keystroke_analysis_consistency(intervals: Vec<u8>) -> TestResult {
         let mut engine = FlowDetectionEngine::new();
         
         let flow_data = FlowStateData {
-            session_id: Uuid::new_v4(),
             keystroke_intervals: intervals.into_iter().map(|x| x as u64 + 50).collect(),
             context_switches: 1,
             error_events: 0,
             window_focus_duration: 20000,
             file_modifications: 2,
-            timestamp: chrono::Utc::now().timestamp_millis(),
             typing_velocity: Some(200.0),
-            pause_patterns: None,
+            ..sample_flow_data()
         };
         
         let result1 = engine.analyze_flow_state(flow_data.clone(), None).await;
@@ -288,15 +416,12 @@ async fn test_memory_efficiency() {
     
     // Process data with all engines
     let flow_data = FlowStateData {
-        session_id: Uuid::new_v4(),
         keystroke_intervals: vec![100; 20],
         context_switches: 1,
         error_events: 0,
         window_focus_duration: 15000,
         file_modifications: 2,
-        timestamp: chrono::Utc::now().timestamp_millis(),
-        typing_velocity: Some(250.0),
-        pause_patterns: None,
+        ..sample_flow_data()
     };
     
     let mut handles = Vec::new();
@@ -329,15 +454,13 @@ async fn test_error_handling() {
     
     // Test with invalid data
     let invalid_flow_data = FlowStateData {
-        session_id: Uuid::new_v4(),
         keystroke_intervals: vec![], // Empty intervals should be handled gracefully
         context_switches: 0,
         error_events: 0,
         window_focus_duration: 0,
         file_modifications: 0,
-        timestamp: chrono::Utc::now().timestamp_millis(),
         typing_velocity: None,
-        pause_patterns: None,
+        ..sample_flow_data()
     };
     
     let result = engine.analyze_flow_state(invalid_flow_data, None).await;
@@ -347,6 +470,243 @@ async fn test_error_handling() {
     assert!(flow_result.flow_intensity >= 0.0); // Should return valid bounds
 }
 
+#[tokio::test]
+async fn test_strict_mode_rejects_malformed_samples() {
+    let empty = || FlowStateData {
+        keystroke_intervals: vec![],
+        context_switches: 0,
+        error_events: 0,
+        window_focus_duration: 0,
+        file_modifications: 0,
+        typing_velocity: None,
+        ..sample_flow_data()
+    };
+
+    let mut lenient = FlowDetectionEngine::new();
+    assert!(lenient.analyze_flow_state(empty(), None).await.is_ok());
+
+    let strict_config = FlowEngineConfig {
+        strict_validation: true,
+        ..FlowEngineConfig::default()
+    };
+    let mut strict =
+        FlowDetectionEngine::with_config(strict_config.clone(), MLInferenceEngine::new());
+    let rejected = strict.analyze_flow_state(empty(), None).await.unwrap_err();
+    assert!(matches!(rejected, AppError::Validation(_)));
+
+    // A request can opt out of the server default, or into strictness
+    strict.set_strict_validation(Some(false));
+    assert!(strict.analyze_flow_state(empty(), None).await.is_ok());
+    lenient.set_strict_validation(Some(true));
+    assert!(lenient.analyze_flow_state(empty(), None).await.is_err());
+
+    let typed = |typing_velocity: f32, window_focus_duration: u64| FlowStateData {
+        keystroke_intervals: vec![120, 135, 98, 142, 156, 89, 167, 134, 145, 123],
+        window_focus_duration,
+        file_modifications: 5,
+        typing_velocity: Some(typing_velocity),
+        ..empty()
+    };
+    let mut strict = FlowDetectionEngine::with_config(strict_config, MLInferenceEngine::new());
+    assert!(strict
+        .analyze_flow_state(typed(250.0, 30000), None)
+        .await
+        .is_ok());
+    // Edits with no time in the editor, and a velocity ~10x what the
+    // ~130ms intervals allow
+    assert!(strict
+        .analyze_flow_state(typed(250.0, 0), None)
+        .await
+        .is_err());
+    assert!(strict
+        .analyze_flow_state(typed(4500.0, 30000), None)
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_short_rhythm_window_sets_recent_consistency() {
+    // 30 erratic intervals (mean 200, cv 0.75) followed by 10 steady ones
+    let mut intervals: Vec<u64> = [50, 350].iter().cycle().take(30).copied().collect();
+    intervals.extend([200; 10]);
+
+    let consistency = |short_window: usize| {
+        let intervals = intervals.clone();
+        async move {
+            let config = FlowEngineConfig {
+                rhythm_short_window: short_window,
+                ..FlowEngineConfig::default()
+            };
+            let mut engine = FlowDetectionEngine::with_config(config, MLInferenceEngine::new());
+            let data = FlowStateData {
+                keystroke_intervals: intervals,
+                context_switches: 0,
+                error_events: 0,
+                window_focus_duration: 60_000,
+                file_modifications: 0,
+                typing_velocity: None,
+                ..sample_flow_data()
+            };
+            engine
+                .analyze_flow_state(data, None)
+                .await
+                .unwrap()
+                .metrics
+                .consistency_score
+        }
+    };
+
+    // Only the steady tail: no variation at all
+    assert!((consistency(10).await - 1.0).abs() < 1e-3);
+    // Ten erratic and ten steady: cv = sqrt(11250) / 200
+    assert!((consistency(20).await - (1.0 - 11250f32.sqrt() / 200.0)).abs() < 1e-3);
+    // The whole buffer is no more recent than the long window, so the
+    // overall variation counts: cv = sqrt(16875) / 200
+    assert!((consistency(40).await - (1.0 - 16875f32.sqrt() / 200.0)).abs() < 1e-3);
+}
+
+#[tokio::test]
+async fn test_computed_metrics_match_a_fresh_engine() {
+    use mindful_code_backend::models::flow::FlowMetrics;
+
+    let sample = |keystroke_intervals: Vec<u64>, aggregates| FlowStateData {
+        keystroke_intervals,
+        context_switches: 3,
+        error_events: 2,
+        window_focus_duration: 10_000,
+        file_modifications: 8,
+        typing_velocity: None,
+        aggregates,
+        ..sample_flow_data()
+    };
+    let erratic_then_steady: Vec<u64> = [50, 350]
+        .iter()
+        .cycle()
+        .take(130)
+        .copied()
+        .chain([200; 20])
+        .collect();
+    let samples = [
+        sample(vec![120, 135, 98, 142, 156, 89, 167, 134, 145, 123], None),
+        // Longer than the keystroke buffer holds
+        sample(erratic_then_steady.clone(), None),
+        // Summary only, as minimized clients send
+        sample(
+            Vec::new(),
+            KeystrokeAggregates::from_intervals(&erratic_then_steady),
+        ),
+    ];
+    let configs = [
+        FlowEngineConfig::default(),
+        FlowEngineConfig {
+            rhythm_short_window: 5,
+            rhythm_long_window: 30,
+            ..FlowEngineConfig::default()
+        },
+    ];
+
+    for config in &configs {
+        for data in &samples {
+            let computed = FlowMetrics::compute(data, config);
+            let mut engine =
+                FlowDetectionEngine::with_config(config.clone(), MLInferenceEngine::new());
+            let analyzed = engine
+                .analyze_flow_state(data.clone(), None)
+                .await
+                .unwrap()
+                .metrics;
+            assert_eq!(computed, analyzed);
+        }
+    }
+
+    // Scoring is pure: the same sample always gets the same metrics
+    let config = &configs[0];
+    assert_eq!(
+        FlowMetrics::compute(&samples[0], config),
+        FlowMetrics::compute(&samples[0], config)
+    );
+    let metrics = FlowMetrics::compute(&samples[0], config);
+    assert!((metrics.focus_score - (-0.6f32).exp()).abs() < 1e-6);
+    assert!((metrics.error_penalty - 2f32.ln() / 10.0).abs() < 1e-6);
+}
+
+#[tokio::test]
+async fn test_pooled_engines_score_like_fresh_ones() {
+    let now = chrono::Utc::now().timestamp_millis();
+    let samples: Vec<FlowStateData> = (0..8)
+        .map(|i| FlowStateData {
+            session_id: Uuid::from_u128(1),
+            keystroke_intervals: vec![110 + i * 7, 125, 98 + i * 3, 142, 120, 131, 118, 127],
+            context_switches: (i % 3) as u32,
+            error_events: (i % 2) as u32,
+            window_focus_duration: 30_000 + i * 1_000,
+            file_modifications: 3,
+            timestamp: now + i as i64 * 1_000,
+            typing_velocity: Some(240.0 + i as f32 * 5.0),
+            ..sample_flow_data()
+        })
+        .collect();
+
+    async fn score_all(
+        engine: &mut FlowDetectionEngine,
+        samples: &[FlowStateData],
+    ) -> Vec<serde_json::Value> {
+        let mut results = Vec::new();
+        for sample in samples {
+            let mut result = engine
+                .analyze_flow_state(sample.clone(), None)
+                .await
+                .unwrap();
+            // Wall-clock timings, not scores
+            result.analysis_time_ms = 0.0;
+            result.flow_duration_ms = 0;
+            results.push(serde_json::to_value(&result).unwrap());
+        }
+        results
+    }
+
+    let expected = score_all(&mut FlowDetectionEngine::new(), &samples).await;
+
+    let registry_dir = std::env::temp_dir().join(format!("model_registry_{}", Uuid::new_v4()));
+    std::fs::create_dir_all(&registry_dir).unwrap();
+    std::fs::write(
+        registry_dir.join("flat.json"),
+        r#"{"weights": [0.0, 0.0, 0.0, 0.0, 0.0], "bias": 0.9}"#,
+    )
+    .unwrap();
+    let pinned = ModelRegistry::new(Some(registry_dir.to_str().unwrap()))
+        .load("flat")
+        .unwrap();
+
+    // Leave every kind of state behind on a pooled engine, including a
+    // backtest's pinned model
+    let pool = std::sync::Arc::new(FlowEnginePool::new(1, 4));
+    {
+        let mut engine = pool.acquire(FlowDetectionEngine::new);
+        engine.pin_model(pinned);
+        engine.record_interruption();
+        engine.set_scoring_flags(mindful_code_backend::services::flow::ScoringFlags {
+            hysteresis: true,
+            ema_smoothing: true,
+        });
+        let mut baseline = FlowBaseline::default();
+        for intensity in [0.2, 0.3, 0.25].iter().cycle().take(40) {
+            baseline.update(*intensity);
+        }
+        engine.restore_baseline(baseline);
+        let scored = score_all(&mut engine, &samples).await;
+        assert_eq!(scored[0]["model_version"], "flat");
+    }
+    assert_eq!(pool.idle(), 1);
+    std::fs::remove_dir_all(registry_dir).unwrap();
+
+    // The engine handed out next is that one, and scores as if new
+    let mut reused = pool.acquire(|| panic!("an idle engine should be reused"));
+    assert_eq!(pool.idle(), 0);
+    assert_eq!(reused.baseline(), FlowBaseline::default());
+    assert_eq!(score_all(&mut reused, &samples).await, expected);
+}
+
 #[tokio::test]
 async fn test_high_load_stability() {
     let high_load_requests = 10000;
@@ -354,19 +714,20 @@ async fn test_high_load_stability() {
     
     let mut handles = Vec::new();
     
+    // Stateless requests, so engines are reused instead of built per task
+    let pool = std::sync::Arc::new(FlowEnginePool::new(4, 64));
     for i in 0..high_load_requests {
+        let pool = pool.clone();
         let handle = tokio::spawn(async move {
-            let mut engine = FlowDetectionEngine::new();
+            let mut engine = pool.acquire(FlowDetectionEngine::new);
             let flow_data = FlowStateData {
-                session_id: Uuid::new_v4(),
                 keystroke_intervals: vec![100 + (i % 50) as u64; 10],
                 context_switches: (i % 5) as u32,
                 error_events: (i % 3) as u32,
                 window_focus_duration: 10000 + (i * 100) as u64,
                 file_modifications: (i % 10) as u32,
-                timestamp: chrono::Utc::now().timestamp_millis(),
                 typing_velocity: Some(200.0 + (i % 100) as f32),
-                pause_patterns: None,
+                ..sample_flow_data()
             };
             
             engine.analyze_flow_state(flow_data, None).await
@@ -405,6 +766,6095 @@ async fn test_high_load_stability() {
            "RPS {:.0} is below 1000 threshold", rps);
 }
 
+#[test]
+fn test_flow_sampler_keeps_keyframes_and_transitions() {
+    let sampler = FlowSampler::new(Duration::from_secs(30));
+    let session_id = Uuid::new_v4();
+    let start = std::time::Instant::now();
+
+    // Rapid analyses within the interval collapse into a single keyframe
+    let persisted = (0..10)
+        .filter(|i| sampler.should_persist_at(session_id, false, start + Duration::from_secs(*i)))
+        .count();
+    assert_eq!(persisted, 1);
+
+    // Entering and leaving flow are persisted even inside the interval
+    assert!(sampler.should_persist_at(session_id, true, start + Duration::from_secs(11)));
+    assert!(!sampler.should_persist_at(session_id, true, start + Duration::from_secs(12)));
+    assert!(sampler.should_persist_at(session_id, false, start + Duration::from_secs(13)));
+
+    // Once the interval elapses a new keyframe is written
+    assert!(sampler.should_persist_at(session_id, false, start + Duration::from_secs(44)));
+}
+
+#[tokio::test]
+async fn test_interruption_dampens_flow_intensity() {
+    let flow_data = FlowStateData {
+        keystroke_intervals: vec![120, 125, 118, 122, 130, 119, 121, 124, 117, 123],
+        context_switches: 0,
+        error_events: 0,
+        window_focus_duration: 600000,
+        file_modifications: 4,
+        typing_velocity: Some(280.0),
+        ..sample_flow_data()
+    };
+
+    let mut baseline = FlowDetectionEngine::new();
+    let mut interrupted = FlowDetectionEngine::new();
+    interrupted.record_interruption();
+
+    let baseline_result = baseline.analyze_flow_state(flow_data.clone(), None).await.unwrap();
+    let interrupted_result = interrupted.analyze_flow_state(flow_data, None).await.unwrap();
+
+    assert!(interrupted_result.flow_intensity < baseline_result.flow_intensity,
+           "Recent interruption should lower flow intensity ({} vs {})",
+           interrupted_result.flow_intensity, baseline_result.flow_intensity);
+}
+
+#[tokio::test]
+async fn test_relative_flow_score_against_baseline() {
+    let flow_data = FlowStateData {
+        keystroke_intervals: vec![120, 125, 118, 122, 130, 119, 121, 124, 117, 123],
+        context_switches: 0,
+        error_events: 0,
+        window_focus_duration: 600000,
+        file_modifications: 4,
+        typing_velocity: Some(280.0),
+        ..sample_flow_data()
+    };
+
+    // Cold start: no history to compare against yet
+    let mut engine = FlowDetectionEngine::new();
+    let result = engine.analyze_flow_state(flow_data.clone(), None).await.unwrap();
+    assert!(result.relative_flow_score.is_none());
+
+    // A user whose normal is low-intensity sees this session as above baseline
+    let mut engine = FlowDetectionEngine::new();
+    let mut baseline = FlowBaseline::default();
+    for intensity in [0.15, 0.25, 0.2, 0.3, 0.1].iter().cycle().take(50) {
+        baseline.update(*intensity);
+    }
+    engine.restore_baseline(baseline);
+
+    let result = engine.analyze_flow_state(flow_data, None).await.unwrap();
+    let relative = result.relative_flow_score.expect("baseline should be warm");
+    assert!(relative > 0.0,
+           "Intensity {} above a ~0.2 baseline should be positive, got {}",
+           result.flow_intensity, relative);
+    assert_eq!(engine.baseline().sample_count, 51);
+}
+
+#[tokio::test]
+async fn test_sustained_focus_dip_alerts_once_per_window() {
+    let config = FlowEngineConfig {
+        intensity_drop_z: -1.5,
+        intensity_drop_window_secs: 300,
+        ..FlowEngineConfig::default()
+    };
+    let mut engine = FlowDetectionEngine::with_config(config, MLInferenceEngine::new());
+    let mut baseline = FlowBaseline::default();
+    for intensity in [0.93, 0.95, 0.97].iter().cycle().take(60) {
+        baseline.update(*intensity);
+    }
+    engine.restore_baseline(baseline);
+
+    // Erratic, distracted typing, far below this user's usual intensity
+    let session_id = Uuid::new_v4();
+    let start = std::time::Instant::now();
+    for offset_ms in [0, 1_000, 2_000, 3_000] {
+        let result = engine
+            .analyze_flow_state(
+                FlowStateData {
+                    session_id,
+                    keystroke_intervals: vec![40, 900, 75, 1400, 60, 2100, 35, 1800, 90, 2500],
+                    context_switches: 12,
+                    error_events: 8,
+                    window_focus_duration: 5000,
+                    file_modifications: 0,
+                    timestamp: chrono::Utc::now().timestamp_millis() + offset_ms,
+                    typing_velocity: Some(40.0),
+                    ..sample_flow_data()
+                },
+                None,
+            )
+            .await
+            .unwrap();
+        assert!(result.relative_flow_score.unwrap() <= -1.5, "{:?}", result);
+    }
+
+    // A dip shorter than the window isn't worth mentioning
+    assert!(!engine.focus_dip_due_at(start));
+    assert!(!engine.focus_dip_due_at(start + Duration::from_secs(120)));
+
+    // Once it has lasted the window, one alert, however often it's checked
+    let alerts = (0..30)
+        .filter(|i| engine.focus_dip_due_at(start + Duration::from_secs(301 + i * 9)))
+        .count();
+    assert_eq!(alerts, 1);
+}
+
+#[test]
+fn test_default_preferences_by_subscription_tier() {
+    let config = FlowEngineConfig {
+        default_sensitivity: 0.65,
+        premium_default_sensitivity: 0.75,
+        ..FlowEngineConfig::default()
+    };
+
+    let free = config.default_preferences(SubscriptionTier::Free);
+    assert_eq!(free.sensitivity_level, 0.65);
+    assert!(!free.personalized_calibration);
+
+    let premium = config.default_preferences(SubscriptionTier::Premium);
+    assert_eq!(premium.sensitivity_level, 0.75);
+    assert!(premium.personalized_calibration);
+
+    // Higher tiers inherit the premium defaults
+    let team = config.default_preferences(SubscriptionTier::Team);
+    assert_eq!(team.sensitivity_level, 0.75);
+}
+
+#[tokio::test]
+async fn test_enveloped_response_wraps_flow_result() {
+    let mut engine = FlowDetectionEngine::new();
+    let flow_data = sample_flow_data();
+    let flow_result = engine.analyze_flow_state(flow_data, None).await.unwrap();
+
+    let response = ResponseFormat::new(true, Some("req-123".to_string()))
+        .respond(flow_result.clone())
+        .into_response();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+    assert_eq!(json["data"]["is_in_flow"], flow_result.is_in_flow);
+    assert_eq!(json["meta"]["request_id"], "req-123");
+    assert!(json["meta"]["duration_ms"].as_f64().unwrap() >= 0.0);
+
+    // Existing clients keep receiving the bare result
+    let response = ResponseFormat::default().respond(flow_result).into_response();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert!(json.get("data").is_none());
+    assert!(json.get("flow_intensity").is_some());
+}
+
+#[tokio::test]
+async fn test_score_precision_rounds_serialized_scores_only() {
+    let mut engine = FlowDetectionEngine::new();
+    let flow_data = sample_flow_data();
+    let mut flow_result = engine.analyze_flow_state(flow_data, None).await.unwrap();
+    flow_result.flow_intensity = 0.8333333;
+
+    let response = ResponseFormat::default()
+        .with_score_decimal_places(Some(2))
+        .respond(flow_result.clone())
+        .into_response();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+    let decimals = |value: &serde_json::Value| {
+        value.to_string().split_once('.').map_or(0, |(_, fraction)| fraction.len())
+    };
+    assert_eq!(json["flow_intensity"].as_f64(), Some(0.83));
+    assert!(decimals(&json["confidence"]) <= 2);
+    assert!(decimals(&json["data_quality"]) <= 2);
+
+    // The engine's value keeps full precision, as does serialization outside a response
+    assert_eq!(flow_result.flow_intensity, 0.8333333);
+    let raw = serde_json::to_value(&flow_result).unwrap();
+    assert!(decimals(&raw["flow_intensity"]) > 2);
+
+    // Unconfigured responses are unrounded
+    let response = ResponseFormat::default().respond(flow_result).into_response();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert!(decimals(&json["flow_intensity"]) > 2);
+}
+
+#[sqlx::test(migrator = "mindful_code_backend::state::MIGRATOR")]
+async fn test_low_sample_analyses_marked_low_quality(db: sqlx::PgPool) {
+    use axum::{extract::State, Json};
+
+    let user_id: Uuid = sqlx::query_scalar(
+        "INSERT INTO users (email, password_hash) VALUES ('quality@example.com', 'x') RETURNING id",
+    )
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    let mut engine = FlowDetectionEngine::new();
+    let mut flow_data = FlowStateData {
+        keystroke_intervals: vec![120, 135, 98, 142],
+        context_switches: 0,
+        error_events: 0,
+        file_modifications: 1,
+        ..sample_flow_data()
+    };
+
+    let early = engine
+        .analyze_flow_state(flow_data.clone(), None)
+        .await
+        .unwrap();
+    flow_data.keystroke_intervals = vec![120, 135, 98, 142, 156, 89, 167, 134, 145, 123];
+    let warm = engine.analyze_flow_state(flow_data, None).await.unwrap();
+
+    assert_eq!(early.data_quality, 0.5);
+    assert_eq!(warm.data_quality, 1.0);
+
+    // One session per sample, so the session count shows which were kept
+    for result in [early, warm] {
+        let session_id: Uuid = sqlx::query_scalar(
+            "INSERT INTO coding_sessions (user_id, start_time) VALUES ($1, NOW()) RETURNING id",
+        )
+        .bind(user_id)
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        let write = flow::PendingFlowWrite {
+            write_id: Uuid::new_v4(),
+            user_id,
+            session_id,
+            result,
+            keystroke_hash: String::new(),
+            focus_mode: false,
+            baseline_samples: engine.take_unsaved_baseline(),
+        };
+        flow::persist_flow_write(&db, &write, false).await.unwrap();
+    }
+
+    let state = AppState::from_pools(Config::from_env().unwrap(), db.clone(), None);
+    let claims = Claims::new(
+        user_id,
+        "quality@example.com".to_string(),
+        "premium".to_string(),
+    );
+    let sessions_counted = |query: &str| {
+        let query: flow::FlowAnalyticsQuery = serde_json::from_str(query).unwrap();
+        let analytics = flow::get_flow_analytics(
+            State(state.clone()),
+            claims.clone(),
+            ResponseFormat::default(),
+            Json(query),
+        );
+        async move {
+            analytics
+                .await
+                .unwrap()
+                .into_data()
+                .unwrap()
+                .flow_sessions_count
+        }
+    };
+
+    assert_eq!(sessions_counted(r#"{"days": 7}"#).await, 2);
+    // A quality floor keeps the warm sample and drops the early one
+    assert_eq!(
+        sessions_counted(r#"{"days": 7, "min_data_quality": 0.8}"#).await,
+        1
+    );
+}
+
+#[tokio::test]
+async fn test_anonymous_token_detects_without_history_access() {
+    // Lazy pool: the anonymous path must never touch the database
+    let db = sqlx::postgres::PgPoolOptions::new()
+        .connect_lazy("postgresql://localhost/mindful_code")
+        .unwrap();
+    let state = AppState::from_pools(Config::from_env().unwrap(), db, None);
+    let claims = Claims::anonymous(Uuid::new_v4(), chrono::Duration::minutes(60));
+
+    let request = FlowDetectionRequest {
+        flow_data: sample_flow_data(),
+        user_preferences: None,
+    };
+
+    let result = flow::detect_flow_state(
+        axum::extract::State(state.clone()),
+        claims.clone(),
+        ResponseFormat::default(),
+        axum::extract::Query(Default::default()),
+        axum::Json(flow::FlowDetectionPayload { request }),
+    )
+    .await;
+    assert!(result.is_ok());
+    assert_eq!(state.user_flow_engines(claims.user_id).len(), 1);
+
+    let insights = flow::get_flow_insights(
+        axum::extract::State(state.clone()),
+        claims.clone(),
+        ResponseFormat::default(),
+    )
+    .await;
+    assert!(matches!(insights, Err(mindful_code_backend::error::AppError::Authorization(_))));
+
+    let history = flow::get_flow_patterns(
+        axum::extract::State(state),
+        claims,
+        ResponseFormat::default(),
+        axum::extract::Query(Default::default()),
+    )
+    .await;
+    assert!(matches!(history, Err(mindful_code_backend::error::AppError::Authorization(_))));
+}
+
+#[tokio::test]
+async fn test_free_tier_is_refused_every_premium_route() {
+    // Lazy pool: the tier check comes before any query
+    let db = sqlx::postgres::PgPoolOptions::new()
+        .connect_lazy("postgresql://localhost/mindful_code")
+        .unwrap();
+    let state = AppState::from_pools(Config::from_env().unwrap(), db, None);
+    let claims = Claims::new(
+        Uuid::new_v4(),
+        "free@example.com".to_string(),
+        "free".to_string(),
+    );
+    fn assert_upgrade_required<T>(route: &str, result: Result<T, AppError>) {
+        let Err(refused) = result else {
+            panic!("{} was served to a free user", route);
+        };
+        assert!(
+            matches!(refused, AppError::Authorization(ref message) if message.contains("Upgrade")),
+            "{} refused a free user with {:?}",
+            route,
+            refused
+        );
+        assert_eq!(
+            refused.into_response().status(),
+            axum::http::StatusCode::FORBIDDEN
+        );
+    }
+
+    assert_upgrade_required(
+        "/api/flow/patterns",
+        flow::get_flow_patterns(
+            axum::extract::State(state.clone()),
+            claims.clone(),
+            ResponseFormat::default(),
+            axum::extract::Query(Default::default()),
+        )
+        .await,
+    );
+    assert_upgrade_required(
+        "/api/flow/insights",
+        flow::get_flow_insights(
+            axum::extract::State(state.clone()),
+            claims.clone(),
+            ResponseFormat::default(),
+        )
+        .await,
+    );
+    assert_upgrade_required(
+        "/api/flow/forecast",
+        flow::get_flow_forecast(
+            axum::extract::State(state.clone()),
+            claims.clone(),
+            ResponseFormat::default(),
+        )
+        .await,
+    );
+    assert_upgrade_required(
+        "/api/flow/session-recommendation",
+        flow::get_session_recommendation(
+            axum::extract::State(state.clone()),
+            claims.clone(),
+            ResponseFormat::default(),
+        )
+        .await,
+    );
+    assert_upgrade_required(
+        "flow analytics",
+        flow::get_flow_analytics(
+            axum::extract::State(state),
+            claims,
+            ResponseFormat::default(),
+            axum::Json(serde_json::from_str(r#"{"days": 7}"#).unwrap()),
+        )
+        .await,
+    );
+}
+
+#[tokio::test]
+async fn test_metrics_scrape_requires_configured_credentials() {
+    let db = sqlx::postgres::PgPoolOptions::new()
+        .connect_lazy("postgresql://localhost/mindful_code")
+        .unwrap();
+    let mut config = Config::from_env().unwrap();
+    config.metrics_auth = MetricsAuth::Bearer {
+        token: "scrape-token".to_string(),
+    };
+    let state = AppState::from_pools(config, db, None);
+
+    let unauthenticated = health::metrics(
+        axum::extract::State(state.clone()),
+        axum::http::HeaderMap::new(),
+    )
+    .await;
+    let response = unauthenticated.unwrap_err().into_response();
+    assert_eq!(response.status(), axum::http::StatusCode::UNAUTHORIZED);
+
+    let mut wrong_token = axum::http::HeaderMap::new();
+    wrong_token.insert("authorization", "Bearer guessed".parse().unwrap());
+    assert!(health::metrics(axum::extract::State(state.clone()), wrong_token).await.is_err());
+
+    let mut headers = axum::http::HeaderMap::new();
+    headers.insert("authorization", "Bearer scrape-token".parse().unwrap());
+    let (status, body) = health::metrics(axum::extract::State(state), headers)
+        .await
+        .unwrap();
+    assert_eq!(status, axum::http::StatusCode::OK);
+    assert!(body.contains("mindful_code_active_sessions"));
+}
+
+#[tokio::test]
+async fn test_flow_forecast_ranks_strong_morning_hours_highest() {
+    use chrono::{TimeZone, Timelike};
+
+    // Strong flow every morning (8-11 UTC), mediocre the rest of the day
+    let mut patterns = Vec::new();
+    for day_of_week in 0..7 {
+        for hour_of_day in 0..24 {
+            let morning = (8..=11).contains(&hour_of_day);
+            patterns.push(ProductivityPattern {
+                hour_of_day,
+                day_of_week,
+                average_flow_score: if morning { 0.9 } else { 0.3 },
+                session_count: if morning { 6 } else { 2 },
+                age_days: 7.0,
+            });
+        }
+    }
+
+    let now = chrono::Utc.with_ymd_and_hms(2024, 3, 6, 15, 20, 0).unwrap();
+    let forecast = flow::forecast_from_patterns(patterns, now, TimeDecay::default()).await;
+
+    assert_eq!(forecast.hours.len(), 24);
+    assert!(!forecast.sparse_history);
+    assert_eq!(forecast.best_windows.len(), 3);
+    for window in &forecast.best_windows {
+        let hour = window.starts_at.hour();
+        assert!((8..=11).contains(&hour), "expected a morning window, got {}:00", hour);
+    }
+
+    let best = forecast.best_windows[0].expected_flow_score;
+    let evening = forecast
+        .hours
+        .iter()
+        .find(|hour| hour.starts_at.hour() == 20)
+        .unwrap();
+    assert!(best > evening.expected_flow_score);
+
+    // No history: neutral scores, flagged sparse, nothing highlighted
+    let empty = flow::forecast_from_patterns(Vec::new(), now, TimeDecay::default()).await;
+    assert!(empty.sparse_history);
+    assert!(empty.best_windows.is_empty());
+    assert!(empty.hours.iter().all(|hour| hour.expected_flow_score == 0.5));
+}
+
+#[tokio::test]
+async fn test_secure_delete_zeroes_buffer() {
+    let master_key = EncryptionService::generate_master_key();
+    let encryption_service = EncryptionService::new(&master_key)
+        .unwrap()
+        .with_secure_delete_passes(5);
+
+    let mut buffer = b"api_key=sk_live_0123456789abcdef".to_vec();
+    encryption_service.secure_delete_data(&mut buffer).await;
+
+    assert_eq!(buffer.len(), 32);
+    assert!(buffer.iter().all(|&byte| byte == 0));
+
+    // Zero passes still zeroes the buffer
+    let no_passes = EncryptionService::new(&master_key)
+        .unwrap()
+        .with_secure_delete_passes(0);
+    let mut buffer = vec![0xAB; 64];
+    no_passes.secure_delete_data(&mut buffer).await;
+    assert!(buffer.iter().all(|&byte| byte == 0));
+}
+
+#[tokio::test]
+async fn test_warm_up_window_suppresses_flow_entry() {
+    let config = FlowEngineConfig {
+        warmup_analyses: 3,
+        ..FlowEngineConfig::default()
+    };
+    let mut engine = FlowDetectionEngine::with_config(config, MLInferenceEngine::new());
+    // Any score counts as flow once warm
+    let preferences = || UserFlowPreferences {
+        sensitivity_level: 0.0,
+        notification_threshold: 0.6,
+        focus_mode_enabled: false,
+        break_reminders_enabled: true,
+        personalized_calibration: false,
+        use_ml: None,
+    };
+    let flow_data = FlowStateData {
+        keystroke_intervals: vec![120, 125, 118, 122, 130, 119, 121, 124, 117, 123],
+        context_switches: 0,
+        error_events: 0,
+        window_focus_duration: 600000,
+        file_modifications: 4,
+        typing_velocity: Some(280.0),
+        ..sample_flow_data()
+    };
+
+    for _ in 0..3 {
+        let result = engine
+            .analyze_flow_state(flow_data.clone(), Some(preferences()))
+            .await
+            .unwrap();
+        assert!(result.warming_up);
+        assert!(!result.is_in_flow);
+        assert_eq!(result.flow_duration_ms, 0);
+    }
+
+    let warm = engine
+        .analyze_flow_state(flow_data.clone(), Some(preferences()))
+        .await
+        .unwrap();
+    assert!(!warm.warming_up);
+    assert!(warm.is_in_flow);
+
+    // A new session warms up again, but doesn't knock an ongoing flow out
+    let next_session = FlowStateData {
+        session_id: Uuid::new_v4(),
+        ..flow_data
+    };
+    let result = engine
+        .analyze_flow_state(next_session, Some(preferences()))
+        .await
+        .unwrap();
+    assert!(result.warming_up);
+    assert!(result.is_in_flow);
+}
+
+#[tokio::test]
+async fn test_flow_states_parquet_export_round_trip() {
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    let session_id = Uuid::new_v4();
+    let started = chrono::Utc::now() - chrono::Duration::hours(1);
+    let rows: Vec<FlowStateExportRow> = (0..25)
+        .map(|i| FlowStateExportRow {
+            id: Uuid::new_v4(),
+            session_id,
+            start_time: started + chrono::Duration::seconds(30 * i),
+            end_time: (i % 2 == 0).then(|| started + chrono::Duration::seconds(30 * i + 20)),
+            duration_ms: (i % 2 == 0).then_some(20_000),
+            intensity_score: 0.5 + (i as f64) / 100.0,
+            context_switches: Some(i as i32 % 4),
+            confidence_score: Some(0.8),
+            data_quality: None,
+        })
+        .collect();
+
+    let path = std::env::temp_dir().join(format!("flow_states_{}.parquet", Uuid::new_v4()));
+    let file = std::fs::File::create(&path).unwrap();
+
+    // Written page by page, as the export streams from the database
+    let mut writer = FlowStateParquetWriter::new(file, 10).unwrap();
+    for page in rows.chunks(10) {
+        writer.write_row_group(page).unwrap();
+    }
+    let (_, rows_written) = writer.finish().unwrap();
+    assert_eq!(rows_written, 25);
+
+    let reader = ParquetRecordBatchReaderBuilder::try_new(std::fs::File::open(&path).unwrap())
+        .unwrap();
+    assert_eq!(reader.schema().as_ref(), flow_states_schema().as_ref());
+    assert_eq!(reader.metadata().num_row_groups(), 3);
+    assert_eq!(reader.metadata().file_metadata().num_rows(), 25);
+
+    let rows_read: usize = reader
+        .build()
+        .unwrap()
+        .map(|batch| batch.unwrap().num_rows())
+        .sum();
+    assert_eq!(rows_read, 25);
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn test_sealed_key_backup_requires_passphrase() {
+    let master_key = EncryptionService::generate_master_key();
+    let encryption_service = EncryptionService::new(&master_key).unwrap();
+
+    let backup = encryption_service
+        .seal_key_backup("correct horse battery staple")
+        .unwrap();
+    assert_eq!(backup.key_id, encryption_service.get_current_key_id());
+    assert!(!backup.sealed_key.contains(&EncryptionService::key_to_hex(&master_key)));
+
+    let unsealed =
+        EncryptionService::unseal_key_backup(&backup, "correct horse battery staple").unwrap();
+    assert_eq!(*unsealed, *master_key);
+
+    assert!(EncryptionService::unseal_key_backup(&backup, "wrong horse battery staple").is_err());
+
+    // Relabelling the backup as another key breaks the seal
+    let mut relabelled = backup.clone();
+    relabelled.key_id = "key_0".to_string();
+    assert!(
+        EncryptionService::unseal_key_backup(&relabelled, "correct horse battery staple").is_err()
+    );
+
+    assert!(encryption_service.seal_key_backup("short").is_err());
+}
+
+#[tokio::test]
+async fn test_retried_flow_detection_is_deduplicated() {
+    let db = sqlx::postgres::PgPoolOptions::new()
+        .connect_lazy("postgresql://localhost/mindful_code")
+        .unwrap();
+    let state = AppState::from_pools(Config::from_env().unwrap(), db, None);
+    // Anonymous claims keep the handler off the database
+    let claims = Claims::anonymous(Uuid::new_v4(), chrono::Duration::minutes(60));
+
+    let request = || FlowDetectionRequest {
+        flow_data: FlowStateData {
+            session_id: Uuid::nil(),
+            timestamp: 1_700_000_000_000,
+            ..sample_flow_data()
+        },
+        user_preferences: None,
+    };
+
+    let mut bodies = Vec::new();
+    for _ in 0..2 {
+        let response = flow::detect_flow_state(
+            axum::extract::State(state.clone()),
+            claims.clone(),
+            ResponseFormat::default(),
+            axum::extract::Query(Default::default()),
+            axum::Json(flow::FlowDetectionPayload { request: request() }),
+        )
+        .await
+        .unwrap()
+        .into_response();
+        bodies.push(axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap());
+    }
+
+    // Byte-identical, down to analysis_time_ms
+    assert_eq!(bodies[0], bodies[1]);
+
+    // Only the first request was analysed, so only it could be persisted
+    let engine = state.get_or_create_flow_engine(claims.user_id, Uuid::nil());
+    assert_eq!(engine.read().baseline().sample_count, 1);
+}
+
+#[sqlx::test(migrator = "mindful_code_backend::state::MIGRATOR")]
+async fn test_retried_flow_detection_is_stored_once_for_registered_users(db: sqlx::PgPool) {
+    let user_id: Uuid = sqlx::query_scalar(
+        "INSERT INTO users (email, password_hash) VALUES ('retry@example.com', 'x') RETURNING id",
+    )
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    let session_id: Uuid = sqlx::query_scalar(
+        "INSERT INTO coding_sessions (user_id, start_time) VALUES ($1, NOW()) RETURNING id",
+    )
+    .bind(user_id)
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    let state = AppState::from_pools(Config::from_env().unwrap(), db.clone(), None);
+    let claims = Claims::new(
+        user_id,
+        "retry@example.com".to_string(),
+        "premium".to_string(),
+    );
+    let (sender, mut updates) = tokio::sync::mpsc::unbounded_channel();
+    state.add_websocket_connection(user_id, sender, false);
+
+    let timestamp = chrono::Utc::now().timestamp_millis();
+    let request = || FlowDetectionRequest {
+        flow_data: FlowStateData {
+            session_id,
+            timestamp,
+            ..sample_flow_data()
+        },
+        user_preferences: None,
+    };
+
+    let mut bodies = Vec::new();
+    for _ in 0..2 {
+        let response = flow::detect_flow_state(
+            axum::extract::State(state.clone()),
+            claims.clone(),
+            ResponseFormat::default(),
+            axum::extract::Query(Default::default()),
+            axum::Json(flow::FlowDetectionPayload { request: request() }),
+        )
+        .await
+        .unwrap()
+        .into_response();
+        bodies.push(
+            axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap(),
+        );
+    }
+    assert_eq!(bodies[0], bodies[1]);
+    assert!(state.flow_writes.drain(Duration::from_secs(5)).await);
+
+    // One stored row, one baseline sample and one live update
+    let rows: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM flow_states WHERE session_id = $1")
+        .bind(session_id)
+        .fetch_one(&db)
+        .await
+        .unwrap();
+    assert_eq!(rows, 1);
+    let samples: i64 =
+        sqlx::query_scalar("SELECT sample_count FROM user_flow_baselines WHERE user_id = $1")
+            .bind(user_id)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+    assert_eq!(samples, 1);
+    let mut flow_updates = 0;
+    while let Ok(message) = updates.try_recv() {
+        if message.contains("\"flow_state_update\"") {
+            flow_updates += 1;
+        }
+    }
+    assert_eq!(flow_updates, 1);
+}
+
+#[sqlx::test(migrator = "mindful_code_backend::state::MIGRATOR")]
+async fn test_concurrent_sessions_merge_into_the_stored_baseline(db: sqlx::PgPool) {
+    let user_id: Uuid = sqlx::query_scalar(
+        "INSERT INTO users (email, password_hash) VALUES ('two-tabs@example.com', 'x') RETURNING id",
+    )
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    sqlx::query(
+        "INSERT INTO user_flow_baselines (user_id, sample_count, mean_intensity, intensity_variance) VALUES ($1, 40, 0.6, 0.01)",
+    )
+    .bind(user_id)
+    .execute(&db)
+    .await
+    .unwrap();
+    let stored = FlowBaseline {
+        sample_count: 40,
+        mean: 0.6,
+        variance: 0.01,
+    };
+
+    // Both sessions start from the same stored baseline and score their own
+    // samples before either is written
+    let mut writes = Vec::new();
+    let mut sample_sum = 0.0;
+    for keystroke_intervals in [
+        vec![120, 125, 118, 122, 130, 119, 121, 124, 117, 123],
+        vec![40, 900, 75, 1400, 60, 2100, 35, 1800, 90, 2500],
+    ] {
+        let session_id: Uuid = sqlx::query_scalar(
+            "INSERT INTO coding_sessions (user_id, start_time) VALUES ($1, NOW()) RETURNING id",
+        )
+        .bind(user_id)
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        let mut engine = FlowDetectionEngine::new();
+        engine.restore_baseline(stored);
+        let result = engine
+            .analyze_flow_state(
+                FlowStateData {
+                    session_id,
+                    keystroke_intervals,
+                    ..sample_flow_data()
+                },
+                None,
+            )
+            .await
+            .unwrap();
+        let baseline_samples = engine.take_unsaved_baseline();
+        assert_eq!(baseline_samples.sample_count, 1);
+        assert_eq!(engine.take_unsaved_baseline(), FlowBaseline::default());
+        sample_sum += baseline_samples.mean;
+        writes.push(flow::PendingFlowWrite {
+            write_id: Uuid::new_v4(),
+            user_id,
+            session_id,
+            result,
+            keystroke_hash: String::new(),
+            focus_mode: false,
+            baseline_samples,
+        });
+    }
+    for write in &writes {
+        flow::persist_flow_write(&db, write, false).await.unwrap();
+    }
+    // A replayed write was merged the first time
+    flow::persist_flow_write(&db, &writes[0], false).await.unwrap();
+
+    let (sample_count, mean): (i64, f64) = sqlx::query_as(
+        "SELECT sample_count, mean_intensity FROM user_flow_baselines WHERE user_id = $1",
+    )
+    .bind(user_id)
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    assert_eq!(sample_count, 42);
+    assert!((mean - (40.0 * 0.6 + sample_sum) / 42.0).abs() < 1e-9);
+}
+
+#[sqlx::test(migrator = "mindful_code_backend::state::MIGRATOR")]
+async fn test_interruption_before_first_sample_seeds_the_engine(db: sqlx::PgPool) {
+    use mindful_code_backend::models::flow::{InterruptionRequest, InterruptionType};
+
+    let user_id: Uuid = sqlx::query_scalar(
+        "INSERT INTO users (email, password_hash) VALUES ('seeded@example.com', 'x') RETURNING id",
+    )
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    sqlx::query(
+        "INSERT INTO user_flow_baselines (user_id, sample_count, mean_intensity, intensity_variance) VALUES ($1, 40, 0.6, 0.01)",
+    )
+    .bind(user_id)
+    .execute(&db)
+    .await
+    .unwrap();
+    let session_id: Uuid = sqlx::query_scalar(
+        "INSERT INTO coding_sessions (user_id, start_time) VALUES ($1, NOW()) RETURNING id",
+    )
+    .bind(user_id)
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    let state = AppState::from_pools(Config::from_env().unwrap(), db.clone(), None);
+    let claims = Claims::new(
+        user_id,
+        "seeded@example.com".to_string(),
+        "free".to_string(),
+    );
+
+    flow::record_interruption(
+        axum::extract::State(state.clone()),
+        claims.clone(),
+        ResponseFormat::default(),
+        axum::Json(InterruptionRequest {
+            session_id,
+            interruption_type: InterruptionType::Call,
+            duration_ms: 60_000,
+        }),
+    )
+    .await
+    .unwrap();
+
+    // The interruption's engine carries the stored baseline, and the first
+    // sample is scored on that same engine
+    let engine = state.flow_engines.get(&(user_id, session_id)).unwrap().clone();
+    assert_eq!(engine.read().baseline().sample_count, 40);
+    flow::detect_flow_state(
+        axum::extract::State(state.clone()),
+        claims,
+        ResponseFormat::default(),
+        axum::extract::Query(Default::default()),
+        axum::Json(flow::FlowDetectionPayload {
+            request: FlowDetectionRequest {
+                flow_data: FlowStateData {
+                    session_id,
+                    ..sample_flow_data()
+                },
+                user_preferences: None,
+            },
+        }),
+    )
+    .await
+    .unwrap();
+    assert!(engine.read().baseline().sample_count > 40);
+}
+
+#[tokio::test]
+async fn test_short_flow_blips_are_not_counted() {
+    let config = FlowEngineConfig {
+        warmup_analyses: 0,
+        min_flow_duration_ms: 50,
+        ..FlowEngineConfig::default()
+    };
+    let mut engine = FlowDetectionEngine::with_config(config, MLInferenceEngine::new());
+    let preferences = |sensitivity_level| UserFlowPreferences {
+        sensitivity_level,
+        notification_threshold: 0.6,
+        focus_mode_enabled: false,
+        break_reminders_enabled: true,
+        personalized_calibration: false,
+        use_ml: None,
+    };
+    let flow_data = FlowStateData {
+        keystroke_intervals: vec![120, 125, 118, 122, 130, 119, 121, 124, 117, 123],
+        context_switches: 0,
+        error_events: 0,
+        window_focus_duration: 600000,
+        file_modifications: 4,
+        typing_velocity: Some(280.0),
+        ..sample_flow_data()
+    };
+
+    // Sensitivity 0 always enters flow, 1 always leaves it
+    let enter = engine.analyze_flow_state(flow_data.clone(), Some(preferences(0.0))).await.unwrap();
+    assert!(enter.is_in_flow);
+    let exit = engine.analyze_flow_state(flow_data.clone(), Some(preferences(1.0))).await.unwrap();
+    assert!(!exit.is_in_flow);
+    assert_eq!(engine.get_session_stats(), (0, Duration::ZERO));
+
+    engine.analyze_flow_state(flow_data.clone(), Some(preferences(0.0))).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(80)).await;
+    engine.analyze_flow_state(flow_data, Some(preferences(1.0))).await.unwrap();
+
+    let (flow_sessions, total_flow_time) = engine.get_session_stats();
+    assert_eq!(flow_sessions, 1);
+    assert!(total_flow_time >= Duration::from_millis(80));
+}
+
+#[tokio::test]
+async fn test_rule_based_scoring_is_deterministic() {
+    let preferences = || UserFlowPreferences {
+        sensitivity_level: 0.7,
+        notification_threshold: 0.6,
+        focus_mode_enabled: false,
+        break_reminders_enabled: true,
+        personalized_calibration: false,
+        use_ml: Some(false),
+    };
+    let flow_data = sample_flow_data();
+
+    let mut first_engine = FlowDetectionEngine::new();
+    let mut second_engine = FlowDetectionEngine::new();
+    let first = first_engine
+        .analyze_flow_state(flow_data.clone(), Some(preferences()))
+        .await
+        .unwrap();
+    let second = second_engine
+        .analyze_flow_state(flow_data, Some(preferences()))
+        .await
+        .unwrap();
+
+    assert_eq!(first.flow_intensity.to_bits(), second.flow_intensity.to_bits());
+
+    // The score is exactly the public rule-based combination of the metrics
+    let metrics = &first.metrics;
+    let rule_based = MLInferenceEngine::new().rule_based_prediction([
+        metrics.rhythm_score,
+        metrics.focus_score,
+        metrics.consistency_score,
+        1.0 - metrics.error_penalty,
+        metrics.velocity_score,
+    ]);
+    assert_eq!(first.flow_intensity.to_bits(), rule_based.to_bits());
+}
+
+#[test]
+fn test_migration_status_lists_baseline_as_applied() {
+    let applied_record = |version: i64| {
+        let migration = MIGRATOR
+            .iter()
+            .find(|migration| migration.version == version)
+            .expect("migration is embedded");
+        AppliedMigration {
+            version,
+            description: migration.description.to_string(),
+            installed_on: chrono::Utc::now(),
+            success: true,
+            checksum: migration.checksum.to_vec(),
+        }
+    };
+
+    // Only the baseline applied: everything after it is pending
+    let status = summarize_migrations(&MIGRATOR, &[applied_record(1)]);
+    let baseline = status.migrations.iter().find(|m| m.version == 1).unwrap();
+    assert!(baseline.applied);
+    assert_eq!(baseline.checksum_matches, Some(true));
+    assert!(status.has_pending);
+    assert!(!status.pending.contains(&1));
+    assert!(status.pending.contains(&2));
+
+    let all: Vec<AppliedMigration> = MIGRATOR.iter().map(|m| applied_record(m.version)).collect();
+    let status = summarize_migrations(&MIGRATOR, &all);
+    assert!(!status.has_pending);
+    assert!(status.migrations.iter().all(|m| m.applied));
+    assert_eq!(status.schema_checksum.len(), 64);
+
+    // An edited migration script no longer matches what was applied
+    let mut edited = applied_record(1);
+    edited.checksum = vec![0; 48];
+    let status = summarize_migrations(&MIGRATOR, &[edited]);
+    assert_eq!(status.migrations[0].checksum_matches, Some(false));
+}
+
+#[tokio::test]
+async fn test_minimized_keystroke_aggregates_score_like_full_data() {
+    let intervals = vec![120, 135, 98, 142, 156, 89, 167, 134, 145, 123, 118, 131];
+    let full = FlowStateData {
+        keystroke_intervals: intervals.clone(),
+        context_switches: 1,
+        error_events: 0,
+        typing_velocity: None,
+        ..sample_flow_data()
+    };
+    let minimized = FlowStateData {
+        keystroke_intervals: vec![],
+        aggregates: KeystrokeAggregates::from_intervals(&intervals),
+        ..full.clone()
+    };
+
+    let full_result = FlowDetectionEngine::new()
+        .analyze_flow_state(full.clone(), None)
+        .await
+        .unwrap();
+    let minimized_result = FlowDetectionEngine::new()
+        .analyze_flow_state(minimized.clone(), None)
+        .await
+        .unwrap();
+
+    assert!(full_result.metrics.rhythm_score > 0.0);
+    assert!((full_result.metrics.rhythm_score - minimized_result.metrics.rhythm_score).abs() < 0.05);
+    assert_eq!(full_result.metrics.velocity_score, minimized_result.metrics.velocity_score);
+    assert_eq!(full_result.data_quality, minimized_result.data_quality);
+
+    // Either raw intervals or aggregates must be present
+    use validator::Validate;
+    assert!(minimized.validate().is_ok());
+    let neither = FlowStateData {
+        aggregates: None,
+        velocity_unit: Default::default(),
+        ..minimized
+    };
+    assert!(neither.validate().is_err());
+}
+
+#[tokio::test]
+async fn test_saved_flow_preferences_validate_and_apply_by_default() {
+    let db = sqlx::postgres::PgPoolOptions::new()
+        .connect_lazy("postgresql://localhost/mindful_code")
+        .unwrap();
+    let state = AppState::from_pools(Config::from_env().unwrap(), db, None);
+    let preferences = |sensitivity_level: f32, notification_threshold: f32| UserFlowPreferences {
+        sensitivity_level,
+        notification_threshold,
+        focus_mode_enabled: true,
+        break_reminders_enabled: false,
+        personalized_calibration: false,
+        use_ml: Some(false),
+    };
+
+    // Out-of-range values are rejected before anything is written
+    let registered = Claims::new(Uuid::new_v4(), "prefs@example.com".to_string(), "free".to_string());
+    for (sensitivity, threshold) in [(1.5, 0.5), (0.5, -0.1)] {
+        let result = flow::update_flow_preferences(
+            axum::extract::State(state.clone()),
+            registered.clone(),
+            ResponseFormat::default(),
+            axum::Json(preferences(sensitivity, threshold)),
+        )
+        .await;
+        assert!(matches!(result, Err(mindful_code_backend::error::AppError::Validation(_))));
+    }
+
+    // Saved preferences round-trip through the API representation
+    let saved: UserFlowPreferences =
+        serde_json::from_value(serde_json::to_value(preferences(0.0, 0.4)).unwrap()).unwrap();
+    assert_eq!(saved.notification_threshold, 0.4);
+    assert_eq!(saved.use_ml, Some(false));
+
+    // A request without preferences uses the saved ones: with a zero
+    // threshold, flow is entered as soon as warm-up ends
+    let claims = Claims::anonymous(Uuid::new_v4(), chrono::Duration::minutes(60));
+    let session_id = Uuid::new_v4();
+    state
+        .get_or_create_flow_engine(claims.user_id, session_id)
+        .write()
+        .set_stored_preferences(Some(saved));
+
+    let mut last = None;
+    for i in 0..5 {
+        let request = FlowDetectionRequest {
+            flow_data: FlowStateData {
+                session_id,
+                keystroke_intervals: vec![120, 125, 118, 122, 130, 119, 121, 124, 117, 123],
+                context_switches: 0,
+                error_events: 0,
+                timestamp: 1_700_000_000_000 + i,
+                ..sample_flow_data()
+            },
+            user_preferences: None,
+        };
+        last = Some(
+            flow::detect_flow_state(
+                axum::extract::State(state.clone()),
+                claims.clone(),
+                ResponseFormat::default(),
+                axum::extract::Query(Default::default()),
+                axum::Json(flow::FlowDetectionPayload { request }),
+            )
+            .await
+            .unwrap(),
+        );
+    }
+
+    let response = last.unwrap().into_response();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let result: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(result["is_in_flow"], true);
+}
+
+#[sqlx::test(migrator = "mindful_code_backend::state::MIGRATOR")]
+async fn test_saved_flow_preferences_are_loaded_for_detection(db: sqlx::PgPool) {
+    let user_id: Uuid = sqlx::query_scalar(
+        "INSERT INTO users (email, password_hash) VALUES ('saved-prefs@example.com', 'x') RETURNING id",
+    )
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    let session_id: Uuid = sqlx::query_scalar(
+        "INSERT INTO coding_sessions (user_id, start_time) VALUES ($1, NOW()) RETURNING id",
+    )
+    .bind(user_id)
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    let state = AppState::from_pools(Config::from_env().unwrap(), db.clone(), None);
+    let claims = Claims::new(
+        user_id,
+        "saved-prefs@example.com".to_string(),
+        "free".to_string(),
+    );
+
+    // Zero sensitivity: flow is entered as soon as warm-up ends
+    let preferences = UserFlowPreferences {
+        sensitivity_level: 0.0,
+        notification_threshold: 0.4,
+        focus_mode_enabled: false,
+        break_reminders_enabled: false,
+        personalized_calibration: false,
+        use_ml: Some(false),
+    };
+    flow::update_flow_preferences(
+        axum::extract::State(state.clone()),
+        claims.clone(),
+        ResponseFormat::default(),
+        axum::Json(preferences),
+    )
+    .await
+    .unwrap();
+    let saved = flow::get_flow_preferences(
+        axum::extract::State(state.clone()),
+        claims.clone(),
+        ResponseFormat::default(),
+    )
+    .await
+    .unwrap()
+    .into_data();
+    assert_eq!(saved.notification_threshold, 0.4);
+    assert_eq!(saved.use_ml, Some(false));
+
+    // The session's engine is created by the first request, which seeds it
+    // from the stored preferences
+    assert!(state.user_flow_engines(user_id).is_empty());
+    let started = chrono::Utc::now().timestamp_millis();
+    let mut last = None;
+    for i in 0..5 {
+        let request = FlowDetectionRequest {
+            flow_data: FlowStateData {
+                session_id,
+                keystroke_intervals: vec![120, 125, 118, 122, 130, 119, 121, 124, 117, 123],
+                context_switches: 0,
+                error_events: 0,
+                timestamp: started + i,
+                ..sample_flow_data()
+            },
+            user_preferences: None,
+        };
+        last = Some(
+            flow::detect_flow_state(
+                axum::extract::State(state.clone()),
+                claims.clone(),
+                ResponseFormat::default(),
+                axum::extract::Query(Default::default()),
+                axum::Json(flow::FlowDetectionPayload { request }),
+            )
+            .await
+            .unwrap()
+            .into_data(),
+        );
+    }
+
+    let result = last.unwrap();
+    assert!(result.is_in_flow);
+    assert_eq!(result.model_version, RULE_BASED_MODEL_VERSION);
+}
+
+#[sqlx::test(migrator = "mindful_code_backend::state::MIGRATOR")]
+async fn test_reading_focus_mode_leaves_it_unchanged(db: sqlx::PgPool) {
+    use mindful_code_backend::models::flow::FocusModeRequest;
+
+    let user_id: Uuid = sqlx::query_scalar(
+        "INSERT INTO users (email, password_hash) VALUES ('focus@example.com', 'x') RETURNING id",
+    )
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    let claims = Claims::new(user_id, "focus@example.com".to_string(), "free".to_string());
+    let get_focus_mode = |state: &AppState| {
+        flow::get_focus_mode(
+            axum::extract::State(state.clone()),
+            claims.clone(),
+            ResponseFormat::default(),
+        )
+    };
+
+    let state = AppState::from_pools(Config::from_env().unwrap(), db.clone(), None);
+    let enabled = flow::update_focus_mode(
+        axum::extract::State(state.clone()),
+        claims.clone(),
+        ResponseFormat::default(),
+        axum::Json(FocusModeRequest { enabled: true }),
+    )
+    .await
+    .unwrap()
+    .into_data();
+    let status = get_focus_mode(&state).await.unwrap().into_data();
+    assert!(status.enabled);
+    assert_eq!(status.started_at, enabled.started_at);
+
+    // After a restart the saved choice is reported, but only detection
+    // turns enforcement back on
+    let restarted = AppState::from_pools(Config::from_env().unwrap(), db.clone(), None);
+    let status = get_focus_mode(&restarted).await.unwrap().into_data();
+    assert!(status.enabled);
+    assert_eq!(status.started_at, None);
+    assert!(!restarted.focus_modes.is_active(user_id));
+}
+
+#[tokio::test]
+async fn test_keystroke_hash_replaces_raw_intervals() {
+    let sample = |keystroke_intervals: Vec<u64>| FlowStateData {
+        session_id: Uuid::nil(),
+        keystroke_intervals,
+        timestamp: 1_700_000_000_000,
+        ..sample_flow_data()
+    };
+    let intervals = vec![120, 135, 98, 142, 156, 89, 167, 134, 145, 123];
+    let hasher = KeystrokeHasher::new(b"test-keystroke-salt");
+
+    let hash = hasher.hash(&sample(intervals.clone()));
+    assert_eq!(hash, hasher.hash(&sample(intervals.clone())));
+    assert_eq!(hash.len(), 64);
+    assert_ne!(hash, hasher.hash(&sample(vec![120, 135, 98, 142, 156, 89, 167, 134, 145, 124])));
+    assert_ne!(hash, KeystrokeHasher::new(b"other-salt").hash(&sample(intervals.clone())));
+
+    // The persisted row carries the hash and nothing of the raw timings
+    let mut engine = FlowDetectionEngine::new();
+    let result = engine.analyze_flow_state(sample(intervals.clone()), None).await.unwrap();
+    let row = serde_json::to_string(&flow::FlowStateRow::new(&result, Some(hash.clone()))).unwrap();
+    assert!(row.contains(&hash));
+    assert!(!row.contains("keystroke_intervals"));
+    assert!(!row.contains("[120,135,98"));
+
+    // A retry that reuses the sample's timestamp with other keystrokes is rejected
+    let db = sqlx::postgres::PgPoolOptions::new()
+        .connect_lazy("postgresql://localhost/mindful_code")
+        .unwrap();
+    let state = AppState::from_pools(Config::from_env().unwrap(), db, None);
+    let claims = Claims::anonymous(Uuid::new_v4(), chrono::Duration::minutes(60));
+    let detect = |keystroke_intervals| {
+        flow::detect_flow_state(
+            axum::extract::State(state.clone()),
+            claims.clone(),
+            ResponseFormat::default(),
+            axum::extract::Query(Default::default()),
+            axum::Json(flow::FlowDetectionPayload {
+                request: FlowDetectionRequest {
+                    flow_data: sample(keystroke_intervals),
+                    user_preferences: None,
+                },
+            }),
+        )
+    };
+
+    assert!(detect(intervals).await.is_ok());
+    let conflict = detect(vec![200; 10]).await.unwrap_err();
+    assert_eq!(conflict.into_response().status(), axum::http::StatusCode::CONFLICT);
+}
+
+#[tokio::test]
+async fn test_failed_wasm_init_leaves_flow_detection_available() {
+    let db = sqlx::postgres::PgPoolOptions::new()
+        .connect_lazy("postgresql://localhost/mindful_code")
+        .unwrap();
+    let state = AppState::from_pools(Config::from_env().unwrap(), db, None)
+        .with_wasm_plugins(Err(AppError::Wasm("unsupported engine config".to_string())));
+    assert!(state.wasm_plugins.is_none());
+
+    let registered = Claims::new(Uuid::new_v4(), "dev@example.com".to_string(), "free".to_string());
+    let unavailable = plugins::list_plugins(axum::extract::State(state.clone()), registered)
+        .await
+        .unwrap_err()
+        .into_response();
+    assert_eq!(unavailable.status(), axum::http::StatusCode::SERVICE_UNAVAILABLE);
+
+    let claims = Claims::anonymous(Uuid::new_v4(), chrono::Duration::minutes(60));
+    let response = flow::detect_flow_state(
+        axum::extract::State(state),
+        claims,
+        ResponseFormat::default(),
+        axum::extract::Query(Default::default()),
+        axum::Json(flow::FlowDetectionPayload {
+            request: FlowDetectionRequest {
+                flow_data: sample_flow_data(),
+                user_preferences: None,
+            },
+        }),
+    )
+    .await
+    .unwrap()
+    .into_response();
+    assert_eq!(response.status(), axum::http::StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_model_version_is_recorded_and_can_be_pinned() {
+    let sample = || FlowStateData {
+        timestamp: 1_700_000_000_000,
+        ..sample_flow_data()
+    };
+
+    // Persisted rows carry the version of the model that scored them
+    let mut engine = FlowDetectionEngine::new();
+    let active = engine.analyze_flow_state(sample(), None).await.unwrap();
+    assert_eq!(active.model_version, RULE_BASED_MODEL_VERSION);
+    assert_eq!(flow::FlowStateRow::new(&active, None).model_version, RULE_BASED_MODEL_VERSION);
+
+    // A registry version that only looks at typing velocity
+    let registry_dir = std::env::temp_dir().join(format!("model_registry_{}", Uuid::new_v4()));
+    std::fs::create_dir_all(&registry_dir).unwrap();
+    std::fs::write(
+        registry_dir.join("velocity-only.json"),
+        r#"{"weights": [0.0, 0.0, 0.0, 0.0, 1.0]}"#,
+    )
+    .unwrap();
+
+    let db = sqlx::postgres::PgPoolOptions::new()
+        .connect_lazy("postgresql://localhost/mindful_code")
+        .unwrap();
+    let mut config = Config::from_env().unwrap();
+    config.model_registry_dir = Some(registry_dir.to_string_lossy().into_owned());
+    let state = AppState::from_pools(config, db, None);
+
+    let detect = |claims: Claims, model_version: &str| {
+        flow::detect_flow_state(
+            axum::extract::State(state.clone()),
+            claims,
+            ResponseFormat::default(),
+            axum::extract::Query(flow::DetectQuery {
+                model_version: Some(model_version.to_string()),
+                strict: None,
+            }),
+            axum::Json(flow::FlowDetectionPayload {
+                request: FlowDetectionRequest {
+                    flow_data: sample(),
+                    user_preferences: None,
+                },
+            }),
+        )
+    };
+
+    let mut admin = Claims::new(Uuid::new_v4(), "admin@example.com".to_string(), "enterprise".to_string());
+    admin.role = UserRole::Admin;
+    let response = detect(admin.clone(), "velocity-only").await.unwrap().into_response();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let pinned: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+    assert_eq!(pinned["model_version"], "velocity-only");
+    let pinned_intensity = pinned["flow_intensity"].as_f64().unwrap();
+    assert!((pinned_intensity - active.flow_intensity as f64).abs() > 1e-3);
+
+    let missing = detect(admin.clone(), "no-such-model").await.unwrap_err().into_response();
+    assert_eq!(missing.status(), axum::http::StatusCode::NOT_FOUND);
+    let traversal = detect(admin, "../velocity-only").await.unwrap_err().into_response();
+    assert_eq!(traversal.status(), axum::http::StatusCode::BAD_REQUEST);
+
+    let member = Claims::new(Uuid::new_v4(), "dev@example.com".to_string(), "enterprise".to_string());
+    let forbidden = detect(member, "velocity-only").await.unwrap_err().into_response();
+    assert_eq!(forbidden.status(), axum::http::StatusCode::FORBIDDEN);
+
+    std::fs::remove_dir_all(registry_dir).unwrap();
+}
+
+#[sqlx::test(migrator = "mindful_code_backend::state::MIGRATOR")]
+async fn test_idle_sessions_are_auto_ended_in_database(db: sqlx::PgPool) {
+    let user_id: Uuid = sqlx::query_scalar(
+        "INSERT INTO users (email, password_hash) VALUES ('idle@example.com', 'x') RETURNING id",
+    )
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    let started_at = chrono::Utc::now() - chrono::Duration::hours(2);
+    let session_id: Uuid = sqlx::query_scalar(
+        "INSERT INTO coding_sessions (user_id, start_time) VALUES ($1, $2) RETURNING id",
+    )
+    .bind(user_id)
+    .bind(started_at)
+    .fetch_one(&db)
+    .await
+    .unwrap();
+
+    let state = AppState::from_pools(Config::from_env().unwrap(), db.clone(), None);
+    // Idle for the last 90 minutes
+    let last_activity = started_at + chrono::Duration::minutes(30);
+    state.add_active_session(SessionInfo {
+        user_id,
+        session_id,
+        started_at,
+        last_activity,
+        is_active: true,
+    });
+    let (sender, mut client) = tokio::sync::mpsc::unbounded_channel();
+    state.add_websocket_connection(user_id, sender, false);
+
+    assert_eq!(sessions::auto_end_idle_sessions(&state).await, 1);
+
+    let (end_time, total_duration_ms): (Option<chrono::DateTime<chrono::Utc>>, Option<i64>) =
+        sqlx::query_as("SELECT end_time, total_duration_ms FROM coding_sessions WHERE id = $1")
+            .bind(session_id)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+    // Ended as of the last activity, not when the sweeper happened to run
+    let end_time = end_time.expect("sweeper should set end_time");
+    assert!((end_time - last_activity).num_milliseconds().abs() < 1);
+    assert_eq!(total_duration_ms, Some(30 * 60_000));
+    assert_eq!(state.get_active_sessions_count(), 0);
+
+    let update: serde_json::Value = serde_json::from_str(&client.recv().await.unwrap()).unwrap();
+    assert_eq!(update["session_id"], session_id.to_string());
+    assert_eq!(update["status"], "auto_ended");
+
+    // Nothing left to sweep
+    assert_eq!(sessions::auto_end_idle_sessions(&state).await, 0);
+}
+
+#[sqlx::test(migrator = "mindful_code_backend::state::MIGRATOR")]
+async fn test_stale_and_failed_sessions_are_auto_ended_on_later_sweeps(db: sqlx::PgPool) {
+    let user_id: Uuid = sqlx::query_scalar(
+        "INSERT INTO users (email, password_hash) VALUES ('stale@example.com', 'x') RETURNING id",
+    )
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    let started_at = chrono::Utc::now() - chrono::Duration::hours(2);
+    let open_session = |updated_at: chrono::DateTime<chrono::Utc>| {
+        sqlx::query_scalar::<_, Uuid>(
+            "INSERT INTO coding_sessions (user_id, start_time, updated_at) VALUES ($1, $2, $3) RETURNING id",
+        )
+        .bind(user_id)
+        .bind(started_at)
+        .bind(updated_at)
+        .fetch_one(&db)
+    };
+    // Left open by a previous process, last sampled 25 minutes in
+    let orphaned = open_session(started_at + chrono::Duration::minutes(20))
+        .await
+        .unwrap();
+    let last_sample = started_at + chrono::Duration::minutes(25);
+    store_flow_samples(
+        &db,
+        user_id,
+        orphaned,
+        &[(last_sample.timestamp_millis(), false)],
+    )
+    .await;
+    // Open elsewhere and still being updated
+    let recent = open_session(chrono::Utc::now()).await.unwrap();
+    // Tracked here, but the database refuses to end it for now
+    let failing = open_session(started_at).await.unwrap();
+    sqlx::query(
+        "CREATE FUNCTION refuse_session_update() RETURNS trigger AS $$ \
+         BEGIN RAISE EXCEPTION 'simulated outage'; END $$ LANGUAGE plpgsql",
+    )
+    .execute(&db)
+    .await
+    .unwrap();
+    sqlx::query(&format!(
+        "CREATE TRIGGER refuse_session_update BEFORE UPDATE ON coding_sessions \
+         FOR EACH ROW WHEN (OLD.id = '{}') EXECUTE FUNCTION refuse_session_update()",
+        failing
+    ))
+    .execute(&db)
+    .await
+    .unwrap();
+
+    let state = AppState::from_pools(Config::from_env().unwrap(), db.clone(), None);
+    let failing_activity = started_at + chrono::Duration::minutes(10);
+    state.add_active_session(SessionInfo {
+        user_id,
+        session_id: failing,
+        started_at,
+        last_activity: failing_activity,
+        is_active: true,
+    });
+
+    let end_time = |session_id: Uuid| {
+        sqlx::query_scalar::<_, Option<chrono::DateTime<chrono::Utc>>>(
+            "SELECT end_time FROM coding_sessions WHERE id = $1",
+        )
+        .bind(session_id)
+        .fetch_one(&db)
+    };
+
+    assert_eq!(sessions::auto_end_idle_sessions(&state).await, 1);
+    // The orphaned session ends as of its last sample
+    let orphaned_end = end_time(orphaned)
+        .await
+        .unwrap()
+        .expect("sweep should end it");
+    assert!((orphaned_end - last_sample).num_milliseconds().abs() < 1);
+    assert_eq!(end_time(recent).await.unwrap(), None);
+    // The failed end is kept for the next sweep
+    assert_eq!(end_time(failing).await.unwrap(), None);
+    assert_eq!(state.get_active_sessions_count(), 1);
+
+    sqlx::query("DROP TRIGGER refuse_session_update ON coding_sessions")
+        .execute(&db)
+        .await
+        .unwrap();
+
+    assert_eq!(sessions::auto_end_idle_sessions(&state).await, 1);
+    let failing_end = end_time(failing)
+        .await
+        .unwrap()
+        .expect("retry should end it");
+    assert!((failing_end - failing_activity).num_milliseconds().abs() < 1);
+    assert_eq!(state.get_active_sessions_count(), 0);
+    assert_eq!(end_time(recent).await.unwrap(), None);
+
+    assert_eq!(sessions::auto_end_idle_sessions(&state).await, 0);
+}
+
+#[tokio::test]
+async fn test_skewed_client_clocks_are_rejected_or_adjusted() {
+    let config = FlowEngineConfig {
+        warmup_analyses: 0,
+        min_flow_duration_ms: 50,
+        ..FlowEngineConfig::default()
+    };
+    let mut engine = FlowDetectionEngine::with_config(config, MLInferenceEngine::new());
+    let in_flow = || UserFlowPreferences {
+        sensitivity_level: 0.0,
+        notification_threshold: 0.6,
+        focus_mode_enabled: false,
+        break_reminders_enabled: true,
+        personalized_calibration: false,
+        use_ml: None,
+    };
+    let sample = |timestamp| FlowStateData {
+        session_id: Uuid::nil(),
+        keystroke_intervals: vec![120, 125, 118, 122, 130, 119, 121, 124, 117, 123],
+        context_switches: 0,
+        error_events: 0,
+        window_focus_duration: 600000,
+        file_modifications: 4,
+        timestamp,
+        typing_velocity: Some(280.0),
+        ..sample_flow_data()
+    };
+    let now = chrono::Utc::now().timestamp_millis();
+
+    let on_time = engine.analyze_flow_state(sample(now), Some(in_flow())).await.unwrap();
+    assert_eq!(on_time.sample_timestamp, now);
+    assert!(!on_time.timestamp_adjusted);
+
+    // A clock a day ahead is rejected before it touches the flow stretch
+    tokio::time::sleep(Duration::from_millis(60)).await;
+    let future = engine
+        .analyze_flow_state(sample(now + 24 * 3_600_000), Some(in_flow()))
+        .await
+        .unwrap_err();
+    assert!(matches!(future, AppError::Validation(_)));
+    assert_eq!(engine.get_session_stats(), (0, Duration::ZERO));
+
+    // A clock a week behind is ordered by receive time instead
+    let stale = engine
+        .analyze_flow_state(sample(now - 7 * 24 * 3_600_000), Some(in_flow()))
+        .await
+        .unwrap();
+    assert!(stale.timestamp_adjusted);
+    assert!(stale.sample_timestamp >= now);
+    let row = flow::FlowStateRow::new(&stale, None);
+    assert_eq!(row.start_time.timestamp_millis(), stale.sample_timestamp);
+
+    // Flow duration still comes from the server clock: tens of
+    // milliseconds, not days
+    assert!(stale.flow_duration_ms >= 60);
+    assert!(stale.flow_duration_ms < 10_000);
+}
+
+#[tokio::test]
+async fn test_identical_input_is_served_from_analysis_cache() {
+    let config = FlowEngineConfig {
+        analysis_cache_size: 4,
+        ..FlowEngineConfig::default()
+    };
+    let mut engine = FlowDetectionEngine::with_config(config, MLInferenceEngine::new());
+    let sample = |session_id, timestamp| FlowStateData {
+        session_id,
+        timestamp,
+        ..sample_flow_data()
+    };
+    let session = Uuid::new_v4();
+    let now = chrono::Utc::now().timestamp_millis();
+
+    let first = engine.analyze_flow_state(sample(session, now), None).await.unwrap();
+    assert_eq!(engine.analysis_cache_stats().hits(), 0);
+
+    // Same window re-sent a moment later
+    let repeat = engine.analyze_flow_state(sample(session, now + 50), None).await.unwrap();
+    assert_eq!(engine.analysis_cache_stats().hits(), 1);
+    assert_eq!(repeat.flow_intensity, first.flow_intensity);
+    assert_eq!(repeat.confidence, first.confidence);
+    assert_eq!(repeat.warming_up, first.warming_up);
+    assert_eq!(repeat.sample_timestamp, now + 50);
+    // Served without counting as another analysis of the baseline
+    assert_eq!(engine.baseline().sample_count, 1);
+
+    // Different preferences, and a new session, both miss
+    let strict = UserFlowPreferences {
+        sensitivity_level: 0.9,
+        notification_threshold: 0.6,
+        focus_mode_enabled: false,
+        break_reminders_enabled: true,
+        personalized_calibration: false,
+        use_ml: None,
+    };
+    engine.analyze_flow_state(sample(session, now + 100), Some(strict)).await.unwrap();
+    engine.analyze_flow_state(sample(Uuid::new_v4(), now + 150), None).await.unwrap();
+    engine.analyze_flow_state(sample(session, now + 200), None).await.unwrap();
+    assert_eq!(engine.analysis_cache_stats().hits(), 1);
+    assert_eq!(engine.analysis_cache_stats().misses(), 4);
+    assert_eq!(engine.analysis_cache_stats().hit_rate(), 0.2);
+}
+
+#[sqlx::test(migrator = "mindful_code_backend::state::MIGRATOR")]
+async fn test_team_operations_are_gated_by_role(db: sqlx::PgPool) {
+    use axum::extract::{Path, State};
+    use axum::Json;
+
+    let mut users = Vec::new();
+    for email in ["owner@example.com", "manager@example.com", "member@example.com"] {
+        let user_id: Uuid = sqlx::query_scalar(
+            "INSERT INTO users (email, password_hash) VALUES ($1, 'x') RETURNING id",
+        )
+        .bind(email)
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        users.push(Claims::new(user_id, email.to_string(), "team".to_string()));
+    }
+    let (owner, manager, member) = (&users[0], &users[1], &users[2]);
+    let state = AppState::from_pools(Config::from_env().unwrap(), db.clone(), None);
+
+    let Json(team) = teams::create_team(
+        State(state.clone()),
+        owner.clone(),
+        Json(CreateTeamRequest { name: "Platform".to_string() }),
+    )
+    .await
+    .unwrap();
+    let owner_role: String =
+        sqlx::query_scalar("SELECT role FROM team_members WHERE team_id = $1 AND user_id = $2")
+            .bind(team.id)
+            .bind(owner.user_id)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+    assert_eq!(owner_role, "owner");
+
+    let add = |claims: &Claims, user_id, role| {
+        teams::add_team_members(
+            State(state.clone()),
+            claims.clone(),
+            Path(team.id),
+            Json(AddTeamMembersRequest {
+                members: vec![TeamMemberSpec { user_id, role }],
+            }),
+        )
+    };
+    add(owner, manager.user_id, TeamRole::Manager).await.unwrap();
+    add(manager, member.user_id, TeamRole::Member).await.unwrap();
+
+    let set_goal = |claims: &Claims| {
+        teams::set_team_goal(
+            State(state.clone()),
+            claims.clone(),
+            Path(team.id),
+            Json(SetTeamGoalRequest {
+                metric: TeamGoalMetric::WeeklyFlowHours,
+                target_value: 20.0,
+            }),
+        )
+    };
+    assert!(set_goal(manager).await.is_ok());
+    assert!(matches!(set_goal(member).await, Err(AppError::Authorization(_))));
+
+    // Members can read goals but not manage the roster
+    let goals = teams::get_team_goals(State(state.clone()), member.clone(), Path(team.id)).await;
+    assert_eq!(goals.unwrap().0.len(), 1);
+    let outsider = Uuid::new_v4();
+    assert!(matches!(
+        add(member, outsider, TeamRole::Member).await,
+        Err(AppError::Authorization(_))
+    ));
+
+    // Managers can't promote past themselves or remove the owner
+    assert!(matches!(
+        add(manager, outsider, TeamRole::Owner).await,
+        Err(AppError::Authorization(_))
+    ));
+    let remove_owner = teams::remove_team_members(
+        State(state.clone()),
+        manager.clone(),
+        Path(team.id),
+        Json(RemoveTeamMembersRequest { user_ids: vec![owner.user_id] }),
+    )
+    .await;
+    assert!(matches!(remove_owner, Err(AppError::Authorization(_))));
+}
+
+#[sqlx::test(migrator = "mindful_code_backend::state::MIGRATOR")]
+async fn test_team_goal_progress_follows_ended_sessions(db: sqlx::PgPool) {
+    use axum::extract::{Path, State};
+    use axum::Json;
+
+    let mut users = Vec::new();
+    for email in ["lead@example.com", "dev1@example.com", "dev2@example.com"] {
+        let user_id: Uuid = sqlx::query_scalar(
+            "INSERT INTO users (email, password_hash) VALUES ($1, 'x') RETURNING id",
+        )
+        .bind(email)
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        users.push(Claims::new(user_id, email.to_string(), "team".to_string()));
+    }
+    let mut config = Config::from_env().unwrap();
+    // Long enough that the 20-minute flow stretches below count in full
+    config.flow_sample_interval_secs = 3600;
+    let state = AppState::from_pools(config, db.clone(), None);
+
+    let Json(team) = teams::create_team(
+        State(state.clone()),
+        users[0].clone(),
+        Json(CreateTeamRequest {
+            name: "Goals".to_string(),
+        }),
+    )
+    .await
+    .unwrap();
+    for member in &users[1..] {
+        teams::add_team_members(
+            State(state.clone()),
+            users[0].clone(),
+            Path(team.id),
+            Json(AddTeamMembersRequest {
+                members: vec![TeamMemberSpec {
+                    user_id: member.user_id,
+                    role: TeamRole::Member,
+                }],
+            }),
+        )
+        .await
+        .unwrap();
+    }
+    sqlx::query("UPDATE team_members SET data_sharing_consent = true WHERE team_id = $1")
+        .bind(team.id)
+        .execute(&db)
+        .await
+        .unwrap();
+    // Half an hour across the team
+    let Json(goal) = teams::set_team_goal(
+        State(state.clone()),
+        users[0].clone(),
+        Path(team.id),
+        Json(SetTeamGoalRequest {
+            metric: TeamGoalMetric::WeeklyFlowHours,
+            target_value: 0.5,
+        }),
+    )
+    .await
+    .unwrap();
+    assert_eq!(goal.achieved_value, 0.0);
+
+    let now = chrono::Utc::now().timestamp_millis();
+    for (member, expected_minutes, completed) in [(&users[1], 20.0, false), (&users[2], 40.0, true)]
+    {
+        let session_id: Uuid = sqlx::query_scalar(
+            "INSERT INTO coding_sessions (user_id, start_time) VALUES ($1, NOW() - INTERVAL '1 hour') RETURNING id",
+        )
+        .bind(member.user_id)
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        store_flow_samples(
+            &db,
+            member.user_id,
+            session_id,
+            &[(now - 30 * 60_000, true), (now - 10 * 60_000, false)],
+        )
+        .await;
+        sessions::end_session(State(state.clone()), member.clone(), Path(session_id))
+            .await
+            .unwrap();
+
+        let Json(progress) =
+            teams::get_team_goals(State(state.clone()), users[0].clone(), Path(team.id))
+                .await
+                .unwrap();
+        assert!((progress[0].achieved_value * 60.0 - expected_minutes).abs() < 1e-6);
+        assert_eq!(progress[0].completed, completed);
+    }
+
+    let last_completed: Option<chrono::DateTime<chrono::Utc>> =
+        sqlx::query_scalar("SELECT last_completed_period FROM team_goals WHERE id = $1")
+            .bind(goal.goal_id)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+    assert_eq!(last_completed, Some(goal.period_start));
+}
+
+#[tokio::test]
+async fn test_time_decay_follows_a_shift_in_peak_hours() {
+    // Two months of mornings at 09:00, then a recent move to 15:00
+    let mut patterns = Vec::new();
+    for day_of_week in 0..7 {
+        for (hour_of_day, old_score, recent_score) in [(9, 0.9, 0.3), (15, 0.3, 0.9)] {
+            patterns.push(ProductivityPattern {
+                hour_of_day,
+                day_of_week,
+                average_flow_score: old_score,
+                session_count: 6,
+                age_days: 60.0,
+            });
+            patterns.push(ProductivityPattern {
+                hour_of_day,
+                day_of_week,
+                average_flow_score: recent_score,
+                session_count: 2,
+                age_days: 3.0,
+            });
+        }
+    }
+
+    let predictor = |decay| {
+        let mut predictor = ProductivityPredictor::new().with_time_decay(decay);
+        for pattern in &patterns {
+            predictor.add_pattern(pattern.clone());
+        }
+        predictor
+    };
+
+    // Uniform weighting is still dominated by the long morning history
+    let uniform = predictor(TimeDecay::default());
+    assert!(
+        uniform.predict_optimal_session_time(9, 3).await
+            > uniform.predict_optimal_session_time(15, 3).await
+    );
+
+    let decayed = predictor(TimeDecay::new(14.0));
+    assert!(
+        decayed.predict_optimal_session_time(15, 3).await
+            > decayed.predict_optimal_session_time(9, 3).await
+    );
+
+    assert_eq!(TimeDecay::new(14.0).weight(14.0), 0.5);
+    assert_eq!(TimeDecay::default().weight(365.0), 1.0);
+}
+
+#[tokio::test]
+async fn test_afternoon_patterns_get_an_afternoon_session_recommendation() {
+    use chrono::{TimeZone, Timelike};
+
+    let mut patterns = Vec::new();
+    for day_of_week in 0..7 {
+        for hour_of_day in 0..24 {
+            let afternoon = (13..=16).contains(&hour_of_day);
+            patterns.push(ProductivityPattern {
+                hour_of_day,
+                day_of_week,
+                average_flow_score: if afternoon { 0.9 } else { 0.3 },
+                session_count: if afternoon { 6 } else { 2 },
+                age_days: 7.0,
+            });
+        }
+    }
+
+    // Early morning: the recommendation should wait for the afternoon
+    let now = chrono::Utc.with_ymd_and_hms(2024, 3, 6, 7, 20, 0).unwrap();
+    let forecast = flow::forecast_from_patterns(patterns, now, TimeDecay::default()).await;
+    let recommendation = flow::recommend_session(&forecast, Some(45.0 * 60_000.0), now);
+
+    assert!(!recommendation.default_window);
+    assert!((12..=17).contains(&recommendation.starts_at.hour()));
+    assert!(recommendation.starts_at > now);
+    assert_eq!(recommendation.target_length_ms, 45 * 60_000);
+    assert_eq!(
+        recommendation.ends_at - recommendation.starts_at,
+        chrono::Duration::minutes(45)
+    );
+    assert!(recommendation.expected_flow_score > 0.8);
+    assert!(!recommendation.reasons.is_empty());
+
+    // No history: a common productive hour and the default length
+    let empty = flow::forecast_from_patterns(Vec::new(), now, TimeDecay::default()).await;
+    let fallback = flow::recommend_session(&empty, None, now);
+    assert!(fallback.default_window);
+    assert_eq!(fallback.starts_at.hour(), 9);
+    assert_eq!(fallback.target_length_ms, 30 * 60_000);
+    assert!(!fallback.reasons.is_empty());
+}
+
+#[tokio::test]
+async fn test_compressed_flow_state_blobs_round_trip() {
+    use mindful_code_backend::services::compression::read_json_column;
+
+    let mut engine = FlowDetectionEngine::new();
+    let result = engine
+        .analyze_flow_state(
+            sample_flow_data(),
+            None,
+        )
+        .await
+        .unwrap();
+
+    let plain = flow::FlowStateRow::new(&result, None);
+    let mut compressed = flow::FlowStateRow::new(&result, None);
+    compressed.compress().unwrap();
+
+    // Only one representation is written per column
+    assert!(compressed.typing_rhythm_data.is_none());
+    assert!(compressed.ml_features.is_none());
+
+    let rhythm = read_json_column(compressed.typing_rhythm_blob.as_deref(), None).unwrap();
+    let features = read_json_column(compressed.ml_features_blob.as_deref(), None).unwrap();
+    assert_eq!(Some(rhythm), plain.typing_rhythm_data);
+    assert_eq!(Some(features), plain.ml_features);
+
+    // Rows written before compression have no blob and still read
+    let legacy = read_json_column(None, plain.typing_rhythm_data.clone()).unwrap();
+    assert_eq!(Some(legacy), plain.typing_rhythm_data);
+}
+
+#[tokio::test]
+async fn test_ml_failure_degrades_to_rule_based_scoring() {
+    // Infinite weights of opposite sign make every prediction NaN
+    let registry_dir = std::env::temp_dir().join(format!("model_registry_{}", Uuid::new_v4()));
+    std::fs::create_dir_all(&registry_dir).unwrap();
+    std::fs::write(
+        registry_dir.join("broken.json"),
+        r#"{"weights": [1e39, -1e39, 0.0, 0.0, 0.0]}"#,
+    )
+    .unwrap();
+    let broken = ModelRegistry::new(Some(registry_dir.to_str().unwrap()))
+        .load("broken")
+        .unwrap();
+    assert!(broken.predict_flow_state([0.8, 0.7, 0.6, 0.9, 0.5]).await.is_err());
+
+    let config = FlowEngineConfig {
+        default_use_ml: true,
+        warmup_analyses: 0,
+        ..FlowEngineConfig::default()
+    };
+    let mut engine = FlowDetectionEngine::with_config(config, broken);
+    let result = engine
+        .analyze_flow_state(
+            sample_flow_data(),
+            None,
+        )
+        .await
+        .expect("ML failure must not fail the analysis");
+
+    assert!(result.degraded);
+    assert_eq!(result.model_version, RULE_BASED_MODEL_VERSION);
+    assert!(result.flow_intensity.is_finite());
+    assert!((0.0..=1.0).contains(&result.flow_intensity));
+    assert!((0.0..=1.0).contains(&result.confidence));
+    assert_eq!(engine.ml_fallbacks(), 1);
+}
+
+#[tokio::test]
+async fn test_tiny_samples_are_not_scored() {
+    // Any prediction on this model fails, so a model call would show up
+    // as a degraded result and a fallback
+    let registry_dir = std::env::temp_dir().join(format!("model_registry_{}", Uuid::new_v4()));
+    std::fs::create_dir_all(&registry_dir).unwrap();
+    std::fs::write(
+        registry_dir.join("broken.json"),
+        r#"{"weights": [1e39, -1e39, 0.0, 0.0, 0.0]}"#,
+    )
+    .unwrap();
+    let broken = ModelRegistry::new(Some(registry_dir.to_str().unwrap()))
+        .load("broken")
+        .unwrap();
+
+    let config = FlowEngineConfig {
+        default_use_ml: true,
+        min_inference_keystrokes: 5,
+        ..FlowEngineConfig::default()
+    };
+    let mut engine = FlowDetectionEngine::with_config(config, broken);
+    let result = engine
+        .analyze_flow_state(
+            FlowStateData {
+                keystroke_intervals: vec![120, 135],
+                context_switches: 0,
+                error_events: 0,
+                file_modifications: 1,
+                ..sample_flow_data()
+            },
+            None,
+        )
+        .await
+        .unwrap();
+
+    assert!(result.insufficient_data);
+    assert!(!result.is_in_flow);
+    assert!(!result.degraded);
+    assert_eq!(result.flow_intensity, 0.0);
+    assert_eq!(result.confidence, 0.0);
+    assert_eq!(result.model_version, UNSCORED_MODEL_VERSION);
+    assert_eq!(engine.ml_fallbacks(), 0);
+}
+
+#[sqlx::test(migrator = "mindful_code_backend::state::MIGRATOR")]
+async fn test_retained_session_context_is_kept_once_per_session(db: sqlx::PgPool) {
+    use axum::extract::{Path, State};
+    use axum::Json;
+    use mindful_code_backend::models::session::UpdateSessionRequest;
+
+    let user_id: Uuid = sqlx::query_scalar(
+        "INSERT INTO users (email, password_hash, privacy_settings) VALUES ('context@example.com', 'x', $1) RETURNING id",
+    )
+    .bind(serde_json::json!({
+        "analytics_enabled": true,
+        "sharing_enabled": false,
+        "encryption_level": "High",
+        "gdpr_compliant": true,
+        "telemetry_consent": false
+    }))
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    let mut config = Config::from_env().unwrap();
+    config.encryption_key = EncryptionService::key_to_hex(&EncryptionService::generate_master_key());
+    let state = AppState::from_pools(config, db.clone(), None);
+    let claims = Claims::new(
+        user_id,
+        "context@example.com".to_string(),
+        "premium".to_string(),
+    );
+    let environment = SessionEnvironment {
+        editor: Some("vim".to_string()),
+        ..Default::default()
+    };
+
+    let session = sessions::start_session(
+        State(state.clone()),
+        claims.clone(),
+        Json(StartSessionRequest {
+            project_path: Some("/home/alice/secret-project".to_string()),
+            environment_data: None,
+        }),
+    )
+    .await
+    .unwrap();
+    let session_id = session.session_id;
+    let update = |project_path: Option<&str>, environment_data: Option<SessionEnvironment>| {
+        sessions::update_session(
+            State(state.clone()),
+            claims.clone(),
+            Path(session_id),
+            Json(UpdateSessionRequest {
+                files_modified: None,
+                keystrokes: None,
+                lines_added: None,
+                lines_deleted: None,
+                project_path: project_path.map(str::to_string),
+                environment_data,
+            }),
+        )
+    };
+    update(None, Some(environment.clone())).await.unwrap();
+    update(Some("/home/alice/other-project"), None)
+        .await
+        .unwrap();
+
+    let retained: Vec<Vec<u8>> =
+        sqlx::query_scalar("SELECT encrypted_data FROM encrypted_user_data WHERE user_id = $1")
+            .bind(user_id)
+            .fetch_all(&db)
+            .await
+            .unwrap();
+    assert_eq!(retained.len(), 1);
+
+    // The latest path, and the environment from the update that sent one
+    let encrypted: EncryptedData = serde_json::from_slice(&retained[0]).unwrap();
+    let context: serde_json::Value = state
+        .encryption
+        .as_ref()
+        .unwrap()
+        .read()
+        .decrypt_sensitive_data(&encrypted)
+        .unwrap();
+    assert_eq!(context["project_path"], "/home/alice/other-project");
+    assert_eq!(context["environment_data"]["editor"], "vim");
+}
+
+#[sqlx::test(migrator = "mindful_code_backend::state::MIGRATOR")]
+async fn test_sessions_can_be_deleted_only_by_their_owner(db: sqlx::PgPool) {
+    use axum::extract::{Path, State};
+    use axum::Json;
+
+    let mut users = Vec::new();
+    for email in ["alice@example.com", "mallory@example.com"] {
+        let user_id: Uuid = sqlx::query_scalar(
+            "INSERT INTO users (email, password_hash) VALUES ($1, 'x') RETURNING id",
+        )
+        .bind(email)
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        users.push(Claims::new(user_id, email.to_string(), "free".to_string()));
+    }
+    let (alice, mallory) = (&users[0], &users[1]);
+
+    let mut sessions = Vec::new();
+    for _ in 0..4 {
+        let session_id: Uuid = sqlx::query_scalar(
+            "INSERT INTO coding_sessions (user_id, start_time) VALUES ($1, NOW()) RETURNING id",
+        )
+        .bind(alice.user_id)
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        sqlx::query(
+            "INSERT INTO flow_states (session_id, start_time, intensity_score) VALUES ($1, NOW(), 0.7)",
+        )
+        .bind(session_id)
+        .execute(&db)
+        .await
+        .unwrap();
+        sessions.push(session_id);
+    }
+    let state = AppState::from_pools(Config::from_env().unwrap(), db.clone(), None);
+    let count = |sql: &'static str| {
+        let db = db.clone();
+        async move { sqlx::query_scalar::<_, i64>(sql).fetch_one(&db).await.unwrap() }
+    };
+
+    // Someone else's session looks like it doesn't exist
+    let stolen =
+        sessions::delete_session(State(state.clone()), mallory.clone(), Path(sessions[0])).await;
+    assert!(matches!(stolen, Err(AppError::NotFound(_))));
+    let mixed = sessions::delete_sessions(
+        State(state.clone()),
+        alice.clone(),
+        Json(DeleteSessionsRequest { session_ids: vec![sessions[1], Uuid::new_v4()] }),
+    )
+    .await;
+    assert!(matches!(mixed, Err(AppError::NotFound(_))));
+    assert_eq!(count("SELECT COUNT(*) FROM coding_sessions").await, 4);
+
+    let Json(single) =
+        sessions::delete_session(State(state.clone()), alice.clone(), Path(sessions[0]))
+            .await
+            .unwrap();
+    assert_eq!(single.deleted, 1);
+
+    let Json(bulk) = sessions::delete_sessions(
+        State(state.clone()),
+        alice.clone(),
+        Json(DeleteSessionsRequest {
+            session_ids: vec![sessions[1], sessions[2], sessions[1]],
+        }),
+    )
+    .await
+    .unwrap();
+    assert_eq!(bulk.deleted, 2);
+    assert_eq!(bulk.session_ids, vec![sessions[1], sessions[2]]);
+
+    // Flow states went with their sessions
+    assert_eq!(count("SELECT COUNT(*) FROM coding_sessions").await, 1);
+    assert_eq!(count("SELECT COUNT(*) FROM flow_states").await, 1);
+}
+
+#[sqlx::test(migrator = "mindful_code_backend::state::MIGRATOR")]
+async fn test_retention_defaults_follow_subscription_tier(db: sqlx::PgPool) {
+    use mindful_code_backend::services::{
+        encryption::{ExportFormat, PrivacyManager},
+        retention::{assign_default_retention, load_retention_days, sweep_expired_sessions},
+    };
+
+    let config = RetentionConfig::default();
+    let mut users = Vec::new();
+    for (email, tier, settings) in [
+        ("free@example.com", "free", serde_json::json!({})),
+        ("premium@example.com", "premium", serde_json::json!({})),
+        ("keeper@example.com", "free", serde_json::json!({ "data_retention_days": 90 })),
+    ] {
+        let user_id: Uuid = sqlx::query_scalar(
+            "INSERT INTO users (email, password_hash, subscription_tier, privacy_settings) \
+             VALUES ($1, 'x', $2, $3) RETURNING id",
+        )
+        .bind(email)
+        .bind(tier)
+        .bind(settings)
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        sqlx::query(
+            "INSERT INTO coding_sessions (user_id, start_time) VALUES ($1, NOW() - INTERVAL '60 days')",
+        )
+        .bind(user_id)
+        .execute(&db)
+        .await
+        .unwrap();
+        users.push(user_id);
+    }
+
+    let mut conn = db.acquire().await.unwrap();
+    assert_eq!(load_retention_days(&mut conn, users[0], &config).await.unwrap(), Some(30));
+    assert_eq!(load_retention_days(&mut conn, users[1], &config).await.unwrap(), Some(365));
+    // An explicit choice wins over the tier default
+    assert_eq!(load_retention_days(&mut conn, users[2], &config).await.unwrap(), Some(90));
+
+    // Only the free user's 60-day-old session is past retention, and a dry
+    // run leaves it in place
+    assert_eq!(sweep_expired_sessions(&db, &config, 100, true).await.unwrap(), 1);
+    let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM coding_sessions")
+        .fetch_one(&db)
+        .await
+        .unwrap();
+    assert_eq!(count, 3);
+
+    // Tier defaults are written into the rows that lack one, so later
+    // changes to the defaults don't move them
+    assert_eq!(assign_default_retention(&db, &config).await.unwrap(), 2);
+    assert_eq!(assign_default_retention(&db, &config).await.unwrap(), 0);
+    let stricter = RetentionConfig {
+        premium_days: 7,
+        ..RetentionConfig::default()
+    };
+    assert_eq!(load_retention_days(&mut conn, users[1], &stricter).await.unwrap(), Some(365));
+
+    assert_eq!(sweep_expired_sessions(&db, &config, 100, false).await.unwrap(), 1);
+    let remaining: Vec<Uuid> =
+        sqlx::query_scalar("SELECT user_id FROM coding_sessions ORDER BY user_id")
+            .fetch_all(&db)
+            .await
+            .unwrap();
+    assert!(!remaining.contains(&users[0]));
+    assert_eq!(remaining.len(), 2);
+
+    // The export reports the same retention the sweeper applies
+    let privacy = PrivacyManager::new(EncryptionService::new(&[7u8; 32]).unwrap());
+    for (user_id, days) in [(users[1], 365), (users[2], 90)] {
+        let export = privacy
+            .export_user_data(&db, user_id, user_id, ExportFormat::Json, &config)
+            .await
+            .unwrap();
+        let sessions = export
+            .data_categories
+            .iter()
+            .find(|category| category.category == "coding_sessions")
+            .unwrap();
+        assert_eq!(sessions.retention_period_days, days);
+    }
+}
+
+#[tokio::test]
+async fn test_flow_broadcast_is_delivered_as_server_sent_event() {
+    use futures::StreamExt;
+
+    let db = sqlx::postgres::PgPoolOptions::new()
+        .connect_lazy("postgresql://localhost/mindful_code")
+        .unwrap();
+    let state = AppState::from_pools(Config::from_env().unwrap(), db, None);
+    let user_id = Uuid::new_v4();
+    let claims = Claims::new(user_id, "dev@example.com".to_string(), "free".to_string());
+
+    let response = websocket::event_stream_handler(axum::extract::State(state.clone()), claims)
+        .await
+        .into_response();
+    assert_eq!(
+        response.headers()[axum::http::header::CONTENT_TYPE],
+        "text/event-stream"
+    );
+    let mut body = response.into_body().into_data_stream();
+
+    let session_id = Uuid::new_v4();
+    websocket::broadcast_flow_update(
+        &state,
+        user_id,
+        session_id,
+        serde_json::json!({ "is_in_flow": true, "flow_intensity": 0.85 }),
+    )
+    .await;
+
+    let frame = tokio::time::timeout(Duration::from_secs(1), body.next())
+        .await
+        .expect("broadcast should arrive as an event")
+        .unwrap()
+        .unwrap();
+    let frame = String::from_utf8(frame.to_vec()).unwrap();
+    assert!(frame.starts_with("event: flow_state_update\ndata: "));
+    assert!(frame.contains(&session_id.to_string()));
+
+    // Disconnecting drops the body, which releases the user's channel
+    drop(body);
+    assert!(!state.websocket_connections.contains_key(&user_id));
+}
+
+#[tokio::test]
+async fn test_closing_a_replaced_connection_keeps_the_newer_one() {
+    let db = sqlx::postgres::PgPoolOptions::new()
+        .connect_lazy("postgresql://localhost/mindful_code")
+        .unwrap();
+    let state = AppState::from_pools(Config::from_env().unwrap(), db, None);
+    let user_id = Uuid::new_v4();
+    let team_id = Uuid::new_v4();
+
+    let (older, older_socket) = tokio::sync::mpsc::unbounded_channel();
+    state.add_websocket_connection(user_id, older, false);
+    let (newer, mut newer_socket) = tokio::sync::mpsc::unbounded_channel();
+    state.add_websocket_connection(user_id, newer, false);
+    state.team_dashboards.subscribe(team_id, user_id);
+
+    // The older socket's writer is gone, but the registration is the newer one's
+    drop(older_socket);
+    assert!(!state.remove_closed_websocket_connection(user_id));
+    assert_eq!(state.team_dashboards.watched_teams(), vec![team_id]);
+    state.broadcast_to_user(user_id, "still here".to_string()).await;
+    assert_eq!(newer_socket.recv().await.unwrap(), "still here");
+
+    drop(newer_socket);
+    assert!(state.remove_closed_websocket_connection(user_id));
+    assert!(!state.websocket_connections.contains_key(&user_id));
+}
+
+#[tokio::test]
+async fn test_refresh_tokens_are_refused_on_protected_routes() {
+    use axum::{
+        body::Body,
+        http::{Request, StatusCode},
+        routing::get,
+        Router,
+    };
+    use mindful_code_backend::utils::auth::{
+        auth_middleware, generate_jwt_token_with, generate_refresh_token,
+    };
+    use tower::ServiceExt;
+
+    let config = Config::from_env().unwrap();
+    let whoami = || get(|claims: Claims| async move { claims.user_id.to_string() });
+    let guarded = Router::new()
+        .route("/api/sessions", whoami())
+        .layer(axum::middleware::from_fn(auth_middleware));
+    // The extractor checks on its own too, for routes the middleware skips
+    let bare = Router::new().route("/api/sessions", whoami());
+    let send = |app: &Router, token: &str| {
+        app.clone().oneshot(
+            Request::get("/api/sessions")
+                .header("authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap(),
+        )
+    };
+
+    let user_id = Uuid::new_v4();
+    let access = generate_jwt_token_with(
+        &Claims::new(user_id, "tokens@example.com".to_string(), "free".to_string()),
+        &config.jwt_secret,
+        config.jwt_algorithm,
+    )
+    .unwrap();
+    let refresh = generate_refresh_token(user_id, &config.jwt_secret, config.jwt_algorithm).unwrap();
+
+    for app in [&guarded, &bare] {
+        assert_eq!(send(app, &access).await.unwrap().status(), StatusCode::OK);
+        assert_eq!(
+            send(app, &refresh).await.unwrap().status(),
+            StatusCode::UNAUTHORIZED
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_flow_detect_concurrency_is_limited_per_user() {
+    let db = sqlx::postgres::PgPoolOptions::new()
+        .connect_lazy("postgresql://localhost/mindful_code")
+        .unwrap();
+    let mut config = Config::from_env().unwrap();
+    config.flow_detect_concurrency_per_user = 2;
+    let state = AppState::from_pools(config, db, None);
+
+    let busy = Claims::anonymous(Uuid::new_v4(), chrono::Duration::minutes(60));
+    let other = Claims::anonymous(Uuid::new_v4(), chrono::Duration::minutes(60));
+    let detect = |claims: Claims| {
+        flow::detect_flow_state(
+            axum::extract::State(state.clone()),
+            claims,
+            ResponseFormat::default(),
+            axum::extract::Query(Default::default()),
+            axum::Json(flow::FlowDetectionPayload {
+                request: FlowDetectionRequest {
+                    flow_data: sample_flow_data(),
+                    user_preferences: None,
+                },
+            }),
+        )
+    };
+
+    // Two detections already in flight for the busy user
+    let in_flight = [
+        state.try_acquire_flow_detect_slot(busy.user_id).unwrap(),
+        state.try_acquire_flow_detect_slot(busy.user_id).unwrap(),
+    ];
+
+    let limited = detect(busy.clone()).await.unwrap_err().into_response();
+    assert_eq!(limited.status(), axum::http::StatusCode::TOO_MANY_REQUESTS);
+    assert!(detect(other.clone()).await.is_ok());
+
+    // Slots in use survive cleanup; idle ones are forgotten
+    state.cleanup_idle_flow_detect_slots();
+    assert!(state.flow_detect_slots.contains_key(&busy.user_id));
+    assert!(!state.flow_detect_slots.contains_key(&other.user_id));
+
+    drop(in_flight);
+    assert!(detect(busy.clone()).await.is_ok());
+    state.cleanup_idle_flow_detect_slots();
+    assert!(state.flow_detect_slots.is_empty());
+}
+
+#[tokio::test]
+async fn test_requests_past_the_global_limit_are_shed() {
+    use axum::{
+        body::Body,
+        http::{Request, StatusCode},
+        routing::get,
+        Router,
+    };
+    use mindful_code_backend::utils::load_shed::load_shed_middleware;
+    use tower::ServiceExt;
+
+    let db = sqlx::postgres::PgPoolOptions::new()
+        .connect_lazy("postgresql://localhost/mindful_code")
+        .unwrap();
+    let mut config = Config::from_env().unwrap();
+    config.load_shedding.max_in_flight_requests = 2;
+    config.load_shedding.retry_after_secs = 5;
+    let state = AppState::from_pools(config, db, None);
+
+    let app = Router::new()
+        .route("/health", get(|| async { "ok" }))
+        .route("/api/flow/patterns", get(|| async { "patterns" }))
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            load_shed_middleware,
+        ));
+    let send = |uri: &str| {
+        app.clone()
+            .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+    };
+
+    assert_eq!(send("/api/flow/patterns").await.unwrap().status(), StatusCode::OK);
+
+    // Two requests already in flight fill the server
+    let slots = state.request_slots.clone().unwrap();
+    let in_flight = [
+        slots.clone().try_acquire_owned().unwrap(),
+        slots.clone().try_acquire_owned().unwrap(),
+    ];
+
+    let shed = send("/api/flow/patterns").await.unwrap();
+    assert_eq!(shed.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(shed.headers()["retry-after"], "5");
+    assert_eq!(send("/health").await.unwrap().status(), StatusCode::OK);
+
+    drop(in_flight);
+    assert_eq!(send("/api/flow/patterns").await.unwrap().status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_flow_detect_times_out_before_exports() {
+    use axum::{
+        body::{to_bytes, Body},
+        http::{Request, StatusCode},
+        routing::{get, post},
+        Router,
+    };
+    use mindful_code_backend::{
+        config::RequestTimeoutConfig, utils::request_timeout::request_timeout_middleware,
+    };
+    use std::time::Duration;
+    use tower::ServiceExt;
+
+    let db = sqlx::postgres::PgPoolOptions::new()
+        .connect_lazy("postgresql://localhost/mindful_code")
+        .unwrap();
+    let mut config = Config::from_env().unwrap();
+    config.request_timeouts = RequestTimeoutConfig {
+        default_ms: 1_000,
+        flow_detect_ms: 50,
+        export_ms: 2_000,
+    };
+    let state = AppState::from_pools(config, db, None);
+
+    // Both handlers take the same time; only the export is allowed it
+    let slow = || async {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        "done"
+    };
+    let app = Router::new()
+        .route("/api/flow/detect", post(slow))
+        .route("/api/flow/export", get(slow))
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            request_timeout_middleware,
+        ));
+    let send = |method: &str, uri: &str| {
+        app.clone().oneshot(
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap(),
+        )
+    };
+
+    let started = std::time::Instant::now();
+    let detect = send("POST", "/api/flow/detect").await.unwrap();
+    assert!(started.elapsed() < Duration::from_millis(200));
+    assert_eq!(detect.status(), StatusCode::REQUEST_TIMEOUT);
+    let body: serde_json::Value =
+        serde_json::from_slice(&to_bytes(detect.into_body(), usize::MAX).await.unwrap()).unwrap();
+    assert_eq!(body["status"], 408);
+    assert_eq!(body["error"], "Request took longer than 50ms");
+
+    let export = send("GET", "/api/flow/export").await.unwrap();
+    assert_eq!(export.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_flow_detect_is_counted_against_the_callers_tier() {
+    use axum::{
+        body::Body,
+        http::{Request, StatusCode},
+        routing::{get, post},
+        Router,
+    };
+    use mindful_code_backend::utils::tier_usage::tier_usage_middleware;
+    use tower::ServiceExt;
+
+    let db = sqlx::postgres::PgPoolOptions::new()
+        .connect_lazy("postgresql://localhost/mindful_code")
+        .unwrap();
+    let state = AppState::from_pools(Config::from_env().unwrap(), db, None);
+
+    let app = Router::new()
+        .route("/api/flow/detect", post(|| async { "detected" }))
+        .route("/api/unmetered", get(|| async { "ok" }))
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            tier_usage_middleware,
+        ));
+    let premium = Claims::new(
+        Uuid::new_v4(),
+        "premium@example.com".to_string(),
+        "premium".to_string(),
+    );
+    let token = generate_jwt_token(&premium, &state.config.jwt_secret).unwrap();
+    let send = |method: &str, uri: &str| {
+        app.clone().oneshot(
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap(),
+        )
+    };
+
+    assert_eq!(send("POST", "/api/flow/detect").await.unwrap().status(), StatusCode::OK);
+    assert_eq!(send("GET", "/api/unmetered").await.unwrap().status(), StatusCode::OK);
+
+    let (_, body) = health::metrics(
+        axum::extract::State(state.clone()),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert!(body.contains(
+        "mindful_code_tier_requests_total{tier=\"premium\",endpoint=\"flow_detect\"} 1"
+    ));
+    // Only the fixed endpoint set is ever labelled
+    assert_eq!(body.matches("mindful_code_tier_requests_total{").count(), 1);
+}
+
+#[tokio::test]
+async fn test_flow_results_report_initializing_until_warm_up() {
+    use mindful_code_backend::{models::flow::ServerMode, services::readiness::warm_up_flow_model};
+
+    let db = sqlx::postgres::PgPoolOptions::new()
+        .connect_lazy("postgresql://localhost/mindful_code")
+        .unwrap();
+    let state = AppState::from_pools(Config::from_env().unwrap(), db.clone(), None);
+    let claims = Claims::anonymous(Uuid::new_v4(), chrono::Duration::minutes(60));
+    let session_id = Uuid::new_v4();
+    let detect = |state: AppState, timestamp: i64| {
+        flow::detect_flow_state(
+            axum::extract::State(state),
+            claims.clone(),
+            ResponseFormat::default(),
+            axum::extract::Query(Default::default()),
+            axum::Json(flow::FlowDetectionPayload {
+                request: FlowDetectionRequest {
+                    flow_data: FlowStateData {
+                        session_id,
+                        timestamp,
+                        ..sample_flow_data()
+                    },
+                    user_preferences: None,
+                },
+            }),
+        )
+    };
+    let now = chrono::Utc::now().timestamp_millis();
+
+    assert_eq!(state.readiness.mode(), ServerMode::Initializing);
+    let early = detect(state.clone(), now).await.unwrap().into_data();
+    assert_eq!(early.server_mode, Some(ServerMode::Initializing));
+    let json = serde_json::to_value(&early).unwrap();
+    assert_eq!(json["server_mode"], "initializing");
+
+    warm_up_flow_model(state.clone()).await;
+    assert_eq!(state.readiness.mode(), ServerMode::Ready);
+    let ready = detect(state.clone(), now + 1000).await.unwrap().into_data();
+    assert_eq!(ready.server_mode, None);
+    assert!(serde_json::to_value(&ready).unwrap().get("server_mode").is_none());
+
+    // Configured to require the model, detection waits for warm-up instead
+    let mut config = Config::from_env().unwrap();
+    config.require_model_ready = true;
+    let strict = AppState::from_pools(config, db, None);
+    assert!(matches!(
+        detect(strict.clone(), now).await,
+        Err(AppError::ServiceUnavailable(_))
+    ));
+    warm_up_flow_model(strict.clone()).await;
+    assert!(detect(strict, now + 2000).await.is_ok());
+}
+
+#[sqlx::test(migrator = "mindful_code_backend::state::MIGRATOR")]
+async fn test_insight_pipeline_refreshes_instead_of_duplicating(db: sqlx::PgPool) {
+    use chrono::TimeZone;
+    use mindful_code_backend::services::insights::{expire_insights, refresh_user_insights};
+
+    let user_id: Uuid = sqlx::query_scalar(
+        "INSERT INTO users (email, password_hash) VALUES ($1, 'x') RETURNING id",
+    )
+    .bind("insights@example.com")
+    .fetch_one(&db)
+    .await
+    .unwrap();
+
+    // A Wednesday, so both runs fall in the same week
+    let now = chrono::Utc
+        .with_ymd_and_hms(2026, 10, 14, 12, 0, 0)
+        .unwrap();
+    let session_id: Uuid = sqlx::query_scalar(
+        r#"
+        INSERT INTO coding_sessions (user_id, start_time, end_time, total_flow_time_ms)
+        VALUES ($1, $2, $3, 1800000) RETURNING id
+        "#,
+    )
+    .bind(user_id)
+    .bind(now - chrono::Duration::hours(1))
+    .bind(now - chrono::Duration::minutes(1))
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    let add_samples = |count: i64| {
+        let db = db.clone();
+        async move {
+            for i in 0..count {
+                sqlx::query(
+                    "INSERT INTO flow_states (session_id, start_time, intensity_score) VALUES ($1, $2, 0.8)",
+                )
+                .bind(session_id)
+                .bind(now - chrono::Duration::minutes(30 + i))
+                .execute(&db)
+                .await
+                .unwrap();
+            }
+        }
+    };
+
+    let mut conn = db.acquire().await.unwrap();
+    add_samples(5).await;
+    let stored = refresh_user_insights(&mut conn, user_id, now)
+        .await
+        .unwrap();
+    assert_eq!(stored, 2);
+    add_samples(5).await;
+    let later = now + chrono::Duration::hours(1);
+    let stored = refresh_user_insights(&mut conn, user_id, later)
+        .await
+        .unwrap();
+    assert_eq!(stored, 2);
+
+    let rows: Vec<(String, bool, serde_json::Value)> = sqlx::query_as(
+        "SELECT insight_type, is_active, insight_data FROM user_insights WHERE user_id = $1 ORDER BY insight_type",
+    )
+    .bind(user_id)
+    .fetch_all(&db)
+    .await
+    .unwrap();
+    assert_eq!(rows.len(), 2);
+    assert_eq!(rows[0].0, "peak_flow_hour");
+    assert_eq!(rows[1].0, "weekly_flow_time");
+    assert!(rows.iter().all(|(_, active, _)| *active));
+    // The second run updated the stored insight rather than adding one
+    assert_eq!(rows[0].2["data_points"], 10);
+
+    let expiry_days = 14;
+    let not_yet = later + chrono::Duration::days(expiry_days - 1);
+    let expired = expire_insights(&mut conn, not_yet, expiry_days)
+        .await
+        .unwrap();
+    assert_eq!(expired, 0);
+    let past_expiry = later + chrono::Duration::days(expiry_days + 1);
+    let expired = expire_insights(&mut conn, past_expiry, expiry_days)
+        .await
+        .unwrap();
+    assert_eq!(expired, 2);
+
+    // Regenerating for the same week reactivates the existing rows
+    refresh_user_insights(&mut conn, user_id, later)
+        .await
+        .unwrap();
+    let active: i64 =
+        sqlx::query_scalar("SELECT COUNT(*) FROM user_insights WHERE user_id = $1 AND is_active")
+            .bind(user_id)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+    assert_eq!(active, 2);
+}
+
+#[sqlx::test(migrator = "mindful_code_backend::state::MIGRATOR")]
+async fn test_sensitive_notifications_are_sealed_for_high_security_users(db: sqlx::PgPool) {
+    use mindful_code_backend::services::encryption::open_payload;
+
+    let user_id: Uuid = sqlx::query_scalar(
+        "INSERT INTO users (email, password_hash, privacy_settings) VALUES ($1, 'x', $2) RETURNING id",
+    )
+    .bind("sealed@example.com")
+    .bind(serde_json::json!({
+        "analytics_enabled": true,
+        "sharing_enabled": false,
+        "encryption_level": "High",
+        "gdpr_compliant": true
+    }))
+    .fetch_one(&db)
+    .await
+    .unwrap();
+
+    let mut config = Config::from_env().unwrap();
+    config.websocket_payload_encryption = true;
+    let state = AppState::from_pools(config, db, None);
+    let password = "correct horse battery staple";
+    let client_key = auth::remember_payload_key(&state, user_id, password)
+        .await
+        .unwrap()
+        .expect("high-security users get a payload key");
+
+    // The salt is random and kept, so the next sign-in derives the same key
+    let again = auth::remember_payload_key(&state, user_id, password).await;
+    assert_eq!(again.unwrap().as_deref(), Some(&*client_key));
+
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<String>();
+    state.add_websocket_connection(user_id, tx, false);
+    let details = "Burnout risk is high: 11 hours in flow today";
+    let notify = || {
+        websocket::send_notification(
+            &state,
+            user_id,
+            "Burnout risk".to_string(),
+            details.to_string(),
+            websocket::NotificationLevel::Warning,
+        )
+    };
+
+    notify().await;
+    let wire = rx.try_recv().unwrap();
+    assert!(!wire.contains("Burnout risk is high"));
+    let json: serde_json::Value = serde_json::from_str(&wire).unwrap();
+    assert_eq!(json["encryption"], "aes-256-gcm");
+
+    // The client opens it with the key it was handed at login
+    let sealed = json["message"].as_str().unwrap();
+    assert_eq!(open_payload(&client_key, user_id, sealed).unwrap(), details);
+    assert!(open_payload(&client_key, Uuid::new_v4(), sealed).is_err());
+
+    // Without a key, as after a restart, the details are withheld
+    state.payload_keys.remove(user_id);
+    notify().await;
+    let json: serde_json::Value = serde_json::from_str(&rx.try_recv().unwrap()).unwrap();
+    assert!(json.get("encryption").is_none());
+    assert_eq!(json["message"], "Sign in again to read this notification");
+}
+
+#[sqlx::test(migrator = "mindful_code_backend::state::MIGRATOR")]
+async fn test_flow_diff_reports_improvement_after_a_tool_change(db: sqlx::PgPool) {
+    use mindful_code_backend::models::flow::FlowDiffRequest;
+
+    let insert_user = |email: &'static str| {
+        let db = db.clone();
+        async move {
+            sqlx::query_scalar::<_, Uuid>(
+                "INSERT INTO users (email, password_hash) VALUES ($1, 'x') RETURNING id",
+            )
+            .bind(email)
+            .fetch_one(&db)
+            .await
+            .unwrap()
+        }
+    };
+    let user_id = insert_user("diff@example.com").await;
+    let other_id = insert_user("other-diff@example.com").await;
+
+    let insert_session = |owner: Uuid, score: f64, flow_time_ms: i64| {
+        let db = db.clone();
+        async move {
+            let session_id: Uuid = sqlx::query_scalar(
+                r#"
+                INSERT INTO coding_sessions (user_id, start_time, end_time, total_flow_time_ms)
+                VALUES ($1, NOW() - INTERVAL '2 hours', NOW() - INTERVAL '1 hour', $2)
+                RETURNING id
+                "#,
+            )
+            .bind(owner)
+            .bind(flow_time_ms)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+            for offset in [-0.01, 0.0, 0.01] {
+                let score = score + offset;
+                sqlx::query(
+                    "INSERT INTO flow_states (session_id, start_time, intensity_score, ml_features) VALUES ($1, NOW(), 0.5, $2)",
+                )
+                .bind(session_id)
+                .bind(serde_json::json!({
+                    "rhythm_score": score,
+                    "focus_score": score,
+                    "consistency_score": score,
+                    "velocity_score": score,
+                    "error_penalty": 0.1
+                }))
+                .execute(&db)
+                .await
+                .unwrap();
+            }
+            session_id
+        }
+    };
+
+    let mut before = Vec::new();
+    let mut after = Vec::new();
+    for (i, jitter) in [0.0, 0.02, -0.02, 0.01].into_iter().enumerate() {
+        let flow_minutes = 20 + i as i64;
+        before.push(insert_session(user_id, 0.4 + jitter, flow_minutes * 60_000).await);
+        after.push(insert_session(user_id, 0.75 + jitter, (flow_minutes + 30) * 60_000).await);
+    }
+
+    let state = AppState::from_pools(Config::from_env().unwrap(), db.clone(), None);
+    let claims = Claims::new(
+        user_id,
+        "diff@example.com".to_string(),
+        "premium".to_string(),
+    );
+    let diff = |before: Vec<Uuid>, after: Vec<Uuid>| {
+        flow::diff_flow_sessions(
+            axum::extract::State(state.clone()),
+            claims.clone(),
+            ResponseFormat::default(),
+            axum::Json(FlowDiffRequest { before, after }),
+        )
+    };
+
+    let result = diff(before.clone(), after.clone())
+        .await
+        .unwrap()
+        .into_data();
+    for metric in [
+        &result.rhythm,
+        &result.focus,
+        &result.consistency,
+        &result.velocity,
+        &result.flow_time_ms,
+    ] {
+        assert!(metric.delta > 0.0, "{:?}", metric);
+        assert!(metric.confidence > 0.95, "{:?}", metric);
+        assert_eq!((metric.before_samples, metric.after_samples), (4, 4));
+    }
+    assert!((result.rhythm.delta - 0.35).abs() < 1e-6);
+    assert!((result.flow_time_ms.delta - 1_800_000.0).abs() < 1e-6);
+
+    // A single session a side says nothing either way
+    let thin = diff(before[..1].to_vec(), after[..1].to_vec())
+        .await
+        .unwrap()
+        .into_data();
+    assert_eq!(thin.rhythm.confidence, 0.0);
+
+    // Someone else's session can't be compared
+    let foreign = insert_session(other_id, 0.9, 60_000).await;
+    let mut with_foreign = after.clone();
+    with_foreign.push(foreign);
+    assert!(matches!(
+        diff(before.clone(), with_foreign).await,
+        Err(AppError::NotFound(_))
+    ));
+    assert!(matches!(
+        diff(before.clone(), before).await,
+        Err(AppError::Validation(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_velocity_units_score_the_same_typing_speed_alike() {
+    use mindful_code_backend::models::flow::VelocityUnit;
+
+    // 300 characters per minute, three ways
+    let mut scores = Vec::new();
+    for (velocity, unit) in [
+        (300.0, VelocityUnit::CharsPerMinute),
+        (60.0, VelocityUnit::WordsPerMinute),
+        (5.0, VelocityUnit::KeystrokesPerSecond),
+    ] {
+        let data = FlowStateData {
+            context_switches: 1,
+            error_events: 0,
+            typing_velocity: Some(velocity),
+            velocity_unit: unit,
+            ..sample_flow_data()
+        };
+        assert_eq!(data.typing_velocity_cpm(), Some(300.0));
+        let result = FlowDetectionEngine::new()
+            .analyze_flow_state(data, None)
+            .await
+            .unwrap();
+        scores.push(result.metrics.velocity_score);
+    }
+    assert_eq!(scores, vec![0.9; 3]);
+
+    // Clients that don't name a unit keep meaning characters per minute
+    let json = serde_json::json!({
+        "session_id": Uuid::new_v4(),
+        "keystroke_intervals": [120, 135],
+        "context_switches": 0,
+        "error_events": 0,
+        "window_focus_duration": 1000,
+        "file_modifications": 0,
+        "timestamp": 0,
+        "typing_velocity": 60.0,
+        "pause_patterns": null
+    });
+    let legacy: FlowStateData = serde_json::from_value(json.clone()).unwrap();
+    assert_eq!(legacy.velocity_unit, VelocityUnit::CharsPerMinute);
+    assert_eq!(legacy.typing_velocity_cpm(), Some(60.0));
+
+    let mut wpm = json;
+    wpm["velocity_unit"] = "words_per_minute".into();
+    let wpm: FlowStateData = serde_json::from_value(wpm).unwrap();
+    assert_eq!(wpm.typing_velocity_cpm(), Some(300.0));
+}
+
+#[sqlx::test(migrator = "mindful_code_backend::state::MIGRATOR")]
+async fn test_sessions_can_be_tagged_and_searched(db: sqlx::PgPool) {
+    use axum::extract::{Path, Query, State};
+    use axum::Json;
+    use mindful_code_backend::{
+        models::session::{SessionSearchQuery, SessionTagsRequest},
+        utils::date_range::DateRangeQuery,
+    };
+
+    let insert_user = |email: &'static str| {
+        let db = db.clone();
+        async move {
+            sqlx::query_scalar::<_, Uuid>(
+                "INSERT INTO users (email, password_hash) VALUES ($1, 'x') RETURNING id",
+            )
+            .bind(email)
+            .fetch_one(&db)
+            .await
+            .unwrap()
+        }
+    };
+    let user_id = insert_user("tags@example.com").await;
+    let other_id = insert_user("other-tags@example.com").await;
+
+    let insert_session = |hours_ago: i32, intensity: f64, languages: serde_json::Value| {
+        let db = db.clone();
+        async move {
+            sqlx::query_scalar::<_, Uuid>(
+                r#"
+                INSERT INTO coding_sessions
+                    (user_id, start_time, end_time, avg_flow_intensity, language_breakdown)
+                VALUES ($1, NOW() - make_interval(hours => $2), NOW(), $3, $4)
+                RETURNING id
+                "#,
+            )
+            .bind(user_id)
+            .bind(hours_ago)
+            .bind(intensity)
+            .bind(languages)
+            .fetch_one(&db)
+            .await
+            .unwrap()
+        }
+    };
+    let rust = serde_json::json!({ "rust": 3_600_000 });
+    let deep_rust = insert_session(3, 0.8, rust.clone()).await;
+    let shallow_rust = insert_session(2, 0.3, rust.clone()).await;
+    let deep_go = insert_session(1, 0.9, serde_json::json!({ "go": 3_600_000 })).await;
+
+    let state = AppState::from_pools(Config::from_env().unwrap(), db.clone(), None);
+    let claims = Claims::new(
+        user_id,
+        "tags@example.com".to_string(),
+        "premium".to_string(),
+    );
+    let tag = |session_id: Uuid, tags: &[&str]| {
+        sessions::add_session_tags(
+            State(state.clone()),
+            claims.clone(),
+            Path(session_id),
+            Json(SessionTagsRequest {
+                tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            }),
+        )
+    };
+
+    let tagged = tag(deep_rust, &["Deep  Work", "refactor"]).await.unwrap().0;
+    assert_eq!(tagged.tags, vec!["deep work", "refactor"]);
+    // Adding again is idempotent
+    let tagged = tag(deep_rust, &["deep work"]).await.unwrap().0;
+    assert_eq!(tagged.tags, vec!["deep work", "refactor"]);
+    tag(shallow_rust, &["deep work"]).await.unwrap();
+    tag(deep_go, &["deep work"]).await.unwrap();
+    assert!(matches!(
+        tag(deep_go, &["   "]).await,
+        Err(AppError::Validation(_))
+    ));
+
+    let search = |query: SessionSearchQuery| {
+        sessions::search_sessions(
+            State(state.clone()),
+            claims.clone(),
+            Query(DateRangeQuery::default()),
+            Query(query),
+        )
+    };
+
+    let found = search(SessionSearchQuery {
+        tag: Some("Deep Work".to_string()),
+        min_intensity: Some(0.5),
+        ..Default::default()
+    })
+    .await
+    .unwrap()
+    .0;
+    let ids: Vec<Uuid> = found.sessions.iter().map(|s| s.session_id).collect();
+    assert_eq!(ids, vec![deep_go, deep_rust]);
+    assert_eq!(found.sessions[1].languages, vec!["rust"]);
+    assert_eq!(found.sessions[1].tags, vec!["deep work", "refactor"]);
+    assert!(found.next_cursor.is_none());
+
+    let rust_only = search(SessionSearchQuery {
+        language: Some("rust".to_string()),
+        ..Default::default()
+    })
+    .await
+    .unwrap()
+    .0;
+    let ids: Vec<Uuid> = rust_only.sessions.iter().map(|s| s.session_id).collect();
+    assert_eq!(ids, vec![shallow_rust, deep_rust]);
+
+    // Paging walks every match exactly once
+    let mut cursor = None;
+    let mut paged = Vec::new();
+    loop {
+        let page = search(SessionSearchQuery {
+            tag: Some("deep work".to_string()),
+            limit: Some(1),
+            cursor: cursor.take(),
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+        .0;
+        paged.extend(page.sessions.iter().map(|s| s.session_id));
+        match page.next_cursor {
+            Some(next) => cursor = Some(next),
+            None => break,
+        }
+    }
+    assert_eq!(paged, vec![deep_go, shallow_rust, deep_rust]);
+
+    // Replacing and deleting
+    let replaced = sessions::replace_session_tags(
+        State(state.clone()),
+        claims.clone(),
+        Path(deep_rust),
+        Json(SessionTagsRequest {
+            tags: vec!["spike".to_string()],
+        }),
+    )
+    .await
+    .unwrap()
+    .0;
+    assert_eq!(replaced.tags, vec!["spike"]);
+    let remaining = sessions::delete_session_tag(
+        State(state.clone()),
+        claims.clone(),
+        Path((deep_rust, "Spike".to_string())),
+    )
+    .await
+    .unwrap()
+    .0;
+    assert!(remaining.tags.is_empty());
+
+    // Other users can't see or tag these sessions
+    let other = Claims::new(
+        other_id,
+        "other-tags@example.com".to_string(),
+        "premium".to_string(),
+    );
+    let foreign = sessions::get_session_tags(State(state.clone()), other, Path(deep_go)).await;
+    assert!(matches!(foreign, Err(AppError::NotFound(_))));
+}
+
+#[sqlx::test(migrator = "mindful_code_backend::state::MIGRATOR")]
+async fn test_ending_a_session_persists_engine_flow_stats(db: sqlx::PgPool) {
+    use axum::extract::{Path, State};
+    use mindful_code_backend::services::flow_stats::load_user_flow_totals;
+
+    let user_id: Uuid = sqlx::query_scalar(
+        "INSERT INTO users (email, password_hash) VALUES ('stats@example.com', 'x') RETURNING id",
+    )
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    let session_id: Uuid = sqlx::query_scalar(
+        "INSERT INTO coding_sessions (user_id, start_time) VALUES ($1, NOW() - INTERVAL '1 hour') RETURNING id",
+    )
+    .bind(user_id)
+    .fetch_one(&db)
+    .await
+    .unwrap();
+
+    let mut config = Config::from_env().unwrap();
+    config.flow_engine.warmup_analyses = 0;
+    config.flow_engine.min_flow_duration_ms = 50;
+    let state = AppState::from_pools(config, db.clone(), None);
+    let claims = Claims::new(
+        user_id,
+        "stats@example.com".to_string(),
+        "premium".to_string(),
+    );
+
+    let preferences = |sensitivity_level| UserFlowPreferences {
+        sensitivity_level,
+        notification_threshold: 0.6,
+        focus_mode_enabled: false,
+        break_reminders_enabled: true,
+        personalized_calibration: false,
+        use_ml: Some(false),
+    };
+    let flow_data = FlowStateData {
+        session_id,
+        keystroke_intervals: vec![120, 125, 118, 122, 130, 119, 121, 124, 117, 123],
+        context_switches: 0,
+        error_events: 0,
+        window_focus_duration: 600000,
+        file_modifications: 4,
+        typing_velocity: Some(280.0),
+        ..sample_flow_data()
+    };
+
+    // One flow stretch of at least 80ms; sensitivity 0 always enters flow,
+    // 1 always leaves it
+    let engine = state.get_or_create_flow_engine(user_id, session_id);
+    engine
+        .write()
+        .analyze_flow_state(flow_data.clone(), Some(preferences(0.0)))
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(80)).await;
+    engine
+        .write()
+        .analyze_flow_state(flow_data, Some(preferences(1.0)))
+        .await
+        .unwrap();
+    drop(engine);
+
+    assert_eq!(
+        load_user_flow_totals(&db, user_id)
+            .await
+            .unwrap()
+            .flow_session_count,
+        0
+    );
+
+    sessions::end_session(State(state.clone()), claims, Path(session_id))
+        .await
+        .unwrap();
+
+    assert!(state.user_flow_engines(user_id).is_empty());
+    let totals = load_user_flow_totals(&db, user_id).await.unwrap();
+    assert_eq!(totals.flow_session_count, 1);
+    assert!(totals.total_flow_time_ms >= 80, "{:?}", totals);
+}
+
+#[sqlx::test(migrator = "mindful_code_backend::state::MIGRATOR")]
+async fn test_evicted_engines_persist_flow_stats(db: sqlx::PgPool) {
+    use mindful_code_backend::services::flow_stats::load_user_flow_totals;
+
+    let user_id: Uuid = sqlx::query_scalar(
+        "INSERT INTO users (email, password_hash) VALUES ('evicted@example.com', 'x') RETURNING id",
+    )
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    let mut config = Config::from_env().unwrap();
+    config.flow_engine.warmup_analyses = 0;
+    config.flow_engine.min_flow_duration_ms = 50;
+    let state = AppState::from_pools(config, db.clone(), None);
+
+    // One flow stretch of at least 60ms; sensitivity 0 always enters flow,
+    // 1 always leaves it
+    let flow_stretch = |session_id: Uuid| {
+        let engine = state.get_or_create_flow_engine(user_id, session_id);
+        async move {
+            for (sensitivity_level, pause) in [(0.0, 60), (1.0, 0)] {
+                engine
+                    .write()
+                    .analyze_flow_state(
+                        FlowStateData {
+                            session_id,
+                            ..sample_flow_data()
+                        },
+                        Some(UserFlowPreferences {
+                            sensitivity_level,
+                            notification_threshold: 0.6,
+                            focus_mode_enabled: false,
+                            break_reminders_enabled: true,
+                            personalized_calibration: false,
+                            use_ml: Some(false),
+                        }),
+                    )
+                    .await
+                    .unwrap();
+                tokio::time::sleep(Duration::from_millis(pause)).await;
+            }
+        }
+    };
+    // Retired on a spawned task, so wait for the totals to catch up
+    let totals_reach = |count: u64| {
+        let db = db.clone();
+        async move {
+            for _ in 0..100 {
+                if load_user_flow_totals(&db, user_id).await.unwrap().flow_session_count >= count {
+                    return;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            panic!("flow stats never reached {}", count);
+        }
+    };
+
+    // The least recently used engine makes room for a new one
+    flow_stretch(Uuid::new_v4()).await;
+    let mut newest = Uuid::nil();
+    for _ in 0..16 {
+        newest = Uuid::new_v4();
+        state.get_or_create_flow_engine(user_id, newest);
+    }
+    assert_eq!(state.user_flow_engines(user_id).len(), 16);
+    totals_reach(1).await;
+
+    // Idle cleanup retires its engines the same way
+    flow_stretch(newest).await;
+    state.cleanup_idle_flow_engines(Duration::ZERO);
+    assert!(state.user_flow_engines(user_id).is_empty());
+    totals_reach(2).await;
+}
+
+/// A typical sample for a fresh session, timestamped now. Tests override
+/// what they exercise with `..sample_flow_data()`.
+fn sample_flow_data() -> FlowStateData {
+    FlowStateData {
+        session_id: Uuid::new_v4(),
+        keystroke_intervals: vec![120, 135, 98, 142, 156, 89, 167, 134, 145, 123],
+        context_switches: 2,
+        error_events: 1,
+        window_focus_duration: 30000,
+        file_modifications: 5,
+        timestamp: chrono::Utc::now().timestamp_millis(),
+        typing_velocity: Some(250.0),
+        pause_patterns: None,
+        aggregates: None,
+        velocity_unit: Default::default(),
+    }
+}
+
+/// Stores one flow state per `(timestamp_ms, is_in_flow)` sample, the way
+/// detection persists them.
+async fn store_flow_samples(
+    db: &sqlx::PgPool,
+    user_id: Uuid,
+    session_id: Uuid,
+    samples: &[(i64, bool)],
+) {
+    let mut engine = FlowDetectionEngine::new();
+    let result = engine
+        .analyze_flow_state(
+            FlowStateData {
+                session_id,
+                keystroke_intervals: vec![120, 125, 118, 122, 130, 119, 121, 124, 117, 123],
+                context_switches: 0,
+                error_events: 0,
+                window_focus_duration: 600000,
+                file_modifications: 4,
+                typing_velocity: Some(280.0),
+                ..sample_flow_data()
+            },
+            None,
+        )
+        .await
+        .unwrap();
+
+    for &(timestamp, is_in_flow) in samples {
+        let mut result = result.clone();
+        result.is_in_flow = is_in_flow;
+        result.sample_timestamp = timestamp;
+        let write = flow::PendingFlowWrite {
+            write_id: Uuid::new_v4(),
+            user_id,
+            session_id,
+            result,
+            keystroke_hash: String::new(),
+            focus_mode: false,
+            baseline_samples: engine.take_unsaved_baseline(),
+        };
+        flow::persist_flow_write(db, &write, false).await.unwrap();
+    }
+}
+
+#[sqlx::test(migrator = "mindful_code_backend::state::MIGRATOR")]
+async fn test_ending_a_session_totals_time_spent_in_flow(db: sqlx::PgPool) {
+    use axum::extract::{Path, State};
+
+    let user_id: Uuid = sqlx::query_scalar(
+        "INSERT INTO users (email, password_hash) VALUES ('flowtime@example.com', 'x') RETURNING id",
+    )
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    let session_id: Uuid = sqlx::query_scalar(
+        "INSERT INTO coding_sessions (user_id, start_time) VALUES ($1, NOW() - INTERVAL '1 minute') RETURNING id",
+    )
+    .bind(user_id)
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    let state = AppState::from_pools(Config::from_env().unwrap(), db.clone(), None);
+    let claims = Claims::new(
+        user_id,
+        "flowtime@example.com".to_string(),
+        "premium".to_string(),
+    );
+
+    // In flow for the first two samples, out of it from the third on
+    let started = chrono::Utc::now().timestamp_millis() - 30_000;
+    store_flow_samples(
+        &db,
+        user_id,
+        session_id,
+        &[(started, true), (started + 10_000, true), (started + 20_000, false)],
+    )
+    .await;
+
+    let ended = sessions::end_session(State(state.clone()), claims, Path(session_id))
+        .await
+        .unwrap()
+        .0;
+    assert_eq!(ended.aggregates.total_flow_time_ms, 20_000);
+
+    let stored: Option<i64> =
+        sqlx::query_scalar("SELECT total_flow_time_ms FROM coding_sessions WHERE id = $1")
+            .bind(session_id)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+    assert_eq!(stored, Some(20_000));
+}
+
+#[sqlx::test(migrator = "mindful_code_backend::state::MIGRATOR")]
+async fn test_ending_a_flow_session_awards_achievements(db: sqlx::PgPool) {
+    use axum::extract::{Path, State};
+
+    let user_id: Uuid = sqlx::query_scalar(
+        "INSERT INTO users (email, password_hash) VALUES ('streak@example.com', 'x') RETURNING id",
+    )
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    // Two days of flow up to yesterday, and a 10s best session
+    let yesterday = chrono::Utc::now().date_naive() - chrono::Duration::days(1);
+    sqlx::query(
+        r#"
+        INSERT INTO user_flow_achievements
+            (user_id, current_streak_days, longest_streak_days, last_flow_date, best_session_flow_ms)
+        VALUES ($1, 2, 2, $2, 10000)
+        "#,
+    )
+    .bind(user_id)
+    .bind(yesterday)
+    .execute(&db)
+    .await
+    .unwrap();
+    let session_id: Uuid = sqlx::query_scalar(
+        "INSERT INTO coding_sessions (user_id, start_time) VALUES ($1, NOW() - INTERVAL '1 minute') RETURNING id",
+    )
+    .bind(user_id)
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    let started = chrono::Utc::now().timestamp_millis() - 40_000;
+    store_flow_samples(
+        &db,
+        user_id,
+        session_id,
+        &[
+            (started, true),
+            (started + 15_000, true),
+            (started + 30_000, false),
+        ],
+    )
+    .await;
+
+    let mut config = Config::from_env().unwrap();
+    config.achievements.min_session_flow_ms = 20_000;
+    config.achievements.streak_milestones = vec![3];
+    let state = AppState::from_pools(config, db.clone(), None);
+    let claims = Claims::new(
+        user_id,
+        "streak@example.com".to_string(),
+        "premium".to_string(),
+    );
+    let (sender, mut messages) = tokio::sync::mpsc::unbounded_channel();
+    state.add_websocket_connection(user_id, sender, false);
+
+    sessions::end_session(State(state.clone()), claims, Path(session_id))
+        .await
+        .unwrap();
+
+    let (streak, best_ms, best_session): (i32, i64, Option<Uuid>) = sqlx::query_as(
+        "SELECT current_streak_days, best_session_flow_ms, best_session_id FROM user_flow_achievements WHERE user_id = $1",
+    )
+    .bind(user_id)
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    assert_eq!(streak, 3);
+    assert_eq!(best_ms, 30_000);
+    assert_eq!(best_session, Some(session_id));
+
+    let mut notifications = Vec::new();
+    while let Ok(message) = messages.try_recv() {
+        if message.contains("\"notification\"") {
+            notifications.push(message);
+        }
+    }
+    assert!(notifications
+        .iter()
+        .any(|n| n.contains("3-day flow streak")));
+    assert!(notifications
+        .iter()
+        .any(|n| n.contains("New best flow session")));
+}
+
+#[sqlx::test(migrator = "mindful_code_backend::state::MIGRATOR")]
+async fn test_session_timeline_interleaves_interruptions_with_flow_points(db: sqlx::PgPool) {
+    use axum::extract::{Path, State};
+    use mindful_code_backend::models::flow::{InterruptionType, TimelineEvent};
+
+    let user_id: Uuid = sqlx::query_scalar(
+        "INSERT INTO users (email, password_hash) VALUES ('timeline@example.com', 'x') RETURNING id",
+    )
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    let started_at = chrono::Utc::now() - chrono::Duration::hours(1);
+    let session_id: Uuid = sqlx::query_scalar(
+        "INSERT INTO coding_sessions (user_id, start_time) VALUES ($1, $2) RETURNING id",
+    )
+    .bind(user_id)
+    .bind(started_at)
+    .fetch_one(&db)
+    .await
+    .unwrap();
+
+    let minutes = |m: i64| started_at + chrono::Duration::minutes(m);
+    for (m, intensity) in [(0, 0.4), (10, 0.8), (20, 0.6)] {
+        sqlx::query(
+            "INSERT INTO flow_states (session_id, start_time, intensity_score, confidence_score) VALUES ($1, $2, $3, 0.9)",
+        )
+        .bind(session_id)
+        .bind(minutes(m))
+        .bind(intensity)
+        .execute(&db)
+        .await
+        .unwrap();
+    }
+    // Stored out of order, to show the timeline sorts them
+    for (m, interruption_type) in [(15, "call"), (5, "meeting")] {
+        sqlx::query(
+            "INSERT INTO flow_interruptions (session_id, interruption_type, duration_ms, occurred_at) VALUES ($1, $2, 60000, $3)",
+        )
+        .bind(session_id)
+        .bind(interruption_type)
+        .bind(minutes(m))
+        .execute(&db)
+        .await
+        .unwrap();
+    }
+
+    let state = AppState::from_pools(Config::from_env().unwrap(), db.clone(), None);
+    let claims = Claims::new(
+        user_id,
+        "timeline@example.com".to_string(),
+        "premium".to_string(),
+    );
+    let timeline = sessions::get_session_timeline(State(state.clone()), claims, Path(session_id))
+        .await
+        .unwrap()
+        .0;
+
+    let kinds: Vec<(i64, Option<InterruptionType>)> = timeline
+        .events
+        .iter()
+        .map(|event| {
+            // Both truncated to microseconds by Postgres
+            let minute = (event.at() - timeline.started_at).num_minutes();
+            match event {
+                TimelineEvent::FlowPoint { .. } => (minute, None),
+                TimelineEvent::Interruption {
+                    interruption_type, ..
+                } => (minute, Some(*interruption_type)),
+            }
+        })
+        .collect();
+    assert_eq!(
+        kinds,
+        vec![
+            (0, None),
+            (5, Some(InterruptionType::Meeting)),
+            (10, None),
+            (15, Some(InterruptionType::Call)),
+            (20, None),
+        ]
+    );
+
+    let json = serde_json::to_value(&timeline).unwrap();
+    assert_eq!(json["events"][1]["kind"], "interruption");
+    assert_eq!(json["events"][1]["interruption_type"], "meeting");
+    assert_eq!(json["events"][2]["kind"], "flow_point");
+
+    let stranger = Claims::new(
+        Uuid::new_v4(),
+        "stranger@example.com".to_string(),
+        "premium".to_string(),
+    );
+    let foreign = sessions::get_session_timeline(State(state), stranger, Path(session_id)).await;
+    assert!(matches!(foreign, Err(AppError::NotFound(_))));
+}
+
+#[sqlx::test(migrator = "mindful_code_backend::state::MIGRATOR")]
+async fn test_flow_patterns_answer_304_until_new_flow_data(db: sqlx::PgPool) {
+    use axum::{
+        extract::{Query, State},
+        http::{header::ETAG, StatusCode},
+        response::IntoResponse,
+    };
+    use mindful_code_backend::utils::{date_range::DateRangeQuery, response::Cached};
+
+    let user_id: Uuid = sqlx::query_scalar(
+        "INSERT INTO users (email, password_hash) VALUES ('etag@example.com', 'x') RETURNING id",
+    )
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    let session_id: Uuid = sqlx::query_scalar(
+        "INSERT INTO coding_sessions (user_id, start_time, end_time) VALUES ($1, NOW() - INTERVAL '1 hour', NOW()) RETURNING id",
+    )
+    .bind(user_id)
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    let add_flow_state = || {
+        sqlx::query(
+            "INSERT INTO flow_states (session_id, start_time, intensity_score) VALUES ($1, NOW(), 0.7)",
+        )
+        .bind(session_id)
+        .execute(&db)
+    };
+    add_flow_state().await.unwrap();
+
+    let state = AppState::from_pools(Config::from_env().unwrap(), db.clone(), None);
+    let claims = Claims::new(
+        user_id,
+        "etag@example.com".to_string(),
+        "premium".to_string(),
+    );
+    // A fixed window, so the tag can't roll over with the minute mid-test
+    let range = DateRangeQuery {
+        from: Some(chrono::Utc::now() - chrono::Duration::days(1)),
+        to: Some(chrono::Utc::now()),
+        ..Default::default()
+    };
+    let patterns = |if_none_match: Option<String>| {
+        flow::get_flow_patterns(
+            State(state.clone()),
+            claims.clone(),
+            ResponseFormat::default().with_if_none_match(if_none_match),
+            Query(range.clone()),
+        )
+    };
+
+    let first = patterns(None).await.unwrap().into_response();
+    assert_eq!(first.status(), StatusCode::OK);
+    let etag = first.headers()[ETAG].to_str().unwrap().to_string();
+    assert!(first.headers()["cache-control"]
+        .to_str()
+        .unwrap()
+        .starts_with("private, max-age="));
+
+    let revalidated = patterns(Some(etag.clone())).await.unwrap();
+    assert!(matches!(revalidated, Cached::NotModified(_)));
+    let revalidated = revalidated.into_response();
+    assert_eq!(revalidated.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(revalidated.headers()[ETAG], etag.as_str());
+
+    // New flow data invalidates the client's copy
+    add_flow_state().await.unwrap();
+    let refreshed = patterns(Some(etag.clone())).await.unwrap().into_response();
+    assert_eq!(refreshed.status(), StatusCode::OK);
+    assert_ne!(refreshed.headers()[ETAG], etag.as_str());
+}
+
+#[sqlx::test(migrator = "mindful_code_backend::state::MIGRATOR")]
+async fn test_startup_self_test_reports_broken_subsystems(db: sqlx::PgPool) {
+    use mindful_code_backend::services::self_test::run_self_test;
+
+    let mut config = Config::from_env().unwrap();
+    config.encryption_key =
+        EncryptionService::key_to_hex(&EncryptionService::generate_master_key());
+    let healthy = AppState::from_pools(config.clone(), db.clone(), None);
+    let report = run_self_test(&healthy).await;
+    assert!(report.passed(), "{:?}", report);
+    for name in ["database", "encryption", "ml", "wasm"] {
+        assert!(report.check(name).unwrap().passed(), "{} failed", name);
+    }
+
+    config.encryption_key = "not-a-hex-key".to_string();
+    let broken = AppState::from_pools(config, db, None);
+    let report = run_self_test(&broken).await;
+    assert!(!report.passed());
+    let failures: Vec<_> = report.required_failures().map(|check| check.name).collect();
+    assert_eq!(failures, vec!["encryption"]);
+    assert!(report
+        .check("encryption")
+        .unwrap()
+        .error
+        .as_ref()
+        .unwrap()
+        .contains("ENCRYPTION_KEY"));
+}
+
+#[sqlx::test(migrator = "mindful_code_backend::state::MIGRATOR")]
+async fn test_flow_analytics_buckets_by_requested_granularity(db: sqlx::PgPool) {
+    use axum::{extract::State, Json};
+    use chrono::TimeZone;
+    use mindful_code_backend::utils::date_range::{DateRangeQuery, Granularity};
+
+    let user_id: Uuid = sqlx::query_scalar(
+        "INSERT INTO users (email, password_hash, timezone) VALUES ('buckets@example.com', 'x', 'America/New_York') RETURNING id",
+    )
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    let session_id: Uuid = sqlx::query_scalar(
+        "INSERT INTO coding_sessions (user_id, start_time) VALUES ($1, NOW()) RETURNING id",
+    )
+    .bind(user_id)
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    let utc = |day: u32, hour: u32, minute: u32| {
+        chrono::Utc
+            .with_ymd_and_hms(2024, 3, day, hour, minute, 0)
+            .unwrap()
+    };
+    // 03:00Z on Monday the 4th is still Sunday evening in New York, so it
+    // falls in the previous local week
+    for start_time in [
+        utc(4, 3, 0),
+        utc(4, 15, 5),
+        utc(4, 15, 40),
+        utc(4, 18, 0),
+        utc(13, 14, 0),
+        utc(20, 14, 0),
+    ] {
+        sqlx::query(
+            "INSERT INTO flow_states (session_id, start_time, intensity_score) VALUES ($1, $2, 0.7)",
+        )
+        .bind(session_id)
+        .bind(start_time)
+        .execute(&db)
+        .await
+        .unwrap();
+    }
+
+    let state = AppState::from_pools(Config::from_env().unwrap(), db.clone(), None);
+    let claims = Claims::new(
+        user_id,
+        "buckets@example.com".to_string(),
+        "premium".to_string(),
+    );
+    let analytics = |range: DateRangeQuery, granularity: Granularity| {
+        flow::get_flow_analytics(
+            State(state.clone()),
+            claims.clone(),
+            ResponseFormat::default(),
+            Json(flow::FlowAnalyticsQuery {
+                range,
+                min_data_quality: None,
+                environment: Default::default(),
+                granularity,
+            }),
+        )
+    };
+
+    // 15:05 and 15:40 share an hour
+    let hourly = analytics(Default::default(), Granularity::Hour)
+        .await
+        .unwrap()
+        .into_data()
+        .unwrap();
+    assert_eq!(hourly.granularity, Granularity::Hour);
+    assert_eq!(hourly.distribution.len(), 5);
+    assert_eq!(hourly.distribution[1].start, utc(4, 15, 0));
+
+    let weekly = analytics(Default::default(), Granularity::Week)
+        .await
+        .unwrap()
+        .into_data()
+        .unwrap();
+    let starts: Vec<_> = weekly
+        .distribution
+        .iter()
+        .map(|bucket| bucket.start)
+        .collect();
+    // Local midnights on Mondays: EST until the 10th, EDT after
+    assert_eq!(
+        starts,
+        vec![
+            chrono::Utc.with_ymd_and_hms(2024, 2, 26, 5, 0, 0).unwrap(),
+            utc(4, 5, 0),
+            utc(11, 4, 0),
+            utc(18, 4, 0),
+        ]
+    );
+
+    // A year of hours is too many buckets
+    let too_many = analytics(
+        DateRangeQuery {
+            days: Some(365),
+            ..Default::default()
+        },
+        Granularity::Hour,
+    )
+    .await;
+    assert!(matches!(too_many, Err(AppError::Validation(_))));
+}
+
+#[sqlx::test(migrator = "mindful_code_backend::state::MIGRATOR")]
+async fn test_flow_analytics_are_read_from_the_replica(db: sqlx::PgPool) {
+    use axum::{extract::State, Json};
+    use sqlx::postgres::PgPoolOptions;
+
+    let user_id: Uuid = sqlx::query_scalar(
+        "INSERT INTO users (email, password_hash) VALUES ('replica@example.com', 'x') RETURNING id",
+    )
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    let session_id: Uuid = sqlx::query_scalar(
+        "INSERT INTO coding_sessions (user_id, start_time) VALUES ($1, NOW()) RETURNING id",
+    )
+    .bind(user_id)
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    // The "replica" sees its own flow_states table ahead of the primary's,
+    // so only reads that go to it find these rows
+    sqlx::query("CREATE SCHEMA replica")
+        .execute(&db)
+        .await
+        .unwrap();
+    sqlx::query("CREATE TABLE replica.flow_states (LIKE public.flow_states INCLUDING ALL)")
+        .execute(&db)
+        .await
+        .unwrap();
+    for _ in 0..3 {
+        sqlx::query(
+            "INSERT INTO replica.flow_states (session_id, start_time, intensity_score) VALUES ($1, NOW(), 0.8)",
+        )
+        .bind(session_id)
+        .execute(&db)
+        .await
+        .unwrap();
+    }
+    let replica = PgPoolOptions::new()
+        .connect_with(
+            (*db.connect_options())
+                .clone()
+                .options([("search_path", "replica,public")]),
+        )
+        .await
+        .unwrap();
+
+    let claims = Claims::new(
+        user_id,
+        "replica@example.com".to_string(),
+        "premium".to_string(),
+    );
+    let analytics = |state: AppState| {
+        flow::get_flow_analytics(
+            State(state),
+            claims.clone(),
+            ResponseFormat::default(),
+            Json(flow::FlowAnalyticsQuery {
+                range: Default::default(),
+                min_data_quality: None,
+                environment: Default::default(),
+                granularity: Default::default(),
+            }),
+        )
+    };
+
+    let config = Config::from_env().unwrap();
+    let with_replica = analytics(AppState::from_pools(
+        config.clone(),
+        db.clone(),
+        Some(replica),
+    ))
+    .await
+    .unwrap()
+    .into_data()
+    .unwrap();
+    assert_eq!(with_replica.flow_sessions_count, 1);
+    assert!((with_replica.average_flow_intensity - 0.8).abs() < 1e-6);
+
+    let primary_only = analytics(AppState::from_pools(config, db.clone(), None))
+        .await
+        .unwrap()
+        .into_data()
+        .unwrap();
+    assert_eq!(primary_only.flow_sessions_count, 0);
+}
+
+#[sqlx::test(migrator = "mindful_code_backend::state::MIGRATOR")]
+async fn test_only_consenting_users_contribute_telemetry(db: sqlx::PgPool) {
+    use mindful_code_backend::services::telemetry::released_telemetry_cells;
+
+    let mut engine = FlowDetectionEngine::new();
+    let flow_data = sample_flow_data();
+    let keystroke_hash = engine.keystroke_hash(&flow_data);
+    let result = engine.analyze_flow_state(flow_data, None).await.unwrap();
+
+    let mut writes = Vec::new();
+    for (email, analytics_enabled, telemetry_consent) in [
+        ("consenting@example.com", true, true),
+        ("silent@example.com", true, false),
+        ("no-analytics@example.com", false, true),
+    ] {
+        let user_id: Uuid = sqlx::query_scalar(
+            "INSERT INTO users (email, password_hash, privacy_settings) VALUES ($1, 'x', $2) RETURNING id",
+        )
+        .bind(email)
+        .bind(serde_json::json!({
+            "analytics_enabled": analytics_enabled,
+            "sharing_enabled": false,
+            "encryption_level": "Standard",
+            "gdpr_compliant": true,
+            "telemetry_consent": telemetry_consent
+        }))
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        let session_id: Uuid = sqlx::query_scalar(
+            "INSERT INTO coding_sessions (user_id, start_time) VALUES ($1, NOW()) RETURNING id",
+        )
+        .bind(user_id)
+        .fetch_one(&db)
+        .await
+        .unwrap();
+
+        let write = flow::PendingFlowWrite {
+            write_id: Uuid::new_v4(),
+            user_id,
+            session_id,
+            result: result.clone(),
+            keystroke_hash: keystroke_hash.clone(),
+            focus_mode: false,
+            baseline_samples: engine.take_unsaved_baseline(),
+        };
+        flow::persist_flow_write(&db, &write, false).await.unwrap();
+        writes.push(write);
+    }
+
+    // Every user's own flow state is stored either way
+    let stored: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM flow_states")
+        .fetch_one(&db)
+        .await
+        .unwrap();
+    assert_eq!(stored, 3);
+
+    // Only the consenting user's result reached the corpus, as binned
+    // scores with nothing identifying them
+    let cells: Vec<(serde_json::Value, f64, i32, i64)> = sqlx::query_as(
+        "SELECT features, flow_intensity, contributor_count, sample_count FROM telemetry_feature_cells",
+    )
+    .fetch_all(&db)
+    .await
+    .unwrap();
+    assert_eq!(cells.len(), 1);
+    let (features, flow_intensity, contributors, samples) = &cells[0];
+    assert_eq!((*contributors, *samples), (1, 1));
+    assert_eq!(
+        *flow_intensity,
+        (result.flow_intensity as f64 * 10.0).round() / 10.0
+    );
+    let mut keys: Vec<&String> = features.as_object().unwrap().keys().collect();
+    keys.sort();
+    assert_eq!(
+        keys,
+        [
+            "consistency_score",
+            "error_penalty",
+            "focus_score",
+            "rhythm_score",
+            "velocity_score"
+        ]
+    );
+    let contributors: Vec<Uuid> = sqlx::query_scalar("SELECT user_id FROM telemetry_contributions")
+        .fetch_all(&db)
+        .await
+        .unwrap();
+    assert_eq!(contributors, vec![writes[0].user_id]);
+
+    // Replaying a write that already landed doesn't count it twice
+    flow::persist_flow_write(&db, &writes[0], false)
+        .await
+        .unwrap();
+    let samples: i64 = sqlx::query_scalar("SELECT sample_count FROM telemetry_feature_cells")
+        .fetch_one(&db)
+        .await
+        .unwrap();
+    assert_eq!(samples, 1);
+
+    // A single contributor is never enough for the cell to be released
+    let config = Config::from_env().unwrap();
+    let mut conn = db.acquire().await.unwrap();
+    assert!(
+        released_telemetry_cells(&mut conn, config.telemetry_min_contributors)
+            .await
+            .unwrap()
+            .is_empty()
+    );
+    assert!(released_telemetry_cells(&mut conn, 1)
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+#[sqlx::test(migrator = "mindful_code_backend::state::MIGRATOR")]
+async fn test_anonymous_sessions_are_claimed_exactly_once(db: sqlx::PgPool) {
+    use mindful_code_backend::models::auth::ClaimAnonymousSessionRequest;
+
+    let state = AppState::from_pools(Config::from_env().unwrap(), db.clone(), None);
+    let trial = Claims::anonymous(Uuid::new_v4(), chrono::Duration::minutes(60));
+    let trial_sessions = [Uuid::new_v4(), Uuid::new_v4()];
+    for session_id in trial_sessions {
+        flow::detect_flow_state(
+            axum::extract::State(state.clone()),
+            trial.clone(),
+            ResponseFormat::default(),
+            axum::extract::Query(Default::default()),
+            axum::Json(flow::FlowDetectionPayload {
+                request: FlowDetectionRequest {
+                    flow_data: FlowStateData {
+                        session_id,
+                        ..sample_flow_data()
+                    },
+                    user_preferences: None,
+                },
+            }),
+        )
+        .await
+        .unwrap();
+    }
+    let anonymous_token = generate_jwt_token(&trial, &state.config.jwt_secret).unwrap();
+
+    let register = |email: &'static str, registered_ago_minutes: i64| {
+        let db = db.clone();
+        async move {
+            let user_id: Uuid = sqlx::query_scalar(
+                "INSERT INTO users (email, password_hash, created_at) VALUES ($1, 'x', $2) RETURNING id",
+            )
+            .bind(email)
+            .bind(chrono::Utc::now() - chrono::Duration::minutes(registered_ago_minutes))
+            .fetch_one(&db)
+            .await
+            .unwrap();
+            Claims::new(user_id, email.to_string(), "free".to_string())
+        }
+    };
+    let claim = |claims: Claims, anonymous_token: String| {
+        auth::claim_anonymous_session(
+            axum::extract::State(state.clone()),
+            claims,
+            axum::Json(ClaimAnonymousSessionRequest { anonymous_token }),
+        )
+    };
+    let sessions_of = |user_id: Uuid| {
+        let db = db.clone();
+        async move {
+            sqlx::query_scalar::<_, Uuid>("SELECT id FROM coding_sessions WHERE user_id = $1")
+                .bind(user_id)
+                .fetch_all(&db)
+                .await
+                .unwrap()
+        }
+    };
+
+    let newcomer = register("new@example.com", 5).await;
+    let first = claim(newcomer.clone(), anonymous_token.clone())
+        .await
+        .unwrap()
+        .0;
+    assert!(first.migrated && !first.already_claimed);
+    assert_eq!(first.sessions_migrated, 2);
+    let mut migrated = sessions_of(newcomer.user_id).await;
+    migrated.sort();
+    let mut expected = trial_sessions.to_vec();
+    expected.sort();
+    assert_eq!(migrated, expected);
+    assert!(state.user_flow_engines(trial.user_id).is_empty());
+    assert_eq!(state.user_flow_engines(newcomer.user_id).len(), 2);
+
+    // Replaying the claim reports the original outcome and copies nothing
+    let replay = claim(newcomer.clone(), anonymous_token.clone())
+        .await
+        .unwrap()
+        .0;
+    assert!(replay.already_claimed && !replay.migrated);
+    assert_eq!(replay.sessions_migrated, 2);
+    assert_eq!(sessions_of(newcomer.user_id).await.len(), 2);
+
+    // Nobody else can take the same trial
+    let other = register("other@example.com", 5).await;
+    let stolen = claim(other.clone(), anonymous_token).await.unwrap_err();
+    assert!(matches!(stolen, AppError::Conflict(_)));
+    assert!(sessions_of(other.user_id).await.is_empty());
+
+    // Long-standing accounts are outside the claim window
+    let veteran = register("veteran@example.com", 24 * 60).await;
+    let late_trial = Claims::anonymous(Uuid::new_v4(), chrono::Duration::minutes(60));
+    let late_token = generate_jwt_token(&late_trial, &state.config.jwt_secret).unwrap();
+    let late = claim(veteran, late_token).await.unwrap_err();
+    assert!(matches!(late, AppError::Authorization(_)));
+    let recorded: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM anonymous_claims")
+        .fetch_one(&db)
+        .await
+        .unwrap();
+    assert_eq!(recorded, 1);
+
+    // A registered token can't be passed off as a trial
+    let not_a_trial = generate_jwt_token(&other, &state.config.jwt_secret).unwrap();
+    let rejected = claim(other, not_a_trial).await.unwrap_err();
+    assert!(matches!(rejected, AppError::BadRequest(_)));
+}
+
+#[sqlx::test(migrator = "mindful_code_backend::state::MIGRATOR")]
+async fn test_postgres_feedback_store_batches_and_evicts(db: sqlx::PgPool) {
+    let user_id: Uuid = sqlx::query_scalar(
+        "INSERT INTO users (email, password_hash) VALUES ('feedback@example.com', 'x') RETURNING id",
+    )
+    .fetch_one(&db)
+    .await
+    .unwrap();
+
+    let config = FeedbackStoreConfig {
+        backend: FeedbackStoreBackend::Postgres,
+        capacity: 2,
+        retention_days: 30,
+    };
+    let store = feedback_store(&config, &db);
+    let now = chrono::Utc::now();
+
+    // Stored in the opposite order to when they were recorded
+    for (days_ago, actual_flow_state) in [(1, 0.1), (2, 0.2), (3, 0.3)] {
+        store
+            .record(FeedbackExample {
+                user_id,
+                features: [0.8, 0.7, 0.6, 0.1, 0.5],
+                actual_flow_state,
+                user_feedback: Some(0.9),
+                recorded_at: now - chrono::Duration::days(days_ago),
+            })
+            .await
+            .unwrap();
+    }
+    // Capacity waits for the next prune
+    assert_eq!(store.count().await.unwrap(), 3);
+
+    // Like the in-memory store, the example stored first goes first
+    assert_eq!(store.prune(now).await.unwrap(), 1);
+    assert_eq!(store.count().await.unwrap(), 2);
+    let batch = store.training_batch(10).await.unwrap();
+    let scores: Vec<f32> = batch.iter().map(|e| e.actual_flow_state).collect();
+    assert_eq!(scores, vec![0.3, 0.2]);
+    assert_eq!(batch[0].features, [0.8, 0.7, 0.6, 0.1, 0.5]);
+    assert_eq!(batch[0].user_feedback, Some(0.9));
+    assert_eq!(store.training_batch(1).await.unwrap().len(), 1);
+
+    assert_eq!(store.prune(now + chrono::Duration::days(28)).await.unwrap(), 1);
+    assert_eq!(store.count().await.unwrap(), 1);
+}
+
+#[tokio::test]
+async fn test_focus_mode_holds_break_reminders_until_flow_ends() {
+    for policy in [DeferredReminderPolicy::Deliver, DeferredReminderPolicy::Drop] {
+        let db = sqlx::postgres::PgPoolOptions::new()
+            .connect_lazy("postgresql://localhost/mindful_code")
+            .unwrap();
+        let mut config = Config::from_env().unwrap();
+        config.focus_mode.deferred_reminders = policy;
+        let state = AppState::from_pools(config, db, None);
+        let user_id = Uuid::new_v4();
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<String>();
+        state.add_websocket_connection(user_id, tx, false);
+        let mut received = || {
+            std::iter::from_fn(|| rx.try_recv().ok())
+                .map(|json| serde_json::from_str::<serde_json::Value>(&json).unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        let status = websocket::set_focus_mode(&state, user_id, true).await;
+        assert!(status.enabled && status.started_at.is_some());
+        assert_eq!(received()[0]["type"], "focus_mode_update");
+
+        websocket::record_focus_flow(&state, user_id, true).await;
+        websocket::send_break_reminder(&state, user_id, 50).await;
+        websocket::send_notification(
+            &state,
+            user_id,
+            "Team update".to_string(),
+            "Not urgent".to_string(),
+            websocket::NotificationLevel::Info,
+        )
+        .await;
+        assert!(received().is_empty());
+
+        // Critical notifications still get through
+        websocket::send_notification(
+            &state,
+            user_id,
+            "Sync failed".to_string(),
+            "Session data could not be saved".to_string(),
+            websocket::NotificationLevel::Error,
+        )
+        .await;
+        assert_eq!(received()[0]["title"], "Sync failed");
+
+        websocket::record_focus_flow(&state, user_id, false).await;
+        let released = received();
+        match policy {
+            DeferredReminderPolicy::Deliver => {
+                assert_eq!(released.len(), 1);
+                assert_eq!(released[0]["title"], "Time for a break");
+            }
+            DeferredReminderPolicy::Drop => assert!(released.is_empty()),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_parallel_sessions_keep_independent_flow_engines() {
+    let detect = |state: AppState,
+                  claims: Claims,
+                  session_id: Uuid,
+                  keystroke_intervals: Vec<u64>,
+                  timestamp: i64| async move {
+        let response = flow::detect_flow_state(
+            axum::extract::State(state),
+            claims,
+            ResponseFormat::default(),
+            axum::extract::Query(Default::default()),
+            axum::Json(flow::FlowDetectionPayload {
+                request: FlowDetectionRequest {
+                    flow_data: FlowStateData {
+                        session_id,
+                        keystroke_intervals,
+                        context_switches: 0,
+                        error_events: 0,
+                        window_focus_duration: 600000,
+                        file_modifications: 4,
+                        timestamp,
+                        typing_velocity: Some(280.0),
+                        ..sample_flow_data()
+                    },
+                    user_preferences: None,
+                },
+            }),
+        )
+        .await
+        .unwrap()
+        .into_response();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+    };
+    let new_state = || {
+        let db = sqlx::postgres::PgPoolOptions::new()
+            .connect_lazy("postgresql://localhost/mindful_code")
+            .unwrap();
+        AppState::from_pools(Config::from_env().unwrap(), db, None)
+    };
+    let steady = || vec![120, 125, 118, 122, 130, 119, 121, 124, 117, 123];
+    let erratic = || vec![40, 900, 65, 700, 30, 850, 120, 600, 45, 950];
+    let now = chrono::Utc::now().timestamp_millis();
+
+    // One user typing steadily in one window and erratically in another
+    let state = new_state();
+    let claims = Claims::anonymous(Uuid::new_v4(), chrono::Duration::minutes(60));
+    let (editor, terminal) = (Uuid::new_v4(), Uuid::new_v4());
+    let mut interleaved = Vec::new();
+    for i in 0..3 {
+        interleaved.push(detect(state.clone(), claims.clone(), editor, steady(), now + i).await);
+        detect(state.clone(), claims.clone(), terminal, erratic(), now + i).await;
+    }
+    assert_eq!(state.user_flow_engines(claims.user_id).len(), 2);
+
+    // The same steady session on its own
+    let solo_state = new_state();
+    let solo_claims = Claims::anonymous(Uuid::new_v4(), chrono::Duration::minutes(60));
+    let mut solo = Vec::new();
+    for i in 0..3 {
+        solo.push(
+            detect(
+                solo_state.clone(),
+                solo_claims.clone(),
+                editor,
+                steady(),
+                now + i,
+            )
+            .await,
+        );
+    }
+
+    for (mixed, alone) in interleaved.iter().zip(&solo) {
+        assert_eq!(mixed["metrics"], alone["metrics"]);
+        assert_eq!(mixed["flow_intensity"], alone["flow_intensity"]);
+    }
+
+    // Ending one session drops only its engine
+    state.remove_active_session(claims.user_id, terminal);
+    assert_eq!(state.user_flow_engines(claims.user_id).len(), 1);
+    assert!(state.flow_engines.contains_key(&(claims.user_id, editor)));
+}
+
+#[sqlx::test(migrator = "mindful_code_backend::state::MIGRATOR")]
+async fn test_flow_engines_are_bounded_per_user(db: sqlx::PgPool) {
+    let user_id: Uuid = sqlx::query_scalar(
+        "INSERT INTO users (email, password_hash) VALUES ('engines@example.com', 'x') RETURNING id",
+    )
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    let ended: Uuid = sqlx::query_scalar(
+        "INSERT INTO coding_sessions (user_id, start_time, end_time) VALUES ($1, NOW(), NOW()) RETURNING id",
+    )
+    .bind(user_id)
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    let state = AppState::from_pools(Config::from_env().unwrap(), db.clone(), None);
+    let detect = |claims: Claims, session_id: Uuid| {
+        flow::detect_flow_state(
+            axum::extract::State(state.clone()),
+            claims,
+            ResponseFormat::default(),
+            axum::extract::Query(Default::default()),
+            axum::Json(flow::FlowDetectionPayload {
+                request: FlowDetectionRequest {
+                    flow_data: FlowStateData {
+                        session_id,
+                        keystroke_intervals: vec![120, 125, 118, 122, 130, 119, 121, 124, 117, 123],
+                        context_switches: 0,
+                        error_events: 0,
+                        window_focus_duration: 600000,
+                        file_modifications: 4,
+                        typing_velocity: Some(280.0),
+                        ..sample_flow_data()
+                    },
+                    user_preferences: None,
+                },
+            }),
+        )
+    };
+
+    // Registered users only get engines for their own open sessions
+    let claims = Claims::new(
+        user_id,
+        "engines@example.com".to_string(),
+        "free".to_string(),
+    );
+    for session_id in [Uuid::new_v4(), ended] {
+        let rejected = detect(claims.clone(), session_id).await.unwrap_err();
+        assert!(matches!(rejected, AppError::NotFound(_)));
+    }
+    assert!(state.user_flow_engines(user_id).is_empty());
+
+    // A trial inventing session ids keeps only its most recently used engines
+    let trial = Claims::anonymous(Uuid::new_v4(), chrono::Duration::minutes(60));
+    let first = Uuid::new_v4();
+    detect(trial.clone(), first).await.unwrap();
+    for _ in 0..20 {
+        detect(trial.clone(), Uuid::new_v4()).await.unwrap();
+    }
+    assert_eq!(state.user_flow_engines(trial.user_id).len(), 16);
+    assert!(!state.flow_engines.contains_key(&(trial.user_id, first)));
+
+    // Idle engines outside tracked sessions are dropped
+    state.cleanup_idle_flow_engines(Duration::from_secs(3600));
+    assert_eq!(state.user_flow_engines(trial.user_id).len(), 16);
+    state.cleanup_idle_flow_engines(Duration::ZERO);
+    assert!(state.flow_engines.is_empty());
+}
+
+#[tokio::test]
+async fn test_admin_engine_snapshot_reflects_recent_analyses() {
+    let db = sqlx::postgres::PgPoolOptions::new()
+        .connect_lazy("postgresql://localhost/mindful_code")
+        .unwrap();
+    let state = AppState::from_pools(Config::from_env().unwrap(), db, None);
+    let user = Claims::anonymous(Uuid::new_v4(), chrono::Duration::minutes(60));
+    let session_id = Uuid::new_v4();
+    let now = chrono::Utc::now().timestamp_millis();
+
+    for i in 0..3 {
+        flow::detect_flow_state(
+            axum::extract::State(state.clone()),
+            user.clone(),
+            ResponseFormat::default(),
+            axum::extract::Query(Default::default()),
+            axum::Json(flow::FlowDetectionPayload {
+                request: FlowDetectionRequest {
+                    flow_data: FlowStateData {
+                        session_id,
+                        timestamp: now + i,
+                        ..sample_flow_data()
+                    },
+                    user_preferences: None,
+                },
+            }),
+        )
+        .await
+        .unwrap();
+    }
+
+    let snapshot = |admin: AdminAuth| {
+        admin::get_user_engine_state(
+            axum::extract::State(state.clone()),
+            admin,
+            axum::extract::Path(user.user_id),
+        )
+    };
+
+    let user_token = generate_jwt_token(&user, &state.config.jwt_secret).unwrap();
+    let denied = extract_admin_auth(&state, "authorization", &format!("Bearer {}", user_token))
+        .await
+        .unwrap_err();
+    assert!(matches!(denied, AppError::Authorization(_)));
+
+    let mut support = Claims::new(
+        Uuid::new_v4(),
+        "support@example.com".to_string(),
+        "enterprise".to_string(),
+    );
+    support.role = UserRole::Admin;
+    let axum::Json(response) = snapshot(AdminAuth::User(support)).await.unwrap();
+    assert_eq!(response.engines.len(), 1);
+    let engine = &response.engines[0];
+    assert_eq!(engine.session_id, Some(session_id));
+    assert_eq!(engine.session_analyses, 3);
+    assert_eq!(engine.keystroke_buffer_len, 30);
+    assert_eq!(engine.confidence_history.len(), 3);
+    assert_eq!(engine.baseline_sample_count, 3);
+    assert!(engine.current_intensity > 0.0);
+
+    // Only counts of the keystroke buffer, never the timings
+    let body = serde_json::to_string(&response).unwrap();
+    assert!(!body.contains("keystroke_intervals"));
+    assert!(!body.contains("[120,135"));
+}
+
+#[sqlx::test(migrator = "mindful_code_backend::state::MIGRATOR")]
+async fn test_replay_rescores_flow_states_and_keeps_originals(db: sqlx::PgPool) {
+    let user_id: Uuid = sqlx::query_scalar(
+        "INSERT INTO users (email, password_hash) VALUES ('replay@example.com', 'x') RETURNING id",
+    )
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    let started_at = chrono::Utc::now() - chrono::Duration::days(2);
+    let session_id: Uuid = sqlx::query_scalar(
+        "INSERT INTO coding_sessions (user_id, start_time) VALUES ($1, $2) RETURNING id",
+    )
+    .bind(user_id)
+    .bind(started_at)
+    .fetch_one(&db)
+    .await
+    .unwrap();
+
+    let mut flow_state_ids = Vec::new();
+    for (minutes, velocity_score) in [(0, 0.4), (5, 0.9)] {
+        let id: Uuid = sqlx::query_scalar(
+            r#"
+            INSERT INTO flow_states
+                (session_id, start_time, intensity_score, confidence_score, data_quality,
+                 ml_features, model_version)
+            VALUES ($1, $2, 0.7, 0.6, 0.5, $3, 'rule-based')
+            RETURNING id
+            "#,
+        )
+        .bind(session_id)
+        .bind(started_at + chrono::Duration::minutes(minutes))
+        .bind(serde_json::json!({
+            "rhythm_score": 0.8,
+            "focus_score": 0.7,
+            "consistency_score": 0.6,
+            "velocity_score": velocity_score,
+            "error_penalty": 0.1
+        }))
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        flow_state_ids.push(id);
+    }
+
+    let registry_dir = std::env::temp_dir().join(format!("model_registry_{}", Uuid::new_v4()));
+    std::fs::create_dir_all(&registry_dir).unwrap();
+    std::fs::write(
+        registry_dir.join("velocity-only.json"),
+        r#"{"weights": [0.0, 0.0, 0.0, 0.0, 1.0]}"#,
+    )
+    .unwrap();
+
+    let mut config = Config::from_env().unwrap();
+    config.model_registry_dir = Some(registry_dir.to_string_lossy().into_owned());
+    config.flow_replay.batch_size = 1;
+    config.flow_replay.rows_per_second = 0;
+    let state = AppState::from_pools(config, db.clone(), None);
+
+    let mut admin = Claims::new(Uuid::new_v4(), "admin@example.com".to_string(), "enterprise".to_string());
+    admin.role = UserRole::Admin;
+    let replay = |cursor: Option<FlowReplayCursor>| {
+        admin::replay_flow_states(
+            axum::extract::State(state.clone()),
+            AdminAuth::User(admin.clone()),
+            axum::extract::Path(user_id),
+            axum::Json(FlowReplayRequest {
+                from: started_at - chrono::Duration::hours(1),
+                to: chrono::Utc::now(),
+                model_version: Some("velocity-only".to_string()),
+                cursor,
+            }),
+        )
+    };
+
+    // One row per batch; each batch resumes after the previous one
+    let first = replay(None).await.unwrap().0;
+    assert_eq!((first.updated, first.skipped), (1, 0));
+    assert_eq!(first.next_cursor.map(|cursor| cursor.id), Some(flow_state_ids[0]));
+    let second = replay(first.next_cursor).await.unwrap().0;
+    assert_eq!(second.updated, 1);
+    let last = replay(second.next_cursor).await.unwrap().0;
+    assert_eq!(last.updated, 0);
+    assert!(last.next_cursor.is_none());
+
+    for (id, expected) in flow_state_ids.iter().zip([0.4, 0.9]) {
+        let (intensity, confidence, model_version, original): (f64, f64, String, serde_json::Value) =
+            sqlx::query_as(
+                r#"
+                SELECT intensity_score::FLOAT8, confidence_score::FLOAT8, model_version,
+                       original_scores
+                FROM flow_states WHERE id = $1
+                "#,
+            )
+            .bind(id)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert!((intensity - expected).abs() < 0.01);
+        assert!((confidence - expected * 0.5).abs() < 0.01);
+        assert_eq!(model_version, "velocity-only");
+        assert_eq!(original["intensity_score"].as_f64(), Some(0.7));
+        assert_eq!(original["model_version"], "rule-based");
+    }
+
+    // Rows already on the model are left alone
+    let again = replay(None).await.unwrap().0;
+    assert_eq!((again.updated, again.skipped), (0, 1));
+
+    std::fs::remove_dir_all(registry_dir).unwrap();
+}
+
+/// Resolves a fixed set of addresses, standing in for a GeoIP database.
+struct FixedGeoLocator(Vec<(std::net::IpAddr, GeoLocation)>);
+
+impl GeoLocator for FixedGeoLocator {
+    fn locate(&self, ip: std::net::IpAddr) -> Option<GeoLocation> {
+        self.0.iter().find(|(known, _)| *known == ip).map(|(_, location)| location.clone())
+    }
+}
+
+#[sqlx::test(migrator = "mindful_code_backend::state::MIGRATOR")]
+async fn test_logins_from_far_apart_ips_flag_impossible_travel(db: sqlx::PgPool) {
+    let user_id: Uuid = sqlx::query_scalar(
+        "INSERT INTO users (email, password_hash) VALUES ('traveller@example.com', 'x') RETURNING id",
+    )
+    .fetch_one(&db)
+    .await
+    .unwrap();
+
+    let new_york: std::net::IpAddr = "198.51.100.7".parse().unwrap();
+    let sydney: std::net::IpAddr = "203.0.113.9".parse().unwrap();
+    let place = |country: &str, city: &str, latitude: f64, longitude: f64| GeoLocation {
+        country: Some(country.to_string()),
+        city: Some(city.to_string()),
+        latitude,
+        longitude,
+    };
+
+    let mut state = AppState::from_pools(Config::from_env().unwrap(), db.clone(), None);
+    state.geo_locator = std::sync::Arc::new(FixedGeoLocator(vec![
+        (new_york, place("US", "New York", 40.7128, -74.006)),
+        (sydney, place("AU", "Sydney", -33.8688, 151.2093)),
+    ]));
+    let (sender, mut client) = tokio::sync::mpsc::unbounded_channel();
+    state.add_websocket_connection(user_id, sender, false);
+
+    let first = auth::record_login(&state, user_id, Some(new_york)).await.unwrap();
+    assert!(!first.new_location && !first.impossible_travel);
+    assert_eq!(first.ip_address.as_deref(), Some("198.51.100.7"));
+    // Stored coarsely
+    assert_eq!(first.location.as_ref().map(|location| location.latitude), Some(40.7));
+    assert!(client.try_recv().is_err());
+
+    // Moments later, from the other side of the world
+    let second = auth::record_login(&state, user_id, Some(sydney)).await.unwrap();
+    assert!(second.new_location && second.impossible_travel);
+    let notification: serde_json::Value =
+        serde_json::from_str(&client.recv().await.unwrap()).unwrap();
+    assert_eq!(notification["title"], "Suspicious sign-in");
+
+    let flagged: Vec<bool> = sqlx::query_scalar(
+        "SELECT impossible_travel FROM login_events WHERE user_id = $1 ORDER BY created_at",
+    )
+    .bind(user_id)
+    .fetch_all(&db)
+    .await
+    .unwrap();
+    assert_eq!(flagged, vec![false, true]);
+
+    // Unlocatable addresses are recorded but never flagged
+    let private = auth::record_login(&state, user_id, "10.0.0.1".parse().ok()).await.unwrap();
+    assert!(private.location.is_none() && !private.impossible_travel);
+
+    // High-security users keep their IPs out of the table
+    sqlx::query(
+        r#"
+        UPDATE users SET privacy_settings = '{"analytics_enabled": true, "sharing_enabled": false,
+            "encryption_level": "High", "gdpr_compliant": true}'
+        WHERE id = $1
+        "#,
+    )
+    .bind(user_id)
+    .execute(&db)
+    .await
+    .unwrap();
+    let private = auth::record_login(&state, user_id, Some(sydney)).await.unwrap();
+    assert_eq!(private.ip_address, None);
+    assert!(private.location.is_some());
+}
+
+#[sqlx::test(migrator = "mindful_code_backend::state::MIGRATOR")]
+async fn test_login_issues_tokens_and_records_the_login(db: sqlx::PgPool) {
+    use axum::{extract::State, Json};
+    use mindful_code_backend::{
+        models::auth::LoginRequest,
+        utils::{auth::validate_jwt_token_with, ClientIp},
+    };
+
+    let user_id: Uuid = sqlx::query_scalar(
+        "INSERT INTO users (email, password_hash, subscription_tier) \
+         VALUES ('login@example.com', $1, 'premium') RETURNING id",
+    )
+    .bind(hash_password("correct horse battery").unwrap())
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    let state = AppState::from_pools(Config::from_env().unwrap(), db.clone(), None);
+    let client_ip: std::net::IpAddr = "198.51.100.7".parse().unwrap();
+    let login = |email: &str, password: &str| {
+        auth::login(
+            State(state.clone()),
+            ClientIp(Some(client_ip)),
+            Json(LoginRequest {
+                email: email.to_string(),
+                password: password.to_string(),
+            }),
+        )
+    };
+
+    let Json(tokens) = login("login@example.com", "correct horse battery")
+        .await
+        .unwrap();
+    let claims = validate_jwt_token_with(
+        &tokens.access_token,
+        &state.config.jwt_secret,
+        state.config.jwt_algorithm,
+    )
+    .unwrap();
+    assert_eq!(claims.user_id, user_id);
+    assert_eq!(claims.subscription_tier, SubscriptionTier::Premium);
+    assert_eq!(tokens.expires_in, 24 * 60 * 60);
+
+    let recorded: Vec<Option<String>> =
+        sqlx::query_scalar("SELECT ip_address FROM login_events WHERE user_id = $1")
+            .bind(user_id)
+            .fetch_all(&db)
+            .await
+            .unwrap();
+    assert_eq!(recorded, vec![Some("198.51.100.7".to_string())]);
+
+    // Wrong passwords and unknown accounts look the same, and aren't recorded
+    for (email, password) in [
+        ("login@example.com", "wrong"),
+        ("nobody@example.com", "correct horse battery"),
+    ] {
+        let rejected = login(email, password).await.unwrap_err();
+        assert!(
+            matches!(rejected, AppError::Authentication(ref message) if message == "Invalid email or password")
+        );
+    }
+    let logins: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM login_events")
+        .fetch_one(&db)
+        .await
+        .unwrap();
+    assert_eq!(logins, 1);
+}
+
+#[sqlx::test(migrator = "mindful_code_backend::state::MIGRATOR")]
+async fn test_register_creates_a_free_account_and_records_the_client_ip(db: sqlx::PgPool) {
+    use axum::{extract::State, Json};
+    use mindful_code_backend::{
+        models::auth::RegisterRequest,
+        utils::{auth::validate_jwt_token_with, ClientIp},
+    };
+
+    let state = AppState::from_pools(Config::from_env().unwrap(), db.clone(), None);
+    let client_ip: std::net::IpAddr = "198.51.100.23".parse().unwrap();
+    let register = |email: &str, password: &str| {
+        auth::register(
+            State(state.clone()),
+            ClientIp(Some(client_ip)),
+            Json(RegisterRequest {
+                email: email.to_string(),
+                password: password.to_string(),
+            }),
+        )
+    };
+
+    let Json(tokens) = register("new@example.com", "correct horse battery")
+        .await
+        .unwrap();
+    let claims = validate_jwt_token_with(
+        &tokens.access_token,
+        &state.config.jwt_secret,
+        state.config.jwt_algorithm,
+    )
+    .unwrap();
+    assert_eq!(claims.subscription_tier, SubscriptionTier::Free);
+
+    let (email, password_hash): (String, String) =
+        sqlx::query_as("SELECT email, password_hash FROM users WHERE id = $1")
+            .bind(claims.user_id)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+    assert_eq!(email, "new@example.com");
+    assert!(verify_password("correct horse battery", &password_hash).unwrap());
+
+    let recorded: Vec<Option<String>> =
+        sqlx::query_scalar("SELECT ip_address FROM login_events WHERE user_id = $1")
+            .bind(claims.user_id)
+            .fetch_all(&db)
+            .await
+            .unwrap();
+    assert_eq!(recorded, vec![Some("198.51.100.23".to_string())]);
+
+    let taken = register("new@example.com", "another password").await.unwrap_err();
+    assert!(matches!(taken, AppError::Conflict(_)));
+    let weak = register("weak@example.com", "short").await.unwrap_err();
+    assert!(matches!(weak, AppError::Validation(_)));
+
+    let logins: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM login_events")
+        .fetch_one(&db)
+        .await
+        .unwrap();
+    assert_eq!(logins, 1);
+}
+
+#[sqlx::test(migrator = "mindful_code_backend::state::MIGRATOR")]
+async fn test_scheduled_key_rotation_reencrypts_and_prunes(db: sqlx::PgPool) {
+    let user_id: Uuid = sqlx::query_scalar(
+        "INSERT INTO users (email, password_hash) VALUES ('keys@example.com', 'x') RETURNING id",
+    )
+    .fetch_one(&db)
+    .await
+    .unwrap();
+
+    let mut config = Config::from_env().unwrap();
+    config.encryption_key = EncryptionService::key_to_hex(&EncryptionService::generate_master_key());
+    config.key_rotation = KeyRotationConfig {
+        interval_days: 1,
+        retired_key_retention_days: 1,
+        check_interval_secs: 1,
+    };
+    let state = AppState::from_pools(config, db.clone(), None);
+    let encryption = state.encryption.clone().unwrap();
+    let start = chrono::Utc::now();
+    key_rotation::load_encryption_keys(&state, start).await.unwrap();
+    let original_key_id = encryption.read().get_current_key_id().to_string();
+
+    let secret = serde_json::json!({ "project_path": "~/work/secret-project" });
+    let sealed = encryption.read().encrypt_sensitive_data(&secret).unwrap();
+    sqlx::query(
+        r#"
+        INSERT INTO encrypted_user_data (user_id, data_type, encrypted_data, encryption_key_id)
+        VALUES ($1, 'session_context', $2, $3)
+        "#,
+    )
+    .bind(user_id)
+    .bind(serde_json::to_vec(&sealed).unwrap())
+    .bind(&sealed.key_id)
+    .execute(&db)
+    .await
+    .unwrap();
+
+    async fn stored(
+        db: &sqlx::PgPool,
+        encryption: &parking_lot::RwLock<EncryptionService>,
+        user_id: Uuid,
+    ) -> (serde_json::Value, String) {
+        let (data, key_id): (Vec<u8>, String) = sqlx::query_as(
+            "SELECT encrypted_data, encryption_key_id FROM encrypted_user_data WHERE user_id = $1",
+        )
+        .bind(user_id)
+        .fetch_one(db)
+        .await
+        .unwrap();
+        let sealed: EncryptedData = serde_json::from_slice(&data).unwrap();
+        assert_eq!(sealed.key_id, key_id);
+        let value = encryption.read().decrypt_sensitive_data(&sealed).unwrap();
+        (value, key_id)
+    }
+
+    // Not due yet
+    let report = key_rotation::run_key_rotation_cycle(&state, start).await.unwrap();
+    assert_eq!(report, KeyRotationReport::default());
+
+    // Two days on, the key rotates and the record moves onto the new key
+    let report = key_rotation::run_key_rotation_cycle(&state, start + chrono::Duration::days(2))
+        .await
+        .unwrap();
+    let rotated_key_id = report.rotated_to.clone().expect("key should rotate");
+    assert_eq!(report.reencrypted, 1);
+    assert!(report.pruned.is_empty());
+    assert_eq!(encryption.read().get_current_key_id(), rotated_key_id);
+    assert_eq!(
+        stored(&db, &encryption, user_id).await,
+        (secret.clone(), rotated_key_id.clone())
+    );
+    // Retired, but kept through the retention window
+    assert!(encryption.read().has_key(&original_key_id));
+
+    // A restarted instance picks up the rotated key
+    let restarted = AppState::from_pools(state.config.clone(), db.clone(), None);
+    key_rotation::load_encryption_keys(&restarted, start + chrono::Duration::days(2))
+        .await
+        .unwrap();
+    assert_eq!(
+        restarted.encryption.as_ref().unwrap().read().get_current_key_id(),
+        rotated_key_id
+    );
+
+    // Past retention and unused, the original key is pruned
+    let report = key_rotation::run_key_rotation_cycle(&state, start + chrono::Duration::days(4))
+        .await
+        .unwrap();
+    assert_eq!(report.pruned, vec![original_key_id.clone()]);
+    assert!(!encryption.read().has_key(&original_key_id));
+    assert_eq!(stored(&db, &encryption, user_id).await.0, secret);
+
+    let rotations: i64 =
+        sqlx::query_scalar("SELECT COUNT(*) FROM audit_log WHERE operation = 'key_rotation'")
+            .fetch_one(&db)
+            .await
+            .unwrap();
+    assert_eq!(rotations, 2);
+}
+
+#[sqlx::test(migrator = "mindful_code_backend::state::MIGRATOR")]
+async fn test_gdpr_export_archive_has_manifest_and_category_files(db: sqlx::PgPool) {
+    use axum::extract::{Query, State};
+    use mindful_code_backend::services::{
+        encryption::{ExportFormat, PrivacyManager},
+        export_bundle::{ExportManifest, EXPORT_MANIFEST_FILE},
+    };
+    use std::io::Read;
+
+    let user_id: Uuid = sqlx::query_scalar(
+        "INSERT INTO users (email, password_hash) VALUES ('gdpr@example.com', 'x') RETURNING id",
+    )
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    let mut session_ids = Vec::new();
+    for minutes_ago in [90, 30] {
+        let session_id: Uuid = sqlx::query_scalar(
+            r#"
+            INSERT INTO coding_sessions (user_id, start_time, total_duration_ms)
+            VALUES ($1, NOW() - make_interval(mins => $2), 1200000)
+            RETURNING id
+            "#,
+        )
+        .bind(user_id)
+        .bind(minutes_ago)
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        session_ids.push(session_id);
+    }
+    for intensity in [0.4, 0.7, 0.9] {
+        sqlx::query(
+            "INSERT INTO flow_states (session_id, start_time, intensity_score) VALUES ($1, NOW(), $2)",
+        )
+        .bind(session_ids[0])
+        .bind(intensity)
+        .execute(&db)
+        .await
+        .unwrap();
+    }
+
+    let privacy = PrivacyManager::new(EncryptionService::new(&[7u8; 32]).unwrap());
+    let archive = |format: ExportFormat| {
+        let (privacy, db) = (&privacy, &db);
+        async move {
+            let (bytes, manifest) = privacy
+                .export_user_data_archive(db, user_id, user_id, format, Vec::new(), || async {
+                    Ok(())
+                })
+                .await
+                .unwrap();
+            (
+                zip::ZipArchive::new(std::io::Cursor::new(bytes)).unwrap(),
+                manifest,
+            )
+        }
+    };
+    let read = |zip: &mut zip::ZipArchive<std::io::Cursor<Vec<u8>>>, name: &str| {
+        let mut contents = String::new();
+        zip.by_name(name)
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        contents
+    };
+
+    let (mut zip, manifest) = archive(ExportFormat::Json).await;
+    let mut names: Vec<_> = zip.file_names().map(str::to_string).collect();
+    names.sort();
+    // No encrypted records, so no file for them
+    assert_eq!(
+        names,
+        [
+            "coding_sessions.json",
+            "flow_states.json",
+            EXPORT_MANIFEST_FILE
+        ]
+    );
+
+    let stored: ExportManifest =
+        serde_json::from_str(&read(&mut zip, EXPORT_MANIFEST_FILE)).unwrap();
+    assert_eq!(stored.user_id, user_id);
+    assert_eq!(stored.exported_at, manifest.exported_at);
+    assert_eq!(stored.total_records, 5);
+    let counts: Vec<_> = stored
+        .files
+        .iter()
+        .map(|file| (file.file.as_str(), file.record_count))
+        .collect();
+    assert_eq!(
+        counts,
+        [("coding_sessions.json", 2), ("flow_states.json", 3)]
+    );
+
+    let sessions: Vec<serde_json::Value> =
+        serde_json::from_str(&read(&mut zip, "coding_sessions.json")).unwrap();
+    assert_eq!(sessions.len(), 2);
+    assert_eq!(sessions[0]["id"], session_ids[0].to_string());
+    let flow_states: Vec<serde_json::Value> =
+        serde_json::from_str(&read(&mut zip, "flow_states.json")).unwrap();
+    assert_eq!(flow_states.len(), 3);
+
+    // CSV files carry a header line and one line per record
+    let (mut zip, _) = archive(ExportFormat::Csv).await;
+    let flow_states = read(&mut zip, "flow_states.csv");
+    assert_eq!(flow_states.lines().count(), 4);
+    assert!(flow_states.starts_with("id,session_id,start_time"));
+
+    assert_eq!(
+        sqlx::query_scalar::<_, i64>(
+            "SELECT COUNT(*) FROM audit_log WHERE operation = 'export' AND subject_user_id = $1",
+        )
+        .bind(user_id)
+        .fetch_one(&db)
+        .await
+        .unwrap(),
+        2
+    );
+
+    // The endpoint streams the same archive
+    let mut config = Config::from_env().unwrap();
+    config.encryption_key = EncryptionService::key_to_hex(&EncryptionService::generate_master_key());
+    let state = AppState::from_pools(config, db.clone(), None);
+    let claims = Claims::new(user_id, "gdpr@example.com".to_string(), "free".to_string());
+    let response = privacy::export_user_data(
+        State(state.clone()),
+        claims.clone(),
+        Query(privacy::PrivacyExportQuery { format: ExportFormat::Csv, archive: true }),
+    )
+    .await
+    .unwrap();
+    assert_eq!(response.headers()["content-type"], "application/zip");
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let mut zip = zip::ZipArchive::new(std::io::Cursor::new(body.to_vec())).unwrap();
+    assert_eq!(read(&mut zip, "flow_states.csv").lines().count(), 4);
+    assert!(zip.by_name(EXPORT_MANIFEST_FILE).is_ok());
+
+    let xml = privacy::export_user_data(
+        State(state),
+        claims,
+        Query(privacy::PrivacyExportQuery { format: ExportFormat::Xml, archive: true }),
+    )
+    .await;
+    assert!(matches!(xml, Err(AppError::Validation(_))));
+}
+
+#[sqlx::test(migrations = false)]
+async fn test_edited_migration_reports_checksum_mismatch(db: sqlx::PgPool) {
+    use mindful_code_backend::services::migrations::{run_migrations, MigrationFailure};
+    use sqlx::migrate::Migrator;
+
+    let fixture = |name: &str| {
+        std::path::PathBuf::from(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/fixtures/migrations"
+        ))
+        .join(name)
+    };
+    let applied = Migrator::new(fixture("applied")).await.unwrap();
+    run_migrations(&applied, &db).await.unwrap();
+
+    // Same version, different SQL
+    let edited = Migrator::new(fixture("edited")).await.unwrap();
+    let failure = run_migrations(&edited, &db).await.unwrap_err();
+    match &failure {
+        MigrationFailure::ChecksumMismatch {
+            version,
+            description,
+        } => {
+            assert_eq!(*version, 1);
+            assert_eq!(description, "create widgets");
+        }
+        other => panic!("expected a checksum mismatch, got {:?}", other),
+    }
+    let message = failure.to_string();
+    assert!(
+        message.contains("migration 1 (create widgets)"),
+        "{}",
+        message
+    );
+    assert!(message.contains("new migration"), "{}", message);
+
+    // Nothing was re-run
+    let runs: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM _sqlx_migrations")
+        .fetch_one(&db)
+        .await
+        .unwrap();
+    assert_eq!(runs, 1);
+}
+
+#[sqlx::test(migrator = "mindful_code_backend::state::MIGRATOR")]
+async fn test_team_dashboard_receives_presence_from_consenting_members_only(db: sqlx::PgPool) {
+    use axum::extract::{Path, State};
+    use axum::Json;
+
+    let mut users = Vec::new();
+    for email in [
+        "lead@example.com",
+        "sharer@example.com",
+        "quiet@example.com",
+    ] {
+        let user_id: Uuid = sqlx::query_scalar(
+            "INSERT INTO users (email, password_hash) VALUES ($1, 'x') RETURNING id",
+        )
+        .bind(email)
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        users.push(Claims::new(user_id, email.to_string(), "team".to_string()));
+    }
+    let (lead, sharer, quiet) = (&users[0], &users[1], &users[2]);
+    let mut config = Config::from_env().unwrap();
+    config.team_dashboard_min_interval_ms = 0;
+    let state = AppState::from_pools(config, db.clone(), None);
+
+    let Json(team) = teams::create_team(
+        State(state.clone()),
+        lead.clone(),
+        Json(CreateTeamRequest {
+            name: "Platform".to_string(),
+        }),
+    )
+    .await
+    .unwrap();
+    teams::add_team_members(
+        State(state.clone()),
+        lead.clone(),
+        Path(team.id),
+        Json(AddTeamMembersRequest {
+            members: [sharer, quiet]
+                .into_iter()
+                .map(|member| TeamMemberSpec {
+                    user_id: member.user_id,
+                    role: TeamRole::Member,
+                })
+                .collect(),
+        }),
+    )
+    .await
+    .unwrap();
+    // Only the sharer shares their data with the team
+    sqlx::query("UPDATE team_members SET data_sharing_consent = (user_id = $2) WHERE team_id = $1")
+        .bind(team.id)
+        .bind(sharer.user_id)
+        .execute(&db)
+        .await
+        .unwrap();
+
+    // Plain members can't open the dashboard
+    let denied = websocket::subscribe_team_dashboard(&state, quiet.user_id, team.id)
+        .await
+        .unwrap_err();
+    assert!(matches!(denied, AppError::Authorization(_)));
+
+    let (sender, mut dashboard) = tokio::sync::mpsc::unbounded_channel();
+    state.add_websocket_connection(lead.user_id, sender, false);
+    websocket::subscribe_team_dashboard(&state, lead.user_id, team.id)
+        .await
+        .unwrap();
+    let opened: serde_json::Value = serde_json::from_str(&dashboard.recv().await.unwrap()).unwrap();
+    assert_eq!(opened["type"], "team_presence");
+    assert_eq!(opened["members"], serde_json::json!([]));
+
+    let detect = |claims: Claims, session_id: Uuid| {
+        flow::detect_flow_state(
+            State(state.clone()),
+            claims,
+            ResponseFormat::default(),
+            axum::extract::Query(Default::default()),
+            Json(flow::FlowDetectionPayload {
+                request: FlowDetectionRequest {
+                    flow_data: FlowStateData {
+                        session_id,
+                        keystroke_intervals: vec![120, 125, 118, 122, 130, 119, 121, 124, 117, 123],
+                        context_switches: 0,
+                        error_events: 0,
+                        window_focus_duration: 600000,
+                        file_modifications: 3,
+                        ..sample_flow_data()
+                    },
+                    user_preferences: None,
+                },
+            }),
+        )
+    };
+
+    let open_session = |claims: &Claims| {
+        sqlx::query_scalar::<_, Uuid>(
+            "INSERT INTO coding_sessions (user_id, start_time) VALUES ($1, NOW()) RETURNING id",
+        )
+        .bind(claims.user_id)
+        .fetch_one(&db)
+    };
+
+    detect(sharer.clone(), open_session(sharer).await.unwrap()).await.unwrap();
+    let update: serde_json::Value = serde_json::from_str(&dashboard.recv().await.unwrap()).unwrap();
+    assert_eq!(update["type"], "team_presence");
+    assert_eq!(update["team_id"], team.id.to_string());
+    assert_eq!(update["members"].as_array().unwrap().len(), 1);
+    assert_eq!(update["members"][0]["user_id"], sharer.user_id.to_string());
+
+    // The opted-out member's flow never reaches the dashboard
+    detect(quiet.clone(), open_session(quiet).await.unwrap()).await.unwrap();
+    assert!(dashboard.try_recv().is_err());
+
+    // A manager demoted after opening the dashboard is dropped from it
+    let set_role = |role: &'static str| {
+        sqlx::query("UPDATE team_members SET role = $3 WHERE team_id = $1 AND user_id = $2")
+            .bind(team.id)
+            .bind(quiet.user_id)
+            .bind(role)
+            .execute(&db)
+    };
+    set_role("manager").await.unwrap();
+    let (sender, mut demoted) = tokio::sync::mpsc::unbounded_channel();
+    state.add_websocket_connection(quiet.user_id, sender, false);
+    websocket::subscribe_team_dashboard(&state, quiet.user_id, team.id)
+        .await
+        .unwrap();
+    demoted.recv().await.unwrap();
+    set_role("member").await.unwrap();
+
+    detect(sharer.clone(), open_session(sharer).await.unwrap()).await.unwrap();
+    let update: serde_json::Value = serde_json::from_str(&dashboard.recv().await.unwrap()).unwrap();
+    assert_eq!(update["type"], "team_presence");
+    assert!(demoted.try_recv().is_err());
+
+    // Closing the connection ends the subscription
+    state.team_dashboards.unsubscribe_all(lead.user_id);
+    assert!(state.team_dashboards.watched_teams().is_empty());
+}
+
+#[sqlx::test(migrator = "mindful_code_backend::state::MIGRATOR")]
+async fn test_flow_writes_retry_transient_failures_and_dead_letter_permanent_ones(
+    db: sqlx::PgPool,
+) {
+    use mindful_code_backend::config::FlowWriteRetryConfig;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    let user_id: Uuid = sqlx::query_scalar(
+        "INSERT INTO users (email, password_hash) VALUES ('retry@example.com', 'x') RETURNING id",
+    )
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    let session_id: Uuid = sqlx::query_scalar(
+        "INSERT INTO coding_sessions (user_id, start_time) VALUES ($1, NOW()) RETURNING id",
+    )
+    .bind(user_id)
+    .fetch_one(&db)
+    .await
+    .unwrap();
+
+    let mut engine = FlowDetectionEngine::new();
+    let flow_data = FlowStateData {
+        session_id,
+        ..sample_flow_data()
+    };
+    let keystroke_hash = engine.keystroke_hash(&flow_data);
+    let result = engine.analyze_flow_state(flow_data, None).await.unwrap();
+    let baseline_samples = engine.take_unsaved_baseline();
+    let write = |session_id| flow::PendingFlowWrite {
+        write_id: Uuid::new_v4(),
+        user_id,
+        session_id,
+        result: result.clone(),
+        keystroke_hash: keystroke_hash.clone(),
+        focus_mode: false,
+        baseline_samples,
+    };
+    let retry = FlowWriteRetryConfig {
+        max_attempts: 3,
+        initial_backoff_ms: 1,
+        max_backoff_ms: 5,
+    };
+
+    // The pool times out once, then the write goes through
+    let flaky = &write(session_id);
+    let attempts = &AtomicU32::new(0);
+    let db_ref = &db;
+    let outcome = flow::store_flow_write_with(&db, flaky, &retry, || async move {
+        if attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+            return Err(AppError::Database(sqlx::Error::PoolTimedOut));
+        }
+        flow::persist_flow_write(db_ref, flaky, false).await
+    })
+    .await
+    .unwrap();
+    assert_eq!(outcome, flow::FlowWriteOutcome::Stored { attempts: 2 });
+    let stored: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM flow_states WHERE write_id = $1")
+        .bind(flaky.write_id)
+        .fetch_one(&db)
+        .await
+        .unwrap();
+    assert_eq!(stored, 1);
+
+    // A session that doesn't exist fails the same way every time, so it is
+    // dead-lettered without retrying
+    let orphaned = write(Uuid::new_v4());
+    let outcome = flow::store_flow_write(&db, &orphaned, false, &retry)
+        .await
+        .unwrap();
+    assert_eq!(
+        outcome,
+        flow::FlowWriteOutcome::DeadLettered { attempts: 1 }
+    );
+
+    // As is one that never stops timing out, once its attempts run out
+    let stuck = write(session_id);
+    let outcome = flow::store_flow_write_with(&db, &stuck, &retry, || async {
+        Err(AppError::Database(sqlx::Error::PoolTimedOut))
+    })
+    .await
+    .unwrap();
+    assert_eq!(
+        outcome,
+        flow::FlowWriteOutcome::DeadLettered { attempts: 3 }
+    );
+
+    let dead_letters: Vec<(Uuid, String, i32, serde_json::Value)> = sqlx::query_as(
+        "SELECT write_id, error, attempts, payload FROM flow_write_dead_letters ORDER BY attempts",
+    )
+    .fetch_all(&db)
+    .await
+    .unwrap();
+    assert_eq!(dead_letters.len(), 2);
+    let (write_id, error, attempts, payload) = &dead_letters[0];
+    assert_eq!((*write_id, *attempts), (orphaned.write_id, 1));
+    assert!(error.contains("foreign key"), "{}", error);
+    assert_eq!(payload["session_id"], orphaned.session_id.to_string());
+    assert_eq!((dead_letters[1].0, dead_letters[1].2), (stuck.write_id, 3));
+}
+
+/// Runs the admin extractor on a request carrying one header.
+async fn extract_admin_auth(
+    state: &AppState,
+    header: &str,
+    value: &str,
+) -> Result<AdminAuth, AppError> {
+    use axum::extract::FromRequestParts;
+
+    let (mut parts, ()) = axum::http::Request::builder()
+        .uri("/api/admin/migrations")
+        .header(header, value)
+        .body(())
+        .unwrap()
+        .into_parts();
+    AdminAuth::from_request_parts(&mut parts, state).await
+}
+
+#[sqlx::test(migrator = "mindful_code_backend::state::MIGRATOR")]
+async fn test_admin_endpoints_require_admin_credential(db: sqlx::PgPool) {
+    let admin_key = "operator-key-0123456789abcdef0123";
+    let mut config = Config::from_env().unwrap();
+    config.admin_api_key = Some(admin_key.to_string());
+    let state = AppState::from_pools(config, db.clone(), None);
+
+    let api_key = extract_admin_auth(&state, "x-admin-api-key", admin_key)
+        .await
+        .unwrap();
+    assert!(matches!(api_key, AdminAuth::ApiKey));
+    let axum::Json(status) =
+        admin::get_migration_status(axum::extract::State(state.clone()), api_key)
+            .await
+            .unwrap();
+    assert!(!status.has_pending);
+
+    let wrong_key = extract_admin_auth(&state, "x-admin-api-key", "operator-key")
+        .await
+        .unwrap_err();
+    assert_eq!(
+        wrong_key.into_response().status(),
+        axum::http::StatusCode::FORBIDDEN
+    );
+
+    // A paying customer is still just a user
+    let premium = Claims::new(
+        Uuid::new_v4(),
+        "premium@example.com".to_string(),
+        "premium".to_string(),
+    );
+    let token = generate_jwt_token(&premium, &state.config.jwt_secret).unwrap();
+    let bearer = format!("Bearer {}", token);
+    let refused = extract_admin_auth(&state, "authorization", &bearer)
+        .await
+        .unwrap_err();
+    assert_eq!(
+        refused.into_response().status(),
+        axum::http::StatusCode::FORBIDDEN
+    );
+
+    let mut operator = Claims::new(
+        Uuid::new_v4(),
+        "ops@example.com".to_string(),
+        "free".to_string(),
+    );
+    operator.role = UserRole::Admin;
+    let token = generate_jwt_token(&operator, &state.config.jwt_secret).unwrap();
+    let bearer = format!("Bearer {}", token);
+    let admin = extract_admin_auth(&state, "authorization", &bearer)
+        .await
+        .unwrap();
+    assert_eq!(admin.actor_id(), Some(operator.user_id));
+
+    // Without a configured key, no key gets in
+    let mut config = Config::from_env().unwrap();
+    config.admin_api_key = None;
+    let keyless = AppState::from_pools(config, db, None);
+    let disabled = extract_admin_auth(&keyless, "x-admin-api-key", admin_key)
+        .await
+        .unwrap_err();
+    assert!(matches!(disabled, AppError::Authorization(_)));
+}
+
+#[sqlx::test(migrator = "mindful_code_backend::state::MIGRATOR")]
+async fn test_websocket_analytics_requests_are_answered_and_rate_limited(db: sqlx::PgPool) {
+    use axum::extract::ws::Message;
+    use mindful_code_backend::handlers::websocket::{WebSocketMessage, MIN_PROTOCOL_VERSION};
+
+    let user_id: Uuid = sqlx::query_scalar(
+        "INSERT INTO users (email, password_hash) VALUES ('live@example.com', 'x') RETURNING id",
+    )
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    let claims = Claims::new(
+        user_id,
+        "live@example.com".to_string(),
+        "premium".to_string(),
+    );
+    let mut config = Config::from_env().unwrap();
+    config.analytics_refreshes_per_minute = 2;
+    let state = AppState::from_pools(config, db.clone(), None);
+    // Two connections for the user; the later one takes over broadcasts
+    let (asking, mut socket) = tokio::sync::mpsc::unbounded_channel();
+    state.add_websocket_connection(user_id, asking.clone(), false);
+    let (other, mut other_socket) = tokio::sync::mpsc::unbounded_channel();
+    state.add_websocket_connection(user_id, other, false);
+
+    let request = Message::Text(r#"{"type":"request_analytics","range":{"days":7}}"#.to_string());
+    let dispatch = |claims: Claims| {
+        let (state, asking) = (state.clone(), asking.clone());
+        let message = websocket::decode_inbound(&request).unwrap();
+        async move {
+            websocket::handle_websocket_message(
+                message,
+                &claims,
+                MIN_PROTOCOL_VERSION,
+                &state,
+                &asking,
+            )
+            .await
+        }
+    };
+
+    dispatch(claims.clone()).await.unwrap();
+    let pushed: serde_json::Value = serde_json::from_str(&socket.recv().await.unwrap()).unwrap();
+    assert_eq!(pushed["type"], "analytics");
+    assert_eq!(pushed["analytics"]["total_flow_time_ms"], 0);
+    assert_eq!(pushed["analytics"]["opted_out"], false);
+    let from =
+        chrono::DateTime::parse_from_rfc3339(pushed["range"]["from"].as_str().unwrap()).unwrap();
+    let to = chrono::DateTime::parse_from_rfc3339(pushed["range"]["to"].as_str().unwrap()).unwrap();
+    assert_eq!(to - from, chrono::Duration::days(7));
+    // Only the connection that asked is answered
+    assert!(other_socket.try_recv().is_err());
+
+    dispatch(claims.clone()).await.unwrap();
+    socket.recv().await.unwrap();
+    let limited = dispatch(claims.clone()).await.unwrap_err();
+    assert!(matches!(limited, AppError::RateLimit));
+    assert!(socket.try_recv().is_err());
+    match websocket::error_reply(&limited) {
+        WebSocketMessage::Error { code, .. } => assert_eq!(code, 429),
+        other => panic!("unexpected reply {:?}", other),
+    }
+
+    // Free users are told to upgrade rather than sent anything
+    let free = Claims::new(
+        Uuid::new_v4(),
+        "free@example.com".to_string(),
+        "free".to_string(),
+    );
+    let locked = dispatch(free).await.unwrap_err();
+    assert!(matches!(locked, AppError::Authorization(_)));
+    assert!(other_socket.try_recv().is_err());
+}
+
+#[sqlx::test(migrator = "mindful_code_backend::state::MIGRATOR")]
+async fn test_slow_queries_are_logged_and_counted(db: sqlx::PgPool) {
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let logs = CapturedLogs::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_ansi(false)
+        .with_writer(move || writer.clone())
+        .finish();
+    let _logging = tracing::subscriber::set_default(subscriber);
+
+    let mut config = Config::from_env().unwrap();
+    config.slow_query_threshold_ms = 20;
+    let state = AppState::from_pools(config, db.clone(), None);
+    // Connect first, so the fast query's time is only the query
+    sqlx::query("SELECT 1").execute(&db).await.unwrap();
+
+    state
+        .slow_queries
+        .time(
+            "test.sleep",
+            sqlx::query("SELECT pg_sleep(0.1)").execute(&db),
+        )
+        .await
+        .unwrap();
+    state
+        .slow_queries
+        .time("test.fast", sqlx::query("SELECT 1").execute(&db))
+        .await
+        .unwrap();
+
+    assert_eq!(state.slow_queries.slow_queries("test.sleep"), 1);
+    assert_eq!(state.slow_queries.slow_queries("test.fast"), 0);
+    assert!(state
+        .slow_queries
+        .render()
+        .contains("mindful_code_slow_queries_total{query=\"test.sleep\"} 1"));
+
+    let logged = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+    let warning = logged
+        .lines()
+        .find(|line| line.contains("Slow database query"))
+        .expect("slow query warning");
+    assert!(warning.contains("WARN"));
+    assert!(warning.contains("query=\"test.sleep\""));
+    assert!(warning.contains("duration_ms="));
+    assert!(!logged.contains("test.fast"));
+}
+
+#[sqlx::test(migrator = "mindful_code_backend::state::MIGRATOR")]
+async fn test_dashboard_sections_degrade_independently(db: sqlx::PgPool) {
+    use axum::extract::{Query, State};
+    use axum::Json;
+
+    let mut users = Vec::new();
+    for (email, tier) in [("lead@example.com", "team"), ("dev@example.com", "free")] {
+        let user_id: Uuid = sqlx::query_scalar(
+            "INSERT INTO users (email, password_hash) VALUES ($1, 'x') RETURNING id",
+        )
+        .bind(email)
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        users.push(Claims::new(user_id, email.to_string(), tier.to_string()));
+    }
+    let (lead, free) = (&users[0], &users[1]);
+    let state = AppState::from_pools(Config::from_env().unwrap(), db.clone(), None);
+
+    let Json(team) = teams::create_team(
+        State(state.clone()),
+        lead.clone(),
+        Json(CreateTeamRequest { name: "Platform".to_string() }),
+    )
+    .await
+    .unwrap();
+    teams::set_team_goal(
+        State(state.clone()),
+        lead.clone(),
+        axum::extract::Path(team.id),
+        Json(SetTeamGoalRequest {
+            metric: TeamGoalMetric::WeeklyFlowHours,
+            target_value: 20.0,
+        }),
+    )
+    .await
+    .unwrap();
+
+    let load = |claims: &Claims| {
+        let (state, claims) = (state.clone(), claims.clone());
+        async move {
+            let dashboard = dashboard::get_dashboard(
+                State(state),
+                claims,
+                ResponseFormat::default(),
+                Query(Default::default()),
+            )
+            .await
+            .unwrap()
+            .into_data();
+            serde_json::to_value(dashboard).unwrap()
+        }
+    };
+    let statuses = |dashboard: &serde_json::Value| {
+        ["patterns", "insights", "analytics", "goals"].map(|section| {
+            dashboard[section]["status"].as_str().unwrap().to_string()
+        })
+    };
+
+    let dashboard = load(lead).await;
+    assert_eq!(statuses(&dashboard), ["ok", "ok", "ok", "ok"]);
+    assert_eq!(dashboard["goals"]["data"].as_array().unwrap().len(), 1);
+
+    // Premium sections are locked on the free tier; the rest still loads
+    let dashboard = load(free).await;
+    assert_eq!(statuses(&dashboard), ["locked", "locked", "locked", "ok"]);
+    assert!(dashboard["goals"]["data"].as_array().unwrap().is_empty());
+
+    // A failing sub-query only takes out its own section
+    sqlx::query("DROP TABLE team_goals").execute(&db).await.unwrap();
+    let dashboard = load(lead).await;
+    assert_eq!(statuses(&dashboard), ["ok", "ok", "ok", "unavailable"]);
+    assert_eq!(dashboard["goals"]["message"], "Could not load goals");
+}
+
+#[sqlx::test(migrator = "mindful_code_backend::state::MIGRATOR")]
+async fn test_typed_session_environment_round_trips_and_filters_analytics(db: sqlx::PgPool) {
+    use axum::extract::State;
+    use axum::Json;
+
+    let user_id: Uuid = sqlx::query_scalar(
+        "INSERT INTO users (email, password_hash) VALUES ('dev@example.com', 'x') RETURNING id",
+    )
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    let claims = Claims::new(user_id, "dev@example.com".to_string(), "premium".to_string());
+    let state = AppState::from_pools(Config::from_env().unwrap(), db.clone(), None);
+
+    let environment = |monitor_count| SessionEnvironment {
+        editor: Some("vscode".to_string()),
+        os: Some("linux".to_string()),
+        monitor_count: Some(monitor_count),
+        theme: Some("dark".to_string()),
+        extensions: vec!["rust-lang.rust-analyzer".to_string()],
+        extra: serde_json::Map::new(),
+    };
+    let mut session_ids = Vec::new();
+    for monitor_count in [2, 1] {
+        let Json(session) = sessions::start_session(
+            State(state.clone()),
+            claims.clone(),
+            Json(StartSessionRequest {
+                project_path: None,
+                environment_data: Some(environment(monitor_count)),
+            }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(session.environment_data, environment(monitor_count));
+        session_ids.push(session.session_id);
+    }
+
+    // Out-of-range values are rejected
+    let invalid = sessions::start_session(
+        State(state.clone()),
+        claims.clone(),
+        Json(StartSessionRequest {
+            project_path: None,
+            environment_data: Some(environment(0)),
+        }),
+    )
+    .await;
+    assert!(matches!(invalid, Err(AppError::Validation(_))));
+
+    // A free-form blob from before the environment was typed still reads
+    let legacy: Uuid = sqlx::query_scalar(
+        r#"
+        INSERT INTO coding_sessions (user_id, start_time, environment_data)
+        VALUES ($1, NOW(), '{"editor": "vim", "monitor_count": "two", "shell": "zsh"}')
+        RETURNING id
+        "#,
+    )
+    .bind(user_id)
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    session_ids.push(legacy);
+    let stored: serde_json::Value =
+        sqlx::query_scalar("SELECT environment_data FROM coding_sessions WHERE id = $1")
+            .bind(legacy)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+    let legacy_environment = SessionEnvironment::from_stored(stored);
+    assert_eq!(legacy_environment.editor.as_deref(), Some("vim"));
+    assert_eq!(legacy_environment.monitor_count, None);
+    assert_eq!(legacy_environment.extra["monitor_count"], "two");
+    assert_eq!(legacy_environment.extra["shell"], "zsh");
+
+    for session_id in &session_ids {
+        sqlx::query(
+            "INSERT INTO flow_states (session_id, start_time, intensity_score) VALUES ($1, NOW(), 0.7)",
+        )
+        .bind(session_id)
+        .execute(&db)
+        .await
+        .unwrap();
+    }
+
+    let analytics = |environment: SessionEnvironmentFilter| {
+        flow::get_flow_analytics(
+            State(state.clone()),
+            claims.clone(),
+            ResponseFormat::default(),
+            Json(flow::FlowAnalyticsQuery {
+                range: Default::default(),
+                min_data_quality: None,
+                environment,
+                granularity: Default::default(),
+            }),
+        )
+    };
+    let all = analytics(Default::default())
+        .await
+        .unwrap()
+        .into_data()
+        .unwrap();
+    assert_eq!(all.flow_sessions_count, 3);
+
+    let dual_monitor = analytics(SessionEnvironmentFilter {
+        monitor_count: Some(2),
+        ..Default::default()
+    })
+    .await
+    .unwrap()
+    .into_data()
+    .unwrap();
+    assert_eq!(dual_monitor.flow_sessions_count, 1);
+
+    let vim = analytics(SessionEnvironmentFilter {
+        editor: Some("vim".to_string()),
+        ..Default::default()
+    })
+    .await
+    .unwrap()
+    .into_data()
+    .unwrap();
+    assert_eq!(vim.flow_sessions_count, 1);
+}
+
+#[sqlx::test(migrator = "mindful_code_backend::state::MIGRATOR")]
+async fn test_pending_flow_writes_are_replayed_after_restart(db: sqlx::PgPool) {
+    let wal_path = std::env::temp_dir().join(format!("flow-wal-{}.log", Uuid::new_v4()));
+    let mut config = Config::from_env().unwrap();
+    config.flow_wal = FlowWalConfig {
+        path: Some(wal_path.to_string_lossy().into_owned()),
+        sync: WalSyncPolicy::Always,
+        ..FlowWalConfig::default()
+    };
+
+    let user_id: Uuid = sqlx::query_scalar(
+        "INSERT INTO users (email, password_hash) VALUES ('dev@example.com', 'x') RETURNING id",
+    )
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    let session_id: Uuid = sqlx::query_scalar(
+        "INSERT INTO coding_sessions (user_id, start_time) VALUES ($1, NOW()) RETURNING id",
+    )
+    .bind(user_id)
+    .fetch_one(&db)
+    .await
+    .unwrap();
+
+    let mut engine = FlowDetectionEngine::new();
+    let mut writes = Vec::new();
+    for offset_ms in [0, 1_000, 2_000] {
+        let flow_data = FlowStateData {
+            session_id,
+            timestamp: chrono::Utc::now().timestamp_millis() + offset_ms,
+            ..sample_flow_data()
+        };
+        let result = engine.analyze_flow_state(flow_data.clone(), None).await.unwrap();
+        writes.push(flow::PendingFlowWrite {
+            write_id: Uuid::new_v4(),
+            user_id,
+            session_id,
+            result,
+            keystroke_hash: engine.keystroke_hash(&flow_data),
+            focus_mode: false,
+            baseline_samples: engine.take_unsaved_baseline(),
+        });
+    }
+
+    // Logged, then the process dies: one write never ran, one was stored
+    // but not marked done, one finished normally
+    let state = AppState::from_pools(config.clone(), db.clone(), None);
+    let wal = state.flow_wal.clone().unwrap();
+    for write in &writes {
+        wal.append(write.write_id, write).await.unwrap();
+    }
+    flow::persist_flow_write(&db, &writes[1], false).await.unwrap();
+    flow::persist_flow_write(&db, &writes[2], false).await.unwrap();
+    wal.complete(writes[2].write_id).await.unwrap();
+    drop((wal, state));
+
+    let stored = || {
+        let db = db.clone();
+        async move {
+            sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM flow_states WHERE session_id = $1")
+                .bind(session_id)
+                .fetch_one(&db)
+                .await
+                .unwrap()
+        }
+    };
+    assert_eq!(stored().await, 2);
+
+    // The restarted process replays both unfinished writes, without
+    // duplicating the one that had already landed
+    let restarted = AppState::from_pools(config, db.clone(), None);
+    assert_eq!(flow::replay_pending_flow_writes(&restarted).await.unwrap(), 2);
+    assert_eq!(stored().await, 3);
+    let write_ids: Vec<Uuid> =
+        sqlx::query_scalar("SELECT write_id FROM flow_states WHERE session_id = $1")
+            .bind(session_id)
+            .fetch_all(&db)
+            .await
+            .unwrap();
+    assert!(writes.iter().all(|write| write_ids.contains(&write.write_id)));
+
+    // Nothing is left to replay on the next start
+    assert_eq!(restarted.flow_wal.as_ref().unwrap().outstanding(), 0);
+    assert_eq!(flow::replay_pending_flow_writes(&restarted).await.unwrap(), 0);
+    assert_eq!(stored().await, 3);
+
+    std::fs::remove_file(&wal_path).unwrap();
+}
+
+#[sqlx::test(migrator = "mindful_code_backend::state::MIGRATOR")]
+async fn test_analytics_opt_out_hides_user_from_analytics_and_teams(db: sqlx::PgPool) {
+    use axum::extract::{Path, State};
+    use axum::Json;
+    use mindful_code_backend::services::encryption::PrivacySettings;
+
+    let mut users = Vec::new();
+    for email in ["lead@example.com", "quiet@example.com"] {
+        let user_id: Uuid = sqlx::query_scalar(
+            "INSERT INTO users (email, password_hash) VALUES ($1, 'x') RETURNING id",
+        )
+        .bind(email)
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        users.push(Claims::new(user_id, email.to_string(), "team".to_string()));
+    }
+    let (lead, quiet) = (&users[0], &users[1]);
+    let state = AppState::from_pools(Config::from_env().unwrap(), db.clone(), None);
+
+    let Json(team) = teams::create_team(
+        State(state.clone()),
+        lead.clone(),
+        Json(CreateTeamRequest {
+            name: "Platform".to_string(),
+        }),
+    )
+    .await
+    .unwrap();
+    teams::add_team_members(
+        State(state.clone()),
+        lead.clone(),
+        Path(team.id),
+        Json(AddTeamMembersRequest {
+            members: vec![TeamMemberSpec {
+                user_id: quiet.user_id,
+                role: TeamRole::Member,
+            }],
+        }),
+    )
+    .await
+    .unwrap();
+
+    // Both share with the team and each has an hour of flow this week
+    for member in [lead, quiet] {
+        sqlx::query(
+            "UPDATE team_members SET data_sharing_consent = true WHERE team_id = $1 AND user_id = $2",
+        )
+        .bind(team.id)
+        .bind(member.user_id)
+        .execute(&db)
+        .await
+        .unwrap();
+        let session_id: Uuid = sqlx::query_scalar(
+            r#"
+            INSERT INTO coding_sessions
+                (user_id, start_time, end_time, total_duration_ms, total_flow_time_ms)
+            VALUES ($1, NOW(), NOW(), 3600000, 3600000)
+            RETURNING id
+            "#,
+        )
+        .bind(member.user_id)
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        sqlx::query(
+            "INSERT INTO flow_states (session_id, start_time, intensity_score) VALUES ($1, NOW(), 0.8)",
+        )
+        .bind(session_id)
+        .execute(&db)
+        .await
+        .unwrap();
+    }
+
+    // ... but one of them has turned analytics off
+    sqlx::query("UPDATE users SET privacy_settings = $2 WHERE id = $1")
+        .bind(quiet.user_id)
+        .bind(
+            serde_json::to_value(PrivacySettings {
+                analytics_enabled: false,
+                ..Default::default()
+            })
+            .unwrap(),
+        )
+        .execute(&db)
+        .await
+        .unwrap();
+
+    let analytics = |claims: &Claims| {
+        flow::get_flow_analytics(
+            State(state.clone()),
+            claims.clone(),
+            ResponseFormat::default(),
+            Json(flow::FlowAnalyticsQuery {
+                range: Default::default(),
+                min_data_quality: None,
+                environment: Default::default(),
+                granularity: Default::default(),
+            }),
+        )
+    };
+    let opted_out = analytics(quiet).await.unwrap().into_data().unwrap();
+    assert!(opted_out.opted_out);
+    assert_eq!(opted_out.total_flow_time_ms, 0);
+    assert_eq!(opted_out.flow_sessions_count, 0);
+    assert!(opted_out.distribution.is_empty());
+    let shared = analytics(lead).await.unwrap().into_data().unwrap();
+    assert!(!shared.opted_out);
+    assert_eq!(shared.flow_sessions_count, 1);
+
+    // The team goal is scaled to the one member still counted
+    let Json(progress) = teams::set_team_goal(
+        State(state.clone()),
+        lead.clone(),
+        Path(team.id),
+        Json(SetTeamGoalRequest {
+            metric: TeamGoalMetric::WeeklyFlowHours,
+            target_value: 10.0,
+        }),
+    )
+    .await
+    .unwrap();
+    assert_eq!(progress.contributing_members, 1);
+    assert_eq!(progress.excluded_members, 1);
+    assert_eq!(progress.effective_target, 5.0);
+    assert_eq!(progress.achieved_value, 1.0);
+}
+
+#[sqlx::test(migrator = "mindful_code_backend::state::MIGRATOR")]
+async fn test_member_flow_similarity_requires_consent(db: sqlx::PgPool) {
+    use axum::extract::{Path, Query, State};
+    use axum::Json;
+
+    let mut users = Vec::new();
+    for email in ["lead@example.com", "ana@example.com", "ben@example.com", "dee@example.com"] {
+        let user_id: Uuid = sqlx::query_scalar(
+            "INSERT INTO users (email, password_hash) VALUES ($1, 'x') RETURNING id",
+        )
+        .bind(email)
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        users.push(Claims::new(user_id, email.to_string(), "team".to_string()));
+    }
+    let (lead, ana, ben, dee) = (&users[0], &users[1], &users[2], &users[3]);
+    let state = AppState::from_pools(Config::from_env().unwrap(), db.clone(), None);
+
+    let Json(team) = teams::create_team(
+        State(state.clone()),
+        lead.clone(),
+        Json(CreateTeamRequest { name: "Mentoring".to_string() }),
+    )
+    .await
+    .unwrap();
+    teams::add_team_members(
+        State(state.clone()),
+        lead.clone(),
+        Path(team.id),
+        Json(AddTeamMembersRequest {
+            members: [ana, ben, dee]
+                .iter()
+                .map(|member| TeamMemberSpec { user_id: member.user_id, role: TeamRole::Member })
+                .collect(),
+        }),
+    )
+    .await
+    .unwrap();
+
+    // Ana and Ben reach flow in the morning, Dee late at night
+    let now = chrono::Utc::now();
+    for (member, hour, rhythm) in [(ana, 9, 0.8), (ben, 9, 0.75), (dee, 22, 0.3)] {
+        for day in 1..=12 {
+            let start = (now - chrono::Duration::days(day))
+                .date_naive()
+                .and_hms_opt(hour, 0, 0)
+                .unwrap()
+                .and_utc();
+            let session_id: Uuid = sqlx::query_scalar(
+                r#"
+                INSERT INTO coding_sessions
+                    (user_id, start_time, total_duration_ms, total_flow_time_ms)
+                VALUES ($1, $2, 3600000, 1800000)
+                RETURNING id
+                "#,
+            )
+            .bind(member.user_id)
+            .bind(start)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+            sqlx::query(
+                r#"
+                INSERT INTO flow_states (session_id, start_time, intensity_score, ml_features)
+                VALUES ($1, $2, 0.8, $3)
+                "#,
+            )
+            .bind(session_id)
+            .bind(start + chrono::Duration::minutes(10))
+            .bind(serde_json::json!({
+                "rhythm_score": rhythm,
+                "consistency_score": rhythm,
+                "velocity_score": rhythm,
+            }))
+            .execute(&db)
+            .await
+            .unwrap();
+        }
+    }
+
+    let set_consent = |member: Uuid, consent: bool| {
+        let db = db.clone();
+        async move {
+            sqlx::query(
+                "UPDATE team_members SET data_sharing_consent = $3 WHERE team_id = $1 AND user_id = $2",
+            )
+            .bind(team.id)
+            .bind(member)
+            .bind(consent)
+            .execute(&db)
+            .await
+            .unwrap();
+        }
+    };
+    for member in [ana, ben, dee] {
+        set_consent(member.user_id, true).await;
+    }
+
+    let compare = |caller: &Claims, member_a: Uuid, member_b: Uuid| {
+        teams::get_member_flow_similarity(
+            State(state.clone()),
+            caller.clone(),
+            Path(team.id),
+            Query(FlowSimilarityQuery { member_a, member_b }),
+        )
+    };
+
+    let Json(alike) = compare(lead, ana.user_id, ben.user_id).await.unwrap();
+    let Json(apart) = compare(lead, ana.user_id, dee.user_id).await.unwrap();
+    assert!(alike.similarity.overall > 0.9, "{:?}", alike.similarity);
+    assert!(alike.similarity.overall > apart.similarity.overall);
+    assert_eq!(apart.similarity.peak_hours, 0.0);
+
+    // Only managers may compare, and only members who share their data
+    assert!(matches!(
+        compare(ana, ana.user_id, ben.user_id).await,
+        Err(AppError::Authorization(_))
+    ));
+    set_consent(ben.user_id, false).await;
+    match compare(lead, ana.user_id, ben.user_id).await {
+        Err(AppError::Authorization(message)) => assert!(message.contains("consent")),
+        other => panic!("expected a consent error, got {:?}", other.map(|json| json.0)),
+    }
+}
+
 // Helper function to estimate memory usage
 fn get_approximate_memory_usage() -> usize {
     // This is a simplified memory measurement for testing